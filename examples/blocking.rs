@@ -0,0 +1,27 @@
+use anyhow::{anyhow, Result};
+use vvcnv::{
+    blocking,
+    video::{VideoConfig, VideoProcessParams, VideoRes},
+};
+
+/// tokio のランタイムを使わずに 1 本だけエンコードする例.
+fn main() -> Result<()> {
+    let input_path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "assets/2.mp4".to_string());
+    let stat = blocking::stat(input_path).map_err(|e| anyhow!(e))?;
+
+    let config = VideoConfig {
+        res: VideoRes::R480p,
+        ..Default::default()
+    };
+    let params = VideoProcessParams::new("out/blocking.mp4", config);
+    let outcome = blocking::process_with_progress(&stat, &params, |fraction| {
+        eprint!("\r{:>3.0}%", fraction * 100.0);
+    })?;
+    eprintln!();
+
+    println!("エンコード完了: {:.1}s", outcome.elapsed.as_secs_f64());
+
+    Ok(())
+}
@@ -0,0 +1,28 @@
+use anyhow::{anyhow, Result};
+use indicatif::ProgressBar;
+use vvcnv::video::{self, VideoConfig, VideoProcessParams, VideoRes};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let input_path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "assets/2.mp4".to_string());
+    let stat = video::stat(input_path).await.map_err(|e| anyhow!(e))?;
+
+    let config = VideoConfig {
+        res: VideoRes::R480p,
+        ..Default::default()
+    };
+    let params =
+        VideoProcessParams::new("out/metadata_hook.mp4", config).with_command_hook(|command| {
+            command.args(["-metadata", "title=Encoded by vvcnv"]);
+        });
+
+    // フックで追加した引数も含めた, 実際に実行される引数
+    let args = video::command_args(&video::build_command(&stat, &params));
+    println!("ffmpeg {}", args.join(" "));
+
+    video::process(stat, params, ProgressBar::new_spinner()).await?;
+
+    Ok(())
+}
@@ -0,0 +1,3 @@
+mod modules;
+
+pub use modules::*;
@@ -1,22 +1,129 @@
-mod modules;
-
 use anyhow::{anyhow, bail, Context, Result};
-use console::style;
-use ffmpeg_sidecar::event::VideoStream;
-use humansize::{format_size, DECIMAL};
+use clap::Parser;
+use console::{style, Term};
+use humansize::format_size;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use itertools::iproduct;
+use itertools::Itertools;
 use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt, fs,
     iter::{self, zip},
-    ops,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
+use tokio::sync::Semaphore;
 
-use modules::{
-    file,
-    video::{self, VideoConfig, VideoRes, VideoStat},
+use vvcnv::{
+    ab, av_sync, chunk,
+    cli::{
+        AbArgs, CleanArgs, Cli, Command, ConfigAction, ConfigArgs, HistoryAction, HistoryArgs,
+        MigrateArgs, OutputLayout, QualityMetric, ReportAction, ReportArgs, RerunArgs,
+        SkipIfBetterMode, SubsAction, SubsArgs,
+    },
+    codec_params,
+    concurrency::Concurrency,
+    config,
+    estimate::{self, Calibration},
+    ffmpeg, file,
+    groups::{BarGroup, Row, TaskEvent, TaskState},
+    history::{History, HistoryEntry},
+    html_report,
+    hwaccel::{self, HwAccel},
+    input::{self, InputFile},
+    integrity::{self, InputIntegrity, IntegrityStatus},
+    layout::{self, Layout},
+    logs::{self, EncodeLog},
+    matrix::{self, ConfigSource, CrfOffsets, Matrix},
+    matrix_file, mux,
+    naming::{self, Migration},
+    overlay::{self, LabelOverlay},
+    overrides,
+    pause::PauseControl,
+    phases::{self, Phase, PhaseLog},
+    plain_progress::{self, PlainLine},
+    presets,
+    publish::{self, MoveStrategy},
+    quality::{self, CurvePoint, QualityCalibration},
+    report::{self, OutcomeStats, SessionReport, Sidecar, TaskReport, TaskStatus},
+    report_diff::{self, ReportDiff},
+    results::{self, EncodeReport, InputFailure, ResultRecord, RunResults},
+    reuse::{self, ReuseEntry, ReuseIndex, ReuseKey},
+    sampling::{self, WindowSpec},
+    sandbox,
+    schedule::{Clock, RunBudget},
+    size_limit::{self, SizeLimit},
+    stall, subs, text,
+    thumbnail::{self, ThumbnailMode},
+    time, verbosity,
+    video::{
+        self, CancelToken, CompatSeverity, FpsMode, FrameProgress, ProcessErr, ProcessOutcome,
+        ProgressDriver, SourceVerdict, TaskMode, Trim, VideoCodec, VideoConfig, VideoProcessParams,
+        VideoRes, VideoStat,
+    },
+    workspace::{self, EntryKind, Workspace},
 };
 
-fn get_style(is_done: bool) -> ProgressStyle {
+type TaskOutput = (TaskStatus, Option<OutcomeStats>);
+
+/// 複数の入力で共有する, 実行全体の状態.
+struct Session {
+    pause: PauseControl,
+    budget: Arc<RunBudget<PauseControl>>,
+    cancel: CancelToken,
+    /// 一時停止の表示を切り替える, 実行中の入力の進捗バー.
+    bars: Arc<Mutex<Vec<ProgressBar>>>,
+    /// すべての入力の段階. 最後に全体の時間の内訳として表示する.
+    phases: Mutex<Vec<Phase>>,
+    /// 出力のパスと, それを作る入力. 別の入力の出力を上書きしないようにする.
+    outputs: Mutex<HashMap<String, String>>,
+    /// 同時に実行するタスクの決め方.
+    concurrency: Concurrency,
+    /// エンコードは `concurrency` で決めた重みの分だけ許可を得てから始める.
+    jobs: Arc<Semaphore>,
+    /// Ctrl+C で中断した. 以降のタスクと入力は始めない.
+    interrupted: Arc<AtomicBool>,
+    /// 処理する入力の数. 複数の場合は, 入力ごとに見出しを付けてタスクの進捗をまとめる.
+    inputs: usize,
+    /// `--json` と `--csv` で書き出す, すべてのタスクの結果.
+    results: Mutex<RunResults>,
+    /// 今回失敗したタスクのログ. `--log-retention` などで古いログを削除するときも残す.
+    failed_logs: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+/// 以前の実行で作った出力の索引と, 入力のハッシュ.
+#[derive(Clone)]
+struct Reuse {
+    index: Arc<Mutex<ReuseIndex>>,
+    input_hash: String,
+}
+
+/// 端末の大きさの変化を確認する間隔.
+const RELAYOUT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// `--retries` の最初の待ち時間. 1 回ごとに倍にする.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// `--deadline` を過ぎたかを確かめる間隔. 一時停止すると期限が延びるので, 最初に求めた時刻までは眠らない.
+const DEADLINE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 端末が小さい場合は 1 タスクを 1 行で表示する. 表示方法は `TaskBars` が切り替える.
+static COMPACT: AtomicBool = AtomicBool::new(false);
+/// 端末でない場合 (`--progress plain`) は, 進捗バーの代わりに色なしの 1 行で表示する.
+static PLAIN: AtomicBool = AtomicBool::new(false);
+
+fn get_style(is_done: bool, unit: &str) -> ProgressStyle {
+    if PLAIN.load(Ordering::Relaxed) {
+        return get_plain_style(is_done, unit);
+    }
+    if COMPACT.load(Ordering::Relaxed) {
+        return get_compact_style(is_done);
+    }
     ProgressStyle::with_template(&format!(
         "\n{} -> {}\n  {}\n  {}{} {} {} | {}{} | {}",
         style("{spinner}").blue(),
@@ -24,7 +131,7 @@ fn get_style(is_done: bool) -> ProgressStyle {
         "{bar:40.cyan/blue}",
         "{pos:>3}",
         style("/{len:>3}").dim(),
-        style("[fr]").dim(),
+        style(format!("[{}]", unit)).dim(),
         style(format!("({})", style("{percent:>3}%").for_stdout())).dim(),
         "{elapsed_precise}",
         if is_done {
@@ -38,117 +145,3553 @@ fn get_style(is_done: bool) -> ProgressStyle {
     .progress_chars("=>-")
 }
 
-async fn process(stat: VideoStat, config: VideoConfig, pb: ProgressBar) -> Result<()> {
+fn get_compact_style(is_done: bool) -> ProgressStyle {
+    ProgressStyle::with_template(&format!(
+        "{} {} {} {} {}{} {}",
+        style("{spinner}").blue(),
+        style("{prefix}"),
+        "{bar:20.cyan/blue}",
+        style("{percent:>3}%").dim(),
+        "{elapsed_precise}",
+        if is_done {
+            style("").dim()
+        } else {
+            style("/{duration_precise}").dim()
+        },
+        style("{msg}")
+    ))
+    .unwrap()
+    .progress_chars("=>-")
+}
+
+fn get_plain_style(is_done: bool, unit: &str) -> ProgressStyle {
+    match is_done {
+        true => ProgressStyle::with_template("{prefix}: {msg}").unwrap(),
+        false => ProgressStyle::with_template(&format!(
+            "{{prefix}}: {{percent}}% ({{pos}}/{{len}} {}) {{elapsed_precise}} {{msg}}",
+            unit
+        ))
+        .unwrap(),
+    }
+}
+
+/// 進捗に表示するタスクの設定. プリセットから作った設定はプリセットの名前を付ける.
+fn task_prefix(config: &VideoConfig) -> String {
+    let fps_mode = match config.fps_mode.filter(FpsMode::interpolates) {
+        Some(mode) => format!(" ({})", mode),
+        None => String::new(),
+    };
+    let codec = match &config.codec {
+        Some(codec) => format!(", CODEC: {}", codec.name()),
+        None => String::new(),
+    };
+    let prefix = format!(
+        "RES: {:?}, FPS: {}{}, CRF: {}{}",
+        config.res, config.fps, fps_mode, config.crf, codec
+    );
+    match &config.preset_name {
+        Some(name) => format!("[{}] {}", name, prefix),
+        None => prefix,
+    }
+}
+
+/// 出力のパスが重なる設定や入力があれば, 途中で上書きする前に止める.
+fn check_collisions(
+    cli: &Cli,
+    stat: &VideoStat,
+    configs: &[VideoConfig],
+    session: &Session,
+) -> Result<()> {
+    let paths = configs
+        .iter()
+        .map(|config| output_path(cli, stat, config))
+        .collect::<Vec<_>>();
+    if let Some((a, b)) = naming::first_collision(&paths) {
+        let hint = match &cli.name_template {
+            Some(_) => "--name-template に {width} / {height} / {fps} / {crf} / {preset} など, 設定ごとに異なる値を含めてください",
+            None => "プリセットを使う場合は --name-preset でファイル名にプリセット名を含めてください",
+        };
+        bail!(
+            "{} 番目 ({}) と {} 番目 ({}) の設定が同じ出力ファイル ({}) になります. {}.",
+            a + 1,
+            task_prefix(&configs[a]),
+            b + 1,
+            task_prefix(&configs[b]),
+            paths[a],
+            hint
+        );
+    }
+
+    let mut outputs = session.outputs.lock().unwrap();
+    if let Some((path, other)) = paths.iter().find_map(|p| {
+        outputs
+            .get_key_value(p)
+            .filter(|(_, input)| **input != stat.path)
+    }) {
+        let hint = match (&cli.name_template, cli.layout) {
+            (Some(_), _) => "--name-template を使う場合は {stem} を含めてください",
+            // per-config ではファイル名に設定を含めないので, 別のディレクトリの同じ名前の入力でも重なる
+            (None, OutputLayout::PerConfig) => {
+                "per-config では元のファイル名のまま書き出すため, 同じ名前の入力は名前を変えるか別々に実行してください"
+            }
+            (None, OutputLayout::Flat) => "入力の名前を変えてください",
+        };
+        bail!(
+            "別の入力 ({}) と同じ出力ファイル ({}) になります. {}.",
+            other,
+            path,
+            hint
+        );
+    }
+    outputs.extend(paths.into_iter().map(|p| (p, stat.path.clone())));
+
+    Ok(())
+}
+
+struct BarsState {
+    layout: Layout,
+    group: BarGroup,
+}
+
+/// タスクごとの進捗. 端末の大きさに合わせて 3 行 / 1 行 / 実行中のタスクだけの表示を切り替える.
+struct TaskBars {
+    progress: MultiProgress,
+    bars: Vec<ProgressBar>,
+    /// 全体の進捗. 終わったタスクの数と, 全タスクで処理したフレーム数を, タスクの進捗の上に表示する.
+    aggregate: ProgressBar,
+    /// 複数の入力を処理する場合の, 入力の見出しと全体の進捗. `aggregate` の代わりに表示する.
+    header: Option<ProgressBar>,
+    /// 1 タスクで処理するフレーム数. 全体の進捗の目安にする.
+    frames: u64,
+    state: Mutex<BarsState>,
+    unit: String,
+    /// 切り詰める前の各タスクの表示.
+    prefixes: Vec<String>,
+    /// 再試行中のタスクの, 試行の回数と上限.
+    attempts: Mutex<Vec<Option<(u32, u32)>>>,
+    /// 1 行ずつ表示する場合の, 全体の進捗 (見出し) と各タスクの描画先. 進捗バーは描画しない.
+    plain: Option<(Option<PlainLine>, Vec<PlainLine>)>,
+}
+
+impl TaskBars {
+    /// `header` を渡すと入力の見出しの下にまとめ, `collapse` の場合は実行中だけタスクの進捗を表示する.
+    fn new(
+        prefixes: Vec<String>,
+        unit: &str,
+        frames: u64,
+        header: Option<String>,
+        collapse: bool,
+    ) -> Self {
+        let layout = Self::measure(prefixes.len());
+        COMPACT.store(layout.is_compact(), Ordering::Relaxed);
+        let bars = prefixes
+            .iter()
+            .map(|_| ProgressBar::hidden().with_style(get_style(false, unit)))
+            .collect::<Vec<_>>();
+        let aggregate = ProgressBar::hidden().with_style(
+            ProgressStyle::with_template(
+                "{prefix} {bar:20.green/blue} {msg} {percent:>3}% ({pos}/{len} fr)",
+            )
+            .unwrap(),
+        );
+        aggregate.set_length(frames * bars.len() as u64);
+        aggregate.set_message(format!("0/{} 完了", bars.len()));
+        aggregate.set_prefix(format!("{}", style("全体").bold()));
+        let header = header.map(|name| {
+            let pb = ProgressBar::hidden().with_style(
+                ProgressStyle::with_template(
+                    "{prefix} {bar:20.green/blue} {pos}/{len} 完了 {elapsed_precise} {msg}",
+                )
+                .unwrap(),
+            );
+            pb.set_length(bars.len() as u64);
+            pb.set_prefix(format!("{}", style(name).bold()));
+            pb
+        });
+        let plain = PLAIN.load(Ordering::Relaxed).then(|| {
+            let lines = bars
+                .iter()
+                .map(|pb| {
+                    let line = PlainLine::new();
+                    pb.set_draw_target(line.draw_target());
+                    line
+                })
+                .collect();
+            let summary = match &header {
+                Some(header) => Some(header),
+                None => (bars.len() > 1).then_some(&aggregate),
+            };
+            let summary = summary.map(|pb| {
+                let line = PlainLine::new();
+                pb.set_draw_target(line.draw_target());
+                line
+            });
+            (summary, lines)
+        });
+
+        let task_bars = Self {
+            progress: MultiProgress::new(),
+            state: Mutex::new(BarsState {
+                layout,
+                group: BarGroup::new(bars.len(), collapse && header.is_some()),
+            }),
+            bars,
+            aggregate,
+            header,
+            frames,
+            unit: unit.to_string(),
+            attempts: Mutex::new(vec![None; prefixes.len()]),
+            prefixes,
+            plain,
+        };
+        task_bars.set_prefixes(layout);
+        task_bars.show(&task_bars.state.lock().unwrap());
+        verbosity::attach(&task_bars.progress);
+
+        task_bars
+    }
+
+    /// 表示幅で切り詰めて揃える. 1 行の表示では, 日本語や絵文字を含んでいても進捗の列がずれないようにする.
+    fn set_prefixes(&self, layout: Layout) {
+        let cols = Term::stderr().size_checked().map(|(_, cols)| cols);
+        let labels = zip(&self.prefixes, self.attempts.lock().unwrap().iter())
+            .map(|(prefix, attempt)| match attempt {
+                Some((attempt, total)) => format!("{} [{}/{}]", prefix, attempt, total),
+                None => prefix.clone(),
+            })
+            .collect::<Vec<_>>();
+        let prefixes = match self.plain {
+            Some(_) => labels,
+            None => text::align(&labels, layout::prefix_width(layout, cols)),
+        };
+        for (pb, prefix) in zip(&self.bars, prefixes) {
+            pb.set_prefix(prefix);
+        }
+    }
+
+    /// 端末でない場合は全タスクを 3 行で表示する.
+    fn measure(tasks: usize) -> Layout {
+        match Term::stderr().size_checked() {
+            Some((rows, cols)) => layout::choose(rows, cols, tasks),
+            None => Layout::Full,
+        }
+    }
+
+    fn apply(&self, state: &mut BarsState, layout: Layout) {
+        let restyle = state.layout.is_compact() != layout.is_compact();
+        state.layout = layout;
+        COMPACT.store(layout.is_compact(), Ordering::Relaxed);
+        self.set_prefixes(layout);
+        if restyle {
+            for pb in &self.bars {
+                pb.set_style(get_style(pb.is_finished(), &self.unit));
+            }
+        }
+        self.show(state);
+    }
+
+    /// 表示方法に合わせて, 表示するタスクを選び直す.
+    fn show(&self, state: &BarsState) {
+        if self.plain.is_some() {
+            return;
+        }
+        let layout = state.layout;
+        for pb in self
+            .bars
+            .iter()
+            .chain([&self.aggregate])
+            .chain(&self.header)
+        {
+            self.progress.remove(pb);
+        }
+        let mut visible = match layout {
+            Layout::Rolling { visible } => visible,
+            _ => self.bars.len(),
+        };
+        if self.header.is_none() && self.bars.len() > 1 {
+            self.progress.add(self.aggregate.clone());
+        }
+        for row in state.group.rows() {
+            match row {
+                Row::Header => {
+                    if let Some(header) = &self.header {
+                        self.progress.add(header.clone());
+                    }
+                }
+                Row::Task(index) => {
+                    let shown = match layout {
+                        Layout::Rolling { .. } => state.group.is_running(index),
+                        _ => true,
+                    };
+                    if shown && visible > 0 {
+                        self.progress.add(self.bars[index].clone());
+                        visible -= 1;
+                    }
+                }
+            }
+        }
+    }
+
+    /// 端末の大きさが変わっていれば表示方法を選び直す.
+    fn relayout(&self) {
+        let layout = Self::measure(self.bars.len());
+        let mut state = self.state.lock().unwrap();
+        if state.layout != layout {
+            self.apply(&mut state, layout);
+        }
+    }
+
+    fn set_active(&self, index: usize, active: bool) {
+        let mut state = self.state.lock().unwrap();
+        let rows = state.group.rows();
+        state.group.apply(match active {
+            true => TaskEvent::Started(index),
+            false => TaskEvent::Finished(index),
+        });
+        if !active {
+            if let Some(header) = &self.header {
+                header.inc(1);
+            }
+        }
+        self.update_aggregate(&state);
+        if matches!(state.layout, Layout::Rolling { .. }) || state.group.rows() != rows {
+            self.show(&state);
+        }
+    }
+
+    /// 再試行するタスクの表示に, 何回目の試行かを付ける.
+    fn set_attempt(&self, index: usize, attempt: u32, total: u32) {
+        self.attempts.lock().unwrap()[index] = Some((attempt, total));
+        self.set_prefixes(self.state.lock().unwrap().layout);
+    }
+
+    /// 各タスクの進捗から, 全体で処理したフレーム数を表示し直す.
+    fn update_aggregate(&self, state: &BarsState) {
+        let progress = self
+            .bars
+            .iter()
+            .map(|pb| (pb.position(), pb.length().unwrap_or(0)))
+            .collect::<Vec<_>>();
+        let frames = state.group.frames(self.frames, &progress);
+        match &self.header {
+            Some(header) if !header.is_finished() => header.set_message(format!(
+                "{}",
+                style(format!(
+                    "{}/{} fr",
+                    frames,
+                    self.frames * self.bars.len() as u64
+                ))
+                .dim()
+            )),
+            Some(_) => {}
+            None => {
+                let (done, total) = state.group.progress();
+                self.aggregate.set_position(frames);
+                self.aggregate
+                    .set_message(format!("{}/{} 完了", done, total));
+            }
+        }
+    }
+
+    /// 1 行ずつ表示する場合に, 前回から変わった見出しと, 始まったタスクの進捗を書き出す.
+    fn report_plain(&self) {
+        let Some((header, lines)) = &self.plain else {
+            return;
+        };
+        let state = self.state.lock().unwrap();
+        // 終わった後の結果のまとめも書き出す
+        let header = header
+            .as_ref()
+            .filter(|_| state.group.is_active() || state.group.is_finished());
+        for line in header.into_iter().chain(
+            lines
+                .iter()
+                .enumerate()
+                .filter(|(index, _)| state.group.state(*index) != TaskState::Waiting)
+                .map(|(_, line)| line),
+        ) {
+            if let Some(line) = line.take() {
+                eprintln!("{}", line);
+            }
+        }
+    }
+
+    /// すべてのタスクが終わった後に, 見出しを結果の 1 行にする.
+    fn finish_header(&self, message: String) {
+        if let Some(header) = &self.header {
+            header.finish_with_message(message);
+        }
+    }
+}
+
+impl Drop for TaskBars {
+    fn drop(&mut self) {
+        verbosity::detach();
+    }
+}
+
+fn output_path(cli: &Cli, stat: &VideoStat, config: &VideoConfig) -> String {
     let (name, ext) = file::get_file_name(&stat.path);
-    let output_path = format!("out/{}{}.{}", name, config.to_file_name(), ext);
+    let name = match cli.ascii_names {
+        true => text::ascii_stem(&name),
+        false => name,
+    };
+    match &cli.stream_to {
+        Some(url) => url.clone(),
+        None => cli
+            .config_out_dir(config)
+            .join(cli.output_file_name(&name, config, &ext))
+            .to_string_lossy()
+            .into_owned(),
+    }
+}
 
-    video::process(
-        stat,
-        video::VideoProcessParams {
-            output_path: output_path.clone(),
-            config: config.clone(),
+/// タスクの ffmpeg のログの置き場所. `--no-logs` の場合は `None`.
+fn log_path(cli: &Cli, stat: &VideoStat, config: &VideoConfig) -> Option<PathBuf> {
+    (!cli.no_logs).then(|| {
+        logs::encode_log_path(
+            &cli.logs_dir(),
+            &cli.out_root(),
+            Path::new(&output_path(cli, stat, config)),
+        )
+    })
+}
+
+/// `--log-retention` と `--log-max-size` を超えた古いタスクのログを削除する. `protected` (今回失敗したタスクのログ) は残す.
+fn prune_logs(cli: &Cli, protected: &HashSet<PathBuf>) {
+    let retention = cli.log_retention();
+    if cli.no_logs || retention == logs::Retention::default() {
+        return;
+    }
+    match logs::prune(&cli.logs_dir(), &retention, protected, SystemTime::now()) {
+        Ok(removed) if !removed.is_empty() => {
+            verbosity::info(format!("古いログを {} 個削除しました", removed.len()))
+        }
+        Ok(_) => {}
+        Err(e) => verbosity::warn(format!("古いログを削除できませんでした: {}", e)),
+    }
+}
+
+/// CLI の指定から, 1 つの設定のエンコードに使うパラメーターを作る. 中断・一時停止・大きさの上限は呼び出し側で設定する.
+fn process_params(
+    cli: &Cli,
+    config: &VideoConfig,
+    output_path: &str,
+    trim: Trim,
+) -> Result<video::VideoProcessParams> {
+    Ok(video::VideoProcessParams {
+        keep_cover: cli.keep_cover,
+        drop_audio: cli.sample.is_some() && !cli.sample_audio,
+        trim,
+        faststart: cli.faststart,
+        shortest: cli.shortest,
+        keep_sar: cli.keep_sar,
+        hwaccel: hwaccel::selected(),
+        stall_timeout: cli
+            .stall_timeout
+            .map(|timeout| stall::scaled_timeout(timeout, config.res.to_wh())),
+        timeout: cli.timeout,
+        label: match cli.wants_label() {
+            true => Some(LabelOverlay::new(
+                config,
+                overlay::resolve_font(cli.label_font.as_deref())?,
+            )),
+            false => None,
         },
-        pb.clone(),
+        ..video::VideoProcessParams::new(output_path, config.clone())
+    })
+}
+
+/// `--skip-existing` で飛ばせる, 完成した出力の大きさ. 空のファイルや, 途中で止まって長さが足りないファイルは数えない.
+async fn existing_output(stat: &VideoStat, trim: &Trim, output_path: &str) -> Option<u64> {
+    let size = fs::metadata(output_path).ok()?.len();
+    if size == 0 {
+        return None;
+    }
+    let output = video::stat(output_path.to_string()).await.ok()?;
+    video::duration_matches(
+        trim.output_duration(stat.duration),
+        output.duration,
+        stat.video_stream.fps,
     )
-    .await?;
+    .then_some(size)
+}
 
-    let output_size =
-        file::calc_size(&output_path).context("出力動画のサイズの取得に失敗しました.")?;
-    let output_size_str = format_size(output_size, DECIMAL);
+/// 1 つのタスク (入力と設定の組) の出力先と進捗. [`process`] は準備, 再利用の確認, エンコード, 結果の記録の順に進める.
+struct Task<'a> {
+    cli: &'a Cli,
+    stat: &'a VideoStat,
+    config: &'a VideoConfig,
+    output_path: String,
+    log_path: Option<PathBuf>,
+    trim: Trim,
+    phases: PhaseLog,
+    pb: ProgressBar,
+}
 
-    pb.set_style(get_style(true));
-    pb.finish_with_message(format!(
-        "{}: {}",
-        style("✓ エンコード完了").green(),
-        style(output_size_str).green().bright()
-    ));
+impl<'a> Task<'a> {
+    /// 出力先のディレクトリを作り, 前回の実行のログを消す.
+    fn prepare(
+        cli: &'a Cli,
+        stat: &'a VideoStat,
+        config: &'a VideoConfig,
+        phases: PhaseLog,
+        pb: ProgressBar,
+    ) -> Result<Self> {
+        let output_path = output_path(cli, stat, config);
+        if cli.layout == OutputLayout::PerConfig {
+            if let Some(dir) = Path::new(&output_path).parent() {
+                fs::create_dir_all(dir)
+                    .with_context(|| format!("出力先を作成できません: {}", dir.display()))?;
+            }
+        }
+
+        let log_path = log_path(cli, stat, config);
+        // 失敗の表示は, ログがあればその場所を添える. 前回の実行のログは紛らわしいので消しておく
+        if let Some(path) = &log_path {
+            let _ = fs::remove_file(path);
+        }
+
+        Ok(Self {
+            cli,
+            stat,
+            config,
+            output_path,
+            log_path,
+            trim: cli.task_trim(stat.duration),
+            phases,
+            pb,
+        })
+    }
+
+    fn finish(&self, unit: &str, message: impl fmt::Display) {
+        self.pb.set_style(get_style(true, unit));
+        self.pb.finish_with_message(message.to_string());
+    }
+
+    /// `--skip-existing` で飛ばせる完成した出力があれば, スキップした結果を返す.
+    /// なければ, 既存のファイルを上書きしてよいかを確かめる.
+    async fn check_existing(&self) -> Result<Option<TaskOutput>> {
+        let cli = self.cli;
+        if cli.skip_existing {
+            if let Some(size) = existing_output(self.stat, &self.trim, &self.output_path).await {
+                self.finish(
+                    cli.progress_unit(),
+                    style(format!(
+                        "- スキップ: 出力がすでにあります ({})",
+                        format_size(size, config::size_format())
+                    ))
+                    .dim(),
+                );
+                return Ok(Some((
+                    TaskStatus::Existing,
+                    Some(OutcomeStats::new(
+                        self.output_path.clone(),
+                        size,
+                        Duration::ZERO,
+                    )),
+                )));
+            }
+        }
+        // --skip-existing で完成していないと判断したファイルは, 前回の実行の残りなので作り直す
+        if !cli.overwrite && !cli.skip_existing && cli.stream_to.is_none() {
+            file::check_not_exists(Path::new(&self.output_path))?;
+        }
+
+        Ok(None)
+    }
+
+    /// 同じ入力と引数の出力が索引にあれば, それを出力先に置いた結果を返す.
+    async fn reuse(
+        &self,
+        reuse: &Reuse,
+        key: &ReuseKey,
+        args: Vec<String>,
+    ) -> Result<Option<TaskOutput>> {
+        let phase = self.phases.start("再利用の確認");
+        let found = reuse_output(self.cli, reuse, key, &self.output_path).await?;
+        drop(phase);
+        let Some(entry) = found else {
+            return Ok(None);
+        };
+
+        if let Ok(path) = fs::canonicalize(&self.output_path) {
+            reuse.index.lock().unwrap().record(ReuseEntry {
+                output_path: path.to_string_lossy().into_owned(),
+                ..entry.clone()
+            });
+        }
+        let stats = OutcomeStats {
+            phases: self.phases.phases(),
+            ..OutcomeStats::new(self.output_path.clone(), entry.output_size, Duration::ZERO)
+        };
+        if self.cli.sidecars {
+            write_sidecar(self.cli, self.stat, self.config, args, &stats)?;
+        }
+        self.finish(
+            self.cli.progress_unit(),
+            format!(
+                "{}: {} {}",
+                style("✓ 再利用").green(),
+                style(format_size(entry.output_size, config::size_format()))
+                    .green()
+                    .bright(),
+                style(format!("← {}", entry.output_path)).dim()
+            ),
+        );
+
+        Ok(Some((TaskStatus::Reused, Some(stats))))
+    }
+
+    /// ffmpeg を実行する. `copy` の場合は元動画をエンコードせずにコピーする.
+    async fn run(
+        &self,
+        mut params: video::VideoProcessParams,
+        copy: bool,
+    ) -> (TaskStatus, Result<ProcessOutcome>, &'static str) {
+        params.log = self.log_path.clone().and_then(|path| {
+            EncodeLog::create(path.clone())
+                .inspect_err(|e| {
+                    verbosity::warn(format!(
+                        "ログを作成できませんでした: {}: {}",
+                        path.display(),
+                        e
+                    ))
+                })
+                .ok()
+        });
+
+        let mode = match copy {
+            true => TaskMode::Copy,
+            false => TaskMode::Encode,
+        };
+        let encode_phase = self.phases.start(match mode {
+            TaskMode::Copy => "コピー",
+            _ => "エンコード",
+        });
+        params.phases = encode_phase.nested();
+        let driver = ProgressDriver::new(self.stat, &params, mode);
+        self.pb.set_style(get_style(false, driver.unit()));
+        verbosity::info(format!("- 進捗の基準: {}: {}", driver, self.output_path));
+        let stat = self.stat.clone();
+        let pb = self.pb.clone();
+        let (status, outcome) = match (copy, self.cli.chunked) {
+            (true, _) => (TaskStatus::Copied, video::remux(stat, params, pb).await),
+            (false, Some(count)) => (
+                TaskStatus::Encoded,
+                chunk::process_chunked(stat, params, count as usize, self.cli.jobs(), pb).await,
+            ),
+            (false, None) => (TaskStatus::Encoded, video::process(stat, params, pb).await),
+        };
+        drop(encode_phase);
+
+        (status, outcome, driver.unit())
+    }
+
+    /// 出力の大きさや A/V 同期を調べ, サイドカーと再利用の索引に記録して, 結果を表示する.
+    async fn record(
+        &self,
+        status: TaskStatus,
+        outcome: ProcessOutcome,
+        unit: &str,
+        reuse: Option<(&Reuse, ReuseKey)>,
+    ) -> Result<TaskOutput> {
+        let (cli, stat, output_path) = (self.cli, self.stat, &self.output_path);
+        let output_size =
+            file::calc_size(output_path).context("出力動画のサイズの取得に失敗しました.")?;
+        let output_size_str = match cli.sample {
+            Some(_) => {
+                let full_trim = cli.trim();
+                let estimate = video::extrapolate_size(
+                    output_size,
+                    self.trim.output_duration(stat.duration),
+                    full_trim.output_duration(stat.duration),
+                    cli.sample_audio,
+                    stat.audio_streams
+                        .first()
+                        .filter(|_| self.config.has_audio)
+                        .and_then(|audio| video::output_audio_bitrate(audio, output_path)),
+                );
+                format!(
+                    "{} (推定: {})",
+                    format_size(output_size, config::size_format()),
+                    format_size(estimate, config::size_format())
+                )
+            }
+            None => format_size(output_size, config::size_format()),
+        };
+
+        let check_sync = (cli.sample.is_none() || cli.sample_audio)
+            && self.config.has_audio
+            && !stat.audio_streams.is_empty();
+        let av_drift_secs = check_sync
+            .then(|| {
+                let _phase = self.phases.start("A/V 同期の確認");
+                check_av_sync(stat, output_path)
+            })
+            .flatten()
+            .map(|m| m.signed_secs());
+        let hash = match reuse {
+            Some(_) => {
+                let _phase = self.phases.start("ハッシュ");
+                Some(integrity::sha256_file(Path::new(output_path), cli.hash_rate()).await)
+            }
+            None => None,
+        };
+        let stats = OutcomeStats {
+            av_drift_secs,
+            frames: outcome.frames,
+            phases: self.phases.phases(),
+            speed: (!outcome.elapsed.is_zero()).then(|| {
+                self.trim.output_duration(stat.duration).as_secs_f64()
+                    / outcome.elapsed.as_secs_f64()
+            }),
+            ..OutcomeStats::new(output_path.clone(), output_size, outcome.elapsed)
+        };
+        if cli.sidecars {
+            write_sidecar(cli, stat, self.config, outcome.args, &stats)?;
+        }
+        if let (Some((reuse, key)), Some(hash)) = (reuse, hash) {
+            // 索引に記録できなくても出力はできているので, 失敗は無視する
+            if let (Ok(output_hash), Ok(path)) = (hash, fs::canonicalize(output_path)) {
+                reuse.index.lock().unwrap().record(ReuseEntry {
+                    key,
+                    output_path: path.to_string_lossy().into_owned(),
+                    output_hash,
+                    output_size,
+                });
+            }
+        }
+
+        let label = match status {
+            TaskStatus::Copied => "✓ コピー完了",
+            _ => "✓ エンコード完了",
+        };
+        let speed = match stats.speed {
+            Some(speed) => format!(" {}", style(format!("平均 {:.1}x", speed)).dim()),
+            None => String::new(),
+        };
+        let note = match stats.frames.note() {
+            Some(note) => format!(" {}", style(note).yellow()),
+            None => String::new(),
+        };
+        self.finish(
+            unit,
+            format!(
+                "{}: {}{}{}",
+                style(label).green(),
+                style(output_size_str).green().bright(),
+                speed,
+                note
+            ),
+        );
+
+        Ok((status, Some(stats)))
+    }
+}
+
+async fn process(
+    stat: VideoStat,
+    config: VideoConfig,
+    cli: &Cli,
+    reuse: Option<&Reuse>,
+    cancel: CancelToken,
+    pause: PauseControl,
+    pb: ProgressBar,
+) -> Result<TaskOutput> {
+    let phases = PhaseLog::with_clock(Arc::new(pause.clone()));
+    let task = Task::prepare(cli, &stat, &config, phases, pb)?;
+    if let Some(existing) = task.check_existing().await? {
+        return Ok(existing);
+    }
+
+    let interrupt = cancel.clone();
+    // 上限で中止するのはこのタスクだけにする
+    let cancel = cancel.child();
+    let size_limit = cli.abort_if_larger.map(|factor| {
+        let limit = size_limit::source_limit(
+            stat.file_size,
+            stat.duration,
+            task.trim.output_duration(stat.duration),
+            factor,
+        );
+        SizeLimit::new(limit, cancel.clone())
+    });
+    let params = video::VideoProcessParams {
+        cancel,
+        pause,
+        size_limit: size_limit.clone(),
+        ..process_params(cli, &config, &task.output_path, task.trim.clone())?
+    };
+
+    let verdict = cli
+        .skip_if_better
+        .map(|mode| (mode, video::judge_source(&stat, &config)));
+    let optimal = matches!(verdict, Some((_, SourceVerdict::AlreadyOptimal)));
+    let args = video::command_args(&video::build_command(&stat, &params));
+    let reuse = reuse.filter(|_| !optimal).map(|reuse| {
+        let key = ReuseKey::new(
+            &reuse.input_hash,
+            &config,
+            &args,
+            (&stat.path, &task.output_path),
+            ffmpeg::detect().map(|b| b.version),
+        );
+        (reuse, key)
+    });
+    if let Some((reuse, key)) = &reuse {
+        if let Some(reused) = task.reuse(reuse, key, args).await? {
+            return Ok(reused);
+        }
+    }
+    if cli.stream_to.is_none() {
+        reuse::detach(Path::new(&task.output_path))
+            .with_context(|| format!("出力先を削除できません: {}", task.output_path))?;
+    }
+    if matches!(verdict, Some((SkipIfBetterMode::Skip, _))) && optimal {
+        task.finish(
+            cli.progress_unit(),
+            style("- スキップ: 元動画がすでに最適です").dim(),
+        );
+        return Ok((TaskStatus::Skipped, None));
+    }
+
+    let (status, outcome, unit) = task.run(params, optimal).await;
+    let outcome = match (outcome, size_limit.as_ref().and_then(SizeLimit::overrun)) {
+        (Err(_), Some(overrun)) => {
+            fs::remove_file(&task.output_path).ok();
+            task.finish(unit, style(format!("- {}", overrun)).yellow());
+            return Ok((TaskStatus::TooLarge, None));
+        }
+        // 中断した場合は, 途中までの出力を残さない
+        (Err(e), None) if interrupt.is_cancelled() && cli.stream_to.is_none() => {
+            fs::remove_file(&task.output_path).ok();
+            return Err(e);
+        }
+        (outcome, _) => outcome?,
+    };
+    if cli.stream_to.is_some() {
+        task.finish(cli.progress_unit(), style("✓ 配信完了").green());
+        return Ok((status, None));
+    }
+
+    task.record(status, outcome, unit, reuse).await
+}
+
+/// 設定ごとのディレクトリに書き出した出力を, 出力先からの相対パスで一覧にする.
+fn print_outputs(cli: &Cli, results: &[&Result<TaskOutput>]) {
+    let root = cli.out_dir();
+    let paths = results
+        .iter()
+        .filter_map(|r| r.as_ref().ok()?.1.as_ref())
+        .map(|stats| {
+            let path = Path::new(&stats.output_path);
+            path.strip_prefix(&root)
+                .unwrap_or(path)
+                .display()
+                .to_string()
+        })
+        .collect::<Vec<_>>();
+    if paths.is_empty() {
+        return;
+    }
+
+    println!("{}", style(format!("出力先: {}", root.display())).bold());
+    for path in paths {
+        println!("{}", style(format!("- {}", path)).dim());
+    }
+}
+
+fn write_sidecar(
+    cli: &Cli,
+    stat: &VideoStat,
+    config: &VideoConfig,
+    ffmpeg_args: Vec<String>,
+    stats: &OutcomeStats,
+) -> Result<()> {
+    let sidecar = Sidecar {
+        input_path: stat.path.clone(),
+        config: config.clone(),
+        ffmpeg_args,
+        ffmpeg_version: ffmpeg::detect().map(|b| b.version),
+        ffmpeg_enabled: ffmpeg::detect().map(|b| b.enabled).unwrap_or_default(),
+        outcome: Some(stats.clone()),
+        quality: cli
+            .quality_calibration
+            .iter()
+            .find(|q| q.codec == video::codec_name(config))
+            .cloned(),
+        options: cli.task_options(config),
+    };
+    report::write_sidecar(&stats.output_path, &sidecar)?;
 
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let input_path = "assets/2.mp4";
+/// 索引に一致する出力が記録したときのまま残っていれば, `output_path` に置いてその記録を返す.
+/// 残っていない記録は取り除く.
+async fn reuse_output(
+    cli: &Cli,
+    reuse: &Reuse,
+    key: &ReuseKey,
+    output_path: &str,
+) -> Result<Option<ReuseEntry>> {
+    loop {
+        let found = reuse.index.lock().unwrap().find(key).cloned();
+        let Some(entry) = found else {
+            return Ok(None);
+        };
+        if entry.is_intact(cli.hash_rate()).await {
+            reuse::link_or_copy(Path::new(&entry.output_path), Path::new(output_path))?;
+            return Ok(Some(entry));
+        }
+        reuse.index.lock().unwrap().remove(&entry.output_path);
+    }
+}
+
+/// 再利用の索引を開き, 入力のハッシュを求める. できない場合は警告を表示し, 再利用せずに続ける.
+async fn open_reuse(cli: &Cli, stat: &VideoStat) -> Option<Reuse> {
+    let warn = |e: anyhow::Error| {
+        verbosity::warn(format!("以前の出力を再利用できません: {:#}", e));
+    };
+    let mut index = ReuseIndex::open_default().map_err(warn).ok()?;
+    let path = Path::new(&stat.path);
+    let input_hash = match index.input_hash(path) {
+        Some(hash) => hash.to_string(),
+        None => {
+            let hash = integrity::sha256_file(path, cli.hash_rate())
+                .await
+                .map_err(warn)
+                .ok()?;
+            index.remember_input_hash(path, hash.clone());
+            hash
+        }
+    };
+
+    Some(Reuse {
+        index: Arc::new(Mutex::new(index)),
+        input_hash,
+    })
+}
+
+/// 出力の映像と音声の長さを調べ, 元動画よりずれが大きくなっていれば返す. 長さを調べられない場合は確認しない.
+fn check_av_sync(stat: &VideoStat, output_path: &str) -> Option<av_sync::Mismatch> {
+    let source = av_sync::probe(&stat.path, &stat.video_selector()).ok()?;
+    av_sync::probe(output_path, "V:0")
+        .ok()?
+        .regression_from(&source)
+}
+
+async fn process_with_fallback(
+    stat: VideoStat,
+    config: VideoConfig,
+    cli: &Cli,
+    reuse: Option<&Reuse>,
+    cancel: CancelToken,
+    pause: PauseControl,
+    pb: ProgressBar,
+) -> Result<TaskOutput> {
+    let err = match process(
+        stat.clone(),
+        config.clone(),
+        cli,
+        reuse,
+        cancel.clone(),
+        pause.clone(),
+        pb.clone(),
+    )
+    .await
+    {
+        Ok(output) => return Ok(output),
+        Err(e) => e,
+    };
+
+    let rejected = matches!(
+        err.downcast_ref::<ProcessErr>(),
+        Some(ProcessErr::EncoderRejected(..))
+    );
+    if !rejected {
+        return Err(err);
+    }
+    match (config.downgrade(), cli.auto_fallback) {
+        (Some((fallback, note)), true) => {
+            pb.reset();
+            process(stat, fallback, cli, reuse, cancel, pause, pb.clone())
+                .await
+                .map(|(_, stats)| (TaskStatus::Downgraded(note.clone()), stats))
+                .with_context(|| {
+                    format!(
+                        "--auto-fallback で近い設定 ({}) でも再試行しましたが失敗しました.",
+                        note
+                    )
+                })
+        }
+        (Some((_, note)), false) => Err(err.context(format!(
+            "--auto-fallback を指定すると近い設定 ({}) で再試行します.",
+            note
+        ))),
+        (None, true) => Err(err.context("--auto-fallback で再試行できる近い設定がありません.")),
+        (None, false) => Err(err),
+    }
+}
+
+/// `--retries` で再試行する失敗か. `check_up_scaling` などの確認の失敗は `ProcessErr` ではないので再試行しない.
+fn is_retryable(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<ProcessErr>()
+        .is_some_and(ProcessErr::is_retryable)
+}
+
+/// `attempt` 回目の試行が失敗した後の待ち時間.
+fn retry_delay(attempt: u32) -> Duration {
+    RETRY_DELAY * 2u32.pow(attempt.clamp(1, 8) - 1)
+}
 
+async fn prepare(cli: &Cli, input_path: &str) -> Result<VideoStat> {
     let stat = video::stat(input_path.to_string())
         .await
         .map_err(|e| anyhow!(e).context("元動画の情報取得に失敗しました."))?;
 
-    let res_iter = VideoRes::list169();
-    let fps_iter = (30..=30).step_by(30).collect::<Vec<_>>();
-    let crf_iter = (20..=40).step_by(20).collect::<Vec<_>>();
-    // let res_iter = (480..=1080)
-    //     .step_by(240)
-    //     .map(|h| VideoRes::from_wh_dynamic(None, Some(h), stat.video_stream.clone()))
-    //     .map(Result::unwrap)
-    //     .collect::<Vec<_>>();
+    if let (Some(start), Some(end)) = (cli.start, cli.end) {
+        if start >= end {
+            bail!("--end は --start より後の位置を指定してください.");
+        }
+    }
+    if cli.start.is_some_and(|start| start >= stat.duration) {
+        bail!("--start が元動画の長さを超えています.");
+    }
+    if cli.wants_label() {
+        overlay::resolve_font(cli.label_font.as_deref()).context("ラベルを描画できません.")?;
+    }
 
-    let iter_prod = iproduct!(res_iter, fps_iter, crf_iter);
+    if !cli.keep_alpha && stat.video_stream.has_alpha() {
+        verbosity::warn(format!(
+            "元動画にアルファチャンネルがありますが ({}), 出力では失われます. 保持するには --keep-alpha を指定してください.",
+            stat.video_stream.pix_fmt
+        ));
+    }
 
-    let progress = MultiProgress::new();
-    let spinner_style = get_style(false);
+    let (_, ext) = file::get_file_name(&stat.path);
+    if cli.keep_cover
+        && !stat.cover_stream_indices.is_empty()
+        && !video::supports_attached_pic(&ext)
+    {
+        verbosity::warn(format!(
+            ".{} はカバーアートに対応していないため, カバーアートは保持されません.",
+            ext
+        ));
+    }
 
-    let tasks = iter_prod.clone().map(|(res, fps, crf)| {
-        let pb = progress.add(ProgressBar::no_length());
-        pb.set_style(spinner_style.clone());
-        pb.set_prefix(format!("RES: {:?}, FPS: {}, CRF: {}", res, fps, crf));
-
-        tokio::spawn({
-            let value = stat.clone();
-            let config = VideoConfig {
-                crf,
-                fps,
-                res,
-                has_audio: true,
+    if !stat.audio_streams.is_empty() {
+        let mismatch = av_sync::probe(&stat.path, &stat.video_selector())
+            .ok()
+            .and_then(|durations| durations.mismatch());
+        if let Some(mismatch) = mismatch {
+            let hint = match cli.shortest {
+                true => "--shortest により短い方に合わせて出力します.",
+                false => "最後に映像が止まって見える場合は --shortest を指定してください.",
             };
+            verbosity::warn(format!("元動画の{}. {}", mismatch, hint));
+        }
+    }
 
-            async move {
-                process(value, config, pb.clone()).await.inspect_err(|e| {
-                    pb.finish_with_message(format!(
-                        "{}: {}",
-                        style("✗ エンコード失敗").red(),
-                        style(&e).red().bright()
-                    ));
-                })
+    Ok(stat)
+}
+
+/// シーンの切り替わりを元動画で一度だけ検出し, すべての出力から同じ時刻のフレームを書き出す.
+fn write_thumbnails(
+    cli: &Cli,
+    stat: &VideoStat,
+    trim: &Trim,
+    results: &[&Result<TaskOutput>],
+    count: usize,
+) -> Result<Vec<PathBuf>> {
+    let dir = cli.out_dir().join(thumbnail::THUMBNAIL_DIR);
+    let scenes = thumbnail::load_or_detect(&dir, &stat.path)?;
+    let timestamps = thumbnail::output_timestamps(&scenes, stat.start_time, trim, count);
+
+    let mut written = Vec::new();
+    for stats in results.iter().filter_map(|r| r.as_ref().ok()?.1.as_ref()) {
+        written.extend(thumbnail::extract(&dir, &stats.output_path, &timestamps)?);
+    }
+
+    Ok(written)
+}
+
+/// 元動画を調べる前に, 書き込み先のディレクトリに書き込めるかを確かめる.
+/// 分割エンコードの作業ディレクトリと所要時間の計測用のファイルは出力先の中に作られる.
+fn preflight(cli: &Cli) -> Result<()> {
+    let mut dirs = Vec::new();
+    if cli.stream_to.is_none() {
+        dirs.push((cli.out_dir(), "出力先 (--out-dir で変更できます)"));
+    }
+    if let Some(dir) = &cli.publish_dir {
+        dirs.push((dir.clone(), "公開先"));
+    }
+    if !cli.no_logs {
+        dirs.push((
+            cli.logs_dir(),
+            "ログの保存先 (--no-logs で保存しないようにできます)",
+        ));
+    }
+    if !cli.no_history {
+        if let Some(dir) = History::open_default()
+            .ok()
+            .and_then(|h| h.path().parent().map(PathBuf::from))
+        {
+            dirs.push((
+                dir,
+                "履歴の保存先 (--no-history で保存しないようにできます)",
+            ));
+        }
+    }
+
+    for (dir, label) in dirs {
+        file::check_writable(&dir).with_context(|| format!("{}に書き込めません.", label))?;
+    }
+
+    Ok(())
+}
+
+/// `--layout per-config` では設定ごとに出力先が分かれるので, 組み合わせが決まってからエンコードの前に確かめる.
+fn preflight_configs(cli: &Cli, configs: &[VideoConfig]) -> Result<()> {
+    if cli.stream_to.is_some() || cli.layout != OutputLayout::PerConfig {
+        return Ok(());
+    }
+    let dirs = configs
+        .iter()
+        .map(|config| cli.config_out_dir(config))
+        .collect::<BTreeSet<_>>();
+    for dir in dirs {
+        file::check_writable(&dir)
+            .context("設定ごとの出力先に書き込めません (--out-dir で変更できます).")?;
+    }
+
+    Ok(())
+}
+
+/// `--hwaccel` (または設定の `hwaccel`) から, エンコードに使うハードウェアを決める.
+/// 指定したエンコーダーが ffmpeg のビルドになければ, すべてのタスクが同じエラーで失敗する前に起動時に止める.
+fn select_hwaccel(cli: &Cli) -> Result<Option<HwAccel>> {
+    let setting = &config::current().hwaccel;
+    let mode = match &setting.value {
+        Some(value) => value
+            .parse::<HwAccel>()
+            .map_err(|e| anyhow!("{} の hwaccel が正しくありません: {}", setting.origin, e))?,
+        None => HwAccel::None,
+    };
+    let mut codecs = cli.codec_list();
+    if codecs.is_empty() {
+        codecs.push(VideoCodec::H264);
+    }
+    let selected = hwaccel::resolve(mode, &codecs, ffmpeg::encoders())?;
+    match (mode, selected) {
+        (_, Some(hw)) => verbosity::info(format!("ハードウェアのエンコーダー ({}) を使います", hw)),
+        (HwAccel::Auto, None) => verbosity::warn(
+            "使えるハードウェアのエンコーダーがないため, ソフトウェアでエンコードします",
+        ),
+        _ => {}
+    }
+
+    Ok(selected)
+}
+
+fn check_compat(cli: &Cli, stat: &VideoStat, configs: &[VideoConfig]) -> Result<()> {
+    if cli.no_compat_checks {
+        return Ok(());
+    }
+
+    let container = video::output_container(cli.stream_to.as_deref().unwrap_or(&stat.path));
+    let mut rejected = Vec::new();
+    for config in configs {
+        for rule in video::check_compat(&container, video::codec_name(config), config) {
+            let line = format!(
+                "RES: {:?}, FPS: {}, CRF: {} - {}",
+                config.res, config.fps, config.crf, rule.text
+            );
+            match rule.severity {
+                CompatSeverity::Warn => verbosity::warn(line),
+                CompatSeverity::Reject => rejected.push(line),
             }
-        })
+        }
+    }
+
+    if !rejected.is_empty() {
+        bail!(
+            "出力形式と互換性のない設定があります (--no-compat-checks で無視できます):\n{}",
+            rejected.join("\n")
+        );
+    }
+
+    Ok(())
+}
+
+/// 入力から選んだサンプルをエンコードして所要時間を計測する. サンプルの位置は入力の内容 (または `--sample-seed`) から決まるので,
+/// 同じ入力では毎回同じ位置を使う.
+async fn calibrate(cli: &Cli, stat: &VideoStat, config: &VideoConfig) -> Result<Calibration> {
+    let (_, ext) = file::get_file_name(&stat.path);
+    let output_path = cli
+        .out_root()
+        .join(format!(".vvcnv-calibration.{}", ext))
+        .to_string_lossy()
+        .into_owned();
+    let seed = match cli.sample_seed {
+        Some(seed) => seed,
+        None => sampling::seed_from_file(&stat.path)?,
+    };
+    let spec = cli.sample_windows.unwrap_or(WindowSpec {
+        count: 1,
+        length: estimate::CALIBRATION_SAMPLE,
+    });
+    let windows = sampling::select_windows(stat.duration, seed, spec, sampling::DEFAULT_MARGIN);
+
+    let pb = ProgressBar::new_spinner();
+    let mut samples = Vec::new();
+    for (i, window) in windows.iter().enumerate() {
+        pb.set_message(format!("所要時間を計測中... ({}/{})", i + 1, windows.len()));
+        let trim = Trim {
+            start: Some(window.start),
+            end: Some(window.end),
+            ..Trim::default()
+        };
+        let params = VideoProcessParams {
+            trim: trim.clone(),
+            drop_audio: true,
+            ..VideoProcessParams::new(output_path.clone(), config.clone())
+        };
+        let outcome = video::process(stat.clone(), params, pb.clone()).await;
+        fs::remove_file(&output_path).ok();
+        let outcome = outcome.context("所要時間の計測に失敗しました.")?;
+        samples.push(estimate::sample(stat, config, &trim, outcome.elapsed));
+    }
+    pb.finish_and_clear();
+
+    Calibration::from_samples(&samples, 1).ok_or_else(|| anyhow!("所要時間の計測に失敗しました."))
+}
+
+/// コーデックごとに, 最初の組み合わせの設定でサンプルを `quality::LADDER` の CRF でエンコードして画質を計測する.
+/// 同じ入力と設定で計測した結果が出力先に残っていれば使い回す. `--dry-run` では計測せず, 残っている結果だけを使う.
+async fn calibrate_quality(
+    cli: &Cli,
+    stat: &VideoStat,
+    configs: &[VideoConfig],
+) -> Result<Vec<QualityCalibration>> {
+    if cli.quality_metric == QualityMetric::Vmaf
+        && !ffmpeg::detect().is_some_and(|b| b.is_enabled("libvmaf"))
+    {
+        bail!("--quality-metric vmaf には libvmaf を有効にした ffmpeg が必要です. --quality-metric ssim を使ってください.");
+    }
+    let dir = cli.out_dir();
+    let targets = configs
+        .iter()
+        .filter(|c| c.codec.as_ref().is_none_or(VideoCodec::uses_crf))
+        .unique_by(|c| video::codec_name(c).to_string())
+        .collect::<Vec<_>>();
+
+    let mut calibrations = Vec::new();
+    for config in targets {
+        let codec = video::codec_name(config).to_string();
+        let calibration = match quality::load_cached(&dir, &stat.path, config, cli.quality_metric) {
+            Some(cached) => cached,
+            None if cli.dry_run => {
+                println!(
+                    "{}",
+                    style(format!(
+                        "画質の計測 ({}): --dry-run のため計測しません",
+                        codec
+                    ))
+                    .dim()
+                );
+                continue;
+            }
+            None => {
+                let calibration = measure_quality(cli, stat, config).await?;
+                if let Err(e) = quality::save_cached(&dir, &stat.path, config, &calibration) {
+                    verbosity::warn(format!("{:#}", e));
+                }
+                calibration
+            }
+        };
+        let calibration = match cli.quality {
+            Some(target) => {
+                let max_crf = config.codec.as_ref().map_or(Some(51), VideoCodec::max_crf);
+                calibration.choose(target, max_crf.unwrap_or(51))?
+            }
+            None => calibration,
+        };
+        let chosen = match (calibration.target, calibration.crf) {
+            (Some(target), Some(crf)) => format!(" → 画質 {} の CRF: {}", target, crf),
+            _ => String::new(),
+        };
+        println!(
+            "{}",
+            style(format!(
+                "画質の計測 ({}, {}): {}{}",
+                codec,
+                calibration.metric,
+                calibration.points_text(),
+                chosen
+            ))
+            .dim()
+        );
+        calibrations.push(calibration);
+    }
+
+    Ok(calibrations)
+}
+
+async fn measure_quality(
+    cli: &Cli,
+    stat: &VideoStat,
+    config: &VideoConfig,
+) -> Result<QualityCalibration> {
+    let (_, ext) = file::get_file_name(&stat.path);
+    let output_path = cli
+        .out_root()
+        .join(format!(".vvcnv-quality.{}", ext))
+        .to_string_lossy()
+        .into_owned();
+    let seed = match cli.sample_seed {
+        Some(seed) => seed,
+        None => sampling::seed_from_file(&stat.path)?,
+    };
+    let spec = cli.sample_windows.unwrap_or(WindowSpec {
+        count: 1,
+        length: quality::SAMPLE_LENGTH,
     });
+    let windows = sampling::select_windows(stat.duration, seed, spec, sampling::DEFAULT_MARGIN);
+    if windows.is_empty() {
+        bail!("元動画が短すぎるため画質を計測できません.");
+    }
+
+    let pb = ProgressBar::new_spinner();
+    let total = quality::LADDER.len() * windows.len();
+    let mut points = Vec::new();
+    for (i, crf) in quality::LADDER.into_iter().enumerate() {
+        // サンプルが複数ある場合は, 同じ CRF の画質の平均を 1 つの点にする
+        let mut scores = Vec::new();
+        for (j, window) in windows.iter().enumerate() {
+            pb.set_message(format!(
+                "画質を計測中... ({}/{})",
+                i * windows.len() + j + 1,
+                total
+            ));
+            let params = VideoProcessParams {
+                trim: Trim {
+                    start: Some(window.start),
+                    end: Some(window.end),
+                    ..Trim::default()
+                },
+                drop_audio: true,
+                ..VideoProcessParams::new(
+                    output_path.clone(),
+                    VideoConfig {
+                        crf,
+                        ..config.clone()
+                    },
+                )
+            };
+            let score = video::process(stat.clone(), params, pb.clone())
+                .await
+                .and_then(|_| {
+                    quality::measure(
+                        &output_path,
+                        &stat.path,
+                        window,
+                        config.fps,
+                        cli.quality_metric,
+                    )
+                });
+            fs::remove_file(&output_path).ok();
+            scores.push(score.context("画質の計測に失敗しました.")?);
+        }
+        let score = scores.iter().sum::<f64>() / scores.len() as f64;
+        points.push(CurvePoint { crf, score });
+    }
+    pb.finish_and_clear();
+
+    Ok(QualityCalibration {
+        codec: video::codec_name(config).to_string(),
+        metric: cli.quality_metric,
+        points,
+        target: None,
+        crf: None,
+    })
+}
+
+fn predict_time(
+    stat: &VideoStat,
+    configs: &[VideoConfig],
+    trim: &Trim,
+    calibration: Option<Calibration>,
+) -> Option<Duration> {
+    calibration.map(|calibration| {
+        let tasks = configs
+            .iter()
+            .map(|config| {
+                let pixels = estimate::encoded_pixels(stat, config, trim);
+                estimate::predict_task(pixels, estimate::cost_factor(config), &calibration)
+            })
+            .collect::<Vec<_>>();
+        estimate::predict_total(&tasks, calibration.parallelism)
+    })
+}
+
+/// `--dry-run` で, 設定ごとの出力先と ffmpeg の引数を表示する. 拡大になる設定はスキップとして理由を示す.
+fn print_dry_run(cli: &Cli, stat: &VideoStat, configs: &[VideoConfig]) -> Result<()> {
+    println!();
+    println!("{}", style(stat.header()).bold());
+    for config in configs {
+        let output_path = output_path(cli, stat, config);
+        let params = process_params(cli, config, &output_path, cli.task_trim(stat.duration))?;
+        println!("{}", style(task_prefix(config)).bold());
+        let verdict = cli
+            .skip_if_better
+            .map(|mode| (mode, video::judge_source(stat, config)));
+        let args = match verdict {
+            Some((SkipIfBetterMode::Skip, SourceVerdict::AlreadyOptimal)) => {
+                println!("{}", style("  - スキップ: 元動画がすでに最適です").dim());
+                continue;
+            }
+            Some((SkipIfBetterMode::Copy, SourceVerdict::AlreadyOptimal)) => Ok(
+                video::command_args(&video::build_remux_command(stat, &params)),
+            ),
+            _ => video::encode_args(stat, &params),
+        };
+        match args {
+            Ok(args) => {
+                println!("{}", style(format!("  出力先: {}", output_path)).dim());
+                let line = args.iter().map(|a| text::shell_word(a)).join(" ");
+                println!("  {} {}", ffmpeg::ffmpeg_path().display(), line);
+                if cli.chunked.is_some() {
+                    println!(
+                        "{}",
+                        style("  分割エンコードでは, 分割ごとに範囲を変えてこの引数で実行します")
+                            .dim()
+                    );
+                }
+            }
+            Err(e) => println!("{}", style(format!("  - スキップ: {:#}", e)).yellow()),
+        }
+    }
+    println!();
+    println!(
+        "{}",
+        style("--dry-run のため, エンコードせずに終了します").dim()
+    );
+
+    Ok(())
+}
+
+fn print_plan(
+    cli: &Cli,
+    stat: &VideoStat,
+    configs: &[VideoConfig],
+    sources: &[ConfigSource],
+    applied: Option<&(PathBuf, Vec<String>)>,
+    predicted: Option<Duration>,
+    concurrency: &Concurrency,
+) {
+    match ffmpeg::detect() {
+        Some(build) => println!("{}", style(format!("ffmpeg: {}", build)).dim()),
+        None => println!("{}", style("ffmpeg: バージョン不明").dim()),
+    }
+    if let Some((path, changes)) = applied {
+        let note = match changes.is_empty() {
+            true => " (変更なし)",
+            false => "",
+        };
+        println!(
+            "{}",
+            style(format!("上書き設定: {}{}", path.display(), note)).yellow()
+        );
+        for change in changes {
+            println!("{}", style(format!("  {}", change)).yellow());
+        }
+    }
+    println!(
+        "{}",
+        style(format!("組み合わせ: {} 個", configs.len())).bold()
+    );
+    if sources.iter().any(|source| *source != ConfigSource::Matrix) {
+        for (config, source) in zip(configs, sources) {
+            println!(
+                "{}",
+                style(format!("  - {} ({})", task_prefix(config), source)).dim()
+            );
+        }
+    }
+    let weights = match concurrency {
+        Concurrency::Weighted { .. } => format!(
+            ". 重み: {}",
+            concurrency
+                .weights(configs.iter().map(|c| &c.res))
+                .iter()
+                .map(|(res, weight)| format!("{} = {}", res, weight))
+                .join(", ")
+        ),
+        Concurrency::Fixed { .. } => String::new(),
+    };
+    println!(
+        "{}",
+        style(format!("同時実行: {}{}", concurrency, weights)).dim()
+    );
+    let chosen_crf = cli.quality_calibration.iter().any(|q| q.crf.is_some());
+    if let Some(config) = configs
+        .first()
+        .filter(|_| cli.crf_list().is_empty() && !chosen_crf)
+    {
+        println!(
+            "{}",
+            style(format!(
+                "CRF: {} ({} の既定値. --crf で変更できます)",
+                video::default_crf(config),
+                video::codec_name(config)
+            ))
+            .dim()
+        );
+    }
+    if let Some((flag, params)) = configs.first().and_then(codec_params::to_args) {
+        println!(
+            "{}",
+            style(format!("エンコーダーのパラメーター: {} {}", flag, params)).dim()
+        );
+    }
+    if cli.stream_to.is_none() {
+        let trim = cli.task_trim(stat.duration);
+        let size = configs
+            .iter()
+            .map(|config| video::estimate_output_size(stat, config, &trim))
+            .sum::<u64>();
+        println!(
+            "{}",
+            style(format!(
+                "推定出力サイズ: 約 {}",
+                format_size(size, config::size_format())
+            ))
+            .bold()
+        );
+    }
+    if configs.iter().any(|c| c.fps_mode == Some(FpsMode::Mci)) {
+        verbosity::warn(format!(
+            "--fps-mode mci は動きを補間するため, 通常の約 {:.0} 倍の時間がかかります (予想所要時間に含めています)",
+            FpsMode::Mci.cost_factor()
+        ));
+    }
+    match predicted {
+        Some(predicted) => println!(
+            "{}",
+            style(format!(
+                "予想所要時間: 約 {} (±{}%)",
+                time::format_clock(predicted),
+                estimate::CONFIDENCE_PERCENT
+            ))
+            .bold()
+        ),
+        None => println!(
+            "{}",
+            style("予想所要時間: 不明 (--estimate-time で計測できます)").dim()
+        ),
+    }
+}
+
+async fn rerun(cli: Arc<Cli>, args: &RerunArgs) -> Result<()> {
+    let sidecar = report::read_sidecar(&args.sidecar)?;
+    let current = ffmpeg::detect().map(|b| b.version);
+    if let Some(warning) =
+        ffmpeg::version_mismatch(sidecar.ffmpeg_version.as_deref(), current.as_deref())
+    {
+        verbosity::warn(warning);
+    }
+    // 出力の内容と置き場所は, 記録したときの指定に戻してから上書きを適用する
+    let cli = Arc::new(cli.with_task_options(&sidecar.options)?);
+    let config = VideoConfig {
+        audio_missing: sidecar.options.audio_missing,
+        ..args.apply(sidecar.config)
+    };
+    let stat = prepare(&cli, &sidecar.input_path).await?;
+    check_compat(&cli, &stat, std::slice::from_ref(&config))?;
+
+    let pb = ProgressBar::no_length();
+    pb.set_style(get_style(false, cli.progress_unit()));
+    pb.set_prefix(task_prefix(&config));
+
+    process_with_fallback(
+        stat,
+        config,
+        &cli,
+        None,
+        CancelToken::new(),
+        PauseControl::new(),
+        pb.clone(),
+    )
+    .await
+    .inspect_err(|e| pb.finish_with_message(failure_message(e)))?;
+
+    Ok(())
+}
+
+/// 出力先にファイルがあったため上書きせずに中止したかどうか. エンコードの失敗とは分けて表示する.
+fn is_refused(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<file::OutputExists>().is_some()
+}
+
+/// 失敗したタスクの進捗バーに表示するメッセージ.
+fn failure_message(e: &anyhow::Error) -> String {
+    match is_refused(e) {
+        true => format!(
+            "{}: {}",
+            style("✗ 上書きしません").yellow(),
+            style(e).yellow()
+        ),
+        false => format!(
+            "{}: {}",
+            style("✗ エンコード失敗").red(),
+            style(e).red().bright()
+        ),
+    }
+}
+
+/// 入力の見出しに表示する, 全タスクの結果のまとめ.
+fn group_summary(results: &[&Result<TaskOutput>]) -> String {
+    let done = results
+        .iter()
+        .filter(|r| matches!(r, Ok((status, _)) if status.is_success()))
+        .count();
+    let failed = results.iter().filter(|r| r.is_err()).count();
+    let size = results
+        .iter()
+        .filter_map(|r| r.as_ref().ok()?.1.as_ref())
+        .map(|stats| stats.output_size)
+        .sum::<u64>();
+    let size = format_size(size, config::size_format());
+    match failed {
+        0 => format!(
+            "{}",
+            style(format!("✓ {} 個完了, 合計 {}", done, size)).green()
+        ),
+        n => format!(
+            "{}",
+            style(format!("✗ {} 個完了, {} 個失敗, 合計 {}", done, n, size)).red()
+        ),
+    }
+}
+
+fn status_label(status: &TaskStatus) -> String {
+    match status {
+        TaskStatus::Encoded => "エンコード完了".to_string(),
+        TaskStatus::Downgraded(note) => format!("フォールバック ({})", note),
+        TaskStatus::Copied => "コピー".to_string(),
+        TaskStatus::Reused => "再利用".to_string(),
+        TaskStatus::Existing => "既存のためスキップ".to_string(),
+        TaskStatus::Skipped => "スキップ".to_string(),
+        TaskStatus::OutOfTime => "時間制限により未完了".to_string(),
+        TaskStatus::TooLarge => "中止: 元より大きくなるため".to_string(),
+        TaskStatus::Refused => "上書きせず中止".to_string(),
+        TaskStatus::Interrupted => "中断".to_string(),
+        TaskStatus::Failed => "エンコード失敗".to_string(),
+    }
+}
+
+fn print_session(entry: &HistoryEntry) {
+    let HistoryEntry {
+        id,
+        started_at,
+        report,
+    } = entry;
+
+    println!(
+        "{}",
+        style(format!("#{} - {}", id, time::format_unix(*started_at))).bold()
+    );
+    println!(
+        "{}",
+        style(format!("入力: {}", report.inputs.join(", "))).dim()
+    );
+    if let Some(build) = &report.ffmpeg {
+        println!("{}", style(format!("ffmpeg: {}", build)).dim());
+    }
+    for task in &report.tasks {
+        let (name, _) = file::get_file_name(&task.input_path);
+        let (size, elapsed) = match &task.outcome {
+            Some(o) if !o.frames.is_clean() => (
+                format_size(o.output_size, config::size_format()),
+                format!(
+                    "{:.1}s | 複製 {} / 破棄 {} フレーム",
+                    o.elapsed_secs, o.frames.dup, o.frames.drop
+                ),
+            ),
+            Some(o) => (
+                format_size(o.output_size, config::size_format()),
+                format!("{:.1}s", o.elapsed_secs),
+            ),
+            None => ("-".to_string(), "-".to_string()),
+        };
+        let line = format!(
+            "- {} - {} - RES: {:?}, FPS: {}, CRF: {} | {} | {}",
+            status_label(&task.status),
+            name,
+            task.config.res,
+            task.config.fps,
+            task.config.crf,
+            size,
+            elapsed
+        );
+        match &task.status {
+            s if s.is_success() => println!("{}", style(line).green()),
+            TaskStatus::Failed => println!("{}", style(line).red()),
+            _ => println!("{}", style(line).dim()),
+        }
+        if let Some(e) = &task.error {
+            println!("    {}", style(e).red().bright());
+        }
+        let breakdown = task
+            .outcome
+            .as_ref()
+            .and_then(|o| phases::breakdown(&phases::totals(&o.phases)));
+        if let Some(breakdown) = breakdown {
+            println!("    {}", style(breakdown).dim());
+        }
+    }
+
+    let totals = &report.totals;
+    println!(
+        "{}",
+        style(format!(
+            "{}/{} 成功, {} 失敗, {} スキップ | 出力合計: {} | {:.1}s",
+            totals.succeeded,
+            totals.tasks,
+            totals.failed,
+            totals.skipped,
+            format_size(totals.output_size, config::size_format()),
+            totals.elapsed_secs
+        ))
+        .dim()
+    );
+}
+
+/// `旧 → 新 (+12.3%)` の形. 割合を求められない場合は省く.
+fn change_text(old: String, new: String, percent: Option<f64>) -> String {
+    match percent {
+        Some(percent) => format!("{} → {} ({:+.1}%)", old, new, percent),
+        None => format!("{} → {}", old, new),
+    }
+}
+
+fn task_label(task: &TaskReport) -> String {
+    let (name, _) = file::get_file_name(&task.input_path);
+    format!(
+        "{} - RES: {:?}, FPS: {}, CRF: {}",
+        name, task.config.res, task.config.fps, task.config.crf
+    )
+}
+
+fn print_report_diff(old: &SessionReport, new: &SessionReport, diff: &ReportDiff) {
+    if !diff.ignored.is_empty() {
+        println!(
+            "{}",
+            style(format!(
+                "対応付けで無視した項目: {}",
+                diff.ignored.join(", ")
+            ))
+            .dim()
+        );
+    }
+
+    let size = |bytes: u64| format_size(bytes, config::size_format());
+    for task in &diff.matched {
+        let mut parts = vec![task_label(task.old)];
+        if task.old.status != task.new.status {
+            parts.push(format!(
+                "{} → {}",
+                status_label(&task.old.status),
+                status_label(&task.new.status)
+            ));
+        }
+        if let (Some(o), Some(n)) = (&task.old.outcome, &task.new.outcome) {
+            parts.push(format!(
+                "サイズ: {}",
+                change_text(
+                    size(o.output_size),
+                    size(n.output_size),
+                    report_diff::percent(o.output_size as f64, n.output_size as f64)
+                )
+            ));
+            parts.push(format!(
+                "時間: {}",
+                change_text(
+                    format!("{:.1}s", o.elapsed_secs),
+                    format!("{:.1}s", n.elapsed_secs),
+                    report_diff::percent(o.elapsed_secs, n.elapsed_secs)
+                )
+            ));
+        }
+        if let Some(drops) = task.drop_delta().filter(|d| *d != 0) {
+            parts.push(format!("破棄 {:+} フレーム", drops));
+        }
+        let line = format!("- {}", parts.join(" | "));
+        match (task.is_regression(), task.axes()) {
+            (true, _) => println!("{}", style(line).red()),
+            (false, Some(axes)) if axes.contains(&ab::Axis::Better) => {
+                println!("{}", style(line).green())
+            }
+            _ => println!("{}", style(line).dim()),
+        }
+    }
+
+    for (title, tasks) in [
+        ("古いレポートにだけあるタスク", &diff.only_old),
+        ("新しいレポートにだけあるタスク", &diff.only_new),
+    ] {
+        if tasks.is_empty() {
+            continue;
+        }
+        println!("{}", style(format!("{}:", title)).bold());
+        for task in tasks.iter() {
+            println!(
+                "{}",
+                style(format!(
+                    "- {} | {}",
+                    task_label(task),
+                    status_label(&task.status)
+                ))
+                .dim()
+            );
+        }
+    }
+
+    let (o, n) = (&old.totals, &new.totals);
+    let regressions = diff.matched.iter().filter(|t| t.is_regression()).count();
+    let line = format!(
+        "合計: 成功 {} → {} | 出力合計: {} | 時間: {} | 悪化 {} 件",
+        o.succeeded,
+        n.succeeded,
+        change_text(
+            size(o.output_size),
+            size(n.output_size),
+            report_diff::percent(o.output_size as f64, n.output_size as f64)
+        ),
+        change_text(
+            format!("{:.1}s", o.elapsed_secs),
+            format!("{:.1}s", n.elapsed_secs),
+            report_diff::percent(o.elapsed_secs, n.elapsed_secs)
+        ),
+        regressions
+    );
+    match regressions {
+        0 => println!("{}", style(line).bold()),
+        _ => println!("{}", style(line).red().bold()),
+    }
+}
+
+fn report(args: &ReportArgs) -> Result<()> {
+    match &args.action {
+        ReportAction::Diff { old, new, ignore } => {
+            let old = report::read_report(old)?;
+            let new = report::read_report(new)?;
+            let ignored = match ignore.is_empty() {
+                true => report_diff::changed_fields(&old, &new),
+                false => ignore.clone(),
+            };
+            print_report_diff(&old, &new, &report_diff::diff(&old, &new, ignored));
+        }
+    }
+
+    Ok(())
+}
+
+fn history(args: &HistoryArgs) -> Result<()> {
+    let history = History::open_default()?;
+
+    match &args.action {
+        None => {
+            let entries = history.load()?;
+            if entries.is_empty() {
+                println!("{}", style("履歴はまだありません.").dim());
+            }
+            for HistoryEntry {
+                id,
+                started_at,
+                report,
+            } in entries
+            {
+                println!(
+                    "#{:<4} {}  {}/{} 成功  {}  {}",
+                    id,
+                    time::format_unix(started_at),
+                    report.totals.succeeded,
+                    report.totals.tasks,
+                    format_size(report.totals.output_size, config::size_format()),
+                    style(report.inputs.join(", ")).dim()
+                );
+            }
+        }
+        Some(HistoryAction::Show { id }) => {
+            let entry = history
+                .find(*id)?
+                .ok_or_else(|| anyhow!("履歴 #{} が見つかりません.", id))?;
+            print_session(&entry);
+        }
+        Some(HistoryAction::Prune { keep }) => {
+            let removed = history.prune(*keep)?;
+            println!(
+                "{}",
+                style(format!("{} 件の履歴を削除しました.", removed)).dim()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn migrate(args: &MigrateArgs) -> Result<()> {
+    let mut files = fs::read_dir(&args.dir)
+        .with_context(|| format!("ディレクトリを開けません: {}", args.dir.display()))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+        .map(|entry| entry.path())
+        .collect::<Vec<_>>();
+    files.sort();
+
+    let plan = naming::plan_migration(&files, &naming::CURRENT_SCHEME, naming::LEGACY_SCHEMES);
+    let mut renamed = 0;
+    let mut manual = 0;
+    for migration in &plan {
+        match migration {
+            Migration::Rename { from, to, version } => {
+                let line = format!(
+                    "{} → {} (v{})",
+                    from.display(),
+                    to.file_name().unwrap_or_default().to_string_lossy(),
+                    version
+                );
+                if args.apply {
+                    naming::apply_rename(from, to)?;
+                }
+                println!("{}", style(line).green());
+                renamed += 1;
+            }
+            Migration::Manual { path, reason } => {
+                println!(
+                    "{}",
+                    style(format!("? {} - {}", path.display(), reason)).yellow()
+                );
+                manual += 1;
+            }
+        }
+    }
+
+    let summary = match args.apply {
+        true => format!("{} 個のファイルの名前を変更しました", renamed),
+        false => format!(
+            "{} 個のファイルの名前を変更できます (--apply で実行します)",
+            renamed
+        ),
+    };
+    println!("{}", style(summary).dim());
+    if manual > 0 {
+        println!(
+            "{}",
+            style(format!(
+                "{} 個のファイルは自動で判別できないため, 手動で確認してください",
+                manual
+            ))
+            .yellow()
+        );
+    }
 
+    Ok(())
+}
+
+fn init() -> Result<()> {
+    let dir = std::env::current_dir().context("カレントディレクトリを取得できません.")?;
+    let (workspace, created) = Workspace::init(&dir)?;
+    let line = match created {
+        true => format!(
+            "ワークスペースを作成しました: {}",
+            workspace.root().display()
+        ),
+        false => format!(
+            "既にワークスペースです. 足りないディレクトリだけを作成しました: {}",
+            workspace.root().display()
+        ),
+    };
+    println!("{}", style(line).green());
+
+    Ok(())
+}
+
+fn show_config(origin: bool) -> Result<()> {
+    let config = config::current();
+    for (key, value, from) in config.entries() {
+        match origin {
+            true => println!(
+                "{} = {}  {}",
+                key,
+                value,
+                style(format!("# {}", from)).dim()
+            ),
+            false => println!("{} = {}", key, value),
+        }
+    }
+    if origin {
+        let global =
+            config::global_path().map_or("なし".to_string(), |path| path.display().to_string());
+        println!("{}", style(format!("グローバル設定: {}", global)).dim());
+    }
+
+    Ok(())
+}
+
+/// 同じサンプルを A と B で交互に `repeat` 回ずつエンコードする. 交互にするのは, マシンの発熱などによる速度の変化を両方に均等にかけるため.
+async fn ab(args: &AbArgs) -> Result<()> {
+    let stat = video::stat(args.input.clone())
+        .await
+        .map_err(|e| anyhow!(e).context("元動画の情報取得に失敗しました."))?;
+    let seed = match args.sample_seed {
+        Some(seed) => seed,
+        None => sampling::seed_from_file(&stat.path)?,
+    };
+    let spec = WindowSpec {
+        count: 1,
+        length: args.length,
+    };
+    let window = sampling::select_windows(stat.duration, seed, spec, sampling::DEFAULT_MARGIN)
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("元動画が短すぎるため, サンプルを選べません."))?;
+    let trim = Trim {
+        start: Some(window.start),
+        end: Some(window.end),
+        ..Trim::default()
+    };
+    let base = VideoConfig {
+        res: VideoRes::from_wh(stat.video_stream.width, stat.video_stream.height),
+        fps: stat.video_stream.fps.round() as u32,
+        has_audio: false,
+        ..Default::default()
+    };
+    let variants = [("A", &args.a), ("B", &args.b)];
     println!(
         "{}",
         style(format!(
-            "元動画のサイズ: {}",
-            format_size(stat.file_size, DECIMAL)
+            "サンプル: {} - {} (シード: {})",
+            time::format_timestamp(window.start),
+            time::format_timestamp(window.end),
+            seed
         ))
         .dim()
     );
 
-    let binding = futures::future::join_all(tasks).await;
-    let results = binding
+    let dir = workspace::out_dir();
+    file::check_writable(&dir).context("出力先に書き込めません.")?;
+    let (_, ext) = file::get_file_name(&stat.path);
+    let pb = ProgressBar::new_spinner();
+    let mut times = [Vec::new(), Vec::new()];
+    let mut sizes = [Vec::new(), Vec::new()];
+    for round in 0..args.repeat {
+        for (i, (name, variant)) in variants.iter().enumerate() {
+            pb.set_message(format!(
+                "{} をエンコード中... ({}/{})",
+                name,
+                round + 1,
+                args.repeat
+            ));
+            let output_path = dir
+                .join(format!(".vvcnv-ab-{}.{}", name.to_lowercase(), ext))
+                .to_string_lossy()
+                .into_owned();
+            let params = VideoProcessParams {
+                trim: trim.clone(),
+                drop_audio: true,
+                ..VideoProcessParams::new(output_path.clone(), variant.apply(&base))
+            };
+            let outcome = video::process(stat.clone(), params, pb.clone()).await;
+            let size = file::calc_size(&output_path);
+            fs::remove_file(&output_path).ok();
+            let outcome =
+                outcome.with_context(|| format!("{} のエンコードに失敗しました.", name))?;
+            times[i].push(outcome.elapsed.as_secs_f64());
+            sizes[i].push(size.context("出力動画のサイズの取得に失敗しました.")?);
+        }
+    }
+    pb.finish_and_clear();
+
+    let stats = times
+        .each_ref()
+        .map(|t| ab::Stats::from_samples(t).expect("repeat は 1 以上"));
+    for (i, (name, variant)) in variants.iter().enumerate() {
+        println!("{}: {}", style(name).bold(), variant.label);
+        println!(
+            "  所要時間: {:.2}s ± {:.2}s ({} 回) | サイズ: {}",
+            stats[i].mean,
+            stats[i].stddev,
+            stats[i].n,
+            format_size(sizes[i][0], config::size_format())
+        );
+        if sizes[i].iter().any(|s| *s != sizes[i][0]) {
+            println!(
+                "{}",
+                style("  出力サイズが実行ごとに異なります. 1 回目の値で比べます.").yellow()
+            );
+        }
+    }
+
+    let axes = [
+        ("所要時間", ab::compare_times(&stats[0], &stats[1])),
+        ("サイズ", ab::compare_sizes(sizes[0][0], sizes[1][0])),
+    ];
+    let detail = axes
         .iter()
-        .map(|r| r.as_ref().unwrap())
+        .map(|(label, axis)| {
+            let result = match axis {
+                ab::Axis::Better => "A",
+                ab::Axis::Worse => "B",
+                ab::Axis::Tie => "差なし",
+            };
+            format!("{}: {}", label, result)
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    let verdict = ab::verdict(&axes.map(|(_, axis)| axis));
+    let line = format!("判定: {} ({})", verdict, detail);
+    match verdict {
+        ab::Verdict::A | ab::Verdict::B => println!("{}", style(line).green()),
+        _ => println!("{}", style(line).yellow()),
+    }
+    if args.repeat < 2 {
+        println!(
+            "{}",
+            style("所要時間のばらつきを考慮するには --repeat に 2 以上を指定してください.").dim()
+        );
+    }
+
+    Ok(())
+}
+
+fn extract_subs(input: &str, track: &[usize], all: bool) -> Result<()> {
+    let streams = video::probe_streams(input)
+        .map_err(|e| anyhow!(e))
+        .with_context(|| format!("入力を読み込めません: {}", input))?;
+    let tracks = subs::tracks(&streams);
+    if tracks.is_empty() {
+        println!("{}", style("字幕が見つかりません").dim());
+        return Ok(());
+    }
+    for t in &tracks {
+        let flags = [(t.default, " [既定]"), (t.forced, " [強制]")]
+            .iter()
+            .filter_map(|(on, label)| on.then_some(*label))
+            .collect::<String>();
+        let note = match t.is_image() {
+            true => style(" (画像の字幕のため元の形式で書き出します)").yellow(),
+            false => style(""),
+        };
+        println!(
+            "#{} - {} - {}{}{}",
+            t.index,
+            t.language_or_und(),
+            t.codec,
+            flags,
+            note
+        );
+    }
+
+    let selected = match all {
+        true => tracks.iter().collect::<Vec<_>>(),
+        false => track
+            .iter()
+            .map(|n| {
+                tracks
+                    .get(*n)
+                    .ok_or_else(|| anyhow!("字幕 #{} はありません.", n))
+            })
+            .collect::<Result<Vec<_>>>()?,
+    };
+    if selected.is_empty() {
+        println!(
+            "{}",
+            style("書き出すには --track N か --all を指定してください").dim()
+        );
+        return Ok(());
+    }
+
+    for path in subs::extract(&workspace::out_dir(), input, &selected, &tracks)? {
+        println!(
+            "{}",
+            style(format!("書き出しました: {}", path.display())).green()
+        );
+    }
+    if selected.iter().any(|t| t.is_image()) {
+        println!(
+            "{}",
+            style("画像の字幕は SRT に変換できないため, 元の形式 (.sup / .mks) で書き出しました")
+                .yellow()
+        );
+    }
+
+    Ok(())
+}
+
+fn clean(args: &CleanArgs) -> Result<()> {
+    let workspace = Workspace::current()
+        .ok_or_else(|| anyhow!("ワークスペースが見つかりません. vvcnv init で作成してください."))?;
+    let manifest = workspace.load_manifest()?;
+    let plan = workspace.plan_clean(&manifest, args.filter.filter(), time::unix_now());
+    for path in &plan.remove {
+        let path = path.strip_prefix(workspace.root()).unwrap_or(path);
+        println!("{}", style(format!("- {}", path.display())).red());
+    }
+
+    let summary = match args.apply {
+        true => format!("{} 個のファイルを削除しました", workspace.clean(&plan)?),
+        false => format!(
+            "{} 個のファイルを削除できます (--apply で実行します)",
+            plan.remove.len()
+        ),
+    };
+    println!("{}", style(summary).dim());
+
+    Ok(())
+}
+
+/// 1 つの入力のすべての組み合わせをエンコードし, 最後にまとめて表示する失敗を返す.
+/// 1 つの入力のエンコードの計画. [`plan_input`] で作り, タスクの実行と結果の記録に使う.
+struct InputPlan {
+    /// 入力のサブディレクトリ, 上書き設定と `--calibrate` の結果を反映したもの.
+    cli: Arc<Cli>,
+    stat: VideoStat,
+    integrity: IntegrityStatus,
+    integrity_report: Vec<InputIntegrity>,
+    configs: Vec<VideoConfig>,
+    task_trim: Trim,
+    calibration: Option<Calibration>,
+    predicted: Option<Duration>,
+    /// 入力に 1 回だけの段階. 組み合わせごとの段階はタスクの結果に記録する.
+    phases: PhaseLog,
+    started_at: Instant,
+    started_at_unix: u64,
+}
+
+/// [`plan_input`] の結果. 上書き設定の誤り, 破損の疑いと `--dry-run` ではエンコードせずに終える.
+enum Planned {
+    Encode(Box<InputPlan>),
+    /// エンコードしない. 失敗があればそのメッセージ.
+    Done(Vec<String>),
+}
+
+async fn encode_input(cli: Arc<Cli>, input: &InputFile, session: &Session) -> Result<Vec<String>> {
+    let plan = match plan_input(cli, input, session).await? {
+        Planned::Encode(plan) => plan,
+        Planned::Done(failures) => return Ok(failures),
+    };
+    let reuse = match plan.cli.no_reuse || plan.cli.stream_to.is_some() {
+        true => None,
+        false => open_reuse(&plan.cli, &plan.stat).await,
+    };
+    let (results, encode_elapsed) = run_tasks(&plan, reuse, session).await;
+
+    Ok(report_input(&plan, &results, encode_elapsed, session))
+}
+
+/// 入力を確かめて解析し, エンコードする設定の組み合わせを決めて計画を表示する.
+async fn plan_input(cli: Arc<Cli>, input: &InputFile, session: &Session) -> Result<Planned> {
+    let input_path = input.path.as_str();
+    let cli = match input.subdir.as_os_str().is_empty() {
+        true => cli,
+        false => Arc::new(Cli {
+            out_subdir: input.subdir.clone(),
+            ..(*cli).clone()
+        }),
+    };
+    let pause = session.pause.clone();
+    let started_at = pause.now();
+    let started_at_unix = time::unix_now();
+    let (cli, applied) = match overrides::load_override(input_path) {
+        Ok(None) => (cli, None),
+        Ok(Some((path, value))) => {
+            let (merged, changes) = value.apply(&cli);
+            (Arc::new(merged), Some((path, changes)))
+        }
+        Err(e) => {
+            eprintln!(
+                "{}: {}",
+                style(format!("✗ スキップ - {}", input_path)).red(),
+                style(format!("{:#}", e)).red().bright()
+            );
+            return Ok(Planned::Done(vec![format!("スキップ: {:#}", e)]));
+        }
+    };
+    // 組み合わせごとの段階はタスクの結果に記録し, ここでは入力に 1 回だけの段階を記録する
+    let phases = PhaseLog::with_clock(Arc::new(pause.clone()));
+    let phase = phases.start("整合性の確認");
+    let integrity = integrity::check(input_path, cli.hash_rate()).await?;
+    drop(phase);
+    let integrity_report = match integrity {
+        IntegrityStatus::NoManifest => Vec::new(),
+        _ => vec![InputIntegrity {
+            input_path: input_path.to_string(),
+            status: integrity.clone(),
+        }],
+    };
+    if integrity.is_mismatch() {
+        let destination = integrity::quarantine(input_path)?;
+        eprintln!(
+            "{}: {}\n{}",
+            style(format!("✗ 破損の疑い - {}", input_path)).red().bold(),
+            style(&integrity).red().bright(),
+            style(format!("→ {} に隔離しました", destination.display())).yellow()
+        );
+        if !cli.no_history {
+            let report = SessionReport {
+                integrity: integrity_report,
+                ..SessionReport::new(
+                    vec![input_path.to_string()],
+                    Vec::new(),
+                    pause.now().duration_since(started_at),
+                )
+            };
+            if let Err(e) = History::open_default().and_then(|h| h.append(started_at_unix, report))
+            {
+                verbosity::warn(format!("履歴を保存できませんでした: {:#}", e));
+            }
+        }
+        return Ok(Planned::Done(vec![integrity.to_string()]));
+    }
+    preflight(&cli)?;
+    let phase = phases.start("解析");
+    let stat = prepare(&cli, input_path).await?;
+    drop(phase);
+
+    let (sources, matrices) = input_matrices(&cli, &stat)?;
+    let task_trim = cli.task_trim(stat.duration);
+    let (sources, configs) = input_configs(
+        &cli,
+        &stat,
+        &sources,
+        &matrices,
+        &task_trim,
+        applied.as_ref(),
+        session,
+    )?;
+
+    let (sources, configs, cli) = match cli.calibrate {
+        true => {
+            let calibrations = calibrate_quality(&cli, &stat, &configs).await?;
+            let configs = configs.into_iter().map(|config| {
+                let crf = calibrations
+                    .iter()
+                    .find(|q| q.codec == video::codec_name(&config))
+                    .and_then(|q| q.crf);
+                VideoConfig {
+                    crf: crf.unwrap_or(config.crf),
+                    ..config
+                }
+            });
+            // CRF を揃えたことで同じになった組み合わせはまとめる
+            let (sources, configs) = matrix::dedupe(zip(sources, configs)).into_iter().unzip();
+            let cli = Arc::new(Cli {
+                quality_calibration: calibrations,
+                ..(*cli).clone()
+            });
+            (sources, configs, cli)
+        }
+        false => (sources, configs, cli),
+    };
+    check_configs(&cli, &stat, &configs, session)?;
+    preflight_configs(&cli, &configs)?;
+
+    let calibration = match History::open_default().and_then(|h| h.latest_calibration()) {
+        Ok(Some(calibration)) => Some(calibration),
+        _ if cli.estimate_time && !cli.dry_run => Some(calibrate(&cli, &stat, &configs[0]).await?),
+        _ => None,
+    };
+    let predicted = predict_time(&stat, &configs, &task_trim, calibration);
+    print_plan(
+        &cli,
+        &stat,
+        &configs,
+        &sources,
+        applied.as_ref(),
+        predicted,
+        &session.concurrency,
+    );
+    if cli.dry_run {
+        print_dry_run(&cli, &stat, &configs)?;
+        return Ok(Planned::Done(Vec::new()));
+    }
+
+    Ok(Planned::Encode(Box::new(InputPlan {
+        cli,
+        stat,
+        integrity,
+        integrity_report,
+        configs,
+        task_trim,
+        calibration,
+        predicted,
+        phases,
+        started_at,
+        started_at_unix,
+    })))
+}
+
+/// CLI の指定 (組み合わせ, プリセット, 個別の設定) から, コーデックごとの組み合わせを作って確かめる.
+fn input_matrices(cli: &Cli, stat: &VideoStat) -> Result<(Vec<ConfigSource>, Vec<Matrix>)> {
+    let mut matrices = Vec::new();
+    if cli.uses_matrix() {
+        match cli.preset.is_empty() {
+            true => matrices.push((
+                ConfigSource::Matrix,
+                Matrix {
+                    res: cli.res_list(),
+                    fps: cli.fps_list(),
+                    crf: cli.crf_list(),
+                    codec: cli.codec_list(),
+                    codec_crf: cli.codec_crf(),
+                    crf_offsets: CrfOffsets {
+                        per_rung: cli.crf_offset_per_rung,
+                        explicit: cli.crf_offset.clone(),
+                    },
+                    base: VideoConfig {
+                        has_audio: cli.has_audio(),
+                        pix_fmt: cli.pix_fmt.clone(),
+                        profile: cli.profile.clone(),
+                        film_grain: cli.av1_film_grain,
+                        codec_params: cli.codec_param.clone(),
+                        fps_mode: cli.fps_mode,
+                        ..Default::default()
+                    },
+                },
+            )),
+            false => {
+                for preset in presets::resolve(&cli.preset, &cli.matrix_file.presets)? {
+                    let source = ConfigSource::Preset(preset.name.clone());
+                    matrices.extend(
+                        preset
+                            .matrices(cli)
+                            .into_iter()
+                            .map(|matrix| (source.clone(), matrix)),
+                    );
+                }
+            }
+        }
+    }
+    matrices.extend(
+        cli.explicit_configs()
+            .into_iter()
+            .map(|(source, entry)| (source, entry.matrix(cli))),
+    );
+    // コーデックを指定していない設定は, 出力形式の既定のエンコーダーとして CRF などを決める
+    let container = video::output_container(cli.stream_to.as_deref().unwrap_or(&stat.path));
+    // コーデックごとの設定 (--keep-alpha やエンコーダー固有のパラメーターの確認) はコーデックごとに行う
+    let matrices = matrices.into_iter().flat_map(|(source, matrix)| {
+        Matrix {
+            base: matrix.base.with_default_codec(&container),
+            ..matrix
+        }
+        .per_codec()
+        .into_iter()
+        .map(move |matrix| (source.clone(), matrix))
+    });
+    let (sources, matrices): (Vec<_>, Vec<_>) = match cli.keep_alpha {
+        true => matrices
+            .map(|(source, matrix)| {
+                let base = video::keep_alpha(&matrix.base, &file::get_file_name(&stat.path).1)
+                    .context("--keep-alpha を指定できません.")?;
+                Ok((source, Matrix { base, ..matrix }))
+            })
+            .collect::<Result<Vec<_>>>()?,
+        false => matrices.collect(),
+    }
+    .into_iter()
+    .unzip();
+    // let res = (480..=1080)
+    //     .step_by(240)
+    //     .map(|h| VideoRes::from_wh_dynamic(None, Some(h), stat.display_wh()))
+    //     .map(Result::unwrap)
+    //     .collect::<Vec<_>>();
+
+    for matrix in &matrices {
+        matrix.validate()?;
+        codec_params::validate(&matrix.base)?;
+    }
+    let offsets = matrices
+        .iter()
+        .filter(|matrix| !matrix.crf_offsets.is_empty())
+        .flat_map(|matrix| {
+            matrix
+                .res
+                .iter()
+                .map(|res| format!("{:?}: {:+}", res, matrix.crf_offset(res)))
+        })
+        .unique()
         .collect::<Vec<_>>();
+    if !offsets.is_empty() {
+        println!(
+            "{}",
+            style(format!("CRF のオフセット: {}", offsets.join(", "))).dim()
+        );
+    }
+
+    Ok((sources, matrices))
+}
+
+/// 組み合わせを展開して重複をまとめる. 多すぎる場合は計画を表示してから断る.
+fn input_configs(
+    cli: &Cli,
+    stat: &VideoStat,
+    sources: &[ConfigSource],
+    matrices: &[Matrix],
+    task_trim: &Trim,
+    applied: Option<&(PathBuf, Vec<String>)>,
+    session: &Session,
+) -> Result<(Vec<ConfigSource>, Vec<VideoConfig>)> {
+    let config_sources = zip(sources, matrices)
+        .flat_map(|(source, matrix)| iter::repeat_n(source.clone(), matrix.count()))
+        .collect::<Vec<_>>();
+    let (sources, configs): (Vec<_>, Vec<_>) = match matrix::build_all(matrices, cli.matrix_limit())
+    {
+        Ok(configs) => matrix::dedupe(zip(config_sources, configs))
+            .into_iter()
+            .unzip(),
+        Err(e) => {
+            let configs = matrices
+                .iter()
+                .flat_map(Matrix::configs)
+                .collect::<Vec<_>>();
+            let calibration = History::open_default()
+                .and_then(|h| h.latest_calibration())
+                .ok()
+                .flatten();
+            print_plan(
+                cli,
+                stat,
+                &configs,
+                &config_sources,
+                applied,
+                predict_time(stat, &configs, task_trim, calibration),
+                &session.concurrency,
+            );
+            return Err(e.into());
+        }
+    };
+
+    Ok(match cli.strict_audio {
+        true => (sources, configs),
+        false => {
+            let adjusted = configs
+                .iter()
+                .map(|config| config.without_missing_audio(stat))
+                .collect::<Vec<_>>();
+            if adjusted.iter().any(Option::is_some) {
+                println!("{}", style("音声なしソースのため音声を無効化").dim());
+            }
+            let configs =
+                zip(configs, adjusted).map(|(config, adjusted)| adjusted.unwrap_or(config));
+            // 音声の有無だけが違った組み合わせはまとめる
+            matrix::dedupe(zip(sources, configs)).into_iter().unzip()
+        }
+    })
+}
+
+/// 出力先の重なり, 出力形式との互換性と配信モードの制限を確かめ, 大きな MP4 を警告する.
+fn check_configs(
+    cli: &Cli,
+    stat: &VideoStat,
+    configs: &[VideoConfig],
+    session: &Session,
+) -> Result<()> {
+    if cli.stream_to.is_none() {
+        check_collisions(cli, stat, configs, session)?;
+    }
+    check_compat(cli, stat, configs)?;
+
+    let combinations = configs.len();
+    if cli.stream_to.is_some() && configs.iter().any(|c| c.fps_mode == Some(FpsMode::Mci)) {
+        bail!("配信モードでは --fps-mode mci を使えません. 動きの補間は実時間で処理できないため, blend か drop を指定してください.");
+    }
+    if cli.stream_to.is_some() && combinations > 1 {
+        bail!(
+            "配信モードでは 1 つの設定しか指定できません ({} 個の組み合わせが指定されています).",
+            combinations
+        );
+    }
+
+    let (_, ext) = file::get_file_name(&stat.path);
+    if cli.faststart && cli.sample.is_none() && video::supports_faststart(&ext) {
+        let trim = cli.trim();
+        let large = configs
+            .iter()
+            .filter(|config| {
+                video::estimate_output_size(stat, config, &trim) > video::LARGE_MP4_THRESHOLD
+            })
+            .count();
+        if large > 0 {
+            verbosity::warn(format!(
+                "{} 個の出力が 4 GiB を超える見込みです. faststart は書き込み後にファイル全体を書き直すため, 時間がかかり, 出力と同じ大きさの空き容量が必要です. MKV での出力を検討してください.",
+                large
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// タスクを別のタスクとして実行するために, 入力と実行全体の状態から複製するもの.
+#[derive(Clone)]
+struct TaskContext {
+    cli: Arc<Cli>,
+    stat: VideoStat,
+    reuse: Option<Reuse>,
+    task_bars: Arc<TaskBars>,
+    budget: Arc<RunBudget<PauseControl>>,
+    cancel: CancelToken,
+    pause: PauseControl,
+    jobs: Arc<Semaphore>,
+    interrupted: Arc<AtomicBool>,
+    failed_logs: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+/// 計画したタスクを同時に実行できる分ずつ実行し, 組み合わせの順の結果とエンコードにかかった時間を返す.
+async fn run_tasks(
+    plan: &InputPlan,
+    reuse: Option<Reuse>,
+    session: &Session,
+) -> (Vec<Result<TaskOutput>>, Duration) {
+    let InputPlan {
+        cli,
+        stat,
+        integrity,
+        configs,
+        task_trim,
+        ..
+    } = plan;
+    let pause = session.pause.clone();
 
     println!();
-    println!();
-    results.clone().iter().all(|r| r.is_ok()).then(|| {
-        println!("{}", style("✓ すべて正常にエンコードしました！").green());
+    println!("{}", style(stat.header()).bold());
+    match integrity {
+        IntegrityStatus::Verified => println!("{}", style(format!("  {}", integrity)).green()),
+        IntegrityStatus::NotListed => println!("{}", style(format!("  {}", integrity)).yellow()),
+        _ => {}
+    }
+    let header = (session.inputs > 1).then(|| {
+        Path::new(&stat.path)
+            .file_name()
+            .map_or(stat.path.clone(), |name| {
+                name.to_string_lossy().into_owned()
+            })
     });
-    zip(iter_prod.clone(), results.clone())
-        .clone()
-        .filter(|(_, r)| r.is_err())
-        .for_each(|((res, fps, crf), e)| {
+    let task_bars = Arc::new(TaskBars::new(
+        configs.iter().map(task_prefix).collect(),
+        cli.progress_unit(),
+        FrameProgress::new(stat, task_trim).total,
+        header,
+        cli.collapse_groups,
+    ));
+    let bars = task_bars.bars.clone();
+    *session.bars.lock().unwrap() = bars.clone();
+    let relayout = tokio::spawn({
+        let task_bars = task_bars.clone();
+        async move {
+            loop {
+                match task_bars.plain {
+                    Some(_) => {
+                        tokio::time::sleep(plain_progress::INTERVAL).await;
+                        task_bars.update_aggregate(&task_bars.state.lock().unwrap());
+                        task_bars.report_plain();
+                    }
+                    None => {
+                        tokio::time::sleep(RELAYOUT_INTERVAL).await;
+                        task_bars.relayout();
+                        task_bars.update_aggregate(&task_bars.state.lock().unwrap());
+                    }
+                }
+            }
+        }
+    });
+
+    let context = TaskContext {
+        cli: cli.clone(),
+        stat: stat.clone(),
+        reuse: reuse.clone(),
+        task_bars: task_bars.clone(),
+        budget: session.budget.clone(),
+        cancel: session.cancel.clone(),
+        pause: pause.clone(),
+        jobs: session.jobs.clone(),
+        interrupted: session.interrupted.clone(),
+        failed_logs: session.failed_logs.clone(),
+    };
+    let tasks = zip(configs, bars).enumerate().map(|(index, (config, pb))| {
+        let weight = session.concurrency.weight(&config.res);
+        tokio::spawn(run_task(context.clone(), index, config.clone(), weight, pb))
+    });
+
+    let encode_started_at = pause.now();
+    let results = futures::future::join_all(tasks)
+        .await
+        .into_iter()
+        .map(Result::unwrap)
+        .collect::<Vec<_>>();
+    relayout.abort();
+    if let Some(reuse) = &reuse {
+        if let Err(e) = reuse.index.lock().unwrap().save() {
+            verbosity::warn(format!("{:#}", e));
+        }
+    }
+    let encode_elapsed = pause.now().duration_since(encode_started_at);
+    task_bars.finish_header(group_summary(&results.iter().collect::<Vec<_>>()));
+    task_bars.report_plain();
+
+    (results, encode_elapsed)
+}
+
+/// 1 つのタスクを, 同時に実行できる数の許可を得てから `--retries` の回数まで試す.
+async fn run_task(
+    context: TaskContext,
+    index: usize,
+    config: VideoConfig,
+    weight: u32,
+    pb: ProgressBar,
+) -> Result<TaskOutput> {
+    let TaskContext {
+        cli,
+        stat: value,
+        reuse,
+        task_bars,
+        budget,
+        cancel,
+        pause,
+        jobs,
+        interrupted,
+        failed_logs,
+    } = context;
+
+    pb.set_message(format!("{}", style("待機中").dim()));
+    // 失敗しても drop で返すので, 後のタスクが待ち続けることはない
+    let _permit = jobs.acquire_many_owned(weight).await.unwrap();
+    pb.set_message("");
+    pb.reset_elapsed();
+    pause.wait_resumed().await;
+    if interrupted.load(Ordering::SeqCst) {
+        pb.set_style(get_style(true, cli.progress_unit()));
+        pb.finish_with_message(format!("{}", style("- 中断したためスキップ").dim()));
+        task_bars.set_active(index, false);
+        return Ok((TaskStatus::Interrupted, None));
+    }
+    if !budget.admit() {
+        pb.set_style(get_style(true, cli.progress_unit()));
+        pb.finish_with_message(format!("{}", style("- 時間制限によりスキップ").dim()));
+        task_bars.set_active(index, false);
+        return Ok((TaskStatus::OutOfTime, None));
+    }
+
+    task_bars.set_active(index, true);
+    let started_at = budget.now();
+    let mut attempt = 1;
+    let result = loop {
+        let result = process_with_fallback(
+            value.clone(),
+            config.clone(),
+            &cli,
+            reuse.as_ref(),
+            cancel.clone(),
+            pause.clone(),
+            pb.clone(),
+        )
+        .await;
+        let e = match result {
+            Err(e)
+                if attempt <= cli.retries
+                    && is_retryable(&e)
+                    && !interrupted.load(Ordering::SeqCst) =>
+            {
+                e
+            }
+            result => {
+                break result.map(|(status, stats)| {
+                    let stats = stats.map(|stats| OutcomeStats {
+                        retries: attempt - 1,
+                        ..stats
+                    });
+                    (status, stats)
+                })
+            }
+        };
+        // 途中までの出力が残っていると, 次の試行が上書きの確認で止まる
+        if cli.stream_to.is_none() {
+            let _ = fs::remove_file(output_path(&cli, &value, &config));
+        }
+        let delay = retry_delay(attempt);
+        pb.set_message(format!(
+            "{}",
+            style(format!("{} 秒後に再試行します: {}", delay.as_secs(), e)).yellow()
+        ));
+        // 待っている間の Ctrl+C や --deadline ですぐに止め, 次の試行を始めない
+        if !pause.sleep_unless_cancelled(delay, &cancel).await || interrupted.load(Ordering::SeqCst)
+        {
+            break Err(e);
+        }
+        attempt += 1;
+        task_bars.set_attempt(index, attempt, cli.retries + 1);
+        pb.set_message("");
+        pb.reset();
+    };
+    budget.record(budget.now().duration_since(started_at));
+    task_bars.set_active(index, false);
+
+    // --fail-fast で止める前に読む. 最初に失敗したタスクは中断ではなく失敗にする
+    let stopped = interrupted.load(Ordering::SeqCst);
+    // --deadline で止めたタスクは失敗に数えない
+    let out_of_time =
+        !stopped && result.is_err() && cancel.is_cancelled() && budget.is_past_deadline();
+    if cli.fail_fast && result.is_err() && !out_of_time && !interrupted.swap(true, Ordering::SeqCst)
+    {
+        eprintln!(
+            "{}",
+            style("--fail-fast: タスクが失敗したため, 残りのタスクを中止します").red()
+        );
+        cancel.cancel();
+        pause.resume();
+    }
+    let result = match result {
+        Err(_) if stopped => {
+            pb.set_style(get_style(true, cli.progress_unit()));
+            pb.finish_with_message(format!("{}", style("- 中断しました").yellow()));
+            Ok((TaskStatus::Interrupted, None))
+        }
+        Err(_) if out_of_time => {
+            pb.set_style(get_style(true, cli.progress_unit()));
+            pb.finish_with_message(format!(
+                "{}",
+                style("- 時間制限により中止しました").yellow()
+            ));
+            Ok((TaskStatus::OutOfTime, None))
+        }
+        result => result.inspect_err(|e| {
+            let mut message = failure_message(e);
+            if attempt > 1 {
+                message += &format!(" {}", style(format!("({} 回試行)", attempt)).dim());
+            }
+            if let Some(path) = log_path(&cli, &value, &config).filter(|path| path.exists()) {
+                message += &format!(" {}", style(format!("ログ: {}", path.display())).red());
+            }
+            pb.finish_with_message(message)
+        }),
+    };
+    let log = log_path(&cli, &value, &config);
+    let mut failed_logs = failed_logs.lock().unwrap();
+    if let (Err(_), Some(log)) = (&result, log) {
+        failed_logs.insert(log);
+    }
+    prune_logs(&cli, &failed_logs);
+    result
+}
+
+/// タスクの結果をまとめて表示し, 後処理 (サムネイル, 公開) と記録 (履歴, ワークスペース) を行う.
+/// 失敗したタスクのメッセージを返す.
+fn report_input(
+    plan: &InputPlan,
+    results: &[Result<TaskOutput>],
+    encode_elapsed: Duration,
+    session: &Session,
+) -> Vec<String> {
+    let InputPlan {
+        cli,
+        stat,
+        integrity_report,
+        configs,
+        task_trim,
+        calibration,
+        predicted,
+        phases,
+        started_at,
+        started_at_unix,
+        ..
+    } = plan;
+    let results = results.iter().collect::<Vec<_>>();
+
+    let tasks = zip(configs, &results)
+        .map(|(config, r)| match r {
+            Ok((status, outcome)) => TaskReport {
+                input_path: stat.path.clone(),
+                config: config.clone(),
+                status: status.clone(),
+                outcome: outcome.clone(),
+                error: None,
+            },
+            Err(e) => TaskReport {
+                input_path: stat.path.clone(),
+                config: config.clone(),
+                status: match is_refused(e) {
+                    true => TaskStatus::Refused,
+                    false => TaskStatus::Failed,
+                },
+                outcome: None,
+                error: Some(format!("{:#}", e)),
+            },
+        })
+        .collect::<Vec<_>>();
+    let records = zip(configs, &tasks)
+        .map(|(config, task)| {
+            ResultRecord::new(task, output_path(cli, stat, config), stat.file_size)
+        })
+        .collect::<Vec<_>>();
+
+    let interrupted = session.interrupted.load(Ordering::SeqCst);
+    print_results(plan, &results, &records, encode_elapsed, interrupted);
+    let thumbnails = finish_outputs(plan, &results, interrupted);
+    let task_phases = results
+        .iter()
+        .filter_map(|r| r.as_ref().ok()?.1.as_ref())
+        .flat_map(|stats| stats.phases.iter().cloned())
+        .collect::<Vec<_>>();
+    let all_phases = phases
+        .phases()
+        .into_iter()
+        .chain(task_phases)
+        .collect::<Vec<_>>();
+    if let Some(breakdown) = phases::breakdown(&phases::totals(&all_phases)) {
+        println!("{}", style(format!("時間の内訳: {}", breakdown)).dim());
+    }
+    session.phases.lock().unwrap().extend(all_phases);
+    print_failures(plan, &results);
+
+    // 終了コードを決めるため, 書き出さない場合も残す
+    session
+        .results
+        .lock()
+        .unwrap()
+        .reports
+        .push(EncodeReport::new(stat, records));
+    if !cli.no_history {
+        let samples = zip(configs, &results)
+            .filter_map(|(config, r)| match r {
+                Ok((TaskStatus::Encoded | TaskStatus::Downgraded(_), Some(outcome))) => {
+                    Some(estimate::sample(
+                        stat,
+                        config,
+                        task_trim,
+                        Duration::from_secs_f64(outcome.elapsed_secs),
+                    ))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let report = SessionReport {
+            predicted_secs: predicted.map(|d| d.as_secs_f64()),
+            calibration: Calibration::from_samples(&samples, cli.jobs().min(configs.len()))
+                .or(*calibration),
+            ffmpeg: ffmpeg::detect(),
+            integrity: integrity_report.clone(),
+            quality: cli.quality_calibration.clone(),
+            ..SessionReport::new(
+                vec![stat.path.clone()],
+                tasks,
+                session.pause.now().duration_since(*started_at),
+            )
+        };
+        if let Err(e) = History::open_default().and_then(|h| h.append(*started_at_unix, report)) {
+            verbosity::warn(format!("履歴を保存できませんでした: {:#}", e));
+        }
+    }
+    if let Some(workspace) = Workspace::current().filter(|_| cli.stream_to.is_none()) {
+        let mut files = Vec::new();
+        for (config, r) in zip(configs, &results) {
+            let output = output_path(cli, stat, config);
+            let sidecar = report::sidecar_path(&output);
+            let failed = matches!(
+                r,
+                Err(_) | Ok((TaskStatus::Interrupted | TaskStatus::OutOfTime, _))
+            );
+            files.push((PathBuf::from(output), EntryKind::Output, failed));
+            if sidecar.exists() {
+                files.push((sidecar, EntryKind::Sidecar, failed));
+            }
+            if let Some(log) = log_path(cli, stat, config).filter(|path| path.exists()) {
+                files.push((log, EntryKind::Log, failed));
+            }
+        }
+        files.extend(
+            thumbnails
+                .into_iter()
+                .map(|path| (path, EntryKind::Thumbnail, false)),
+        );
+        if let Err(e) = workspace.record(&files, cli.layout, time::unix_now()) {
+            verbosity::warn(format!("ワークスペースに記録できませんでした: {:#}", e));
+        }
+    }
+
+    zip(configs, &results)
+        .filter_map(|(config, r)| {
+            let e = r.as_ref().err()?;
+            Some(format!(
+                "RES: {:?}, FPS: {}, CRF: {}: {:#}",
+                config.res, config.fps, config.crf, e
+            ))
+        })
+        .collect()
+}
+
+/// タスクの結果の一覧, 大きさの表, 再試行やフレームの複製などの注意と所要時間を表示する.
+fn print_results(
+    plan: &InputPlan,
+    results: &[&Result<TaskOutput>],
+    records: &[ResultRecord],
+    encode_elapsed: Duration,
+    interrupted: bool,
+) {
+    let InputPlan {
+        cli,
+        configs,
+        predicted,
+        ..
+    } = plan;
+
+    println!();
+    println!();
+    let out_of_time = results
+        .iter()
+        .filter(|r| matches!(r, Ok((TaskStatus::OutOfTime, _))))
+        .count();
+    let too_large = results
+        .iter()
+        .any(|r| matches!(r, Ok((TaskStatus::TooLarge, _))));
+    if interrupted {
+        let done = results
+            .iter()
+            .filter(|r| matches!(r, Ok((status, _)) if status.is_success()))
+            .count();
+        println!(
+            "{}",
+            style(format!(
+                "中断しました: {} / {} 個のタスクが完了しました",
+                done,
+                results.len()
+            ))
+            .yellow()
+        );
+        for stats in results
+            .iter()
+            .filter_map(|r| r.as_ref().ok().filter(|(s, _)| s.is_success())?.1.as_ref())
+        {
+            println!("{}", style(format!("- {}", stats.output_path)).dim());
+        }
+    }
+    (out_of_time == 0 && !too_large && !interrupted && results.iter().all(|r| r.is_ok())).then(
+        || {
+            println!("{}", style("✓ すべて正常にエンコードしました！").green());
+        },
+    );
+    zip(configs, results)
+        .filter_map(|(c, r)| match r {
+            Ok((TaskStatus::Downgraded(note), _)) => {
+                Some((c, format!("フォールバック ({})", note)))
+            }
+            Ok((TaskStatus::Copied, _)) => Some((c, "コピー".to_string())),
+            Ok((TaskStatus::Reused, _)) => Some((c, "再利用".to_string())),
+            Ok((TaskStatus::Existing, _)) => Some((c, "既存のためスキップ".to_string())),
+            Ok((TaskStatus::Skipped, _)) => Some((c, "スキップ".to_string())),
+            Ok((TaskStatus::OutOfTime, _)) => Some((c, "時間制限により未完了".to_string())),
+            Ok((TaskStatus::TooLarge, _)) => Some((c, "中止: 元より大きくなるため".to_string())),
+            Ok((TaskStatus::Interrupted, _)) => Some((c, "中断".to_string())),
+            _ => None,
+        })
+        .for_each(|(config, label)| {
+            println!(
+                "{}",
+                style(format!(
+                    "- {} - RES: {:?}, FPS: {}, CRF: {}",
+                    label, config.res, config.fps, config.crf
+                ))
+                .dim()
+            );
+        });
+    let table = results::summary_table(records);
+    if table.len() > 1 {
+        println!();
+        for (i, line) in table.iter().enumerate() {
+            match (i, line.larger) {
+                (0, _) => println!("{}", style(&line.text).bold()),
+                (_, true) => println!("{}", style(&line.text).red()),
+                (_, false) => println!("{}", line.text),
+            }
+        }
+        println!();
+    }
+    for stats in results.iter().filter_map(|r| r.as_ref().ok()?.1.as_ref()) {
+        if stats.retries > 0 {
+            println!(
+                "{}",
+                style(format!(
+                    "- {} 回目の試行で成功: {}",
+                    stats.retries + 1,
+                    stats.output_path
+                ))
+                .yellow()
+            );
+        }
+        if let Some(breakdown) = phases::breakdown(&phases::totals(&stats.phases)) {
+            verbosity::info(format!("- 内訳: {}: {}", stats.output_path, breakdown));
+        }
+        if !stats.frames.is_clean() {
+            let line = format!(
+                "- フレームの複製: {}, 破棄: {} ({:.1}%): {}",
+                stats.frames.dup,
+                stats.frames.drop,
+                stats.frames.drop_ratio() * 100.0,
+                stats.output_path
+            );
+            match stats.frames.drops_exceed_threshold() {
+                true => println!("{}", style(line).yellow()),
+                false => println!("{}", style(line).dim()),
+            }
+        }
+        if let Some(drift) = stats.av_drift_secs {
+            let longer = match drift > 0.0 {
+                true => "音声",
+                false => "映像",
+            };
+            println!(
+                "{}",
+                style(format!(
+                    "- 映像と音声の長さのずれが元動画より大きくなりました ({}が {:.3}s 長い): {}",
+                    longer,
+                    drift.abs(),
+                    stats.output_path
+                ))
+                .yellow()
+            );
+        }
+    }
+    if cli.layout == OutputLayout::PerConfig {
+        print_outputs(cli, results);
+    }
+    if let Some(predicted) = predicted {
+        println!(
+            "{}",
+            style(format!(
+                "所要時間: {} (予測: {}, {:+.0}%)",
+                time::format_clock(encode_elapsed),
+                time::format_clock(*predicted),
+                estimate::deviation_percent(*predicted, encode_elapsed)
+            ))
+            .dim()
+        );
+    }
+    if out_of_time > 0 {
+        println!(
+            "{}",
+            style(format!(
+                "{} 個の組み合わせが時間制限により完了していません. 再開するには --max-runtime / --deadline を延ばし, --skip-existing を付けて再実行してください.",
+                out_of_time
+            ))
+            .yellow()
+        );
+    }
+}
+
+/// `--thumbnails` と `--publish-dir` の後処理を行い, 書き出したサムネイルを返す.
+fn finish_outputs(
+    plan: &InputPlan,
+    results: &[&Result<TaskOutput>],
+    interrupted: bool,
+) -> Vec<PathBuf> {
+    let InputPlan {
+        cli,
+        stat,
+        task_trim,
+        phases,
+        ..
+    } = plan;
+
+    let mut thumbnails = Vec::new();
+    if let Some(ThumbnailMode::Scene(count)) = cli.thumbnails.filter(|_| !interrupted) {
+        let _phase = phases.start("サムネイル");
+        let line = match write_thumbnails(cli, stat, task_trim, results, count) {
+            Ok(written) => {
+                thumbnails = written;
+                style(format!(
+                    "サムネイル: {} 枚を書き出しました",
+                    thumbnails.len()
+                ))
+                .green()
+            }
+            Err(e) => style(format!("サムネイル: 失敗しました: {:#}", e)).red(),
+        };
+        println!("{}", line);
+    }
+    if let Some(dir) = &cli.publish_dir {
+        let _phase = phases.start("公開");
+        let (name, _) = file::get_file_name(&stat.path);
+        let complete = results.iter().all(|r| {
+            matches!(r, Ok((status, _))
+                    if !matches!(status, TaskStatus::OutOfTime | TaskStatus::Interrupted))
+        });
+        let line = match complete {
+            false => style(format!(
+                "公開: {} - 完了していないタスクがあるため公開しません",
+                name
+            ))
+            .yellow(),
+            true => {
+                let outputs = results
+                    .iter()
+                    .filter_map(|r| r.as_ref().ok()?.1.as_ref())
+                    .flat_map(|stats| {
+                        let sidecar = cli
+                            .sidecars
+                            .then(|| report::sidecar_path(&stats.output_path));
+                        [Some(PathBuf::from(&stats.output_path)), sidecar]
+                    })
+                    .flatten()
+                    .collect::<Vec<_>>();
+                // 出力先からの相対パス (入力のサブディレクトリや per-config の設定ごとのディレクトリ) を公開先でも保つ
+                match publish::publish(&outputs, &cli.out_root(), dir, cli.publish_verify_hash) {
+                    Ok(published) => {
+                        let copied = published
+                            .iter()
+                            .filter(|p| p.strategy == MoveStrategy::Copy)
+                            .count();
+                        let note = match copied {
+                            0 => String::new(),
+                            n => format!(" ({} 個は {})", n, MoveStrategy::Copy),
+                        };
+                        style(format!(
+                            "公開: {} - {} 個のファイルを {} に移動しました{}",
+                            name,
+                            published.len(),
+                            dir.display(),
+                            note
+                        ))
+                        .green()
+                    }
+                    Err(e) => style(format!("公開: {} - 失敗しました: {:#}", name, e)).red(),
+                }
+            }
+        };
+        println!("{}", line);
+    }
+
+    thumbnails
+}
+
+/// 失敗したタスクのエラーと対処の提案, 上書きしなかった出力を表示する.
+fn print_failures(plan: &InputPlan, results: &[&Result<TaskOutput>]) {
+    let InputPlan {
+        cli, stat, configs, ..
+    } = plan;
+
+    let container = video::output_container(cli.stream_to.as_deref().unwrap_or(&stat.path));
+    zip(configs, results)
+        .filter(|(_, r)| r.as_ref().is_err_and(|e| !is_refused(e)))
+        .for_each(|(config, e)| {
+            let e = e.as_ref().unwrap_err();
+            let advice = match e.downcast_ref::<ProcessErr>() {
+                Some(ProcessErr::Mux(mux, _)) => mux::suggest(mux, config, &container),
+                _ => None,
+            };
             eprintln!(
                 "\n{}\n{}:\n{:?}",
                 style("--------------------").dim(),
                 style(format!(
                     "✗ エンコード失敗 - RES: {:?}, FPS: {}, CRF: {}",
-                    res, fps, crf
+                    config.res, config.fps, config.crf
                 ))
                 .red(),
-                style(e.as_ref().unwrap_err()).red().bright()
+                style(e).red().bright()
             );
+            if let Some(advice) = advice {
+                eprintln!("{}", style(format!("→ {}", advice)).yellow());
+            }
         });
+    let refused = results
+        .iter()
+        .filter_map(|r| r.as_ref().err()?.downcast_ref::<file::OutputExists>())
+        .collect::<Vec<_>>();
+    if !refused.is_empty() {
+        eprintln!(
+            "\n{}\n{}",
+            style("--------------------").dim(),
+            style(format!(
+                "{} 個のタスクは出力先にファイルがあるため上書きしませんでした (エンコードの失敗ではありません). 上書きするには --overwrite, 完成した出力を残すには --skip-existing を指定してください.",
+                refused.len()
+            ))
+            .yellow()
+        );
+        for e in refused {
+            eprintln!("{}", style(format!("  - {}", e.path.display())).yellow());
+        }
+    }
+}
 
-    Ok(())
+async fn run_command(cli: Arc<Cli>, command: &Command) -> Result<()> {
+    match command {
+        Command::Rerun(args) => rerun(cli, args).await,
+        Command::History(args) => history(args),
+        Command::Migrate(args) => migrate(args),
+        Command::Init => init(),
+        Command::Clean(args) => clean(args),
+        Command::Subs(SubsArgs {
+            action: SubsAction::Extract { input, track, all },
+        }) => extract_subs(input, track, *all),
+        Command::Ab(args) => ab(args).await,
+        Command::Report(args) => report(args),
+        Command::Config(ConfigArgs {
+            action: ConfigAction::Show { origin },
+        }) => show_config(*origin),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<ExitCode> {
+    let mut cli = Cli::parse();
+    if let Some(path) = &cli.config {
+        cli.matrix_file = matrix_file::load(path)?;
+    }
+    // 入力を処理し始める前に, プリセットの名前を確かめる
+    presets::resolve(&cli.preset, &cli.matrix_file.presets)?;
+    let cli = Arc::new(cli);
+    let json_out = match cli.json && cli.command.is_none() {
+        true => Some(results::redirect_stdout().context("標準出力を切り替えられませんでした")?),
+        false => None,
+    };
+    verbosity::set(cli.verbosity());
+    PLAIN.store(cli.plain_progress(), Ordering::Relaxed);
+    for warning in config::init(cli.config_layer())? {
+        verbosity::warn(warning);
+    }
+    if let Some(command) = &cli.command {
+        return run_command(cli.clone(), command)
+            .await
+            .map(|()| ExitCode::SUCCESS);
+    }
+    let pause = PauseControl::new();
+    // 記録するビルド情報を起動時に一度だけ取得しておく
+    ffmpeg::detect();
+    hwaccel::init(select_hwaccel(&cli)?);
+    if cli.sandbox {
+        match sandbox::enable() {
+            Ok(()) => println!(
+                "{}",
+                style("サンドボックスの中で ffmpeg を実行します").dim()
+            ),
+            Err(e) => verbosity::warn(format!("{}. サンドボックスなしで実行します", e)),
+        }
+    }
+
+    if cli.inputs.is_empty() {
+        bail!("入力ファイルを指定してください (例: vvcnv video.mkv --crf 18,23,28 --fps 30,60)");
+    }
+    let paths = input::resolve(&cli.inputs)?;
+    for path in paths.iter().map(Path::new).filter(|path| !path.is_dir()) {
+        file::check_readable(path)?;
+    }
+    // ディレクトリの中のファイルは拡張子だけで選び, 開いて調べるのはエンコードの直前にする
+    let mut inputs = Vec::new();
+    for path in paths {
+        if Path::new(&path).is_dir() {
+            let found = input::walk(Path::new(&path), &cli.walk_options());
+            if found.is_empty() {
+                verbosity::warn(format!("動画が見つかりません: {}", path));
+            }
+            inputs.extend(found);
+            continue;
+        }
+        let (videos, skipped) = input::select_videos(vec![path], &cli.ext_filter()).await;
+        for path in &skipped {
+            verbosity::warn(format!("動画ではないためスキップします: {}", path));
+        }
+        inputs.extend(videos.into_iter().map(InputFile::new));
+    }
+
+    let concurrency = Concurrency::choose(&config::current().jobs);
+    let session = Session {
+        pause: pause.clone(),
+        budget: Arc::new(RunBudget::new(pause.clone(), cli.max_runtime, cli.deadline)),
+        cancel: CancelToken::new(),
+        bars: Arc::new(Mutex::new(Vec::new())),
+        phases: Mutex::new(Vec::new()),
+        outputs: Mutex::new(HashMap::new()),
+        jobs: Arc::new(Semaphore::new(concurrency.permits())),
+        concurrency,
+        interrupted: Arc::new(AtomicBool::new(false)),
+        inputs: inputs.len(),
+        results: Mutex::new(RunResults::default()),
+        failed_logs: Arc::new(Mutex::new(HashSet::new())),
+    };
+    tokio::spawn({
+        let cancel = session.cancel.clone();
+        let pause = pause.clone();
+        let interrupted = session.interrupted.clone();
+        async move {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                if interrupted.swap(true, Ordering::SeqCst) {
+                    // 2 回目は後片付けをせずに終了する. ffmpeg は別のプロセスグループなので終了させておく
+                    pause.kill_children();
+                    std::process::exit(130);
+                }
+                eprintln!(
+                    "\n{}",
+                    style("中断しています… もう一度 Ctrl+C で直ちに終了します").yellow()
+                );
+                cancel.cancel();
+                // 止めている ffmpeg は終了させても待っているタスクが進まないので再開させる
+                pause.resume();
+                pause.kill_children();
+            }
+        }
+    });
+    if session.budget.until_deadline().is_some() {
+        let cancel = session.cancel.clone();
+        let pause = pause.clone();
+        let budget = session.budget.clone();
+        thread::spawn(move || {
+            // タスクを始めるかの判断と同じく, 一時停止していた時間を除いた時計で待つ
+            while let Some(remaining) = budget.until_deadline().filter(|r| !r.is_zero()) {
+                thread::sleep(remaining.min(DEADLINE_POLL_INTERVAL));
+            }
+            cancel.cancel();
+            // 止めている ffmpeg は出力を返さず中断を検知できないので再開させる
+            pause.resume();
+        });
+    }
+
+    #[cfg(unix)]
+    tokio::spawn({
+        let pause = pause.clone();
+        let bars = session.bars.clone();
+        async move {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let Ok(mut signal) = signal(SignalKind::user_defined1()) else {
+                return;
+            };
+            while signal.recv().await.is_some() {
+                let paused = pause.toggle();
+                for pb in bars.lock().unwrap().iter().filter(|pb| !pb.is_finished()) {
+                    match paused {
+                        true => pb.set_message(format!("{}", style("一時停止中").yellow())),
+                        false => pb.set_message(""),
+                    }
+                }
+            }
+        }
+    });
+
+    let mut failed = Vec::new();
+    let mut fatal = None;
+    for (i, input) in inputs.iter().enumerate() {
+        if session.interrupted.load(Ordering::SeqCst) {
+            println!(
+                "{}",
+                style(format!(
+                    "中断したため, 残りの {} 個の入力は処理しませんでした",
+                    inputs.len() - i
+                ))
+                .yellow()
+            );
+            break;
+        }
+        let input_path = &input.path;
+        if inputs.len() > 1 {
+            println!(
+                "{}",
+                style(format!("[{}/{}] {}", i + 1, inputs.len(), input_path)).dim()
+            );
+        }
+        let recorded = session.results.lock().unwrap().reports.len();
+        let failures = match encode_input(cli.clone(), input, &session).await {
+            Ok(failures) => failures,
+            Err(e) if inputs.len() == 1 && !cli.json => return Err(e),
+            // JSON を書き出してから失敗を返す
+            Err(e) if inputs.len() == 1 => {
+                let failures = vec![format!("{:#}", e)];
+                fatal = Some(e);
+                failures
+            }
+            Err(e) => {
+                eprintln!(
+                    "{}: {}",
+                    style(format!("✗ 失敗 - {}", input_path)).red(),
+                    style(format!("{:#}", e)).red().bright()
+                );
+                vec![format!("{:#}", e)]
+            }
+        };
+        let mut results = session.results.lock().unwrap();
+        if results.reports.len() == recorded && !failures.is_empty() {
+            results.failed_inputs.push(InputFailure {
+                input_path: input_path.clone(),
+                error: failures.join("\n"),
+            });
+        }
+        if let Some(path) = &cli.csv {
+            results::write_csv(path, results.records())?;
+        }
+        drop(results);
+        let failed_here = !failures.is_empty();
+        if failed_here {
+            failed.push((input_path, failures));
+        }
+        println!();
+        if cli.fail_fast && failed_here && i + 1 < inputs.len() {
+            println!(
+                "{}",
+                style(format!(
+                    "--fail-fast: 失敗したため, 残りの {} 個の入力は処理しませんでした",
+                    inputs.len() - i - 1
+                ))
+                .yellow()
+            );
+            break;
+        }
+    }
+
+    // 入力が 1 つの場合は, その入力の内訳と同じになる
+    let totals = phases::totals(session.phases.lock().unwrap().iter());
+    if let Some(breakdown) = phases::breakdown(&totals).filter(|_| inputs.len() > 1) {
+        println!(
+            "{}",
+            style(format!("全体の時間の内訳: {}", breakdown)).dim()
+        );
+    }
+
+    if inputs.len() > 1 && !failed.is_empty() {
+        eprintln!(
+            "{}\n{}",
+            style("--------------------").dim(),
+            style(format!(
+                "✗ {} / {} 個の入力で失敗しました",
+                failed.len(),
+                inputs.len()
+            ))
+            .red()
+        );
+        for (input_path, failures) in &failed {
+            eprintln!("{}", style(input_path).red().bold());
+            for failure in failures {
+                eprintln!("{}", style(format!("  - {}", failure)).red().bright());
+            }
+        }
+    }
+
+    let results = session.results.into_inner().unwrap();
+    if cli.html {
+        let path = cli.out_root().join(html_report::FILE_NAME);
+        html_report::write(&path, &results)?;
+        println!("{}", style(format!("レポート: {}", path.display())).green());
+    }
+    if let Some(out) = json_out {
+        results
+            .to_document()
+            .write(out)
+            .context("結果の JSON を書き出せませんでした")?;
+    }
+    if let Some(e) = fatal {
+        return Err(e);
+    }
+
+    Ok(ExitCode::from(results.outcome().exit_code()))
 }
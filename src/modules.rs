@@ -1,2 +1,51 @@
+pub mod ab;
+pub mod av_sync;
+pub mod blocking;
+pub mod chunk;
+pub mod cli;
+pub mod codec_params;
+pub mod concurrency;
+pub mod config;
+pub mod estimate;
+pub mod ffmpeg;
 pub mod file;
+pub mod frames;
+pub mod groups;
+pub mod history;
+pub mod html_report;
+pub mod hwaccel;
+pub mod input;
+pub mod integrity;
+pub mod layout;
+pub mod logs;
+pub mod matrix;
+pub mod matrix_file;
+pub mod mux;
+pub mod naming;
+pub mod overlay;
+pub mod overrides;
+pub mod pause;
+pub mod phases;
+pub mod plain_progress;
+pub mod presets;
+pub mod publish;
+pub mod quality;
+pub mod report;
+pub mod report_diff;
+pub mod results;
+pub mod reuse;
+pub mod sampling;
+pub mod sandbox;
+pub mod schedule;
+pub mod size_limit;
+pub mod stall;
+pub mod subs;
+#[cfg(test)]
+pub(crate) mod testing;
+pub mod text;
+pub mod thumbnail;
+pub mod time;
+pub mod verbosity;
 pub mod video;
+pub mod warnings;
+pub mod workspace;
@@ -0,0 +1,261 @@
+use core::fmt;
+
+use super::video::{VideoConfig, VideoRes};
+
+/// 所要時間の差がこの割合より小さい場合は, ばらつきに関わらず同じとみなす.
+pub const TIME_TOLERANCE: f64 = 0.02;
+/// 出力サイズの差がこの割合より小さい場合は同じとみなす.
+pub const SIZE_TOLERANCE: f64 = 0.01;
+
+/// `--a` / `--b` で変更できる設定.
+#[derive(Debug, Clone)]
+pub enum Setting {
+    Crf(u32),
+    Preset(String),
+    Res(VideoRes),
+    Fps(u32),
+    PixFmt(String),
+    Profile(String),
+}
+
+/// 比較する設定の一方. 指定しなかった項目は共通の設定を使う.
+#[derive(Debug, Clone)]
+pub struct Variant {
+    pub label: String,
+    pub settings: Vec<Setting>,
+}
+
+/// `crf=24,preset=medium` の形式.
+pub fn parse_variant(input: &str) -> Result<Variant, String> {
+    let settings = input
+        .split(',')
+        .filter(|part| !part.trim().is_empty())
+        .map(|part| {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("key=value の形式で指定してください: {}", part))?;
+            let (key, value) = (key.trim(), value.trim());
+            let number = || {
+                value
+                    .parse::<u32>()
+                    .map_err(|_| format!("{} には整数を指定してください: {}", key, value))
+            };
+            Ok(match key {
+                "crf" => Setting::Crf(number()?),
+                "fps" => Setting::Fps(number()?),
+                "preset" => Setting::Preset(value.to_string()),
+                "res" => Setting::Res(value.parse().map_err(|e| format!("{}", e))?),
+                "pix-fmt" => Setting::PixFmt(value.to_string()),
+                "profile" => Setting::Profile(value.to_string()),
+                _ => {
+                    return Err(format!(
+                        "不明な項目です: {} (crf / preset / res / fps / pix-fmt / profile)",
+                        key
+                    ))
+                }
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    if settings.is_empty() {
+        return Err("設定を 1 つ以上指定してください (例: crf=24,preset=medium)".to_string());
+    }
+
+    Ok(Variant {
+        label: input.to_string(),
+        settings,
+    })
+}
+
+impl Variant {
+    pub fn apply(&self, base: &VideoConfig) -> VideoConfig {
+        self.settings
+            .iter()
+            .fold(base.clone(), |config, setting| match setting {
+                Setting::Crf(crf) => VideoConfig {
+                    crf: *crf,
+                    ..config
+                },
+                Setting::Preset(preset) => VideoConfig {
+                    preset: Some(preset.clone()),
+                    ..config
+                },
+                Setting::Res(res) => VideoConfig {
+                    res: res.clone(),
+                    ..config
+                },
+                Setting::Fps(fps) => VideoConfig {
+                    fps: *fps,
+                    ..config
+                },
+                Setting::PixFmt(pix_fmt) => VideoConfig {
+                    pix_fmt: Some(pix_fmt.clone()),
+                    ..config
+                },
+                Setting::Profile(profile) => VideoConfig {
+                    profile: Some(profile.clone()),
+                    ..config
+                },
+            })
+    }
+}
+
+/// 繰り返し計測した値の平均と標準偏差 (不偏).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub n: usize,
+    pub mean: f64,
+    pub stddev: f64,
+}
+
+impl Stats {
+    pub fn from_samples(samples: &[f64]) -> Option<Self> {
+        let n = samples.len();
+        if n == 0 {
+            return None;
+        }
+        let mean = samples.iter().sum::<f64>() / n as f64;
+        let stddev = match n {
+            1 => 0.0,
+            _ => {
+                let variance =
+                    samples.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / (n - 1) as f64;
+                variance.sqrt()
+            }
+        };
+
+        Some(Self { n, mean, stddev })
+    }
+
+    fn standard_error(&self) -> f64 {
+        self.stddev / (self.n as f64).sqrt()
+    }
+}
+
+/// 1 つの軸での A の B に対する結果. 小さい方を良いとする.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Axis {
+    Better,
+    Worse,
+    Tie,
+}
+
+fn compare(a: f64, b: f64, threshold: f64) -> Axis {
+    match a - b {
+        d if d.abs() <= threshold => Axis::Tie,
+        d if d < 0.0 => Axis::Better,
+        _ => Axis::Worse,
+    }
+}
+
+/// 平均の差が, 差の標準誤差の 2 倍 (おおよそ 95%) と `TIME_TOLERANCE` の大きい方を超えた場合だけ差があるとみなす.
+pub fn compare_times(a: &Stats, b: &Stats) -> Axis {
+    let noise = 2.0 * (a.standard_error().powi(2) + b.standard_error().powi(2)).sqrt();
+    let tolerance = a.mean.max(b.mean) * TIME_TOLERANCE;
+    compare(a.mean, b.mean, noise.max(tolerance))
+}
+
+/// 出力サイズは同じ設定であれば毎回同じになるので, 1 回分を比べる.
+pub fn compare_sizes(a: u64, b: u64) -> Axis {
+    compare(a as f64, b as f64, a.max(b) as f64 * SIZE_TOLERANCE)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// A がすべての軸で B 以上で, 少なくとも 1 つで良い (パレート優位).
+    A,
+    B,
+    /// どの軸にも差がない.
+    Equivalent,
+    /// 軸によって良い方が異なる.
+    TradeOff,
+}
+
+pub fn verdict(axes: &[Axis]) -> Verdict {
+    let better = axes.contains(&Axis::Better);
+    let worse = axes.contains(&Axis::Worse);
+    match (better, worse) {
+        (true, false) => Verdict::A,
+        (false, true) => Verdict::B,
+        (false, false) => Verdict::Equivalent,
+        (true, true) => Verdict::TradeOff,
+    }
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Verdict::A => write!(f, "A の方が良い設定です (すべての項目で B 以上)"),
+            Verdict::B => write!(f, "B の方が良い設定です (すべての項目で A 以上)"),
+            Verdict::Equivalent => write!(f, "差はばらつきの範囲内です"),
+            Verdict::TradeOff => write!(f, "優劣は付きません (項目によって良い方が異なります)"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(samples: &[f64]) -> Stats {
+        Stats::from_samples(samples).unwrap()
+    }
+
+    #[test]
+    fn test_parse_variant() {
+        let variant = parse_variant("crf=24, preset=slow,res=1920x1080").unwrap();
+        assert_eq!(variant.settings.len(), 3);
+        let config = variant.apply(&VideoConfig::default());
+        assert_eq!(config.crf, 24);
+        assert_eq!(config.preset.as_deref(), Some("slow"));
+        assert_eq!(config.res.to_wh(), (1920, 1080));
+        assert_eq!(config.fps, VideoConfig::default().fps);
+
+        assert!(parse_variant("crf=high").is_err());
+        assert!(parse_variant("bitrate=1M").is_err());
+        assert!(parse_variant("crf").is_err());
+        assert!(parse_variant("").is_err());
+    }
+
+    #[test]
+    fn test_stats() {
+        let s = stats(&[10.0, 12.0, 14.0]);
+        assert_eq!(s.n, 3);
+        assert!((s.mean - 12.0).abs() < 1e-9);
+        assert!((s.stddev - 2.0).abs() < 1e-9);
+        assert_eq!(stats(&[5.0]).stddev, 0.0);
+        assert_eq!(Stats::from_samples(&[]), None);
+    }
+
+    #[test]
+    fn test_compare_times() {
+        // ばらつきが小さければ 10% の差は有意
+        let fast = stats(&[10.0, 10.1, 9.9]);
+        let slow = stats(&[11.0, 11.1, 10.9]);
+        assert_eq!(compare_times(&fast, &slow), Axis::Better);
+        assert_eq!(compare_times(&slow, &fast), Axis::Worse);
+
+        // 同じ平均の差でも, ばらつきが大きければ差はない
+        let noisy_fast = stats(&[7.0, 13.0, 10.0]);
+        let noisy_slow = stats(&[8.0, 14.0, 11.0]);
+        assert_eq!(compare_times(&noisy_fast, &noisy_slow), Axis::Tie);
+
+        // 1 回ずつでは, わずかな差は同じとみなす
+        assert_eq!(compare_times(&stats(&[10.0]), &stats(&[10.1])), Axis::Tie);
+        assert_eq!(
+            compare_times(&stats(&[10.0]), &stats(&[12.0])),
+            Axis::Better
+        );
+    }
+
+    #[test]
+    fn test_verdict() {
+        assert_eq!(compare_sizes(1_000_000, 1_005_000), Axis::Tie);
+        assert_eq!(compare_sizes(900_000, 1_000_000), Axis::Better);
+
+        assert_eq!(verdict(&[Axis::Better, Axis::Tie]), Verdict::A);
+        assert_eq!(verdict(&[Axis::Tie, Axis::Worse]), Verdict::B);
+        assert_eq!(verdict(&[Axis::Tie, Axis::Tie]), Verdict::Equivalent);
+        assert_eq!(verdict(&[Axis::Better, Axis::Worse]), Verdict::TradeOff);
+        assert!(Verdict::TradeOff.to_string().contains("優劣"));
+    }
+}
@@ -0,0 +1,164 @@
+use anyhow::{anyhow, Context, Result};
+use core::fmt;
+use std::{process::Command, time::Duration};
+
+use super::{ffmpeg, sandbox, time};
+
+/// 映像と音声の長さの差がこれを超えたら警告する.
+pub const MISMATCH_THRESHOLD: Duration = Duration::from_millis(200);
+
+/// 映像と音声のストリームそれぞれの長さ. コンテナやストリームが長さを持たない場合は `None`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamDurations {
+    pub video: Option<Duration>,
+    pub audio: Option<Duration>,
+}
+
+/// 映像と音声の長さのずれ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mismatch {
+    pub video: Duration,
+    pub audio: Duration,
+}
+
+impl Mismatch {
+    pub fn diff(&self) -> Duration {
+        self.video.abs_diff(self.audio)
+    }
+
+    /// 音声が長い場合は正.
+    pub fn signed_secs(&self) -> f64 {
+        self.audio.as_secs_f64() - self.video.as_secs_f64()
+    }
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let longer = match self.audio > self.video {
+            true => "音声が映像",
+            false => "映像が音声",
+        };
+        write!(
+            f,
+            "{}より {:.3}s 長くなっています (映像: {}, 音声: {})",
+            longer,
+            self.diff().as_secs_f64(),
+            time::format_timestamp(self.video),
+            time::format_timestamp(self.audio)
+        )
+    }
+}
+
+impl StreamDurations {
+    /// 両方の長さが分かり, その差が `MISMATCH_THRESHOLD` を超える場合のずれ.
+    pub fn mismatch(&self) -> Option<Mismatch> {
+        let mismatch = Mismatch {
+            video: self.video?,
+            audio: self.audio?,
+        };
+        (mismatch.diff() > MISMATCH_THRESHOLD).then_some(mismatch)
+    }
+
+    /// 出力のずれが, 元動画のずれより `MISMATCH_THRESHOLD` を超えて大きくなった場合のずれ.
+    /// 元動画と同じだけずれているのは, エンコードによるものではないので含めない.
+    pub fn regression_from(&self, source: &StreamDurations) -> Option<Mismatch> {
+        let output = self.mismatch()?;
+        let source_diff = source.mismatch().map(|m| m.diff()).unwrap_or_default();
+        (output.diff() > source_diff + MISMATCH_THRESHOLD).then_some(output)
+    }
+}
+
+/// `ffprobe -show_entries stream=duration:stream_tags=DURATION -of csv=p=0` の 1 行目.
+/// MKV はストリームの長さを持たず, `DURATION` タグ (`00:01:40.000000000`) にだけ記録するので, その場合はタグを読む.
+pub fn parse_stream_duration(csv: &str) -> Option<Duration> {
+    let line = csv.lines().next()?.trim();
+    line.split(',').find_map(|field| match field.contains(':') {
+        true => time::parse_timestamp(field).ok(),
+        false => Duration::try_from_secs_f64(field.parse().ok()?).ok(),
+    })
+}
+
+fn probe_stream(path: &str, selector: &str) -> Result<Option<Duration>> {
+    let mut command = Command::new(ffmpeg::ffprobe_path());
+    command
+        .args(["-v", "error", "-select_streams", selector])
+        .args([
+            "-show_entries",
+            "stream=duration:stream_tags=DURATION",
+            "-of",
+            "csv=p=0",
+            path,
+        ]);
+    let output = sandbox::confine_reader(&mut command, path)
+        .and_then(|_| command.output())
+        .context("ストリームの長さの取得の実行に失敗しました.")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "ストリームの長さの取得に失敗しました: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(parse_stream_duration(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+/// `video_selector` の映像 (`V:0` など) と最初の音声の長さを調べる.
+pub fn probe(path: &str, video_selector: &str) -> Result<StreamDurations> {
+    Ok(StreamDurations {
+        video: probe_stream(path, video_selector)?,
+        audio: probe_stream(path, "a:0")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(s: f64) -> Option<Duration> {
+        Some(Duration::from_secs_f64(s))
+    }
+
+    #[test]
+    fn test_parse_stream_duration() {
+        assert_eq!(parse_stream_duration("100.023000\n"), secs(100.023));
+        assert_eq!(
+            parse_stream_duration("N/A,00:01:42.350000000\n"),
+            secs(102.35)
+        );
+        assert_eq!(parse_stream_duration("N/A\n"), None);
+        assert_eq!(parse_stream_duration(""), None);
+    }
+
+    #[test]
+    fn test_mismatch() {
+        let durations = |video, audio| StreamDurations {
+            video: secs(video),
+            audio: secs(audio),
+        };
+
+        let source = durations(100.0, 102.35);
+        let mismatch = source.mismatch().unwrap();
+        assert_eq!(mismatch.diff(), Duration::from_millis(2350));
+        assert!(mismatch.signed_secs() > 0.0);
+        assert!(mismatch.to_string().starts_with("音声が映像より 2.350s"));
+        assert_eq!(durations(100.0, 100.15).mismatch(), None);
+        assert_eq!(
+            StreamDurations {
+                video: secs(100.0),
+                audio: None
+            }
+            .mismatch(),
+            None
+        );
+
+        // 元動画と同じだけずれている出力は, エンコードによるずれではない
+        assert_eq!(durations(100.0, 102.36).regression_from(&source), None);
+        assert!(durations(100.0, 103.0).regression_from(&source).is_some());
+        assert!(durations(98.0, 100.0)
+            .regression_from(&durations(100.0, 100.0))
+            .is_some());
+        assert_eq!(durations(100.0, 100.0).regression_from(&source), None);
+    }
+}
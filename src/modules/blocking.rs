@@ -0,0 +1,130 @@
+//! tokio のランタイムを使わずに呼び出せる版の API.
+//!
+//! ffmpeg-sidecar のイベントの読み取りはもともと同期的なので, ランタイムを内部で立ち上げることはせず,
+//! 呼び出したスレッドで ffmpeg の終了まで待つ. 進捗の通知だけは別スレッドから行われる.
+//! 非同期のコードからは [`crate::video`] の関数を使うか, `tokio::task::spawn_blocking` の中で呼び出すこと.
+//!
+//! ```no_run
+//! use vvcnv::{blocking, video::{VideoConfig, VideoProcessParams, VideoRes}};
+//!
+//! fn main() -> anyhow::Result<()> {
+//!     let stat = blocking::stat("assets/2.mp4").map_err(|e| anyhow::anyhow!(e))?;
+//!     let config = VideoConfig {
+//!         res: VideoRes::R480p,
+//!         ..Default::default()
+//!     };
+//!     let outcome = blocking::process(&stat, &VideoProcessParams::new("out/480p.mp4", config))?;
+//!     println!("{:.1}s", outcome.elapsed.as_secs_f64());
+//!     Ok(())
+//! }
+//! ```
+
+use anyhow::Result;
+
+use super::video::{self, ProcessOutcome, VideoProcessParams, VideoStat, VideoStatErr};
+
+/// [`video::stat`] の同期版.
+pub fn stat(input_path: impl Into<String>) -> Result<VideoStat, VideoStatErr> {
+    video::stat_blocking(input_path.into())
+}
+
+/// [`video::process`] の同期版. 進捗は表示しない.
+pub fn process(stat: &VideoStat, params: &VideoProcessParams) -> Result<ProcessOutcome> {
+    video::process_with(stat, params, |_, _, _| {})
+}
+
+/// [`video::process_with_progress`] の同期版. `on_progress` は ffmpeg の進捗を読むスレッドと,
+/// 1 秒ごとに現在の割合を通知するスレッドの両方から呼ばれる.
+pub fn process_with_progress(
+    stat: &VideoStat,
+    params: &VideoProcessParams,
+    on_progress: impl Fn(f32) + Sync,
+) -> Result<ProcessOutcome> {
+    video::process_with_progress_blocking(stat, params, on_progress)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::{
+        testing::{self, TempDir},
+        video::{VideoConfig, VideoRes, VideoStreamInfo},
+    };
+    use std::{sync::Mutex, time::Duration};
+
+    fn source_stat() -> VideoStat {
+        VideoStat {
+            path: "assets/2.mp4".to_string(),
+            video_stream: VideoStreamInfo {
+                width: 640,
+                height: 360,
+                fps: 30.0,
+                pix_fmt: "yuv420p".to_string(),
+                ..Default::default()
+            },
+            video_stream_index: 0,
+            audio_streams: vec![],
+            cover_stream_indices: vec![],
+            ignored_stream_indices: vec![],
+            duration: Duration::from_secs(10),
+            start_time: Duration::ZERO,
+            file_size: 1_000_000,
+        }
+    }
+
+    /// ランタイムの外から呼んでも, ffmpeg を起動する前の検証エラーがそのまま返る.
+    #[test]
+    fn test_process_without_runtime() {
+        let params = VideoProcessParams::new(
+            "out/blocking.mp4",
+            VideoConfig {
+                res: VideoRes::R1080p,
+                ..Default::default()
+            },
+        );
+        assert!(process(&source_stat(), &params).is_err());
+
+        let reported = Mutex::new(Vec::new());
+        let result = process_with_progress(&source_stat(), &params, |fraction| {
+            reported.lock().unwrap().push(fraction)
+        });
+        assert!(result.is_err());
+        assert!(reported.into_inner().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_blocking_encode() {
+        if !testing::ffmpeg_tests_enabled("test_blocking_encode") {
+            return;
+        }
+        let dir = TempDir::new("blocking");
+        let source = dir.join("source.mp4").to_string_lossy().into_owned();
+        let status = std::process::Command::new(ffmpeg_sidecar::paths::ffmpeg_path())
+            .args([
+                "-y",
+                "-f",
+                "lavfi",
+                "-i",
+                "testsrc=s=640x360:r=30:d=2",
+                &source,
+            ])
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let stat = stat(source).unwrap();
+        let output = dir.join("output.mp4").to_string_lossy().into_owned();
+        let params = VideoProcessParams::new(
+            output.clone(),
+            VideoConfig {
+                res: VideoRes::R240p,
+                has_audio: false,
+                ..Default::default()
+            },
+        );
+        let last = Mutex::new(0.0);
+        process_with_progress(&stat, &params, |f| *last.lock().unwrap() = f).unwrap();
+        assert_eq!(*last.lock().unwrap(), 1.0);
+        assert!(std::fs::metadata(&output).unwrap().len() > 0);
+    }
+}
@@ -0,0 +1,547 @@
+use anyhow::{anyhow, Context, Result};
+use console::style;
+use core::fmt;
+use ffmpeg_sidecar::command::FfmpegCommand;
+use indicatif::ProgressBar;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use super::{
+    ffmpeg, file,
+    frames::FrameLog,
+    sandbox,
+    schedule::Clock,
+    size_limit,
+    video::{
+        self, build_command_with_hook, command_args, finish_command, report_to_bar, CommandHook,
+        FrameProgress, ProcessErr, ProcessOutcome, ProgressDriver, RunContext, TaskMode, Trim,
+        VideoConfig, VideoProcessParams, VideoStat, VideoStreamInfo, DEFAULT_AUDIO_BITRATE,
+    },
+};
+
+#[derive(Debug)]
+pub enum ChunkErr {
+    Probe(String),
+    NoKeyframes,
+    Mismatch {
+        index: usize,
+        expected: String,
+        actual: String,
+    },
+    Duration {
+        expected: Duration,
+        actual: Duration,
+    },
+}
+
+impl fmt::Display for ChunkErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChunkErr::Probe(e) => write!(f, "キーフレームの解析に失敗しました: {}", e),
+            ChunkErr::NoKeyframes => write!(f, "キーフレームが見つかりません"),
+            ChunkErr::Mismatch {
+                index,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "分割 {} のエンコード結果が他と一致しません (期待: {}, 実際: {})",
+                index, expected, actual
+            ),
+            ChunkErr::Duration { expected, actual } => write!(
+                f,
+                "結合後の長さが元動画と一致しません (期待: {:.3}s, 実際: {:.3}s)",
+                expected.as_secs_f64(),
+                actual.as_secs_f64()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ChunkErr {}
+
+/// キーフレームの位置を, 元動画の最初のタイムスタンプ (`start_time`) からの時間で返す.
+pub fn probe_keyframes(stat: &VideoStat) -> Result<Vec<Duration>, ChunkErr> {
+    let mut command = Command::new(ffmpeg::ffprobe_path());
+    command
+        .args(["-v", "error", "-select_streams"])
+        .arg(stat.video_selector())
+        .args([
+            "-show_entries",
+            "packet=pts_time,flags",
+            "-of",
+            "csv=p=0",
+            &stat.path,
+        ]);
+    let output = sandbox::confine_reader(&mut command, &stat.path)
+        .and_then(|_| command.output())
+        .map_err(|e| ChunkErr::Probe(e.to_string()))?;
+
+    if !output.status.success() {
+        return Err(ChunkErr::Probe(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+
+    let keyframes = parse_keyframes(&String::from_utf8_lossy(&output.stdout))
+        .into_iter()
+        .map(|k| k.saturating_sub(stat.start_time))
+        .collect::<Vec<_>>();
+    match keyframes.is_empty() {
+        true => Err(ChunkErr::NoKeyframes),
+        false => Ok(keyframes),
+    }
+}
+
+/// `ffprobe -show_entries packet=pts_time,flags -of csv=p=0` の出力 (デコード順) を, 表示順に並べる.
+pub fn parse_keyframes(csv: &str) -> Vec<Duration> {
+    let mut keyframes = csv
+        .lines()
+        .filter_map(|line| {
+            let (pts, flags) = line.trim().split_once(',')?;
+            if !flags.contains('K') {
+                return None;
+            }
+            Duration::try_from_secs_f64(pts.parse().ok()?).ok()
+        })
+        .collect::<Vec<_>>();
+    keyframes.sort();
+    keyframes.dedup();
+
+    keyframes
+}
+
+pub fn plan_chunks(
+    keyframes: &[Duration],
+    start: Duration,
+    end: Duration,
+    count: usize,
+) -> Vec<(Duration, Duration)> {
+    let length = end.saturating_sub(start);
+    let mut boundaries = vec![start];
+    for i in 1..count {
+        let target = start + length.mul_f64(i as f64 / count as f64);
+        let prev = *boundaries.last().unwrap();
+        let nearest = keyframes
+            .iter()
+            .filter(|k| **k > prev && **k < end)
+            .min_by_key(|k| k.abs_diff(target));
+        if let Some(k) = nearest {
+            boundaries.push(*k);
+        }
+    }
+    boundaries.push(end);
+
+    boundaries.windows(2).map(|w| (w[0], w[1])).collect()
+}
+
+pub fn concat_list(names: &[String]) -> String {
+    names
+        .iter()
+        .map(|name| format!("file '{}'\n", name.replace('\'', r"'\''")))
+        .collect()
+}
+
+fn signature(stream: &VideoStreamInfo) -> String {
+    format!(
+        "{}x{} {} {}fps",
+        stream.width, stream.height, stream.pix_fmt, stream.fps
+    )
+}
+
+fn work_dir(output_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}.chunks", output_path))
+}
+
+fn encode_chunks(
+    stat: &VideoStat,
+    params: &VideoProcessParams,
+    chunks: &[(Duration, Duration)],
+    jobs: usize,
+    work_dir: &Path,
+    pb: &ProgressBar,
+) -> Result<Vec<(PathBuf, Vec<String>)>> {
+    let parent = params;
+    let (_, ext) = file::get_file_name(&params.output_path);
+    let cancel = params.cancel.child();
+    let chunk_params = |index: usize| {
+        let (start, end) = chunks[index];
+        VideoProcessParams {
+            output_path: work_dir
+                .join(format!("chunk-{:03}.{}", index, ext))
+                .to_string_lossy()
+                .into_owned(),
+            config: params.config.clone(),
+            keep_cover: false,
+            drop_audio: true,
+            trim: Trim {
+                start: Some(start),
+                end: Some(end),
+                seek: params.trim.seek,
+            },
+            label: params.label.clone(),
+            faststart: false,
+            shortest: false,
+            keep_sar: params.keep_sar,
+            hwaccel: params.hwaccel,
+            cancel: cancel.clone(),
+            pause: params.pause.clone(),
+            warnings: params.warnings.clone(),
+            frames: params.frames.clone(),
+            // 上限はタスク全体の進み具合で確かめる
+            size_limit: None,
+            phases: params.phases.clone(),
+            stall_timeout: params.stall_timeout,
+            timeout: params.timeout,
+            command_hook: None,
+            log: params.log.clone(),
+        }
+    };
+
+    let progress = Mutex::new(
+        (0..chunks.len())
+            .map(|i| {
+                let params = chunk_params(i);
+                let fps = params.config.output_fps(stat);
+                (0, FrameProgress::with_fps(stat, &params.trim, fps).total)
+            })
+            .collect::<Vec<_>>(),
+    );
+    let results = Mutex::new((0..chunks.len()).map(|_| None).collect::<Vec<_>>());
+    let next = AtomicUsize::new(0);
+
+    pb.set_message(format!("エンコード中... ({} 分割)", chunks.len()));
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, chunks.len()) {
+            scope.spawn(|| loop {
+                parent.pause.wait_resumed_blocking();
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                if index >= chunks.len() || cancel.is_cancelled() {
+                    break;
+                }
+
+                let params = chunk_params(index);
+                let command = build_command_with_hook(stat, &params, parent.command_hook.as_ref());
+                let args = command_args(&command);
+                let driver = ProgressDriver::new(stat, &params, TaskMode::Encode);
+                let result = video::run(
+                    command,
+                    driver,
+                    params.run_context(),
+                    |position, length, _| {
+                        let mut progress = progress.lock().unwrap();
+                        progress[index] = (position, length);
+                        let (position, length) = progress
+                            .iter()
+                            .fold((0, 0), |(p, l), (a, b)| (p + a, l + b));
+                        pb.set_length(length);
+                        pb.set_position(position);
+                        let fraction = position as f64 / length.max(1) as f64;
+                        let bytes = parent.frames.bytes();
+                        if let Some(limit) = &parent.size_limit {
+                            limit.check(bytes, fraction);
+                        }
+                        let speed = parent
+                            .frames
+                            .speed()
+                            .map(|speed| format!(" {}", style(speed).cyan()))
+                            .unwrap_or_default();
+                        let size = size_limit::progress_note(bytes, fraction)
+                            .map(|note| format!(" {}", style(note).dim()))
+                            .unwrap_or_default();
+                        pb.set_message(format!(
+                            "エンコード中... ({} 分割){}{}",
+                            chunks.len(),
+                            speed,
+                            size
+                        ));
+                    },
+                );
+                if result.is_err() {
+                    cancel.cancel();
+                }
+
+                results.lock().unwrap()[index] =
+                    Some(result.map(|_| (PathBuf::from(&params.output_path), args)));
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    let is_cancelled =
+        |e: &anyhow::Error| matches!(e.downcast_ref::<ProcessErr>(), Some(ProcessErr::Cancelled));
+    // 他の分割を中断させた原因のエラーを優先して返す
+    if let Some(index) = results
+        .iter()
+        .position(|r| matches!(r, Some(Err(e)) if !is_cancelled(e)))
+    {
+        let e = results.swap_remove(index).unwrap().unwrap_err();
+        return Err(e.context(format!("分割 {} のエンコードに失敗しました", index)));
+    }
+
+    results
+        .into_iter()
+        .map(|r| r.unwrap_or_else(|| Err(anyhow!(ProcessErr::Cancelled))))
+        .collect()
+}
+
+fn build_audio_command(stat: &VideoStat, trim: &Trim, output_path: &Path) -> FfmpegCommand {
+    let (input_args, output_args) = trim.to_args();
+
+    let mut command = ffmpeg::command();
+    command
+        .args(input_args)
+        .input(&stat.path)
+        .args(output_args)
+        .no_video()
+        .map("0:a:0")
+        .codec_audio("aac")
+        .args(["-b:a", &DEFAULT_AUDIO_BITRATE.to_string()])
+        .output(output_path.to_string_lossy().as_ref())
+        .overwrite();
+
+    command
+}
+
+fn build_concat_command(
+    list: &Path,
+    audio: Option<&Path>,
+    output_path: &str,
+    faststart: bool,
+    shortest: bool,
+    hook: Option<&CommandHook>,
+) -> FfmpegCommand {
+    let mut command = ffmpeg::command();
+    command
+        .format("concat")
+        .args(["-safe", "0"])
+        .input(list.to_string_lossy().as_ref());
+    if let Some(audio) = audio {
+        command
+            .input(audio.to_string_lossy().as_ref())
+            .map("0:v:0")
+            .map("1:a:0");
+        if shortest {
+            command.args(["-shortest"]);
+        }
+    }
+    command.codec_video("copy").codec_audio("copy");
+    if faststart {
+        command.args(["-movflags", "+faststart"]);
+    }
+
+    finish_command(command, output_path, hook)
+}
+
+async fn encode_and_concat(
+    stat: &VideoStat,
+    params: &VideoProcessParams,
+    chunks: &[(Duration, Duration)],
+    jobs: usize,
+    work_dir: &Path,
+    pb: &ProgressBar,
+) -> Result<Vec<String>> {
+    let phase = params.phases.start("分割エンコード");
+    let outputs = encode_chunks(stat, params, chunks, jobs, work_dir, pb)?;
+    drop(phase);
+
+    let phase = params.phases.start("分割の検証");
+    let mut expected = None;
+    for (index, (path, _)) in outputs.iter().enumerate() {
+        let chunk = video::stat(path.to_string_lossy().into_owned())
+            .await
+            .map_err(|e| anyhow!(e).context(format!("分割 {} の情報取得に失敗しました", index)))?;
+        let actual = signature(&chunk.video_stream);
+        match &expected {
+            None => expected = Some(actual),
+            Some(expected) if *expected != actual => {
+                return Err(anyhow!(ChunkErr::Mismatch {
+                    index,
+                    expected: expected.clone(),
+                    actual,
+                }));
+            }
+            _ => {}
+        }
+    }
+    drop(phase);
+
+    let audio_path =
+        match !params.drop_audio && params.config.has_audio && !stat.audio_streams.is_empty() {
+            true => {
+                let _phase = params.phases.start("音声のエンコード");
+                let (_, ext) = file::get_file_name(&params.output_path);
+                let path = work_dir.join(format!("audio.{}", ext));
+                let driver = ProgressDriver::new(stat, params, TaskMode::Audio);
+                video::run(
+                    build_audio_command(stat, &params.trim, &path),
+                    driver,
+                    RunContext {
+                        frames: &FrameLog::new(),
+                        ..params.run_context()
+                    },
+                    |_, _, _| pb.set_message("音声をエンコード中..."),
+                )?;
+                Some(path)
+            }
+            false => None,
+        };
+
+    let names = outputs
+        .iter()
+        .map(|(path, _)| path.file_name().unwrap().to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    let list = work_dir.join("concat.txt");
+    fs::write(&list, concat_list(&names))
+        .with_context(|| format!("結合リストの書き込みに失敗しました: {}", list.display()))?;
+
+    let phase = params.phases.start("結合");
+    video::run(
+        build_concat_command(
+            &list,
+            audio_path.as_deref(),
+            &params.output_path,
+            params.faststart
+                && video::supports_faststart(&file::get_file_name(&params.output_path).1),
+            params.shortest,
+            params.command_hook.as_ref(),
+        ),
+        ProgressDriver::new(stat, params, TaskMode::Copy),
+        // 結合はストリームのコピーなので, フレームは各分割で数えた分だけにする
+        RunContext {
+            frames: &FrameLog::new(),
+            ..params.run_context()
+        },
+        report_to_bar(pb, "結合中..."),
+    )?;
+    drop(phase);
+
+    let _phase = params.phases.start("結合後の検証");
+    let expected = params.trim.output_duration(stat.duration);
+    let actual = video::stat(params.output_path.clone())
+        .await
+        .map_err(|e| anyhow!(e).context("結合後の動画の情報取得に失敗しました"))?
+        .duration;
+    if !video::duration_matches(expected, actual, stat.video_stream.fps) {
+        return Err(anyhow!(ChunkErr::Duration { expected, actual }));
+    }
+
+    Ok(outputs
+        .into_iter()
+        .next()
+        .map(|(_, args)| args)
+        .unwrap_or_default())
+}
+
+pub async fn process_chunked(
+    stat: VideoStat,
+    params: VideoProcessParams,
+    count: usize,
+    jobs: usize,
+    pb: ProgressBar,
+) -> Result<ProcessOutcome> {
+    if let Err(e) = VideoConfig::check_up_scaling(&params.config, &stat) {
+        return Err(anyhow!(e)).context("エンコード設定に問題があります");
+    }
+
+    let started_at = params.pause.now();
+    pb.set_message("キーフレームを解析中...");
+    let phase = params.phases.start("キーフレームの解析");
+    let keyframes = probe_keyframes(&stat)?;
+    drop(phase);
+    let start = params.trim.start.unwrap_or_default();
+    let end = start + params.trim.output_duration(stat.duration);
+    let chunks = plan_chunks(&keyframes, start, end, count);
+
+    let work_dir = work_dir(&params.output_path);
+    fs::create_dir_all(&work_dir).with_context(|| {
+        format!(
+            "作業ディレクトリの作成に失敗しました: {}",
+            work_dir.display()
+        )
+    })?;
+    let result = encode_and_concat(&stat, &params, &chunks, jobs, &work_dir, &pb).await;
+    fs::remove_dir_all(&work_dir).ok();
+
+    Ok(ProcessOutcome {
+        args: result?,
+        elapsed: params.pause.now().duration_since(started_at),
+        warnings: params.warnings.lines(),
+        frames: params.frames.counts(),
+        phases: params.phases.phases(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(s: f64) -> Duration {
+        Duration::from_secs_f64(s)
+    }
+
+    #[test]
+    fn test_parse_keyframes() {
+        let csv = "0.000000,K__\n0.033333,___\n4.000000,K_\n2.000000,K__\n-0.040000,K__\nN/A,K__\n";
+        assert_eq!(parse_keyframes(csv), vec![secs(0.0), secs(2.0), secs(4.0)]);
+    }
+
+    #[test]
+    fn test_plan_chunks() {
+        let keyframes = (0..30).map(|i| secs(i as f64 * 2.0)).collect::<Vec<_>>();
+
+        let chunks = plan_chunks(&keyframes, secs(0.0), secs(60.0), 4);
+        assert_eq!(
+            chunks,
+            vec![
+                (secs(0.0), secs(14.0)),
+                (secs(14.0), secs(30.0)),
+                (secs(30.0), secs(44.0)),
+                (secs(44.0), secs(60.0)),
+            ]
+        );
+
+        let chunks = plan_chunks(&keyframes, secs(10.0), secs(20.0), 2);
+        assert_eq!(
+            chunks,
+            vec![(secs(10.0), secs(14.0)), (secs(14.0), secs(20.0))]
+        );
+
+        let chunks = plan_chunks(&[secs(0.0)], secs(0.0), secs(60.0), 4);
+        assert_eq!(chunks, vec![(secs(0.0), secs(60.0))]);
+    }
+
+    #[test]
+    fn test_concat_list() {
+        assert_eq!(
+            concat_list(&["chunk-000.mp4".to_string(), "it's.mp4".to_string()]),
+            "file 'chunk-000.mp4'\nfile 'it'\\''s.mp4'\n"
+        );
+    }
+
+    #[test]
+    fn test_concat_command() {
+        let args = command_args(&build_concat_command(
+            Path::new("out/a.chunks/concat.txt"),
+            Some(Path::new("out/a.chunks/audio.mp4")),
+            "out/a.mp4",
+            false,
+            true,
+            None,
+        ));
+        let position = |arg: &str| args.iter().position(|a| a == arg).unwrap();
+        assert!(position("concat") < position("out/a.chunks/concat.txt"));
+        assert!(args.windows(2).any(|w| w == ["-map", "1:a:0"]));
+        assert!(args.windows(2).any(|w| w == ["-c:v", "copy"]));
+        assert!(args.contains(&"-shortest".to_string()));
+    }
+}
@@ -0,0 +1,1319 @@
+use anyhow::Context;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use core::fmt;
+use serde::{Deserialize, Serialize};
+use std::{io::IsTerminal, path::PathBuf, time::Duration};
+
+use super::{
+    ab::{self, Variant},
+    codec_params::{self, CodecParam},
+    config::{self, ConfigLayer},
+    hwaccel::HwAccel,
+    input::{self, ExtFilter, WalkOptions},
+    integrity, logs,
+    matrix::{self, ConfigSource},
+    matrix_file::MatrixFile,
+    naming::{self, NameTemplate, Placeholder},
+    presets::{self, ConfigEntry},
+    quality::QualityCalibration,
+    report::TaskOptions,
+    sampling::{self, WindowSpec},
+    thumbnail::{self, ThumbnailMode},
+    time,
+    verbosity::Verbosity,
+    video::{self, FpsMode, SeekMode, Trim, VideoCodec, VideoConfig, VideoRes},
+    workspace::{self, CleanFilter, Workspace},
+};
+
+/// `--fps` を指定しない場合の FPS.
+pub const DEFAULT_FPS: u32 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SkipIfBetterMode {
+    Skip,
+    Copy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LabelOverlayMode {
+    Preview,
+    All,
+}
+
+/// `--calibrate` で使う画質の指標.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QualityMetric {
+    /// SSIM を 100 倍した値.
+    #[default]
+    Ssim,
+    /// VMAF. libvmaf を有効にした ffmpeg が必要.
+    Vmaf,
+}
+
+impl fmt::Display for QualityMetric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QualityMetric::Ssim => write!(f, "SSIM"),
+            QualityMetric::Vmaf => write!(f, "VMAF"),
+        }
+    }
+}
+
+/// 出力の置き方.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputLayout {
+    /// 出力先の直下に, 設定を含めた名前で置く.
+    #[default]
+    Flat,
+    /// 設定ごとのディレクトリ (`res-<W>x<H>--fps-<FPS>--crf-<CRF>/`) に, 元の名前のまま置く.
+    PerConfig,
+}
+
+/// 進捗の表示方法.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ProgressMode {
+    /// 標準エラー出力が端末なら進捗バー, そうでなければ 1 行ずつの表示にする.
+    #[default]
+    Auto,
+    Bars,
+    /// 各タスクの進捗を数秒ごとに 1 行で書き出す. nohup や CI のログ向け.
+    Plain,
+}
+
+#[derive(Debug, Clone, Parser)]
+#[command(
+    version,
+    about,
+    group = clap::ArgGroup::new("sampling").args(["estimate_time", "calibrate"]).multiple(true),
+    after_help = "実行中に SIGUSR1 を送ると一時停止/再開します (例: kill -USR1 <PID>). 一時停止中は新しいタスクを開始せず, 実行中の ffmpeg も止めます.\nWindows では実行中の ffmpeg を止められないため, 一時停止の機能はありません.\n\n終了コード: 0 すべての設定が成功, 2 一部の設定が失敗, 1 すべての設定が失敗または開始前のエラー (入力の誤り, 情報取得の失敗など), 3 失敗はないが時間制限 (--max-runtime / --deadline) で完了しなかった設定がある, 130 Ctrl+C で中断."
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// 入力ファイル (複数指定できる. 同じ組み合わせを入力ごとにエンコードする)
+    #[arg(value_name = "INPUT")]
+    pub inputs: Vec<String>,
+
+    /// 元動画が設定と同等以上の場合に, エンコードをスキップ (skip) または再エンコードせずにコピー (copy) する
+    #[arg(
+        long,
+        value_enum,
+        value_name = "MODE",
+        num_args = 0..=1,
+        default_missing_value = "skip"
+    )]
+    pub skip_if_better: Option<SkipIfBetterMode>,
+
+    /// カバーアート (attached pic) を出力に保持する
+    #[arg(long)]
+    pub keep_cover: bool,
+
+    /// 全体の実行時間の上限. 超えそうな場合は新しいタスクを開始しない (例: 2h, 90m)
+    #[arg(long, value_name = "DURATION", value_parser = time::parse_duration)]
+    pub max_runtime: Option<Duration>,
+
+    /// 全体の実行時間の上限. 超えた時点で実行中のタスクも中断する (例: 2h, 90m)
+    #[arg(long, value_name = "DURATION", value_parser = time::parse_duration)]
+    pub deadline: Option<Duration>,
+
+    /// フレーム数がこの時間増えないタスクを, 停止したとみなして中止する. 長いエンコードでも進んでいる間は中止しない.
+    /// 1080p を超える解像度では画素数に比例して延ばす (例: 600, 10m)
+    #[arg(long, value_name = "DURATION", value_parser = time::parse_duration)]
+    pub stall_timeout: Option<Duration>,
+
+    /// 1 回のエンコードの時間の上限. 超えたタスクは ffmpeg を終了させて失敗にし, 残りのタスクは続ける.
+    /// 分割エンコードでは分割ごとに数える (例: 30m, 2h)
+    #[arg(long, value_name = "DURATION", value_parser = time::parse_duration)]
+    pub timeout: Option<Duration>,
+
+    /// ffmpeg が実行中に失敗したタスクを, 途中までの出力を削除してこの回数まで再試行する.
+    /// 待ち時間は 5 秒から始めて 1 回ごとに倍にする. 設定の誤りなど, 繰り返しても同じ結果になる失敗は再試行しない
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub retries: u32,
+
+    /// 最初にタスクが失敗した時点で, 実行中のタスクを中止し, 残りのタスクと入力を処理せずに終了する.
+    /// 再試行するタスクは, すべての試行が失敗した時点で数える
+    #[arg(long)]
+    pub fail_fast: bool,
+
+    /// 出力のピクセルフォーマット (例: yuv420p10le)
+    #[arg(long)]
+    pub pix_fmt: Option<String>,
+
+    /// 元動画のアルファチャンネル (透過) を保持する. 出力は .webm/.mkv (VP9) か .mov (ProRes 4444) に限られる
+    #[arg(long, conflicts_with = "stream_to")]
+    pub keep_alpha: bool,
+
+    /// エンコーダーのプロファイル (例: high10, main10)
+    #[arg(long)]
+    pub profile: Option<String>,
+
+    /// AV1 のフィルムグレインの合成の強さ (0〜50. libsvtav1 / libaom-av1 のみ)
+    #[arg(long, value_name = "LEVEL", value_parser = clap::value_parser!(u32).range(0..=codec_params::MAX_FILM_GRAIN as i64))]
+    pub av1_film_grain: Option<u32>,
+
+    /// エンコーダー固有のパラメーター (例: aq-mode=3). 複数回指定でき, -x264-params / -x265-params / -svtav1-params / -aom-params にまとめて渡す
+    #[arg(long, value_name = "KEY=VALUE", value_parser = codec_params::parse_codec_param)]
+    pub codec_param: Vec<CodecParam>,
+
+    /// FPS を下げるときのフレームの作り方. drop は間引く (fps), blend は前後のフレームを混ぜる (framerate), mci は動きを補間する (minterpolate, 非常に遅い)
+    #[arg(long, value_name = "MODE", value_parser = clap::value_parser!(FpsMode))]
+    pub fps_mode: Option<FpsMode>,
+
+    /// エンコーダーがピクセルフォーマット/プロファイルを拒否した場合に, 近い設定で一度だけ再試行する
+    #[arg(long)]
+    pub auto_fallback: bool,
+
+    /// 切り出しの開始位置 (例: 90, 1:30, 00:01:30.250)
+    #[arg(long, value_name = "TIMESTAMP", value_parser = time::parse_timestamp)]
+    pub start: Option<Duration>,
+
+    /// 切り出しの終了位置 (例: 120, 2:00, 00:02:00.000)
+    #[arg(long, value_name = "TIMESTAMP", value_parser = time::parse_timestamp)]
+    pub end: Option<Duration>,
+
+    /// 開始位置までをすべてデコードして破棄し, フレーム単位で正確に切り出す (低速).
+    /// 指定しない場合は開始位置の数秒前まで高速シークし, 残りをデコードして切り出す
+    #[arg(long)]
+    pub accurate_seek: bool,
+
+    /// 中央付近の指定した長さだけをエンコードし, 全体のサイズを推定する (例: 10s)
+    #[arg(long, value_name = "DURATION", value_parser = time::parse_duration)]
+    pub sample: Option<Duration>,
+
+    /// サンプルエンコードで音声を残す (既定では映像の品質確認のため音声を除く)
+    #[arg(long, requires = "sample")]
+    pub sample_audio: bool,
+
+    /// ファイルに書き出す代わりに RTMP/SRT で配信する (例: rtmp://localhost/live/test)
+    #[arg(
+        long,
+        value_name = "URL",
+        value_parser = parse_stream_url,
+        conflicts_with_all = ["skip_if_better", "sample"]
+    )]
+    pub stream_to: Option<String>,
+
+    /// 解像度 (カンマ区切りで複数指定できる. 例: 720p,1920x1080). 指定しない場合は 240p〜4320p の 16:9 の解像度すべて
+    #[arg(long, value_name = "RES", value_delimiter = ',', value_parser = matrix::parse_res)]
+    pub res: Vec<VideoRes>,
+
+    /// FPS (カンマ区切りで複数指定できる). 指定しない場合は 30
+    #[arg(long, value_name = "FPS", value_delimiter = ',', value_parser = clap::value_parser!(u32).range(1..))]
+    pub fps: Vec<u32>,
+
+    /// CRF (カンマ区切りで複数指定できる). 指定しない場合はコーデックごとの既定値 (x264: 23, x265: 28, VP9: 32, AV1: 30)
+    #[arg(long, value_name = "CRF", value_delimiter = ',')]
+    pub crf: Vec<u32>,
+
+    /// コーデック (カンマ区切りで複数指定できる. 例: h264,h265,vp9,av1). 指定しない場合は出力形式の既定のエンコーダー
+    #[arg(long, value_name = "CODEC", value_delimiter = ',', value_parser = matrix::parse_codec)]
+    pub codec: Vec<VideoCodec>,
+
+    /// ハードウェアのエンコーダーを使う (auto / nvenc / videotoolbox / qsv / vaapi / none). CRF は各エンコーダーの品質の指定 (-cq / -q:v など) に置き換える. 指定しない場合は設定の hwaccel
+    #[arg(long, value_name = "MODE", value_parser = clap::value_parser!(HwAccel))]
+    pub hwaccel: Option<HwAccel>,
+
+    /// 解像度 / FPS / CRF / 音声の有無を書いた設定ファイル (雛形は vvcnv init で作成される). --res / --fps / --crf を指定した項目はそちらを優先する
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// `--config` から読み込んだ組み合わせ.
+    #[arg(skip)]
+    pub matrix_file: MatrixFile,
+
+    /// 名前の付いた設定の組を使う (例: chat,archive). 組み込みは chat / archive / preview で, --config のファイルで追加できる. --res / --fps / --crf などを指定した項目はプリセットより優先する
+    #[arg(long, value_name = "NAME", num_args = 1.., value_delimiter = ',')]
+    pub preset: Vec<String>,
+
+    /// 出力のファイル名にプリセット名を含める
+    #[arg(long, requires = "preset")]
+    pub name_preset: bool,
+
+    /// 出力のファイル名の形式 (例: "{stem}_{height}p_crf{crf}.{ext}"). 使える値は {stem}, {ext}, {width}, {height}, {fps}, {crf}, {preset}. 指定しない場合は <元の名前>--res-<幅>x<高さ>--fps-<FPS>--crf-<CRF>.<拡張子>
+    #[arg(long, value_name = "TEMPLATE", value_parser = clap::value_parser!(NameTemplate), conflicts_with = "stream_to")]
+    pub name_template: Option<NameTemplate>,
+
+    /// 組み合わせを作らずに, この設定をそのまま加える (複数回指定できる. 例: "720p,30,crf=28,codec=h265"). 名前のない値は解像度, FPS, CRF の順
+    #[arg(long, value_name = "SPEC", value_parser = presets::parse_entry)]
+    pub add_config: Vec<ConfigEntry>,
+
+    /// 組み合わせの中で最も高い解像度から 1 段下がるごとに CRF に加える値 (例: 2)
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 0,
+        allow_negative_numbers = true
+    )]
+    pub crf_offset_per_rung: i32,
+
+    /// 解像度ごとに CRF に加える値 (カンマ区切りで複数指定できる. 例: 240p=6,1080p=0). --crf-offset-per-rung より優先する
+    #[arg(long, value_name = "RES=OFFSET", value_delimiter = ',', value_parser = matrix::parse_crf_offset)]
+    pub crf_offset: Vec<(VideoRes, i32)>,
+
+    /// 解像度 / FPS / CRF の組み合わせ数の上限
+    #[arg(long, value_name = "N", default_value_t = matrix::DEFAULT_MATRIX_LIMIT)]
+    pub max_combinations: usize,
+
+    /// 組み合わせ数が上限を超えていても実行する
+    #[arg(long)]
+    pub yes_really: bool,
+
+    /// 出力先に完成した出力がすでにある設定はエンコードしない. 空のファイルや途中で止まったファイルは作り直す
+    #[arg(long, conflicts_with = "stream_to", overrides_with = "overwrite")]
+    pub skip_existing: bool,
+
+    /// 出力先にあるファイルを上書きする. 指定しない場合, 出力先にファイルがあるタスクは失敗にする.
+    /// 先に指定した --skip-existing を打ち消す
+    #[arg(long, visible_alias = "force", overrides_with = "skip_existing")]
+    pub overwrite: bool,
+
+    /// エンコードせず, 設定ごとの出力先と ffmpeg の引数を表示して終了する
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// 出力形式と設定の互換性チェックを行わない
+    #[arg(long)]
+    pub no_compat_checks: bool,
+
+    /// 過去の実行結果がない場合は 5 秒のサンプルをエンコードして計測し, 全体の所要時間を予測する
+    #[arg(long, conflicts_with = "stream_to")]
+    pub estimate_time: bool,
+
+    /// 計測 (--estimate-time / --calibrate) に使うサンプルの数と長さ (例: 5x10s). 位置は先頭と末尾の 5% を除いた範囲から, 重ならないように選ぶ
+    #[arg(long, value_name = "NxDURATION", value_parser = sampling::parse_spec, requires = "sampling")]
+    pub sample_windows: Option<WindowSpec>,
+
+    /// サンプルの位置を選ぶシード. 指定しない場合は入力の内容から求めるので, 同じ入力では同じ位置になる
+    #[arg(long, value_name = "SEED", requires = "sampling")]
+    pub sample_seed: Option<u64>,
+
+    /// 元動画の短いサンプルを CRF 20 / 28 / 36 でエンコードして画質を計測し, この入力での画質と CRF の関係を求める.
+    /// 結果は出力先に保存し, 同じ入力では次回から計測を省く
+    #[arg(long, conflicts_with = "stream_to")]
+    pub calibrate: bool,
+
+    /// 目標の画質 (0〜100). --calibrate で求めた関係から, この画質を下回らない最大の CRF を選んですべての組み合わせに使う
+    #[arg(
+        long,
+        value_name = "SCORE",
+        value_parser = parse_quality,
+        requires = "calibrate",
+        conflicts_with = "crf"
+    )]
+    pub quality: Option<f64>,
+
+    /// --calibrate で使う画質の指標
+    #[arg(long, value_enum, value_name = "METRIC", default_value_t)]
+    pub quality_metric: QualityMetric,
+
+    /// --calibrate で計測した結果. 設定ファイル (*.vvcnv.json) と実行の記録に残す
+    #[arg(skip)]
+    pub quality_calibration: Vec<QualityCalibration>,
+
+    /// 実行結果を履歴に保存しない
+    #[arg(long)]
+    pub no_history: bool,
+
+    /// タスクごとの ffmpeg のコマンドとログを, 出力先の logs/<出力の名前>.log (ワークスペースでは <ワークスペース>/logs/) に書き出さない
+    #[arg(long)]
+    pub no_logs: bool,
+
+    /// 残すタスクのログの数. タスクが終わるたびに, 超えた分を古いものから削除する (今回失敗したタスクのログは残す).
+    /// 設定の `log-retention`
+    #[arg(long, value_name = "N")]
+    pub log_retention: Option<usize>,
+
+    /// タスクのログの合計の大きさの上限 (例: 500MB, 2GiB). 超えた分を古いものから削除する. 設定の `log-max-size`
+    #[arg(long, value_name = "SIZE", value_parser = logs::parse_size)]
+    pub log_max_size: Option<u64>,
+
+    /// すべてのタスクの結果 (設定, 出力のパスと大きさ, 時間, 成否) を JSON で標準出力に書き出す.
+    /// 進捗や表示は標準エラー出力に送る
+    #[arg(long)]
+    pub json: bool,
+
+    /// 設定ごとの結果 (解像度, FPS, CRF, 出力の大きさと元動画に対する比率, 時間, 成否) を CSV に書き出す.
+    /// 失敗したタスクも含め, 入力を 1 つ処理するごとに書き直す
+    #[arg(long, value_name = "PATH")]
+    pub csv: Option<PathBuf>,
+
+    /// 元動画の情報と, 並べ替えられる結果の表を出力先の report.html に書き出す. CSS と JavaScript も含めた 1 ファイルにする
+    #[arg(long)]
+    pub html: bool,
+
+    /// エンコード中の出力が元動画の大きさ × FACTOR (既定: 1.0) を超える見込みになったら, そのタスクを中止する
+    #[arg(
+        long,
+        value_name = "FACTOR",
+        num_args = 0..=1,
+        default_missing_value = "1.0",
+        value_parser = parse_size_factor,
+        conflicts_with = "stream_to"
+    )]
+    pub abort_if_larger: Option<f64>,
+
+    /// 以前の実行で同じ入力と設定から作った出力が残っていても, 使わずにエンコードし直す
+    #[arg(long)]
+    pub no_reuse: bool,
+
+    /// 結果の表示を詳しくする. 組み合わせごとの段階 (解析, エンコード, 確認など) の時間の内訳と ffmpeg の情報のログを表示する.
+    /// -vv では ffmpeg が出力したすべてのイベントも表示する
+    #[arg(short, long, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    pub verbose: u8,
+
+    /// 警告を表示せず, 進捗と結果だけを表示する
+    #[arg(short, long)]
+    pub quiet: bool,
+
+    /// 元動画に音声がないのに音声を含める設定の場合に, エラーにする. 指定しなければその入力だけ音声を含めずにエンコードする
+    #[arg(long)]
+    pub strict_audio: bool,
+
+    /// 複数の入力を処理する場合に, 入力の見出しだけを表示し, 実行中の入力だけタスクごとの進捗を広げる.
+    /// 終わった入力は結果をまとめた 1 行にする
+    #[arg(long)]
+    pub collapse_groups: bool,
+
+    /// 進捗の表示方法
+    #[arg(long, value_enum, value_name = "MODE", default_value_t = ProgressMode::Auto)]
+    pub progress: ProgressMode,
+
+    /// 出力ごとに再実行用の設定ファイル (<出力名>.vvcnv.json) を書き出す
+    #[arg(long, conflicts_with = "stream_to")]
+    pub sidecars: bool,
+
+    /// 出力を見比べるためのサムネイルを out/thumbnails に書き出す. scene:N で元動画のシーンの切り替わりを N 個選び, すべての出力から同じ時刻のフレームを切り出す
+    #[arg(
+        long,
+        value_name = "MODE",
+        value_parser = thumbnail::parse_mode,
+        conflicts_with = "stream_to"
+    )]
+    pub thumbnails: Option<ThumbnailMode>,
+
+    /// 設定 (解像度 / FPS / CRF) を映像の左上に描画する. 既定ではサンプルと配信のみ (preview), all で全出力に描画する
+    #[arg(
+        long,
+        value_enum,
+        value_name = "MODE",
+        num_args = 0..=1,
+        default_missing_value = "preview"
+    )]
+    pub label_overlay: Option<LabelOverlayMode>,
+
+    /// 1 つの動画をキーフレーム位置で N 個に分割して並列にエンコードし, 結合する
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(2..),
+        conflicts_with_all = ["stream_to", "sample", "keep_cover"]
+    )]
+    pub chunked: Option<u32>,
+
+    /// 同時に実行する ffmpeg の数. 残りのタスクは順番を待つ. 設定の jobs より優先する.
+    /// どちらも指定しない場合は, 解像度ごとの重みの合計が CPU の数を超えない範囲で自動で並べる
+    #[arg(
+        short,
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub jobs: Option<u32>,
+
+    /// MP4/MOV の出力で moov atom を先頭に移動し, ダウンロード途中から再生できるようにする
+    #[arg(long, conflicts_with = "stream_to")]
+    pub faststart: bool,
+
+    /// 入力と同じディレクトリの checksums.txt と照合するときに, ハッシュを求める速さの上限 (MiB/s, 0 で無制限)
+    #[arg(long, value_name = "MIB", default_value_t = integrity::DEFAULT_RATE_MIB)]
+    pub hash_rate_limit: u64,
+
+    /// (Linux のみ) ffmpeg をネットワークから切り離し, 入力の読み込みと出力先への書き込み以外のファイルへのアクセスを禁止し,
+    /// CPU 時間・メモリ・ファイルの大きさを制限して実行する. 信頼できない入力を扱う場合に指定する
+    #[arg(long, conflicts_with = "stream_to")]
+    pub sandbox: bool,
+
+    /// 映像と音声の長さが異なる場合に, 短い方に合わせて出力を切る (ffmpeg の -shortest)
+    #[arg(long)]
+    pub shortest: bool,
+
+    /// 画素が正方形でない元動画 (DV など) の SAR を出力でも保つ. 指定しない場合は画素を正方形にし, 表示上の大きさで出力する
+    #[arg(long)]
+    pub keep_sar: bool,
+
+    /// 出力先のディレクトリ (無ければ作成する). 指定しない場合はワークスペースの out/, ワークスペースの外ではカレントディレクトリの out/
+    #[arg(long = "out-dir", value_name = "DIR", conflicts_with = "stream_to")]
+    pub output_dir: Option<PathBuf>,
+
+    /// 出力の置き方. per-config では設定ごとのディレクトリ (out/res-1280x720--fps-30--crf-28/) に元のファイル名のまま書き出す.
+    /// 入力のサブディレクトリは設定のディレクトリの中に作るので, 1 つの設定の出力だけをまとめて rsync できる
+    #[arg(
+        long,
+        visible_alias = "out-layout",
+        value_enum,
+        value_name = "LAYOUT",
+        default_value_t,
+        conflicts_with = "stream_to"
+    )]
+    pub layout: OutputLayout,
+
+    /// 出力のファイル名を ASCII だけにする. 記号付きのラテン文字は記号を外し, それ以外の文字 (日本語や絵文字など) は _ にまとめる
+    #[arg(long, conflicts_with = "stream_to")]
+    pub ascii_names: bool,
+
+    /// すべてのタスクが成功した入力の出力を, 実行の最後にまとめてこのディレクトリに移動する. 出力先からの相対パス (設定ごとのディレクトリなど) は保つ
+    #[arg(long, value_name = "DIR", conflicts_with = "stream_to")]
+    pub publish_dir: Option<PathBuf>,
+
+    /// 公開先が別のファイルシステムでコピーが必要な場合に, 大きさに加えて内容 (sha256) も確かめてから元のファイルを消す
+    #[arg(long, requires = "publish_dir")]
+    pub publish_verify_hash: bool,
+
+    /// 動画として扱う拡張子を追加する (例: ts,m2ts)
+    #[arg(long, value_name = "EXT", value_delimiter = ',')]
+    pub include_ext: Vec<String>,
+
+    /// 動画として扱わない拡張子 (例: webm)
+    #[arg(long, value_name = "EXT", value_delimiter = ',')]
+    pub exclude_ext: Vec<String>,
+
+    /// ディレクトリを入力に指定した場合に, 中から拾う拡張子
+    #[arg(long, value_name = "EXT", value_delimiter = ',', default_values = input::DIR_EXTENSIONS)]
+    pub dir_ext: Vec<String>,
+
+    /// ディレクトリを入力に指定した場合に, たどるサブディレクトリの深さ (0 で直下のファイルのみ). 指定しない場合は制限しない
+    #[arg(long, value_name = "N")]
+    pub max_depth: Option<usize>,
+
+    /// ディレクトリを入力に指定した場合に, シンボリックリンクもたどる (既定では無視する)
+    #[arg(long)]
+    pub follow_symlinks: bool,
+
+    /// 入力ごとの出力先の中のサブディレクトリ. ディレクトリを入力に指定した場合に, その構造を出力先に再現する
+    #[arg(skip)]
+    pub out_subdir: PathBuf,
+
+    /// ラベルの描画に使うフォントファイル (指定しない場合はシステムのフォントを探す)
+    #[arg(long, value_name = "PATH", requires = "label_overlay")]
+    pub label_font: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// 設定ファイル (*.vvcnv.json) を読み込み, 同じ設定で再エンコードする
+    Rerun(RerunArgs),
+
+    /// これまでの実行履歴を表示する
+    History(HistoryArgs),
+
+    /// 古い命名規則の出力ファイルを, 現在の命名規則の名前に付け直す (既定では変更内容を表示するだけ)
+    Migrate(MigrateArgs),
+
+    /// カレントディレクトリをワークスペースにする (out/, logs/, 記録ファイル, vvcnv.toml, vvcnv.matrix.toml を作成する)
+    Init,
+
+    /// ワークスペースに記録された出力・ログ・一時ファイルを削除する (既定では削除するファイルを表示するだけ)
+    Clean(CleanArgs),
+
+    /// 字幕を扱う
+    Subs(SubsArgs),
+
+    /// 2 つの設定で同じサンプルを繰り返しエンコードし, 所要時間と出力サイズを比べる
+    Ab(AbArgs),
+
+    /// 設定 (グローバル設定 → プロジェクト設定 → 環境変数の順に重ねたもの) を扱う
+    Config(ConfigArgs),
+
+    /// 実行結果のレポートを扱う
+    Report(ReportArgs),
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ReportArgs {
+    #[command(subcommand)]
+    pub action: ReportAction,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum ReportAction {
+    /// 2 つの実行結果を, 入力と設定が同じタスクごとに比べる. 履歴 (history.jsonl) の 1 行もそのまま読める
+    Diff {
+        /// 比べる元のレポート
+        old: PathBuf,
+
+        /// 比べる先のレポート
+        new: PathBuf,
+
+        /// 対応付けで無視する設定の項目 (例: preset). 指定しない場合は, 2 つの実行で値が 1 つも重ならない項目を無視する
+        #[arg(long, value_name = "FIELD", value_delimiter = ',')]
+        ignore: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ConfigArgs {
+    #[command(subcommand)]
+    pub action: ConfigAction,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum ConfigAction {
+    /// 実際に使われる設定の値を表示する
+    Show {
+        /// それぞれの値をどこで指定したかも表示する
+        #[arg(long)]
+        origin: bool,
+    },
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct AbArgs {
+    /// 入力ファイル
+    #[arg(value_name = "INPUT")]
+    pub input: String,
+
+    /// 比較する設定 A (例: crf=24,preset=medium). 指定しなかった項目は元動画の解像度・FPS とエンコーダーの既定値になる
+    #[arg(long, value_name = "SETTINGS", value_parser = ab::parse_variant)]
+    pub a: Variant,
+
+    /// 比較する設定 B (例: crf=26,preset=slow)
+    #[arg(long, value_name = "SETTINGS", value_parser = ab::parse_variant)]
+    pub b: Variant,
+
+    /// それぞれの設定をエンコードする回数
+    #[arg(long, value_name = "N", default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    pub repeat: u32,
+
+    /// サンプルの長さ
+    #[arg(long, value_name = "DURATION", default_value = "10s", value_parser = time::parse_duration)]
+    pub length: Duration,
+
+    /// サンプルの位置を選ぶシード. 指定しない場合は入力の内容から求める
+    #[arg(long, value_name = "SEED")]
+    pub sample_seed: Option<u64>,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct SubsArgs {
+    #[command(subcommand)]
+    pub action: SubsAction,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum SubsAction {
+    /// 埋め込まれた字幕を一覧し, 指定したものを .srt / .ass (画像の字幕は元の形式) に書き出す
+    Extract {
+        /// 入力ファイル
+        #[arg(value_name = "INPUT")]
+        input: String,
+
+        /// 書き出す字幕の番号 (一覧の # の値. カンマ区切りで複数指定できる)
+        #[arg(long, value_name = "N", value_delimiter = ',', conflicts_with = "all")]
+        track: Vec<usize>,
+
+        /// すべての字幕を書き出す
+        #[arg(long)]
+        all: bool,
+    },
+}
+
+#[derive(Debug, Clone, Args)]
+#[group(required = true, multiple = false)]
+pub struct CleanFilterArgs {
+    /// 失敗したタスクのファイルだけを削除する
+    #[arg(long)]
+    pub failed_only: bool,
+
+    /// 記録してから指定した時間が経ったファイルだけを削除する (例: 30d)
+    #[arg(long, value_name = "DURATION", value_parser = time::parse_duration)]
+    pub older_than: Option<Duration>,
+
+    /// 記録されたすべてのファイルを削除する
+    #[arg(long)]
+    pub all: bool,
+}
+
+impl CleanFilterArgs {
+    pub fn filter(&self) -> CleanFilter {
+        match (self.failed_only, self.older_than) {
+            (true, _) => CleanFilter::FailedOnly,
+            (false, Some(age)) => CleanFilter::OlderThan(age),
+            (false, None) => CleanFilter::All,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct CleanArgs {
+    #[command(flatten)]
+    pub filter: CleanFilterArgs,
+
+    /// 実際に削除する
+    #[arg(long)]
+    pub apply: bool,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct MigrateArgs {
+    /// 出力ディレクトリ
+    #[arg(value_name = "DIR")]
+    pub dir: PathBuf,
+
+    /// 実際に名前を変更する
+    #[arg(long)]
+    pub apply: bool,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct HistoryArgs {
+    #[command(subcommand)]
+    pub action: Option<HistoryAction>,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum HistoryAction {
+    /// 指定した実行の結果を表示する
+    Show {
+        /// 履歴の ID (`vvcnv history` で確認できる)
+        id: u64,
+    },
+
+    /// 古い履歴を削除する
+    Prune {
+        /// 残す件数
+        #[arg(long, default_value_t = 50)]
+        keep: usize,
+    },
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct RerunArgs {
+    /// 再実行する設定ファイル
+    #[arg(value_name = "SIDECAR")]
+    pub sidecar: PathBuf,
+
+    /// 解像度を上書きする (例: 1280x720)
+    #[arg(long)]
+    pub res: Option<VideoRes>,
+
+    /// FPS を上書きする
+    #[arg(long)]
+    pub fps: Option<u32>,
+
+    /// CRF を上書きする
+    #[arg(long)]
+    pub crf: Option<u32>,
+}
+
+impl RerunArgs {
+    pub fn apply(&self, config: VideoConfig) -> VideoConfig {
+        VideoConfig {
+            res: self.res.clone().unwrap_or(config.res),
+            fps: self.fps.unwrap_or(config.fps),
+            crf: self.crf.unwrap_or(config.crf),
+            ..config
+        }
+    }
+}
+
+fn parse_stream_url(input: &str) -> Result<String, String> {
+    if video::is_stream_url(input) {
+        Ok(input.to_string())
+    } else {
+        Err(format!(
+            "配信先の URL が不正です: \"{}\" (rtmp://, rtmps://, srt://, udp:// に対応しています)",
+            input
+        ))
+    }
+}
+
+fn parse_quality(input: &str) -> Result<f64, String> {
+    match input.parse::<f64>() {
+        Ok(score) if (0.0..=100.0).contains(&score) => Ok(score),
+        _ => Err(format!(
+            "画質は 0〜100 の数で指定してください: \"{}\" (例: 90, 95.5)",
+            input
+        )),
+    }
+}
+
+fn parse_size_factor(input: &str) -> Result<f64, String> {
+    match input.parse::<f64>() {
+        Ok(factor) if factor.is_finite() && factor > 0.0 => Ok(factor),
+        _ => Err(format!(
+            "倍率は正の数で指定してください: \"{}\" (例: 1.0, 0.8)",
+            input
+        )),
+    }
+}
+
+impl Cli {
+    /// 進捗を 1 行ずつ書き出すかどうか.
+    pub fn plain_progress(&self) -> bool {
+        match self.progress {
+            ProgressMode::Auto => !std::io::stderr().is_terminal(),
+            ProgressMode::Bars => false,
+            ProgressMode::Plain => true,
+        }
+    }
+
+    pub fn verbosity(&self) -> Verbosity {
+        Verbosity::new(self.quiet, self.verbose)
+    }
+
+    pub fn progress_unit(&self) -> &'static str {
+        match self.stream_to {
+            Some(_) => "s",
+            None => "fr",
+        }
+    }
+
+    pub fn is_preview(&self) -> bool {
+        self.sample.is_some() || self.stream_to.is_some()
+    }
+
+    pub fn wants_label(&self) -> bool {
+        match self.label_overlay {
+            Some(LabelOverlayMode::All) => true,
+            Some(LabelOverlayMode::Preview) => self.is_preview(),
+            None => false,
+        }
+    }
+
+    pub fn matrix_limit(&self) -> Option<usize> {
+        match self.yes_really {
+            true => None,
+            false => Some(self.max_combinations),
+        }
+    }
+
+    /// `--res` の解像度. 指定しない場合は設定ファイルの値, それもない場合は 16:9 の解像度すべて.
+    pub fn res_list(&self) -> Vec<VideoRes> {
+        match (self.res.is_empty(), &self.matrix_file.res) {
+            (false, _) => self.res.clone(),
+            (true, Some(res)) => res.clone(),
+            (true, None) => VideoRes::list169(),
+        }
+    }
+
+    pub fn fps_list(&self) -> Vec<u32> {
+        match (self.fps.is_empty(), &self.matrix_file.fps) {
+            (false, _) => self.fps.clone(),
+            (true, Some(fps)) => fps.clone(),
+            (true, None) => vec![DEFAULT_FPS],
+        }
+    }
+
+    /// 空の場合はコーデックの既定値を使う.
+    pub fn crf_list(&self) -> Vec<u32> {
+        match (self.crf.is_empty(), &self.matrix_file.crf) {
+            (true, Some(crf)) => crf.clone(),
+            _ => self.crf.clone(),
+        }
+    }
+
+    pub fn codec_list(&self) -> Vec<VideoCodec> {
+        match (self.codec.is_empty(), &self.matrix_file.codec) {
+            (true, Some(codec)) => codec.clone(),
+            _ => self.codec.clone(),
+        }
+    }
+
+    /// 設定ファイルのコーデックごとの CRF. `--crf` を指定した場合はすべてのコーデックでそちらを使う.
+    pub fn codec_crf(&self) -> Vec<(VideoCodec, Vec<u32>)> {
+        match self.crf.is_empty() {
+            true => self.matrix_file.codec_crf.clone(),
+            false => Vec::new(),
+        }
+    }
+
+    /// 組み合わせを作らずに使う設定と, その指定元.
+    pub fn explicit_configs(&self) -> Vec<(ConfigSource, &ConfigEntry)> {
+        let file = self
+            .matrix_file
+            .configs
+            .iter()
+            .map(|c| (ConfigSource::File, c));
+        let cli = self.add_config.iter().map(|c| (ConfigSource::Cli, c));
+        file.chain(cli).collect()
+    }
+
+    /// `--res` などの組み合わせを使うかどうか. 組み合わせを作らない設定だけを指定した場合は使わない.
+    pub fn uses_matrix(&self) -> bool {
+        let file = &self.matrix_file;
+        self.explicit_configs().is_empty()
+            || !self.preset.is_empty()
+            || !(self.res.is_empty()
+                && self.fps.is_empty()
+                && self.crf.is_empty()
+                && self.codec.is_empty())
+            || file.res.is_some()
+            || file.fps.is_some()
+            || file.crf.is_some()
+            || file.codec.is_some()
+    }
+
+    pub fn has_audio(&self) -> bool {
+        self.matrix_file.has_audio.unwrap_or(true)
+    }
+
+    pub fn ext_filter(&self) -> ExtFilter {
+        ExtFilter::new(&self.include_ext, &self.exclude_ext)
+    }
+
+    pub fn walk_options(&self) -> WalkOptions {
+        WalkOptions {
+            extensions: self.dir_ext.clone(),
+            max_depth: self.max_depth,
+            follow_symlinks: self.follow_symlinks,
+        }
+    }
+
+    /// 出力先のルート. `--out-dir` を指定しない場合はワークスペース (またはカレントディレクトリ) の `out/`.
+    pub fn out_root(&self) -> PathBuf {
+        self.output_dir.clone().unwrap_or_else(workspace::out_dir)
+    }
+
+    /// タスクのログ (`--no-logs` で止める) を置くディレクトリ. `-o` を指定しなければ,
+    /// ワークスペースでは出力と分けて `<ワークスペース>/logs` に, それ以外では出力先の `logs` に置く.
+    pub fn logs_dir(&self) -> PathBuf {
+        match (&self.output_dir, Workspace::current()) {
+            (None, Some(workspace)) => workspace.logs_dir(),
+            _ => self.out_root().join(logs::DIR_NAME),
+        }
+    }
+
+    /// 設定にプリセット名を残すかどうか. `--name-template` に `{preset}` を含める場合は `--name-preset` がなくても残す.
+    pub fn name_preset(&self) -> bool {
+        self.name_preset
+            || self
+                .name_template
+                .as_ref()
+                .is_some_and(|t| t.uses(Placeholder::Preset))
+    }
+
+    /// 出力のファイル名. per-config では設定をディレクトリで表すので, 元の名前のままにする.
+    pub fn output_file_name(&self, stem: &str, config: &VideoConfig, ext: &str) -> String {
+        let sample = self.sample.is_some();
+        match (&self.name_template, self.layout) {
+            (Some(template), _) => template.render(stem, config, sample, ext),
+            (None, OutputLayout::PerConfig) => {
+                let suffix = if sample { naming::SAMPLE_SUFFIX } else { "" };
+                format!("{}{}.{}", stem, suffix, ext)
+            }
+            (None, OutputLayout::Flat) => naming::output_file_name(stem, config, sample, ext),
+        }
+    }
+
+    /// `config` の出力を置くディレクトリ. per-config では `<出力先>/<設定>/<入力のサブディレクトリ>`.
+    pub fn config_out_dir(&self, config: &VideoConfig) -> PathBuf {
+        match self.layout {
+            OutputLayout::Flat => self.out_dir(),
+            OutputLayout::PerConfig => self
+                .out_root()
+                .join(naming::config_dir(config))
+                .join(&self.out_subdir),
+        }
+    }
+
+    /// この入力の出力先.
+    pub fn out_dir(&self) -> PathBuf {
+        self.out_root().join(&self.out_subdir)
+    }
+
+    /// タスクのログの保持数と合計サイズの上限. `--log-retention`, `--log-max-size` か設定の値.
+    pub fn log_retention(&self) -> logs::Retention {
+        let config = config::current();
+        logs::Retention {
+            max_count: config.log_retention.value,
+            max_size: config.log_max_size.value,
+        }
+    }
+
+    /// 同時に実行する ffmpeg の数 (タスクの数と, 分割エンコードの並列数). `--jobs` か設定の `jobs`.
+    pub fn jobs(&self) -> usize {
+        config::current().jobs.value
+    }
+
+    /// 設定より優先する, コマンドラインで指定した値.
+    pub fn config_layer(&self) -> ConfigLayer {
+        ConfigLayer {
+            jobs: self.jobs.map(|n| n as usize),
+            hwaccel: self.hwaccel.map(|hw| hw.to_string()),
+            log_retention: self.log_retention,
+            log_max_size: self.log_max_size.map(|size| size.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// チェックサムを求める速さの上限 (バイト/秒). 0 の場合は制限しない.
+    pub fn hash_rate(&self) -> Option<u64> {
+        (self.hash_rate_limit > 0).then(|| self.hash_rate_limit * 1024 * 1024)
+    }
+
+    /// `vvcnv rerun` で同じ出力を作り直すための, `config` 以外の指定.
+    pub fn task_options(&self, config: &VideoConfig) -> TaskOptions {
+        let out_root = self.out_root();
+        TaskOptions {
+            start_secs: self.start.map(|d| d.as_secs_f64()),
+            end_secs: self.end.map(|d| d.as_secs_f64()),
+            accurate_seek: self.accurate_seek,
+            sample_secs: self.sample.map(|d| d.as_secs_f64()),
+            sample_audio: self.sample_audio,
+            keep_cover: self.keep_cover,
+            faststart: self.faststart,
+            shortest: self.shortest,
+            keep_sar: self.keep_sar,
+            label: self.wants_label(),
+            label_font: self.label_font.clone(),
+            layout: self.layout,
+            out_root: Some(std::path::absolute(&out_root).unwrap_or(out_root)),
+            out_subdir: Some(self.out_subdir.clone()).filter(|dir| !dir.as_os_str().is_empty()),
+            name_template: self.name_template.as_ref().map(|t| t.to_string()),
+            name_preset: self.name_preset,
+            ascii_names: self.ascii_names,
+            audio_missing: config.audio_missing,
+        }
+    }
+
+    /// 設定ファイルに記録した `options` で, 出力の内容と置き場所を決める指定を置き換える.
+    pub fn with_task_options(&self, options: &TaskOptions) -> anyhow::Result<Cli> {
+        let secs = |secs: Option<f64>| secs.map(Duration::from_secs_f64);
+        Ok(Cli {
+            start: secs(options.start_secs),
+            end: secs(options.end_secs),
+            accurate_seek: options.accurate_seek,
+            sample: secs(options.sample_secs),
+            sample_audio: options.sample_audio,
+            keep_cover: options.keep_cover,
+            faststart: options.faststart,
+            shortest: options.shortest,
+            keep_sar: options.keep_sar,
+            label_overlay: options.label.then_some(LabelOverlayMode::All),
+            label_font: options.label_font.clone(),
+            layout: options.layout,
+            output_dir: options.out_root.clone().or(self.output_dir.clone()),
+            out_subdir: options.out_subdir.clone().unwrap_or_default(),
+            name_template: match &options.name_template {
+                Some(template) => Some(
+                    template
+                        .parse()
+                        .with_context(|| format!("名前のテンプレートが不正です: {}", template))?,
+                ),
+                None => None,
+            },
+            name_preset: options.name_preset,
+            ascii_names: options.ascii_names,
+            ..self.clone()
+        })
+    }
+
+    pub fn task_trim(&self, source: Duration) -> Trim {
+        match self.sample {
+            Some(length) => self.trim().sample(source, length),
+            None => self.trim(),
+        }
+    }
+
+    pub fn trim(&self) -> Trim {
+        Trim {
+            start: self.start,
+            end: self.end,
+            seek: if self.accurate_seek {
+                SeekMode::Accurate
+            } else {
+                SeekMode::Hybrid
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::matrix_file;
+    use clap::CommandFactory;
+    use std::path::Path;
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_input_and_matrix() {
+        let cli = Cli::parse_from([
+            "vvcnv",
+            "my_video.mkv",
+            "--crf",
+            "18,23,28",
+            "--fps",
+            "30,60",
+            "--res",
+            "720p,640x480",
+        ]);
+        assert_eq!(cli.inputs, vec!["my_video.mkv"]);
+        assert!(cli.command.is_none());
+        assert_eq!(cli.crf, vec![18, 23, 28]);
+        assert_eq!(cli.fps_list(), vec![30, 60]);
+        assert_eq!(
+            cli.res_list().iter().map(|r| r.to_wh()).collect::<Vec<_>>(),
+            vec![(1280, 720), (640, 480)]
+        );
+
+        let cli = Cli::parse_from(["vvcnv", "history"]);
+        assert!(cli.inputs.is_empty());
+        assert!(matches!(cli.command, Some(Command::History(_))));
+        assert_eq!(cli.fps_list(), vec![DEFAULT_FPS]);
+        assert_eq!(cli.res_list().len(), VideoRes::list169().len());
+        assert_eq!(cli.abort_if_larger, None);
+
+        let cli = Cli::parse_from(["vvcnv", "my_video.mkv", "--abort-if-larger"]);
+        assert_eq!(cli.abort_if_larger, Some(1.0));
+        let cli = Cli::parse_from(["vvcnv", "--abort-if-larger", "0.8", "my_video.mkv"]);
+        assert_eq!(cli.abort_if_larger, Some(0.8));
+
+        // --quality は --calibrate で求めた関係から CRF を選ぶ
+        let cli = Cli::parse_from(["vvcnv", "--calibrate", "--quality", "92.5", "a.mp4"]);
+        assert_eq!(cli.quality, Some(92.5));
+        assert_eq!(cli.quality_metric, QualityMetric::Ssim);
+        assert!(Cli::try_parse_from(["vvcnv", "--quality", "90", "a.mp4"]).is_err());
+        assert!(
+            Cli::try_parse_from(["vvcnv", "--calibrate", "--quality", "101", "a.mp4"]).is_err()
+        );
+        assert!(Cli::try_parse_from([
+            "vvcnv",
+            "--calibrate",
+            "--quality",
+            "90",
+            "--crf",
+            "23",
+            "a.mp4"
+        ])
+        .is_err());
+
+        // サンプルの指定は所要時間の予測と画質の計測のどちらにも使う
+        let cli = Cli::parse_from(["vvcnv", "--calibrate", "--sample-windows", "3x4s", "a.mp4"]);
+        assert_eq!(cli.sample_windows.map(|spec| spec.count), Some(3));
+        assert!(
+            Cli::try_parse_from(["vvcnv", "--estimate-time", "--sample-seed", "7", "a.mp4"])
+                .is_ok()
+        );
+        assert!(Cli::try_parse_from(["vvcnv", "--sample-windows", "3x4s", "a.mp4"]).is_err());
+
+        // --jobs は設定より優先する層になる
+        let cli = Cli::parse_from(["vvcnv", "-j", "2", "a.mp4"]);
+        assert_eq!(cli.config_layer().jobs, Some(2));
+        let cli = Cli::parse_from(["vvcnv", "--hwaccel", "nvenc", "a.mp4"]);
+        assert_eq!(cli.config_layer().hwaccel.as_deref(), Some("nvenc"));
+        assert!(Cli::try_parse_from(["vvcnv", "--hwaccel", "amd", "a.mp4"]).is_err());
+        assert_eq!(
+            Cli::parse_from(["vvcnv", "a.mp4"]).config_layer(),
+            ConfigLayer::default()
+        );
+        assert!(Cli::try_parse_from(["vvcnv", "--jobs", "0", "a.mp4"]).is_err());
+        assert!(Cli::try_parse_from(["vvcnv", "--abort-if-larger", "0", "my_video.mkv"]).is_err());
+
+        // 設定ファイルの値は, コマンドラインで指定しなかった項目にだけ使う
+        let mut cli = Cli::parse_from(["vvcnv", "--config", "m.toml", "--res", "720p", "a.mp4"]);
+        cli.matrix_file =
+            matrix_file::parse("res = [\"480p\"]\nfps = [24]\ncrf = [30]\nhas-audio = false\n")
+                .unwrap();
+        assert_eq!(cli.res_list()[0].to_wh(), (1280, 720));
+        assert_eq!(cli.fps_list(), vec![24]);
+        assert_eq!(cli.crf_list(), vec![30]);
+        assert!(!cli.has_audio());
+        assert!(Cli::parse_from(["vvcnv", "a.mp4"]).has_audio());
+
+        // コーデックごとの CRF は --crf を指定しなかった場合だけ使う
+        let codec_file = "codec = [\"h264\", \"vp9\"]\n[codec-crf]\nvp9 = [30, 36]\n";
+        let mut cli = Cli::parse_from(["vvcnv", "a.mp4"]);
+        cli.matrix_file = matrix_file::parse(codec_file).unwrap();
+        assert_eq!(cli.codec_list(), [VideoCodec::H264, VideoCodec::Vp9]);
+        assert_eq!(cli.codec_crf(), [(VideoCodec::Vp9, vec![30, 36])]);
+        assert!(cli.uses_matrix());
+        let mut cli = Cli::parse_from(["vvcnv", "--codec", "h265,av1", "--crf", "30", "a.mp4"]);
+        cli.matrix_file = matrix_file::parse(codec_file).unwrap();
+        assert_eq!(cli.codec_list(), [VideoCodec::H265, VideoCodec::Av1]);
+        assert!(cli.codec_crf().is_empty());
+        assert!(Cli::try_parse_from(["vvcnv", "--codec", "", "a.mp4"]).is_err());
+        assert!(!Cli::parse_from(["vvcnv", "a.mp4"]).strict_audio);
+        assert!(Cli::parse_from(["vvcnv", "--strict-audio", "a.mp4"]).strict_audio);
+        assert_eq!(Cli::parse_from(["vvcnv", "a.mp4"]).retries, 0);
+        assert_eq!(
+            Cli::parse_from(["vvcnv", "--retries", "3", "a.mp4"]).retries,
+            3
+        );
+        assert!(!Cli::parse_from(["vvcnv", "a.mp4"]).fail_fast);
+        assert_eq!(
+            Cli::parse_from(["vvcnv", "a.mp4"]).verbosity(),
+            Verbosity::Normal
+        );
+        assert_eq!(
+            Cli::parse_from(["vvcnv", "-vv", "a.mp4"]).verbosity(),
+            Verbosity::Debug
+        );
+        assert_eq!(
+            Cli::parse_from(["vvcnv", "-q", "a.mp4"]).verbosity(),
+            Verbosity::Quiet
+        );
+        assert!(Cli::try_parse_from(["vvcnv", "-q", "-v", "a.mp4"]).is_err());
+        assert!(Cli::parse_from(["vvcnv", "--progress", "plain", "a.mp4"]).plain_progress());
+        assert!(!Cli::parse_from(["vvcnv", "--progress", "bars", "a.mp4"]).plain_progress());
+        assert!(Cli::parse_from(["vvcnv", "--fail-fast", "a.mp4"]).fail_fast);
+
+        let mut cli = Cli::parse_from(["vvcnv", "--out-dir", "/srv/encoded", "a.mp4"]);
+        cli.out_subdir = PathBuf::from("day1");
+        assert_eq!(cli.out_dir(), Path::new("/srv/encoded").join("day1"));
+
+        // {preset} を使うテンプレートは --name-preset がなくてもプリセット名を残す
+        let cli = Cli::parse_from([
+            "vvcnv",
+            "--preset",
+            "chat",
+            "--name-template",
+            "{stem}_{preset}_crf{crf}.{ext}",
+            "a.mp4",
+        ]);
+        assert!(cli.name_preset());
+        let config = presets::builtins()
+            .into_iter()
+            .find(|p| p.name == "chat")
+            .unwrap()
+            .matrices(&cli)[0]
+            .configs()
+            .next()
+            .unwrap();
+        assert_eq!(
+            cli.output_file_name("a", &config, "mp4"),
+            "a_chat_crf28.mp4"
+        );
+        assert!(!Cli::parse_from(["vvcnv", "a.mp4"]).name_preset());
+
+        // per-config では設定をディレクトリで表し, 元の名前のまま書き出す
+        let mut cli =
+            Cli::parse_from(["vvcnv", "--layout", "per-config", "--out-dir", "o", "a.mp4"]);
+        let config = VideoConfig {
+            crf: 28,
+            ..Default::default()
+        };
+        assert_eq!(
+            cli.config_out_dir(&config),
+            Path::new("o").join("res-1280x720--fps-30--crf-28")
+        );
+        cli.out_subdir = PathBuf::from("day1");
+        assert_eq!(
+            cli.config_out_dir(&config),
+            Path::new("o")
+                .join("res-1280x720--fps-30--crf-28")
+                .join("day1")
+        );
+        cli.out_subdir = PathBuf::new();
+        assert_eq!(
+            Cli::parse_from(["vvcnv", "--out-layout", "per-config", "a.mp4"]).layout,
+            OutputLayout::PerConfig
+        );
+        assert_eq!(
+            cli.output_file_name("会議録画", &config, "mp4"),
+            "会議録画.mp4"
+        );
+        cli.layout = OutputLayout::Flat;
+        assert_eq!(cli.config_out_dir(&config), Path::new("o"));
+        assert_eq!(
+            cli.output_file_name("a", &config, "mp4"),
+            "a--res-1280x720--fps-30--crf-28.mp4"
+        );
+        assert!(Cli::try_parse_from(["vvcnv", "--name-template", "{res}", "a.mp4"]).is_err());
+
+        // 後に指定した方が優先される
+        assert!(Cli::parse_from(["vvcnv", "--skip-existing", "a.mp4"]).skip_existing);
+        let cli = Cli::parse_from(["vvcnv", "--skip-existing", "--force", "a.mp4"]);
+        assert!(!cli.skip_existing && cli.overwrite);
+        assert!(
+            Cli::parse_from(["vvcnv", "--overwrite", "--skip-existing", "a.mp4"]).skip_existing
+        );
+        assert!(!Cli::parse_from(["vvcnv", "a.mp4"]).overwrite);
+    }
+
+    #[test]
+    fn test_rerun_overrides() {
+        let cli = Cli::parse_from([
+            "vvcnv",
+            "--sidecars",
+            "rerun",
+            "out/clip--res-1280x720--fps-30--crf-28.vvcnv.json",
+            "--crf",
+            "26",
+        ]);
+        assert!(cli.sidecars);
+        let Some(Command::Rerun(args)) = cli.command else {
+            panic!("rerun subcommand was not parsed");
+        };
+
+        let config = args.apply(VideoConfig {
+            res: VideoRes::R720p,
+            fps: 30,
+            crf: 28,
+            ..Default::default()
+        });
+        assert_eq!(config.to_file_name(), "--res-1280x720--fps-30--crf-26");
+    }
+
+    #[test]
+    fn test_task_options_round_trip() {
+        let original = Cli::parse_from([
+            "vvcnv",
+            "--start",
+            "1:30.25",
+            "--end",
+            "2:00",
+            "--accurate-seek",
+            "--sample",
+            "10s",
+            "--faststart",
+            "--keep-sar",
+            "--label-overlay",
+            "all",
+            "--layout",
+            "per-config",
+            "--out-dir",
+            "/tmp/vvcnv-out",
+            "--name-template",
+            "{stem}_{height}p.{ext}",
+            "a.mp4",
+        ]);
+        let config = VideoConfig {
+            res: VideoRes::R720p,
+            has_audio: false,
+            audio_missing: true,
+            ..Default::default()
+        };
+        let options = original.task_options(&config);
+        assert!(options.audio_missing);
+
+        // rerun では記録した指定に戻るので, 同じ場所に同じ名前と範囲で作り直す
+        let rerun = Cli::parse_from(["vvcnv", "rerun", "a.vvcnv.json"])
+            .with_task_options(&options)
+            .unwrap();
+        assert_eq!(rerun.trim(), original.trim());
+        assert_eq!(
+            rerun.task_trim(Duration::from_secs(300)),
+            original.task_trim(Duration::from_secs(300))
+        );
+        assert_eq!(
+            rerun.config_out_dir(&config),
+            original.config_out_dir(&config)
+        );
+        assert_eq!(
+            rerun.output_file_name("a", &config, "mp4"),
+            original.output_file_name("a", &config, "mp4")
+        );
+        assert!(rerun.faststart && rerun.keep_sar && rerun.wants_label());
+        assert_eq!(rerun.task_options(&config), options);
+    }
+}
@@ -0,0 +1,215 @@
+use core::fmt;
+use serde::{Deserialize, Serialize};
+
+use super::video::{self, VideoConfig};
+
+/// `--av1-film-grain` に指定できる最大の強さ.
+pub const MAX_FILM_GRAIN: u32 = 50;
+
+/// `--codec-param` で指定したエンコーダー固有のパラメーター. 値はエンコーダーが検証するので, ここでは確認しない.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CodecParam {
+    pub key: String,
+    pub value: String,
+}
+
+/// `key=value` の形式. 値には `=` や `:` を含められる.
+pub fn parse_codec_param(input: &str) -> Result<CodecParam, String> {
+    match input.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => Ok(CodecParam {
+            key: key.trim().to_string(),
+            value: value.to_string(),
+        }),
+        _ => Err(format!("key=value の形式で指定してください: {}", input)),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodecParamErr {
+    /// エンコーダーがパラメーターをまとめて受け取るオプションを持たない.
+    Unsupported(String),
+    /// フィルムグレインは AV1 のエンコーダーでしか使えない.
+    FilmGrain(String),
+}
+
+impl fmt::Display for CodecParamErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CodecParamErr::Unsupported(encoder) => write!(
+                f,
+                "{} では --codec-param を使えません (libx264 / libx265 / libsvtav1 / libaom-av1 のみ)",
+                encoder
+            ),
+            CodecParamErr::FilmGrain(encoder) => write!(
+                f,
+                "{} では --av1-film-grain を使えません (AV1 のエンコーダーのみ)",
+                encoder
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CodecParamErr {}
+
+/// エンコーダーごとの, パラメーターをまとめて渡すオプション.
+pub fn params_flag(encoder: &str) -> Option<&'static str> {
+    match encoder {
+        "libx264" => Some("-x264-params"),
+        "libx265" => Some("-x265-params"),
+        "libsvtav1" => Some("-svtav1-params"),
+        "libaom-av1" => Some("-aom-params"),
+        _ => None,
+    }
+}
+
+/// フィルムグレインの合成を指定するパラメーター名.
+fn film_grain_key(encoder: &str) -> Option<&'static str> {
+    match encoder {
+        "libsvtav1" => Some("film-grain"),
+        "libaom-av1" => Some("denoise-noise-level"),
+        _ => None,
+    }
+}
+
+/// ffmpeg は `key=value` を `:` で区切って読むので, 値の中の `:` と引用符はバックスラッシュでエスケープする.
+fn escape_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '\\' | ':' | '\'') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// 同じキーを複数回指定した場合は, 最初の位置に最後の値を使う.
+pub fn merge(params: &[CodecParam]) -> String {
+    let mut merged: Vec<&CodecParam> = Vec::new();
+    for param in params {
+        match merged.iter_mut().find(|p| p.key == param.key) {
+            Some(existing) => *existing = param,
+            None => merged.push(param),
+        }
+    }
+
+    merged
+        .iter()
+        .map(|p| format!("{}={}", p.key, escape_value(&p.value)))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+pub fn validate(config: &VideoConfig) -> Result<(), CodecParamErr> {
+    let encoder = video::codec_name(config);
+    if config.film_grain.is_some() && film_grain_key(encoder).is_none() {
+        return Err(CodecParamErr::FilmGrain(encoder.to_string()));
+    }
+    if !config.codec_params.is_empty() && params_flag(encoder).is_none() {
+        return Err(CodecParamErr::Unsupported(encoder.to_string()));
+    }
+
+    Ok(())
+}
+
+/// ffmpeg に渡すオプションと値. フィルムグレインは `--codec-param` で上書きできるように先頭に置く.
+pub fn to_args(config: &VideoConfig) -> Option<(&'static str, String)> {
+    let encoder = video::codec_name(config);
+    let flag = params_flag(encoder)?;
+    let film_grain = config.film_grain.and_then(|level| {
+        Some(CodecParam {
+            key: film_grain_key(encoder)?.to_string(),
+            value: level.to_string(),
+        })
+    });
+    let params = film_grain
+        .into_iter()
+        .chain(config.codec_params.iter().cloned())
+        .collect::<Vec<_>>();
+
+    (!params.is_empty()).then(|| (flag, merge(&params)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::video::VideoCodec;
+
+    fn param(input: &str) -> CodecParam {
+        parse_codec_param(input).unwrap()
+    }
+
+    #[test]
+    fn test_parse_codec_param() {
+        assert_eq!(
+            param("tune=0"),
+            CodecParam {
+                key: "tune".to_string(),
+                value: "0".to_string()
+            }
+        );
+        assert_eq!(param("zones=0,100,b=2").value, "0,100,b=2");
+        assert_eq!(param("empty=").value, "");
+        assert!(parse_codec_param("tune").is_err());
+        assert!(parse_codec_param("=1").is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let params = ["aq-mode=2", "psy-rd=1.0:0.15", "aq-mode=3", "unknown-key=x"].map(param);
+        assert_eq!(merge(&params), "aq-mode=3:psy-rd=1.0\\:0.15:unknown-key=x");
+        assert_eq!(merge(&[param("path=C:\\it's")]), "path=C\\:\\\\it\\'s");
+        assert_eq!(merge(&[]), "");
+    }
+
+    #[test]
+    fn test_to_args() {
+        let config = |codec, film_grain, params: &[&str]| VideoConfig {
+            codec,
+            film_grain,
+            codec_params: params.iter().map(|p| param(p)).collect(),
+            ..Default::default()
+        };
+
+        assert_eq!(config(None, None, &[]).film_grain, None);
+        assert_eq!(to_args(&config(None, None, &[])), None);
+        assert_eq!(
+            to_args(&config(None, None, &["keyint=120"])),
+            Some(("-x264-params", "keyint=120".to_string()))
+        );
+        assert_eq!(
+            to_args(&config(Some(VideoCodec::H265), None, &["b-adapt=2"]))
+                .unwrap()
+                .0,
+            "-x265-params"
+        );
+        assert_eq!(
+            to_args(&config(
+                Some(VideoCodec::Other("libsvtav1".to_string())),
+                Some(8),
+                &["tune=0"]
+            )),
+            Some(("-svtav1-params", "film-grain=8:tune=0".to_string()))
+        );
+        // --codec-param で同じキーを指定した場合はそちらを使う
+        assert_eq!(
+            to_args(&config(
+                Some(VideoCodec::Av1),
+                Some(8),
+                &["denoise-noise-level=4"]
+            )),
+            Some(("-aom-params", "denoise-noise-level=4".to_string()))
+        );
+
+        assert!(validate(&config(Some(VideoCodec::Av1), Some(8), &[])).is_ok());
+        assert_eq!(
+            validate(&config(None, Some(8), &[])),
+            Err(CodecParamErr::FilmGrain("libx264".to_string()))
+        );
+        assert_eq!(
+            validate(&config(Some(VideoCodec::Vp9), None, &["row-mt=1"])),
+            Err(CodecParamErr::Unsupported("libvpx-vp9".to_string()))
+        );
+    }
+}
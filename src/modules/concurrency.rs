@@ -0,0 +1,129 @@
+use core::fmt;
+use std::thread;
+
+use super::{
+    config::{Origin, Sourced},
+    video::VideoRes,
+};
+
+/// 1 CPU が受け持つ画素数の目安. 360p のエンコード 1 つで CPU 1 個を使うとみなす.
+pub const PIXELS_PER_CPU: u64 = 640 * 360;
+
+/// 同時に実行するタスクの決め方.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Concurrency {
+    /// `--jobs` か設定の `jobs` で指定した数だけ同時に実行する.
+    Fixed { jobs: usize, origin: Origin },
+    /// 解像度から決めた重みの合計が `budget` (CPU の数) を超えない範囲で同時に実行する.
+    /// 240p / 360p は多く並べ, 4K は 1 つずつ実行する.
+    Weighted { budget: usize },
+}
+
+impl Concurrency {
+    /// `jobs` を指定していなければ, CPU の数から重みの上限を決める.
+    pub fn choose(jobs: &Sourced<usize>) -> Self {
+        match jobs.origin {
+            Origin::Default => Self::Weighted {
+                budget: thread::available_parallelism().map_or(1, |n| n.get()),
+            },
+            _ => Self::Fixed {
+                jobs: jobs.value,
+                origin: jobs.origin.clone(),
+            },
+        }
+    }
+
+    /// 全体で使える重み. `tokio::sync::Semaphore` の許可の数にする.
+    pub fn permits(&self) -> usize {
+        match self {
+            Self::Fixed { jobs, .. } => *jobs,
+            Self::Weighted { budget } => *budget,
+        }
+    }
+
+    /// 1 タスクの重み. 上限を超える重みは上限に揃え, 大きな解像度でも 1 つは実行できるようにする.
+    pub fn weight(&self, res: &VideoRes) -> u32 {
+        match self {
+            Self::Fixed { .. } => 1,
+            Self::Weighted { budget } => {
+                let (w, h) = res.to_wh();
+                let weight = (w as u64 * h as u64).div_ceil(PIXELS_PER_CPU);
+                weight.clamp(1, *budget as u64) as u32
+            }
+        }
+    }
+
+    /// `configs` の解像度ごとの重み. 表示用に重複を除いて小さい順に並べる.
+    pub fn weights<'a>(&self, res: impl IntoIterator<Item = &'a VideoRes>) -> Vec<(String, u32)> {
+        let mut weights = res
+            .into_iter()
+            .map(|res| (res.to_wh(), self.weight(res)))
+            .collect::<Vec<_>>();
+        weights.sort_by_key(|((w, h), _)| *w as u64 * *h as u64);
+        weights.dedup();
+        weights
+            .into_iter()
+            .map(|((w, h), weight)| (format!("{}x{}", w, h), weight))
+            .collect()
+    }
+}
+
+impl fmt::Display for Concurrency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Fixed { jobs, origin } => write!(f, "{} 個まで ({})", jobs, origin),
+            Self::Weighted { budget } => write!(
+                f,
+                "重みの合計 {} まで (CPU の数から自動で決定. --jobs で固定できます)",
+                budget
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_weight() {
+        let auto = Concurrency::Weighted { budget: 16 };
+        assert_eq!(auto.permits(), 16);
+        assert_eq!(auto.weight(&VideoRes::R240p), 1);
+        assert_eq!(auto.weight(&VideoRes::R360p), 1);
+        assert_eq!(auto.weight(&VideoRes::R720p), 4);
+        assert_eq!(auto.weight(&VideoRes::R1080p), 9);
+        // 上限を超える重みは上限に揃えるので, 4K / 8K も 1 つずつは実行できる
+        assert_eq!(auto.weight(&VideoRes::R2160p), 16);
+        assert_eq!(auto.weight(&VideoRes::R4320p), 16);
+        assert_eq!(auto.weight(&VideoRes::Other(0, 0)), 1);
+
+        assert_eq!(
+            auto.weights(&[VideoRes::R1080p, VideoRes::R360p, VideoRes::R1080p]),
+            [("640x360".to_string(), 1), ("1920x1080".to_string(), 9)]
+        );
+
+        let fixed = Concurrency::Fixed {
+            jobs: 2,
+            origin: Origin::Cli,
+        };
+        assert_eq!(fixed.permits(), 2);
+        assert_eq!(fixed.weight(&VideoRes::R2160p), 1);
+    }
+
+    #[test]
+    fn test_choose() {
+        let jobs = |origin| Sourced { value: 3, origin };
+        assert!(matches!(
+            Concurrency::choose(&jobs(Origin::Default)),
+            Concurrency::Weighted { budget } if budget >= 1
+        ));
+        assert_eq!(
+            Concurrency::choose(&jobs(Origin::Env("VVCNV_JOBS"))),
+            Concurrency::Fixed {
+                jobs: 3,
+                origin: Origin::Env("VVCNV_JOBS")
+            }
+        );
+    }
+}
@@ -0,0 +1,506 @@
+use anyhow::{anyhow, bail, Context, Result};
+use core::fmt;
+use humansize::{FormatSizeOptions, BINARY, DECIMAL};
+use serde::Deserialize;
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    thread,
+};
+
+use super::{
+    logs,
+    workspace::{self, Workspace},
+};
+
+pub const GLOBAL_CONFIG_DIR: &str = "vvcnv";
+pub const GLOBAL_CONFIG_FILE: &str = "config.toml";
+/// メッセージは日本語のみ.
+pub const SUPPORTED_LANGUAGES: &[&str] = &["ja"];
+
+/// 設定ファイルと環境変数で指定できる項目. キーと環境変数の名前の対応.
+const KEYS: &[(&str, &str)] = &[
+    ("ffmpeg-path", "VVCNV_FFMPEG_PATH"),
+    ("jobs", "VVCNV_JOBS"),
+    ("hwaccel", "VVCNV_HWACCEL"),
+    ("log-retention", "VVCNV_LOG_RETENTION"),
+    ("log-max-size", "VVCNV_LOG_MAX_SIZE"),
+    ("size-units", "VVCNV_SIZE_UNITS"),
+    ("language", "VVCNV_LANGUAGE"),
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SizeUnits {
+    /// 1 kB = 1000 B.
+    #[default]
+    Decimal,
+    /// 1 KiB = 1024 B.
+    Binary,
+}
+
+impl SizeUnits {
+    pub fn format_options(&self) -> FormatSizeOptions {
+        match self {
+            SizeUnits::Decimal => DECIMAL,
+            SizeUnits::Binary => BINARY,
+        }
+    }
+}
+
+impl fmt::Display for SizeUnits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SizeUnits::Decimal => write!(f, "decimal"),
+            SizeUnits::Binary => write!(f, "binary"),
+        }
+    }
+}
+
+/// 1 つの層 (グローバル, プロジェクト, 環境変数) で指定された値. 指定しなかった項目は下の層の値を使う.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConfigLayer {
+    pub ffmpeg_path: Option<PathBuf>,
+    pub jobs: Option<usize>,
+    pub hwaccel: Option<String>,
+    pub log_retention: Option<usize>,
+    /// `500MB` などの形式 ([`logs::parse_size`]).
+    pub log_max_size: Option<String>,
+    pub size_units: Option<SizeUnits>,
+    pub language: Option<String>,
+}
+
+/// 値をどこで指定したか.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Origin {
+    Default,
+    Global(PathBuf),
+    Project(PathBuf),
+    Env(&'static str),
+    Cli,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Origin::Default => write!(f, "既定値"),
+            Origin::Global(path) => write!(f, "グローバル設定 ({})", path.display()),
+            Origin::Project(path) => write!(f, "プロジェクト設定 ({})", path.display()),
+            Origin::Env(name) => write!(f, "環境変数 {}", name),
+            Origin::Cli => write!(f, "コマンドライン"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sourced<T> {
+    pub value: T,
+    pub origin: Origin,
+}
+
+impl<T> Sourced<T> {
+    fn default(value: T) -> Self {
+        Self {
+            value,
+            origin: Origin::Default,
+        }
+    }
+
+    fn merge(&mut self, value: Option<T>, origin: &Origin) {
+        if let Some(value) = value {
+            *self = Self {
+                value,
+                origin: origin.clone(),
+            };
+        }
+    }
+}
+
+/// すべての層を重ねた結果.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub ffmpeg_path: Sourced<Option<PathBuf>>,
+    pub jobs: Sourced<usize>,
+    pub hwaccel: Sourced<Option<String>>,
+    /// 残すタスクのログの数. `None` は制限しない.
+    pub log_retention: Sourced<Option<usize>>,
+    /// タスクのログの合計の大きさ (バイト) の上限. `None` は制限しない.
+    pub log_max_size: Sourced<Option<u64>>,
+    pub size_units: Sourced<SizeUnits>,
+    pub language: Sourced<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            ffmpeg_path: Sourced::default(None),
+            jobs: Sourced::default(default_jobs()),
+            hwaccel: Sourced::default(None),
+            log_retention: Sourced::default(None),
+            log_max_size: Sourced::default(None),
+            size_units: Sourced::default(SizeUnits::default()),
+            language: Sourced::default(SUPPORTED_LANGUAGES[0].to_string()),
+        }
+    }
+}
+
+impl Config {
+    /// 層を下から順に重ねる. 後の層で指定した値が優先される.
+    pub fn resolve(layers: &[(Origin, ConfigLayer)]) -> Self {
+        layers
+            .iter()
+            .fold(Self::default(), |mut config, (origin, layer)| {
+                config.merge(layer.clone(), origin);
+                config
+            })
+    }
+
+    fn merge(&mut self, layer: ConfigLayer, origin: &Origin) {
+        self.ffmpeg_path.merge(layer.ffmpeg_path.map(Some), origin);
+        self.jobs.merge(layer.jobs, origin);
+        self.hwaccel.merge(layer.hwaccel.map(Some), origin);
+        self.log_retention
+            .merge(layer.log_retention.map(Some), origin);
+        self.log_max_size.merge(
+            layer.log_max_size.map(|size| logs::parse_size(&size).ok()),
+            origin,
+        );
+        self.size_units.merge(layer.size_units, origin);
+        self.language.merge(layer.language, origin);
+    }
+
+    /// `vvcnv config show` で表示する, キーと値と指定元.
+    pub fn entries(&self) -> Vec<(&'static str, String, &Origin)> {
+        let none = || "なし".to_string();
+        vec![
+            (
+                "ffmpeg-path",
+                self.ffmpeg_path
+                    .value
+                    .as_ref()
+                    .map_or_else(none, |p| p.display().to_string()),
+                &self.ffmpeg_path.origin,
+            ),
+            ("jobs", self.jobs.value.to_string(), &self.jobs.origin),
+            (
+                "hwaccel",
+                self.hwaccel.value.clone().unwrap_or_else(none),
+                &self.hwaccel.origin,
+            ),
+            (
+                "log-retention",
+                self.log_retention
+                    .value
+                    .map_or_else(none, |n| n.to_string()),
+                &self.log_retention.origin,
+            ),
+            (
+                "log-max-size",
+                self.log_max_size.value.map_or_else(none, |size| {
+                    humansize::format_size(size, self.size_units.value.format_options())
+                }),
+                &self.log_max_size.origin,
+            ),
+            (
+                "size-units",
+                self.size_units.value.to_string(),
+                &self.size_units.origin,
+            ),
+            (
+                "language",
+                self.language.value.clone(),
+                &self.language.origin,
+            ),
+        ]
+    }
+}
+
+/// `jobs` の既定値. ffmpeg はそれぞれ複数のスレッドでエンコードするので, 論理 CPU の数の半分 (SMT を除いた物理コアの数の目安) にする.
+fn default_jobs() -> usize {
+    let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+    (cpus / 2).max(1)
+}
+
+fn validate(layer: &ConfigLayer) -> Result<()> {
+    if layer.jobs == Some(0) {
+        bail!("jobs には 1 以上を指定してください");
+    }
+    if let Some(size) = &layer.log_max_size {
+        logs::parse_size(size).map_err(|e| anyhow!("log-max-size: {}", e))?;
+    }
+    if let Some(language) = &layer.language {
+        if !SUPPORTED_LANGUAGES.contains(&language.as_str()) {
+            bail!(
+                "未対応の言語です: {} ({} のみ)",
+                language,
+                SUPPORTED_LANGUAGES.join(" / ")
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// 設定ファイルの内容を読む. `lenient` の場合は不明なキーを取り除き, 警告として返す.
+/// 新しいバージョンで追加したキーを含むグローバル設定を, 古いバージョンでも読めるようにするため.
+pub fn parse_layer(content: &str, lenient: bool) -> Result<(ConfigLayer, Vec<String>)> {
+    let mut table = toml::from_str::<toml::Table>(content)?;
+    let unknown = table
+        .keys()
+        .filter(|key| !KEYS.iter().any(|(k, _)| k == key))
+        .cloned()
+        .collect::<Vec<_>>();
+    if !lenient && !unknown.is_empty() {
+        bail!("不明な項目です: {}", unknown.join(", "));
+    }
+    unknown.iter().for_each(|key| {
+        table.remove(key);
+    });
+    let layer = table.try_into::<ConfigLayer>()?;
+    validate(&layer)?;
+
+    let warnings = unknown
+        .into_iter()
+        .map(|key| format!("不明な項目を無視します: {}", key))
+        .collect();
+    Ok((layer, warnings))
+}
+
+/// `VVCNV_*` の環境変数から層を作る. 空の変数は指定しなかったものとして扱う.
+pub fn env_layer(var: impl Fn(&str) -> Option<String>) -> Result<Vec<(Origin, ConfigLayer)>> {
+    KEYS.iter()
+        .filter_map(|(key, name)| {
+            let value = var(name).filter(|v| !v.is_empty())?;
+            Some((key, *name, value))
+        })
+        .map(|(key, name, value)| {
+            let value = match *key {
+                "jobs" | "log-retention" => toml::Value::Integer(
+                    value
+                        .parse()
+                        .map_err(|_| anyhow!("{} には整数を指定してください: {}", name, value))?,
+                ),
+                _ => toml::Value::String(value),
+            };
+            let table = toml::Table::from_iter([(key.to_string(), value)]);
+            let layer = table
+                .try_into::<ConfigLayer>()
+                .with_context(|| format!("環境変数 {} の値が不正です", name))?;
+            validate(&layer).with_context(|| format!("環境変数 {} の値が不正です", name))?;
+            Ok((Origin::Env(name), layer))
+        })
+        .collect()
+}
+
+fn read_layer(path: &Path, lenient: bool) -> Result<Option<(ConfigLayer, Vec<String>)>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(e).with_context(|| {
+                format!("設定ファイルの読み込みに失敗しました: {}", path.display())
+            })
+        }
+    };
+    parse_layer(&content, lenient)
+        .map(Some)
+        .with_context(|| format!("設定ファイルの形式が不正です: {}", path.display()))
+}
+
+/// `~/.config/vvcnv/config.toml` (OS の設定ディレクトリ).
+pub fn global_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join(GLOBAL_CONFIG_DIR).join(GLOBAL_CONFIG_FILE))
+}
+
+/// グローバル → プロジェクト → 環境変数の順に重ねる. 警告はグローバル設定の不明なキー.
+pub fn load(
+    global: Option<&Path>,
+    project: Option<&Path>,
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<(Config, Vec<String>)> {
+    let mut layers = Vec::new();
+    let mut warnings = Vec::new();
+    if let Some(path) = global {
+        if let Some((layer, unknown)) = read_layer(path, true)? {
+            warnings.extend(
+                unknown
+                    .into_iter()
+                    .map(|w| format!("{} ({})", w, path.display())),
+            );
+            layers.push((Origin::Global(path.to_path_buf()), layer));
+        }
+    }
+    if let Some(path) = project {
+        if let Some((layer, _)) = read_layer(path, false)? {
+            layers.push((Origin::Project(path.to_path_buf()), layer));
+        }
+    }
+    layers.extend(env_layer(var)?);
+
+    Ok((Config::resolve(&layers), warnings))
+}
+
+static CURRENT: OnceLock<Config> = OnceLock::new();
+
+/// 起動時に一度だけ設定を読み込む. コマンドラインで指定した `cli` の値を最も優先する. 警告を返す.
+pub fn init(cli: ConfigLayer) -> Result<Vec<String>> {
+    let project = Workspace::current().map(|w| w.root().join(workspace::CONFIG_FILE));
+    let (mut config, warnings) = load(global_path().as_deref(), project.as_deref(), |name| {
+        env::var(name).ok()
+    })?;
+    config.merge(cli, &Origin::Cli);
+    CURRENT.set(config).ok();
+
+    Ok(warnings)
+}
+
+/// 読み込んだ設定. `init` の前 (テストなど) は既定値になる.
+pub fn current() -> &'static Config {
+    CURRENT.get_or_init(Config::default)
+}
+
+/// 設定した単位でサイズを表示するための書式.
+pub fn size_format() -> FormatSizeOptions {
+    current().size_units.value.format_options()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::testing::TempDir;
+
+    fn layer(content: &str) -> ConfigLayer {
+        parse_layer(content, false).unwrap().0
+    }
+
+    #[test]
+    fn test_parse_layer() {
+        assert_eq!(
+            layer("jobs = 4\nsize-units = \"binary\"\n"),
+            ConfigLayer {
+                jobs: Some(4),
+                size_units: Some(SizeUnits::Binary),
+                ..Default::default()
+            }
+        );
+        assert_eq!(layer("# コメントだけ\n"), ConfigLayer::default());
+
+        // グローバル設定の不明なキーは警告にとどめる
+        let (lenient, warnings) = parse_layer("jobs = 2\nfuture-option = true\n", true).unwrap();
+        assert_eq!(lenient.jobs, Some(2));
+        assert_eq!(warnings, vec!["不明な項目を無視します: future-option"]);
+        assert!(parse_layer("future-option = true\n", false).is_err());
+
+        assert!(parse_layer("jobs = 0\n", true).is_err());
+        assert!(parse_layer("jobs = \"many\"\n", true).is_err());
+        assert!(parse_layer("language = \"en\"\n", true).is_err());
+        assert!(parse_layer("size-units = \"metric\"\n", true).is_err());
+
+        let retention = Config::resolve(&[(
+            Origin::Default,
+            layer("log-retention = 200\nlog-max-size = \"500MB\"\n"),
+        )]);
+        assert_eq!(retention.log_retention.value, Some(200));
+        assert_eq!(retention.log_max_size.value, Some(500_000_000));
+        assert!(parse_layer("log-max-size = \"lots\"\n", true).is_err());
+    }
+
+    #[test]
+    fn test_resolve_order() {
+        let global = PathBuf::from("/home/me/.config/vvcnv/config.toml");
+        let project = PathBuf::from("/work/vvcnv.toml");
+        let layers = [
+            (
+                Origin::Global(global.clone()),
+                layer("jobs = 2\nhwaccel = \"vaapi\"\nsize-units = \"binary\"\n"),
+            ),
+            (Origin::Project(project.clone()), layer("jobs = 4\n")),
+            (
+                Origin::Env("VVCNV_HWACCEL"),
+                ConfigLayer {
+                    hwaccel: Some("cuda".to_string()),
+                    ..Default::default()
+                },
+            ),
+            (
+                Origin::Cli,
+                ConfigLayer {
+                    jobs: Some(8),
+                    ..Default::default()
+                },
+            ),
+        ];
+
+        let config = Config::resolve(&layers);
+        assert_eq!(config.jobs.value, 8);
+        assert_eq!(config.jobs.origin, Origin::Cli);
+        assert_eq!(config.hwaccel.value.as_deref(), Some("cuda"));
+        assert_eq!(config.hwaccel.origin, Origin::Env("VVCNV_HWACCEL"));
+        assert_eq!(config.size_units.value, SizeUnits::Binary);
+        assert_eq!(config.size_units.origin, Origin::Global(global));
+        assert_eq!(config.language.origin, Origin::Default);
+
+        // CLI を除くとプロジェクト設定がグローバル設定より優先される
+        let config = Config::resolve(&layers[..2]);
+        assert_eq!(config.jobs.value, 4);
+        assert_eq!(config.jobs.origin, Origin::Project(project));
+        assert_eq!(config.hwaccel.value.as_deref(), Some("vaapi"));
+        assert_eq!(Config::resolve(&[]), Config::default());
+    }
+
+    #[test]
+    fn test_env_layer() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(n, _)| *n == name)
+                    .map(|(_, v)| v.to_string())
+            }
+        };
+
+        let layers = env_layer(env(&[
+            ("VVCNV_JOBS", "3"),
+            ("VVCNV_FFMPEG_PATH", "/opt/ffmpeg/bin/ffmpeg"),
+            ("VVCNV_HWACCEL", ""),
+        ]))
+        .unwrap();
+        let config = Config::resolve(&layers);
+        assert_eq!(config.jobs.value, 3);
+        assert_eq!(config.jobs.origin, Origin::Env("VVCNV_JOBS"));
+        assert_eq!(
+            config.ffmpeg_path.value,
+            Some(PathBuf::from("/opt/ffmpeg/bin/ffmpeg"))
+        );
+        assert_eq!(config.hwaccel.origin, Origin::Default);
+
+        assert!(env_layer(env(&[("VVCNV_JOBS", "all")])).is_err());
+        assert!(env_layer(env(&[("VVCNV_SIZE_UNITS", "metric")])).is_err());
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = TempDir::new("config");
+        let global = dir.join("config.toml");
+        let project = dir.join("vvcnv.toml");
+        fs::write(&global, "jobs = 2\nsize-units = \"binary\"\nnew-key = 1\n").unwrap();
+        fs::write(&project, "jobs = 6\n").unwrap();
+
+        let (config, warnings) = load(Some(&global), Some(&project), |name| {
+            (name == "VVCNV_SIZE_UNITS").then(|| "decimal".to_string())
+        })
+        .unwrap();
+        assert_eq!(config.jobs.value, 6);
+        assert_eq!(config.size_units.value, SizeUnits::Decimal);
+        assert_eq!(config.size_units.origin, Origin::Env("VVCNV_SIZE_UNITS"));
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("new-key"));
+
+        // 存在しないファイルは読み飛ばす. プロジェクト設定の不明なキーはエラー
+        let missing = dir.join("missing.toml");
+        assert!(load(Some(&missing), None, |_| None).is_ok());
+        fs::write(&project, "new-key = 1\n").unwrap();
+        assert!(load(None, Some(&project), |_| None).is_err());
+    }
+}
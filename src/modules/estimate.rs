@@ -0,0 +1,234 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use super::video::{Trim, VideoCodec, VideoConfig, VideoStat};
+
+pub const DEFAULT_PRESET: &str = "medium";
+pub const CALIBRATION_SAMPLE: Duration = Duration::from_secs(5);
+pub const CONFIDENCE_PERCENT: u32 = 40;
+
+/// x264 のプリセットごとの, medium を 1 としたおおよそのエンコード時間の比.
+const PRESET_FACTORS: &[(&str, f64)] = &[
+    ("ultrafast", 0.15),
+    ("superfast", 0.2),
+    ("veryfast", 0.3),
+    ("faster", 0.5),
+    ("fast", 0.7),
+    ("medium", 1.0),
+    ("slow", 1.6),
+    ("slower", 2.8),
+    ("veryslow", 5.5),
+    ("placebo", 15.0),
+];
+
+pub fn preset_factor(preset: &str) -> f64 {
+    PRESET_FACTORS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(preset))
+        .map_or(1.0, |(_, factor)| *factor)
+}
+
+/// 同じプリセットの x264 を 1 としたおおよそのエンコード時間の比. 不明なエンコーダーは x264 と同じとみなす.
+pub fn codec_factor(codec: Option<&VideoCodec>) -> f64 {
+    match codec {
+        None | Some(VideoCodec::H264) | Some(VideoCodec::Other(_)) => 1.0,
+        Some(VideoCodec::H265) => 2.5,
+        Some(VideoCodec::Vp9) => 3.0,
+        Some(VideoCodec::Av1) => 8.0,
+        Some(VideoCodec::ProRes) => 0.3,
+    }
+}
+
+/// x264 の medium で FPS をそのまま変える設定を 1 とした, `config` のエンコード時間の比.
+/// プリセット, コーデックと FPS の変え方 (`--fps-mode`) の比を掛けたもの.
+pub fn cost_factor(config: &VideoConfig) -> f64 {
+    let preset = config.preset.as_deref().unwrap_or(DEFAULT_PRESET);
+    let fps_mode = config.fps_mode.map_or(1.0, |mode| mode.cost_factor());
+
+    preset_factor(preset) * codec_factor(config.codec.as_ref()) * fps_mode
+}
+
+/// 1 つのタスクの計測結果を, [`Calibration::from_samples`] に渡す基準 (x264 の medium) の値にする.
+/// 重い設定で計測した時間を `cost_factor` で割るので, 予測で同じ比を掛けても二重に数えない.
+pub fn sample(
+    stat: &VideoStat,
+    config: &VideoConfig,
+    trim: &Trim,
+    elapsed: Duration,
+) -> (f64, Duration) {
+    (
+        encoded_pixels(stat, config, trim),
+        elapsed.div_f64(cost_factor(config)),
+    )
+}
+
+/// x264 の medium プリセットで 1 秒あたりにエンコードできるピクセル数.
+/// 同時に実行したタスクの数 (`parallelism`) の下で測ったもので, 予測も同じ並列数を前提にする.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Calibration {
+    pub pixel_rate: f64,
+    #[serde(default = "default_parallelism")]
+    pub parallelism: usize,
+}
+
+fn default_parallelism() -> usize {
+    1
+}
+
+impl Calibration {
+    pub fn from_samples(samples: &[(f64, Duration)], parallelism: usize) -> Option<Self> {
+        let pixels = samples.iter().map(|(p, _)| p).sum::<f64>();
+        let secs = samples.iter().map(|(_, d)| d.as_secs_f64()).sum::<f64>();
+        if pixels <= 0.0 || secs <= 0.0 {
+            return None;
+        }
+
+        Some(Self {
+            pixel_rate: pixels / secs,
+            parallelism: parallelism.max(1),
+        })
+    }
+}
+
+pub fn encoded_pixels(stat: &VideoStat, config: &VideoConfig, trim: &Trim) -> f64 {
+    let (width, height) = config.res.to_wh();
+    let fps = (config.fps as f64).min(stat.video_stream.fps as f64);
+
+    width as f64 * height as f64 * fps * trim.output_duration(stat.duration).as_secs_f64()
+}
+
+/// `factor` は設定のエンコード時間の比 ([`cost_factor`]).
+pub fn predict_task(pixels: f64, factor: f64, calibration: &Calibration) -> Duration {
+    Duration::from_secs_f64(pixels / calibration.pixel_rate * factor)
+}
+
+/// `parallelism` にはキャリブレーションを測ったときの並列数を渡す.
+/// 各タスクの予測はその並列数で同時に実行したときの時間なので, 合計を並列数で割ったものが全体の時間になる.
+pub fn predict_total(tasks: &[Duration], parallelism: usize) -> Duration {
+    let longest = tasks.iter().max().copied().unwrap_or_default();
+    let parallelism = parallelism.min(tasks.len()).max(1);
+
+    (tasks.iter().sum::<Duration>() / parallelism as u32).max(longest)
+}
+
+pub fn deviation_percent(predicted: Duration, actual: Duration) -> f64 {
+    match predicted.is_zero() {
+        true => 0.0,
+        false => (actual.as_secs_f64() / predicted.as_secs_f64() - 1.0) * 100.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::video::{VideoRes, VideoStreamInfo};
+
+    #[test]
+    fn test_preset_factor() {
+        assert_eq!(preset_factor("medium"), 1.0);
+        assert!(preset_factor("slow") > preset_factor("medium"));
+        assert!(preset_factor("VeryFast") < 1.0);
+        assert_eq!(preset_factor("unknown"), 1.0);
+    }
+
+    #[test]
+    fn test_calibration_from_samples() {
+        let calibration = Calibration::from_samples(
+            &[
+                (1_000_000.0, Duration::from_secs(1)),
+                (3_000_000.0, Duration::from_secs(1)),
+            ],
+            2,
+        )
+        .unwrap();
+        assert_eq!(calibration.pixel_rate, 2_000_000.0);
+        assert_eq!(calibration.parallelism, 2);
+        assert!(Calibration::from_samples(&[], 1).is_none());
+        assert!(Calibration::from_samples(&[(1.0, Duration::ZERO)], 1).is_none());
+    }
+
+    #[test]
+    fn test_predict() {
+        let calibration = Calibration {
+            pixel_rate: 1_000_000.0,
+            parallelism: 1,
+        };
+        assert_eq!(
+            predict_task(10_000_000.0, 1.0, &calibration),
+            Duration::from_secs(10)
+        );
+        assert_eq!(
+            predict_task(10_000_000.0, preset_factor("slow"), &calibration),
+            Duration::from_secs(16)
+        );
+
+        let tasks = [10, 10, 10, 10].map(Duration::from_secs);
+        assert_eq!(predict_total(&tasks, 1), Duration::from_secs(40));
+        assert_eq!(predict_total(&tasks, 2), Duration::from_secs(20));
+        assert_eq!(predict_total(&tasks, 8), Duration::from_secs(10));
+        assert_eq!(
+            predict_total(&[40, 1, 1].map(Duration::from_secs), 3),
+            Duration::from_secs(40)
+        );
+        assert_eq!(predict_total(&[], 4), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_calibration_mixed_presets() {
+        let stat = VideoStat {
+            path: "in.mp4".to_string(),
+            video_stream: VideoStreamInfo {
+                fps: 30.0,
+                ..Default::default()
+            },
+            video_stream_index: 0,
+            audio_streams: vec![],
+            cover_stream_indices: vec![],
+            ignored_stream_indices: vec![],
+            duration: Duration::from_secs(10),
+            start_time: Duration::ZERO,
+            file_size: 0,
+        };
+        let config = |preset: &str, codec| VideoConfig {
+            res: VideoRes::Other(100, 100),
+            fps: 10,
+            preset: Some(preset.to_string()),
+            codec,
+            ..Default::default()
+        };
+        let (medium, slow, h265) = (
+            config("medium", None),
+            config("slow", None),
+            config("medium", Some(VideoCodec::H265)),
+        );
+        assert_eq!(cost_factor(&slow), 1.6);
+        assert_eq!(cost_factor(&h265), 2.5);
+
+        // 同じ速さのマシンで, slow と H.265 は重さの分だけ時間がかかった
+        let trim = Trim::default();
+        let samples = [
+            sample(&stat, &medium, &trim, Duration::from_secs(1)),
+            sample(&stat, &slow, &trim, Duration::from_millis(1600)),
+            sample(&stat, &h265, &trim, Duration::from_millis(2500)),
+        ];
+        let calibration = Calibration::from_samples(&samples, 1).unwrap();
+        assert!((calibration.pixel_rate - 1_000_000.0).abs() < 1e-6);
+
+        // 予測でも重さを 1 回だけ掛ける
+        let pixels = encoded_pixels(&stat, &slow, &trim);
+        let predicted = predict_task(pixels, cost_factor(&slow), &calibration);
+        assert!((predicted.as_secs_f64() - 1.6).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_deviation_percent() {
+        assert_eq!(
+            deviation_percent(Duration::from_secs(100), Duration::from_secs(150)),
+            50.0
+        );
+        assert_eq!(
+            deviation_percent(Duration::ZERO, Duration::from_secs(1)),
+            0.0
+        );
+    }
+}
@@ -0,0 +1,218 @@
+use core::fmt;
+use ffmpeg_sidecar::command::FfmpegCommand;
+use serde::{Deserialize, Serialize};
+use std::{env, path::PathBuf, process::Command, sync::OnceLock};
+
+use super::config;
+
+/// 使用する ffmpeg. 設定の `ffmpeg-path` がなければ ffmpeg-sidecar の既定 (PATH 上の ffmpeg など) を使う.
+pub fn ffmpeg_path() -> PathBuf {
+    config::current()
+        .ffmpeg_path
+        .value
+        .clone()
+        .unwrap_or_else(ffmpeg_sidecar::paths::ffmpeg_path)
+}
+
+/// `ffmpeg-path` と同じディレクトリに ffprobe があればそれを, なければ ffmpeg-sidecar の既定を使う.
+pub fn ffprobe_path() -> PathBuf {
+    config::current()
+        .ffmpeg_path
+        .value
+        .as_ref()
+        .and_then(|path| path.parent())
+        .map(|dir| dir.join(format!("ffprobe{}", env::consts::EXE_SUFFIX)))
+        .filter(|path| path.is_file())
+        .unwrap_or_else(ffmpeg_sidecar::ffprobe::ffprobe_path)
+}
+
+pub fn command() -> FfmpegCommand {
+    FfmpegCommand::new_with_path(ffmpeg_path())
+}
+
+/// `ffmpeg -version` から読み取ったバージョンとビルド構成.
+/// ビルドによってエンコード結果が変わるので, 出力や履歴と一緒に記録しておく.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FfmpegBuild {
+    pub version: String,
+    /// `configuration:` のうち `--enable-*` で有効にされた機能 (`libx264` など).
+    #[serde(default)]
+    pub enabled: Vec<String>,
+}
+
+impl fmt::Display for FfmpegBuild {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.version)
+    }
+}
+
+impl FfmpegBuild {
+    pub fn is_enabled(&self, feature: &str) -> bool {
+        self.enabled.iter().any(|f| f == feature)
+    }
+}
+
+/// `ffmpeg version <バージョン> ...` の行と `configuration: ...` の行を読む.
+/// ディストリビューションのビルドは `4.4.2-0ubuntu0.22.04.1`, BtbN のビルドは `N-113448-g...` のように
+/// バージョンの書式が異なるので, 空白までをそのままバージョンとして扱う.
+pub fn parse_banner(banner: &str) -> Option<FfmpegBuild> {
+    let mut lines = banner.lines().map(str::trim);
+    let version = lines
+        .by_ref()
+        .find_map(|line| line.strip_prefix("ffmpeg version "))?
+        .split_whitespace()
+        .next()?
+        .to_string();
+    let enabled = lines
+        .find_map(|line| line.strip_prefix("configuration:"))
+        .map(|configuration| {
+            configuration
+                .split_whitespace()
+                .filter_map(|flag| flag.strip_prefix("--enable-"))
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Some(FfmpegBuild { version, enabled })
+}
+
+/// 使用する ffmpeg のビルドを調べる. 起動中に ffmpeg が変わることはないので一度だけ実行する.
+pub fn detect() -> Option<FfmpegBuild> {
+    static BUILD: OnceLock<Option<FfmpegBuild>> = OnceLock::new();
+    BUILD
+        .get_or_init(|| {
+            let output = Command::new(ffmpeg_path()).arg("-version").output().ok()?;
+            parse_banner(&String::from_utf8_lossy(&output.stdout))
+        })
+        .clone()
+}
+
+/// `ffmpeg -encoders` の一覧から, 映像のエンコーダーの名前を読む.
+/// 一覧は `------` の行の後に `V....D libx264  libx264 H.264 ...` のように, 種類とフラグ, 名前, 説明の順で並ぶ.
+pub fn parse_encoders(list: &str) -> Vec<String> {
+    list.lines()
+        .map(str::trim)
+        .skip_while(|line| !line.starts_with("---"))
+        .skip(1)
+        .filter_map(|line| {
+            let mut columns = line.split_whitespace();
+            let flags = columns.next()?;
+            flags.starts_with('V').then(|| columns.next()).flatten()
+        })
+        .map(String::from)
+        .collect()
+}
+
+/// 使用する ffmpeg で使える映像のエンコーダー. `detect` と同じく一度だけ実行する.
+pub fn encoders() -> Option<&'static [String]> {
+    static ENCODERS: OnceLock<Option<Vec<String>>> = OnceLock::new();
+    ENCODERS
+        .get_or_init(|| {
+            let output = Command::new(ffmpeg_path())
+                .args(["-hide_banner", "-encoders"])
+                .output()
+                .ok()?;
+            Some(parse_encoders(&String::from_utf8_lossy(&output.stdout)))
+                .filter(|encoders| !encoders.is_empty())
+        })
+        .as_deref()
+}
+
+/// 記録されたバージョンと現在のバージョンが異なる場合に, 警告の文面を返す.
+pub fn version_mismatch(recorded: Option<&str>, current: Option<&str>) -> Option<String> {
+    match (recorded, current) {
+        (Some(recorded), Some(current)) if recorded != current => Some(format!(
+            "記録時と ffmpeg のバージョンが異なります (記録時: {}, 現在: {}). 出力が一致しない可能性があります.",
+            recorded, current
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UBUNTU: &str = "\
+ffmpeg version 4.4.2-0ubuntu0.22.04.1 Copyright (c) 2000-2021 the FFmpeg developers
+built with gcc 11 (Ubuntu 11.2.0-19ubuntu1)
+configuration: --prefix=/usr --extra-version=0ubuntu0.22.04.1 --toolchain=hardened --libdir=/usr/lib/x86_64-linux-gnu --enable-gpl --disable-stripping --enable-gnutls --enable-libaom --enable-libvpx --enable-libx264 --enable-libx265 --enable-shared
+libavutil      56. 70.100 / 56. 70.100
+libavcodec     58.134.100 / 58.134.100
+";
+
+    const BTBN: &str = "\
+ffmpeg version N-113448-g1cd5ab2a3b-20240128 Copyright (c) 2000-2024 the FFmpeg developers
+built with gcc 13.2.0 (crosstool-NG 1.25.0.232_c175b21)
+configuration: --prefix=/ffbuild/prefix --pkg-config-flags=--static --pkg-config=pkg-config --cross-prefix=x86_64-w64-mingw32- --arch=x86_64 --target-os=mingw32 --enable-gpl --enable-version3 --disable-debug --enable-libsvtav1 --enable-nvenc --enable-libx264 --extra-cflags=-DLIBTWOLAME_STATIC
+libavutil      58. 36.101 / 58. 36.101
+";
+
+    const HOMEBREW: &str = "\
+ffmpeg version 7.1 Copyright (c) 2000-2024 the FFmpeg developers
+built with Apple clang version 16.0.0 (clang-1600.0.26.4)
+configuration: --prefix=/opt/homebrew/Cellar/ffmpeg/7.1_3 --enable-shared --enable-pthreads --enable-version3 --cc=clang --host-cflags= --host-ldflags='-Wl,-ld_classic' --enable-ffplay --enable-gpl --enable-libsvtav1 --enable-libvpx --enable-libx264 --enable-libx265 --enable-videotoolbox --enable-audiotoolbox --enable-neon
+libavutil      59. 39.100 / 59. 39.100
+";
+
+    #[test]
+    fn test_parse_banner() {
+        let ubuntu = parse_banner(UBUNTU).unwrap();
+        assert_eq!(ubuntu.version, "4.4.2-0ubuntu0.22.04.1");
+        assert_eq!(
+            ubuntu.enabled,
+            ["gpl", "gnutls", "libaom", "libvpx", "libx264", "libx265", "shared"]
+        );
+
+        let btbn = parse_banner(BTBN).unwrap();
+        assert_eq!(btbn.version, "N-113448-g1cd5ab2a3b-20240128");
+        assert!(btbn.is_enabled("nvenc"));
+        assert!(!btbn.is_enabled("debug"));
+
+        let homebrew = parse_banner(HOMEBREW).unwrap();
+        assert_eq!(homebrew.version, "7.1");
+        assert!(homebrew.is_enabled("videotoolbox"));
+        assert_eq!(homebrew.to_string(), "7.1");
+    }
+
+    #[test]
+    fn test_parse_banner_partial() {
+        let build = parse_banner("ffmpeg version n6.1.1 Copyright (c) 2000-2023").unwrap();
+        assert_eq!(build.version, "n6.1.1");
+        assert!(build.enabled.is_empty());
+
+        assert!(parse_banner("").is_none());
+        assert!(parse_banner("ffprobe version 7.1 Copyright (c) 2007-2024").is_none());
+        assert!(parse_banner("ffmpeg version ").is_none());
+    }
+
+    #[test]
+    fn test_parse_encoders() {
+        let list = "\
+Encoders:
+ V..... = Video
+ A..... = Audio
+ ------
+ V....D libx264              libx264 H.264 / AVC / MPEG-4 AVC / MPEG-4 part 10 (codec h264)
+ V....D h264_nvenc           NVIDIA NVENC H.264 encoder (codec h264)
+ A....D aac                  AAC (Advanced Audio Coding)
+ V....D hevc_vaapi           H.265/HEVC (VAAPI) (codec hevc)
+";
+        assert_eq!(
+            parse_encoders(list),
+            ["libx264", "h264_nvenc", "hevc_vaapi"]
+        );
+        assert!(parse_encoders("").is_empty());
+    }
+
+    #[test]
+    fn test_version_mismatch() {
+        assert!(version_mismatch(Some("7.1"), Some("7.1")).is_none());
+        assert!(version_mismatch(None, Some("7.1")).is_none());
+        assert!(version_mismatch(Some("7.1"), None).is_none());
+        assert!(version_mismatch(Some("6.1.1"), Some("7.1"))
+            .unwrap()
+            .contains("記録時: 6.1.1, 現在: 7.1"));
+    }
+}
@@ -1,26 +1,274 @@
 use anyhow::{Context, Result};
-use std::{fs, io};
+use core::fmt;
+use std::{
+    fs::{self, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+/// 書き出し中の出力の大きさを調べる間隔.
+pub const SIZE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug)]
+pub struct NotWritable {
+    pub dir: PathBuf,
+    pub source: io::Error,
+}
+
+impl fmt::Display for NotWritable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.source.kind() {
+            io::ErrorKind::NotADirectory => write!(
+                f,
+                "ディレクトリではなくファイルです: {}. 別のディレクトリを指定してください",
+                self.dir.display()
+            ),
+            _ => write!(
+                f,
+                "ディレクトリに書き込めません: {} ({}). 書き込み権限を確認するか, 別のディレクトリを指定してください",
+                self.dir.display(),
+                self.source
+            ),
+        }
+    }
+}
+
+impl std::error::Error for NotWritable {}
+
+#[derive(Debug)]
+pub struct NotReadable {
+    pub path: PathBuf,
+    pub source: io::Error,
+}
+
+impl fmt::Display for NotReadable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.source.kind() {
+            io::ErrorKind::NotFound => {
+                write!(f, "入力ファイルが見つかりません: {}", self.path.display())
+            }
+            _ => write!(
+                f,
+                "入力ファイルを読めません: {} ({})",
+                self.path.display(),
+                self.source
+            ),
+        }
+    }
+}
+
+impl std::error::Error for NotReadable {}
+
+/// 出力先にファイルがすでにあるため, 上書きせずに中止した.
+#[derive(Debug)]
+pub struct OutputExists {
+    pub path: PathBuf,
+}
+
+impl fmt::Display for OutputExists {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "出力先にファイルがすでにあるため上書きしませんでした: {}. 上書きするには --overwrite を指定してください",
+            self.path.display()
+        )
+    }
+}
+
+impl std::error::Error for OutputExists {}
+
+/// 出力先に何もないことを確かめる. 壊れたシンボリックリンクも上書きの対象として扱う.
+pub fn check_not_exists(path: &Path) -> Result<(), OutputExists> {
+    match fs::symlink_metadata(path) {
+        Ok(_) => Err(OutputExists {
+            path: path.to_path_buf(),
+        }),
+        Err(_) => Ok(()),
+    }
+}
+
+/// ffmpeg に渡す前に, 入力が読み込めるファイルであることを確かめる.
+pub fn check_readable(path: &Path) -> Result<(), NotReadable> {
+    let not_readable = |source| NotReadable {
+        path: path.to_path_buf(),
+        source,
+    };
+    if fs::metadata(path).map_err(not_readable)?.is_dir() {
+        return Err(not_readable(io::ErrorKind::IsADirectory.into()));
+    }
+    fs::File::open(path).map(|_| ()).map_err(not_readable)
+}
+
+/// ディレクトリを作成し, 小さなファイルを作成・削除できるかを確かめる.
+pub fn check_writable(dir: &Path) -> Result<(), NotWritable> {
+    let not_writable = |source| NotWritable {
+        dir: dir.to_path_buf(),
+        source,
+    };
+    // 途中にファイルがあると create_dir_all が分かりにくいエラーを返すので, 先に確かめる
+    if let Some(file) = dir.ancestors().find(|path| path.is_file()) {
+        return Err(NotWritable {
+            dir: file.to_path_buf(),
+            source: io::ErrorKind::NotADirectory.into(),
+        });
+    }
+    fs::create_dir_all(dir).map_err(not_writable)?;
+
+    let probe = dir.join(format!(".vvcnv-write-test-{}", std::process::id()));
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+        .map_err(not_writable)?;
+    fs::remove_file(&probe).map_err(not_writable)
+}
+
+/// 元動画から求めた結果を使い回すかどうか決める, 大きさと更新時刻 (UNIX 秒).
+pub fn cache_key(input_path: &str) -> Result<(u64, u64)> {
+    let metadata = fs::metadata(input_path)
+        .with_context(|| format!("元動画の情報取得に失敗しました: {}", input_path))?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs());
+
+    Ok((metadata.len(), modified))
+}
 
 pub fn calc_size(path: &str) -> Result<u64, io::Error> {
     let metadata = fs::metadata(path)?;
     Ok(metadata.len())
 }
 
+/// 書き出し中のファイルの大きさ. ファイルシステムに負担をかけないよう, `interval` の間は前回の値を返す.
+/// まだファイルがない場合は 0 とする.
+#[derive(Debug)]
+pub struct SizePoll {
+    path: String,
+    interval: Duration,
+    last: Option<(Instant, u64)>,
+}
+
+impl SizePoll {
+    pub fn new(path: impl Into<String>, interval: Duration) -> Self {
+        Self {
+            path: path.into(),
+            interval,
+            last: None,
+        }
+    }
+
+    pub fn size(&mut self, now: Instant) -> u64 {
+        match self.last {
+            Some((at, size)) if now.saturating_duration_since(at) < self.interval => size,
+            _ => {
+                let size = calc_size(&self.path).unwrap_or(0);
+                self.last = Some((now, size));
+                size
+            }
+        }
+    }
+}
+
+/// ファイル名から拡張子を除いた部分と, 拡張子を返す. ディレクトリの部分は含めない.
+/// 拡張子は最後の `.` の後ろなので, `clip.v2.mp4` は (`clip.v2`, `mp4`) になる.
 pub fn get_file_name(path: &str) -> (String, String) {
-    let file_name = path.split('/').last().unwrap();
-    let mut file_name_parts = file_name.split('.');
-    let file_name_without_ext = file_name_parts.next().unwrap();
-    let ext = file_name_parts.next().unwrap();
-    (file_name_without_ext.to_string(), ext.to_string())
+    let path = Path::new(path);
+    let part =
+        |s: Option<&std::ffi::OsStr>| s.map_or(String::new(), |s| s.to_string_lossy().into_owned());
+    (part(path.file_stem()), part(path.extension()))
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::modules::testing::TempDir;
+
+    #[test]
+    fn test_size_poll() {
+        let dir = TempDir::new("size-poll");
+        let path = dir.join("clip.mp4");
+        let mut poll = SizePoll::new(path.to_string_lossy(), Duration::from_secs(2));
+        let now = Instant::now();
+        assert_eq!(poll.size(now), 0);
+
+        fs::write(&path, [0; 100]).unwrap();
+        // 間隔の間は調べ直さない
+        assert_eq!(poll.size(now + Duration::from_secs(1)), 0);
+        assert_eq!(poll.size(now + Duration::from_secs(2)), 100);
+    }
+
+    #[test]
+    fn test_check_writable() {
+        let dir = TempDir::new("writable");
+        check_writable(&dir.join("nested")).unwrap();
+        assert_eq!(fs::read_dir(dir.join("nested")).unwrap().count(), 0);
+
+        let file = dir.join("file");
+        fs::write(&file, "").unwrap();
+        for path in [file.clone(), file.join("out")] {
+            let e = check_writable(&path).unwrap_err();
+            assert_eq!(e.dir, file);
+            assert!(e
+                .to_string()
+                .starts_with("ディレクトリではなくファイルです"));
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let readonly = dir.join("readonly");
+            fs::create_dir_all(&readonly).unwrap();
+            fs::set_permissions(&readonly, fs::Permissions::from_mode(0o555)).unwrap();
+            // root は権限に関係なく書き込めるので確かめられない
+            if unsafe { libc::geteuid() } != 0 {
+                let e = check_writable(&readonly).unwrap_err();
+                assert_eq!(e.dir, readonly);
+                assert_eq!(e.source.kind(), io::ErrorKind::PermissionDenied);
+                assert!(e.to_string().contains(&readonly.display().to_string()));
+            }
+            fs::set_permissions(&readonly, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_check_readable() {
+        let dir = TempDir::new("readable");
+        fs::write(dir.join("a.mp4"), "a").unwrap();
+        check_readable(&dir.join("a.mp4")).unwrap();
+
+        let e = check_readable(&dir.join("missing.mp4")).unwrap_err();
+        assert_eq!(e.source.kind(), io::ErrorKind::NotFound);
+        assert!(e.to_string().starts_with("入力ファイルが見つかりません"));
+        let e = check_readable(&dir).unwrap_err();
+        assert_eq!(e.source.kind(), io::ErrorKind::IsADirectory);
+    }
+
+    #[test]
+    fn test_check_not_exists() {
+        let dir = TempDir::new("exists");
+        let path = dir.join("clip.mp4");
+        check_not_exists(&path).unwrap();
+
+        fs::write(&path, "").unwrap();
+        let e = check_not_exists(&path).unwrap_err();
+        assert_eq!(e.path, path);
+        assert!(e.to_string().contains("--overwrite"));
+    }
+
     #[test]
     fn test_get_file_name() {
         let path = "assets/2.mp4";
         let (file_name, file_name_without_ext) = super::get_file_name(path);
         assert_eq!(file_name, "2");
         assert_eq!(file_name_without_ext, "mp4");
+
+        let (name, ext) = super::get_file_name("captures/2024-01/clip.v2.MKV");
+        assert_eq!((name.as_str(), ext.as_str()), ("clip.v2", "MKV"));
+        let (name, ext) = super::get_file_name("capture");
+        assert_eq!((name.as_str(), ext.as_str()), ("capture", ""));
     }
 }
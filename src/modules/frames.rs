@@ -0,0 +1,254 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// 落としたフレームがこの割合を超えたら注意を表示する.
+pub const DROP_WARN_RATIO: f64 = 0.01;
+
+/// 出力したフレームの数と, FPS の変換などで ffmpeg が複製 (`dup`) / 破棄 (`drop`) したフレームの数.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FrameCounts {
+    pub frames: u64,
+    pub dup: u64,
+    pub drop: u64,
+}
+
+impl FrameCounts {
+    /// 出力したフレームと落としたフレームの合計に対する, 落としたフレームの割合.
+    pub fn drop_ratio(&self) -> f64 {
+        match self.frames + self.drop {
+            0 => 0.0,
+            total => self.drop as f64 / total as f64,
+        }
+    }
+
+    pub fn drops_exceed_threshold(&self) -> bool {
+        self.drop_ratio() > DROP_WARN_RATIO
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.dup == 0 && self.drop == 0
+    }
+
+    /// 進捗の表示に付ける注意.
+    pub fn note(&self) -> Option<String> {
+        self.drops_exceed_threshold().then(|| {
+            format!(
+                "{} フレーム破棄 ({:.1}%)",
+                self.drop,
+                self.drop_ratio() * 100.0
+            )
+        })
+    }
+}
+
+/// ffmpeg が報告するエンコードの速さ. `speed` は元動画の再生時間に対する倍率.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct EncodeSpeed {
+    pub speed: f32,
+    pub fps: f32,
+}
+
+impl fmt::Display for EncodeSpeed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:.1}x ({:.0} fps)", self.speed, self.fps)
+    }
+}
+
+fn field(line: &str, key: &str) -> Option<u64> {
+    line.split_whitespace()
+        .find_map(|token| token.strip_prefix(key))
+        .and_then(|value| value.parse().ok())
+}
+
+/// ffmpeg の進捗行 (`frame=  240 fps= 60 ... dup=3 drop=12 speed=2x`) の, その時点までの累計.
+/// `dup=` / `drop=` はビルドや設定によって出力されないので, ない場合は 0 とする.
+pub fn parse_progress_line(line: &str, frame: u32) -> FrameCounts {
+    FrameCounts {
+        frames: frame as u64,
+        dup: field(line, "dup=").unwrap_or(0),
+        drop: field(line, "drop=").unwrap_or(0),
+    }
+}
+
+/// タスクごとの累計. 分割エンコードでは各分割の ffmpeg の分を合計する.
+/// 書き出した出力の大きさ (バイト) も同じように合計する.
+#[derive(Debug, Clone, Default)]
+pub struct FrameLog {
+    inner: Arc<Mutex<FrameCounts>>,
+    bytes: Arc<AtomicU64>,
+    /// 実行中の ffmpeg ごとの最新の速さ. 終わった ffmpeg の分は取り除く.
+    speeds: Arc<Mutex<HashMap<u64, EncodeSpeed>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl FrameLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn counts(&self) -> FrameCounts {
+        *self.inner.lock().unwrap()
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::SeqCst)
+    }
+
+    /// 実行中の ffmpeg の速さの合計. 分割エンコードでは並行する分割の分を足す.
+    pub fn speed(&self) -> Option<EncodeSpeed> {
+        let speeds = self.speeds.lock().unwrap();
+        (!speeds.is_empty()).then(|| {
+            speeds
+                .values()
+                .fold(EncodeSpeed::default(), |sum, s| EncodeSpeed {
+                    speed: sum.speed + s.speed,
+                    fps: sum.fps + s.fps,
+                })
+        })
+    }
+
+    fn add(&self, delta: FrameCounts) {
+        let mut counts = self.inner.lock().unwrap();
+        counts.frames += delta.frames;
+        counts.dup += delta.dup;
+        counts.drop += delta.drop;
+    }
+}
+
+/// 1 つの ffmpeg の累計を `FrameLog` に反映する. ffmpeg は累計を出力するので, 前回からの増加分だけを足す.
+#[derive(Debug, Default)]
+pub struct FrameTracker {
+    last: FrameCounts,
+    last_bytes: u64,
+    /// 速さを記録した先と, その中でこの ffmpeg を表す番号. drop で取り除く.
+    speed_slot: Option<(FrameLog, u64)>,
+}
+
+impl FrameTracker {
+    pub fn update(&mut self, log: &FrameLog, current: FrameCounts) {
+        let delta = FrameCounts {
+            frames: current.frames.saturating_sub(self.last.frames),
+            dup: current.dup.saturating_sub(self.last.dup),
+            drop: current.drop.saturating_sub(self.last.drop),
+        };
+        self.last = FrameCounts {
+            frames: self.last.frames.max(current.frames),
+            dup: self.last.dup.max(current.dup),
+            drop: self.last.drop.max(current.drop),
+        };
+        log.add(delta);
+    }
+
+    /// ffmpeg の進捗の `size=` (その時点までに書き出した大きさ) を反映する.
+    pub fn update_bytes(&mut self, log: &FrameLog, bytes: u64) {
+        let delta = bytes.saturating_sub(self.last_bytes);
+        self.last_bytes = self.last_bytes.max(bytes);
+        log.bytes.fetch_add(delta, Ordering::SeqCst);
+    }
+
+    /// ffmpeg の進捗の `speed=` と `fps=` を反映する. 始めのうちの 0 (N/A) は記録しない.
+    pub fn update_speed(&mut self, log: &FrameLog, speed: EncodeSpeed) {
+        if speed.speed <= 0.0 {
+            return;
+        }
+        let (log, id) = self
+            .speed_slot
+            .get_or_insert_with(|| (log.clone(), log.next_id.fetch_add(1, Ordering::SeqCst)));
+        log.speeds.lock().unwrap().insert(*id, speed);
+    }
+}
+
+impl Drop for FrameTracker {
+    fn drop(&mut self) {
+        if let Some((log, id)) = &self.speed_slot {
+            log.speeds.lock().unwrap().remove(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_progress_line() {
+        let line = "frame=  240 fps= 60 q=28.0 size=    1024KiB time=00:00:08.00 bitrate=1048.6kbits/s dup=3 drop=12 speed=2.01x";
+        assert_eq!(
+            parse_progress_line(line, 240),
+            FrameCounts {
+                frames: 240,
+                dup: 3,
+                drop: 12
+            }
+        );
+
+        // 古い書式と, dup / drop を出力しないビルド
+        let line = "[info] frame=  30 fps=0.0 q=-1.0 Lsize=     256kB time=00:00:01.00 bitrate=2097.2kbits/s dup=0 drop=1 speed=3.5x";
+        assert_eq!(parse_progress_line(line, 30).drop, 1);
+        let line = "frame=  120 fps= 30 q=23.0 size=     512KiB time=00:00:04.00 bitrate=1048.6kbits/s speed=1x";
+        assert!(parse_progress_line(line, 120).is_clean());
+        assert_eq!(parse_progress_line("drop=abc", 0).drop, 0);
+    }
+
+    #[test]
+    fn test_frame_log() {
+        let log = FrameLog::new();
+        let mut a = FrameTracker::default();
+        let mut b = FrameTracker::default();
+        let counts = |frames, drop| FrameCounts {
+            frames,
+            dup: 0,
+            drop,
+        };
+
+        // 分割ごとの累計を合計する
+        a.update(&log, counts(100, 1));
+        a.update(&log, counts(200, 3));
+        b.update(&log, counts(150, 0));
+        a.update(&log, counts(200, 3));
+        assert_eq!(log.counts(), counts(350, 3));
+        assert!(!log.counts().drops_exceed_threshold());
+        assert_eq!(log.counts().note(), None);
+
+        b.update(&log, counts(300, 10));
+        assert_eq!(log.counts(), counts(500, 13));
+        assert!(log.counts().drops_exceed_threshold());
+        assert_eq!(log.counts().note().unwrap(), "13 フレーム破棄 (2.5%)");
+        assert_eq!(FrameCounts::default().drop_ratio(), 0.0);
+
+        a.update_bytes(&log, 1000);
+        b.update_bytes(&log, 500);
+        a.update_bytes(&log, 1500);
+        a.update_bytes(&log, 1200);
+        assert_eq!(log.bytes(), 2000);
+    }
+
+    #[test]
+    fn test_speed() {
+        let log = FrameLog::new();
+        let speed = |speed, fps| EncodeSpeed { speed, fps };
+        let mut a = FrameTracker::default();
+        a.update_speed(&log, speed(0.0, 0.0));
+        assert_eq!(log.speed(), None);
+
+        a.update_speed(&log, speed(1.5, 45.0));
+        a.update_speed(&log, speed(2.0, 60.0));
+        let mut b = FrameTracker::default();
+        b.update_speed(&log, speed(0.4, 12.0));
+        assert_eq!(log.speed().unwrap().to_string(), "2.4x (72 fps)");
+
+        // 終わった ffmpeg の分は数えない
+        drop(a);
+        assert_eq!(log.speed(), Some(speed(0.4, 12.0)));
+        drop(b);
+        assert_eq!(log.speed(), None);
+    }
+}
@@ -0,0 +1,167 @@
+use std::iter::zip;
+
+/// 入力ごとにまとめた進捗の, 各タスクの状態.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    /// 実行の許可を待っている.
+    Waiting,
+    Running,
+    Done,
+}
+
+/// タスクの開始と終了. `TaskBars::set_active` から届く.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskEvent {
+    Started(usize),
+    Finished(usize),
+}
+
+/// 表示する行. 見出しは入力の全体の進捗.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Row {
+    Header,
+    Task(usize),
+}
+
+/// 1 つの入力のタスクの進捗をまとめる. 表示 (indicatif) とは切り離して, 表示する行だけを決める.
+/// `collapse` (`--collapse-groups`) の場合は, タスクが始まるまでと全部終わった後は見出しの 1 行だけにする.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BarGroup {
+    tasks: Vec<TaskState>,
+    collapse: bool,
+}
+
+impl BarGroup {
+    pub fn new(tasks: usize, collapse: bool) -> Self {
+        Self {
+            tasks: vec![TaskState::Waiting; tasks],
+            collapse,
+        }
+    }
+
+    /// 終わったタスクは, 後から開始の知らせが届いても終わったままにする.
+    pub fn apply(&mut self, event: TaskEvent) {
+        let (index, state) = match event {
+            TaskEvent::Started(index) => (index, TaskState::Running),
+            TaskEvent::Finished(index) => (index, TaskState::Done),
+        };
+        if let Some(task) = self.tasks.get_mut(index) {
+            if *task != TaskState::Done {
+                *task = state;
+            }
+        }
+    }
+
+    pub fn state(&self, index: usize) -> TaskState {
+        self.tasks[index]
+    }
+
+    pub fn is_running(&self, index: usize) -> bool {
+        self.state(index) == TaskState::Running
+    }
+
+    /// 1 つでもタスクが始まり, まだ終わっていないものがある.
+    pub fn is_active(&self) -> bool {
+        self.tasks.iter().any(|t| *t != TaskState::Waiting) && !self.is_finished()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.tasks.iter().all(|t| *t == TaskState::Done)
+    }
+
+    /// 終わったタスクの数と, 全体のタスクの数.
+    pub fn progress(&self) -> (usize, usize) {
+        let done = self.tasks.iter().filter(|t| **t == TaskState::Done).count();
+        (done, self.tasks.len())
+    }
+
+    /// 全体で処理したフレーム数の見積もり. 各タスクは `frames` フレームとして, 実行中のタスクは進捗 (位置と長さ) の割合,
+    /// 終わったタスクはスキップや失敗でも全部を数えるので, すべて終われば必ず `frames * タスクの数` になる.
+    pub fn frames(&self, frames: u64, progress: &[(u64, u64)]) -> u64 {
+        zip(&self.tasks, progress)
+            .map(|(state, &(position, length))| match state {
+                TaskState::Waiting => 0,
+                TaskState::Running if length == 0 => 0,
+                TaskState::Running => {
+                    (frames as f64 * (position.min(length) as f64 / length as f64)) as u64
+                }
+                TaskState::Done => frames,
+            })
+            .sum()
+    }
+
+    /// 上から順に表示する行. タスクは設定の順に並べる.
+    pub fn rows(&self) -> Vec<Row> {
+        let expanded = !self.collapse || self.is_active();
+        let tasks = (0..self.tasks.len()).map(Row::Task).filter(|_| expanded);
+        [Row::Header].into_iter().chain(tasks).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames() {
+        let mut group = BarGroup::new(3, false);
+        let progress = [(50, 100), (30, 0), (10, 10)];
+        assert_eq!(group.frames(300, &progress), 0);
+
+        group.apply(TaskEvent::Started(0));
+        group.apply(TaskEvent::Started(1));
+        assert_eq!(group.frames(300, &progress), 150);
+
+        // スキップしたタスクは開始しないまま終わる
+        group.apply(TaskEvent::Finished(2));
+        group.apply(TaskEvent::Finished(1));
+        assert_eq!(group.frames(300, &progress), 750);
+        group.apply(TaskEvent::Finished(0));
+        assert_eq!(group.frames(300, &progress), 900);
+    }
+
+    #[test]
+    fn test_rows() {
+        let mut group = BarGroup::new(3, false);
+        assert_eq!(
+            group.rows(),
+            [Row::Header, Row::Task(0), Row::Task(1), Row::Task(2)]
+        );
+        group.apply(TaskEvent::Started(1));
+        assert!(group.is_running(1));
+        assert!(group.is_active());
+        for index in 0..3 {
+            group.apply(TaskEvent::Finished(index));
+        }
+        assert!(group.is_finished());
+        // まとめない場合は終わった後もすべて表示する
+        assert_eq!(group.rows().len(), 4);
+    }
+
+    #[test]
+    fn test_collapse() {
+        let mut group = BarGroup::new(2, true);
+        assert_eq!(group.rows(), [Row::Header]);
+        assert_eq!(group.progress(), (0, 2));
+
+        group.apply(TaskEvent::Started(0));
+        assert_eq!(group.rows(), [Row::Header, Row::Task(0), Row::Task(1)]);
+        assert_eq!(group.state(1), TaskState::Waiting);
+
+        // 開始せずに終わる (時間制限など) タスクもある
+        group.apply(TaskEvent::Finished(1));
+        group.apply(TaskEvent::Finished(0));
+        assert_eq!(group.progress(), (2, 2));
+        assert!(!group.is_active());
+        assert_eq!(group.rows(), [Row::Header]);
+
+        // 終わった後に開始の知らせが届いても開き直さない
+        group.apply(TaskEvent::Started(0));
+        assert_eq!(group.state(0), TaskState::Done);
+        assert_eq!(group.rows(), [Row::Header]);
+
+        // タスクのない入力は初めから終わっている
+        assert_eq!(BarGroup::new(0, true).rows(), [Row::Header]);
+        assert!(BarGroup::new(0, false).is_finished());
+    }
+}
@@ -0,0 +1,163 @@
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use super::{estimate::Calibration, report::SessionReport};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: u64,
+    pub started_at: u64,
+    #[serde(flatten)]
+    pub report: SessionReport,
+}
+
+pub struct History {
+    path: PathBuf,
+}
+
+impl History {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn open_default() -> Result<Self> {
+        let dir = dirs::data_dir().ok_or_else(|| anyhow!("データディレクトリが見つかりません"))?;
+
+        Ok(Self::new(dir.join("vvcnv").join("history.jsonl")))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// 壊れた行 (書き込み途中で中断された場合など) は読み飛ばす.
+    pub fn load(&self) -> Result<Vec<HistoryEntry>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("履歴の読み込みに失敗しました: {}", self.path.display())
+                })
+            }
+        };
+
+        Ok(content
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+
+    pub fn find(&self, id: u64) -> Result<Option<HistoryEntry>> {
+        Ok(self.load()?.into_iter().find(|e| e.id == id))
+    }
+
+    pub fn latest_calibration(&self) -> Result<Option<Calibration>> {
+        Ok(self
+            .load()?
+            .into_iter()
+            .rev()
+            .find_map(|e| e.report.calibration))
+    }
+
+    pub fn append(&self, started_at: u64, report: SessionReport) -> Result<u64> {
+        let id = self.load()?.last().map_or(1, |e| e.id + 1);
+        let entry = HistoryEntry {
+            id,
+            started_at,
+            report,
+        };
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).with_context(|| {
+                format!("履歴ディレクトリの作成に失敗しました: {}", dir.display())
+            })?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("履歴ファイルを開けません: {}", self.path.display()))?;
+        writeln!(file, "{}", serde_json::to_string(&entry)?)
+            .with_context(|| format!("履歴の書き込みに失敗しました: {}", self.path.display()))?;
+
+        Ok(id)
+    }
+
+    pub fn prune(&self, keep: usize) -> Result<usize> {
+        let entries = self.load()?;
+        let removed = entries.len().saturating_sub(keep);
+        if removed == 0 {
+            return Ok(0);
+        }
+
+        let content = entries[removed..]
+            .iter()
+            .map(|e| serde_json::to_string(e).map(|line| line + "\n"))
+            .collect::<Result<String, _>>()?;
+        fs::write(&self.path, content)
+            .with_context(|| format!("履歴の書き込みに失敗しました: {}", self.path.display()))?;
+
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::testing::TempDir;
+    use std::time::Duration;
+
+    fn report(input: &str) -> SessionReport {
+        SessionReport::new(vec![input.to_string()], Vec::new(), Duration::from_secs(1))
+    }
+
+    #[test]
+    fn test_history() {
+        let dir = TempDir::new("history");
+        let history = History::new(dir.join("history.jsonl"));
+
+        assert!(history.load().unwrap().is_empty());
+        assert!(history.latest_calibration().unwrap().is_none());
+        assert_eq!(history.append(100, report("a.mp4")).unwrap(), 1);
+        assert_eq!(history.append(200, report("b.mp4")).unwrap(), 2);
+        fs::OpenOptions::new()
+            .append(true)
+            .open(history.path())
+            .unwrap()
+            .write_all(b"{\"id\": 3, \"started_at\"\n")
+            .unwrap();
+        let calibrated = SessionReport {
+            calibration: Some(Calibration {
+                pixel_rate: 1.0,
+                parallelism: 2,
+            }),
+            ..report("c.mp4")
+        };
+        assert_eq!(history.append(300, calibrated).unwrap(), 3);
+
+        let entries = history.load().unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1].report.inputs, vec!["b.mp4".to_string()]);
+        assert_eq!(history.find(3).unwrap().unwrap().started_at, 300);
+        assert_eq!(
+            history.latest_calibration().unwrap().unwrap().parallelism,
+            2
+        );
+
+        assert_eq!(history.prune(2).unwrap(), 1);
+        assert_eq!(history.prune(2).unwrap(), 0);
+        let ids = history
+            .load()
+            .unwrap()
+            .iter()
+            .map(|e| e.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![2, 3]);
+    }
+}
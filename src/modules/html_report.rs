@@ -0,0 +1,356 @@
+use anyhow::{Context, Result};
+use humansize::format_size;
+use std::{
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use super::{
+    config,
+    results::{EncodeReport, ResultRecord, RunResults},
+    time, video,
+};
+
+/// `--html` で出力先に書き出すファイルの名前.
+pub const FILE_NAME: &str = "report.html";
+
+const STYLE: &str = r#"
+body { font-family: system-ui, sans-serif; margin: 2em; color: #222; }
+h2 { margin-top: 2em; word-break: break-all; }
+dl { display: grid; grid-template-columns: max-content auto; gap: 0.2em 1em; }
+dt { color: #666; }
+dd { margin: 0; }
+table { border-collapse: collapse; }
+th, td { padding: 0.3em 0.8em; border-bottom: 1px solid #ddd; text-align: right; white-space: nowrap; }
+td.text { text-align: left; white-space: normal; }
+th { cursor: pointer; user-select: none; background: #f4f4f4; }
+th[aria-sort="ascending"]::after { content: " ▲"; }
+th[aria-sort="descending"]::after { content: " ▼"; }
+tr.failed td { color: #b00; }
+"#;
+
+/// 見出しを押すと, その列の `data-sort` (なければ表示) で並べ替える. 数値の列は数として比べる.
+const SCRIPT: &str = r#"
+document.querySelectorAll("table.results").forEach((table) => {
+  table.querySelectorAll("th").forEach((th, column) => {
+    th.addEventListener("click", () => {
+      const ascending = th.getAttribute("aria-sort") !== "ascending";
+      table.querySelectorAll("th").forEach((other) => other.removeAttribute("aria-sort"));
+      th.setAttribute("aria-sort", ascending ? "ascending" : "descending");
+      const key = (row) => {
+        const cell = row.children[column];
+        const value = cell.dataset.sort ?? cell.textContent;
+        return value === "" || isNaN(value) ? value : Number(value);
+      };
+      const body = table.tBodies[0];
+      const rows = Array.from(body.rows).sort((a, b) => {
+        const [x, y] = [key(a), key(b)];
+        const order = typeof x === typeof y ? (x < y ? -1 : x > y ? 1 : 0) : typeof x === "number" ? -1 : 1;
+        return ascending ? order : -order;
+      });
+      rows.forEach((row) => body.appendChild(row));
+    });
+  });
+});
+"#;
+
+/// 外部のファイルを読まずに開ける 1 つの HTML. 出力へのリンクは `dir` (レポートの置き場所) からの相対パスにする.
+pub fn render(results: &RunResults, dir: &Path) -> String {
+    let mut html = String::new();
+    html += "<!DOCTYPE html>\n<html lang=\"ja\">\n<head>\n<meta charset=\"utf-8\">\n";
+    html += "<title>vvcnv のレポート</title>\n";
+    let _ = writeln!(html, "<style>{}</style>\n</head>\n<body>", STYLE);
+    html += "<h1>vvcnv のレポート</h1>\n";
+    for report in &results.reports {
+        render_report(&mut html, report, dir);
+    }
+    if !results.failed_inputs.is_empty() {
+        html += "<h2>処理できなかった入力</h2>\n<ul>\n";
+        for failure in &results.failed_inputs {
+            let _ = writeln!(
+                html,
+                "<li>{}: {}</li>",
+                escape(&failure.input_path),
+                escape(&failure.error)
+            );
+        }
+        html += "</ul>\n";
+    }
+    let _ = writeln!(html, "<script>{}</script>\n</body>\n</html>", SCRIPT);
+    html
+}
+
+fn render_report(html: &mut String, report: &EncodeReport, dir: &Path) {
+    let source = &report.source;
+    let video = &source.video_stream;
+    let _ = writeln!(html, "<h2>{}</h2>\n<dl>", escape(&source.path));
+    let audio = match source.audio_streams.as_slice() {
+        [] => "なし".to_string(),
+        streams => streams
+            .iter()
+            .map(|a| format!("{} {} {} Hz", a.codec, a.channels, a.sample_rate))
+            .collect::<Vec<_>>()
+            .join(", "),
+    };
+    let properties = [
+        ("解像度", format!("{}x{}", video.width, video.height)),
+        ("FPS", format!("{:.3}", video.fps)),
+        ("コーデック", video.codec.clone()),
+        ("ピクセルフォーマット", video.pix_fmt.clone()),
+        (
+            "長さ",
+            time::format_clock(Duration::from_secs_f64(source.duration_secs)),
+        ),
+        (
+            "大きさ",
+            format_size(source.file_size, config::size_format()),
+        ),
+        ("音声", audio),
+    ];
+    for (name, value) in properties {
+        let _ = writeln!(html, "<dt>{}</dt><dd>{}</dd>", name, escape(&value));
+    }
+    *html += "</dl>\n<table class=\"results\">\n<thead><tr>";
+    for column in [
+        "解像度",
+        "FPS",
+        "CRF",
+        "コーデック",
+        "音声",
+        "大きさ",
+        "比率",
+        "時間",
+        "結果",
+        "出力",
+    ] {
+        let _ = write!(html, "<th>{}</th>", column);
+    }
+    *html += "</tr></thead>\n<tbody>\n";
+    for record in &report.results {
+        render_row(html, record, dir);
+    }
+    *html += "</tbody>\n</table>\n";
+}
+
+fn render_row(html: &mut String, record: &ResultRecord, dir: &Path) {
+    let (width, height) = record.res.to_wh();
+    let size = record.output_size;
+    let ratio = size
+        .filter(|_| record.source_size > 0)
+        .map(|size| size as f64 / record.source_size as f64);
+    let cells = [
+        (
+            Some((width as u64 * height as u64).to_string()),
+            format!("{}x{}", width, height),
+        ),
+        (None, record.fps.to_string()),
+        (None, record.crf.to_string()),
+        (None, record.codec.clone().unwrap_or_default()),
+        (
+            None,
+            match record.has_audio {
+                true => "あり",
+                false => "なし",
+            }
+            .to_string(),
+        ),
+        (
+            Some(size.map(|s| s.to_string()).unwrap_or_default()),
+            size.map(|s| format_size(s, config::size_format()))
+                .unwrap_or_default(),
+        ),
+        (
+            Some(ratio.map(|r| format!("{:.4}", r)).unwrap_or_default()),
+            ratio
+                .map(|r| format!("{:.1}%", r * 100.0))
+                .unwrap_or_default(),
+        ),
+        (
+            Some(
+                record
+                    .elapsed_secs
+                    .map(|s| format!("{:.2}", s))
+                    .unwrap_or_default(),
+            ),
+            record
+                .elapsed_secs
+                .map(|s| time::format_clock(Duration::from_secs_f64(s)))
+                .unwrap_or_default(),
+        ),
+    ];
+    let _ = write!(
+        html,
+        "<tr{}>",
+        match record.is_ok() {
+            true => "",
+            false => " class=\"failed\"",
+        }
+    );
+    for (sort, text) in cells {
+        match sort {
+            Some(sort) => {
+                let _ = write!(html, "<td data-sort=\"{}\">{}</td>", sort, escape(&text));
+            }
+            None => {
+                let _ = write!(html, "<td>{}</td>", escape(&text));
+            }
+        }
+    }
+    let _ = write!(html, "<td class=\"text\">{}</td>", escape(&record.result));
+    let output = match size.is_some() && !video::is_stream_url(&record.output_path) {
+        true => format!(
+            "<a href=\"{}\">{}</a>",
+            escape(&href(&link_path(dir, Path::new(&record.output_path)))),
+            escape(&record.output_path)
+        ),
+        false => escape(&record.output_path),
+    };
+    let _ = writeln!(html, "<td class=\"text\">{}</td></tr>", output);
+}
+
+/// レポートからの相対パス. レポートの下にない出力は絶対パスにする.
+fn link_path(dir: &Path, output: &Path) -> PathBuf {
+    if let Ok(relative) = output.strip_prefix(dir) {
+        return relative.to_path_buf();
+    }
+    match (fs::canonicalize(dir), fs::canonicalize(output)) {
+        (Ok(dir), Ok(output)) => output
+            .strip_prefix(&dir)
+            .map_or(output.clone(), Path::to_path_buf),
+        _ => output.to_path_buf(),
+    }
+}
+
+/// `href` に書けるよう, 区切りの `/` 以外の記号と日本語などを `%XX` にする.
+fn href(path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let mut href = match path.starts_with('/') {
+        true => "file://".to_string(),
+        false => String::new(),
+    };
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                href.push(byte as char)
+            }
+            _ => {
+                let _ = write!(href, "%{:02X}", byte);
+            }
+        }
+    }
+    href
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped += "&amp;",
+            '<' => escaped += "&lt;",
+            '>' => escaped += "&gt;",
+            '"' => escaped += "&quot;",
+            '\'' => escaped += "&#39;",
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn write(path: &Path, results: &RunResults) -> Result<()> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    fs::create_dir_all(dir)
+        .with_context(|| format!("レポートの置き場所を作れませんでした: {}", dir.display()))?;
+    fs::write(path, render(results, dir))
+        .with_context(|| format!("レポートを書き込めませんでした: {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::{
+        report::TaskStatus,
+        results::{InputFailure, SourceSummary},
+        video::{VideoRes, VideoStreamInfo},
+    };
+
+    fn record(output_path: &str, output_size: Option<u64>, result: &str) -> ResultRecord {
+        ResultRecord {
+            input_path: "in/clip.mp4".to_string(),
+            res: VideoRes::R720p,
+            fps: 30,
+            crf: 28,
+            codec: None,
+            has_audio: false,
+            status: TaskStatus::Encoded,
+            output_path: output_path.to_string(),
+            output_size,
+            source_size: 4096,
+            elapsed_secs: output_size.map(|_| 75.0),
+            speed: None,
+            result: result.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let results = RunResults {
+            reports: vec![EncodeReport {
+                source: SourceSummary {
+                    path: "in/<clip>.mp4".to_string(),
+                    video_stream: VideoStreamInfo {
+                        codec: "h264".to_string(),
+                        width: 1920,
+                        height: 1080,
+                        fps: 30.0,
+                        ..Default::default()
+                    },
+                    audio_streams: Vec::new(),
+                    duration_secs: 90.0,
+                    file_size: 4096,
+                },
+                results: vec![
+                    record("out/clip 720p.mp4", Some(1024), "ok"),
+                    ResultRecord {
+                        codec: Some("vp9".to_string()),
+                        ..record("out/clip-480p.mp4", None, "Invalid argument & \"crf\"")
+                    },
+                ],
+            }],
+            failed_inputs: vec![InputFailure {
+                input_path: "in/broken.mp4".to_string(),
+                error: "元動画の情報取得に失敗しました.".to_string(),
+            }],
+        };
+        let html = render(&results, Path::new("out"));
+
+        assert!(html.contains("<h2>in/&lt;clip&gt;.mp4</h2>"));
+        assert!(html.contains("<dt>解像度</dt><dd>1920x1080</dd>"));
+        assert!(html.contains("<dt>長さ</dt><dd>00:01:30</dd>"));
+        assert_eq!(html.matches("<tr").count(), 3);
+        assert!(html.contains("<td data-sort=\"0.2500\">25.0%</td>"));
+        assert!(html.contains("<th>コーデック</th>") && html.contains("<td>28</td><td>vp9</td>"));
+        assert!(html.contains("<td data-sort=\"75.00\">00:01:15</td>"));
+        assert!(html.contains("<a href=\"clip%20720p.mp4\">out/clip 720p.mp4</a>"));
+        // 出力のない行はリンクにせず, 失敗として表示する
+        assert!(html.contains("<tr class=\"failed\">"));
+        assert!(html.contains("Invalid argument &amp; &quot;crf&quot;"));
+        assert!(!html.contains("href=\"clip-480p.mp4\""));
+        assert!(html.contains("<li>in/broken.mp4: 元動画の情報取得に失敗しました.</li>"));
+        // 外部のファイルを読まない
+        assert!(!html.contains("src=") && !html.contains("<link"));
+    }
+
+    #[test]
+    fn test_href() {
+        assert_eq!(
+            href(&link_path(Path::new("out"), Path::new("out/a/動画.mp4"))),
+            "a/%E5%8B%95%E7%94%BB.mp4"
+        );
+        assert_eq!(
+            href(Path::new("/srv/encoded/a#1.mp4")),
+            "file:///srv/encoded/a%231.mp4"
+        );
+    }
+}
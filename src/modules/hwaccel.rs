@@ -0,0 +1,305 @@
+use core::fmt;
+use std::{str::FromStr, sync::OnceLock};
+
+use super::video::VideoCodec;
+
+/// VA-API で使うデバイス. 1 枚目の GPU になる.
+pub const VAAPI_DEVICE: &str = "/dev/dri/renderD128";
+
+/// エンコードに使うハードウェア. `--hwaccel` または設定の `hwaccel` で選ぶ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HwAccel {
+    /// ffmpeg のビルドにあるものから選ぶ. どれもなければソフトウェアでエンコードする.
+    Auto,
+    /// NVIDIA の GPU (`h264_nvenc` など).
+    Nvenc,
+    /// macOS (`h264_videotoolbox` など).
+    VideoToolbox,
+    /// Intel の Quick Sync Video (`h264_qsv` など).
+    Qsv,
+    /// Linux の VA-API (`h264_vaapi` など).
+    Vaapi,
+    /// ハードウェアを使わない.
+    None,
+}
+
+impl HwAccel {
+    /// `Auto` で試す順番. VideoToolbox は macOS 向けのビルドにしかないので先に試しても他の環境には影響しない.
+    const AUTO_ORDER: [HwAccel; 4] = [
+        HwAccel::VideoToolbox,
+        HwAccel::Nvenc,
+        HwAccel::Qsv,
+        HwAccel::Vaapi,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HwAccel::Auto => "auto",
+            HwAccel::Nvenc => "nvenc",
+            HwAccel::VideoToolbox => "videotoolbox",
+            HwAccel::Qsv => "qsv",
+            HwAccel::Vaapi => "vaapi",
+            HwAccel::None => "none",
+        }
+    }
+
+    /// `codec` をエンコードするハードウェアのエンコーダー. 対応していないコーデックでは `None`.
+    pub fn encoder(&self, codec: &VideoCodec) -> Option<&'static str> {
+        Some(match (self, codec) {
+            (HwAccel::Nvenc, VideoCodec::H264) => "h264_nvenc",
+            (HwAccel::Nvenc, VideoCodec::H265) => "hevc_nvenc",
+            (HwAccel::Nvenc, VideoCodec::Av1) => "av1_nvenc",
+            (HwAccel::VideoToolbox, VideoCodec::H264) => "h264_videotoolbox",
+            (HwAccel::VideoToolbox, VideoCodec::H265) => "hevc_videotoolbox",
+            (HwAccel::VideoToolbox, VideoCodec::ProRes) => "prores_videotoolbox",
+            (HwAccel::Qsv, VideoCodec::H264) => "h264_qsv",
+            (HwAccel::Qsv, VideoCodec::H265) => "hevc_qsv",
+            (HwAccel::Qsv, VideoCodec::Vp9) => "vp9_qsv",
+            (HwAccel::Qsv, VideoCodec::Av1) => "av1_qsv",
+            (HwAccel::Vaapi, VideoCodec::H264) => "h264_vaapi",
+            (HwAccel::Vaapi, VideoCodec::H265) => "hevc_vaapi",
+            (HwAccel::Vaapi, VideoCodec::Vp9) => "vp9_vaapi",
+            (HwAccel::Vaapi, VideoCodec::Av1) => "av1_vaapi",
+            _ => return None,
+        })
+    }
+
+    /// CRF の代わりに渡す品質の指定. ハードウェアのエンコーダーは `-crf` を受け付けない.
+    /// NVENC, QSV, VA-API は CRF と同じく小さいほど高画質の値を取るので, CRF をそのまま使う.
+    pub fn quality_args(&self, crf: u32) -> Vec<String> {
+        let args = match self {
+            // 既定のビットレートが上限にならないよう, ビットレートを指定しない
+            HwAccel::Nvenc => format!("-rc vbr -cq {} -b:v 0", crf),
+            HwAccel::VideoToolbox => format!("-q:v {}", videotoolbox_quality(crf)),
+            HwAccel::Qsv => format!("-global_quality {}", crf.max(1)),
+            HwAccel::Vaapi => format!("-rc_mode CQP -qp {}", crf),
+            HwAccel::Auto | HwAccel::None => String::new(),
+        };
+        args.split_whitespace().map(String::from).collect()
+    }
+
+    /// 入力より前に渡すオプション. VA-API はエンコードに使うデバイスを指定する.
+    pub fn input_args(&self) -> &'static [&'static str] {
+        match self {
+            HwAccel::Vaapi => &["-vaapi_device", VAAPI_DEVICE],
+            _ => &[],
+        }
+    }
+
+    /// フィルターの最後に加えるフィルター. VA-API はフレームを GPU のメモリに移してからエンコードする.
+    pub fn upload_filter(&self) -> Option<&'static str> {
+        match self {
+            HwAccel::Vaapi => Some("format=nv12,hwupload"),
+            _ => None,
+        }
+    }
+}
+
+/// VideoToolbox の `-q:v` は 1〜100 で, 大きいほど高画質. CRF の 0〜51 を逆向きに対応させる (CRF 23 → 54).
+fn videotoolbox_quality(crf: u32) -> u32 {
+    100u32.saturating_sub(crf * 2).max(1)
+}
+
+impl fmt::Display for HwAccel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for HwAccel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            HwAccel::Auto,
+            HwAccel::Nvenc,
+            HwAccel::VideoToolbox,
+            HwAccel::Qsv,
+            HwAccel::Vaapi,
+            HwAccel::None,
+        ]
+        .into_iter()
+        .find(|hw| hw.name() == s.trim().to_lowercase())
+        .ok_or_else(|| {
+            format!(
+                "auto, nvenc, videotoolbox, qsv, vaapi, none のいずれかを指定してください: {}",
+                s
+            )
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HwAccelErr {
+    UnsupportedCodec(HwAccel, VideoCodec),
+    /// ffmpeg のビルドにないエンコーダーと, ビルドにあるハードウェアのエンコーダー.
+    Unavailable {
+        encoder: &'static str,
+        available: Vec<String>,
+    },
+}
+
+impl fmt::Display for HwAccelErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HwAccelErr::UnsupportedCodec(hw, codec) => write!(
+                f,
+                "--hwaccel {} では {} をエンコードできません.",
+                hw,
+                codec.name()
+            ),
+            HwAccelErr::Unavailable { encoder, available } if available.is_empty() => write!(
+                f,
+                "{} はこの ffmpeg のビルドでは使えません. ハードウェアのエンコーダーが 1 つもないため, --hwaccel none でエンコードしてください.",
+                encoder
+            ),
+            HwAccelErr::Unavailable { encoder, available } => write!(
+                f,
+                "{} はこの ffmpeg のビルドでは使えません. 使えるハードウェアのエンコーダー: {}",
+                encoder,
+                available.join(", ")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HwAccelErr {}
+
+/// `encoders` のうち vvcnv が使えるハードウェアのエンコーダー.
+pub fn hardware_encoders(encoders: &[String]) -> Vec<String> {
+    encoders
+        .iter()
+        .filter(|encoder| {
+            ["_nvenc", "_videotoolbox", "_qsv", "_vaapi"]
+                .iter()
+                .any(|suffix| encoder.ends_with(suffix))
+        })
+        .cloned()
+        .collect()
+}
+
+/// `codecs` をエンコードするハードウェアを決める. ソフトウェアでエンコードする場合は `None`.
+/// `available` は ffmpeg のビルドにあるエンコーダーで, 調べられなかった場合 (`None`) は指定をそのまま使う.
+pub fn resolve(
+    mode: HwAccel,
+    codecs: &[VideoCodec],
+    available: Option<&[String]>,
+) -> Result<Option<HwAccel>, HwAccelErr> {
+    let has = |encoder: &str| available.is_none_or(|list| list.iter().any(|e| e == encoder));
+    match mode {
+        HwAccel::None => Ok(None),
+        // すべてのコーデックをエンコードできるものだけを選ぶ
+        HwAccel::Auto => Ok(available.and_then(|_| {
+            HwAccel::AUTO_ORDER
+                .into_iter()
+                .find(|hw| codecs.iter().all(|c| hw.encoder(c).is_some_and(has)))
+        })),
+        hw => {
+            for codec in codecs {
+                let encoder = hw
+                    .encoder(codec)
+                    .ok_or_else(|| HwAccelErr::UnsupportedCodec(hw, codec.clone()))?;
+                if !has(encoder) {
+                    return Err(HwAccelErr::Unavailable {
+                        encoder,
+                        available: hardware_encoders(available.unwrap_or_default()),
+                    });
+                }
+            }
+            Ok(Some(hw))
+        }
+    }
+}
+
+static SELECTED: OnceLock<Option<HwAccel>> = OnceLock::new();
+
+/// 起動時に決めたハードウェアを覚えておく. 各タスクは [`selected`] で取り出す.
+pub fn init(selected: Option<HwAccel>) {
+    SELECTED.set(selected).ok();
+}
+
+/// 起動時に決めたハードウェア. `init` の前 (テストなど) はソフトウェアでエンコードする.
+pub fn selected() -> Option<HwAccel> {
+    SELECTED.get().copied().flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoders(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("nvenc".parse(), Ok(HwAccel::Nvenc));
+        assert_eq!(" VideoToolbox".parse(), Ok(HwAccel::VideoToolbox));
+        assert_eq!("none".parse(), Ok(HwAccel::None));
+        assert!("cuda".parse::<HwAccel>().is_err());
+        assert_eq!(HwAccel::Qsv.to_string(), "qsv");
+    }
+
+    #[test]
+    fn test_quality_args() {
+        assert_eq!(
+            HwAccel::Nvenc.encoder(&VideoCodec::H265),
+            Some("hevc_nvenc")
+        );
+        assert_eq!(HwAccel::Nvenc.encoder(&VideoCodec::Vp9), None);
+        assert_eq!(
+            HwAccel::Nvenc.quality_args(28),
+            ["-rc", "vbr", "-cq", "28", "-b:v", "0"]
+        );
+        assert_eq!(HwAccel::VideoToolbox.quality_args(23), ["-q:v", "54"]);
+        assert_eq!(HwAccel::VideoToolbox.quality_args(0), ["-q:v", "100"]);
+        assert_eq!(HwAccel::VideoToolbox.quality_args(51), ["-q:v", "1"]);
+        assert_eq!(HwAccel::Qsv.quality_args(0), ["-global_quality", "1"]);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let h264 = [VideoCodec::H264];
+        let nvidia = encoders(&["libx264", "h264_nvenc", "hevc_nvenc", "h264_vaapi"]);
+        assert_eq!(resolve(HwAccel::None, &h264, Some(&nvidia)), Ok(None));
+        assert_eq!(
+            resolve(HwAccel::Auto, &h264, Some(&nvidia)),
+            Ok(Some(HwAccel::Nvenc))
+        );
+        // すべてのコーデックをエンコードできるものがなければソフトウェアにする
+        assert_eq!(
+            resolve(
+                HwAccel::Auto,
+                &[VideoCodec::H264, VideoCodec::Vp9],
+                Some(&nvidia)
+            ),
+            Ok(None)
+        );
+        assert_eq!(resolve(HwAccel::Auto, &h264, None), Ok(None));
+        assert_eq!(
+            resolve(HwAccel::Nvenc, &[VideoCodec::H265], Some(&nvidia)),
+            Ok(Some(HwAccel::Nvenc))
+        );
+
+        // 使えない場合は, 使えるハードウェアのエンコーダーを示す
+        let err = resolve(HwAccel::VideoToolbox, &h264, Some(&nvidia)).unwrap_err();
+        assert_eq!(
+            err,
+            HwAccelErr::Unavailable {
+                encoder: "h264_videotoolbox",
+                available: encoders(&["h264_nvenc", "hevc_nvenc", "h264_vaapi"]),
+            }
+        );
+        assert!(err
+            .to_string()
+            .contains("h264_nvenc, hevc_nvenc, h264_vaapi"));
+        assert_eq!(
+            resolve(HwAccel::Nvenc, &[VideoCodec::Vp9], Some(&nvidia)),
+            Err(HwAccelErr::UnsupportedCodec(
+                HwAccel::Nvenc,
+                VideoCodec::Vp9
+            ))
+        );
+        assert_eq!(resolve(HwAccel::Qsv, &h264, None), Ok(Some(HwAccel::Qsv)));
+    }
+}
@@ -0,0 +1,518 @@
+use core::fmt;
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use super::{
+    verbosity,
+    video::{self, VideoStatErr},
+};
+
+/// ディレクトリを入力に指定した場合に拾う拡張子の既定値.
+pub const DIR_EXTENSIONS: &[&str] = &["mp4", "mov", "mkv", "webm"];
+
+pub const VIDEO_EXTENSIONS: &[&str] = &[
+    "3gp", "avi", "flv", "m2ts", "m4v", "mkv", "mov", "mp4", "mpeg", "mpg", "mts", "ogv", "ts",
+    "webm", "wmv",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtVerdict {
+    Allowed,
+    Denied,
+    Unknown,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ExtFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+fn normalize_ext(ext: &str) -> String {
+    ext.trim().trim_start_matches('.').to_lowercase()
+}
+
+impl ExtFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Self {
+        Self {
+            include: include.iter().map(|e| normalize_ext(e)).collect(),
+            exclude: exclude.iter().map(|e| normalize_ext(e)).collect(),
+        }
+    }
+
+    pub fn check(&self, path: &str) -> ExtVerdict {
+        let path = Path::new(path);
+        let is_hidden = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        if is_hidden {
+            return ExtVerdict::Denied;
+        }
+
+        let Some(ext) = path.extension() else {
+            return ExtVerdict::Unknown;
+        };
+        let ext = normalize_ext(&ext.to_string_lossy());
+        if self.exclude.contains(&ext) {
+            ExtVerdict::Denied
+        } else if self.include.contains(&ext) || VIDEO_EXTENSIONS.contains(&ext.as_str()) {
+            ExtVerdict::Allowed
+        } else {
+            ExtVerdict::Denied
+        }
+    }
+}
+
+/// ffmpeg が入力を開いたうえで, 動画ではないと判断したときのログ.
+const NOT_MEDIA_MESSAGE: &str = "Invalid data found when processing input";
+
+/// ffmpeg が実行でき, ファイルに動画ストリームがない (メディアとして読めない) と分かった場合だけ非動画とする.
+/// ffmpeg を起動できない場合や権限がない場合も `FfmpegError` になるので, メッセージで区別する.
+fn is_not_video(err: &VideoStatErr) -> bool {
+    match err {
+        VideoStatErr::NoVideoStreamFound => true,
+        VideoStatErr::FfmpegError(message) => message.contains(NOT_MEDIA_MESSAGE),
+        _ => false,
+    }
+}
+
+/// 拡張子で判断できないファイルは ffmpeg で開いてみて, 動画ストリームがなければ非動画とする.
+/// それ以外の失敗 (ffmpeg を起動できない, 読み込めないなど) は警告して動画として扱い, エンコード時のエラーとして報告させる.
+async fn probe_is_video(path: &str) -> bool {
+    match video::stat(path.to_string()).await {
+        Ok(_) => true,
+        Err(e) if is_not_video(&e) => false,
+        Err(e) => {
+            verbosity::warn(format!(
+                "{} が動画か判断できなかったため, 動画として扱います: {}",
+                path, e
+            ));
+            true
+        }
+    }
+}
+
+/// 動画として扱う入力と, 動画ではないためスキップした入力.
+pub async fn select_videos(paths: Vec<String>, filter: &ExtFilter) -> (Vec<String>, Vec<String>) {
+    let mut videos = Vec::with_capacity(paths.len());
+    let mut skipped = Vec::new();
+    for path in paths {
+        let is_video = match filter.check(&path) {
+            ExtVerdict::Allowed => true,
+            ExtVerdict::Denied => false,
+            ExtVerdict::Unknown => probe_is_video(&path).await,
+        };
+        match is_video {
+            true => videos.push(path),
+            false => skipped.push(path),
+        }
+    }
+
+    (videos, skipped)
+}
+
+/// エンコードする入力.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputFile {
+    pub path: String,
+    /// 出力先の中のサブディレクトリ. ディレクトリを入力に指定した場合は, そのディレクトリからの相対パスになる.
+    pub subdir: PathBuf,
+}
+
+impl InputFile {
+    pub fn new(path: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            subdir: PathBuf::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WalkOptions {
+    pub extensions: Vec<String>,
+    /// たどるサブディレクトリの深さ. 0 の場合は直下のファイルだけ. `None` の場合は制限しない.
+    pub max_depth: Option<usize>,
+    pub follow_symlinks: bool,
+}
+
+/// ディレクトリを再帰的にたどり, 拡張子が `extensions` に含まれるファイルを名前の順に返す.
+/// ファイルを開いて調べることはしないので, 多くのファイルがあってもすぐに終わる.
+/// `.` で始まるファイルとディレクトリは含めない. シンボリックリンクは `follow_symlinks` の場合だけたどり, 循環は一度だけたどる.
+pub fn walk(root: &Path, options: &WalkOptions) -> Vec<InputFile> {
+    let extensions = options
+        .extensions
+        .iter()
+        .map(|e| normalize_ext(e))
+        .collect::<Vec<_>>();
+    let mut visited = HashSet::new();
+    visited.extend(fs::canonicalize(root));
+    let mut inputs = Vec::new();
+    visit(
+        root,
+        root,
+        0,
+        options,
+        &extensions,
+        &mut visited,
+        &mut inputs,
+    );
+
+    inputs
+}
+
+fn visit(
+    root: &Path,
+    dir: &Path,
+    depth: usize,
+    options: &WalkOptions,
+    extensions: &[String],
+    visited: &mut HashSet<PathBuf>,
+    inputs: &mut Vec<InputFile>,
+) {
+    let Ok(read) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut entries = read.flatten().collect::<Vec<_>>();
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = entry.path();
+        let is_symlink = entry.file_type().is_ok_and(|t| t.is_symlink());
+        if is_symlink && !options.follow_symlinks {
+            continue;
+        }
+        let Ok(meta) = fs::metadata(&path) else {
+            continue;
+        };
+        if meta.is_dir() {
+            let within_depth = options.max_depth.is_none_or(|max| depth < max);
+            if within_depth && fs::canonicalize(&path).is_ok_and(|real| visited.insert(real)) {
+                visit(root, &path, depth + 1, options, extensions, visited, inputs);
+            }
+            continue;
+        }
+        let matches = path
+            .extension()
+            .is_some_and(|ext| extensions.contains(&normalize_ext(&ext.to_string_lossy())));
+        if meta.is_file() && matches {
+            inputs.push(InputFile {
+                path: path.to_string_lossy().into_owned(),
+                subdir: dir
+                    .strip_prefix(root)
+                    .unwrap_or(Path::new(""))
+                    .to_path_buf(),
+            });
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoMatch(pub String);
+
+impl fmt::Display for NoMatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "パターンに一致するファイルがありません: {}", self.0)
+    }
+}
+
+impl std::error::Error for NoMatch {}
+
+/// `*` / `?` / `[...]` を含む指定をグロブとして扱う.
+pub fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '['])
+}
+
+/// `[a-z]` / `[!0-9]` の範囲を照合し, 一致したかと `]` の次の位置を返す. 閉じていない場合は `None`.
+fn match_class(pattern: &[char], c: char) -> Option<(bool, usize)> {
+    let negated = matches!(pattern.first(), Some('!' | '^'));
+    let mut i = negated as usize;
+    let mut matched = false;
+    let mut first = true;
+    while let Some(&p) = pattern.get(i) {
+        if p == ']' && !first {
+            return Some((matched != negated, i + 1));
+        }
+        first = false;
+        match (pattern.get(i + 1), pattern.get(i + 2)) {
+            (Some('-'), Some(&end)) if end != ']' => {
+                matched |= (p..=end).contains(&c);
+                i += 3;
+            }
+            _ => {
+                matched |= p == c;
+                i += 1;
+            }
+        }
+    }
+    None
+}
+
+/// パスの 1 つの要素をパターンと照合する.
+fn match_component(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some('*'), _) => {
+            match_component(&pattern[1..], name)
+                || (!name.is_empty() && match_component(pattern, &name[1..]))
+        }
+        (Some('?'), Some(_)) => match_component(&pattern[1..], &name[1..]),
+        (Some('['), Some(&c)) => match match_class(&pattern[1..], c) {
+            Some((true, end)) => match_component(&pattern[1 + end..], &name[1..]),
+            Some((false, _)) => false,
+            // 閉じていない `[` はそのままの文字として扱う
+            None => c == '[' && match_component(&pattern[1..], &name[1..]),
+        },
+        (Some(p), Some(c)) => p == c && match_component(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+fn is_separator(c: char) -> bool {
+    c == '/' || (cfg!(windows) && c == '\\')
+}
+
+/// パターンをディレクトリごとに展開する. `.` で始まるファイルは, パターンも `.` で始まる場合だけ含める.
+fn expand(pattern: &str) -> Vec<PathBuf> {
+    // `/` や, Windows の `C:\` から始まる場合はそこを起点にする
+    let (root, rest) = match pattern.find(is_separator) {
+        Some(i) if i == 0 || (cfg!(windows) && pattern[..i].ends_with(':')) => {
+            (PathBuf::from(&pattern[..=i]), &pattern[i + 1..])
+        }
+        _ => (PathBuf::new(), pattern),
+    };
+    let mut paths = vec![root];
+    for component in rest.split(is_separator).filter(|c| !c.is_empty()) {
+        if !is_glob(component) {
+            paths.iter_mut().for_each(|path| path.push(component));
+            continue;
+        }
+        let chars = component.chars().collect::<Vec<_>>();
+        paths = paths
+            .iter()
+            .flat_map(|dir| {
+                let read = match dir.as_os_str().is_empty() {
+                    true => fs::read_dir("."),
+                    false => fs::read_dir(dir),
+                };
+                let mut names = read
+                    .into_iter()
+                    .flatten()
+                    .flatten()
+                    .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                    .filter(|name| !name.starts_with('.') || component.starts_with('.'))
+                    .filter(|name| match_component(&chars, &name.chars().collect::<Vec<_>>()))
+                    .collect::<Vec<_>>();
+                names.sort();
+                names.into_iter().map(|name| dir.join(name))
+            })
+            .collect();
+    }
+
+    paths.into_iter().filter(|path| path.is_file()).collect()
+}
+
+/// 入力の指定のグロブを展開する. グロブでない指定はそのまま返す (存在は `file::check_readable` で確かめる).
+/// シェルが展開しない環境 (Windows の cmd など) や, シェルが日本語のファイル名を扱えない場合のため.
+pub fn resolve(patterns: &[String]) -> Result<Vec<String>, NoMatch> {
+    let mut inputs = Vec::new();
+    for pattern in patterns {
+        let expanded = match is_glob(pattern) {
+            true => expand(pattern)
+                .into_iter()
+                .map(|path| path.to_string_lossy().into_owned())
+                .collect(),
+            false => vec![pattern.clone()],
+        };
+        if expanded.is_empty() {
+            return Err(NoMatch(pattern.clone()));
+        }
+        for path in expanded {
+            if !inputs.contains(&path) {
+                inputs.push(path);
+            }
+        }
+    }
+
+    Ok(inputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::testing::TempDir;
+
+    #[test]
+    fn test_ext_filter() {
+        let filter = ExtFilter::default();
+        assert_eq!(filter.check("assets/2.mp4"), ExtVerdict::Allowed);
+        assert_eq!(filter.check("assets/2.MKV"), ExtVerdict::Allowed);
+        assert_eq!(filter.check("assets/2.srt"), ExtVerdict::Denied);
+        assert_eq!(filter.check("assets/cover.jpg"), ExtVerdict::Denied);
+        assert_eq!(filter.check("assets/.DS_Store"), ExtVerdict::Denied);
+        assert_eq!(filter.check("assets/.hidden.mp4"), ExtVerdict::Denied);
+        assert_eq!(filter.check("assets/capture"), ExtVerdict::Unknown);
+        assert_eq!(filter.check("assets/00001.m2ts"), ExtVerdict::Allowed);
+        assert_eq!(filter.check("assets/00001.MTS"), ExtVerdict::Allowed);
+        assert_eq!(filter.check("assets/2.y4m"), ExtVerdict::Denied);
+
+        let filter = ExtFilter::new(
+            &[".Y4M".to_string(), "dv".to_string()],
+            &["webm".to_string(), "ts".to_string()],
+        );
+        assert_eq!(filter.check("assets/2.y4m"), ExtVerdict::Allowed);
+        assert_eq!(filter.check("assets/2.dv"), ExtVerdict::Allowed);
+        assert_eq!(filter.check("assets/2.ts"), ExtVerdict::Denied);
+        assert_eq!(filter.check("assets/2.webm"), ExtVerdict::Denied);
+    }
+
+    #[test]
+    fn test_is_not_video() {
+        assert!(is_not_video(&VideoStatErr::NoVideoStreamFound));
+        assert!(is_not_video(&VideoStatErr::FfmpegError(format!(
+            "notes.txt: {}",
+            NOT_MEDIA_MESSAGE
+        ))));
+        assert!(!is_not_video(&VideoStatErr::FfmpegError(
+            "No such file or directory (os error 2)".to_string()
+        )));
+        assert!(!is_not_video(&VideoStatErr::FfmpegError(
+            "notes.txt: Permission denied".to_string()
+        )));
+        assert!(!is_not_video(&VideoStatErr::NoDurationFound));
+    }
+
+    #[test]
+    fn test_match_component() {
+        let matches = |pattern: &str, name: &str| {
+            match_component(
+                &pattern.chars().collect::<Vec<_>>(),
+                &name.chars().collect::<Vec<_>>(),
+            )
+        };
+        assert!(matches("*.mp4", "画面収録 2024-01-01.mp4"));
+        assert!(matches("*.mp4", ".mp4"));
+        assert!(!matches("*.mp4", "a.mp4.srt"));
+        assert!(matches("clip-??.mkv", "clip-01.mkv"));
+        assert!(!matches("clip-??.mkv", "clip-1.mkv"));
+        assert!(matches("[a-c]*", "b.mp4"));
+        assert!(!matches("[!a-c]*", "b.mp4"));
+        assert!(matches("[]]x", "]x"));
+        assert!(matches("a[b", "a[b"));
+        assert!(matches("**", ""));
+    }
+
+    #[test]
+    fn test_walk() {
+        let dir = TempDir::new("walk");
+        let root = dir.join("captures");
+        for name in [
+            "a/clip.mp4",
+            "b/clip.MOV",
+            "b/deep/clip.webm",
+            "b/notes.txt",
+            "top.mkv",
+            "top.avi",
+            ".cache/clip.mp4",
+        ] {
+            let path = root.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        let options = WalkOptions {
+            extensions: DIR_EXTENSIONS.iter().map(|e| e.to_string()).collect(),
+            ..Default::default()
+        };
+        let found = |options: &WalkOptions| {
+            walk(&root, options)
+                .into_iter()
+                .map(|input| {
+                    let name = Path::new(&input.path).file_name().unwrap().to_owned();
+                    input.subdir.join(name).to_string_lossy().replace('\\', "/")
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // 同じ名前のファイルも, サブディレクトリで区別できる
+        assert_eq!(
+            found(&options),
+            ["a/clip.mp4", "b/clip.MOV", "b/deep/clip.webm", "top.mkv"]
+        );
+        let shallow = WalkOptions {
+            max_depth: Some(1),
+            ..options.clone()
+        };
+        assert_eq!(found(&shallow), ["a/clip.mp4", "b/clip.MOV", "top.mkv"]);
+        let top = WalkOptions {
+            max_depth: Some(0),
+            ..options.clone()
+        };
+        assert_eq!(found(&top), ["top.mkv"]);
+
+        #[cfg(unix)]
+        {
+            fs::create_dir_all(dir.join("external")).unwrap();
+            fs::write(dir.join("external").join("ext.mp4"), "").unwrap();
+            std::os::unix::fs::symlink(dir.join("external"), root.join("linked")).unwrap();
+            // 循環するリンク
+            std::os::unix::fs::symlink(&root, root.join("a").join("loop")).unwrap();
+            assert_eq!(found(&options).len(), 4);
+            let follow = WalkOptions {
+                follow_symlinks: true,
+                ..options.clone()
+            };
+            assert_eq!(
+                found(&follow),
+                [
+                    "a/clip.mp4",
+                    "b/clip.MOV",
+                    "b/deep/clip.webm",
+                    "linked/ext.mp4",
+                    "top.mkv"
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let dir = TempDir::new("glob");
+        fs::create_dir_all(dir.join("captures").join("sub")).unwrap();
+        for name in [
+            "収録1.mp4",
+            "収録2.mp4",
+            "収録1.srt",
+            ".hidden.mp4",
+            "sub/収録3.mp4",
+        ] {
+            fs::write(dir.join("captures").join(name), "").unwrap();
+        }
+        let pattern = |p: &str| dir.join(p).to_string_lossy().into_owned();
+
+        let inputs = resolve(&[pattern("captures/*.mp4")]).unwrap();
+        assert_eq!(
+            inputs,
+            vec![pattern("captures/収録1.mp4"), pattern("captures/収録2.mp4")]
+        );
+        assert_eq!(
+            resolve(&[pattern("*/*/*.mp4"), pattern("captures/sub/収録3.mp4")]).unwrap(),
+            vec![pattern("captures/sub/収録3.mp4")]
+        );
+        assert_eq!(resolve(&[pattern("captures/収録1.*")]).unwrap().len(), 2);
+
+        // グロブでない指定は存在しなくてもそのまま返す
+        assert_eq!(
+            resolve(&["missing.mp4".to_string()]).unwrap(),
+            vec!["missing.mp4"]
+        );
+        assert_eq!(
+            resolve(&[pattern("captures/*.mov")]).unwrap_err(),
+            NoMatch(pattern("captures/*.mov"))
+        );
+    }
+}
@@ -0,0 +1,263 @@
+use anyhow::{Context, Result};
+use core::fmt;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::{io::AsyncReadExt, time::Instant};
+
+/// 入力と同じディレクトリに置くチェックサムの一覧 (`sha256sum` の出力と同じ形式).
+pub const MANIFEST_FILE: &str = "checksums.txt";
+/// チェックサムが一致しなかった入力の移動先 (入力と同じディレクトリの中).
+pub const QUARANTINE_DIR: &str = "failed-integrity";
+/// ハッシュを求める速さの既定値 (MiB/s). 実行中のエンコードの読み込みを妨げないように抑える.
+pub const DEFAULT_RATE_MIB: u64 = 128;
+const CHUNK: usize = 1024 * 1024;
+
+/// `<sha256>  <パス>` の行を, パスからハッシュ (小文字) への対応にする.
+/// `sha256sum -b` の `*` や `./` の接頭辞, 空行と `#` のコメントは無視する.
+pub fn parse_manifest(content: &str) -> Result<HashMap<String, String>, String> {
+    content
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| {
+            let (hash, path) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| format!("{} 行目: <sha256>  <パス> の形式ではありません", number))?;
+            if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!("{} 行目: sha256 ではありません: {}", number, hash));
+            }
+            let path = path.trim_start();
+            let path = path.strip_prefix('*').unwrap_or(path);
+            let path = path.strip_prefix("./").unwrap_or(path);
+            Ok((path.to_string(), hash.to_ascii_lowercase()))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum IntegrityStatus {
+    Verified,
+    Mismatch {
+        expected: String,
+        actual: String,
+    },
+    /// チェックサムの一覧はあるが, 入力が載っていない.
+    NotListed,
+    NoManifest,
+}
+
+impl IntegrityStatus {
+    pub fn is_mismatch(&self) -> bool {
+        matches!(self, IntegrityStatus::Mismatch { .. })
+    }
+}
+
+impl fmt::Display for IntegrityStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IntegrityStatus::Verified => write!(f, "チェックサム一致"),
+            IntegrityStatus::Mismatch { expected, actual } => write!(
+                f,
+                "チェックサム不一致 (期待: {}, 実際: {})",
+                expected, actual
+            ),
+            IntegrityStatus::NotListed => write!(f, "{} に記載なし", MANIFEST_FILE),
+            IntegrityStatus::NoManifest => write!(f, "{} なし", MANIFEST_FILE),
+        }
+    }
+}
+
+/// 履歴に残す, 入力ごとの検証の結果.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputIntegrity {
+    pub input_path: String,
+    #[serde(flatten)]
+    pub status: IntegrityStatus,
+}
+
+/// ファイルの sha256 を小文字の 16 進数で求める.
+/// `rate` (バイト/秒) を指定した場合は, 読んだ量がそれを超えないように待つ.
+pub async fn sha256_file(path: &Path, rate: Option<u64>) -> Result<String> {
+    let context = || {
+        format!(
+            "チェックサムを求めるために入力を読めません: {}",
+            path.display()
+        )
+    };
+    let mut file = tokio::fs::File::open(path).await.with_context(context)?;
+    let mut hasher = digest::Context::new(&digest::SHA256);
+    let mut buffer = vec![0u8; CHUNK];
+    let started_at = Instant::now();
+    let mut total = 0u64;
+    loop {
+        let read = file.read(&mut buffer).await.with_context(context)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        total += read as u64;
+        if let Some(rate) = rate.filter(|rate| *rate > 0) {
+            let due = started_at + Duration::from_secs_f64(total as f64 / rate as f64);
+            tokio::time::sleep_until(due).await;
+        }
+    }
+
+    Ok(hex(hasher.finish()))
+}
+
+/// 同期的に sha256 を求める. 速さは制限しない.
+pub fn sha256_blocking(path: &Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = digest::Context::new(&digest::SHA256);
+    let mut buffer = vec![0u8; CHUNK];
+    loop {
+        match std::io::Read::read(&mut file, &mut buffer)? {
+            0 => break,
+            read => hasher.update(&buffer[..read]),
+        }
+    }
+
+    Ok(hex(hasher.finish()))
+}
+
+fn hex(digest: digest::Digest) -> String {
+    digest
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// 入力と同じディレクトリの `MANIFEST_FILE` と照合する.
+pub async fn check(input_path: &str, rate: Option<u64>) -> Result<IntegrityStatus> {
+    let path = Path::new(input_path);
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let manifest_path = dir.join(MANIFEST_FILE);
+    let content = match tokio::fs::read_to_string(&manifest_path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(IntegrityStatus::NoManifest)
+        }
+        Err(e) => {
+            return Err(e).with_context(|| {
+                format!(
+                    "チェックサムの一覧を読めません: {}",
+                    manifest_path.display()
+                )
+            })
+        }
+    };
+    let manifest = parse_manifest(&content)
+        .map_err(anyhow::Error::msg)
+        .with_context(|| format!("チェックサムの一覧が不正です: {}", manifest_path.display()))?;
+    let name = path
+        .file_name()
+        .map_or(input_path.into(), |n| n.to_string_lossy());
+    let Some(expected) = manifest.get(name.as_ref()) else {
+        return Ok(IntegrityStatus::NotListed);
+    };
+
+    let actual = sha256_file(path, rate).await?;
+    Ok(match *expected == actual {
+        true => IntegrityStatus::Verified,
+        false => IntegrityStatus::Mismatch {
+            expected: expected.clone(),
+            actual,
+        },
+    })
+}
+
+/// 入力を `QUARANTINE_DIR` に移動し, 移動先を返す. 同じ名前のファイルがある場合は番号を付ける.
+pub fn quarantine(input_path: &str) -> Result<PathBuf> {
+    let path = Path::new(input_path);
+    let dir = path.parent().unwrap_or(Path::new("")).join(QUARANTINE_DIR);
+    std::fs::create_dir_all(&dir)
+        .with_context(|| format!("隔離先を作成できません: {}", dir.display()))?;
+    let name = path
+        .file_name()
+        .with_context(|| format!("ファイル名がありません: {}", input_path))?;
+    let destination = (0..)
+        .map(|i| match i {
+            0 => dir.join(name),
+            _ => dir.join(format!("{}.{}", name.to_string_lossy(), i)),
+        })
+        .find(|candidate| !candidate.exists())
+        .unwrap();
+    std::fs::rename(path, &destination).with_context(|| {
+        format!(
+            "入力を隔離できません: {} → {}",
+            input_path,
+            destination.display()
+        )
+    })?;
+
+    Ok(destination)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::testing::TempDir;
+
+    const ABC: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[test]
+    fn test_parse_manifest() {
+        let content = format!(
+            "# 生成: sha256sum\n{}  a.mp4\n\n{} *./b b.mkv\n",
+            ABC,
+            ABC.to_uppercase()
+        );
+        let manifest = parse_manifest(&content).unwrap();
+        assert_eq!(manifest.len(), 2);
+        assert_eq!(manifest["a.mp4"], ABC);
+        assert_eq!(manifest["b b.mkv"], ABC);
+
+        assert!(parse_manifest("abc  a.mp4").is_err());
+        assert!(parse_manifest(ABC).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_check_and_quarantine() {
+        let dir = TempDir::new("integrity");
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        std::fs::write(path("good.mp4"), "abc").unwrap();
+        std::fs::write(path("bad.mp4"), "abd").unwrap();
+        std::fs::write(path("other.mp4"), "abc").unwrap();
+
+        assert_eq!(
+            check(&path("good.mp4"), None).await.unwrap(),
+            IntegrityStatus::NoManifest
+        );
+        std::fs::write(
+            path(MANIFEST_FILE),
+            format!("{}  good.mp4\n{}  bad.mp4\n", ABC, ABC),
+        )
+        .unwrap();
+        assert_eq!(
+            check(&path("good.mp4"), Some(1024)).await.unwrap(),
+            IntegrityStatus::Verified
+        );
+        assert_eq!(
+            check(&path("other.mp4"), None).await.unwrap(),
+            IntegrityStatus::NotListed
+        );
+        let status = check(&path("bad.mp4"), None).await.unwrap();
+        assert!(status.is_mismatch());
+
+        // 同じ名前のファイルがすでに隔離されていても上書きしない
+        let first = quarantine(&path("bad.mp4")).unwrap();
+        assert_eq!(first, dir.join(QUARANTINE_DIR).join("bad.mp4"));
+        std::fs::write(path("bad.mp4"), "abd").unwrap();
+        let second = quarantine(&path("bad.mp4")).unwrap();
+        assert_eq!(second, dir.join(QUARANTINE_DIR).join("bad.mp4.1"));
+        assert!(!Path::new(&path("bad.mp4")).exists());
+    }
+}
@@ -0,0 +1,91 @@
+/// 通常の表示で 1 タスクが使う行数 (空行, 設定, 進捗).
+pub const FULL_LINES: usize = 3;
+/// 通常の表示の進捗の行が折り返さない最小の幅.
+pub const FULL_MIN_COLS: u16 = 80;
+/// 進捗の表示以外に使う行 (元動画の見出しとプロンプトなど).
+pub const RESERVED_ROWS: u16 = 4;
+
+/// 1 行の表示のうち, タスクの設定以外 (スピナー, 進捗, 経過時間) が使う幅.
+pub const COMPACT_RESERVED_COLS: u16 = 48;
+/// 3 行の表示で, タスクの設定の前に付く `⠋ -> ` の幅.
+pub const FULL_RESERVED_COLS: u16 = 5;
+/// 端末が極端に狭くても, タスクの設定をこの幅までは表示する.
+pub const MIN_PREFIX_COLS: usize = 12;
+
+/// 端末の大きさに合わせた進捗の表示方法.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// 1 タスクあたり 3 行.
+    Full,
+    /// 1 タスクあたり 1 行.
+    Compact,
+    /// 実行中のタスクだけを最大 `visible` 行表示し, 残りは全体の進捗の 1 行にまとめる.
+    Rolling { visible: usize },
+}
+
+impl Layout {
+    pub fn is_compact(&self) -> bool {
+        !matches!(self, Layout::Full)
+    }
+}
+
+/// 端末の行数・列数とタスクの数から表示方法を決める. 全タスクが収まる中で最も詳しい表示を選ぶ.
+pub fn choose(rows: u16, cols: u16, tasks: usize) -> Layout {
+    let available = rows.saturating_sub(RESERVED_ROWS) as usize;
+    if cols >= FULL_MIN_COLS && tasks * FULL_LINES <= available {
+        return Layout::Full;
+    }
+    if tasks <= available {
+        return Layout::Compact;
+    }
+
+    // 1 行は全体の進捗に使う
+    Layout::Rolling {
+        visible: available.saturating_sub(1).max(1),
+    }
+}
+
+/// タスクの設定の表示に使える幅. 端末でない場合は切り詰めない.
+pub fn prefix_width(layout: Layout, cols: Option<u16>) -> usize {
+    let Some(cols) = cols else {
+        return usize::MAX;
+    };
+    let reserved = match layout {
+        Layout::Full => FULL_RESERVED_COLS,
+        _ => COMPACT_RESERVED_COLS,
+    };
+
+    (cols.saturating_sub(reserved) as usize).max(MIN_PREFIX_COLS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_choose() {
+        // 80x24 では 20 行を使える
+        assert_eq!(choose(24, 80, 6), Layout::Full);
+        assert_eq!(choose(24, 80, 7), Layout::Compact);
+        assert_eq!(choose(24, 80, 20), Layout::Compact);
+        assert_eq!(choose(24, 80, 21), Layout::Rolling { visible: 19 });
+        assert_eq!(choose(50, 200, 8), Layout::Full);
+
+        // 幅が足りない場合は行数に余裕があっても 1 行にする
+        assert_eq!(choose(50, 60, 2), Layout::Compact);
+
+        // 極端に小さい端末でも実行中のタスクを 1 行は表示する
+        assert_eq!(choose(4, 80, 3), Layout::Rolling { visible: 1 });
+        assert_eq!(choose(0, 0, 1), Layout::Rolling { visible: 1 });
+        assert_eq!(choose(24, 80, 0), Layout::Full);
+    }
+
+    #[test]
+    fn test_prefix_width() {
+        assert_eq!(prefix_width(Layout::Full, Some(80)), 75);
+        assert_eq!(prefix_width(Layout::Compact, Some(80)), 32);
+        assert_eq!(prefix_width(Layout::Rolling { visible: 3 }, Some(100)), 52);
+        assert_eq!(prefix_width(Layout::Compact, Some(40)), MIN_PREFIX_COLS);
+        assert_eq!(prefix_width(Layout::Compact, None), usize::MAX);
+    }
+}
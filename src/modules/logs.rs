@@ -0,0 +1,319 @@
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use super::text;
+
+pub const LOG_EXTENSION: &str = "log";
+/// 出力先の中で, エンコードごとのログを置くディレクトリ.
+pub const DIR_NAME: &str = "logs";
+
+/// 他のプロセスが書き込み中の可能性があるので, これより最近に更新されたログは消さない.
+pub const WRITE_GRACE: Duration = Duration::from_secs(10);
+
+/// ログの保持数と合計サイズの上限. `None` の項目は制限しない.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retention {
+    pub max_count: Option<usize>,
+    pub max_size: Option<u64>,
+}
+
+/// `500MB` や `2GiB` の形式の大きさ. 単位を省略した場合はバイト. `--log-max-size` と設定の `log-max-size` で使う.
+pub fn parse_size(input: &str) -> Result<u64, String> {
+    let invalid = || format!("大きさの形式が不正です (例: 500MB, 2GiB): {}", input);
+    let trimmed = input.trim();
+    let at = trimmed
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(at);
+    let number = number.parse::<f64>().map_err(|_| invalid())?;
+    let factor: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1000,
+        "m" | "mb" => 1000_u64.pow(2),
+        "g" | "gb" => 1000_u64.pow(3),
+        "t" | "tb" => 1000_u64.pow(4),
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        _ => return Err(invalid()),
+    };
+    let size = number * factor as f64;
+    match size.is_finite() && size < u64::MAX as f64 {
+        true => Ok(size.round() as u64),
+        false => Err(invalid()),
+    }
+}
+
+/// 1 つのタスクのログの置き場所. 出力先 `out_root` からの相対パスを `logs_dir` の下に写し, 拡張子を `.log` にする.
+/// 出力先の下にない出力 (`--stream-to` など) はファイル名だけを使う.
+pub fn encode_log_path(logs_dir: &Path, out_root: &Path, output_path: &Path) -> PathBuf {
+    let relative = match output_path.strip_prefix(out_root) {
+        Ok(relative) => relative,
+        Err(_) => Path::new(output_path.file_name().unwrap_or(OsStr::new("output"))),
+    };
+    logs_dir.join(relative).with_extension(LOG_EXTENSION)
+}
+
+/// ffmpeg のログをすべて書き出すファイル. 複製して分割エンコードの各 ffmpeg から共有する.
+/// 書き込めなくてもエンコードは続けるので, 書き込みの失敗は無視する.
+#[derive(Debug, Clone)]
+pub struct EncodeLog {
+    path: PathBuf,
+    file: Arc<Mutex<File>>,
+}
+
+impl EncodeLog {
+    pub fn create(path: PathBuf) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = File::create(&path)?;
+        Ok(Self {
+            path,
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// 実行する ffmpeg のコマンド. そのままシェルに貼り付けて再現できる.
+    pub fn command(&self, program: &OsStr, args: &[String]) {
+        let line = std::iter::once(program.to_string_lossy().into_owned())
+            .chain(args.iter().map(|arg| text::shell_word(arg)))
+            .collect::<Vec<_>>()
+            .join(" ");
+        self.line(&format!("$ {}", line));
+    }
+
+    pub fn line(&self, line: &str) {
+        let _ = writeln!(self.file.lock().unwrap(), "{}", line);
+    }
+}
+
+#[derive(Debug)]
+struct LogFile {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+fn ignore_not_found(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// 並行して削除されたファイルやディレクトリは無視する.
+fn collect(dir: &Path, logs: &mut Vec<LogFile>) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        entries => entries?,
+    };
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+        let metadata = match entry.metadata() {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            metadata => metadata?,
+        };
+        if metadata.is_dir() {
+            collect(&path, logs)?;
+        } else if path.extension().is_some_and(|ext| ext == LOG_EXTENSION) {
+            logs.push(LogFile {
+                path,
+                size: metadata.len(),
+                modified: metadata.modified()?,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// `dir` 以下のログを新しい順に数え, 上限を超えた古いものから削除する. 削除したパスを返す.
+/// `protected` (今回失敗したタスクのログなど) と, 最近更新されたログは上限に関係なく残す.
+pub fn prune(
+    dir: &Path,
+    retention: &Retention,
+    protected: &HashSet<PathBuf>,
+    now: SystemTime,
+) -> io::Result<Vec<PathBuf>> {
+    let mut logs = Vec::new();
+    collect(dir, &mut logs)?;
+    logs.retain(|log| !protected.contains(&log.path));
+    logs.sort_by(|a, b| b.modified.cmp(&a.modified).then(b.path.cmp(&a.path)));
+
+    let (mut count, mut size) = (0, 0);
+    let mut full = false;
+    let mut removed = Vec::new();
+    for log in logs {
+        let recent = !now
+            .duration_since(log.modified)
+            .is_ok_and(|age| age >= WRITE_GRACE);
+        full = full
+            || retention.max_count.is_some_and(|max| count + 1 > max)
+            || retention.max_size.is_some_and(|max| size + log.size > max);
+        if full && !recent {
+            ignore_not_found(fs::remove_file(&log.path))?;
+            removed.push(log.path);
+            continue;
+        }
+        count += 1;
+        size += log.size;
+    }
+
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::testing::TempDir;
+    use std::fs::File;
+
+    fn log_tree(name: &str, now: SystemTime) -> TempDir {
+        let dir = TempDir::new(&format!("logs-{}", name));
+        fs::create_dir_all(dir.join("session")).unwrap();
+        for (i, name) in ["a.log", "b.log", "session/c.log", "session/d.log", "e.log"]
+            .iter()
+            .enumerate()
+        {
+            let path = dir.join(name);
+            fs::write(&path, vec![b'x'; 100]).unwrap();
+            let age = Duration::from_secs(60 * (5 - i as u64));
+            File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(now - age)
+                .unwrap();
+        }
+        fs::write(dir.join("notes.txt"), "keep").unwrap();
+        dir
+    }
+
+    fn survivors(dir: &Path) -> Vec<String> {
+        let mut logs = Vec::new();
+        collect(dir, &mut logs).unwrap();
+        let mut names = logs
+            .iter()
+            .map(|log| {
+                log.path
+                    .strip_prefix(dir)
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("500MB"), Ok(500_000_000));
+        assert_eq!(parse_size("2GiB"), Ok(2 << 30));
+        assert_eq!(parse_size("1.5 kb"), Ok(1500));
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("MB").is_err());
+        assert!(parse_size("10 parsecs").is_err());
+        assert!(parse_size("99999999999TB").is_err());
+    }
+
+    #[test]
+    fn test_encode_log() {
+        let (logs, root) = (Path::new("out/logs"), Path::new("out"));
+        assert_eq!(
+            encode_log_path(logs, root, Path::new("out/720p/clip--crf-28.mp4")),
+            Path::new("out/logs/720p/clip--crf-28.log")
+        );
+        assert_eq!(
+            encode_log_path(logs, root, Path::new("rtmp://live/stream")),
+            Path::new("out/logs/stream.log")
+        );
+        // ワークスペースでは出力の外にまとめる
+        assert_eq!(
+            encode_log_path(
+                Path::new("ws/logs"),
+                Path::new("ws/out"),
+                Path::new("ws/out/a.mp4")
+            ),
+            Path::new("ws/logs/a.log")
+        );
+
+        let dir = TempDir::new("encode-log");
+        let log = EncodeLog::create(dir.join("a/clip.log")).unwrap();
+        log.command(
+            OsStr::new("ffmpeg"),
+            &["-i".to_string(), "in/会議 録画.mp4".to_string()],
+        );
+        log.clone().line("[error] Conversion failed!");
+        assert_eq!(
+            fs::read_to_string(log.path()).unwrap(),
+            "$ ffmpeg -i 'in/会議 録画.mp4'\n[error] Conversion failed!\n"
+        );
+    }
+
+    #[test]
+    fn test_prune_by_count() {
+        let now = SystemTime::now();
+        let dir = log_tree("count", now);
+        let retention = Retention {
+            max_count: Some(2),
+            ..Default::default()
+        };
+
+        let removed = prune(&dir, &retention, &HashSet::new(), now).unwrap();
+        assert_eq!(removed.len(), 3);
+        assert_eq!(survivors(&dir), ["e.log", "session/d.log"]);
+        assert!(dir.join("notes.txt").exists());
+        assert!(prune(&dir, &retention, &HashSet::new(), now)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_prune_by_size_keeps_protected() {
+        let now = SystemTime::now();
+        let dir = log_tree("size", now);
+        let retention = Retention {
+            max_size: Some(250),
+            ..Default::default()
+        };
+        let protected = HashSet::from([dir.join("a.log")]);
+
+        prune(&dir, &retention, &protected, now).unwrap();
+        assert_eq!(survivors(&dir), ["a.log", "e.log", "session/d.log"]);
+    }
+
+    #[test]
+    fn test_prune_skips_recent() {
+        let now = SystemTime::now();
+        let dir = log_tree("recent", now);
+        fs::write(dir.join("session/writing.log"), "in progress").unwrap();
+        let retention = Retention {
+            max_count: Some(0),
+            ..Default::default()
+        };
+
+        prune(&dir, &retention, &HashSet::new(), now).unwrap();
+        assert_eq!(survivors(&dir), ["session/writing.log"]);
+        assert!(
+            prune(&dir.join("missing"), &retention, &HashSet::new(), now)
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,495 @@
+use core::fmt;
+use itertools::{iproduct, Itertools};
+
+use super::video::{self, VideoCodec, VideoConfig, VideoRes};
+
+pub const DEFAULT_MATRIX_LIMIT: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatrixTooLarge {
+    pub count: usize,
+    pub limit: usize,
+}
+
+impl fmt::Display for MatrixTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "組み合わせが {} 個あり, 上限の {} 個を超えています. 意図した指定であれば --yes-really を付けて実行するか, --max-combinations で上限を変更してください.",
+            self.count, self.limit
+        )
+    }
+}
+
+impl std::error::Error for MatrixTooLarge {}
+
+#[derive(Debug, Clone)]
+pub struct CrfOutOfRange {
+    pub res: VideoRes,
+    pub crf: i64,
+    pub encoder: String,
+    pub max: Option<u32>,
+}
+
+impl fmt::Display for CrfOutOfRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let range = match self.max {
+            Some(max) => format!("0〜{}", max),
+            None => "0 以上".to_string(),
+        };
+        write!(
+            f,
+            "{:?} の CRF がオフセットの適用後に {} になり, {} の範囲 ({}) を外れています. --crf か CRF のオフセットを見直してください.",
+            self.res, self.crf, self.encoder, range
+        )
+    }
+}
+
+impl std::error::Error for CrfOutOfRange {}
+
+/// 解像度ごとの CRF のオフセット. 低い解像度ほど CRF を上げても劣化が目立ちにくいため, その分ビットレートを抑えられる.
+#[derive(Debug, Clone, Default)]
+pub struct CrfOffsets {
+    /// 組み合わせの中で最も高い解像度から 1 段下がるごとに加える値.
+    pub per_rung: i32,
+    /// 解像度ごとに指定した値. `per_rung` より優先する.
+    pub explicit: Vec<(VideoRes, i32)>,
+}
+
+impl CrfOffsets {
+    pub fn is_empty(&self) -> bool {
+        self.per_rung == 0 && self.explicit.is_empty()
+    }
+}
+
+/// 組み合わせをどこで指定したか. 確認の表示に使う.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSource {
+    /// `--res` / `--fps` / `--crf` (と設定ファイルの同じ項目) の組み合わせ.
+    Matrix,
+    Preset(String),
+    /// 設定ファイルの `[[configs]]`.
+    File,
+    /// `--add-config`.
+    Cli,
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigSource::Matrix => write!(f, "組み合わせ"),
+            ConfigSource::Preset(name) => write!(f, "プリセット {}", name),
+            ConfigSource::File => write!(f, "設定ファイル"),
+            ConfigSource::Cli => write!(f, "--add-config"),
+        }
+    }
+}
+
+/// `720p` (16:9 の解像度) か `1280x720` の形式.
+pub fn parse_res(input: &str) -> Result<VideoRes, String> {
+    let input = input.trim();
+    match input.strip_suffix('p').and_then(|h| h.parse::<u32>().ok()) {
+        Some(height) => VideoRes::list169()
+            .into_iter()
+            .find(|r| r.to_wh().1 == height)
+            .ok_or_else(|| format!("16:9 の解像度ではありません: {}", input)),
+        None => input.parse::<VideoRes>().map_err(|e| e.to_string()),
+    }
+}
+
+/// `h265` や `libvpx-vp9` などのコーデック名. 組み込みの名前でなければエンコーダー名としてそのまま使う.
+pub fn parse_codec(input: &str) -> Result<VideoCodec, String> {
+    match input.trim() {
+        "" => Err("コーデックが空です".to_string()),
+        codec => Ok(VideoCodec::from(codec.to_string())),
+    }
+}
+
+/// `240p=6` や `1280x720=-2` の形式.
+pub fn parse_crf_offset(input: &str) -> Result<(VideoRes, i32), String> {
+    let (res, offset) = input
+        .split_once('=')
+        .ok_or_else(|| format!("解像度=オフセット の形式で指定してください: {}", input))?;
+    let res = parse_res(res)?;
+    let offset = offset
+        .parse::<i32>()
+        .map_err(|_| format!("オフセットには整数を指定してください: {}", offset))?;
+
+    Ok((res, offset))
+}
+
+fn pixels(res: &VideoRes) -> u64 {
+    let (w, h) = res.to_wh();
+    w as u64 * h as u64
+}
+
+/// 解像度 / FPS / CRF / コーデックの組み合わせ. `base` の残りのフィールドはすべての組み合わせで共通になる.
+/// `crf` が空の場合は `base` のコーデックの既定値を使う.
+#[derive(Debug, Clone, Default)]
+pub struct Matrix {
+    pub res: Vec<VideoRes>,
+    pub fps: Vec<u32>,
+    pub crf: Vec<u32>,
+    /// 空の場合は `base` のコーデックだけを使う.
+    pub codec: Vec<VideoCodec>,
+    /// コーデックごとの CRF. 同じ CRF でもコーデックによって画質が異なるため, `crf` より優先する.
+    pub codec_crf: Vec<(VideoCodec, Vec<u32>)>,
+    pub crf_offsets: CrfOffsets,
+    pub base: VideoConfig,
+}
+
+impl Matrix {
+    /// コーデックごとの組み合わせに分ける. 分けた後の `base` には, それぞれのコーデックと CRF が入る.
+    pub fn per_codec(&self) -> Vec<Matrix> {
+        let codecs = match self.codec.is_empty() {
+            true => vec![self.base.codec.clone()],
+            false => self.codec.iter().cloned().map(Some).collect(),
+        };
+        codecs
+            .into_iter()
+            .map(|codec| {
+                let key = codec.clone().unwrap_or(VideoCodec::H264);
+                let crf = self
+                    .codec_crf
+                    .iter()
+                    .find(|(c, _)| *c == key)
+                    .map_or(self.crf.clone(), |(_, crf)| crf.clone());
+                Matrix {
+                    crf,
+                    codec: Vec::new(),
+                    codec_crf: Vec::new(),
+                    base: VideoConfig {
+                        codec,
+                        ..self.base.clone()
+                    },
+                    ..self.clone()
+                }
+            })
+            .collect()
+    }
+
+    /// `base` のコーデックの CRF. コーデックごとの値は [`Matrix::per_codec`] で分けてから使う.
+    pub fn crf_values(&self) -> Vec<u32> {
+        match self.crf.is_empty() {
+            true => vec![video::default_crf(&self.base)],
+            false => self.crf.clone(),
+        }
+    }
+
+    /// `res` に加える CRF のオフセット.
+    pub fn crf_offset(&self, res: &VideoRes) -> i32 {
+        let explicit = self
+            .crf_offsets
+            .explicit
+            .iter()
+            .find(|(r, _)| r.to_wh() == res.to_wh());
+        if let Some((_, offset)) = explicit {
+            return *offset;
+        }
+        let rung = self.res.iter().filter(|r| pixels(r) > pixels(res)).count();
+
+        self.crf_offsets.per_rung * rung as i32
+    }
+
+    fn effective_crf(&self, res: &VideoRes, crf: u32) -> i64 {
+        crf as i64 + self.crf_offset(res) as i64
+    }
+
+    /// オフセットを適用した後の CRF がコーデックの範囲に収まるかを確認する.
+    /// CRF を使わないコーデックでは確認しない.
+    pub fn validate(&self) -> Result<(), CrfOutOfRange> {
+        if !self.codec.is_empty() || !self.codec_crf.is_empty() {
+            return self.per_codec().iter().try_for_each(Matrix::validate);
+        }
+        let codec = self.base.codec.clone().unwrap_or(VideoCodec::H264);
+        if !codec.uses_crf() {
+            return Ok(());
+        }
+        let max = codec.max_crf();
+        for (res, crf) in iproduct!(&self.res, self.crf_values()) {
+            let crf = self.effective_crf(res, crf);
+            if crf < 0 || max.is_some_and(|max| crf > max as i64) {
+                return Err(CrfOutOfRange {
+                    res: res.clone(),
+                    crf,
+                    encoder: codec.encoder().to_string(),
+                    max,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn count(&self) -> usize {
+        self.per_codec()
+            .iter()
+            .map(|m| m.res.len() * m.fps.len() * m.crf_values().len())
+            .sum()
+    }
+
+    /// 上限を確認せずにすべての組み合わせを列挙する. CRF はオフセットを適用した値になる.
+    /// 比べやすいよう, コーデックごとにまとめて並べる.
+    pub fn configs(&self) -> impl Iterator<Item = VideoConfig> + '_ {
+        self.per_codec().into_iter().flat_map(|m| {
+            iproduct!(&m.res, &m.fps, m.crf_values())
+                .map(|(res, fps, crf)| VideoConfig {
+                    res: res.clone(),
+                    fps: *fps,
+                    crf: m.effective_crf(res, crf).clamp(0, u32::MAX as i64) as u32,
+                    ..m.base.clone()
+                })
+                .collect::<Vec<_>>()
+        })
+    }
+
+    /// `limit` が `None` の場合は上限を確認しない.
+    pub fn build(&self, limit: Option<usize>) -> Result<Vec<VideoConfig>, MatrixTooLarge> {
+        build_all(std::slice::from_ref(self), limit)
+    }
+}
+
+/// プリセットごとの組み合わせをまとめて列挙する. 上限は合計の数で確認する.
+pub fn build_all(
+    matrices: &[Matrix],
+    limit: Option<usize>,
+) -> Result<Vec<VideoConfig>, MatrixTooLarge> {
+    let count = matrices.iter().map(Matrix::count).sum();
+    match limit {
+        Some(limit) if count > limit => Err(MatrixTooLarge { count, limit }),
+        _ => Ok(matrices.iter().flat_map(Matrix::configs).collect()),
+    }
+}
+
+/// 同じ設定が複数の指定元から現れた場合は, 最初のものだけを残す.
+pub fn dedupe(
+    configs: impl IntoIterator<Item = (ConfigSource, VideoConfig)>,
+) -> Vec<(ConfigSource, VideoConfig)> {
+    configs
+        .into_iter()
+        .unique_by(|(_, config)| serde_json::to_string(config).unwrap_or_default())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matrix_build() {
+        let matrix = Matrix {
+            res: vec![VideoRes::R480p, VideoRes::R720p, VideoRes::R1080p],
+            fps: vec![30, 60],
+            crf: vec![20, 28, 36],
+            base: VideoConfig {
+                has_audio: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(matrix.count(), 18);
+
+        let configs = matrix.build(Some(18)).unwrap();
+        assert_eq!(configs.len(), 18);
+        assert!(configs.iter().all(|c| c.has_audio));
+        assert_eq!(configs[1].to_file_name(), "--res-854x480--fps-30--crf-28");
+
+        assert_eq!(
+            matrix.build(Some(17)).unwrap_err(),
+            MatrixTooLarge {
+                count: 18,
+                limit: 17
+            }
+        );
+        assert_eq!(matrix.build(None).unwrap().len(), 18);
+    }
+
+    #[test]
+    fn test_matrix_default_crf() {
+        let matrix = |codec, crf: &[u32]| Matrix {
+            res: vec![VideoRes::R720p],
+            fps: vec![30],
+            crf: crf.to_vec(),
+            base: VideoConfig {
+                codec,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let crfs = |m: Matrix| m.configs().map(|c| c.crf).collect::<Vec<_>>();
+
+        assert_eq!(crfs(matrix(None, &[])), vec![23]);
+        assert_eq!(crfs(matrix(Some(VideoCodec::H265), &[])), vec![28]);
+        assert_eq!(crfs(matrix(Some(VideoCodec::Vp9), &[])), vec![32]);
+        assert_eq!(crfs(matrix(Some(VideoCodec::Av1), &[])), vec![30]);
+        assert_eq!(crfs(matrix(Some(VideoCodec::Vp9), &[20, 40])), vec![20, 40]);
+        assert_eq!(matrix(Some(VideoCodec::Vp9), &[]).count(), 1);
+    }
+
+    #[test]
+    fn test_matrix_codec() {
+        let matrix = Matrix {
+            res: vec![VideoRes::R720p],
+            fps: vec![30],
+            crf: vec![28],
+            codec: vec![VideoCodec::H264, VideoCodec::Vp9, VideoCodec::H265],
+            codec_crf: vec![
+                (VideoCodec::H264, vec![20, 26, 32]),
+                (VideoCodec::Vp9, vec![30, 36, 42]),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(matrix.count(), 7);
+        let configs = matrix
+            .configs()
+            .map(|c| (c.codec.map(|c| c.name().to_string()), c.crf))
+            .collect::<Vec<_>>();
+        let expected = [
+            ("h264", 20),
+            ("h264", 26),
+            ("h264", 32),
+            ("vp9", 30),
+            ("vp9", 36),
+            ("vp9", 42),
+            // コーデックごとの CRF がなければ共通の値を使う
+            ("h265", 28),
+        ]
+        .map(|(codec, crf)| (Some(codec.to_string()), crf));
+        assert_eq!(configs, expected);
+        assert!(matrix.validate().is_ok());
+
+        // コーデックごとの範囲で確かめる
+        let too_high = Matrix {
+            codec_crf: vec![(VideoCodec::H264, vec![60])],
+            ..matrix.clone()
+        };
+        assert_eq!(too_high.validate().unwrap_err().max, Some(51));
+
+        // コーデックを並べない場合は, base のコーデックにコーデックごとの CRF を使う
+        let single = Matrix {
+            codec: Vec::new(),
+            base: VideoConfig {
+                codec: Some(VideoCodec::Vp9),
+                ..Default::default()
+            },
+            ..matrix
+        };
+        assert_eq!(
+            single.configs().map(|c| c.crf).collect::<Vec<_>>(),
+            [30, 36, 42]
+        );
+    }
+
+    #[test]
+    fn test_matrix_empty_axis() {
+        let matrix = Matrix {
+            res: vec![VideoRes::R720p],
+            fps: Vec::new(),
+            crf: vec![28],
+            ..Default::default()
+        };
+        assert_eq!(matrix.count(), 0);
+        assert!(matrix.build(Some(0)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_crf_offsets() {
+        let matrix = |crf_offsets, codec| Matrix {
+            res: vec![VideoRes::R240p, VideoRes::R480p, VideoRes::R1080p],
+            fps: vec![30],
+            crf: vec![24],
+            codec: Vec::new(),
+            codec_crf: Vec::new(),
+            crf_offsets,
+            base: VideoConfig {
+                codec,
+                ..Default::default()
+            },
+        };
+        let crfs = |m: &Matrix| m.configs().map(|c| c.crf).collect::<Vec<_>>();
+
+        let per_rung = matrix(
+            CrfOffsets {
+                per_rung: 2,
+                ..Default::default()
+            },
+            None,
+        );
+        assert_eq!(crfs(&per_rung), vec![28, 26, 24]);
+        assert_eq!(
+            per_rung.configs().next().unwrap().to_file_name(),
+            "--res-426x240--fps-30--crf-28"
+        );
+
+        let explicit = matrix(
+            CrfOffsets {
+                per_rung: 2,
+                explicit: vec![parse_crf_offset("240p=+6").unwrap()],
+            },
+            None,
+        );
+        assert_eq!(crfs(&explicit), vec![30, 26, 24]);
+        assert!(explicit.validate().is_ok());
+
+        // オフセットの適用後に範囲を確認する
+        let too_high = matrix(
+            CrfOffsets {
+                per_rung: 14,
+                ..Default::default()
+            },
+            None,
+        );
+        let err = too_high.validate().unwrap_err();
+        assert_eq!((err.crf, err.max), (52, Some(51)));
+        assert!(matrix(too_high.crf_offsets.clone(), Some(VideoCodec::Vp9))
+            .validate()
+            .is_ok());
+        assert!(matrix(
+            CrfOffsets {
+                explicit: vec![parse_crf_offset("1920x1080=-30").unwrap()],
+                ..Default::default()
+            },
+            None
+        )
+        .validate()
+        .is_err());
+        assert!(matrix(too_high.crf_offsets, Some(VideoCodec::ProRes))
+            .validate()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_dedupe() {
+        let config = |crf| VideoConfig {
+            crf,
+            ..Default::default()
+        };
+        let configs = dedupe([
+            (ConfigSource::Matrix, config(23)),
+            (ConfigSource::Matrix, config(28)),
+            (ConfigSource::Cli, config(23)),
+            (ConfigSource::File, config(30)),
+        ]);
+        assert_eq!(
+            configs
+                .iter()
+                .map(|(source, c)| (source.to_string(), c.crf))
+                .collect::<Vec<_>>(),
+            [
+                ("組み合わせ".to_string(), 23),
+                ("組み合わせ".to_string(), 28),
+                ("設定ファイル".to_string(), 30)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_crf_offset() {
+        let parsed = parse_crf_offset("720p=-2").unwrap();
+        assert_eq!((parsed.0.to_wh(), parsed.1), ((1280, 720), -2));
+        assert_eq!(parse_crf_offset("640x480=3").unwrap().0.to_wh(), (640, 480));
+        assert!(parse_crf_offset("720p").is_err());
+        assert!(parse_crf_offset("721p=1").is_err());
+        assert!(parse_crf_offset("720p=x").is_err());
+    }
+}
@@ -0,0 +1,220 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Deserializer};
+use std::{collections::BTreeMap, fs, path::Path};
+
+use super::{
+    matrix,
+    presets::{self, ConfigEntry, Preset},
+    video::{VideoCodec, VideoRes},
+};
+
+/// `vvcnv init` で雛形を書き出す, 組み合わせの設定ファイル.
+pub const MATRIX_FILE: &str = "vvcnv.matrix.toml";
+
+/// どのコーデックでも範囲外になる CRF. コーデックごとの範囲はエンコードの前に `Matrix::validate` で確認する.
+const MAX_CRF: u32 = 63;
+
+pub const DEFAULT_CONTENT: &str = "\
+# vvcnv の組み合わせの設定 (使うには: vvcnv --config vvcnv.matrix.toml <INPUT>)
+# コマンドラインで --res / --fps / --crf / --codec を指定した項目は, そちらが優先されます.
+
+# 解像度. 16:9 の名前 (720p) か 幅x高さ (1280x720) で指定します. 指定しない場合は 16:9 のすべての解像度
+# res = [\"480p\", \"720p\", \"1920x1080\"]
+
+# FPS. 指定しない場合は 30
+# fps = [30, 60]
+
+# CRF. 指定しない場合はコーデックごとの既定値
+# crf = [23, 28]
+
+# コーデック. 複数指定すると, それぞれで組み合わせを作ります. 指定しない場合は出力形式の既定のエンコーダー
+# codec = [\"h264\", \"vp9\"]
+
+# 音声を残すかどうか
+# has-audio = true
+
+# コーデックごとの CRF. 同じ数値でもコーデックによって画質が異なるため, 指定したコーデックでは crf の代わりに使います
+# [codec-crf]
+# h264 = [20, 26, 32]
+# vp9 = [30, 36, 42]
+
+# --preset で選べるプリセット. 組み込みのプリセットは chat (720p/30/28), archive (1080p/60/23), preview (480p/30/35)
+# 設定を複数書く場合は [[preset.<名前>]] を繰り返します. 書ける項目:
+# res, fps, crf, has-audio, pix-fmt, profile, codec, encoder-preset, film-grain, codec-params, fps-mode
+# [preset.story]
+# res = \"1080x1920\"
+# fps = 30
+# crf = 26
+# codec = \"h265\"
+
+# 組み合わせを作らずにそのまま使う設定 (res は必須). res / fps / crf の組み合わせやプリセットと一緒に使うと, 続けて実行します
+# [[configs]]
+# res = \"720p\"
+# fps = 30
+# crf = 28
+# codec = \"h265\"
+";
+
+/// `--config` で読み込む組み合わせ. 指定しなかった項目はコマンドラインの値か既定値を使う.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct MatrixFile {
+    #[serde(deserialize_with = "deserialize_res")]
+    pub res: Option<Vec<VideoRes>>,
+    pub fps: Option<Vec<u32>>,
+    pub crf: Option<Vec<u32>>,
+    pub codec: Option<Vec<VideoCodec>>,
+    #[serde(deserialize_with = "deserialize_codec_crf")]
+    pub codec_crf: Vec<(VideoCodec, Vec<u32>)>,
+    pub has_audio: Option<bool>,
+    /// `--preset` で選べるプリセット. 同じ名前の組み込みのプリセットより優先する.
+    #[serde(rename = "preset", deserialize_with = "presets::deserialize_presets")]
+    pub presets: Vec<Preset>,
+    /// 組み合わせを作らずにそのまま使う設定.
+    pub configs: Vec<ConfigEntry>,
+}
+
+pub(crate) fn deserialize_res<'de, D: Deserializer<'de>>(
+    d: D,
+) -> Result<Option<Vec<VideoRes>>, D::Error> {
+    Vec::<String>::deserialize(d)?
+        .iter()
+        .map(|res| matrix::parse_res(res))
+        .collect::<Result<Vec<_>, _>>()
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_codec_crf<'de, D: Deserializer<'de>>(
+    d: D,
+) -> Result<Vec<(VideoCodec, Vec<u32>)>, D::Error> {
+    Ok(BTreeMap::<String, Vec<u32>>::deserialize(d)?
+        .into_iter()
+        .map(|(codec, crf)| (VideoCodec::from(codec), crf))
+        .collect())
+}
+
+impl MatrixFile {
+    fn validate(&self) -> Result<()> {
+        let empty = [
+            ("res", self.res.as_ref().map(Vec::len)),
+            ("fps", self.fps.as_ref().map(Vec::len)),
+            ("crf", self.crf.as_ref().map(Vec::len)),
+            ("codec", self.codec.as_ref().map(Vec::len)),
+        ]
+        .into_iter()
+        .find(|(_, len)| *len == Some(0));
+        if let Some((key, _)) = empty {
+            bail!("{} が空です. 使わない場合は項目ごと省いてください", key);
+        }
+        if let Some((codec, _)) = self.codec_crf.iter().find(|(_, crf)| crf.is_empty()) {
+            bail!(
+                "codec-crf の {} が空です. 使わない場合は項目ごと省いてください",
+                codec.name()
+            );
+        }
+        if let Some(fps) = self.fps.iter().flatten().find(|fps| **fps == 0) {
+            bail!("fps には 1 以上を指定してください: {}", fps);
+        }
+        let crfs = self
+            .crf
+            .iter()
+            .flatten()
+            .chain(self.codec_crf.iter().flat_map(|(_, crf)| crf));
+        if let Some(crf) = crfs.into_iter().find(|crf| **crf > MAX_CRF) {
+            bail!("crf は 0〜{} の範囲で指定してください: {}", MAX_CRF, crf);
+        }
+        for (i, entry) in self.configs.iter().enumerate() {
+            if let Err(e) = entry.validate_explicit() {
+                bail!("configs の {} 番目: {}", i + 1, e);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub fn parse(content: &str) -> Result<MatrixFile> {
+    let file = toml::from_str::<MatrixFile>(content)?;
+    file.validate()?;
+
+    Ok(file)
+}
+
+pub fn load(path: &Path) -> Result<MatrixFile> {
+    let content = fs::read_to_string(path).with_context(|| {
+        format!(
+            "組み合わせの設定ファイルを読み込めません: {}",
+            path.display()
+        )
+    })?;
+    parse(&content).with_context(|| {
+        format!(
+            "組み合わせの設定ファイルの形式が不正です: {}",
+            path.display()
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let default = parse(DEFAULT_CONTENT).unwrap();
+        assert!(default.res.is_none() && default.fps.is_none() && default.crf.is_none());
+        assert_eq!(default.has_audio, None);
+
+        let file = parse(
+            "res = [\"480p\", \"1920x1080\"]\nfps = [30, 60]\ncrf = [23]\nhas-audio = false\n",
+        )
+        .unwrap();
+        let res = file
+            .res
+            .unwrap()
+            .iter()
+            .map(|r| r.to_wh())
+            .collect::<Vec<_>>();
+        assert_eq!(res, [(854, 480), (1920, 1080)]);
+        assert_eq!(file.fps, Some(vec![30, 60]));
+        assert_eq!(file.crf, Some(vec![23]));
+        assert_eq!(file.has_audio, Some(false));
+
+        // 雛形のコメントを外しただけで読める
+        let uncommented = DEFAULT_CONTENT
+            .lines()
+            .map(|line| {
+                line.strip_prefix("# ")
+                    .filter(|l| l.contains(" = ") || l.starts_with('['))
+                    .unwrap_or(line)
+            })
+            .filter(|line| !line.starts_with('#'))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let file = parse(&uncommented).unwrap();
+        assert_eq!(file.fps, Some(vec![30, 60]));
+        assert_eq!(file.presets[0].name, "story");
+        assert_eq!(file.configs.len(), 1);
+        assert_eq!(file.codec, Some(vec![VideoCodec::H264, VideoCodec::Vp9]));
+        assert_eq!(
+            file.codec_crf,
+            [
+                (VideoCodec::H264, vec![20, 26, 32]),
+                (VideoCodec::Vp9, vec![30, 36, 42])
+            ]
+        );
+
+        let err = |content| format!("{:#}", parse(content).unwrap_err());
+        assert!(err("res = [\"721p\"]\n").contains("721p"));
+        assert!(err("res = [\"wide\"]\n").contains("wide"));
+        assert!(err("crf = [70]\n").contains("0〜63"));
+        assert!(err("crf = [-1]\n").contains("crf"));
+        assert!(err("fps = [0]\n").contains("1 以上"));
+        assert!(err("fps = []\n").contains("fps が空です"));
+        assert!(err("[codec-crf]\nvp9 = []\n").contains("codec-crf の vp9 が空です"));
+        assert!(err("[codec-crf]\nvp9 = [64]\n").contains("0〜63"));
+        assert!(err("resolution = [\"720p\"]\n").contains("resolution"));
+        assert!(err("[[configs]]\nfps = 30\n").contains("configs の 1 番目"));
+    }
+}
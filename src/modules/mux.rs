@@ -0,0 +1,345 @@
+use core::fmt;
+
+use super::video::{self, VideoConfig};
+
+/// 出力コンテナが映像・音声のコーデックを格納できずに失敗したことを表す.
+/// ffmpeg のメッセージから読み取れた場合は, コーデックとコンテナ (マルチプレクサー名) も記録する.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MuxError {
+    pub codec: Option<String>,
+    pub container: Option<String>,
+}
+
+impl fmt::Display for MuxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "出力コンテナがコーデックに対応していません")
+    }
+}
+
+/// 小文字にしたメッセージにこれらが含まれていれば, コンテナとコーデックの組み合わせの問題とみなす.
+const MUX_ERROR_PATTERNS: &[&str] = &[
+    "not currently supported in container",
+    "could not find tag for codec",
+    "incompatible with output codec id",
+    "are supported for webm",
+    "not compatible with flv",
+    "muxer supports only",
+];
+
+/// `[mp4 @ 0x55d0c8a3e940] ...` の `mp4`.
+fn parse_muxer(message: &str) -> Option<String> {
+    let rest = message.split('[').find_map(|part| part.split_once(" @ "))?;
+    Some(rest.0.trim().to_string())
+}
+
+/// `Could not find tag for codec vp9 in stream #0` や `Video codec hevc not compatible with flv` のコーデック名.
+fn parse_codec(message: &str) -> Option<String> {
+    let after = ["tag for codec ", "video codec ", "audio codec "]
+        .iter()
+        .find_map(|prefix| {
+            let start = message.to_lowercase().find(prefix)? + prefix.len();
+            Some(&message[start..])
+        })?;
+    after
+        .split_whitespace()
+        .next()
+        .map(|codec| codec.trim_matches(|c: char| !c.is_alphanumeric() && c != '_'))
+        .filter(|codec| !codec.is_empty())
+        .map(str::to_lowercase)
+}
+
+pub fn classify_mux_error(message: &str) -> Option<MuxError> {
+    let lower = message.to_lowercase();
+    if !MUX_ERROR_PATTERNS.iter().any(|p| lower.contains(p)) {
+        return None;
+    }
+    let container = match lower.contains("are supported for webm") {
+        true => Some("webm".to_string()),
+        false => parse_muxer(message),
+    };
+
+    Some(MuxError {
+        codec: parse_codec(message),
+        container,
+    })
+}
+
+struct ContainerSupport {
+    ext: &'static str,
+    /// ffmpeg のログに出るマルチプレクサー名.
+    muxers: &'static [&'static str],
+    codecs: &'static [&'static str],
+}
+
+/// 候補として提案するコンテナと, 格納できる主なコーデック. 上から順に提案する.
+const CONTAINERS: &[ContainerSupport] = &[
+    ContainerSupport {
+        ext: "mp4",
+        muxers: &["mp4", "ipod"],
+        codecs: &[
+            "h264", "hevc", "av1", "vp9", "mpeg4", "aac", "mp3", "opus", "alac", "flac",
+        ],
+    },
+    ContainerSupport {
+        ext: "webm",
+        muxers: &["webm"],
+        codecs: &["vp8", "vp9", "av1", "opus", "vorbis"],
+    },
+    ContainerSupport {
+        ext: "mkv",
+        muxers: &["matroska"],
+        codecs: &[
+            "h264",
+            "hevc",
+            "av1",
+            "vp8",
+            "vp9",
+            "prores",
+            "mpeg4",
+            "aac",
+            "mp3",
+            "opus",
+            "vorbis",
+            "flac",
+            "alac",
+            "ac3",
+            "pcm_s16le",
+            "pcm_s24le",
+        ],
+    },
+    ContainerSupport {
+        ext: "mov",
+        muxers: &["mov"],
+        codecs: &[
+            "h264",
+            "hevc",
+            "prores",
+            "mpeg4",
+            "aac",
+            "mp3",
+            "alac",
+            "ac3",
+            "pcm_s16le",
+            "pcm_s24le",
+        ],
+    },
+    ContainerSupport {
+        ext: "flv",
+        muxers: &["flv"],
+        codecs: &["h264", "aac", "mp3"],
+    },
+    ContainerSupport {
+        ext: "gif",
+        muxers: &["gif"],
+        codecs: &["gif"],
+    },
+];
+
+const CODEC_LABELS: &[(&str, &str)] = &[
+    ("h264", "H.264"),
+    ("hevc", "H.265"),
+    ("vp8", "VP8"),
+    ("vp9", "VP9"),
+    ("av1", "AV1"),
+    ("prores", "ProRes"),
+    ("aac", "AAC"),
+    ("opus", "Opus"),
+    ("vorbis", "Vorbis"),
+    ("flac", "FLAC"),
+    ("pcm_s16le", "PCM"),
+    ("pcm_s24le", "PCM"),
+];
+
+/// エンコーダー名 (`libx264` など) を, ログやコンテナの対応表で使うコーデック名に直す.
+const ENCODER_CODECS: &[(&str, &str)] = &[
+    ("libx264", "h264"),
+    ("libx265", "hevc"),
+    ("libvpx", "vp8"),
+    ("libvpx-vp9", "vp9"),
+    ("libaom-av1", "av1"),
+    ("libsvtav1", "av1"),
+    ("prores_ks", "prores"),
+    ("libopus", "opus"),
+    ("libvorbis", "vorbis"),
+];
+
+fn lookup<'a>(table: &[(&str, &'a str)], key: &str) -> Option<&'a str> {
+    table.iter().find(|(k, _)| *k == key).map(|(_, v)| *v)
+}
+
+fn codec_label(codec: &str) -> String {
+    lookup(CODEC_LABELS, codec).map_or(codec.to_string(), str::to_string)
+}
+
+fn container_ext(container: &str) -> String {
+    let container = container.to_lowercase();
+    CONTAINERS
+        .iter()
+        .find(|c| c.ext == container || c.muxers.contains(&container.as_str()))
+        .map_or(container, |c| c.ext.to_string())
+}
+
+/// 出力の拡張子 (かマルチプレクサー名) のコンテナが, 元動画の音声のコーデックをそのまま格納できるか.
+/// 対応表にないコンテナでは確かめられないので `false` にする.
+pub fn can_copy_audio(ext: &str, codec: &str) -> bool {
+    let container = container_ext(ext);
+    CONTAINERS
+        .iter()
+        .find(|c| c.ext == container)
+        .is_some_and(|c| c.codecs.contains(&codec))
+}
+
+/// 音声をコピーできない場合に使うエンコーダー. 音声を格納できないコンテナ (GIF) では `None`.
+pub fn audio_encoder(ext: &str) -> Option<&'static str> {
+    match container_ext(ext).as_str() {
+        "gif" => None,
+        "webm" => Some("libopus"),
+        _ => Some("aac"),
+    }
+}
+
+/// 失敗したタスクの設定から, 代わりに使えるコンテナを提案する.
+/// ffmpeg のメッセージからコーデックやコンテナが読み取れなかった場合は, 設定と出力の拡張子から補う.
+pub fn suggest(error: &MuxError, config: &VideoConfig, output_ext: &str) -> Option<String> {
+    let codec = error.codec.clone().unwrap_or_else(|| {
+        let encoder = video::codec_name(config);
+        lookup(ENCODER_CODECS, encoder)
+            .unwrap_or(encoder)
+            .to_string()
+    });
+    let container = container_ext(error.container.as_deref().unwrap_or(output_ext));
+
+    let alternatives = CONTAINERS
+        .iter()
+        .filter(|c| c.ext != container && c.codecs.contains(&codec.as_str()))
+        .map(|c| c.ext)
+        .collect::<Vec<_>>();
+    if alternatives.is_empty() {
+        return None;
+    }
+
+    Some(format!(
+        "{} は {} に格納できません. 出力を {} のいずれかのコンテナにしてみてください",
+        codec_label(&codec),
+        container.to_uppercase(),
+        alternatives.join(" / ")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::video::VideoCodec;
+
+    fn config(codec: Option<VideoCodec>) -> VideoConfig {
+        VideoConfig {
+            codec,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_classify_mux_error() {
+        let cases = [
+            (
+                "[mp4 @ 0x55d0c8a3e940] Could not find tag for codec vp8 in stream #0, codec not currently supported in container",
+                Some("vp8"),
+                Some("mp4"),
+            ),
+            (
+                "[webm @ 0x7f9b1c004c00] Only VP8 or VP9 or AV1 video and Vorbis or Opus audio and WebVTT subtitles are supported for WebM.",
+                None,
+                Some("webm"),
+            ),
+            (
+                "[mp4 @ 0x5581] Could not find tag for codec pcm_s16le in stream #1, codec not currently supported in container",
+                Some("pcm_s16le"),
+                Some("mp4"),
+            ),
+            (
+                "[flv @ 0x600003] Video codec hevc not compatible with flv",
+                Some("hevc"),
+                Some("flv"),
+            ),
+            (
+                "[mov @ 0x14e6] Could not find tag for codec vp9 in stream #0, codec not currently supported in container",
+                Some("vp9"),
+                Some("mov"),
+            ),
+            (
+                "[gif @ 0x1234] GIF muxer supports only a single video GIF stream.",
+                None,
+                Some("gif"),
+            ),
+        ];
+        for (message, codec, container) in cases {
+            assert_eq!(
+                classify_mux_error(message),
+                Some(MuxError {
+                    codec: codec.map(str::to_string),
+                    container: container.map(str::to_string),
+                }),
+                "{}",
+                message
+            );
+        }
+
+        assert!(classify_mux_error("Unknown encoder 'libfoo'").is_none());
+        assert!(classify_mux_error("Error setting profile main10.").is_none());
+    }
+
+    #[test]
+    fn test_audio_codec() {
+        assert!(can_copy_audio("mp4", "aac"));
+        assert!(can_copy_audio("MKV", "pcm_s16le"));
+        assert!(!can_copy_audio("webm", "aac"));
+        assert!(!can_copy_audio("mpegts", "aac"));
+        assert!(!can_copy_audio("mp4", ""));
+
+        assert_eq!(audio_encoder("webm"), Some("libopus"));
+        assert_eq!(audio_encoder("mov"), Some("aac"));
+        assert_eq!(audio_encoder("gif"), None);
+    }
+
+    #[test]
+    fn test_suggest() {
+        let suggest_for = |message: &str, codec, ext| {
+            suggest(&classify_mux_error(message).unwrap(), &config(codec), ext)
+        };
+
+        assert_eq!(
+            suggest_for(
+                "[mov @ 0x14e6] Could not find tag for codec vp9 in stream #0, codec not currently supported in container",
+                Some(VideoCodec::Vp9),
+                "mov"
+            )
+            .unwrap(),
+            "VP9 は MOV に格納できません. 出力を mp4 / webm / mkv のいずれかのコンテナにしてみてください"
+        );
+        // メッセージにコーデックがない場合は設定から補う
+        assert_eq!(
+            suggest_for(
+                "[webm @ 0x7f9b] Only VP8 or VP9 or AV1 video and Vorbis or Opus audio and WebVTT subtitles are supported for WebM.",
+                None,
+                "webm"
+            )
+            .unwrap(),
+            "H.264 は WEBM に格納できません. 出力を mp4 / mkv / mov / flv のいずれかのコンテナにしてみてください"
+        );
+        assert_eq!(
+            suggest_for(
+                "Could not find tag for codec prores in stream #0, codec not currently supported in container",
+                Some(VideoCodec::ProRes),
+                "mp4"
+            )
+            .unwrap(),
+            "ProRes は MP4 に格納できません. 出力を mkv / mov のいずれかのコンテナにしてみてください"
+        );
+        assert!(suggest_for(
+            "[gif @ 0x1234] GIF muxer supports only a single video GIF stream.",
+            Some(VideoCodec::Other("gif".to_string())),
+            "gif"
+        )
+        .is_none());
+    }
+}
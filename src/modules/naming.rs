@@ -0,0 +1,802 @@
+use anyhow::{Context, Result};
+use itertools::Itertools;
+use std::{
+    collections::HashSet,
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use super::{
+    cli::OutputLayout,
+    report::{self, SIDECAR_EXTENSION},
+    video::{FpsMode, VideoCodec, VideoConfig, VideoRes},
+};
+
+pub const SAMPLE_SUFFIX: &str = "--sample";
+pub const PRESET_PREFIX: &str = "--preset-";
+pub const FPS_MODE_PREFIX: &str = "--fps-mode-";
+pub const CODEC_PREFIX: &str = "--codec-";
+/// 音声を含めない設定の出力に付ける. 音声の有無だけが違う出力が同じ名前にならないようにする.
+pub const NO_AUDIO_SUFFIX: &str = "--no-audio";
+
+/// 出力のファイル名から読み取った情報. `config` のうちファイル名に含まれない項目は既定値になる.
+#[derive(Debug, Clone)]
+pub struct ParsedName {
+    pub source: String,
+    pub config: VideoConfig,
+    pub sample: bool,
+    pub ext: String,
+}
+
+impl ParsedName {
+    pub fn to_file_name(&self) -> String {
+        output_file_name(&self.source, &self.config, self.sample, &self.ext)
+    }
+}
+
+pub struct NamingScheme {
+    pub version: u32,
+    pub parse: fn(&str) -> Option<ParsedName>,
+}
+
+pub const CURRENT_SCHEME: NamingScheme = NamingScheme {
+    version: 3,
+    parse: parse_v3,
+};
+
+/// 過去の命名規則. `VideoConfig::to_file_name` を変えるときは, 変更前のパーサーをここに移して
+/// `vvcnv migrate` で古い出力の名前を付け直せるようにする.
+pub const LEGACY_SCHEMES: &[NamingScheme] = &[
+    NamingScheme {
+        version: 1,
+        parse: parse_v1,
+    },
+    NamingScheme {
+        version: 2,
+        parse: parse_v2,
+    },
+];
+
+pub fn output_file_name(source: &str, config: &VideoConfig, sample: bool, ext: &str) -> String {
+    format!(
+        "{}{}{}.{}",
+        source,
+        config.to_file_name(),
+        if sample { SAMPLE_SUFFIX } else { "" },
+        ext
+    )
+}
+
+/// `--name-template` で使える値.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placeholder {
+    Stem,
+    Ext,
+    Width,
+    Height,
+    Fps,
+    Crf,
+    Preset,
+}
+
+impl Placeholder {
+    pub const ALL: [Placeholder; 7] = [
+        Placeholder::Stem,
+        Placeholder::Ext,
+        Placeholder::Width,
+        Placeholder::Height,
+        Placeholder::Fps,
+        Placeholder::Crf,
+        Placeholder::Preset,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Placeholder::Stem => "stem",
+            Placeholder::Ext => "ext",
+            Placeholder::Width => "width",
+            Placeholder::Height => "height",
+            Placeholder::Fps => "fps",
+            Placeholder::Crf => "crf",
+            Placeholder::Preset => "preset",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum NameTemplateErr {
+    Unknown(String),
+    Unclosed,
+    Separator,
+    Empty,
+}
+
+impl fmt::Display for NameTemplateErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NameTemplateErr::Unknown(name) => write!(
+                f,
+                "不明な値です: {{{}}} (使える値: {})",
+                name,
+                Placeholder::ALL
+                    .iter()
+                    .map(|p| format!("{{{}}}", p.name()))
+                    .join(", ")
+            ),
+            NameTemplateErr::Unclosed => {
+                write!(f, "{{ が閉じられていません. {{ そのものは {{{{ と書いてください")
+            }
+            NameTemplateErr::Separator => write!(
+                f,
+                "ファイル名にディレクトリの区切りは使えません. 出力先は --out-dir で指定してください"
+            ),
+            NameTemplateErr::Empty => write!(f, "ファイル名が空です"),
+        }
+    }
+}
+
+impl std::error::Error for NameTemplateErr {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Value(Placeholder),
+}
+
+/// `--name-template` で指定する出力のファイル名 (例: `{stem}_{height}p_crf{crf}.{ext}`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameTemplate {
+    parts: Vec<Part>,
+}
+
+impl FromStr for NameTemplate {
+    type Err = NameTemplateErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(NameTemplateErr::Empty);
+        }
+        if s.contains(['/', '\\']) {
+            return Err(NameTemplateErr::Separator);
+        }
+
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    let mut closed = false;
+                    for c in chars.by_ref() {
+                        if c == '}' {
+                            closed = true;
+                            break;
+                        }
+                        name.push(c);
+                    }
+                    if !closed {
+                        return Err(NameTemplateErr::Unclosed);
+                    }
+                    let value = Placeholder::ALL
+                        .into_iter()
+                        .find(|p| p.name() == name)
+                        .ok_or(NameTemplateErr::Unknown(name))?;
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Value(value));
+                }
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Ok(Self { parts })
+    }
+}
+
+/// 読み直すと同じテンプレートになる文字列. `{` と `}` そのものは重ねて書く.
+impl fmt::Display for NameTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for part in &self.parts {
+            match part {
+                Part::Literal(s) => write!(f, "{}", s.replace('{', "{{").replace('}', "}}"))?,
+                Part::Value(value) => write!(f, "{{{}}}", value.name())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl NameTemplate {
+    pub fn uses(&self, placeholder: Placeholder) -> bool {
+        self.parts.contains(&Part::Value(placeholder))
+    }
+
+    /// 出力のファイル名. 試し出力では拡張子の前に `--sample` を付ける.
+    /// プリセットを使わない設定の `{preset}` は空になる.
+    pub fn render(&self, source: &str, config: &VideoConfig, sample: bool, ext: &str) -> String {
+        let (width, height) = config.res.to_wh();
+        let name = self
+            .parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(s) => s.clone(),
+                Part::Value(value) => match value {
+                    Placeholder::Stem => source.to_string(),
+                    Placeholder::Ext => ext.to_string(),
+                    Placeholder::Width => width.to_string(),
+                    Placeholder::Height => height.to_string(),
+                    Placeholder::Fps => config.fps.to_string(),
+                    Placeholder::Crf => config.crf.to_string(),
+                    Placeholder::Preset => config.preset_name.clone().unwrap_or_default(),
+                },
+            })
+            .collect::<String>();
+        if !sample {
+            return name;
+        }
+
+        match name.strip_suffix(&format!(".{}", ext)) {
+            Some(stem) if !ext.is_empty() => format!("{}{}.{}", stem, SAMPLE_SUFFIX, ext),
+            _ => format!("{}{}", name, SAMPLE_SUFFIX),
+        }
+    }
+}
+
+/// `--layout per-config` で設定ごとに分けるディレクトリ. ファイル名の設定の部分から先頭の `--` を除いたもの
+/// (`[preset-<プリセット名>--]res-<W>x<H>--fps-<FPS>--crf-<CRF>[--fps-mode-<blend|mci>][--codec-<コーデック>][--no-audio]`).
+pub fn config_dir(config: &VideoConfig) -> PathBuf {
+    PathBuf::from(config.to_file_name().trim_start_matches("--"))
+}
+
+/// `config_dir` の名前から設定を読み取る. 作り直した名前が一致しない場合は `None`.
+pub fn parse_config_dir(name: &str) -> Option<VideoConfig> {
+    let name = format!("--{}", name);
+    let at = name.rfind("--res-")?;
+    let preset_name = match name[..at].strip_prefix(PRESET_PREFIX) {
+        Some(preset) => Some(preset.to_string()),
+        None if at == 0 => None,
+        None => return None,
+    };
+    let config = parse_config(&name[at..], preset_name)?;
+
+    (config.to_file_name() == name).then_some(config)
+}
+
+/// 出力のパスから元の名前と設定を読み取る. per-config では設定を親のディレクトリ (入力のサブディレクトリを挟んでもよい) から,
+/// 元の名前をファイル名から読む.
+pub fn parse_output_path(path: &Path, layout: OutputLayout) -> Option<ParsedName> {
+    let file_name = path.file_name()?.to_str()?;
+    match layout {
+        OutputLayout::Flat => parse_v3(file_name),
+        OutputLayout::PerConfig => {
+            let config = path
+                .ancestors()
+                .skip(1)
+                .filter_map(|dir| dir.file_name()?.to_str())
+                .find_map(parse_config_dir)?;
+            let (stem, ext) = file_name.rsplit_once('.')?;
+            let (source, sample) = match stem.strip_suffix(SAMPLE_SUFFIX) {
+                Some(stem) => (stem, true),
+                None => (stem, false),
+            };
+            (!source.is_empty()).then(|| ParsedName {
+                source: source.to_string(),
+                config,
+                sample,
+                ext: ext.to_string(),
+            })
+        }
+    }
+}
+
+/// 同じパスになる最初の 2 つの番号.
+pub fn first_collision(paths: &[String]) -> Option<(usize, usize)> {
+    paths
+        .iter()
+        .enumerate()
+        .tuple_combinations()
+        .find_map(|((i, a), (j, b))| (a == b).then_some((i, j)))
+}
+
+/// `--res-<W>x<H>--fps-<FPS>--crf-<CRF>[--fps-mode-<blend|mci>][--codec-<コーデック>][--no-audio]` を読む. 名前との一致は呼び出し側で確かめる.
+fn parse_config(config: &str, preset_name: Option<String>) -> Option<VideoConfig> {
+    let (config, has_audio) = match config.strip_suffix(NO_AUDIO_SUFFIX) {
+        Some(config) => (config, false),
+        None => (config, true),
+    };
+    let (config, codec) = match config.rsplit_once(CODEC_PREFIX) {
+        Some((config, codec)) => (config, Some(VideoCodec::from(codec.to_string()))),
+        None => (config, None),
+    };
+    let (res, rest) = config.strip_prefix("--res-")?.split_once("--fps-")?;
+    let (fps, crf) = rest.split_once("--crf-")?;
+    let (crf, fps_mode) = match crf.split_once(FPS_MODE_PREFIX) {
+        Some((crf, mode)) => (crf, Some(mode.parse::<FpsMode>().ok()?)),
+        None => (crf, None),
+    };
+
+    Some(VideoConfig {
+        res: res.parse::<VideoRes>().ok()?,
+        fps: fps.parse().ok()?,
+        crf: crf.parse().ok()?,
+        has_audio,
+        codec,
+        preset_name,
+        fps_mode,
+        ..Default::default()
+    })
+}
+
+/// `--no-audio` を付ける前の命名規則 (`<元の名前>[--preset-<プリセット名>]--res-<W>x<H>--fps-<FPS>--crf-<CRF>[--fps-mode-<blend|mci>][--sample].<拡張子>`).
+/// 当時は `has_audio` にかかわらず音声を含めていたため, 音声を含める設定として読む.
+fn parse_v1(file_name: &str) -> Option<ParsedName> {
+    parse_v2(file_name).filter(|parsed| parsed.config.has_audio)
+}
+
+/// `--codec-<コーデック>` を付ける前の命名規則 (`<元の名前>[--preset-<プリセット名>]--res-<W>x<H>--fps-<FPS>--crf-<CRF>[--fps-mode-<blend|mci>][--no-audio][--sample].<拡張子>`).
+/// 当時はすべて libx264 でエンコードしていたため, コーデックを指定しない設定として読む.
+fn parse_v2(file_name: &str) -> Option<ParsedName> {
+    parse_v3(file_name).filter(|parsed| parsed.config.codec.is_none())
+}
+
+/// `<元の名前>[--preset-<プリセット名>]--res-<W>x<H>--fps-<FPS>--crf-<CRF>[--fps-mode-<blend|mci>][--codec-<コーデック>][--no-audio][--sample].<拡張子>`
+fn parse_v3(file_name: &str) -> Option<ParsedName> {
+    let (stem, ext) = file_name.rsplit_once('.')?;
+    let (stem, sample) = match stem.strip_suffix(SAMPLE_SUFFIX) {
+        Some(stem) => (stem, true),
+        None => (stem, false),
+    };
+    let (source, config) = stem.split_at(stem.rfind("--res-")?);
+    let (source, preset_name) = match source.rfind(PRESET_PREFIX) {
+        Some(at) => (
+            &source[..at],
+            Some(source[at + PRESET_PREFIX.len()..].to_string()),
+        ),
+        None => (source, None),
+    };
+
+    let config = parse_config(config, preset_name)?;
+    if source.is_empty() || config.to_file_name() != stem[source.len()..] {
+        return None;
+    }
+
+    Some(ParsedName {
+        source: source.to_string(),
+        config,
+        sample,
+        ext: ext.to_string(),
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Migration {
+    Rename {
+        from: PathBuf,
+        to: PathBuf,
+        version: u32,
+    },
+    Manual {
+        path: PathBuf,
+        reason: String,
+    },
+}
+
+fn is_sidecar(path: &Path) -> bool {
+    path.to_string_lossy()
+        .ends_with(&format!(".{}", SIDECAR_EXTENSION))
+}
+
+/// 最新の命名規則に一致しないファイルについて, 付け直す名前を決める.
+/// どの規則で読むべきか決まらないものや, 付け直すと他のファイルと衝突するものは推測せずに `Manual` にする.
+pub fn plan_migration(
+    files: &[PathBuf],
+    current: &NamingScheme,
+    legacy: &[NamingScheme],
+) -> Vec<Migration> {
+    let existing = files.iter().collect::<HashSet<_>>();
+    let mut targets = HashSet::new();
+    let mut plan = Vec::new();
+
+    for path in files {
+        let Some(name) = path.file_name().map(|n| n.to_string_lossy().into_owned()) else {
+            continue;
+        };
+        // per-config の出力は元の名前のままなので, 設定のディレクトリの中にあれば最新の規則とみなす
+        let is_current = (current.parse)(&name).is_some()
+            || parse_output_path(path, OutputLayout::PerConfig).is_some();
+        if name.starts_with('.') || is_sidecar(path) || is_current {
+            continue;
+        }
+        let manual = |reason: String| Migration::Manual {
+            path: path.clone(),
+            reason,
+        };
+
+        let mut candidates = legacy
+            .iter()
+            .filter_map(|scheme| Some((scheme.version, (scheme.parse)(&name)?.to_file_name())))
+            .collect::<Vec<_>>();
+        candidates.sort_by(|a, b| a.1.cmp(&b.1));
+        candidates.dedup_by(|a, b| a.1 == b.1);
+
+        let migration = match candidates.as_slice() {
+            [] => manual("命名規則を判別できません".to_string()),
+            [(version, new_name)] => {
+                let to = path.with_file_name(new_name);
+                if existing.contains(&to) || !targets.insert(to.clone()) {
+                    manual(format!("移動先が既に存在します: {}", to.display()))
+                } else {
+                    Migration::Rename {
+                        from: path.clone(),
+                        to,
+                        version: *version,
+                    }
+                }
+            }
+            _ => manual(format!(
+                "複数の命名規則に一致します ({})",
+                candidates
+                    .iter()
+                    .map(|(version, name)| format!("v{}: {}", version, name))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+        };
+        plan.push(migration);
+    }
+
+    plan
+}
+
+/// ファイルの名前を付け直し, 設定ファイル (*.vvcnv.json) があれば出力パスを書き換えて一緒に移動する.
+pub fn apply_rename(from: &Path, to: &Path) -> Result<()> {
+    fs::rename(from, to)
+        .with_context(|| format!("名前の変更に失敗しました: {}", from.display()))?;
+
+    let sidecar = report::sidecar_path(&from.to_string_lossy());
+    if !sidecar.exists() {
+        return Ok(());
+    }
+    let mut value = report::read_sidecar(&sidecar)?;
+    let to = to.to_string_lossy();
+    if let Some(outcome) = &mut value.outcome {
+        outcome.output_path = to.to_string();
+    }
+    report::write_sidecar(&to, &value)?;
+    fs::remove_file(&sidecar).with_context(|| {
+        format!(
+            "古い設定ファイルの削除に失敗しました: {}",
+            sidecar.display()
+        )
+    })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `--crf` が末尾ではなかった頃を想定した規則.
+    fn parse_legacy(file_name: &str) -> Option<ParsedName> {
+        let (stem, ext) = file_name.rsplit_once('.')?;
+        let (source, rest) = stem.split_once("--crf-")?;
+        let (crf, res) = rest.split_once("--res-")?;
+        let (width, height) = res.split_once('x')?;
+
+        Some(ParsedName {
+            source: source.to_string(),
+            config: VideoConfig {
+                res: VideoRes::from_wh(width.parse().ok()?, height.parse().ok()?),
+                crf: crf.parse().ok()?,
+                ..Default::default()
+            },
+            sample: false,
+            ext: ext.to_string(),
+        })
+    }
+
+    /// どんな名前にも一致してしまう規則.
+    fn parse_greedy(file_name: &str) -> Option<ParsedName> {
+        let (stem, ext) = file_name.rsplit_once('.')?;
+        Some(ParsedName {
+            source: stem.to_string(),
+            config: VideoConfig::default(),
+            sample: false,
+            ext: ext.to_string(),
+        })
+    }
+
+    #[test]
+    fn test_parse_v3() {
+        let config = VideoConfig {
+            res: VideoRes::R720p,
+            fps: 30,
+            crf: 28,
+            ..Default::default()
+        };
+        let name = output_file_name("my--clip", &config, true, "mp4");
+        assert_eq!(name, "my--clip--res-1280x720--fps-30--crf-28--sample.mp4");
+
+        let parsed = parse_v3(&name).unwrap();
+        assert_eq!(parsed.source, "my--clip");
+        assert!(parsed.sample);
+        assert_eq!(parsed.to_file_name(), name);
+        assert!(parse_v3("clip--res-1280x720--fps-30--crf-28.mkv").is_some());
+        let parsed = parse_v3("clip--preset-chat--res-1280x720--fps-30--crf-28.mp4").unwrap();
+        assert_eq!(parsed.source, "clip");
+        assert_eq!(parsed.config.preset_name.as_deref(), Some("chat"));
+
+        // 日本語, 絵文字, 結合文字を含む名前もそのまま戻る
+        for source in [
+            "会議録画🎥 2024",
+            "cafe\u{301}--take",
+            "ｆｕｌｌ　ｗｉｄｔｈ",
+        ] {
+            let name = output_file_name(source, &config, false, "mp4");
+            let parsed = parse_v3(&name).unwrap();
+            assert_eq!(parsed.source, source);
+            assert_eq!(parsed.to_file_name(), name);
+        }
+        let name = "会議録画🎥--preset-週報--res-1280x720--fps-30--crf-28.mp4";
+        let parsed = parse_v3(name).unwrap();
+        assert_eq!(parsed.source, "会議録画🎥");
+        assert_eq!(parsed.config.preset_name.as_deref(), Some("週報"));
+
+        let parsed = parse_v3("clip--res-1280x720--fps-24--crf-28--fps-mode-mci.mp4").unwrap();
+        assert_eq!(parsed.config.fps_mode, Some(FpsMode::Mci));
+        assert_eq!(parsed.config.crf, 28);
+        assert!(parsed.config.has_audio);
+
+        // 音声の有無だけが違う出力は別の名前になる
+        let silent = VideoConfig {
+            has_audio: false,
+            ..config.clone()
+        };
+        let name = output_file_name("clip", &silent, false, "mp4");
+        assert_eq!(name, "clip--res-1280x720--fps-30--crf-28--no-audio.mp4");
+        assert!(!parse_v3(&name).unwrap().config.has_audio);
+        let parsed =
+            parse_v3("clip--res-1280x720--fps-24--crf-28--fps-mode-mci--no-audio.mp4").unwrap();
+        assert_eq!(parsed.config.fps_mode, Some(FpsMode::Mci));
+        assert!(!parsed.config.has_audio);
+
+        // コーデックだけが違う出力も別の名前になる. H.264 は既定と同じ名前にする
+        let h265 = VideoConfig {
+            codec: Some(VideoCodec::H265),
+            ..silent.clone()
+        };
+        let name = output_file_name("clip", &h265, false, "mkv");
+        assert_eq!(
+            name,
+            "clip--res-1280x720--fps-30--crf-28--codec-h265--no-audio.mkv"
+        );
+        assert_eq!(
+            parse_v3(&name).unwrap().config.codec,
+            Some(VideoCodec::H265)
+        );
+        let nvenc = VideoConfig {
+            codec: Some(VideoCodec::Other("h264_nvenc".to_string())),
+            ..config.clone()
+        };
+        let name = output_file_name("clip", &nvenc, false, "mp4");
+        assert_eq!(parse_v3(&name).unwrap().config.codec, nvenc.codec);
+        let h264 = VideoConfig {
+            codec: Some(VideoCodec::H264),
+            ..config.clone()
+        };
+        assert_eq!(h264.to_file_name(), config.to_file_name());
+        assert!(parse_v3("clip--res-1280x720--fps-30--crf-28--codec-h264.mp4").is_none());
+
+        assert!(parse_v3("clip.mp4").is_none());
+        // drop はファイル名に含めない
+        assert!(parse_v3("clip--res-1280x720--fps-24--crf-28--fps-mode-drop.mp4").is_none());
+        assert!(parse_v3("--res-1280x720--fps-30--crf-28.mp4").is_none());
+        assert!(parse_v3("clip--res-1280x720--fps-030--crf-28.mp4").is_none());
+        assert!(parse_v3("clip--res-1280x720--crf-28.mp4").is_none());
+    }
+
+    #[test]
+    fn test_legacy_schemes() {
+        // 音声を含めない設定でも音声が残っていた頃の出力は, 音声を含める設定として今の規則と同じ名前になる
+        let name = "clip--preset-chat--res-1280x720--fps-30--crf-28--sample.mp4";
+        for scheme in LEGACY_SCHEMES {
+            let parsed = (scheme.parse)(name).unwrap();
+            assert!(parsed.config.has_audio);
+            assert_eq!(parsed.to_file_name(), name);
+        }
+        assert!(LEGACY_SCHEMES
+            .iter()
+            .all(|scheme| scheme.version < CURRENT_SCHEME.version));
+        assert!(parse_v1("clip--res-1280x720--fps-30--crf-28--no-audio.mp4").is_none());
+        // コーデックを名前に含める前の出力は, H.264 の設定として今の規則と同じ名前になる
+        let silent = "clip--res-1280x720--fps-30--crf-28--no-audio.mkv";
+        let parsed = parse_v2(silent).unwrap();
+        assert_eq!(parsed.config.codec, None);
+        assert!(!parsed.config.has_audio);
+        assert_eq!(parsed.to_file_name(), silent);
+        assert_eq!(
+            parse_v3(silent).unwrap().to_file_name(),
+            parsed.to_file_name()
+        );
+        assert!(parse_v2("clip--res-1280x720--fps-30--crf-28--codec-h265.mkv").is_none());
+        assert!(plan_migration(&[PathBuf::from(name)], &CURRENT_SCHEME, LEGACY_SCHEMES).is_empty());
+    }
+
+    #[test]
+    fn test_name_template() {
+        let config = VideoConfig {
+            res: VideoRes::R720p,
+            fps: 30,
+            crf: 28,
+            ..Default::default()
+        };
+        let template = "{stem}_{height}p_crf{crf}.{ext}"
+            .parse::<NameTemplate>()
+            .unwrap();
+        for text in ["{stem}_{height}p_crf{crf}.{ext}", "{{{stem}}}.{ext}"] {
+            let parsed = text.parse::<NameTemplate>().unwrap();
+            assert_eq!(parsed.to_string(), text);
+        }
+        assert_eq!(
+            template.render("会議録画🎥", &config, false, "mp4"),
+            "会議録画🎥_720p_crf28.mp4"
+        );
+        assert_eq!(
+            template.render("clip", &config, true, "mp4"),
+            "clip_720p_crf28--sample.mp4"
+        );
+        assert!(template.uses(Placeholder::Crf));
+        assert!(!template.uses(Placeholder::Preset));
+
+        let template = "{{{preset}}}{width}x{height}@{fps}"
+            .parse::<NameTemplate>()
+            .unwrap();
+        let preset = VideoConfig {
+            preset_name: Some("chat".to_string()),
+            ..config.clone()
+        };
+        assert_eq!(
+            template.render("", &preset, false, "mp4"),
+            "{chat}1280x720@30"
+        );
+        assert_eq!(
+            template.render("", &config, true, "mp4"),
+            "{}1280x720@30--sample"
+        );
+
+        let err = |s: &str| s.parse::<NameTemplate>().unwrap_err().to_string();
+        assert!(err("{stem}_{bitrate}.{ext}").starts_with("不明な値です: {bitrate}"));
+        assert!(err("{stem").contains("閉じられていません"));
+        assert!(err("out/{stem}.{ext}").contains("--out-dir"));
+        assert!(err("").contains("空"));
+    }
+
+    #[test]
+    fn test_config_dir() {
+        let config = VideoConfig {
+            res: VideoRes::R720p,
+            fps: 30,
+            crf: 28,
+            ..Default::default()
+        };
+        assert_eq!(
+            config_dir(&config),
+            Path::new("res-1280x720--fps-30--crf-28")
+        );
+        let preset = VideoConfig {
+            preset_name: Some("chat".to_string()),
+            fps_mode: Some(FpsMode::Blend),
+            ..config.clone()
+        };
+        assert_eq!(
+            config_dir(&preset),
+            Path::new("preset-chat--res-1280x720--fps-30--crf-28--fps-mode-blend")
+        );
+
+        // ディレクトリの名前から設定に戻る
+        for config in [&config, &preset] {
+            let name = config_dir(config);
+            let parsed = parse_config_dir(name.to_str().unwrap()).unwrap();
+            assert_eq!(parsed.to_file_name(), config.to_file_name());
+        }
+        assert!(parse_config_dir("1280x720").is_none());
+        assert!(parse_config_dir("day1--res-1280x720--fps-30--crf-28").is_none());
+        assert!(parse_config_dir("res-1280x720--fps-030--crf-28").is_none());
+
+        let path = Path::new("out")
+            .join("res-1280x720--fps-30--crf-28")
+            .join("day1")
+            .join("会議録画--sample.mp4");
+        let parsed = parse_output_path(&path, OutputLayout::PerConfig).unwrap();
+        assert_eq!(parsed.source, "会議録画");
+        assert!(parsed.sample);
+        assert_eq!(parsed.config.res.to_wh(), (1280, 720));
+        assert_eq!((parsed.config.fps, parsed.config.crf), (30, 28));
+        assert!(parse_output_path(&path, OutputLayout::Flat).is_none());
+        assert!(
+            parse_output_path(Path::new("out/day1/clip.mp4"), OutputLayout::PerConfig).is_none()
+        );
+        let flat = Path::new("out/clip--res-1280x720--fps-30--crf-28.mp4");
+        assert_eq!(
+            parse_output_path(flat, OutputLayout::Flat).unwrap().source,
+            "clip"
+        );
+    }
+
+    #[test]
+    fn test_first_collision() {
+        let paths = ["a.mp4", "b.mp4", "c.mp4", "b.mp4"].map(String::from);
+        assert_eq!(first_collision(&paths), Some((1, 3)));
+        assert_eq!(first_collision(&paths[..3]), None);
+    }
+
+    #[test]
+    fn test_plan_migration() {
+        let files = [
+            "out/clip--res-1280x720--fps-30--crf-28.mp4",
+            "out/clip--res-1280x720--fps-30--crf-28.vvcnv.json",
+            "out/clip--crf-20--res-1920x1080.mp4",
+            "out/clip--crf-28--res-1280x720.mp4",
+            "out/notes.txt",
+            "out/.DS_Store",
+            "out/res-1280x720--fps-30--crf-28/clip.mp4",
+        ]
+        .map(PathBuf::from);
+        let legacy = [NamingScheme {
+            version: 0,
+            parse: parse_legacy,
+        }];
+
+        let plan = plan_migration(&files, &CURRENT_SCHEME, &legacy);
+        assert_eq!(
+            plan,
+            vec![
+                Migration::Rename {
+                    from: files[2].clone(),
+                    to: PathBuf::from("out/clip--res-1920x1080--fps-30--crf-20.mp4"),
+                    version: 0,
+                },
+                Migration::Manual {
+                    path: files[3].clone(),
+                    reason: "移動先が既に存在します: out/clip--res-1280x720--fps-30--crf-28.mp4"
+                        .to_string(),
+                },
+                Migration::Manual {
+                    path: files[4].clone(),
+                    reason: "命名規則を判別できません".to_string(),
+                },
+            ]
+        );
+
+        let legacy = [
+            NamingScheme {
+                version: 0,
+                parse: parse_legacy,
+            },
+            NamingScheme {
+                version: 1,
+                parse: parse_greedy,
+            },
+        ];
+        let plan = plan_migration(&files[2..3], &CURRENT_SCHEME, &legacy);
+        assert!(matches!(
+            &plan[..],
+            [Migration::Manual { reason, .. }] if reason.starts_with("複数の命名規則")
+        ));
+    }
+}
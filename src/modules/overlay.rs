@@ -0,0 +1,193 @@
+use core::fmt;
+use std::path::{Path, PathBuf};
+
+use super::video::{self, VideoConfig};
+
+#[cfg(target_os = "macos")]
+const FONT_CANDIDATES: &[&str] = &[
+    "/System/Library/Fonts/Helvetica.ttc",
+    "/System/Library/Fonts/Supplemental/Arial.ttf",
+    "/Library/Fonts/Arial.ttf",
+];
+
+#[cfg(target_os = "windows")]
+const FONT_CANDIDATES: &[&str] = &[
+    "C:/Windows/Fonts/arial.ttf",
+    "C:/Windows/Fonts/segoeui.ttf",
+    "C:/Windows/Fonts/tahoma.ttf",
+];
+
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+const FONT_CANDIDATES: &[&str] = &[
+    "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf",
+    "/usr/share/fonts/dejavu/DejaVuSans.ttf",
+    "/usr/share/fonts/TTF/DejaVuSans.ttf",
+    "/usr/share/fonts/truetype/liberation/LiberationSans-Regular.ttf",
+    "/usr/share/fonts/liberation/LiberationSans-Regular.ttf",
+    "/usr/share/fonts/noto/NotoSans-Regular.ttf",
+    "/usr/share/fonts/truetype/noto/NotoSans-Regular.ttf",
+];
+
+#[derive(Debug)]
+pub enum FontErr {
+    NotFound(PathBuf),
+    NoCandidate(Vec<PathBuf>),
+}
+
+impl fmt::Display for FontErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FontErr::NotFound(path) => {
+                write!(f, "フォントファイルが見つかりません: {}", path.display())
+            }
+            FontErr::NoCandidate(searched) => write!(
+                f,
+                "ラベル用のフォントが見つかりませんでした. --label-font でフォントファイルを指定してください (探索したパス: {})",
+                searched
+                    .iter()
+                    .map(|p| p.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FontErr {}
+
+pub fn resolve_font(explicit: Option<&Path>) -> Result<PathBuf, FontErr> {
+    resolve_font_from(explicit, FONT_CANDIDATES, |p| p.is_file())
+}
+
+fn resolve_font_from(
+    explicit: Option<&Path>,
+    candidates: &[&str],
+    exists: impl Fn(&Path) -> bool,
+) -> Result<PathBuf, FontErr> {
+    if let Some(path) = explicit {
+        return match exists(path) {
+            true => Ok(path.to_path_buf()),
+            false => Err(FontErr::NotFound(path.to_path_buf())),
+        };
+    }
+
+    candidates
+        .iter()
+        .map(PathBuf::from)
+        .find(|p| exists(p))
+        .ok_or_else(|| FontErr::NoCandidate(candidates.iter().map(PathBuf::from).collect()))
+}
+
+#[derive(Debug, Clone)]
+pub struct LabelOverlay {
+    pub text: String,
+    pub fontfile: PathBuf,
+}
+
+impl LabelOverlay {
+    /// エンコーダーと `-preset` は実際に渡すものを書く. プリセットを指定しない場合はエンコーダー名だけにする.
+    pub fn new(config: &VideoConfig, fontfile: PathBuf) -> Self {
+        let (_, h) = config.res.to_wh();
+        let encoder = match &config.preset {
+            Some(preset) => format!("{} {}", video::codec_name(config), preset),
+            None => video::codec_name(config).to_string(),
+        };
+
+        Self {
+            text: format!(
+                "{}p / {}fps / CRF {} / {}",
+                h, config.fps, config.crf, encoder
+            ),
+            fontfile,
+        }
+    }
+
+    /// `h` は入力の高さだが, 出力の `-s` によるスケールは描画後に掛かるので
+    /// 出力上でも高さに対して同じ比率の文字サイズになる.
+    pub fn to_filter(&self) -> String {
+        format!(
+            "drawtext=fontfile={}:text={}:expansion=none:fontsize=h/24:fontcolor=white:box=1:boxcolor=black@0.5:boxborderw=8:x=h/48:y=h/48",
+            escape_filter_value(&self.fontfile.to_string_lossy()),
+            escape_filter_value(&self.text)
+        )
+    }
+}
+
+/// フィルターのオプション値としてのエスケープと, フィルターグラフとしてのエスケープの 2 段階を掛ける.
+fn escape_filter_value(value: &str) -> String {
+    let escape = |s: &str, specials: &[char]| {
+        s.chars().fold(String::new(), |mut acc, c| {
+            if specials.contains(&c) {
+                acc.push('\\');
+            }
+            acc.push(c);
+            acc
+        })
+    };
+
+    escape(
+        &escape(value, &['\\', '\'', ':']),
+        &['\\', '\'', '[', ']', ',', ';'],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::video::{VideoCodec, VideoRes};
+
+    #[test]
+    fn test_resolve_font() {
+        let candidates = ["/a.ttf", "/b.ttf", "/c.ttf"];
+        let exists = |p: &Path| p != Path::new("/a.ttf");
+
+        assert_eq!(
+            resolve_font_from(None, &candidates, exists).unwrap(),
+            PathBuf::from("/b.ttf")
+        );
+        assert_eq!(
+            resolve_font_from(Some(Path::new("/c.ttf")), &candidates, exists).unwrap(),
+            PathBuf::from("/c.ttf")
+        );
+        assert!(matches!(
+            resolve_font_from(Some(Path::new("/a.ttf")), &candidates, exists),
+            Err(FontErr::NotFound(_))
+        ));
+        assert!(matches!(
+            resolve_font_from(None, &candidates, |_| false),
+            Err(FontErr::NoCandidate(searched)) if searched.len() == 3
+        ));
+    }
+
+    #[test]
+    fn test_label_filter() {
+        let config = VideoConfig {
+            res: VideoRes::R720p,
+            fps: 30,
+            crf: 28,
+            ..Default::default()
+        };
+        let label = LabelOverlay::new(&config, PathBuf::from("C:/Windows/Fonts/arial.ttf"));
+
+        assert_eq!(label.text, "720p / 30fps / CRF 28 / libx264");
+        let filter = label.to_filter();
+        assert!(filter.starts_with(r"drawtext=fontfile=C\\:/Windows/Fonts/arial.ttf:"));
+        assert!(filter.contains(r":text=720p / 30fps / CRF 28 / libx264:"));
+
+        let config = VideoConfig {
+            codec: Some(VideoCodec::H265),
+            preset: Some("slow".to_string()),
+            ..config
+        };
+        let label = LabelOverlay::new(&config, PathBuf::from("arial.ttf"));
+        assert_eq!(label.text, "720p / 30fps / CRF 28 / libx265 slow");
+    }
+
+    #[test]
+    fn test_escape_filter_value() {
+        assert_eq!(escape_filter_value("a:b"), r"a\\:b");
+        assert_eq!(escape_filter_value("it's"), r"it\\\'s");
+        assert_eq!(escape_filter_value("a,b"), r"a\,b");
+        assert_eq!(escape_filter_value(r"C:\x"), r"C\\:\\\\x");
+    }
+}
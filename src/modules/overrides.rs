@@ -0,0 +1,349 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Deserializer};
+use std::{fs, io, path::PathBuf, time::Duration};
+
+use super::{
+    cli::{Cli, SkipIfBetterMode},
+    matrix_file, time,
+    video::{VideoCodec, VideoRes},
+};
+
+pub const OVERRIDE_EXTENSION: &str = "vvcnv.toml";
+
+/// 入力の隣に置く `<入力名>.vvcnv.toml`. キーはコマンドラインのオプション名と同じで,
+/// 指定した項目だけがその入力に対して重ねる最後の層になる.
+/// 既定値 → 設定ファイル (`--config`) → コマンドライン → 上書き設定の順に, 後で指定した値が優先される.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct InputOverride {
+    #[serde(deserialize_with = "matrix_file::deserialize_res")]
+    pub res: Option<Vec<VideoRes>>,
+    pub fps: Option<Vec<u32>>,
+    pub crf: Option<Vec<u32>>,
+    pub codec: Option<Vec<VideoCodec>>,
+    pub skip_if_better: Option<SkipIfBetterMode>,
+    pub keep_cover: Option<bool>,
+    pub keep_alpha: Option<bool>,
+    pub pix_fmt: Option<String>,
+    pub profile: Option<String>,
+    pub auto_fallback: Option<bool>,
+    #[serde(deserialize_with = "deserialize_timestamp")]
+    pub start: Option<Duration>,
+    #[serde(deserialize_with = "deserialize_timestamp")]
+    pub end: Option<Duration>,
+    pub accurate_seek: Option<bool>,
+    pub faststart: Option<bool>,
+}
+
+fn deserialize_timestamp<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    let value = String::deserialize(d)?;
+    time::parse_timestamp(&value)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+pub fn override_path(input_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}.{}", input_path, OVERRIDE_EXTENSION))
+}
+
+pub fn load_override(input_path: &str) -> Result<Option<(PathBuf, InputOverride)>> {
+    let path = override_path(input_path);
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("上書き設定の読み込みに失敗しました: {}", path.display()))
+        }
+    };
+    let value = toml::from_str::<InputOverride>(&content)
+        .map_err(anyhow::Error::from)
+        .and_then(|value| value.validate().map(|_| value))
+        .with_context(|| format!("上書き設定の形式が不正です: {}", path.display()))?;
+
+    Ok(Some((path, value)))
+}
+
+fn merge<T: Clone + PartialEq>(
+    changes: &mut Vec<String>,
+    key: &str,
+    target: &mut T,
+    value: Option<T>,
+    show: impl Fn(&T) -> String,
+) {
+    let Some(value) = value else {
+        return;
+    };
+    if *target != value {
+        changes.push(format!("{}: {} → {}", key, show(target), show(&value)));
+        *target = value;
+    }
+}
+
+/// `--res` などの組み合わせの項目. コマンドラインの値は設定ファイルの値より優先されるので, そこに書き込む.
+/// `current` は設定ファイルを含めて実際に使われる値. `VideoRes` は比べられないので表示で比べる.
+fn merge_list<T: Clone>(
+    changes: &mut Vec<String>,
+    key: &str,
+    target: &mut Vec<T>,
+    current: Vec<T>,
+    value: &Option<Vec<T>>,
+    show: impl Fn(&T) -> String,
+) {
+    let Some(value) = value else {
+        return;
+    };
+    let show_all = |values: &[T]| match values.is_empty() {
+        true => "既定値".to_string(),
+        false => values.iter().map(&show).collect::<Vec<_>>().join(","),
+    };
+    let (before, after) = (show_all(&current), show_all(value));
+    if before != after {
+        changes.push(format!("{}: {} → {}", key, before, after));
+    }
+    *target = value.clone();
+}
+
+fn show_option<T>(value: &Option<T>, show: impl Fn(&T) -> String) -> String {
+    value.as_ref().map_or("なし".to_string(), show)
+}
+
+impl InputOverride {
+    fn validate(&self) -> Result<()> {
+        let empty = [
+            ("res", self.res.as_ref().map(Vec::len)),
+            ("fps", self.fps.as_ref().map(Vec::len)),
+            ("crf", self.crf.as_ref().map(Vec::len)),
+            ("codec", self.codec.as_ref().map(Vec::len)),
+        ]
+        .into_iter()
+        .find(|(_, len)| *len == Some(0));
+        if let Some((key, _)) = empty {
+            bail!("{} が空です. 使わない場合は項目ごと省いてください", key);
+        }
+        if self.fps.iter().flatten().any(|fps| *fps == 0) {
+            bail!("fps には 1 以上を指定してください");
+        }
+
+        Ok(())
+    }
+
+    /// 上書きした `Cli` と, 実際に値が変わった項目の一覧を返す.
+    pub fn apply(&self, cli: &Cli) -> (Cli, Vec<String>) {
+        let mut cli = cli.clone();
+        let mut changes = Vec::new();
+        let show_bool = |v: &bool| v.to_string();
+        let show_str = |v: &Option<String>| show_option(v, String::clone);
+        let show_time = |v: &Option<Duration>| show_option(v, |d| time::format_timestamp(*d));
+
+        // 組み合わせの項目は, 設定ファイルの値を含めて実際に使われる値と比べる
+        let (res, fps, crf, codec) = (
+            cli.res_list(),
+            cli.fps_list(),
+            cli.crf_list(),
+            cli.codec_list(),
+        );
+        merge_list(
+            &mut changes,
+            "res",
+            &mut cli.res,
+            res,
+            &self.res,
+            VideoRes::to_file_name,
+        );
+        merge_list(
+            &mut changes,
+            "fps",
+            &mut cli.fps,
+            fps,
+            &self.fps,
+            ToString::to_string,
+        );
+        merge_list(
+            &mut changes,
+            "crf",
+            &mut cli.crf,
+            crf,
+            &self.crf,
+            ToString::to_string,
+        );
+        merge_list(
+            &mut changes,
+            "codec",
+            &mut cli.codec,
+            codec,
+            &self.codec,
+            |c| c.name().to_string(),
+        );
+        merge(
+            &mut changes,
+            "skip-if-better",
+            &mut cli.skip_if_better,
+            self.skip_if_better.map(Some),
+            |v| show_option(v, |m| format!("{:?}", m).to_lowercase()),
+        );
+        merge(
+            &mut changes,
+            "keep-cover",
+            &mut cli.keep_cover,
+            self.keep_cover,
+            show_bool,
+        );
+        merge(
+            &mut changes,
+            "keep-alpha",
+            &mut cli.keep_alpha,
+            self.keep_alpha,
+            show_bool,
+        );
+        merge(
+            &mut changes,
+            "pix-fmt",
+            &mut cli.pix_fmt,
+            self.pix_fmt.clone().map(Some),
+            show_str,
+        );
+        merge(
+            &mut changes,
+            "profile",
+            &mut cli.profile,
+            self.profile.clone().map(Some),
+            show_str,
+        );
+        merge(
+            &mut changes,
+            "auto-fallback",
+            &mut cli.auto_fallback,
+            self.auto_fallback,
+            show_bool,
+        );
+        merge(
+            &mut changes,
+            "start",
+            &mut cli.start,
+            self.start.map(Some),
+            show_time,
+        );
+        merge(
+            &mut changes,
+            "end",
+            &mut cli.end,
+            self.end.map(Some),
+            show_time,
+        );
+        merge(
+            &mut changes,
+            "accurate-seek",
+            &mut cli.accurate_seek,
+            self.accurate_seek,
+            show_bool,
+        );
+        merge(
+            &mut changes,
+            "faststart",
+            &mut cli.faststart,
+            self.faststart,
+            show_bool,
+        );
+
+        (cli, changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn test_override_path() {
+        assert_eq!(
+            override_path("assets/clip.mp4"),
+            PathBuf::from("assets/clip.mp4.vvcnv.toml")
+        );
+    }
+
+    #[test]
+    fn test_parse_override() {
+        let value: InputOverride = toml::from_str(
+            r#"
+            pix-fmt = "yuv420p10le"
+            start = "1:30"
+            skip-if-better = "copy"
+            res = ["720p", "1280x720"]
+            codec = ["h265"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(value.pix_fmt.as_deref(), Some("yuv420p10le"));
+        assert_eq!(value.start, Some(Duration::from_secs(90)));
+        assert_eq!(value.skip_if_better, Some(SkipIfBetterMode::Copy));
+        assert_eq!(value.end, None);
+        assert_eq!(value.res.map(|res| res.len()), Some(2));
+        assert_eq!(value.codec, Some(vec![VideoCodec::H265]));
+        assert_eq!(value.crf, None);
+
+        assert!(toml::from_str::<InputOverride>("pix_fmt = \"yuv420p\"").is_err());
+        assert!(toml::from_str::<InputOverride>("start = \"1:xx\"").is_err());
+        assert!(toml::from_str::<InputOverride>("res = [\"huge\"]").is_err());
+        assert!(toml::from_str::<InputOverride>("crf = []")
+            .unwrap()
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn test_apply_override() {
+        let cli = Cli::parse_from([
+            "vvcnv",
+            "--pix-fmt",
+            "yuv420p",
+            "--keep-cover",
+            "--crf",
+            "23",
+        ]);
+        let value = InputOverride {
+            pix_fmt: Some("yuv420p10le".to_string()),
+            keep_cover: Some(true),
+            end: Some(Duration::from_secs(120)),
+            crf: Some(vec![18, 20]),
+            fps: Some(vec![30]),
+            codec: Some(vec![VideoCodec::Vp9]),
+            ..Default::default()
+        };
+
+        let (merged, changes) = value.apply(&cli);
+        assert_eq!(merged.pix_fmt.as_deref(), Some("yuv420p10le"));
+        assert!(merged.keep_cover);
+        assert_eq!(merged.end, Some(Duration::from_secs(120)));
+        assert_eq!(merged.start, None);
+        assert_eq!(merged.crf_list(), vec![18, 20]);
+        assert_eq!(merged.fps_list(), vec![30]);
+        assert_eq!(merged.codec_list(), vec![VideoCodec::Vp9]);
+        // 既定値と同じ FPS は変更として表示しない
+        assert_eq!(
+            changes,
+            vec![
+                "crf: 23 → 18,20".to_string(),
+                "codec: 既定値 → vp9".to_string(),
+                "pix-fmt: yuv420p → yuv420p10le".to_string(),
+                "end: なし → 00:02:00.000".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply_override_over_matrix_file() {
+        let mut cli = Cli::parse_from(["vvcnv"]);
+        cli.matrix_file.res = Some(vec![VideoRes::R720p]);
+        let value = InputOverride {
+            res: Some(vec![VideoRes::R480p]),
+            ..Default::default()
+        };
+
+        let (merged, changes) = value.apply(&cli);
+        assert_eq!(merged.res_list().len(), 1);
+        assert_eq!(merged.res_list()[0].to_file_name(), "854x480");
+        assert_eq!(changes, vec!["res: 1280x720 → 854x480".to_string()]);
+    }
+}
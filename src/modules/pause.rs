@@ -0,0 +1,272 @@
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::{schedule::Clock, stall, video::CancelToken};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Default)]
+struct PauseState {
+    paused_at: Option<Instant>,
+    paused_total: Duration,
+    children: Vec<u32>,
+}
+
+/// 実行全体の一時停止/再開を管理する.
+/// 一時停止中は新しいタスクを開始せず, 実行中の ffmpeg には SIGSTOP を送る (再開時に SIGCONT).
+/// Windows ではプロセスを止められないため, 新しいタスクを開始しないだけで実行中のエンコードは続く.
+#[derive(Debug, Clone, Default)]
+pub struct PauseControl {
+    inner: Arc<(Mutex<PauseState>, Condvar)>,
+}
+
+pub struct ChildGuard {
+    control: PauseControl,
+    pid: u32,
+}
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        let mut state = self.control.inner.0.lock().unwrap();
+        state.children.retain(|pid| *pid != self.pid);
+    }
+}
+
+#[cfg(unix)]
+fn signal_child(pid: u32, paused: bool) {
+    let signal = match paused {
+        true => libc::SIGSTOP,
+        false => libc::SIGCONT,
+    };
+    // 0 や負の値はプロセスグループ全体への送信になるので送らない
+    let Some(pid) = libc::pid_t::try_from(pid).ok().filter(|pid| *pid > 0) else {
+        return;
+    };
+    // SAFETY: 自分で起動した子プロセスの PID にシグナルを送るだけ
+    unsafe {
+        libc::kill(pid, signal);
+    }
+}
+
+#[cfg(not(unix))]
+fn signal_child(_pid: u32, _paused: bool) {}
+
+impl PauseControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.inner.0.lock().unwrap().paused_at.is_some()
+    }
+
+    pub fn pause(&self) {
+        let mut state = self.inner.0.lock().unwrap();
+        if state.paused_at.is_some() {
+            return;
+        }
+        state.paused_at = Some(Instant::now());
+        state
+            .children
+            .iter()
+            .for_each(|pid| signal_child(*pid, true));
+    }
+
+    pub fn resume(&self) {
+        let (lock, resumed) = &*self.inner;
+        let mut state = lock.lock().unwrap();
+        let Some(paused_at) = state.paused_at.take() else {
+            return;
+        };
+        state.paused_total += paused_at.elapsed();
+        state
+            .children
+            .iter()
+            .for_each(|pid| signal_child(*pid, false));
+        resumed.notify_all();
+    }
+
+    /// 切り替え後に一時停止中であれば `true` を返す.
+    pub fn toggle(&self) -> bool {
+        match self.is_paused() {
+            true => self.resume(),
+            false => self.pause(),
+        }
+        self.is_paused()
+    }
+
+    /// 実行中の ffmpeg を登録する. 一時停止中に起動したものはすぐに止める.
+    pub fn register(&self, pid: u32) -> ChildGuard {
+        let mut state = self.inner.0.lock().unwrap();
+        state.children.push(pid);
+        if state.paused_at.is_some() {
+            signal_child(pid, true);
+        }
+
+        ChildGuard {
+            control: self.clone(),
+            pid,
+        }
+    }
+
+    /// 実行中の ffmpeg をすべて終了させる (Ctrl+C). 一時停止中のものも `SIGKILL` で終了する.
+    /// 登録は `ChildGuard` が外すので, ここでは送るだけにする.
+    pub fn kill_children(&self) {
+        let state = self.inner.0.lock().unwrap();
+        state.children.iter().for_each(|pid| stall::kill(*pid));
+    }
+
+    /// これまでに一時停止していた時間 (一時停止中であれば現在までを含む).
+    pub fn paused_total(&self) -> Duration {
+        let state = self.inner.0.lock().unwrap();
+        state.paused_total + state.paused_at.map_or(Duration::ZERO, |at| at.elapsed())
+    }
+
+    pub fn wait_resumed_blocking(&self) {
+        let (lock, resumed) = &*self.inner;
+        let state = lock.lock().unwrap();
+        let _state = resumed
+            .wait_while(state, |state| state.paused_at.is_some())
+            .unwrap();
+    }
+
+    pub async fn wait_resumed(&self) {
+        while self.is_paused() {
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// 一時停止していた時間を除いて `duration` 待つ.
+    pub async fn sleep(&self, duration: Duration) {
+        let until = self.now() + duration;
+        loop {
+            let remaining = until.saturating_duration_since(self.now());
+            if remaining.is_zero() {
+                return;
+            }
+            tokio::time::sleep(remaining.min(POLL_INTERVAL)).await;
+        }
+    }
+
+    /// [`PauseControl::sleep`] と同じく待つが, `cancel` が中断されたらすぐにやめて `false` を返す.
+    pub async fn sleep_unless_cancelled(&self, duration: Duration, cancel: &CancelToken) -> bool {
+        tokio::select! {
+            () = self.sleep(duration) => true,
+            () = cancel.cancelled() => false,
+        }
+    }
+}
+
+/// 一時停止していた時間を除いた時刻. これで測った経過時間には一時停止中の時間が含まれない.
+impl Clock for PauseControl {
+    fn now(&self) -> Instant {
+        let now = Instant::now();
+        now.checked_sub(self.paused_total()).unwrap_or(now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_pause_accounting() {
+        let control = PauseControl::new();
+        let started_at = control.now();
+        assert!(!control.is_paused());
+
+        assert!(control.toggle());
+        thread::sleep(Duration::from_millis(50));
+        assert!(control.paused_total() >= Duration::from_millis(50));
+        assert!(!control.toggle());
+
+        let paused = control.paused_total();
+        assert!(paused >= Duration::from_millis(50));
+        assert!(control.now().duration_since(started_at) < Duration::from_millis(50));
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(control.paused_total(), paused);
+    }
+
+    #[test]
+    fn test_wait_resumed() {
+        let control = PauseControl::new();
+        control.wait_resumed_blocking();
+
+        control.pause();
+        control.pause();
+        let waiter = thread::spawn({
+            let control = control.clone();
+            move || control.wait_resumed_blocking()
+        });
+        thread::sleep(Duration::from_millis(20));
+        assert!(!waiter.is_finished());
+        control.resume();
+        waiter.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sleep_unless_cancelled() {
+        let control = PauseControl::new();
+        let cancel = CancelToken::new();
+        assert!(
+            control
+                .sleep_unless_cancelled(Duration::from_millis(20), &cancel)
+                .await
+        );
+
+        // 一時停止中は待ち時間が進まない
+        control.pause();
+        let sleeping = tokio::spawn({
+            let control = control.clone();
+            async move { control.sleep(Duration::from_millis(50)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!sleeping.is_finished());
+        control.resume();
+        sleeping.await.unwrap();
+
+        // 長い待ち時間の途中で中断されたら, 待ち終わるのを待たずに戻る
+        let started_at = Instant::now();
+        let waiter = tokio::spawn({
+            let (control, cancel) = (control.clone(), cancel.clone());
+            async move {
+                control
+                    .sleep_unless_cancelled(Duration::from_secs(600), &cancel)
+                    .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        cancel.cancel();
+        assert!(!waiter.await.unwrap());
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_register_child() {
+        let control = PauseControl::new();
+        let guard = control.register(12345);
+        assert_eq!(control.inner.0.lock().unwrap().children, vec![12345]);
+        drop(guard);
+        assert!(control.inner.0.lock().unwrap().children.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_kill_children() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let control = PauseControl::new();
+        let mut child = std::process::Command::new("sleep")
+            .arg("30")
+            .spawn()
+            .unwrap();
+        let _guard = control.register(child.id());
+        // 一時停止中の子プロセスも終了させる
+        control.pause();
+        control.kill_children();
+        assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGKILL));
+    }
+}
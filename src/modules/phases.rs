@@ -0,0 +1,248 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::{
+    schedule::{Clock, SystemClock},
+    time,
+};
+
+/// タスクの中の 1 つの段階 (解析, エンコード, 結合など). 時刻は記録を始めてからの秒数.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Phase {
+    pub name: String,
+    /// 入れ子の深さ. 0 が最も外側の段階.
+    pub depth: usize,
+    pub start_secs: f64,
+    pub end_secs: f64,
+}
+
+impl Phase {
+    pub fn secs(&self) -> f64 {
+        self.end_secs - self.start_secs
+    }
+}
+
+struct State {
+    clock: Arc<dyn Clock>,
+    origin: Instant,
+    phases: Vec<Phase>,
+}
+
+/// 段階の記録先. `start` で始めた段階は, 返された `PhaseTimer` を捨てたときに終わる.
+#[derive(Clone)]
+pub struct PhaseLog {
+    state: Arc<Mutex<State>>,
+    depth: usize,
+}
+
+impl fmt::Debug for PhaseLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PhaseLog")
+            .field("depth", &self.depth)
+            .field("phases", &self.phases())
+            .finish()
+    }
+}
+
+impl Default for PhaseLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PhaseLog {
+    pub fn new() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+
+    /// 一時停止した時間を除く場合は `PauseControl` を渡す.
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        let origin = clock.now();
+        Self {
+            state: Arc::new(Mutex::new(State {
+                clock,
+                origin,
+                phases: Vec::new(),
+            })),
+            depth: 0,
+        }
+    }
+
+    pub fn start(&self, name: &str) -> PhaseTimer {
+        let started_at = self.state.lock().unwrap().clock.now();
+        PhaseTimer {
+            log: self.clone(),
+            name: name.to_string(),
+            started_at,
+        }
+    }
+
+    /// 終わった段階を, 始まった順に返す.
+    pub fn phases(&self) -> Vec<Phase> {
+        let mut phases = self.state.lock().unwrap().phases.clone();
+        phases.sort_by(|a, b| {
+            a.start_secs
+                .total_cmp(&b.start_secs)
+                .then(a.depth.cmp(&b.depth))
+        });
+        phases
+    }
+}
+
+/// 捨てたときに段階を記録する.
+pub struct PhaseTimer {
+    log: PhaseLog,
+    name: String,
+    started_at: Instant,
+}
+
+impl PhaseTimer {
+    /// この段階の中に入れ子の段階を記録する記録先.
+    pub fn nested(&self) -> PhaseLog {
+        PhaseLog {
+            state: self.log.state.clone(),
+            depth: self.log.depth + 1,
+        }
+    }
+}
+
+impl Drop for PhaseTimer {
+    fn drop(&mut self) {
+        let mut state = self.log.state.lock().unwrap();
+        let secs = |at: Instant| at.saturating_duration_since(state.origin).as_secs_f64();
+        let phase = Phase {
+            name: std::mem::take(&mut self.name),
+            depth: self.log.depth,
+            start_secs: secs(self.started_at),
+            end_secs: secs(state.clock.now()),
+        };
+        state.phases.push(phase);
+    }
+}
+
+/// 最も外側の段階の, 名前ごとの合計秒数. 最初に現れた順に並べる.
+pub fn totals<'a>(phases: impl IntoIterator<Item = &'a Phase>) -> Vec<(String, f64)> {
+    let mut totals: Vec<(String, f64)> = Vec::new();
+    for phase in phases.into_iter().filter(|p| p.depth == 0) {
+        match totals.iter_mut().find(|(name, _)| *name == phase.name) {
+            Some((_, secs)) => *secs += phase.secs(),
+            None => totals.push((phase.name.clone(), phase.secs())),
+        }
+    }
+
+    totals
+}
+
+/// `totals` の内訳を `エンコード 92% (00:08:20), 結合 5% (00:00:27)` の形にする. 合計が 0 の場合は `None`.
+pub fn breakdown(totals: &[(String, f64)]) -> Option<String> {
+    let sum = totals.iter().map(|(_, secs)| secs).sum::<f64>();
+    if sum <= 0.0 {
+        return None;
+    }
+
+    let mut sorted = totals.to_vec();
+    sorted.sort_by(|a, b| b.1.total_cmp(&a.1));
+    Some(
+        sorted
+            .iter()
+            .map(|(name, secs)| {
+                format!(
+                    "{} {:.0}% ({})",
+                    name,
+                    secs / sum * 100.0,
+                    time::format_clock(Duration::from_secs_f64(*secs))
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", "),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone)]
+    struct MockClock(Arc<Mutex<Instant>>);
+
+    impl MockClock {
+        fn advance(&self, secs: u64) {
+            *self.0.lock().unwrap() += Duration::from_secs(secs);
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[test]
+    fn test_phases_nest_and_sum() {
+        let clock = MockClock(Arc::new(Mutex::new(Instant::now())));
+        let log = PhaseLog::with_clock(Arc::new(clock.clone()));
+
+        {
+            let _probe = log.start("解析");
+            clock.advance(2);
+        }
+        {
+            let encode = log.start("エンコード");
+            let nested = encode.nested();
+            for _ in 0..2 {
+                let _chunk = nested.start("分割エンコード");
+                clock.advance(3);
+            }
+            let _concat = nested.start("結合");
+            clock.advance(1);
+        }
+        let _check = log.start("A/V 同期の確認");
+        clock.advance(1);
+        drop(_check);
+        let _unfinished = log.start("サムネイル");
+
+        let phases = log.phases();
+        let names = phases.iter().map(|p| p.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "解析",
+                "エンコード",
+                "分割エンコード",
+                "分割エンコード",
+                "結合",
+                "A/V 同期の確認"
+            ]
+        );
+
+        // 入れ子の段階は外側の段階の中に収まり, 合計は外側の長さを超えない
+        let encode = &phases[1];
+        assert_eq!((encode.start_secs, encode.end_secs), (2.0, 9.0));
+        let nested = phases.iter().filter(|p| p.depth == 1).collect::<Vec<_>>();
+        assert!(nested
+            .iter()
+            .all(|p| p.start_secs >= encode.start_secs && p.end_secs <= encode.end_secs));
+        assert_eq!(nested.iter().map(|p| p.secs()).sum::<f64>(), encode.secs());
+
+        // 外側の段階の合計は, 記録した全体の長さと一致する
+        let totals = totals(&phases);
+        assert_eq!(
+            totals,
+            [
+                ("解析".to_string(), 2.0),
+                ("エンコード".to_string(), 7.0),
+                ("A/V 同期の確認".to_string(), 1.0)
+            ]
+        );
+        assert_eq!(totals.iter().map(|(_, s)| s).sum::<f64>(), 10.0);
+        assert_eq!(
+            breakdown(&totals).unwrap(),
+            "エンコード 70% (00:00:07), 解析 20% (00:00:02), A/V 同期の確認 10% (00:00:01)"
+        );
+        assert_eq!(breakdown(&[]), None);
+    }
+}
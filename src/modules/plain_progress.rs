@@ -0,0 +1,143 @@
+use console::strip_ansi_codes;
+use indicatif::{ProgressDrawTarget, TermLike};
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// 端末でない場合に, 各タスクの進捗を 1 行で書き出す間隔.
+pub const INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default)]
+struct PlainState {
+    /// 描画中の内容. `flush` で 1 行にまとめる.
+    buffer: String,
+    latest: Option<String>,
+    emitted: Option<String>,
+}
+
+/// 進捗を端末に描画する代わりに, 最後に描画した内容を色なしの 1 行として覚えておく描画先.
+/// 書き出す頻度は呼び出し側が [`PlainLine::take`] で決める.
+#[derive(Debug, Clone, Default)]
+pub struct PlainLine {
+    state: Arc<Mutex<PlainState>>,
+}
+
+impl PlainLine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn draw_target(&self) -> ProgressDrawTarget {
+        ProgressDrawTarget::term_like(Box::new(self.clone()))
+    }
+
+    /// 前回から変わっていれば, 最後に描画した内容を返す.
+    pub fn take(&self) -> Option<String> {
+        let mut state = self.state.lock().unwrap();
+        let latest = state.latest.clone()?;
+        if state.emitted.as_ref() == Some(&latest) {
+            return None;
+        }
+        state.emitted = Some(latest.clone());
+        Some(latest)
+    }
+}
+
+impl TermLike for PlainLine {
+    fn width(&self) -> u16 {
+        u16::MAX
+    }
+
+    fn height(&self) -> u16 {
+        u16::MAX
+    }
+
+    fn move_cursor_up(&self, _: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_down(&self, _: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_right(&self, _: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_left(&self, _: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_line(&self, s: &str) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.buffer += s;
+        state.buffer.push('\n');
+        Ok(())
+    }
+
+    fn write_str(&self, s: &str) -> io::Result<()> {
+        self.state.lock().unwrap().buffer += s;
+        Ok(())
+    }
+
+    fn clear_line(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let buffer = std::mem::take(&mut state.buffer);
+        let line = strip_ansi_codes(&buffer)
+            .split(['\n', '\r'])
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+        if !line.is_empty() {
+            state.latest = Some(line);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use console::style;
+    use indicatif::{ProgressBar, ProgressStyle};
+
+    #[test]
+    fn test_take() {
+        let line = PlainLine::new();
+        let pb = ProgressBar::with_draw_target(Some(2740), line.draw_target()).with_style(
+            ProgressStyle::with_template(&format!(
+                "{}: {{percent}}% ({{pos}}/{{len}} fr) {{msg}}",
+                style("{prefix}").bold()
+            ))
+            .unwrap(),
+        );
+        assert_eq!(line.take(), None);
+        pb.set_prefix("RES: R720p, FPS: 30, CRF: 23");
+        assert_eq!(
+            line.take().as_deref(),
+            Some("RES: R720p, FPS: 30, CRF: 23: 0% (0/2740 fr)")
+        );
+
+        pb.set_position(1234);
+        assert_eq!(
+            line.take().as_deref(),
+            Some("RES: R720p, FPS: 30, CRF: 23: 45% (1234/2740 fr)")
+        );
+        // 変わっていなければ書き出さない
+        assert_eq!(line.take(), None);
+
+        pb.set_style(ProgressStyle::with_template("{prefix}: {msg}").unwrap());
+        pb.finish_with_message(format!("{}", style("✓ 完了").green()));
+        assert_eq!(
+            line.take().as_deref(),
+            Some("RES: R720p, FPS: 30, CRF: 23: ✓ 完了")
+        );
+    }
+}
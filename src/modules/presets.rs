@@ -0,0 +1,432 @@
+use anyhow::{bail, Result};
+use itertools::Itertools;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+
+use super::{
+    cli::{Cli, DEFAULT_FPS},
+    codec_params::{self, CodecParam},
+    matrix::{self, CrfOffsets, Matrix},
+    video::{FpsMode, VideoCodec, VideoConfig, VideoRes},
+};
+
+/// プリセットや設定ファイルの `[[configs]]`, `--add-config` の 1 つの設定. 指定しなかった項目は別の値で埋める.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConfigEntry {
+    #[serde(deserialize_with = "deserialize_res")]
+    pub res: Option<VideoRes>,
+    pub fps: Option<u32>,
+    pub crf: Option<u32>,
+    pub has_audio: Option<bool>,
+    pub pix_fmt: Option<String>,
+    pub profile: Option<String>,
+    pub codec: Option<VideoCodec>,
+    /// `-preset` に渡す値.
+    pub encoder_preset: Option<String>,
+    pub film_grain: Option<u32>,
+    #[serde(deserialize_with = "deserialize_codec_params")]
+    pub codec_params: Vec<CodecParam>,
+    pub fps_mode: Option<FpsMode>,
+}
+
+fn deserialize_res<'de, D: Deserializer<'de>>(d: D) -> Result<Option<VideoRes>, D::Error> {
+    matrix::parse_res(&String::deserialize(d)?)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_codec_params<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<CodecParam>, D::Error> {
+    Vec::<String>::deserialize(d)?
+        .iter()
+        .map(|param| codec_params::parse_codec_param(param))
+        .collect::<Result<_, _>>()
+        .map_err(serde::de::Error::custom)
+}
+
+impl ConfigEntry {
+    /// コマンドラインで指定した, 解像度 / FPS / CRF 以外の項目.
+    fn from_cli(cli: &Cli) -> Self {
+        Self {
+            pix_fmt: cli.pix_fmt.clone(),
+            profile: cli.profile.clone(),
+            film_grain: cli.av1_film_grain,
+            codec_params: cli.codec_param.clone(),
+            fps_mode: cli.fps_mode,
+            ..Default::default()
+        }
+    }
+
+    /// 指定しなかった項目を `other` の値で埋める.
+    fn or(&self, other: &Self) -> Self {
+        Self {
+            res: self.res.clone().or(other.res.clone()),
+            fps: self.fps.or(other.fps),
+            crf: self.crf.or(other.crf),
+            has_audio: self.has_audio.or(other.has_audio),
+            pix_fmt: self.pix_fmt.clone().or(other.pix_fmt.clone()),
+            profile: self.profile.clone().or(other.profile.clone()),
+            codec: self.codec.clone().or(other.codec.clone()),
+            encoder_preset: self.encoder_preset.clone().or(other.encoder_preset.clone()),
+            film_grain: self.film_grain.or(other.film_grain),
+            codec_params: match self.codec_params.is_empty() {
+                true => other.codec_params.clone(),
+                false => self.codec_params.clone(),
+            },
+            fps_mode: self.fps_mode.or(other.fps_mode),
+        }
+    }
+
+    /// 解像度 / FPS / CRF を除いた, 組み合わせに共通の設定.
+    fn base(&self, cli: &Cli, preset_name: Option<String>) -> VideoConfig {
+        VideoConfig {
+            has_audio: self.has_audio.unwrap_or(cli.has_audio()),
+            pix_fmt: self.pix_fmt.clone(),
+            profile: self.profile.clone(),
+            codec: self.codec.clone(),
+            preset: self.encoder_preset.clone(),
+            film_grain: self.film_grain,
+            codec_params: self.codec_params.clone(),
+            preset_name,
+            fps_mode: self.fps_mode,
+            ..Default::default()
+        }
+    }
+
+    /// 組み合わせを作らず, この設定 1 つだけにする. 指定しなかった項目はコマンドラインの値を使う.
+    /// CRF を指定しなかった場合はコーデックの既定値になる.
+    pub fn matrix(&self, cli: &Cli) -> Matrix {
+        let entry = self.or(&Self::from_cli(cli));
+        Matrix {
+            res: entry.res.iter().cloned().collect(),
+            fps: vec![entry.fps.unwrap_or(DEFAULT_FPS)],
+            crf: entry.crf.into_iter().collect(),
+            codec: Vec::new(),
+            codec_crf: Vec::new(),
+            crf_offsets: CrfOffsets::default(),
+            base: entry.base(cli, None),
+        }
+    }
+
+    /// 組み合わせを作らずに使う設定として足りているかを確かめる.
+    pub fn validate_explicit(&self) -> Result<(), String> {
+        match self.res {
+            Some(_) => Ok(()),
+            None => Err("res (解像度) を指定してください".to_string()),
+        }
+    }
+}
+
+/// `--add-config` の `720p,30,crf=28,codec=h265` の形式. 名前のない値は解像度, FPS, CRF の順に読む.
+/// 名前は設定ファイルと同じ (`audio` は `has-audio` の略).
+pub fn parse_entry(input: &str) -> Result<ConfigEntry, String> {
+    const POSITIONAL: [&str; 3] = ["res", "fps", "crf"];
+
+    let mut table = toml::Table::new();
+    let mut positional = POSITIONAL.iter();
+    for token in input.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+        let (key, value) = match token.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => (
+                *positional
+                    .next()
+                    .ok_or_else(|| format!("名前のない値が多すぎます: {}", token))?,
+                token,
+            ),
+        };
+        let key = match key {
+            "audio" => "has-audio",
+            key => key,
+        };
+        let value = match key {
+            "fps" | "crf" | "film-grain" => toml::Value::Integer(
+                value
+                    .parse()
+                    .map_err(|_| format!("{} には整数を指定してください: {}", key, value))?,
+            ),
+            "has-audio" => toml::Value::Boolean(match value {
+                "true" | "on" | "yes" => true,
+                "false" | "off" | "no" => false,
+                _ => return Err(format!("{} には true か false を指定してください", key)),
+            }),
+            _ => toml::Value::String(value.to_string()),
+        };
+        if table.insert(key.to_string(), value).is_some() {
+            return Err(format!("{} を二度指定しています", key));
+        }
+    }
+    let entry = table.try_into::<ConfigEntry>().map_err(|e| e.to_string())?;
+    entry.validate_explicit()?;
+
+    Ok(entry)
+}
+
+/// 名前の付いた設定の組. 1 つのプリセットが複数の設定に展開されることもある.
+#[derive(Debug, Clone)]
+pub struct Preset {
+    pub name: String,
+    pub entries: Vec<ConfigEntry>,
+}
+
+impl Preset {
+    fn builtin(name: &str, res: VideoRes, fps: u32, crf: u32) -> Self {
+        Self {
+            name: name.to_string(),
+            entries: vec![ConfigEntry {
+                res: Some(res),
+                fps: Some(fps),
+                crf: Some(crf),
+                ..Default::default()
+            }],
+        }
+    }
+
+    /// 設定ごとの組み合わせ. コマンドラインで指定した項目はプリセットの値より優先する.
+    pub fn matrices(&self, cli: &Cli) -> Vec<Matrix> {
+        self.entries
+            .iter()
+            .map(|entry| Matrix {
+                res: axis(&cli.res, entry.res.clone(), cli.res_list()),
+                fps: axis(&cli.fps, entry.fps, cli.fps_list()),
+                crf: axis(&cli.crf, entry.crf, cli.crf_list()),
+                codec: Vec::new(),
+                codec_crf: Vec::new(),
+                crf_offsets: CrfOffsets {
+                    per_rung: cli.crf_offset_per_rung,
+                    explicit: cli.crf_offset.clone(),
+                },
+                base: ConfigEntry::from_cli(cli)
+                    .or(entry)
+                    .base(cli, cli.name_preset().then(|| self.name.clone())),
+            })
+            .collect()
+    }
+}
+
+/// コマンドラインで指定した値, プリセットの値, それ以外の既定値の順に選ぶ.
+fn axis<T: Clone>(explicit: &[T], preset: Option<T>, fallback: Vec<T>) -> Vec<T> {
+    match (explicit.is_empty(), preset) {
+        (false, _) => explicit.to_vec(),
+        (true, Some(value)) => vec![value],
+        (true, None) => fallback,
+    }
+}
+
+/// 組み込みのプリセット.
+pub fn builtins() -> Vec<Preset> {
+    vec![
+        Preset::builtin("chat", VideoRes::R720p, 30, 28),
+        Preset::builtin("archive", VideoRes::R1080p, 60, 23),
+        Preset::builtin("preview", VideoRes::R480p, 30, 35),
+    ]
+}
+
+/// 設定ファイルの `[preset.<名前>]` (設定が 1 つ) か `[[preset.<名前>]]` (複数).
+pub fn deserialize_presets<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Preset>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Entries {
+        Many(Vec<toml::Table>),
+        One(toml::Table),
+    }
+
+    BTreeMap::<String, Entries>::deserialize(d)?
+        .into_iter()
+        .map(|(name, entries)| {
+            let tables = match entries {
+                Entries::Many(tables) => tables,
+                Entries::One(table) => vec![table],
+            };
+            let entries = tables
+                .into_iter()
+                .map(|table| table.try_into::<ConfigEntry>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| serde::de::Error::custom(format!("プリセット {}: {}", name, e)))?;
+            if entries.is_empty() {
+                return Err(serde::de::Error::custom(format!(
+                    "プリセット {} に設定がありません",
+                    name
+                )));
+            }
+            Ok(Preset { name, entries })
+        })
+        .collect()
+}
+
+/// 名前からプリセットを選ぶ. 設定ファイルのプリセットは同じ名前の組み込みのプリセットより優先する.
+pub fn resolve(names: &[String], user: &[Preset]) -> Result<Vec<Preset>> {
+    let available = user
+        .iter()
+        .cloned()
+        .chain(builtins())
+        .unique_by(|preset| preset.name.clone())
+        .collect::<Vec<_>>();
+    names
+        .iter()
+        .unique()
+        .map(|name| match available.iter().find(|p| p.name == *name) {
+            Some(preset) => Ok(preset.clone()),
+            None => bail!(
+                "不明なプリセットです: {} (使えるプリセット: {})",
+                name,
+                available
+                    .iter()
+                    .map(|p| p.name.as_str())
+                    .sorted()
+                    .join(", ")
+            ),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn user_presets(content: &str) -> Vec<Preset> {
+        #[derive(Deserialize)]
+        struct File {
+            #[serde(deserialize_with = "deserialize_presets")]
+            preset: Vec<Preset>,
+        }
+        toml::from_str::<File>(content).unwrap().preset
+    }
+
+    #[test]
+    fn test_resolve() {
+        let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        let presets = resolve(&names(&["chat", "archive", "chat"]), &[]).unwrap();
+        assert_eq!(
+            presets.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(),
+            ["chat", "archive"]
+        );
+
+        // 設定ファイルのプリセットは組み込みのものより優先する
+        let user = user_presets(
+            "[preset.chat]\nres = \"480p\"\n\n[[preset.social]]\nres = \"1080x1920\"\nhas-audio = false\n\n[[preset.social]]\nres = \"720p\"\ncodec = \"vp9\"\ncodec-params = [\"row-mt=1\"]\n",
+        );
+        let presets = resolve(&names(&["chat", "social"]), &user).unwrap();
+        assert_eq!(
+            presets[0].entries[0].res.as_ref().unwrap().to_wh(),
+            (854, 480)
+        );
+        assert_eq!(presets[1].entries.len(), 2);
+        assert_eq!(presets[1].entries[1].codec, Some(VideoCodec::Vp9));
+        assert_eq!(presets[1].entries[1].codec_params[0].key, "row-mt");
+
+        let err = resolve(&names(&["chat", "cinema"]), &user).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "不明なプリセットです: cinema (使えるプリセット: archive, chat, preview, social)"
+        );
+    }
+
+    #[test]
+    fn test_expand() {
+        let configs = |args: &[&str]| {
+            let cli = Cli::parse_from(["vvcnv", "a.mp4"].iter().chain(args));
+            resolve(&cli.preset, &[])
+                .unwrap()
+                .iter()
+                .flat_map(|p| p.matrices(&cli))
+                .flat_map(|m| m.configs().collect::<Vec<_>>())
+                .map(|c| c.to_file_name())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            configs(&["--preset", "chat", "archive"]),
+            [
+                "--res-1280x720--fps-30--crf-28",
+                "--res-1920x1080--fps-60--crf-23"
+            ]
+        );
+        // コマンドラインで指定した項目はプリセットの値より優先する
+        assert_eq!(
+            configs(&["--preset", "chat,preview", "--crf", "20,24"]),
+            [
+                "--res-1280x720--fps-30--crf-20",
+                "--res-1280x720--fps-30--crf-24",
+                "--res-854x480--fps-30--crf-20",
+                "--res-854x480--fps-30--crf-24"
+            ]
+        );
+        assert_eq!(
+            configs(&["--preset", "chat", "--name-preset"]),
+            ["--preset-chat--res-1280x720--fps-30--crf-28"]
+        );
+
+        let cli = Cli::parse_from([
+            "vvcnv",
+            "a.mp4",
+            "--preset",
+            "chat",
+            "--pix-fmt",
+            "yuv420p10le",
+        ]);
+        let user = user_presets("[preset.chat]\npix-fmt = \"yuv420p\"\nhas-audio = false\n");
+        let base = &resolve(&cli.preset, &user).unwrap()[0].matrices(&cli)[0].base;
+        assert_eq!(base.pix_fmt.as_deref(), Some("yuv420p10le"));
+        assert!(!base.has_audio);
+    }
+
+    #[test]
+    fn test_parse_entry() {
+        let entry = parse_entry("720p,30,crf=28,codec=h265,audio=off").unwrap();
+        assert_eq!(entry.res.as_ref().unwrap().to_wh(), (1280, 720));
+        assert_eq!((entry.fps, entry.crf), (Some(30), Some(28)));
+        assert_eq!(entry.codec, Some(VideoCodec::H265));
+        assert_eq!(entry.has_audio, Some(false));
+        assert_eq!(
+            parse_entry("640x480,24,35").unwrap().crf,
+            Some(35),
+            "名前のない値は解像度, FPS, CRF の順"
+        );
+
+        assert!(parse_entry("fps=30").unwrap_err().contains("res"));
+        assert!(parse_entry("720p,30,28,1")
+            .unwrap_err()
+            .contains("多すぎます"));
+        assert!(parse_entry("720p,crf=x").unwrap_err().contains("整数"));
+        assert!(parse_entry("720p,res=1080p").unwrap_err().contains("二度"));
+        assert!(parse_entry("721p").is_err());
+        assert!(parse_entry("720p,speed=fast").is_err());
+
+        // 組み合わせを作らず, 指定しなかった項目はコマンドラインの値を使う
+        let cli = Cli::parse_from([
+            "vvcnv",
+            "a.mp4",
+            "--fps",
+            "24,60",
+            "--pix-fmt",
+            "yuv420p10le",
+            "--add-config",
+            "480p,pix-fmt=yuv420p",
+            "--add-config",
+            "720p,60",
+        ]);
+        let names = cli
+            .add_config
+            .iter()
+            .flat_map(|entry| entry.matrix(&cli).configs().collect::<Vec<_>>())
+            .map(|c| (c.to_file_name(), c.pix_fmt))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                (
+                    "--res-854x480--fps-30--crf-23".to_string(),
+                    Some("yuv420p".to_string())
+                ),
+                (
+                    "--res-1280x720--fps-60--crf-23".to_string(),
+                    Some("yuv420p10le".to_string())
+                )
+            ]
+        );
+        assert!(cli.uses_matrix());
+        let cli = Cli::parse_from(["vvcnv", "a.mp4", "--add-config", "720p"]);
+        assert!(!cli.uses_matrix());
+    }
+}
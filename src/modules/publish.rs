@@ -0,0 +1,532 @@
+use anyhow::{bail, Context, Result};
+use core::fmt;
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io,
+    iter::zip,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use super::integrity;
+
+/// `rename` が一時的に失敗した場合に試す回数と, 最初の待ち時間 (1 回ごとに倍にする).
+const RETRY_ATTEMPTS: u32 = 5;
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// 移動に使うファイル操作. テストでは別のファイルシステムへの移動などを再現するために差し替える.
+pub trait FileSystem {
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        fs::copy(from, to)
+    }
+
+    fn sync(&self, path: &Path) -> io::Result<()> {
+        File::open(path)?.sync_all()
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn file_size(&self, path: &Path) -> io::Result<u64> {
+        Ok(fs::metadata(path)?.len())
+    }
+
+    /// 待てば成功しうる失敗か. Windows の共有フォルダでは, 削除待ち (delete pending) のファイルや
+    /// ほかのプロセスが開いているファイルへの操作が ERROR_ACCESS_DENIED (5) / ERROR_SHARING_VIOLATION (32) で失敗する.
+    fn is_transient(&self, e: &io::Error) -> bool {
+        cfg!(windows) && matches!(e.raw_os_error(), Some(5 | 32))
+    }
+}
+
+pub struct RealFileSystem;
+
+impl FileSystem for RealFileSystem {}
+
+fn retry<T>(files: &impl FileSystem, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut backoff = RETRY_BACKOFF;
+    for _ in 1..RETRY_ATTEMPTS {
+        match op() {
+            Err(e) if files.is_transient(&e) => {
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+    op()
+}
+
+/// ファイルをどのように移動したか.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoveStrategy {
+    Rename,
+    /// `rename` できないため, コピーしてから元のファイルを消した.
+    Copy,
+}
+
+impl fmt::Display for MoveStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MoveStrategy::Rename => write!(f, "名前の変更"),
+            MoveStrategy::Copy => write!(f, "コピー (別のファイルシステム)"),
+        }
+    }
+}
+
+fn invalid_copy(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn copy_verified(
+    files: &impl FileSystem,
+    from: &Path,
+    temp: &Path,
+    verify_hash: bool,
+) -> io::Result<()> {
+    files.copy(from, temp)?;
+    files.sync(temp)?;
+    let (expected, actual) = (files.file_size(from)?, files.file_size(temp)?);
+    if expected != actual {
+        return Err(invalid_copy(format!(
+            "コピーしたファイルの大きさが一致しません ({} / {} バイト)",
+            actual, expected
+        )));
+    }
+    if verify_hash && integrity::sha256_blocking(from)? != integrity::sha256_blocking(temp)? {
+        return Err(invalid_copy(
+            "コピーしたファイルの内容が一致しません".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// `rename` が別のファイルシステムをまたぐ (EXDEV) かサポートされない場合は, 移動先と同じディレクトリに
+/// 一時ファイルとしてコピーし, fsync して大きさ (と `verify_hash` の場合は内容) を確かめてから名前を変える.
+/// 移動先に書きかけのファイルが見えることはなく, 元のファイルは確かめた後にだけ消す.
+pub fn move_file_with(
+    files: &impl FileSystem,
+    from: &Path,
+    to: &Path,
+    verify_hash: bool,
+) -> io::Result<MoveStrategy> {
+    match retry(files, || files.rename(from, to)) {
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::CrossesDevices | io::ErrorKind::Unsupported
+            ) => {}
+        result => return result.map(|_| MoveStrategy::Rename),
+    }
+
+    let name = to.file_name().unwrap_or_default().to_string_lossy();
+    let temp = to.with_file_name(format!(".{}.vvcnv-tmp", name));
+    let copied = copy_verified(files, from, &temp, verify_hash)
+        .and_then(|_| retry(files, || files.rename(&temp, to)));
+    if let Err(e) = copied {
+        files.remove_file(&temp).ok();
+        return Err(e);
+    }
+
+    retry(files, || files.remove_file(from))?;
+    Ok(MoveStrategy::Copy)
+}
+
+/// 公開したファイルと, 公開先への移動の方法.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Published {
+    pub path: PathBuf,
+    pub strategy: MoveStrategy,
+}
+
+fn rollback(files: &impl FileSystem, moved: &[(PathBuf, PathBuf)]) {
+    for (from, to) in moved.iter().rev() {
+        move_file_with(files, to, from, false).ok();
+    }
+}
+
+/// `root` の下の `outputs` を, `root` からの相対パスを保ったまままとめて `dir` に移動し, 移動後のパスを返す.
+/// `root` の下にない出力はファイル名だけを使う.
+/// いったん `dir` の隣の隠しディレクトリ ([`staging_dir`]) に集めてから 1 ファイルずつ名前を変えて公開するので,
+/// `dir` を監視しているツールには完成したファイルだけが見える. 途中で失敗した場合は
+/// すべてのファイルを元の場所に戻し, `dir` には何も残さない.
+pub fn publish(
+    outputs: &[PathBuf],
+    root: &Path,
+    dir: &Path,
+    verify_hash: bool,
+) -> Result<Vec<Published>> {
+    publish_with(&RealFileSystem, outputs, root, dir, verify_hash)
+}
+
+/// 公開用の一時ディレクトリ. `dir` の中に作ると監視しているツールに拾われるので, 同じファイルシステムにあるはずの
+/// 隣に作り, そこからの移動を名前の変更だけで済ませる.
+fn staging_dir(dir: &Path) -> PathBuf {
+    let name = dir
+        .file_name()
+        .map_or_else(|| "publish".into(), |name| name.to_string_lossy());
+    let sibling = format!(".{}.vvcnv-publish-{}", name, std::process::id());
+    match dir.parent() {
+        Some(parent) => parent.join(sibling),
+        None => dir.join(sibling),
+    }
+}
+
+/// 公開先での `root` からの相対パス. 別の出力と重なるものや, 公開先に既にあるものは移動する前に拒否する.
+fn targets(outputs: &[PathBuf], root: &Path, dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut seen = HashSet::new();
+    let mut targets = Vec::with_capacity(outputs.len());
+    for output in outputs {
+        let relative = match output.strip_prefix(root) {
+            Ok(relative) if relative.file_name().is_some() => relative.to_path_buf(),
+            _ => match output.file_name() {
+                Some(name) => PathBuf::from(name),
+                None => bail!("ファイル名がありません: {}", output.display()),
+            },
+        };
+        if !seen.insert(relative.clone()) {
+            bail!(
+                "公開先で同じ名前になる出力があります: {}",
+                dir.join(&relative).display()
+            );
+        }
+        if dir.join(&relative).exists() {
+            bail!(
+                "公開先に同じ名前のファイルが既にあります: {}",
+                dir.join(&relative).display()
+            );
+        }
+        targets.push(relative);
+    }
+
+    Ok(targets)
+}
+
+pub fn publish_with(
+    files: &impl FileSystem,
+    outputs: &[PathBuf],
+    root: &Path,
+    dir: &Path,
+    verify_hash: bool,
+) -> Result<Vec<Published>> {
+    let targets = targets(outputs, root, dir)?;
+    fs::create_dir_all(dir)
+        .with_context(|| format!("公開先ディレクトリの作成に失敗しました: {}", dir.display()))?;
+    let staging = staging_dir(dir);
+    fs::create_dir_all(&staging).with_context(|| {
+        format!(
+            "公開用の一時ディレクトリの作成に失敗しました: {}",
+            staging.display()
+        )
+    })?;
+
+    let mut created = Vec::new();
+    let result = stage_and_commit(
+        files,
+        zip(outputs, &targets),
+        dir,
+        &staging,
+        &mut created,
+        verify_hash,
+    );
+    fs::remove_dir_all(&staging).ok();
+    if result.is_err() {
+        // 公開先に作ったディレクトリは, 空になったものだけを深い方から消す
+        for dir in created.iter().rev() {
+            fs::remove_dir(dir).ok();
+        }
+    }
+
+    result
+}
+
+/// `path` の親ディレクトリを作り, 新しく作ったディレクトリを浅い方から `created` に加える.
+fn create_parent(path: &Path, created: &mut Vec<PathBuf>) -> io::Result<()> {
+    let Some(parent) = path.parent() else {
+        return Ok(());
+    };
+    let mut missing = parent
+        .ancestors()
+        .take_while(|dir| !dir.exists())
+        .map(Path::to_path_buf)
+        .collect::<Vec<_>>();
+    fs::create_dir_all(parent)?;
+    missing.reverse();
+    created.extend(missing);
+    Ok(())
+}
+
+fn stage_and_commit<'a>(
+    files: &impl FileSystem,
+    outputs: impl Iterator<Item = (&'a PathBuf, &'a PathBuf)>,
+    dir: &Path,
+    staging: &Path,
+    created: &mut Vec<PathBuf>,
+    verify_hash: bool,
+) -> Result<Vec<Published>> {
+    let mut staged = Vec::new();
+    let mut strategies = Vec::new();
+    let mut relatives = Vec::new();
+    for (output, relative) in outputs {
+        let to = staging.join(relative);
+        let moved = create_parent(&to, &mut Vec::new())
+            .and_then(|_| move_file_with(files, output, &to, verify_hash));
+        match moved {
+            Ok(strategy) => strategies.push(strategy),
+            Err(e) => {
+                rollback(files, &staged);
+                return Err(e).with_context(|| format!("公開に失敗しました: {}", output.display()));
+            }
+        }
+        staged.push((output.clone(), to));
+        relatives.push(relative);
+    }
+
+    let mut published = Vec::with_capacity(staged.len());
+    for (i, ((from, to), relative)) in zip(&staged, relatives).enumerate() {
+        let target = dir.join(relative);
+        let renamed = create_parent(&target, created)
+            .and_then(|_| retry(files, || files.rename(to, &target)));
+        if let Err(e) = renamed {
+            rollback(files, &published);
+            rollback(files, &staged[i..]);
+            return Err(e).with_context(|| format!("公開に失敗しました: {}", from.display()));
+        }
+        published.push((from.clone(), target));
+    }
+
+    Ok(zip(published, strategies)
+        .map(|((_, path), strategy)| Published { path, strategy })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::testing::TempDir;
+    use std::{cell::Cell, ffi::OsStr};
+
+    fn temp_dir(name: &str) -> TempDir {
+        let dir = TempDir::new(name);
+        fs::create_dir_all(dir.join("out")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_publish() {
+        let dir = temp_dir("publish");
+        let outputs = ["a.mp4", "a.vvcnv.json"].map(|name| {
+            let path = dir.join("out").join(name);
+            fs::write(&path, name).unwrap();
+            path
+        });
+
+        let published = publish(&outputs, &dir.join("out"), &dir.join("ready"), false).unwrap();
+        assert_eq!(published.len(), 2);
+        assert_eq!(fs::read_to_string(&published[0].path).unwrap(), "a.mp4");
+        assert_eq!(published[0].strategy, MoveStrategy::Rename);
+        assert!(outputs.iter().all(|p| !p.exists()));
+        assert_eq!(fs::read_dir(dir.join("ready")).unwrap().count(), 2);
+        // 一時ディレクトリは公開先の外に作り, 終わったら消す
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_staging_dir() {
+        let staging = staging_dir(Path::new("/srv/ready"));
+        assert_eq!(staging.parent(), Some(Path::new("/srv")));
+        assert_eq!(
+            staging.file_name().unwrap().to_string_lossy(),
+            format!(".ready.vvcnv-publish-{}", std::process::id())
+        );
+    }
+
+    #[test]
+    fn test_publish_per_config() {
+        let dir = temp_dir("publish-per-config");
+        let outputs = ["720p", "480p"].map(|config| {
+            let path = dir.join("out").join(config).join("day1").join("clip.mp4");
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, config).unwrap();
+            path
+        });
+        let ready = dir.join("ready");
+
+        // 同じ名前の出力も, 設定ごとのディレクトリを保って公開する
+        let published = publish(&outputs, &dir.join("out"), &ready, false).unwrap();
+        assert_eq!(published[0].path, ready.join("720p/day1/clip.mp4"));
+        assert_eq!(fs::read_to_string(&published[0].path).unwrap(), "720p");
+        assert_eq!(fs::read_to_string(&published[1].path).unwrap(), "480p");
+
+        // 公開先に既にあるものと, 公開先で重なるものは何も移動せずに拒否する
+        for (config, content) in [("720p", "new"), ("1080p", "1080p")] {
+            let path = dir.join("out").join(config).join("day1").join("clip.mp4");
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, content).unwrap();
+        }
+        let e = publish(
+            &[
+                dir.join("out/1080p/day1/clip.mp4"),
+                dir.join("out/720p/day1/clip.mp4"),
+            ],
+            &dir.join("out"),
+            &ready,
+            false,
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("既にあります"));
+        assert!(dir.join("out/1080p/day1/clip.mp4").exists());
+        assert_eq!(
+            fs::read_to_string(ready.join("720p/day1/clip.mp4")).unwrap(),
+            "720p"
+        );
+        assert!(!ready.join("1080p").exists());
+
+        let other = dir.join("other").join("clip.mp4");
+        fs::create_dir_all(other.parent().unwrap()).unwrap();
+        fs::write(&other, "other").unwrap();
+        let e = publish(
+            &[
+                dir.join("out/1080p/day1/clip.mp4"),
+                other.clone(),
+                dir.join("out/720p/day1/clip.mp4"),
+            ],
+            &dir.join("other"),
+            &dir.join("ready-2"),
+            false,
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("同じ名前になる"));
+        assert!(other.exists() && dir.join("out/1080p/day1/clip.mp4").exists());
+        assert!(!dir.join("ready-2").exists());
+    }
+
+    /// `out/` と `ready/` が別のファイルシステムにあるように振る舞う.
+    #[derive(Default)]
+    struct CrossDevice {
+        /// コピーした内容を壊す (大きさは変えない).
+        corrupt: bool,
+        /// 元のファイルの削除が一時的に失敗する回数.
+        busy: Cell<u32>,
+    }
+
+    fn mount(path: &Path) -> Option<&OsStr> {
+        path.ancestors()
+            .find(|p| p.parent().is_some_and(|parent| parent.ends_with("mounts")))
+            .and_then(|p| p.file_name())
+    }
+
+    impl FileSystem for CrossDevice {
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            match mount(from) == mount(to) {
+                true => fs::rename(from, to),
+                false => Err(io::Error::from(io::ErrorKind::CrossesDevices)),
+            }
+        }
+
+        fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+            let mut content = fs::read(from)?;
+            if self.corrupt {
+                content.iter_mut().for_each(|b| *b = !*b);
+            }
+            fs::write(to, &content)?;
+            Ok(content.len() as u64)
+        }
+
+        fn remove_file(&self, path: &Path) -> io::Result<()> {
+            match self.busy.get() {
+                0 => fs::remove_file(path),
+                n => {
+                    self.busy.set(n - 1);
+                    Err(io::Error::from(io::ErrorKind::ResourceBusy))
+                }
+            }
+        }
+
+        fn is_transient(&self, e: &io::Error) -> bool {
+            e.kind() == io::ErrorKind::ResourceBusy
+        }
+    }
+
+    fn mounts(name: &str) -> (TempDir, PathBuf, PathBuf) {
+        let root = temp_dir(name);
+        let dir = root.join("mounts");
+        fs::create_dir_all(dir.join("out")).unwrap();
+        fs::write(dir.join("out").join("a.mp4"), "a.mp4").unwrap();
+        // 一時ディレクトリは公開先の隣に作るので, 公開先はマウントポイントの下にする
+        (
+            root,
+            dir.join("out").join("a.mp4"),
+            dir.join("pub").join("ready"),
+        )
+    }
+
+    #[test]
+    fn test_publish_cross_device() {
+        let (_dir, output, ready) = mounts("publish-exdev");
+        let files = CrossDevice {
+            busy: Cell::new(2),
+            ..Default::default()
+        };
+
+        let published = publish_with(
+            &files,
+            std::slice::from_ref(&output),
+            output.parent().unwrap(),
+            &ready,
+            true,
+        )
+        .unwrap();
+        assert_eq!(published[0].strategy, MoveStrategy::Copy);
+        assert_eq!(fs::read_to_string(&published[0].path).unwrap(), "a.mp4");
+        assert!(!output.exists());
+        assert_eq!(fs::read_dir(&ready).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_publish_cross_device_verify() {
+        let (_dir, output, ready) = mounts("publish-exdev-verify");
+        let files = CrossDevice {
+            corrupt: true,
+            ..Default::default()
+        };
+
+        let e = publish_with(
+            &files,
+            std::slice::from_ref(&output),
+            output.parent().unwrap(),
+            &ready,
+            true,
+        )
+        .unwrap_err();
+        assert!(format!("{:#}", e).contains("内容が一致しません"));
+        assert_eq!(fs::read_to_string(&output).unwrap(), "a.mp4");
+        assert_eq!(fs::read_dir(&ready).unwrap().count(), 0);
+
+        // 内容を確かめない場合は大きさだけを比べる
+        let to = ready.join("b.mp4");
+        assert_eq!(
+            move_file_with(&files, &output, &to, false).unwrap(),
+            MoveStrategy::Copy
+        );
+    }
+
+    #[test]
+    fn test_publish_rollback() {
+        let dir = temp_dir("publish-rollback");
+        let existing = dir.join("out").join("a.mp4");
+        fs::write(&existing, "a").unwrap();
+        let outputs = [existing.clone(), dir.join("out").join("missing.mp4")];
+
+        assert!(publish(&outputs, &dir.join("out"), &dir.join("ready"), false).is_err());
+        assert_eq!(fs::read_to_string(&existing).unwrap(), "a");
+        assert_eq!(fs::read_dir(dir.join("ready")).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,396 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+
+use super::{
+    cli::QualityMetric,
+    ffmpeg, file, sandbox,
+    video::{self, VideoConfig},
+};
+
+/// 画質を計測するサンプルの CRF. 低い, 中くらい, 高い CRF の 3 点から関係を求める.
+pub const LADDER: [u32; 3] = [20, 28, 36];
+/// 画質を計測するサンプルの長さ.
+pub const SAMPLE_LENGTH: Duration = Duration::from_secs(4);
+
+/// 1 つのサンプルの CRF と画質 (0〜100).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CurvePoint {
+    pub crf: u32,
+    pub score: f64,
+}
+
+/// 画質と CRF の関係を表す直線 `score = intercept + slope * crf`. 最小二乗法で求める.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QualityCurve {
+    pub intercept: f64,
+    pub slope: f64,
+}
+
+impl QualityCurve {
+    /// 異なる CRF の 2 点以上から求める. CRF を上げても画質が下がらない場合は CRF を選べないので `None`.
+    pub fn fit(points: &[CurvePoint]) -> Option<Self> {
+        let n = points.len() as f64;
+        let mean_x = points.iter().map(|p| p.crf as f64).sum::<f64>() / n;
+        let mean_y = points.iter().map(|p| p.score).sum::<f64>() / n;
+        let sxx = points
+            .iter()
+            .map(|p| (p.crf as f64 - mean_x).powi(2))
+            .sum::<f64>();
+        let sxy = points
+            .iter()
+            .map(|p| (p.crf as f64 - mean_x) * (p.score - mean_y))
+            .sum::<f64>();
+        if points.len() < 2 || sxx == 0.0 {
+            return None;
+        }
+
+        let slope = sxy / sxx;
+        (slope < 0.0 && slope.is_finite()).then_some(Self {
+            intercept: mean_y - slope * mean_x,
+            slope,
+        })
+    }
+
+    pub fn score(&self, crf: u32) -> f64 {
+        self.intercept + self.slope * crf as f64
+    }
+
+    /// 画質が `target` を下回らない最大の CRF. 0〜`max_crf` に収める.
+    pub fn crf_for(&self, target: f64, max_crf: u32) -> u32 {
+        let crf = ((target - self.intercept) / self.slope).floor();
+        crf.clamp(0.0, max_crf as f64) as u32
+    }
+}
+
+/// 1 つのコーデックについて計測した結果. 設定ファイル (*.vvcnv.json) と実行の記録に残す.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QualityCalibration {
+    /// エンコーダーの名前 (`video::codec_name`).
+    pub codec: String,
+    pub metric: QualityMetric,
+    pub points: Vec<CurvePoint>,
+    /// `--quality` で指定した画質と, そこから選んだ CRF.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crf: Option<u32>,
+}
+
+impl QualityCalibration {
+    pub fn curve(&self) -> Option<QualityCurve> {
+        QualityCurve::fit(&self.points)
+    }
+
+    /// `target` の画質になる CRF を選ぶ. 計測した点から関係を求められない場合はエラー.
+    pub fn choose(self, target: f64, max_crf: u32) -> Result<Self> {
+        let Some(curve) = self.curve() else {
+            bail!(
+                "{} の画質が CRF によって下がらないため, CRF を選べません ({})",
+                self.codec,
+                self.points_text()
+            );
+        };
+        Ok(Self {
+            target: Some(target),
+            crf: Some(curve.crf_for(target, max_crf)),
+            ..self
+        })
+    }
+
+    /// `CRF 20 = 98.1, CRF 28 = 95.3` の形式.
+    pub fn points_text(&self) -> String {
+        self.points
+            .iter()
+            .map(|p| format!("CRF {} = {:.1}", p.crf, p.score))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// ffmpeg の `ssim` フィルターの出力から, 全体の SSIM を 100 倍して返す.
+pub fn parse_ssim(stderr: &str) -> Option<f64> {
+    let line = stderr.lines().rev().find(|l| l.contains("SSIM "))?;
+    let value = line.split("All:").nth(1)?.split_whitespace().next()?;
+    value.parse::<f64>().ok().map(|ssim| ssim * 100.0)
+}
+
+/// ffmpeg の `libvmaf` フィルターの出力から, VMAF の平均を返す.
+pub fn parse_vmaf(stderr: &str) -> Option<f64> {
+    let line = stderr.lines().rev().find(|l| l.contains("VMAF score"))?;
+    line.rsplit(':').next()?.trim().parse().ok()
+}
+
+/// サンプル `distorted` を, 元動画の同じ範囲 `window` と比べる引数. 元動画はサンプルの大きさに縮めてから比べる.
+pub fn score_args(
+    distorted: &str,
+    source: &str,
+    window: &Range<Duration>,
+    fps: u32,
+    metric: QualityMetric,
+) -> Vec<String> {
+    let filter = match metric {
+        QualityMetric::Ssim => "ssim",
+        QualityMetric::Vmaf => "libvmaf",
+    };
+    [
+        "-hide_banner",
+        "-nostats",
+        "-i",
+        distorted,
+        "-ss",
+        &format!("{:.3}", window.start.as_secs_f64()),
+        "-t",
+        &format!("{:.3}", (window.end - window.start).as_secs_f64()),
+        "-i",
+        source,
+        "-lavfi",
+        &format!(
+            "[1:v]fps={},setpts=PTS-STARTPTS[src];[src][0:v]scale2ref=flags=bicubic[ref][enc];[enc]setpts=PTS-STARTPTS[dist];[dist][ref]{}",
+            fps, filter
+        ),
+        "-f",
+        "null",
+        "-",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+/// サンプルの画質を計測する.
+pub fn measure(
+    distorted: &str,
+    source: &str,
+    window: &Range<Duration>,
+    fps: u32,
+    metric: QualityMetric,
+) -> Result<f64> {
+    let mut command = Command::new(ffmpeg::ffmpeg_path());
+    command.args(score_args(distorted, source, window, fps, metric));
+    let output = sandbox::confine(&mut command)
+        .and_then(|_| command.output())
+        .context("画質の計測の実行に失敗しました.")?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        bail!("画質の計測に失敗しました: {}", stderr.trim());
+    }
+    let score = match metric {
+        QualityMetric::Ssim => parse_ssim(&stderr),
+        QualityMetric::Vmaf => parse_vmaf(&stderr),
+    };
+
+    score.with_context(|| format!("{} の値を読み取れませんでした.", metric))
+}
+
+/// 計測に使った設定. CRF は計測するたびに変えるので 0 にしておく.
+pub fn probe_config(config: &VideoConfig) -> VideoConfig {
+    VideoConfig {
+        crf: 0,
+        has_audio: false,
+        ..config.clone()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedCalibration {
+    config: VideoConfig,
+    metric: QualityMetric,
+    points: Vec<CurvePoint>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct QualityCache {
+    input_size: u64,
+    input_modified_secs: u64,
+    entries: Vec<CachedCalibration>,
+}
+
+fn same_config(a: &VideoConfig, b: &VideoConfig) -> bool {
+    serde_json::to_value(a).ok() == serde_json::to_value(b).ok()
+}
+
+pub fn cache_path(dir: &Path, input_path: &str) -> PathBuf {
+    let (name, _) = file::get_file_name(input_path);
+    dir.join(format!(".{}.quality.json", name))
+}
+
+fn read_cache(path: &Path, input_path: &str) -> Result<QualityCache> {
+    let (input_size, input_modified_secs) = file::cache_key(input_path)?;
+    let cached = fs::read_to_string(path)
+        .ok()
+        .and_then(|json| serde_json::from_str::<QualityCache>(&json).ok())
+        .filter(|c| c.input_size == input_size && c.input_modified_secs == input_modified_secs);
+
+    Ok(cached.unwrap_or(QualityCache {
+        input_size,
+        input_modified_secs,
+        entries: Vec::new(),
+    }))
+}
+
+/// 同じ入力を同じ設定と指標で計測した結果. 入力が変わっていれば使わない.
+pub fn load_cached(
+    dir: &Path,
+    input_path: &str,
+    config: &VideoConfig,
+    metric: QualityMetric,
+) -> Option<QualityCalibration> {
+    let config = probe_config(config);
+    let cache = read_cache(&cache_path(dir, input_path), input_path).ok()?;
+    cache
+        .entries
+        .into_iter()
+        .find(|e| same_config(&e.config, &config) && e.metric == metric)
+        .map(|e| QualityCalibration {
+            codec: video::codec_name(&config).to_string(),
+            metric,
+            points: e.points,
+            target: None,
+            crf: None,
+        })
+}
+
+/// 計測した結果を `dir` に保存し, 次回から同じ入力では計測を省く.
+pub fn save_cached(
+    dir: &Path,
+    input_path: &str,
+    config: &VideoConfig,
+    calibration: &QualityCalibration,
+) -> Result<()> {
+    let path = cache_path(dir, input_path);
+    let mut cache = read_cache(&path, input_path)?;
+    let config = probe_config(config);
+    cache
+        .entries
+        .retain(|e| !(same_config(&e.config, &config) && e.metric == calibration.metric));
+    cache.entries.push(CachedCalibration {
+        config,
+        metric: calibration.metric,
+        points: calibration.points.clone(),
+    });
+    fs::create_dir_all(dir)
+        .and_then(|_| fs::write(&path, serde_json::to_string(&cache)?))
+        .with_context(|| format!("画質の計測結果の保存に失敗しました: {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::testing::TempDir;
+    use crate::modules::video::VideoRes;
+
+    fn points(scores: &[(u32, f64)]) -> Vec<CurvePoint> {
+        scores
+            .iter()
+            .map(|&(crf, score)| CurvePoint { crf, score })
+            .collect()
+    }
+
+    #[test]
+    fn test_fit_and_invert() {
+        // score = 120 - crf の直線上の点からは, その直線に戻る
+        let exact = QualityCurve::fit(&points(&[(20, 100.0), (28, 92.0), (36, 84.0)])).unwrap();
+        assert!((exact.intercept - 120.0).abs() < 1e-9);
+        assert!((exact.slope + 1.0).abs() < 1e-9);
+        assert_eq!(exact.crf_for(90.0, 51), 30);
+        assert!((exact.score(30) - 90.0).abs() < 1e-9);
+
+        // ばらつきのある点では, 選んだ CRF の画質が目標を下回らない
+        let noisy = QualityCurve::fit(&points(&[(20, 98.2), (28, 95.1), (36, 89.9)])).unwrap();
+        let crf = noisy.crf_for(93.0, 51);
+        assert!(noisy.score(crf) >= 93.0);
+        assert!(noisy.score(crf + 1) < 93.0);
+
+        // 範囲外の目標は CRF の範囲に収める
+        assert_eq!(exact.crf_for(130.0, 51), 0);
+        assert_eq!(exact.crf_for(10.0, 51), 51);
+
+        // CRF を上げても画質が下がらない, 点が足りない場合は求めない
+        assert!(QualityCurve::fit(&points(&[(20, 90.0), (28, 92.0)])).is_none());
+        assert!(QualityCurve::fit(&points(&[(20, 90.0), (20, 80.0)])).is_none());
+        assert!(QualityCurve::fit(&points(&[(20, 90.0)])).is_none());
+        assert!(QualityCurve::fit(&[]).is_none());
+
+        let calibration = QualityCalibration {
+            codec: "libx264".to_string(),
+            metric: QualityMetric::Ssim,
+            points: points(&[(20, 100.0), (28, 92.0), (36, 84.0)]),
+            target: None,
+            crf: None,
+        };
+        let chosen = calibration.clone().choose(90.0, 51).unwrap();
+        assert_eq!((chosen.target, chosen.crf), (Some(90.0), Some(30)));
+        let flat = QualityCalibration {
+            points: points(&[(20, 90.0), (28, 90.0)]),
+            ..calibration
+        };
+        assert!(format!("{:#}", flat.choose(90.0, 51).unwrap_err()).contains("CRF 20 = 90.0"));
+    }
+
+    #[test]
+    fn test_parse_scores() {
+        let ssim = "[Parsed_ssim_4 @ 0x1] SSIM Y:0.990000 (20.00) U:0.99 (21.0) V:0.99 (21.0) All:0.987654 (19.08)\n";
+        assert!((parse_ssim(ssim).unwrap() - 98.7654).abs() < 1e-9);
+        let vmaf = "[libvmaf @ 0x1] VMAF score: 93.456789\n";
+        assert!((parse_vmaf(vmaf).unwrap() - 93.456789).abs() < 1e-9);
+        assert_eq!(parse_ssim("frame=  100 fps=0.0\n"), None);
+        assert_eq!(parse_vmaf(ssim), None);
+
+        let args = score_args(
+            "out/.s.mp4",
+            "in.mp4",
+            &(Duration::from_millis(1500)..Duration::from_millis(5500)),
+            30,
+            QualityMetric::Vmaf,
+        );
+        assert_eq!(&args[4..8], ["-ss", "1.500", "-t", "4.000"]);
+        assert!(args[11].starts_with("[1:v]fps=30,") && args[11].ends_with("libvmaf"));
+    }
+
+    #[test]
+    fn test_cache() {
+        let dir = TempDir::new("quality");
+        let input = dir.join("clip.mp4");
+        fs::write(&input, "video").unwrap();
+        let input = input.to_string_lossy();
+        let config = VideoConfig {
+            res: VideoRes::R720p,
+            crf: 28,
+            ..Default::default()
+        };
+        let calibration = QualityCalibration {
+            codec: "libx264".to_string(),
+            metric: QualityMetric::Ssim,
+            points: points(&[(20, 99.0), (28, 97.0), (36, 93.0)]),
+            target: Some(95.0),
+            crf: Some(31),
+        };
+
+        assert!(load_cached(&dir, &input, &config, QualityMetric::Ssim).is_none());
+        save_cached(&dir, &input, &config, &calibration).unwrap();
+        // CRF が違っても同じ設定として使い回し, 目標と選んだ CRF は残さない
+        let other_crf = VideoConfig {
+            crf: 35,
+            ..config.clone()
+        };
+        let cached = load_cached(&dir, &input, &other_crf, QualityMetric::Ssim).unwrap();
+        assert_eq!(cached.points, calibration.points);
+        assert_eq!((cached.target, cached.crf), (None, None));
+        assert!(load_cached(&dir, &input, &config, QualityMetric::Vmaf).is_none());
+        let other_res = VideoConfig {
+            res: VideoRes::R1080p,
+            ..config.clone()
+        };
+        assert!(load_cached(&dir, &input, &other_res, QualityMetric::Ssim).is_none());
+
+        // 入力が変わった場合は計測し直す
+        fs::write(dir.join("clip.mp4"), "another video").unwrap();
+        assert!(load_cached(&dir, &input, &config, QualityMetric::Ssim).is_none());
+    }
+}
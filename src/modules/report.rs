@@ -0,0 +1,396 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use super::{
+    cli::OutputLayout, estimate::Calibration, ffmpeg::FfmpegBuild, frames::FrameCounts,
+    integrity::InputIntegrity, phases::Phase, quality::QualityCalibration, video::VideoConfig,
+};
+
+pub const SIDECAR_EXTENSION: &str = "vvcnv.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutcomeStats {
+    pub output_path: String,
+    pub output_size: u64,
+    pub elapsed_secs: f64,
+    /// 出力の音声が映像より長い秒数 (短い場合は負). 元動画よりずれが大きくなった場合にだけ記録する.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub av_drift_secs: Option<f64>,
+    #[serde(default)]
+    pub frames: FrameCounts,
+    /// 解析, エンコード, 確認などの段階ごとの時間.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub phases: Vec<Phase>,
+    /// 成功するまでに再試行した回数 (`--retries`).
+    #[serde(default)]
+    pub retries: u32,
+    /// 出力の再生時間をエンコードにかかった時間で割った, 平均の速さ (倍率).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speed: Option<f64>,
+}
+
+impl OutcomeStats {
+    pub fn new(output_path: String, output_size: u64, elapsed: Duration) -> Self {
+        Self {
+            output_path,
+            output_size,
+            elapsed_secs: elapsed.as_secs_f64(),
+            av_drift_secs: None,
+            frames: FrameCounts::default(),
+            phases: Vec::new(),
+            retries: 0,
+            speed: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sidecar {
+    pub input_path: String,
+    pub config: VideoConfig,
+    #[serde(default)]
+    pub ffmpeg_args: Vec<String>,
+    #[serde(default)]
+    pub ffmpeg_version: Option<String>,
+    #[serde(default)]
+    pub ffmpeg_enabled: Vec<String>,
+    #[serde(default)]
+    pub outcome: Option<OutcomeStats>,
+    /// `--calibrate` で計測した, この設定のコーデックの画質と CRF の関係.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality: Option<QualityCalibration>,
+    /// `config` 以外の, 出力の内容と置き場所を決めた指定. 記録していなかった頃の設定ファイルでは既定値になる.
+    #[serde(default)]
+    pub options: TaskOptions,
+}
+
+/// 出力を作ったときの, 設定 (`VideoConfig`) 以外の指定. `vvcnv rerun` で同じ名前と内容の出力を作り直すのに使う.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TaskOptions {
+    /// `--start` (秒).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_secs: Option<f64>,
+    /// `--end` (秒).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_secs: Option<f64>,
+    pub accurate_seek: bool,
+    /// `--sample` の長さ (秒).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample_secs: Option<f64>,
+    pub sample_audio: bool,
+    pub keep_cover: bool,
+    pub faststart: bool,
+    pub shortest: bool,
+    pub keep_sar: bool,
+    /// 設定を映像に描画した (`--label-overlay`).
+    pub label: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label_font: Option<PathBuf>,
+    pub layout: OutputLayout,
+    /// 出力先 (`--out-dir` かワークスペースの out/) の絶対パス.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub out_root: Option<PathBuf>,
+    /// 入力にディレクトリを指定した場合の, 出力先の中のサブディレクトリ.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub out_subdir: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name_template: Option<String>,
+    pub name_preset: bool,
+    pub ascii_names: bool,
+    /// 元動画に音声がないため音声を外した ([`VideoConfig::audio_missing`]). 出力の名前は音声を含める設定のままになる.
+    pub audio_missing: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskStatus {
+    Encoded,
+    Downgraded(String),
+    Copied,
+    /// 以前の実行で同じ入力と設定から作った出力を使った.
+    Reused,
+    /// 出力先にすでに完成した出力があったため, エンコードしなかった (`--skip-existing`).
+    Existing,
+    Skipped,
+    OutOfTime,
+    /// 出力が `--abort-if-larger` の上限を超える見込みになったため, 途中で中止した.
+    TooLarge,
+    /// 出力先にファイルがすでにあったため, 上書きせずに中止した (`--overwrite` なし).
+    Refused,
+    /// Ctrl+C で中断したため, 完了しなかった. 途中までの出力は削除する.
+    Interrupted,
+    Failed,
+}
+
+impl TaskStatus {
+    pub fn is_success(&self) -> bool {
+        matches!(
+            self,
+            TaskStatus::Encoded
+                | TaskStatus::Downgraded(_)
+                | TaskStatus::Copied
+                | TaskStatus::Reused
+                | TaskStatus::Existing
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskReport {
+    pub input_path: String,
+    pub config: VideoConfig,
+    pub status: TaskStatus,
+    #[serde(default)]
+    pub outcome: Option<OutcomeStats>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Totals {
+    pub tasks: usize,
+    pub succeeded: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub output_size: u64,
+    pub elapsed_secs: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionReport {
+    pub inputs: Vec<String>,
+    pub tasks: Vec<TaskReport>,
+    #[serde(default)]
+    pub totals: Totals,
+    #[serde(default)]
+    pub predicted_secs: Option<f64>,
+    #[serde(default)]
+    pub calibration: Option<Calibration>,
+    #[serde(default)]
+    pub ffmpeg: Option<FfmpegBuild>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub integrity: Vec<InputIntegrity>,
+    /// `--calibrate` で計測した画質と CRF の関係.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub quality: Vec<QualityCalibration>,
+}
+
+impl SessionReport {
+    pub fn new(inputs: Vec<String>, tasks: Vec<TaskReport>, elapsed: Duration) -> Self {
+        let count = |f: fn(&TaskStatus) -> bool| tasks.iter().filter(|t| f(&t.status)).count();
+        let totals = Totals {
+            tasks: tasks.len(),
+            succeeded: count(TaskStatus::is_success),
+            skipped: count(|s| {
+                matches!(
+                    s,
+                    TaskStatus::Skipped
+                        | TaskStatus::OutOfTime
+                        | TaskStatus::TooLarge
+                        | TaskStatus::Interrupted
+                )
+            }),
+            failed: count(|s| matches!(s, TaskStatus::Failed | TaskStatus::Refused)),
+            output_size: tasks
+                .iter()
+                .filter_map(|t| t.outcome.as_ref())
+                .map(|o| o.output_size)
+                .sum(),
+            elapsed_secs: elapsed.as_secs_f64(),
+        };
+
+        Self {
+            inputs,
+            tasks,
+            totals,
+            predicted_secs: None,
+            calibration: None,
+            ffmpeg: None,
+            integrity: Vec::new(),
+            quality: Vec::new(),
+        }
+    }
+}
+
+pub fn sidecar_path(output_path: &str) -> PathBuf {
+    Path::new(output_path).with_extension(SIDECAR_EXTENSION)
+}
+
+pub fn write_sidecar(output_path: &str, sidecar: &Sidecar) -> Result<PathBuf> {
+    let path = sidecar_path(output_path);
+    let json =
+        serde_json::to_string_pretty(sidecar).context("設定ファイルの生成に失敗しました.")?;
+    fs::write(&path, json)
+        .with_context(|| format!("設定ファイルの書き込みに失敗しました: {}", path.display()))?;
+
+    Ok(path)
+}
+
+pub fn read_sidecar(path: &Path) -> Result<Sidecar> {
+    let json = fs::read_to_string(path)
+        .with_context(|| format!("設定ファイルの読み込みに失敗しました: {}", path.display()))?;
+
+    serde_json::from_str(&json)
+        .with_context(|| format!("設定ファイルの形式が不正です: {}", path.display()))
+}
+
+/// 1 回の実行の結果を読み込む.
+pub fn read_report(path: &Path) -> Result<SessionReport> {
+    let json = fs::read_to_string(path)
+        .with_context(|| format!("レポートの読み込みに失敗しました: {}", path.display()))?;
+
+    serde_json::from_str(&json)
+        .with_context(|| format!("レポートの形式が不正です: {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::{cli::QualityMetric, quality::CurvePoint, video::VideoRes};
+
+    #[test]
+    fn test_sidecar_path() {
+        assert_eq!(
+            sidecar_path("out/clip--res-1280x720--fps-30--crf-28.mp4"),
+            PathBuf::from("out/clip--res-1280x720--fps-30--crf-28.vvcnv.json")
+        );
+    }
+
+    #[test]
+    fn test_sidecar_round_trip() {
+        let sidecar = Sidecar {
+            input_path: "assets/clip.mp4".to_string(),
+            config: VideoConfig {
+                res: VideoRes::R720p,
+                crf: 28,
+                ..Default::default()
+            },
+            ffmpeg_args: vec!["-i".to_string(), "assets/clip.mp4".to_string()],
+            ffmpeg_version: Some("7.1".to_string()),
+            ffmpeg_enabled: vec!["libx264".to_string()],
+            outcome: Some(OutcomeStats {
+                phases: vec![Phase {
+                    name: "エンコード".to_string(),
+                    depth: 0,
+                    start_secs: 0.0,
+                    end_secs: 1.5,
+                }],
+                retries: 2,
+                ..OutcomeStats::new(
+                    "out/clip.mp4".to_string(),
+                    1024,
+                    Duration::from_millis(1500),
+                )
+            }),
+            quality: Some(QualityCalibration {
+                codec: "libx264".to_string(),
+                metric: QualityMetric::Ssim,
+                points: vec![CurvePoint {
+                    crf: 20,
+                    score: 98.5,
+                }],
+                target: Some(95.0),
+                crf: Some(30),
+            }),
+            options: TaskOptions {
+                start_secs: Some(90.25),
+                sample_secs: Some(10.0),
+                faststart: true,
+                layout: OutputLayout::PerConfig,
+                name_template: Some("{stem}_{height}p.{ext}".to_string()),
+                audio_missing: true,
+                ..Default::default()
+            },
+        };
+
+        let json = serde_json::to_string(&sidecar).unwrap();
+        let restored: Sidecar = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.input_path, sidecar.input_path);
+        assert_eq!(
+            restored.config.to_file_name(),
+            sidecar.config.to_file_name()
+        );
+        assert_eq!(restored.ffmpeg_args, sidecar.ffmpeg_args);
+        assert_eq!(restored.ffmpeg_enabled, sidecar.ffmpeg_enabled);
+        let outcome = restored.outcome.unwrap();
+        assert_eq!(outcome.elapsed_secs, 1.5);
+        assert_eq!(outcome.retries, 2);
+        assert_eq!(outcome.phases, sidecar.outcome.unwrap().phases);
+        assert_eq!(restored.quality, sidecar.quality);
+        assert_eq!(restored.options, sidecar.options);
+
+        // 設定以外の指定を記録していなかった頃の設定ファイルも読める
+        let legacy: Sidecar =
+            serde_json::from_str(r#"{"input_path": "assets/clip.mp4", "config": {}}"#).unwrap();
+        assert_eq!(legacy.options, TaskOptions::default());
+    }
+
+    #[test]
+    fn test_session_totals() {
+        let task = |status, output_size: Option<u64>| TaskReport {
+            input_path: "assets/clip.mp4".to_string(),
+            config: VideoConfig::default(),
+            status,
+            outcome: output_size
+                .map(|size| OutcomeStats::new("out/clip.mp4".to_string(), size, Duration::ZERO)),
+            error: None,
+        };
+        let report = SessionReport::new(
+            vec!["assets/clip.mp4".to_string()],
+            vec![
+                task(TaskStatus::Encoded, Some(100)),
+                task(
+                    TaskStatus::Downgraded("yuv420p10le → yuv420p".to_string()),
+                    Some(50),
+                ),
+                task(TaskStatus::Reused, Some(100)),
+                task(TaskStatus::Existing, Some(10)),
+                task(TaskStatus::Skipped, None),
+                task(TaskStatus::OutOfTime, None),
+                task(TaskStatus::TooLarge, None),
+                task(TaskStatus::Failed, None),
+                task(TaskStatus::Refused, None),
+                task(TaskStatus::Interrupted, None),
+            ],
+            Duration::from_secs(3),
+        );
+
+        assert_eq!(report.totals.tasks, 10);
+        assert_eq!(report.totals.succeeded, 4);
+        assert_eq!(report.totals.skipped, 4);
+        assert_eq!(report.totals.failed, 2);
+        assert_eq!(report.totals.output_size, 260);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["tasks"][0]["status"], "encoded");
+        assert_eq!(
+            json["tasks"][1]["status"]["downgraded"],
+            "yuv420p10le → yuv420p"
+        );
+        assert_eq!(json["tasks"][2]["status"], "reused");
+        assert_eq!(json["tasks"][3]["status"], "existing");
+    }
+
+    #[test]
+    fn test_sidecar_tolerates_unknown_fields() {
+        let json = r#"{
+            "input_path": "assets/clip.mp4",
+            "config": { "res": "1280x720", "fps": 30, "crf": 28, "codec": "av1" },
+            "schema": 2,
+            "outcome": { "output_path": "out/clip.mp4", "output_size": 1, "elapsed_secs": 0.5, "vmaf": 95.0 }
+        }"#;
+        let sidecar: Sidecar = serde_json::from_str(json).unwrap();
+        assert_eq!(sidecar.config.crf, 28);
+        assert!(sidecar.ffmpeg_args.is_empty());
+        assert!(sidecar.ffmpeg_version.is_none());
+        assert!(sidecar.ffmpeg_enabled.is_empty());
+        assert_eq!(sidecar.outcome.unwrap().retries, 0);
+    }
+}
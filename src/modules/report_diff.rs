@@ -0,0 +1,257 @@
+use serde_json::{Map, Value};
+use std::collections::BTreeSet;
+
+use super::{
+    ab::{self, Axis, Stats},
+    report::{SessionReport, TaskReport},
+};
+
+/// 設定を JSON にした項目と値. 省略された項目は `null` として扱う.
+fn config_fields(task: &TaskReport) -> Map<String, Value> {
+    match serde_json::to_value(&task.config) {
+        Ok(Value::Object(fields)) => fields,
+        _ => Map::new(),
+    }
+}
+
+/// 2 つの実行で値が 1 つも重ならない設定の項目. 既定値を変えて実行し直した場合の, 変えた項目にあたる.
+pub fn changed_fields(old: &SessionReport, new: &SessionReport) -> Vec<String> {
+    let values = |report: &SessionReport, key: &str| {
+        report
+            .tasks
+            .iter()
+            .map(|t| config_fields(t).get(key).cloned().unwrap_or(Value::Null))
+            .map(|v| v.to_string())
+            .collect::<BTreeSet<_>>()
+    };
+    let keys = old
+        .tasks
+        .iter()
+        .chain(&new.tasks)
+        .flat_map(|t| config_fields(t).into_iter().map(|(key, _)| key))
+        .collect::<BTreeSet<_>>();
+
+    keys.into_iter()
+        .filter(|key| values(old, key).is_disjoint(&values(new, key)))
+        .collect()
+}
+
+/// 入力と同じ設定 (`ignored` の項目を除く) で実行された, 2 つの実行のタスク.
+#[derive(Debug, Clone, Copy)]
+pub struct TaskDiff<'a> {
+    pub old: &'a TaskReport,
+    pub new: &'a TaskReport,
+}
+
+impl TaskDiff<'_> {
+    /// 出力サイズの差 (新 - 旧). どちらかに出力がない場合は `None`.
+    pub fn size_delta(&self) -> Option<i64> {
+        let (old, new) = (self.old.outcome.as_ref()?, self.new.outcome.as_ref()?);
+        Some(new.output_size as i64 - old.output_size as i64)
+    }
+
+    /// 所要時間の差 (新 - 旧, 秒).
+    pub fn elapsed_delta(&self) -> Option<f64> {
+        let (old, new) = (self.old.outcome.as_ref()?, self.new.outcome.as_ref()?);
+        Some(new.elapsed_secs - old.elapsed_secs)
+    }
+
+    /// 破棄したフレーム数の差 (新 - 旧).
+    pub fn drop_delta(&self) -> Option<i64> {
+        let (old, new) = (self.old.outcome.as_ref()?, self.new.outcome.as_ref()?);
+        Some(new.frames.drop as i64 - old.frames.drop as i64)
+    }
+
+    /// 新しい実行の, 古い実行に対する出力サイズと所要時間の結果. 小さい方を良いとする.
+    pub fn axes(&self) -> Option<[Axis; 2]> {
+        let (old, new) = (self.old.outcome.as_ref()?, self.new.outcome.as_ref()?);
+        let time = |secs| Stats::from_samples(&[secs]).unwrap();
+        Some([
+            ab::compare_sizes(new.output_size, old.output_size),
+            ab::compare_times(&time(new.elapsed_secs), &time(old.elapsed_secs)),
+        ])
+    }
+
+    /// 成功していたタスクが失敗した, またはいずれかの項目が悪くなった.
+    pub fn is_regression(&self) -> bool {
+        let failed = self.old.status.is_success() && !self.new.status.is_success();
+        let worse = self.drop_delta().is_some_and(|d| d > 0)
+            || self.axes().is_some_and(|axes| axes.contains(&Axis::Worse));
+        failed || worse
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReportDiff<'a> {
+    /// 対応付けで無視した設定の項目.
+    pub ignored: Vec<String>,
+    pub matched: Vec<TaskDiff<'a>>,
+    pub only_old: Vec<&'a TaskReport>,
+    pub only_new: Vec<&'a TaskReport>,
+}
+
+/// 入力と, `ignored` の項目を除いた設定が同じタスクを対応付ける. 出力のパスは名前が変わっていてもよいので比べない.
+/// 同じ条件のタスクが複数ある場合は, 現れた順に対応付ける.
+pub fn diff<'a>(
+    old: &'a SessionReport,
+    new: &'a SessionReport,
+    ignored: Vec<String>,
+) -> ReportDiff<'a> {
+    let key = |task: &TaskReport| {
+        let mut fields = config_fields(task);
+        for field in &ignored {
+            fields.remove(field);
+        }
+        (task.input_path.clone(), fields)
+    };
+
+    let mut rest = new
+        .tasks
+        .iter()
+        .map(|t| Some((key(t), t)))
+        .collect::<Vec<_>>();
+    let mut matched = Vec::new();
+    let mut only_old = Vec::new();
+    for task in &old.tasks {
+        let old_key = key(task);
+        let found = rest
+            .iter_mut()
+            .find(|slot| matches!(slot, Some((k, _)) if *k == old_key))
+            .and_then(Option::take);
+        match found {
+            Some((_, new)) => matched.push(TaskDiff { old: task, new }),
+            None => only_old.push(task),
+        }
+    }
+
+    ReportDiff {
+        ignored,
+        matched,
+        only_old,
+        only_new: rest.into_iter().flatten().map(|(_, t)| t).collect(),
+    }
+}
+
+/// `old` から `new` への変化の割合 (%). `old` が 0 の場合は `None`.
+pub fn percent(old: f64, new: f64) -> Option<f64> {
+    (old != 0.0).then(|| (new - old) / old * 100.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::{
+        report::{OutcomeStats, TaskStatus},
+        video::{VideoConfig, VideoRes},
+    };
+    use std::time::Duration;
+
+    fn task(
+        res: VideoRes,
+        preset: &str,
+        status: TaskStatus,
+        out: Option<(&str, u64, f64)>,
+    ) -> TaskReport {
+        TaskReport {
+            input_path: "assets/clip.mp4".to_string(),
+            config: VideoConfig {
+                res,
+                crf: 28,
+                preset: Some(preset.to_string()),
+                ..Default::default()
+            },
+            status,
+            outcome: out.map(|(path, size, secs)| {
+                OutcomeStats::new(path.to_string(), size, Duration::from_secs_f64(secs))
+            }),
+            error: None,
+        }
+    }
+
+    fn report(tasks: Vec<TaskReport>) -> SessionReport {
+        SessionReport::new(
+            vec!["assets/clip.mp4".to_string()],
+            tasks,
+            Duration::from_secs(10),
+        )
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = report(vec![
+            task(
+                VideoRes::R720p,
+                "medium",
+                TaskStatus::Encoded,
+                Some(("out/a-720.mp4", 1000, 10.0)),
+            ),
+            task(
+                VideoRes::R1080p,
+                "medium",
+                TaskStatus::Encoded,
+                Some(("out/a-1080.mp4", 2000, 20.0)),
+            ),
+            task(
+                VideoRes::R480p,
+                "medium",
+                TaskStatus::Encoded,
+                Some(("out/a-480.mp4", 500, 5.0)),
+            ),
+        ]);
+        // 出力の名前が変わり, 1080p は失敗し, 360p が増えた
+        let new = report(vec![
+            task(VideoRes::R1080p, "slow", TaskStatus::Failed, None),
+            task(
+                VideoRes::R720p,
+                "slow",
+                TaskStatus::Encoded,
+                Some(("out/b/720.mp4", 900, 15.0)),
+            ),
+            task(
+                VideoRes::R360p,
+                "slow",
+                TaskStatus::Encoded,
+                Some(("out/b/360.mp4", 300, 3.0)),
+            ),
+        ]);
+
+        let changed = changed_fields(&old, &new);
+        assert_eq!(changed, ["preset"]);
+        let diff = diff(&old, &new, changed);
+        assert_eq!(diff.matched.len(), 2);
+
+        let r720 = diff.matched[0];
+        assert_eq!(
+            r720.new.outcome.as_ref().unwrap().output_path,
+            "out/b/720.mp4"
+        );
+        assert_eq!(r720.size_delta(), Some(-100));
+        assert_eq!(r720.elapsed_delta(), Some(5.0));
+        assert_eq!(r720.axes(), Some([Axis::Better, Axis::Worse]));
+        assert!(r720.is_regression());
+
+        let r1080 = diff.matched[1];
+        assert_eq!(r1080.size_delta(), None);
+        assert_eq!(r1080.axes(), None);
+        assert!(r1080.is_regression());
+
+        assert_eq!(diff.only_old.len(), 1);
+        assert_eq!(diff.only_old[0].config.res.to_wh(), VideoRes::R480p.to_wh());
+        assert_eq!(diff.only_new.len(), 1);
+        assert_eq!(diff.only_new[0].config.res.to_wh(), VideoRes::R360p.to_wh());
+
+        // 無視しない場合は, 設定が変わったタスクを対応付けない
+        let strict = super::diff(&old, &new, Vec::new());
+        assert!(strict.matched.is_empty());
+        assert_eq!((strict.only_old.len(), strict.only_new.len()), (3, 3));
+
+        // 同じ実行どうしでは変わった項目も回帰もない
+        assert!(changed_fields(&old, &old).is_empty());
+        let same = super::diff(&old, &old, Vec::new());
+        assert_eq!(same.matched.len(), 3);
+        assert!(same.matched.iter().all(|t| !t.is_regression()));
+
+        assert_eq!(percent(1000.0, 900.0), Some(-10.0));
+        assert_eq!(percent(0.0, 900.0), None);
+    }
+}
@@ -0,0 +1,630 @@
+use anyhow::{Context, Result};
+use humansize::format_size;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::{self, Write},
+    iter::zip,
+    path::Path,
+    time::Duration,
+};
+
+use super::{
+    config,
+    report::{TaskReport, TaskStatus},
+    text, time,
+    video::{AudioStreamInfo, VideoRes, VideoStat, VideoStreamInfo},
+};
+
+/// 成功したタスクの `result`.
+pub const OK: &str = "ok";
+
+/// 1 つのタスクの結果.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResultRecord {
+    pub input_path: String,
+    pub res: VideoRes,
+    pub fps: u32,
+    pub crf: u32,
+    /// 指定したコーデック (`VideoCodec::name`). 出力形式の既定のエンコーダーを使った場合は `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub codec: Option<String>,
+    pub has_audio: bool,
+    pub status: TaskStatus,
+    pub output_path: String,
+    /// 出力を作らなかった場合は `None`.
+    pub output_size: Option<u64>,
+    pub source_size: u64,
+    /// エンコードにかかった時間. 一時停止していた間は含めない.
+    pub elapsed_secs: Option<f64>,
+    /// 元動画の再生時間に対するエンコードの平均の速さ (倍率).
+    #[serde(default)]
+    pub speed: Option<f64>,
+    /// 成功した場合は `"ok"`, それ以外は失敗やスキップの理由.
+    pub result: String,
+}
+
+impl ResultRecord {
+    /// `output_path` は出力を作らなかった場合にも, 作るはずだったパスを渡す.
+    pub fn new(task: &TaskReport, output_path: String, source_size: u64) -> Self {
+        let result = match (&task.error, &task.status) {
+            (Some(e), _) => e.clone(),
+            (None, status) if status.is_success() => OK.to_string(),
+            (None, status) => reason(status).to_string(),
+        };
+        Self {
+            input_path: task.input_path.clone(),
+            res: task.config.res.clone(),
+            fps: task.config.fps,
+            crf: task.config.crf,
+            codec: task.config.codec.as_ref().map(|c| c.name().to_string()),
+            has_audio: task.config.has_audio,
+            status: task.status.clone(),
+            output_path: task
+                .outcome
+                .as_ref()
+                .map_or(output_path, |o| o.output_path.clone()),
+            output_size: task.outcome.as_ref().map(|o| o.output_size),
+            source_size,
+            elapsed_secs: task.outcome.as_ref().map(|o| o.elapsed_secs),
+            speed: task.outcome.as_ref().and_then(|o| o.speed),
+            result,
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.result == OK
+    }
+}
+
+/// 出力を作らずに終わったタスクの理由.
+fn reason(status: &TaskStatus) -> &'static str {
+    match status {
+        TaskStatus::Skipped => "元動画がすでに最適なためスキップしました",
+        TaskStatus::OutOfTime => "時間制限により完了しませんでした",
+        TaskStatus::TooLarge => "元動画より大きくなるため中止しました",
+        TaskStatus::Interrupted => "中断しました",
+        TaskStatus::Refused => "出力先にファイルがあるため上書きしませんでした",
+        _ => "失敗しました",
+    }
+}
+
+/// 結果に添える, 元動画の情報.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceSummary {
+    pub path: String,
+    pub video_stream: VideoStreamInfo,
+    pub audio_streams: Vec<AudioStreamInfo>,
+    pub duration_secs: f64,
+    pub file_size: u64,
+}
+
+impl From<&VideoStat> for SourceSummary {
+    fn from(stat: &VideoStat) -> Self {
+        Self {
+            path: stat.path.clone(),
+            video_stream: stat.video_stream.clone(),
+            audio_streams: stat.audio_streams.clone(),
+            duration_secs: stat.duration.as_secs_f64(),
+            file_size: stat.file_size,
+        }
+    }
+}
+
+/// 1 つの入力の元動画と, すべての設定の結果.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncodeReport {
+    pub source: SourceSummary,
+    pub results: Vec<ResultRecord>,
+}
+
+impl EncodeReport {
+    pub fn new(stat: &VideoStat, results: Vec<ResultRecord>) -> Self {
+        Self {
+            source: SourceSummary::from(stat),
+            results,
+        }
+    }
+}
+
+/// 実行全体の結果. `--json`, `--csv`, `--html` はどれもこれから書き出す.
+#[derive(Debug, Clone, Default)]
+pub struct RunResults {
+    pub reports: Vec<EncodeReport>,
+    pub failed_inputs: Vec<InputFailure>,
+}
+
+impl RunResults {
+    /// すべての入力のタスクの結果. 入力の順, 設定の順に並べる.
+    pub fn records(&self) -> impl Iterator<Item = &ResultRecord> {
+        self.reports.iter().flat_map(|r| &r.results)
+    }
+
+    pub fn to_document(&self) -> ResultsDocument {
+        ResultsDocument {
+            results: self.records().cloned().collect(),
+            failed_inputs: self.failed_inputs.clone(),
+        }
+    }
+
+    /// 元動画が最適なためスキップしたタスクは成功に, Ctrl+C や時間制限 (`--max-runtime` / `--deadline`) で
+    /// 止めたタスクはどちらにも数えない. 情報を取得できなかった入力は 1 つの失敗に数える.
+    pub fn outcome(&self) -> RunOutcome {
+        let count = |f: fn(&TaskStatus) -> bool| self.records().filter(|r| f(&r.status)).count();
+        let succeeded = count(|s| s.is_success() || *s == TaskStatus::Skipped);
+        let interrupted = count(|s| *s == TaskStatus::Interrupted);
+        let out_of_time = count(|s| *s == TaskStatus::OutOfTime);
+        let failed = self.records().count() - succeeded - interrupted - out_of_time
+            + self.failed_inputs.len();
+        match (succeeded, failed) {
+            (_, 0) if interrupted > 0 => RunOutcome::Interrupted,
+            (_, 0) if out_of_time > 0 => RunOutcome::OutOfTime,
+            (_, 0) => RunOutcome::Succeeded,
+            (0, _) => RunOutcome::Failed,
+            _ => RunOutcome::Partial,
+        }
+    }
+}
+
+/// 実行全体の結果. 終了コードに使う.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunOutcome {
+    /// すべての設定が成功した.
+    Succeeded,
+    /// 成功した設定と失敗した設定がある.
+    Partial,
+    /// すべての設定が失敗した.
+    Failed,
+    /// 失敗はないが, Ctrl+C で中断した.
+    Interrupted,
+    /// 失敗はないが, 時間制限で完了しなかった設定がある.
+    OutOfTime,
+}
+
+impl RunOutcome {
+    pub fn exit_code(self) -> u8 {
+        match self {
+            RunOutcome::Succeeded => 0,
+            RunOutcome::Failed => 1,
+            RunOutcome::Partial => 2,
+            RunOutcome::Interrupted => 130,
+            RunOutcome::OutOfTime => 3,
+        }
+    }
+}
+
+/// 終了時に表示する, 出力の大きさの表の 1 行.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SummaryLine {
+    pub text: String,
+    /// 元動画より大きくなった. エンコードした意味がないので目立たせる.
+    pub larger: bool,
+}
+
+/// 出力を作ったタスクを小さい順に並べ, 列を揃えた表. 最初の行は見出しにする.
+/// 出力が 1 つもなければ見出しだけを返す.
+pub fn summary_table(records: &[ResultRecord]) -> Vec<SummaryLine> {
+    let mut rows = records
+        .iter()
+        .filter_map(|r| Some((r, r.output_size?)))
+        .collect::<Vec<_>>();
+    // コーデックごとにまとめ, その中で大きさの順に並べる
+    rows.sort_by(|(a, a_size), (b, b_size)| a.codec.cmp(&b.codec).then(a_size.cmp(b_size)));
+
+    let header = ["設定", "大きさ", "元動画比", "時間", "速度"].map(String::from);
+    let cells = rows
+        .iter()
+        .map(|(record, size)| {
+            let (width, height) = record.res.to_wh();
+            let ratio = match record.source_size {
+                0 => "-".to_string(),
+                source => format!("{:.1}%", *size as f64 / source as f64 * 100.0),
+            };
+            let codec = match &record.codec {
+                Some(codec) => format!("{} ", codec),
+                None => String::new(),
+            };
+            [
+                format!(
+                    "{}{}x{} {}fps CRF {}",
+                    codec, width, height, record.fps, record.crf
+                ),
+                format_size(*size, config::size_format()),
+                ratio,
+                record
+                    .elapsed_secs
+                    .map(|s| time::format_clock(Duration::from_secs_f64(s)))
+                    .unwrap_or_else(|| "-".to_string()),
+                record
+                    .speed
+                    .map(|speed| format!("{:.1}x", speed))
+                    .unwrap_or_else(|| "-".to_string()),
+            ]
+        })
+        .collect::<Vec<_>>();
+    let widths = (0..header.len())
+        .map(|i| {
+            std::iter::once(&header)
+                .chain(&cells)
+                .map(|row| text::width(&row[i]))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect::<Vec<_>>();
+    // 設定の列だけ左に揃え, 数値の列は右に揃える
+    let line = |row: &[String; 5]| {
+        row.iter()
+            .zip(&widths)
+            .enumerate()
+            .map(|(i, (cell, width))| match i {
+                0 => text::pad(cell, *width),
+                _ => format!("{}{}", " ".repeat(width - text::width(cell)), cell),
+            })
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+
+    std::iter::once(SummaryLine {
+        text: line(&header),
+        larger: false,
+    })
+    .chain(zip(&rows, &cells).map(|((record, size), row)| SummaryLine {
+        text: line(row),
+        larger: *size > record.source_size,
+    }))
+    .collect()
+}
+
+/// `--csv` の列. `codec` は `--codec` を指定しなかった設定では空にする.
+const CSV_HEADER: [&str; 11] = [
+    "input",
+    "width",
+    "height",
+    "fps",
+    "crf",
+    "codec",
+    "output_path",
+    "output_size",
+    "size_ratio",
+    "elapsed_secs",
+    "status",
+];
+
+/// 1 設定 1 行の CSV. 出力の大きさは元動画に対する比率も書き, 作らなかった出力の列は空にする.
+pub fn to_csv<'a>(records: impl IntoIterator<Item = &'a ResultRecord>) -> String {
+    let mut csv = CSV_HEADER.join(",") + "\n";
+    for record in records {
+        let (width, height) = record.res.to_wh();
+        let ratio = record
+            .output_size
+            .filter(|_| record.source_size > 0)
+            .map(|size| format!("{:.4}", size as f64 / record.source_size as f64));
+        let row = [
+            record.input_path.clone(),
+            width.to_string(),
+            height.to_string(),
+            record.fps.to_string(),
+            record.crf.to_string(),
+            record.codec.clone().unwrap_or_default(),
+            record.output_path.clone(),
+            record
+                .output_size
+                .map(|s| s.to_string())
+                .unwrap_or_default(),
+            ratio.unwrap_or_default(),
+            record
+                .elapsed_secs
+                .map(|s| format!("{:.2}", s))
+                .unwrap_or_default(),
+            record.result.clone(),
+        ];
+        csv += &row
+            .iter()
+            .map(|f| csv_field(f))
+            .collect::<Vec<_>>()
+            .join(",");
+        csv += "\n";
+    }
+    csv
+}
+
+/// 区切りや引用符, 改行を含む値は `"` で囲み, 中の `"` は重ねる.
+fn csv_field(value: &str) -> String {
+    match value.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_string(),
+    }
+}
+
+/// 途中の入力までの結果でも開けるよう, 入力ごとに全体を書き直す.
+pub fn write_csv<'a>(
+    path: &Path,
+    records: impl IntoIterator<Item = &'a ResultRecord>,
+) -> Result<()> {
+    fs::write(path, to_csv(records))
+        .with_context(|| format!("CSV を書き込めませんでした: {}", path.display()))
+}
+
+/// タスクを作る前に失敗した (元動画を解析できないなど) 入力.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputFailure {
+    pub input_path: String,
+    pub error: String,
+}
+
+/// `--json` で標準出力に書き出す, 実行全体の結果.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ResultsDocument {
+    pub results: Vec<ResultRecord>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failed_inputs: Vec<InputFailure>,
+}
+
+impl ResultsDocument {
+    pub fn write(&self, mut out: impl Write) -> io::Result<()> {
+        serde_json::to_writer_pretty(&mut out, self)?;
+        writeln!(out)?;
+        out.flush()
+    }
+}
+
+/// 以降の標準出力 (表示や警告) を標準エラー出力に送り, 元の標準出力を返す.
+/// 返した出力には `--json` の結果だけを書き, 標準出力を JSON だけにする.
+#[cfg(unix)]
+pub fn redirect_stdout() -> io::Result<Box<dyn Write + Send>> {
+    use std::{fs::File, os::fd::FromRawFd};
+
+    io::stdout().flush()?;
+    // SAFETY: 標準出力を複製してから, 標準エラー出力で置き換えるだけ. 複製した fd は返す `File` だけが持つ
+    unsafe {
+        let original = libc::dup(libc::STDOUT_FILENO);
+        if original < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) < 0 {
+            let e = io::Error::last_os_error();
+            libc::close(original);
+            return Err(e);
+        }
+        Ok(Box::new(File::from_raw_fd(original)))
+    }
+}
+
+/// 標準出力を差し替えられない環境では, 表示と同じ標準出力に書く.
+#[cfg(not(unix))]
+pub fn redirect_stdout() -> io::Result<Box<dyn Write + Send>> {
+    Ok(Box::new(io::stdout()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::{report::OutcomeStats, video::VideoConfig};
+    use std::time::Duration;
+
+    #[test]
+    fn test_record() {
+        let task = |status, outcome, error: Option<&str>| TaskReport {
+            input_path: "in/clip.mp4".to_string(),
+            config: VideoConfig {
+                res: VideoRes::R720p,
+                fps: 30,
+                crf: 28,
+                has_audio: true,
+                ..Default::default()
+            },
+            status,
+            outcome,
+            error: error.map(str::to_string),
+        };
+        let outcome = OutcomeStats::new(
+            "out/clip--720p.mp4".to_string(),
+            2048,
+            Duration::from_millis(2500),
+        );
+
+        let ok = ResultRecord::new(
+            &task(TaskStatus::Encoded, Some(outcome), None),
+            "out/unused.mp4".to_string(),
+            8192,
+        );
+        assert!(ok.is_ok());
+        assert_eq!(ok.output_path, "out/clip--720p.mp4");
+        assert_eq!(ok.output_size, Some(2048));
+        assert_eq!(ok.elapsed_secs, Some(2.5));
+
+        let mut failed = ResultRecord::new(
+            &task(TaskStatus::Failed, None, Some("Conversion failed!")),
+            "out/clip--720p.mp4".to_string(),
+            8192,
+        );
+        assert_eq!(failed.result, "Conversion failed!");
+        assert_eq!(failed.output_path, "out/clip--720p.mp4");
+        assert_eq!(failed.output_size, None);
+        failed.codec = Some("h265".to_string());
+
+        let skipped = ResultRecord::new(
+            &task(TaskStatus::OutOfTime, None, None),
+            "out/clip--720p.mp4".to_string(),
+            8192,
+        );
+        assert!(!skipped.is_ok());
+        assert_eq!(skipped.result, "時間制限により完了しませんでした");
+
+        let document = ResultsDocument {
+            results: vec![ok, failed],
+            failed_inputs: Vec::new(),
+        };
+        let mut out = Vec::new();
+        document.write(&mut out).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json["results"][0]["res"], "1280x720");
+        assert_eq!(json["results"][0]["has_audio"], true);
+        assert_eq!(json["results"][0]["source_size"], 8192);
+        assert_eq!(json["results"][0]["result"], "ok");
+        assert_eq!(json["results"][1]["output_size"], serde_json::Value::Null);
+        assert!(json.get("failed_inputs").is_none());
+
+        let csv = to_csv(&document.results);
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], CSV_HEADER.join(","));
+        assert_eq!(
+            lines[1],
+            "in/clip.mp4,1280,720,30,28,,out/clip--720p.mp4,2048,0.2500,2.50,ok"
+        );
+        assert_eq!(
+            lines[2],
+            "in/clip.mp4,1280,720,30,28,h265,out/clip--720p.mp4,,,,Conversion failed!"
+        );
+    }
+
+    #[test]
+    fn test_summary_table() {
+        let record = |res, size: Option<u64>| ResultRecord {
+            input_path: "in/clip.mp4".to_string(),
+            res,
+            fps: 30,
+            crf: 28,
+            codec: None,
+            has_audio: true,
+            status: TaskStatus::Encoded,
+            output_path: "out/clip.mp4".to_string(),
+            output_size: size,
+            source_size: 1_000_000,
+            elapsed_secs: size.map(|_| 83.0),
+            speed: size.map(|_| 2.4),
+            result: OK.to_string(),
+        };
+        let table = summary_table(&[
+            record(VideoRes::R1080p, Some(1_200_000)),
+            record(VideoRes::R240p, Some(50_000)),
+            record(VideoRes::R720p, None),
+        ]);
+        let lines = table.iter().map(|l| l.text.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                "設定                     大きさ  元動画比      時間  速度",
+                "426x240 30fps CRF 28      50 kB      5.0%  00:01:23  2.4x",
+                "1920x1080 30fps CRF 28  1.20 MB    120.0%  00:01:23  2.4x",
+            ]
+        );
+        assert_eq!(
+            table.iter().map(|l| l.larger).collect::<Vec<_>>(),
+            [false, false, true]
+        );
+        assert_eq!(summary_table(&[record(VideoRes::R720p, None)]).len(), 1);
+
+        // コーデックごとにまとめて並べる
+        let codec = |codec: &str, size| ResultRecord {
+            codec: Some(codec.to_string()),
+            ..record(VideoRes::R720p, Some(size))
+        };
+        let table = summary_table(&[
+            codec("vp9", 300_000),
+            codec("h264", 500_000),
+            codec("vp9", 200_000),
+            codec("h264", 400_000),
+        ]);
+        let settings = table[1..]
+            .iter()
+            .map(|l| l.text.split("  ").next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            settings,
+            [
+                "h264 1280x720 30fps CRF 28",
+                "h264 1280x720 30fps CRF 28",
+                "vp9 1280x720 30fps CRF 28",
+                "vp9 1280x720 30fps CRF 28",
+            ]
+        );
+        assert!(table[1].text.contains("400 kB") && table[3].text.contains("200 kB"));
+    }
+
+    #[test]
+    fn test_outcome() {
+        let results = |statuses: &[TaskStatus], failed_inputs: usize| RunResults {
+            reports: vec![EncodeReport {
+                source: SourceSummary {
+                    path: "in/clip.mp4".to_string(),
+                    video_stream: VideoStreamInfo::default(),
+                    audio_streams: Vec::new(),
+                    duration_secs: 1.0,
+                    file_size: 1,
+                },
+                results: statuses
+                    .iter()
+                    .map(|status| ResultRecord {
+                        input_path: "in/clip.mp4".to_string(),
+                        res: VideoRes::R720p,
+                        fps: 30,
+                        crf: 28,
+                        codec: None,
+                        has_audio: true,
+                        status: status.clone(),
+                        output_path: "out/clip.mp4".to_string(),
+                        output_size: None,
+                        source_size: 1,
+                        elapsed_secs: None,
+                        speed: None,
+                        result: String::new(),
+                    })
+                    .collect(),
+            }],
+            failed_inputs: (0..failed_inputs)
+                .map(|_| InputFailure {
+                    input_path: "in/broken.mp4".to_string(),
+                    error: String::new(),
+                })
+                .collect(),
+        };
+        use TaskStatus::*;
+
+        assert_eq!(
+            results(&[Encoded, Skipped], 0).outcome(),
+            RunOutcome::Succeeded
+        );
+        assert_eq!(results(&[], 0).outcome(), RunOutcome::Succeeded);
+        assert_eq!(
+            results(&[Encoded, Failed], 0).outcome(),
+            RunOutcome::Partial
+        );
+        assert_eq!(results(&[Copied], 1).outcome(), RunOutcome::Partial);
+        assert_eq!(results(&[Failed, Refused], 0).outcome(), RunOutcome::Failed);
+        assert_eq!(results(&[], 1).outcome(), RunOutcome::Failed);
+        // 時間制限で止めたタスクは失敗に数えない
+        assert_eq!(
+            results(&[Encoded, OutOfTime], 0).outcome(),
+            RunOutcome::OutOfTime
+        );
+        assert_eq!(
+            results(&[Failed, OutOfTime], 0).outcome(),
+            RunOutcome::Failed
+        );
+        assert_eq!(RunOutcome::OutOfTime.exit_code(), 3);
+        assert_eq!(
+            results(&[Encoded, Interrupted], 0).outcome(),
+            RunOutcome::Interrupted
+        );
+        // 中断より失敗を優先する
+        assert_eq!(
+            results(&[Failed, Interrupted], 0).outcome(),
+            RunOutcome::Failed
+        );
+        assert_eq!(RunOutcome::Partial.exit_code(), 2);
+    }
+
+    #[test]
+    fn test_csv_field() {
+        assert_eq!(csv_field("ok"), "ok");
+        assert_eq!(
+            csv_field("Invalid argument, \"-crf\""),
+            "\"Invalid argument, \"\"-crf\"\"\""
+        );
+        assert_eq!(csv_field("1 行目\n2 行目"), "\"1 行目\n2 行目\"");
+    }
+}
@@ -0,0 +1,389 @@
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use super::{integrity, video::VideoConfig};
+
+/// 索引を置くファイルの名前 (履歴と同じディレクトリ).
+pub const INDEX_FILE: &str = "reuse.json";
+
+/// 同じ出力になるかどうかを決める値.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReuseKey {
+    pub input_hash: String,
+    /// `VideoConfig` の JSON. フィールドは定義の順に並ぶので, 同じ設定は同じ文字列になる.
+    pub config: String,
+    /// 入出力のパスを `{input}` / `{output}` に置き換えた ffmpeg の引数.
+    /// 切り出しやラベルなど, 設定の外のオプションの違いを区別する.
+    pub args: Vec<String>,
+    pub ffmpeg_version: Option<String>,
+}
+
+impl ReuseKey {
+    pub fn new(
+        input_hash: &str,
+        config: &VideoConfig,
+        args: &[String],
+        (input_path, output_path): (&str, &str),
+        ffmpeg_version: Option<String>,
+    ) -> Self {
+        Self {
+            input_hash: input_hash.to_string(),
+            config: serde_json::to_string(config).unwrap_or_default(),
+            args: args
+                .iter()
+                .map(|arg| match arg.as_str() {
+                    a if a == input_path => "{input}".to_string(),
+                    a if a == output_path => "{output}".to_string(),
+                    a => a.to_string(),
+                })
+                .collect(),
+            ffmpeg_version,
+        }
+    }
+}
+
+/// 完成した出力の記録.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReuseEntry {
+    #[serde(flatten)]
+    pub key: ReuseKey,
+    /// 絶対パス.
+    pub output_path: String,
+    pub output_hash: String,
+    pub output_size: u64,
+}
+
+impl ReuseEntry {
+    /// 出力が記録したときのまま残っているか. 大きさを比べてから, 一致した場合だけハッシュを求める.
+    pub async fn is_intact(&self, rate: Option<u64>) -> bool {
+        let path = Path::new(&self.output_path);
+        match fs::metadata(path) {
+            Ok(meta) if meta.is_file() && meta.len() == self.output_size => {
+                integrity::sha256_file(path, rate)
+                    .await
+                    .is_ok_and(|hash| hash == self.output_hash)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// 入力のハッシュの控え. 大きさと更新日時が変わっていなければ, 読み直さずに使う.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct InputDigest {
+    path: String,
+    size: u64,
+    modified: u64,
+    hash: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct IndexFile {
+    inputs: Vec<InputDigest>,
+    entries: Vec<ReuseEntry>,
+}
+
+/// 以前の実行で作った出力の索引.
+pub struct ReuseIndex {
+    path: PathBuf,
+    file: IndexFile,
+}
+
+/// 絶対パスと, 大きさと更新日時 (UNIX 時間の秒).
+fn fingerprint(path: &Path) -> Option<(String, u64, u64)> {
+    let meta = fs::metadata(path).ok()?;
+    let modified = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    let path = fs::canonicalize(path).ok()?;
+
+    Some((
+        path.to_string_lossy().into_owned(),
+        meta.len(),
+        modified.as_secs(),
+    ))
+}
+
+impl ReuseIndex {
+    /// 索引を読み込み, 残っていない出力や変更された入力の記録を取り除く.
+    /// 壊れた索引 (書き込み途中で中断された場合など) は空として扱う.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let file = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_default(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => IndexFile::default(),
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("再利用の索引の読み込みに失敗しました: {}", path.display())
+                })
+            }
+        };
+        let mut index = Self { path, file };
+        index.prune();
+
+        Ok(index)
+    }
+
+    pub fn open_default() -> Result<Self> {
+        let dir = dirs::data_dir().ok_or_else(|| anyhow!("データディレクトリが見つかりません"))?;
+
+        Self::open(dir.join("vvcnv").join(INDEX_FILE))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn entries(&self) -> &[ReuseEntry] {
+        &self.file.entries
+    }
+
+    /// 出力が移動・削除された記録と, 大きさや更新日時が変わった入力の控えを取り除き, 取り除いた数を返す.
+    pub fn prune(&mut self) -> usize {
+        let before = self.file.entries.len() + self.file.inputs.len();
+        self.file.entries.retain(|entry| {
+            fs::metadata(&entry.output_path)
+                .is_ok_and(|meta| meta.is_file() && meta.len() == entry.output_size)
+        });
+        self.file.inputs.retain(|input| {
+            fingerprint(Path::new(&input.path))
+                .is_some_and(|(_, size, modified)| size == input.size && modified == input.modified)
+        });
+
+        before - self.file.entries.len() - self.file.inputs.len()
+    }
+
+    pub fn find(&self, key: &ReuseKey) -> Option<&ReuseEntry> {
+        self.file.entries.iter().find(|entry| entry.key == *key)
+    }
+
+    /// 同じ出力先の記録は置き換える.
+    pub fn record(&mut self, entry: ReuseEntry) {
+        self.remove(&entry.output_path);
+        self.file.entries.push(entry);
+    }
+
+    pub fn remove(&mut self, output_path: &str) {
+        self.file
+            .entries
+            .retain(|entry| entry.output_path != output_path);
+    }
+
+    pub fn input_hash(&self, path: &Path) -> Option<&str> {
+        let (path, size, modified) = fingerprint(path)?;
+        self.file
+            .inputs
+            .iter()
+            .find(|i| i.path == path && i.size == size && i.modified == modified)
+            .map(|i| i.hash.as_str())
+    }
+
+    pub fn remember_input_hash(&mut self, path: &Path, hash: String) {
+        let Some((path, size, modified)) = fingerprint(path) else {
+            return;
+        };
+        self.file.inputs.retain(|i| i.path != path);
+        self.file.inputs.push(InputDigest {
+            path,
+            size,
+            modified,
+            hash,
+        });
+    }
+
+    /// 書き込み途中で中断しても壊れないように, 一時ファイルに書いてから置き換える.
+    pub fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).with_context(|| {
+                format!("履歴ディレクトリの作成に失敗しました: {}", dir.display())
+            })?;
+        }
+        let tmp = self
+            .path
+            .with_extension(format!("json.tmp-{}", std::process::id()));
+        fs::write(&tmp, serde_json::to_string(&self.file)?)
+            .and_then(|_| fs::rename(&tmp, &self.path))
+            .with_context(|| format!("再利用の索引の保存に失敗しました: {}", self.path.display()))
+    }
+}
+
+/// 出力を作る前に呼ぶ. 再利用した出力はハードリンクなので, そのまま ffmpeg が上書きすると
+/// リンク元の出力まで書き換わってしまう. 他にリンクがある場合は先に削除しておく.
+pub fn detach(path: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if fs::symlink_metadata(path).is_ok_and(|meta| meta.is_file() && meta.nlink() > 1) {
+            return fs::remove_file(path);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+
+    Ok(())
+}
+
+/// `from` を `to` に置く. Unix ではハードリンクを試し, 別のファイルシステムなどで作れない場合はコピーする.
+/// ハードリンクの数を確かめられない環境では `detach` できないので, 常にコピーする.
+/// `from` と `to` が同じファイルの場合は何もしない.
+pub fn link_or_copy(from: &Path, to: &Path) -> Result<()> {
+    if fs::canonicalize(to).is_ok_and(|to| fs::canonicalize(from).is_ok_and(|from| from == to)) {
+        return Ok(());
+    }
+    let tmp = to.with_file_name(format!(
+        ".{}.reuse-{}",
+        to.file_name().unwrap_or_default().to_string_lossy(),
+        std::process::id()
+    ));
+    fs::remove_file(&tmp).ok();
+    let linked = cfg!(unix) && fs::hard_link(from, &tmp).is_ok();
+    match linked {
+        true => Ok(()),
+        false => fs::copy(from, &tmp).map(|_| ()),
+    }
+    .and_then(|_| fs::rename(&tmp, to))
+    .inspect_err(|_| {
+        fs::remove_file(&tmp).ok();
+    })
+    .with_context(|| {
+        format!(
+            "以前の出力を再利用できません: {} → {}",
+            from.display(),
+            to.display()
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::testing::TempDir;
+    use crate::modules::video::{VideoConfig, VideoRes};
+
+    fn temp_dir(name: &str) -> TempDir {
+        TempDir::new(&format!("reuse-{}", name))
+    }
+
+    fn key(crf: u32, args: &[&str]) -> ReuseKey {
+        let config = VideoConfig {
+            res: VideoRes::R720p,
+            crf,
+            ..Default::default()
+        };
+        let args = args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        ReuseKey::new(
+            "abc",
+            &config,
+            &args,
+            ("/in/a.mp4", "/old/a--crf.mp4"),
+            Some("7.0".into()),
+        )
+    }
+
+    #[test]
+    fn test_key() {
+        let a = key(28, &["-i", "/in/a.mp4", "-crf", "28", "/old/a--crf.mp4"]);
+        assert_eq!(a.args, ["-i", "{input}", "-crf", "28", "{output}"]);
+        assert_eq!(
+            a,
+            key(28, &["-i", "/in/a.mp4", "-crf", "28", "/old/a--crf.mp4"])
+        );
+        assert_ne!(
+            a,
+            key(30, &["-i", "/in/a.mp4", "-crf", "28", "/old/a--crf.mp4"])
+        );
+        // 切り出しなど, 設定の外のオプションが違えば別の出力
+        assert_ne!(
+            a,
+            key(
+                28,
+                &[
+                    "-ss",
+                    "10",
+                    "-i",
+                    "/in/a.mp4",
+                    "-crf",
+                    "28",
+                    "/old/a--crf.mp4"
+                ]
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_index() {
+        let dir = temp_dir("index");
+        let output = dir.join("old.mp4");
+        fs::write(&output, "encoded").unwrap();
+        let output_path = fs::canonicalize(&output)
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        let entry = ReuseEntry {
+            key: key(28, &[]),
+            output_path: output_path.clone(),
+            output_hash: integrity::sha256_file(&output, None).await.unwrap(),
+            output_size: 7,
+        };
+
+        let mut index = ReuseIndex::open(dir.join(INDEX_FILE)).unwrap();
+        assert!(index.entries().is_empty());
+        index.record(entry.clone());
+        index.record(entry.clone());
+        index.remember_input_hash(&output, "abc".into());
+        index.save().unwrap();
+
+        let mut index = ReuseIndex::open(dir.join(INDEX_FILE)).unwrap();
+        assert_eq!(index.entries(), std::slice::from_ref(&entry));
+        assert_eq!(index.input_hash(&output), Some("abc"));
+        assert!(index.find(&key(30, &[])).is_none());
+        let found = index.find(&key(28, &[])).unwrap().clone();
+        assert!(found.is_intact(None).await);
+
+        // 同じ大きさで中身が変わった出力は使わない
+        fs::write(&output, "ENCODED").unwrap();
+        assert!(!found.is_intact(None).await);
+        index.remove(&output_path);
+        assert!(index.entries().is_empty());
+
+        // 移動・削除された出力の記録は読み込み時に取り除く
+        index.record(entry);
+        index.save().unwrap();
+        fs::remove_file(&output).unwrap();
+        let index = ReuseIndex::open(dir.join(INDEX_FILE)).unwrap();
+        assert!(index.entries().is_empty());
+        assert_eq!(index.input_hash(&output), None);
+
+        fs::write(dir.join(INDEX_FILE), "{\"entries\": [").unwrap();
+        assert!(ReuseIndex::open(dir.join(INDEX_FILE))
+            .unwrap()
+            .entries()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_link_or_copy() {
+        let dir = temp_dir("link");
+        let from = dir.join("old.mp4");
+        let to = dir.join("out").join("new.mp4");
+        fs::create_dir_all(to.parent().unwrap()).unwrap();
+        fs::write(&from, "encoded").unwrap();
+        fs::write(&to, "stale").unwrap();
+
+        link_or_copy(&from, &to).unwrap();
+        assert_eq!(fs::read_to_string(&to).unwrap(), "encoded");
+        link_or_copy(&to, &to).unwrap();
+        assert_eq!(fs::read_dir(to.parent().unwrap()).unwrap().count(), 1);
+
+        // 上書きする前に切り離すので, リンク元は変わらない
+        detach(&to).unwrap();
+        fs::write(&to, "re-encoded").unwrap();
+        assert_eq!(fs::read_to_string(&from).unwrap(), "encoded");
+        assert!(link_or_copy(&dir.join("missing.mp4"), &to).is_err());
+        assert_eq!(fs::read_to_string(&to).unwrap(), "re-encoded");
+    }
+}
@@ -0,0 +1,229 @@
+use anyhow::{Context, Result};
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    ops::Range,
+    time::Duration,
+};
+
+use super::time;
+
+/// 先頭と末尾のこの割合はサンプルに使わない (黒画面のイントロやクレジットを避ける).
+pub const DEFAULT_MARGIN: f64 = 0.05;
+/// 入力のハッシュに使う, 先頭と末尾のバイト数.
+const HASH_CHUNK: u64 = 1024 * 1024;
+
+/// サンプルの数と 1 つあたりの長さ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowSpec {
+    pub count: usize,
+    pub length: Duration,
+}
+
+/// `5x10s` の形式.
+pub fn parse_spec(input: &str) -> Result<WindowSpec, String> {
+    let (count, length) = input
+        .split_once('x')
+        .ok_or_else(|| format!("数x長さ の形式で指定してください (例: 5x10s): {}", input))?;
+    let count = match count.trim().parse::<usize>() {
+        Ok(count) if count > 0 => count,
+        _ => return Err(format!("数には 1 以上の整数を指定してください: {}", count)),
+    };
+    let length = time::parse_duration(length).map_err(|e| e.to_string())?;
+    if length.is_zero() {
+        return Err(format!(
+            "長さには 0 より大きい値を指定してください: {}",
+            input
+        ));
+    }
+
+    Ok(WindowSpec { count, length })
+}
+
+/// 再現できる疑似乱数 (SplitMix64). 標準ライブラリのハッシュは Rust のバージョンで変わりうるので使わない.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// `0..=max` の一様な値.
+    fn below_or_eq(&mut self, max: u64) -> u64 {
+        match max.checked_add(1) {
+            Some(bound) => self.next() % bound,
+            None => self.next(),
+        }
+    }
+}
+
+/// FNV-1a (64 bit).
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// 入力の大きさと先頭・末尾の内容から求めたシード. 大きな入力でも全体は読まない.
+pub fn seed_from_file(path: &str) -> Result<u64> {
+    let context = || format!("サンプルのシードを求めるために入力を読めません: {}", path);
+    let mut file = File::open(path).with_context(context)?;
+    let size = file.metadata().with_context(context)?.len();
+
+    let hash = fnv1a(0xcbf2_9ce4_8422_2325, &size.to_le_bytes());
+    let mut buffer = Vec::new();
+    (&mut file)
+        .take(HASH_CHUNK)
+        .read_to_end(&mut buffer)
+        .with_context(context)?;
+    if size > HASH_CHUNK * 2 {
+        file.seek(SeekFrom::Start(size - HASH_CHUNK))
+            .with_context(context)?;
+        file.take(HASH_CHUNK)
+            .read_to_end(&mut buffer)
+            .with_context(context)?;
+    }
+
+    Ok(fnv1a(hash, &buffer))
+}
+
+/// `duration` の中から, 重ならないサンプルの範囲を `seed` に従って選ぶ. 同じ引数であれば常に同じ範囲になる.
+/// 先頭と末尾の `margin` の割合は避け, 残りに収まらない分はサンプルの数を減らす.
+/// 1 つも収まらない場合は, 残りの範囲全体を 1 つのサンプルにする.
+pub fn select_windows(
+    duration: Duration,
+    seed: u64,
+    spec: WindowSpec,
+    margin: f64,
+) -> Vec<Range<Duration>> {
+    let total = duration.as_millis() as u64;
+    let skip = (total as f64 * margin.clamp(0.0, 0.49)) as u64;
+    let (lo, hi) = (skip, total - skip);
+    let usable = hi - lo;
+    let length = (spec.length.as_millis() as u64).max(1);
+    if usable == 0 {
+        return Vec::new();
+    }
+    if length >= usable {
+        return vec![Duration::from_millis(lo)..Duration::from_millis(hi)];
+    }
+
+    // 余りの長さの中に乱数で区切りを置き, 区切りの順にサンプルを並べると重ならない
+    let count = (spec.count as u64).min(usable / length);
+    let slack = usable - count * length;
+    let mut rng = SplitMix64(seed);
+    let mut offsets = (0..count)
+        .map(|_| rng.below_or_eq(slack))
+        .collect::<Vec<_>>();
+    offsets.sort();
+
+    offsets
+        .into_iter()
+        .enumerate()
+        .map(|(i, offset)| {
+            let start = lo + offset + i as u64 * length;
+            Duration::from_millis(start)..Duration::from_millis(start + length)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::testing::TempDir;
+
+    fn spec(count: usize, secs: u64) -> WindowSpec {
+        WindowSpec {
+            count,
+            length: Duration::from_secs(secs),
+        }
+    }
+
+    #[test]
+    fn test_parse_spec() {
+        assert_eq!(parse_spec("5x10s"), Ok(spec(5, 10)));
+        assert_eq!(parse_spec("1x1m"), Ok(spec(1, 60)));
+        assert!(parse_spec("0x10s").is_err());
+        assert!(parse_spec("5x0").is_err());
+        assert!(parse_spec("5").is_err());
+        assert!(parse_spec("ax10s").is_err());
+    }
+
+    #[test]
+    fn test_select_windows_properties() {
+        let mut rng = SplitMix64(42);
+        for _ in 0..2_000 {
+            let duration = Duration::from_millis(rng.below_or_eq(4 * 3600 * 1000));
+            let seed = rng.next();
+            let spec = spec(1 + rng.below_or_eq(9) as usize, 1 + rng.below_or_eq(120));
+
+            let windows = select_windows(duration, seed, spec, DEFAULT_MARGIN);
+            assert_eq!(
+                windows,
+                select_windows(duration, seed, spec, DEFAULT_MARGIN)
+            );
+            assert!(windows.len() <= spec.count);
+
+            let total = duration.as_millis() as u64;
+            let skip = Duration::from_millis((total as f64 * DEFAULT_MARGIN) as u64);
+            for window in &windows {
+                assert!(window.start >= skip, "{:?} {:?}", duration, window);
+                assert!(window.end <= duration - skip, "{:?} {:?}", duration, window);
+                assert!(window.start < window.end);
+            }
+            for pair in windows.windows(2) {
+                assert!(pair[0].end <= pair[1].start, "{:?}", windows);
+            }
+            // 十分に長い入力では指定した数と長さのサンプルを選ぶ
+            if (duration - skip * 2) >= spec.length * spec.count as u32 && !windows.is_empty() {
+                assert_eq!(windows.len(), spec.count);
+                assert!(windows.iter().all(|w| w.end - w.start == spec.length));
+            }
+        }
+    }
+
+    #[test]
+    fn test_select_windows_seed() {
+        let duration = Duration::from_secs(600);
+        let a = select_windows(duration, 1, spec(5, 10), DEFAULT_MARGIN);
+        let b = select_windows(duration, 2, spec(5, 10), DEFAULT_MARGIN);
+        assert_eq!(a.len(), 5);
+        assert_ne!(a, b);
+
+        // 短すぎる場合は使える範囲全体を 1 つにする
+        assert_eq!(
+            select_windows(Duration::from_secs(10), 1, spec(3, 20), DEFAULT_MARGIN),
+            vec![Duration::from_millis(500)..Duration::from_millis(9_500)]
+        );
+        assert_eq!(
+            select_windows(Duration::from_secs(100), 1, spec(20, 10), DEFAULT_MARGIN).len(),
+            9
+        );
+        assert!(select_windows(Duration::ZERO, 1, spec(1, 1), DEFAULT_MARGIN).is_empty());
+    }
+
+    #[test]
+    fn test_seed_from_file() {
+        let dir = TempDir::new("sampling");
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        std::fs::write(path("a"), vec![1u8; 3 * HASH_CHUNK as usize]).unwrap();
+        std::fs::write(path("b"), vec![1u8; 3 * HASH_CHUNK as usize]).unwrap();
+        let mut changed = vec![1u8; 3 * HASH_CHUNK as usize];
+        *changed.last_mut().unwrap() = 2;
+        std::fs::write(path("c"), changed).unwrap();
+
+        assert_eq!(
+            seed_from_file(&path("a")).unwrap(),
+            seed_from_file(&path("b")).unwrap()
+        );
+        assert_ne!(
+            seed_from_file(&path("a")).unwrap(),
+            seed_from_file(&path("c")).unwrap()
+        );
+        assert!(seed_from_file(&path("missing")).is_err());
+    }
+}
@@ -0,0 +1,464 @@
+use core::fmt;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use super::ffmpeg;
+
+/// ffmpeg 1 つあたりの CPU 時間の上限. 複数のスレッドの合計なので, 実際の時間よりかなり長くしておく.
+pub const CPU_LIMIT: Duration = Duration::from_secs(48 * 3600);
+/// ffmpeg 1 つあたりの仮想メモリの上限.
+pub const MEMORY_LIMIT: u64 = 16 * 1024 * 1024 * 1024;
+/// ffmpeg が書き込めるファイルの大きさの上限.
+pub const FILE_SIZE_LIMIT: u64 = 256 * 1024 * 1024 * 1024;
+/// ffmpeg 自身と共有ライブラリ, フォントなどを読むために, 読み込みと実行だけを許可するディレクトリ.
+const SYSTEM_DIRS: &[&str] = &[
+    "/usr", "/lib", "/lib32", "/lib64", "/bin", "/sbin", "/etc", "/opt", "/nix", "/proc", "/sys",
+    "/dev",
+];
+/// 値を取らない ffmpeg のオプション. これに続く引数は出力のパスでありうる.
+const FLAGS: &[&str] = &[
+    "-y",
+    "-n",
+    "-an",
+    "-vn",
+    "-sn",
+    "-dn",
+    "-re",
+    "-shortest",
+    "-nostats",
+    "-nostdin",
+    "-hide_banner",
+];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+pub enum SandboxErr {
+    UnsupportedPlatform,
+    /// Landlock (Linux 5.13 以降) が使えない.
+    Landlock(io::Error),
+    /// 制限した環境で ffmpeg を起動できない (ユーザー名前空間が無効など).
+    Probe(io::Error),
+}
+
+impl fmt::Display for SandboxErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SandboxErr::UnsupportedPlatform => write!(f, "サンドボックスは Linux でのみ使えます"),
+            SandboxErr::Landlock(e) => write!(
+                f,
+                "Landlock が使えないため, サンドボックスを使えません ({})",
+                e
+            ),
+            SandboxErr::Probe(e) => write!(
+                f,
+                "サンドボックスの中で ffmpeg を起動できません ({}). ユーザー名前空間が無効になっていないか確認してください",
+                e
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SandboxErr {}
+
+/// ffmpeg に許可するファイルへのアクセス. `SYSTEM_DIRS` はこれとは別に常に読み込める.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Policy {
+    /// 読み込みだけを許可するファイルとディレクトリ.
+    pub read: Vec<PathBuf>,
+    /// 読み書きを許可するディレクトリ.
+    pub write: Vec<PathBuf>,
+}
+
+fn is_url(path: &str) -> bool {
+    path.contains("://") || path.starts_with("pipe:")
+}
+
+fn dir_of(path: &str) -> PathBuf {
+    match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// ffmpeg の引数から, 読み込む入力 (`-i`) と, 出力 (最後の引数) のディレクトリを求める.
+/// 結合リスト (`-f concat`) は中に書いたファイルも読むので, リストのディレクトリを読めるようにする.
+pub fn policy(args: &[String]) -> Policy {
+    let mut policy = Policy::default();
+    let mut format = None;
+    for (i, arg) in args.iter().enumerate() {
+        match (arg.as_str(), args.get(i + 1)) {
+            ("-f", Some(value)) => format = Some(value.as_str()),
+            ("-i", Some(input)) => {
+                match format {
+                    _ if is_url(input) => {}
+                    Some("lavfi") => {}
+                    Some("concat") => policy.read.push(dir_of(input)),
+                    _ => policy.read.push(PathBuf::from(input)),
+                }
+                format = None;
+            }
+            _ => {}
+        }
+    }
+
+    let output = args
+        .iter()
+        .enumerate()
+        .rev()
+        .find(|(_, arg)| !FLAGS.contains(&arg.as_str()));
+    if let Some((i, output)) = output {
+        let is_value = i
+            .checked_sub(1)
+            .map(|prev| &args[prev])
+            .is_some_and(|prev| prev.starts_with('-') && !FLAGS.contains(&prev.as_str()));
+        if !is_value && !output.starts_with('-') && !is_url(output) {
+            policy.write.push(dir_of(output));
+        }
+    }
+
+    policy
+}
+
+/// `--sandbox` を有効にする. 使えない環境では有効にせず, 理由を返す.
+pub fn enable() -> Result<(), SandboxErr> {
+    imp::check()?;
+    let mut probe = Command::new(ffmpeg::ffmpeg_path());
+    probe.arg("-version");
+    confine_with(&mut probe, &Policy::default()).map_err(SandboxErr::Landlock)?;
+    match probe.output() {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => {
+            return Err(SandboxErr::Probe(io::Error::other(format!(
+                "終了コード {}",
+                output.status
+            ))))
+        }
+        Err(e) => return Err(SandboxErr::Probe(e)),
+    }
+
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// `--sandbox` の場合は, 引数から求めた `Policy` の中で ffmpeg を起動するようにする.
+pub fn confine(command: &mut Command) -> io::Result<()> {
+    if !is_enabled() {
+        return Ok(());
+    }
+    let args = command
+        .get_args()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    confine_with(command, &policy(&args))
+}
+
+/// 入力を読むだけのコマンド (ffprobe など) を, `input` だけを読める環境で起動するようにする.
+pub fn confine_reader(command: &mut Command, input: &str) -> io::Result<()> {
+    if !is_enabled() {
+        return Ok(());
+    }
+    let policy = Policy {
+        read: vec![PathBuf::from(input)],
+        write: Vec::new(),
+    };
+    confine_with(command, &policy)
+}
+
+/// 実行するファイルのディレクトリ. ディレクトリを含まない場合は PATH から探す.
+fn program_dir(program: &Path) -> Option<PathBuf> {
+    match program.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => Some(dir.to_path_buf()),
+        _ => std::env::split_paths(&std::env::var_os("PATH")?)
+            .find(|dir| dir.join(program).is_file()),
+    }
+}
+
+/// ネットワークを切り離し (ネットワーク名前空間), `policy` 以外のファイルへのアクセスを禁止し (Landlock),
+/// CPU 時間・メモリ・ファイルの大きさを制限して (rlimit) 起動するようにする.
+/// 実行するファイルのディレクトリは, システムのディレクトリの外 (`ffmpeg-path` や ffmpeg-sidecar がダウンロードしたもの) でも読める.
+pub fn confine_with(command: &mut Command, policy: &Policy) -> io::Result<()> {
+    let mut policy = policy.clone();
+    if let Some(dir) = program_dir(Path::new(command.get_program())) {
+        policy.read.push(dir);
+    }
+    imp::confine(command, &policy)
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::{
+        ffi::CString,
+        io, mem,
+        os::{
+            fd::{AsRawFd, FromRawFd, OwnedFd},
+            unix::{ffi::OsStrExt, process::CommandExt},
+        },
+        path::Path,
+        process::Command,
+        sync::Arc,
+    };
+
+    use super::{Policy, SandboxErr, CPU_LIMIT, FILE_SIZE_LIMIT, MEMORY_LIMIT, SYSTEM_DIRS};
+
+    const CREATE_RULESET_VERSION: u32 = 1 << 0;
+    const RULE_PATH_BENEATH: libc::c_int = 1;
+
+    const EXECUTE: u64 = 1 << 0;
+    const WRITE_FILE: u64 = 1 << 1;
+    const READ_FILE: u64 = 1 << 2;
+    const READ_DIR: u64 = 1 << 3;
+    /// ABI 1 で扱えるすべての操作 (`REMOVE_DIR` から `MAKE_SYM` まで).
+    const ABI1: u64 = (1 << 13) - 1;
+    const REFER: u64 = 1 << 13;
+    const TRUNCATE: u64 = 1 << 14;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    fn abi() -> io::Result<i64> {
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0,
+                CREATE_RULESET_VERSION,
+            )
+        };
+        match abi {
+            n if n < 0 => Err(io::Error::last_os_error()),
+            n => Ok(n),
+        }
+    }
+
+    pub fn check() -> Result<(), SandboxErr> {
+        abi().map(|_| ()).map_err(SandboxErr::Landlock)
+    }
+
+    fn handled(abi: i64) -> u64 {
+        match abi {
+            1 => ABI1,
+            2 => ABI1 | REFER,
+            _ => ABI1 | REFER | TRUNCATE,
+        }
+    }
+
+    fn add_rule(ruleset: &OwnedFd, path: &Path, access: u64) -> io::Result<()> {
+        let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else {
+            return Ok(());
+        };
+        let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+        // 存在しないパスは許可しない (アクセスできないだけなので, 失敗にはしない)
+        if fd < 0 {
+            return Ok(());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        // ファイルにはファイルに対する操作だけを許可できる
+        let access = match path.is_dir() {
+            true => access,
+            false => access & (EXECUTE | WRITE_FILE | READ_FILE | TRUNCATE),
+        };
+        let attr = PathBeneathAttr {
+            allowed_access: access,
+            parent_fd: fd.as_raw_fd(),
+        };
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                RULE_PATH_BENEATH,
+                &attr as *const PathBeneathAttr,
+                0,
+            )
+        };
+        match result {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    fn ruleset(policy: &Policy) -> io::Result<OwnedFd> {
+        let handled = handled(abi()?);
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let ruleset = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+
+        let read = EXECUTE | READ_FILE | READ_DIR;
+        for dir in SYSTEM_DIRS {
+            add_rule(&ruleset, Path::new(dir), read)?;
+        }
+        for path in &policy.read {
+            add_rule(&ruleset, path, read)?;
+        }
+        for dir in &policy.write {
+            add_rule(&ruleset, dir, handled)?;
+        }
+
+        Ok(ruleset)
+    }
+
+    fn check_result(result: libc::c_long) -> io::Result<()> {
+        match result {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    pub fn confine(command: &mut Command, policy: &Policy) -> io::Result<()> {
+        let ruleset = Arc::new(ruleset(policy)?);
+        let is_root = unsafe { libc::geteuid() } == 0;
+        // fork した後の子プロセスで, exec する前に実行する. メモリの確保などはしない
+        let restrict = move || {
+            let limits = [
+                (libc::RLIMIT_CPU, CPU_LIMIT.as_secs()),
+                (libc::RLIMIT_AS, MEMORY_LIMIT),
+                (libc::RLIMIT_FSIZE, FILE_SIZE_LIMIT),
+            ];
+            for (resource, limit) in limits {
+                let rlimit = libc::rlimit {
+                    rlim_cur: limit,
+                    rlim_max: limit,
+                };
+                check_result(unsafe { libc::setrlimit(resource, &rlimit) } as libc::c_long)?;
+            }
+            // root 以外はユーザー名前空間を作らないとネットワーク名前空間を作れない
+            let flags = match is_root {
+                true => libc::CLONE_NEWNET,
+                false => libc::CLONE_NEWUSER | libc::CLONE_NEWNET,
+            };
+            check_result(unsafe { libc::unshare(flags) } as libc::c_long)?;
+            check_result(
+                unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } as libc::c_long,
+            )?;
+            check_result(unsafe {
+                libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0)
+            })
+        };
+        unsafe {
+            command.pre_exec(restrict);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::{io, process::Command};
+
+    use super::{Policy, SandboxErr};
+
+    pub fn check() -> Result<(), SandboxErr> {
+        Err(SandboxErr::UnsupportedPlatform)
+    }
+
+    pub fn confine(_command: &mut Command, _policy: &Policy) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::testing::TempDir;
+
+    fn args(args: &str) -> Vec<String> {
+        args.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_policy() {
+        let policy = super::policy(&args(
+            "-loglevel level+info -ss 10 -i in/a.mp4 -crf 23 -vf scale=1280:720 out/a--crf-23.mp4 -y",
+        ));
+        assert_eq!(policy.read, vec![PathBuf::from("in/a.mp4")]);
+        assert_eq!(policy.write, vec![PathBuf::from("out")]);
+
+        // 結合リストのディレクトリと, カレントディレクトリへの出力
+        let policy = super::policy(&args(
+            "-f concat -safe 0 -i work/concat.txt -i work/audio.mp4 -c copy a.mp4 -y",
+        ));
+        assert_eq!(
+            policy.read,
+            vec![PathBuf::from("work"), PathBuf::from("work/audio.mp4")]
+        );
+        assert_eq!(policy.write, vec![PathBuf::from(".")]);
+
+        // 出力のない情報の取得, 標準出力, 配信
+        assert!(super::policy(&args("-loglevel level+info -i a.mp4"))
+            .write
+            .is_empty());
+        assert!(super::policy(&args("-i a.mp4 -an -f null -"))
+            .write
+            .is_empty());
+        let policy = super::policy(&args("-re -i a.mp4 -f flv rtmp://localhost/live"));
+        assert!(policy.write.is_empty());
+        assert!(super::policy(&args("-f lavfi -i testsrc out.mp4"))
+            .read
+            .is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_confine_write() {
+        if let Err(e) = imp::check() {
+            eprintln!("Landlock が使えないため確認しません: {}", e);
+            return;
+        }
+        let dir = TempDir::new("sandbox");
+        let out = dir.join("out");
+        std::fs::create_dir_all(&out).unwrap();
+        let inside = out.join("inside.txt");
+        let outside = dir.join("outside.txt");
+
+        let mut command = Command::new("/bin/sh");
+        command.arg("-c").arg(format!(
+            "echo a > '{}'; echo b > '{}'",
+            inside.display(),
+            outside.display()
+        ));
+        let policy = Policy {
+            read: Vec::new(),
+            write: vec![out.clone()],
+        };
+        confine_with(&mut command, &policy).unwrap();
+        match command.output() {
+            Ok(output) => {
+                assert!(!output.status.success());
+                assert_eq!(std::fs::read_to_string(&inside).unwrap(), "a\n");
+                assert!(!outside.exists());
+            }
+            // ネットワーク名前空間を作れない環境 (コンテナの中など)
+            Err(e) => eprintln!("サンドボックスの中で起動できないため確認しません: {}", e),
+        }
+    }
+}
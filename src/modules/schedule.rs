@@ -0,0 +1,191 @@
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// タスクの所要時間の平均に使う, 直近のタスクの数.
+const ROLLING_WINDOW: usize = 8;
+
+/// 経過時間を測る時計. 実行では一時停止していた時間を除く [`PauseControl`](super::pause::PauseControl) を使い,
+/// テストでは進める時間を自由に決められる時計に差し替える.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// 一時停止を考えない, そのままの時計.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// 実行全体の時間制限 (`--max-runtime` / `--deadline`). 時刻はすべて `clock` で測るので,
+/// `PauseControl` を使う場合は一時停止していた時間だけ制限が延びる.
+pub struct RunBudget<C: Clock = SystemClock> {
+    clock: C,
+    started_at: Instant,
+    /// 新しいタスクを始めてよい時間. 実行中のタスクは止めない.
+    max_runtime: Option<Duration>,
+    /// 実行中のタスクも止める時間.
+    deadline: Option<Duration>,
+    /// 直近 `ROLLING_WINDOW` 個のタスクの所要時間.
+    recent: Mutex<VecDeque<Duration>>,
+}
+
+impl<C: Clock> RunBudget<C> {
+    /// `max_runtime` と `deadline` は `clock` の現在時刻 (実行の開始) からの時間.
+    pub fn new(clock: C, max_runtime: Option<Duration>, deadline: Option<Duration>) -> Self {
+        let started_at = clock.now();
+        Self {
+            clock,
+            started_at,
+            max_runtime,
+            deadline,
+            recent: Mutex::new(VecDeque::with_capacity(ROLLING_WINDOW)),
+        }
+    }
+
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// 実行の開始からの時間 (`clock` で測る).
+    pub fn elapsed(&self) -> Duration {
+        self.clock.now().saturating_duration_since(self.started_at)
+    }
+
+    /// 終わったタスクの所要時間を記録する. 直近 `ROLLING_WINDOW` (8) 個より古いものは捨てる.
+    pub fn record(&self, task_duration: Duration) {
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == ROLLING_WINDOW {
+            recent.pop_front();
+        }
+        recent.push_back(task_duration);
+    }
+
+    /// 直近 8 個のタスクの所要時間の平均. まだ記録がなければ `None`.
+    pub fn average_task_duration(&self) -> Option<Duration> {
+        let recent = self.recent.lock().unwrap();
+        if recent.is_empty() {
+            return None;
+        }
+        Some(recent.iter().sum::<Duration>() / recent.len() as u32)
+    }
+
+    /// 新しいタスクを始めてよいか. `deadline` を過ぎていれば始めない.
+    /// `max_runtime` がある場合は, 直近 8 個のタスクの平均の所要時間で終わる見込みのときだけ始める.
+    /// 最初のタスクは平均がないため, `max_runtime` を過ぎていなければ始める.
+    pub fn admit(&self) -> bool {
+        let elapsed = self.elapsed();
+        if self.is_past_deadline() {
+            return false;
+        }
+
+        match self.max_runtime {
+            None => true,
+            Some(max) => {
+                let expected = self.average_task_duration().unwrap_or_default();
+                elapsed < max && elapsed + expected <= max
+            }
+        }
+    }
+
+    /// `deadline` の時刻. `clock` の時刻なので, `PauseControl` では実際の時刻より一時停止の分だけ前になる.
+    pub fn deadline_at(&self) -> Option<Instant> {
+        self.deadline.map(|d| self.started_at + d)
+    }
+
+    /// `deadline` までの残り (`clock` で測る). 過ぎていれば 0.
+    pub fn until_deadline(&self) -> Option<Duration> {
+        self.deadline_at()
+            .map(|at| at.saturating_duration_since(self.clock.now()))
+    }
+
+    /// `clock` で測った経過時間が `deadline` に達したか.
+    pub fn is_past_deadline(&self) -> bool {
+        self.deadline.is_some_and(|d| self.elapsed() >= d)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[derive(Clone)]
+    struct MockClock(Arc<Mutex<Instant>>);
+
+    impl MockClock {
+        fn new() -> Self {
+            Self(Arc::new(Mutex::new(Instant::now())))
+        }
+
+        fn advance(&self, d: Duration) {
+            *self.0.lock().unwrap() += d;
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[test]
+    fn test_admit_by_rolling_average() {
+        let clock = MockClock::new();
+        let budget = RunBudget::new(clock.clone(), Some(Duration::from_secs(100)), None);
+
+        assert!(budget.admit());
+
+        clock.advance(Duration::from_secs(30));
+        budget.record(Duration::from_secs(30));
+        assert!(budget.admit());
+
+        clock.advance(Duration::from_secs(30));
+        budget.record(Duration::from_secs(30));
+        assert_eq!(
+            budget.average_task_duration(),
+            Some(Duration::from_secs(30))
+        );
+        assert!(budget.admit());
+
+        clock.advance(Duration::from_secs(20));
+        assert!(!budget.admit());
+    }
+
+    #[test]
+    fn test_rolling_window() {
+        let budget = RunBudget::new(MockClock::new(), None, None);
+        for _ in 0..ROLLING_WINDOW {
+            budget.record(Duration::from_secs(100));
+        }
+        for _ in 0..ROLLING_WINDOW {
+            budget.record(Duration::from_secs(10));
+        }
+        assert_eq!(
+            budget.average_task_duration(),
+            Some(Duration::from_secs(10))
+        );
+        assert!(budget.admit());
+    }
+
+    #[test]
+    fn test_deadline() {
+        let clock = MockClock::new();
+        let budget = RunBudget::new(clock.clone(), None, Some(Duration::from_secs(60)));
+
+        assert!(budget.admit());
+        assert!(!budget.is_past_deadline());
+
+        clock.advance(Duration::from_secs(45));
+        assert_eq!(budget.until_deadline(), Some(Duration::from_secs(15)));
+        clock.advance(Duration::from_secs(15));
+        assert_eq!(budget.until_deadline(), Some(Duration::ZERO));
+        assert!(budget.is_past_deadline());
+        assert!(!budget.admit());
+    }
+}
@@ -0,0 +1,163 @@
+use core::fmt;
+use humansize::format_size;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use super::{config, video::CancelToken};
+
+/// 冒頭はヘッダーやキーフレームの分だけ大きく見積もりやすいので, 進み具合がこれに満たない間は見込みを出さない.
+pub const MIN_PROJECTION_FRACTION: f64 = 0.1;
+
+/// 進み具合 (0.0〜1.0) から, 最後まで同じ割合で増えた場合の大きさを見込む.
+pub fn project(bytes: u64, fraction: f64) -> Option<u64> {
+    (fraction >= MIN_PROJECTION_FRACTION).then(|| (bytes as f64 / fraction.min(1.0)) as u64)
+}
+
+/// 元動画の大きさ × `factor` を, 切り出す長さの割合で按分した上限.
+pub fn source_limit(
+    source_size: u64,
+    source_duration: Duration,
+    output_duration: Duration,
+    factor: f64,
+) -> u64 {
+    let ratio = match source_duration.is_zero() {
+        true => 1.0,
+        false => (output_duration.as_secs_f64() / source_duration.as_secs_f64()).min(1.0),
+    };
+
+    (source_size as f64 * ratio * factor) as u64
+}
+
+/// 進捗の表示に付ける, 現在の出力の大きさと見込み.
+pub fn progress_note(bytes: u64, fraction: f64) -> Option<String> {
+    let current = format_size(bytes, config::size_format());
+    match (bytes, project(bytes, fraction)) {
+        (0, _) => None,
+        (_, Some(projected)) if fraction < 1.0 => Some(format!(
+            "{} (見込み {})",
+            current,
+            format_size(projected, config::size_format())
+        )),
+        _ => Some(current),
+    }
+}
+
+/// 上限を超えた, または超える見込みになったときの大きさ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Overrun {
+    pub bytes: u64,
+    pub projected: u64,
+    pub limit: u64,
+}
+
+impl fmt::Display for Overrun {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "中止: 元より大きくなるため (現在: {}, 見込み: {}, 上限: {})",
+            format_size(self.bytes, config::size_format()),
+            format_size(self.projected, config::size_format()),
+            format_size(self.limit, config::size_format())
+        )
+    }
+}
+
+/// エンコード中の出力の大きさの上限. 超えた, または超える見込みになった時点で `cancel` を中断する.
+#[derive(Debug, Clone)]
+pub struct SizeLimit {
+    limit: u64,
+    cancel: CancelToken,
+    overrun: Arc<Mutex<Option<Overrun>>>,
+}
+
+impl SizeLimit {
+    pub fn new(limit: u64, cancel: CancelToken) -> Self {
+        Self {
+            limit,
+            cancel,
+            overrun: Arc::default(),
+        }
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// 現在の大きさと進み具合を確かめ, 上限を超える場合は中断する.
+    pub fn check(&self, bytes: u64, fraction: f64) -> Option<Overrun> {
+        let projected = project(bytes, fraction).unwrap_or(bytes).max(bytes);
+        if projected <= self.limit {
+            return None;
+        }
+
+        let mut overrun = self.overrun.lock().unwrap();
+        if overrun.is_none() {
+            *overrun = Some(Overrun {
+                bytes,
+                projected,
+                limit: self.limit,
+            });
+            self.cancel.cancel();
+        }
+        *overrun
+    }
+
+    /// 上限によって中断した場合, そのときの大きさ.
+    pub fn overrun(&self) -> Option<Overrun> {
+        *self.overrun.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_project() {
+        assert_eq!(project(100, 0.05), None);
+        assert_eq!(project(100, 0.25), Some(400));
+        assert_eq!(project(100, 1.5), Some(100));
+
+        let secs = Duration::from_secs;
+        assert_eq!(source_limit(1000, secs(100), secs(100), 1.0), 1000);
+        assert_eq!(source_limit(1000, secs(100), secs(25), 0.8), 200);
+        assert_eq!(
+            source_limit(1000, Duration::ZERO, Duration::ZERO, 1.0),
+            1000
+        );
+
+        assert_eq!(progress_note(0, 0.5), None);
+        // 単位は設定によるので, 見込みの有無だけを確かめる
+        assert!(!progress_note(1024, 0.05).unwrap().contains("見込み"));
+        assert!(progress_note(1024, 0.5).unwrap().contains("見込み"));
+        assert!(!progress_note(1024, 1.0).unwrap().contains("見込み"));
+    }
+
+    #[test]
+    fn test_size_limit() {
+        let task = CancelToken::new();
+        let cancel = task.child();
+        let limit = SizeLimit::new(1000, cancel.clone());
+
+        // 冒頭は見込みではなく, 現在の大きさだけで判断する
+        assert_eq!(limit.check(900, 0.05), None);
+        assert_eq!(limit.check(400, 0.5), None);
+        assert!(!cancel.is_cancelled());
+
+        // 上限を超える前に, 見込みで中断する
+        let overrun = limit.check(300, 0.2).unwrap();
+        assert_eq!(overrun.projected, 1500);
+        assert!(cancel.is_cancelled());
+        assert!(!task.is_cancelled());
+        assert_eq!(limit.check(2000, 0.9), Some(overrun));
+        assert_eq!(limit.overrun(), Some(overrun));
+        assert!(overrun
+            .to_string()
+            .starts_with("中止: 元より大きくなるため"));
+
+        let limit = SizeLimit::new(1000, CancelToken::new());
+        assert_eq!(limit.check(1001, 0.01).unwrap().projected, 1001);
+    }
+}
@@ -0,0 +1,283 @@
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::schedule::Clock;
+
+/// 停止していないか確かめる間隔.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// `--stall-timeout` の時間をそのまま使う解像度の画素数 (1080p).
+pub const REFERENCE_PIXELS: u64 = 1920 * 1080;
+
+/// フレーム数が増えないまま `idle` が過ぎたときの状態.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stall {
+    /// 最後に進んだときのフレーム数.
+    pub frame: u64,
+    pub idle: Duration,
+}
+
+/// `StallWatch` が ffmpeg を止めるべきと判断した理由.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    Stalled(Stall),
+    /// 進んでいても, 開始から `--timeout` の時間が過ぎた.
+    TimedOut(Duration),
+}
+
+struct State {
+    frame: u64,
+    changed_at: Instant,
+    stopped: Option<Stop>,
+    finished: bool,
+}
+
+/// ffmpeg のフレーム数が増えなくなったことを検出する. ネットワークの読み込みで止まっている場合なども
+/// ffmpeg は同じフレーム数の進捗を出し続けるので, 進捗が届いた時刻ではなくフレーム数が増えた時刻で判断する.
+/// `with_limit` で, 進んでいるかどうかに関わらない 1 回のエンコードの時間の上限も確かめる.
+pub struct StallWatch {
+    clock: Arc<dyn Clock>,
+    timeout: Option<Duration>,
+    limit: Option<Duration>,
+    started_at: Instant,
+    state: Mutex<State>,
+    finished: Condvar,
+}
+
+impl StallWatch {
+    /// 一時停止した時間を除く場合は `PauseControl` を渡す. `timeout` が `None` なら停止は検出しない.
+    pub fn new(clock: Arc<dyn Clock>, timeout: Option<Duration>) -> Self {
+        let started_at = clock.now();
+        Self {
+            clock,
+            timeout,
+            limit: None,
+            started_at,
+            state: Mutex::new(State {
+                frame: 0,
+                changed_at: started_at,
+                stopped: None,
+                finished: false,
+            }),
+            finished: Condvar::new(),
+        }
+    }
+
+    pub fn with_limit(self, limit: Option<Duration>) -> Self {
+        Self { limit, ..self }
+    }
+
+    /// 進捗で届いたフレーム数を記録する. 増えた場合だけ時刻を更新する.
+    pub fn observe(&self, frame: u64) {
+        let mut state = self.state.lock().unwrap();
+        if frame > state.frame {
+            state.frame = frame;
+            state.changed_at = self.clock.now();
+        }
+    }
+
+    /// 停止しているか上限を過ぎていれば記録して返す. 一度止めると判断した後は, その時の状態を返し続ける.
+    pub fn check(&self) -> Option<Stop> {
+        let mut state = self.state.lock().unwrap();
+        if state.stopped.is_none() {
+            let now = self.clock.now();
+            let idle = now.saturating_duration_since(state.changed_at);
+            let elapsed = now.saturating_duration_since(self.started_at);
+            state.stopped = match (self.timeout, self.limit) {
+                (_, Some(limit)) if elapsed >= limit => Some(Stop::TimedOut(limit)),
+                (Some(timeout), _) if idle >= timeout => Some(Stop::Stalled(Stall {
+                    frame: state.frame,
+                    idle,
+                })),
+                _ => None,
+            };
+        }
+        state.stopped
+    }
+
+    pub fn stopped(&self) -> Option<Stop> {
+        self.state.lock().unwrap().stopped
+    }
+
+    /// `finish` が呼ばれるまで `interval` ごとに確かめ, 止めると判断した時点で `on_stop` を呼んで終わる.
+    pub fn watch(&self, interval: Duration, on_stop: impl FnOnce(Stop)) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.finished {
+                return;
+            }
+            drop(state);
+            if let Some(stop) = self.check() {
+                on_stop(stop);
+                return;
+            }
+            state = self.state.lock().unwrap();
+            if !state.finished {
+                state = self.finished.wait_timeout(state, interval).unwrap().0;
+            }
+        }
+    }
+
+    /// `watch` を終わらせる.
+    pub fn finish(&self) {
+        self.state.lock().unwrap().finished = true;
+        self.finished.notify_all();
+    }
+}
+
+/// 1 フレームにかかる時間は画素数にほぼ比例するので, 1080p を超える解像度では停止とみなすまでの時間を延ばす.
+/// 8K では 16 倍になる.
+pub fn scaled_timeout(timeout: Duration, (width, height): (u32, u32)) -> Duration {
+    let factor = (width as u64 * height as u64) as f64 / REFERENCE_PIXELS as f64;
+    timeout.mul_f64(factor.max(1.0))
+}
+
+/// 停止した ffmpeg を終了させる. 応答しなくなっていることがあるので `SIGKILL` を送る.
+#[cfg(unix)]
+pub fn kill(pid: u32) {
+    // 0 や負の値はプロセスグループ全体への送信になるので送らない
+    let Some(pid) = libc::pid_t::try_from(pid).ok().filter(|pid| *pid > 0) else {
+        return;
+    };
+    // SAFETY: 自分で起動した子プロセスの PID にシグナルを送るだけ
+    unsafe {
+        libc::kill(pid, libc::SIGKILL);
+    }
+}
+
+/// シグナルを送れない環境では, 次の進捗が届いた時点で `run` が終了させる.
+#[cfg(not(unix))]
+pub fn kill(_pid: u32) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[derive(Clone)]
+    struct MockClock(Arc<Mutex<Instant>>);
+
+    impl MockClock {
+        fn advance(&self, secs: u64) {
+            *self.0.lock().unwrap() += Duration::from_secs(secs);
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn clock() -> MockClock {
+        MockClock(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    #[test]
+    fn test_stall_on_unchanged_frames() {
+        let clock = clock();
+        let watch = StallWatch::new(Arc::new(clock.clone()), Some(Duration::from_secs(600)));
+
+        // フレーム数が増えている間は止まっていない
+        for frame in [30, 60, 90] {
+            clock.advance(300);
+            watch.observe(frame);
+            assert_eq!(watch.check(), None);
+        }
+
+        // 同じフレーム数の進捗が届き続けても, 最後に増えた時刻から数える
+        for _ in 0..5 {
+            clock.advance(100);
+            watch.observe(90);
+            watch.observe(60);
+        }
+        assert_eq!(watch.check(), None);
+        clock.advance(100);
+        let stall = watch.check().unwrap();
+        assert_eq!(
+            stall,
+            Stop::Stalled(Stall {
+                frame: 90,
+                idle: Duration::from_secs(600)
+            })
+        );
+
+        // 一度止まったと判断した後は, その時の状態のまま
+        watch.observe(120);
+        clock.advance(1000);
+        assert_eq!(watch.check(), Some(stall));
+        assert_eq!(watch.stopped(), Some(stall));
+
+        // 最初のフレームが出ないまま止まった場合も検出する
+        let watch = StallWatch::new(Arc::new(clock.clone()), Some(Duration::from_secs(10)));
+        clock.advance(10);
+        assert!(matches!(
+            watch.check(),
+            Some(Stop::Stalled(Stall { frame: 0, .. }))
+        ));
+    }
+
+    #[test]
+    fn test_limit() {
+        let clock = clock();
+        let watch = StallWatch::new(Arc::new(clock.clone()), Some(Duration::from_secs(60)))
+            .with_limit(Some(Duration::from_secs(100)));
+        // 進んでいても上限を過ぎれば止める
+        for frame in 1..=3 {
+            clock.advance(30);
+            watch.observe(frame);
+            assert_eq!(watch.check(), None);
+        }
+        clock.advance(10);
+        assert_eq!(
+            watch.check(),
+            Some(Stop::TimedOut(Duration::from_secs(100)))
+        );
+
+        // 停止の検出を使わずに上限だけを確かめることもできる
+        let watch =
+            StallWatch::new(Arc::new(clock.clone()), None).with_limit(Some(Duration::from_secs(5)));
+        clock.advance(4);
+        assert_eq!(watch.check(), None);
+        clock.advance(1);
+        assert!(matches!(watch.check(), Some(Stop::TimedOut(_))));
+
+        assert_eq!(
+            scaled_timeout(Duration::from_secs(60), (1280, 720)),
+            Duration::from_secs(60)
+        );
+        assert_eq!(
+            scaled_timeout(Duration::from_secs(60), (7680, 4320)),
+            Duration::from_secs(960)
+        );
+    }
+
+    #[test]
+    fn test_watch_loop() {
+        let clock = clock();
+        let watch = StallWatch::new(Arc::new(clock.clone()), Some(Duration::from_secs(60)));
+        let found = thread::scope(|scope| {
+            let found = scope.spawn(|| {
+                let mut found = None;
+                watch.watch(Duration::from_millis(1), |stop| found = Some(stop));
+                found
+            });
+            watch.observe(10);
+            clock.advance(61);
+            found.join().unwrap()
+        });
+        assert!(matches!(
+            found,
+            Some(Stop::Stalled(Stall { frame: 10, .. }))
+        ));
+
+        // 停止する前に終わった場合は呼ばない
+        let watch = StallWatch::new(Arc::new(clock.clone()), Some(Duration::from_secs(60)));
+        thread::scope(|scope| {
+            scope.spawn(|| watch.watch(Duration::from_secs(3600), |_| panic!("停止していない")));
+            watch.finish();
+        });
+        assert_eq!(watch.stopped(), None);
+    }
+}
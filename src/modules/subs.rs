@@ -0,0 +1,226 @@
+use anyhow::{anyhow, Context, Result};
+use ffmpeg_sidecar::event::Stream;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use super::{ffmpeg, file, sandbox};
+
+/// 画像として記録された字幕. SRT などのテキストには変換できない.
+const IMAGE_CODECS: &[&str] = &["hdmv_pgs_subtitle", "dvd_subtitle", "dvb_subtitle", "xsub"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubtitleTrack {
+    /// 字幕ストリームの中での番号 (`--track` で指定する値).
+    pub index: usize,
+    /// 入力の中でのストリームの番号.
+    pub stream_index: u32,
+    pub codec: String,
+    pub language: Option<String>,
+    pub default: bool,
+    pub forced: bool,
+}
+
+impl SubtitleTrack {
+    pub fn is_image(&self) -> bool {
+        IMAGE_CODECS.contains(&self.codec.as_str())
+    }
+
+    /// 書き出す拡張子と `-c:s` に渡す値. ASS はスタイルを保つためにそのまま, 他のテキストは SRT に変換し,
+    /// 画像の字幕は元の形式のまま書き出す.
+    pub fn target(&self) -> (&'static str, &'static str) {
+        match self.codec.as_str() {
+            "ass" | "ssa" => ("ass", "copy"),
+            "subrip" | "srt" => ("srt", "copy"),
+            "hdmv_pgs_subtitle" => ("sup", "copy"),
+            codec if IMAGE_CODECS.contains(&codec) => ("mks", "copy"),
+            _ => ("srt", "srt"),
+        }
+    }
+
+    pub fn language_or_und(&self) -> &str {
+        self.language.as_deref().unwrap_or("und")
+    }
+}
+
+/// 入力のストリームから字幕を列挙する.
+pub fn tracks(streams: &[Stream]) -> Vec<SubtitleTrack> {
+    streams
+        .iter()
+        .filter(|s| s.is_subtitle())
+        .enumerate()
+        .map(|(index, s)| {
+            let flags = s.raw_log_message.to_lowercase();
+            SubtitleTrack {
+                index,
+                stream_index: s.stream_index,
+                codec: s.format.clone(),
+                language: (!s.language.is_empty()).then(|| s.language.clone()),
+                default: flags.contains("(default)"),
+                forced: flags.contains("(forced)"),
+            }
+        })
+        .collect()
+}
+
+/// `{入力の名前}.{言語}.{拡張子}`. 同じ言語の字幕が複数ある場合は番号を付けて区別する.
+pub fn output_path(
+    dir: &Path,
+    input_path: &str,
+    track: &SubtitleTrack,
+    all: &[SubtitleTrack],
+) -> PathBuf {
+    let (name, _) = file::get_file_name(input_path);
+    let (ext, _) = track.target();
+    let language = track.language_or_und();
+    let shared = all
+        .iter()
+        .filter(|t| t.language_or_und() == language)
+        .count()
+        > 1;
+    let mut file_name = format!("{}.{}", name, language);
+    if shared {
+        file_name.push_str(&format!(".{}", track.index));
+    }
+    if track.forced {
+        file_name.push_str(".forced");
+    }
+
+    dir.join(format!("{}.{}", file_name, ext))
+}
+
+pub fn extract_args(input_path: &str, track: &SubtitleTrack, output: &Path) -> Vec<String> {
+    let (_, codec) = track.target();
+    [
+        "-y",
+        "-v",
+        "error",
+        "-i",
+        input_path,
+        "-map",
+        &format!("0:{}", track.stream_index),
+        "-c:s",
+        codec,
+        &output.to_string_lossy(),
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+/// `tracks` の字幕を `dir` に書き出す.
+pub fn extract(
+    dir: &Path,
+    input_path: &str,
+    tracks: &[&SubtitleTrack],
+    all: &[SubtitleTrack],
+) -> Result<Vec<PathBuf>> {
+    fs::create_dir_all(dir)
+        .with_context(|| format!("字幕の保存先の作成に失敗しました: {}", dir.display()))?;
+    tracks
+        .iter()
+        .map(|track| {
+            let path = output_path(dir, input_path, track, all);
+            let mut command = Command::new(ffmpeg::ffmpeg_path());
+            command.args(extract_args(input_path, track, &path));
+            let output = sandbox::confine(&mut command)
+                .and_then(|_| command.output())
+                .context("字幕の書き出しの実行に失敗しました.")?;
+            match output.status.success() {
+                true => Ok(path),
+                false => Err(anyhow!(
+                    "字幕の書き出しに失敗しました ({}): {}",
+                    path.display(),
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::video;
+    use ffmpeg_sidecar::{event::FfmpegEvent, log_parser::FfmpegLogParser};
+
+    const MULTI_TRACK_LOG: &str = "\
+[info] Input #0, matroska,webm, from 'assets/movie.mkv':
+[info]   Duration: 00:01:40.00, start: 0.000000, bitrate: 4000 kb/s
+[info]   Stream #0:0: Video: h264 (High), yuv420p(progressive), 1920x1080, 23.98 fps, 23.98 tbr, 1k tbn (default)
+[info]   Stream #0:1(jpn): Audio: aac (LC), 48000 Hz, stereo, fltp (default)
+[info]   Stream #0:2(jpn): Subtitle: ass (default)
+[info]   Stream #0:3(eng): Subtitle: subrip
+[info]   Stream #0:4(eng): Subtitle: subrip (forced)
+[info]   Stream #0:5(jpn): Subtitle: hdmv_pgs_subtitle, 1920x1080
+[info]   Stream #0:6: Subtitle: mov_text (tx3g / 0x67337874)
+";
+
+    fn fixture_tracks() -> Vec<SubtitleTrack> {
+        let mut parser = FfmpegLogParser::new(MULTI_TRACK_LOG.as_bytes());
+        let events = std::iter::from_fn(|| match parser.parse_next_event() {
+            Ok(FfmpegEvent::LogEOF) | Err(_) => None,
+            Ok(e) => Some(e),
+        });
+        tracks(&video::list_streams(events).unwrap())
+    }
+
+    #[test]
+    fn test_tracks() {
+        let tracks = fixture_tracks();
+        let summary = tracks
+            .iter()
+            .map(|t| {
+                (
+                    t.stream_index,
+                    t.codec.as_str(),
+                    t.language.as_deref(),
+                    t.default,
+                    t.forced,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (2, "ass", Some("jpn"), true, false),
+                (3, "subrip", Some("eng"), false, false),
+                (4, "subrip", Some("eng"), false, true),
+                (5, "hdmv_pgs_subtitle", Some("jpn"), false, false),
+                (6, "mov_text", None, false, false),
+            ]
+        );
+        assert!(tracks[3].is_image());
+        assert!(!tracks[0].is_image());
+    }
+
+    #[test]
+    fn test_output_paths() {
+        let tracks = fixture_tracks();
+        let dir = Path::new("out/subs");
+        let paths = tracks
+            .iter()
+            .map(|t| output_path(dir, "assets/movie.mkv", t, &tracks))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            [
+                "out/subs/movie.jpn.0.ass",
+                "out/subs/movie.eng.1.srt",
+                "out/subs/movie.eng.2.forced.srt",
+                "out/subs/movie.jpn.3.sup",
+                "out/subs/movie.und.srt",
+            ]
+            .map(PathBuf::from)
+        );
+
+        // ASS と SRT, 画像の字幕はそのまま, 他のテキストは SRT に変換する
+        let args = extract_args("assets/movie.mkv", &tracks[0], &paths[0]);
+        assert!(args.windows(2).any(|w| w == ["-map", "0:2"]));
+        assert!(args.windows(2).any(|w| w == ["-c:s", "copy"]));
+        let args = extract_args("assets/movie.mkv", &tracks[4], &paths[4]);
+        assert!(args.windows(2).any(|w| w == ["-c:s", "srt"]));
+        assert_eq!(tracks[3].target(), ("sup", "copy"));
+    }
+}
@@ -0,0 +1,57 @@
+//! 単体テストの共通部分. 結合テスト (`tests/common`) からも `#[path]` で読み込んで使う.
+
+use std::{
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+/// ffmpeg を実際に動かすテストを実行する環境変数.
+pub const FFMPEG_TESTS_ENV: &str = "VVCNV_FFMPEG_TESTS";
+
+/// `VVCNV_FFMPEG_TESTS=1` を指定した場合だけ ffmpeg を使うテストを実行する.
+/// 指定していなければ `false` を返すので, テストはそのまま終える.
+pub fn ffmpeg_tests_enabled(name: &str) -> bool {
+    let enabled = std::env::var(FFMPEG_TESTS_ENV).is_ok_and(|v| v == "1");
+    if !enabled {
+        eprintln!(
+            "{} を指定していないため, {} を実行しません",
+            FFMPEG_TESTS_ENV, name
+        );
+    }
+    enabled
+}
+
+/// テスト用の一時ディレクトリ `vvcnv-<name>-<pid>`. 作るときに前回の残りを消し, drop で中身ごと消す.
+/// テストは並列に実行されるので, `name` はテストごとに変える.
+#[derive(Debug)]
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("vvcnv-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,178 @@
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+/// 切り詰めたことを示す記号. 表示幅は 1.
+pub const ELLIPSIS: char = '…';
+
+/// `--ascii-names` で, 元の名前が何も残らなかった場合に使う名前.
+pub const FALLBACK_STEM: &str = "video";
+
+/// 端末での表示幅. 全角文字や絵文字は 2, 結合文字は 0 として数える.
+pub fn width(s: &str) -> usize {
+    s.width()
+}
+
+/// 表示幅が `max` を超える場合は末尾を `…` にして切り詰める. 結合文字は直前の文字と一緒に残す.
+pub fn truncate(s: &str, max: usize) -> String {
+    if width(s) <= max {
+        return s.to_string();
+    }
+
+    let budget = max.saturating_sub(1);
+    let mut out = String::new();
+    let mut used = 0;
+    for c in s.chars() {
+        let w = c.width().unwrap_or(0);
+        if used + w > budget {
+            break;
+        }
+        used += w;
+        out.push(c);
+    }
+    if max > 0 {
+        out.push(ELLIPSIS);
+    }
+
+    out
+}
+
+/// 表示幅が `to` になるまで末尾に空白を足す.
+pub fn pad(s: &str, to: usize) -> String {
+    format!("{}{}", s, " ".repeat(to.saturating_sub(width(s))))
+}
+
+/// 各行を表示幅 `max` までに切り詰め, 最も長い行に合わせて揃える.
+pub fn align(lines: &[String], max: usize) -> Vec<String> {
+    let lines = lines.iter().map(|l| truncate(l, max)).collect::<Vec<_>>();
+    let to = lines.iter().map(|l| width(l)).max().unwrap_or(0);
+    lines.iter().map(|l| pad(l, to)).collect()
+}
+
+/// よく使うラテン文字の, 記号を除いた読み. 結合文字は `ascii_stem` で取り除く.
+fn fold_latin(c: char) -> Option<&'static str> {
+    let folded = match c {
+        'À'..='Å' => "A",
+        'à'..='å' => "a",
+        'Æ' => "AE",
+        'æ' => "ae",
+        'Ç' => "C",
+        'ç' => "c",
+        'È'..='Ë' => "E",
+        'è'..='ë' => "e",
+        'Ì'..='Ï' => "I",
+        'ì'..='ï' => "i",
+        'Ñ' => "N",
+        'ñ' => "n",
+        'Ò'..='Ö' | 'Ø' => "O",
+        'ò'..='ö' | 'ø' => "o",
+        'Ù'..='Ü' => "U",
+        'ù'..='ü' => "u",
+        'Ý' => "Y",
+        'ý' | 'ÿ' => "y",
+        'ß' => "ss",
+        _ => return None,
+    };
+    Some(folded)
+}
+
+fn is_combining(c: char) -> bool {
+    matches!(c, '\u{0300}'..='\u{036F}' | '\u{1AB0}'..='\u{1AFF}' | '\u{20D0}'..='\u{20FF}' | '\u{FE20}'..='\u{FE2F}')
+}
+
+/// 出力のファイル名に使う, ASCII だけの名前. ラテン文字は記号を外して残し,
+/// それ以外の文字やファイルシステムで使えない文字は `_` にまとめる.
+pub fn ascii_stem(stem: &str) -> String {
+    let mut out = String::new();
+    let mut push = |s: &str| {
+        for c in s.chars() {
+            let c = match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '.' => c,
+                _ => '_',
+            };
+            if !(c == '_' && out.ends_with('_')) {
+                out.push(c);
+            }
+        }
+    };
+    for c in stem.chars().filter(|c| !is_combining(*c)) {
+        match fold_latin(c) {
+            Some(folded) => push(folded),
+            None => push(c.encode_utf8(&mut [0; 4])),
+        }
+    }
+
+    let trimmed = out.trim_matches(|c| c == '_' || c == '.');
+    match trimmed.is_empty() {
+        true => FALLBACK_STEM.to_string(),
+        false => trimmed.to_string(),
+    }
+}
+
+/// シェルにそのまま貼り付けられるように, 必要な場合だけ `'` で囲む.
+pub fn shell_word(s: &str) -> String {
+    let plain = !s.is_empty()
+        && s.chars().all(|c| {
+            c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/' | ':' | '=' | ',' | '+')
+        });
+    match plain {
+        true => s.to_string(),
+        false => format!("'{}'", s.replace('\'', "'\\''")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_width_and_truncate() {
+        assert_eq!(width("clip"), 4);
+        assert_eq!(width("会議録画"), 8);
+        assert_eq!(width("🎥"), 2);
+        // 結合文字は幅を持たない
+        assert_eq!(width("e\u{301}"), 1);
+
+        assert_eq!(truncate("clip", 4), "clip");
+        assert_eq!(truncate("会議録画🎥 2024", 7), "会議録…");
+        assert_eq!(width(&truncate("会議録画🎥 2024", 7)), 7);
+        // 全角文字の途中で切れる場合は 1 文字分短くする
+        assert_eq!(truncate("会議録画", 6), "会議…");
+        assert_eq!(truncate("cafe\u{301} latte", 5), "cafe\u{301}…");
+        assert_eq!(truncate("🎥🎥", 0), "");
+
+        let aligned = align(
+            &[
+                "会議録画🎥".to_string(),
+                "clip".to_string(),
+                "cafe\u{301}".to_string(),
+            ],
+            20,
+        );
+        assert!(aligned.iter().all(|l| width(l) == 10));
+        let aligned = align(&["会議録画🎥 2024".to_string(), "clip".to_string()], 8);
+        assert_eq!(aligned, ["会議録…".to_string(), "clip   ".to_string()]);
+    }
+
+    #[test]
+    fn test_ascii_stem() {
+        assert_eq!(ascii_stem("clip-01"), "clip-01");
+        assert_eq!(ascii_stem("会議録画🎥 2024"), "2024");
+        assert_eq!(ascii_stem("Café déjà vu"), "Cafe_deja_vu");
+        assert_eq!(ascii_stem("cafe\u{301}"), "cafe");
+        assert_eq!(ascii_stem("a:b/c?d"), "a_b_c_d");
+        assert_eq!(ascii_stem("週報 🎥 v2.final"), "v2.final");
+        assert_eq!(ascii_stem("会議録画🎥"), FALLBACK_STEM);
+        assert_eq!(ascii_stem(".hidden"), "hidden");
+    }
+
+    #[test]
+    fn test_shell_word() {
+        assert_eq!(shell_word("-filter:v:0"), "-filter:v:0");
+        assert_eq!(
+            shell_word("scale=854:480,setsar=1"),
+            "scale=854:480,setsar=1"
+        );
+        assert_eq!(shell_word("out/会議 録画.mp4"), "'out/会議 録画.mp4'");
+        assert_eq!(shell_word("it's"), "'it'\\''s'");
+        assert_eq!(shell_word(""), "''");
+    }
+}
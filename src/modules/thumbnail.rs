@@ -0,0 +1,307 @@
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+
+use super::{ffmpeg, file, sandbox, video::Trim};
+
+/// `select='gt(scene,X)'` の閾値. これより変化の小さいフレームは候補にしない.
+pub const SCENE_THRESHOLD: f64 = 0.3;
+pub const THUMBNAIL_DIR: &str = "thumbnails";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThumbnailMode {
+    /// 変化の大きいシーンの切り替わりを N 個選ぶ.
+    Scene(usize),
+}
+
+/// `scene:N` の形式.
+pub fn parse_mode(input: &str) -> Result<ThumbnailMode, String> {
+    let count = input
+        .strip_prefix("scene:")
+        .ok_or_else(|| format!("scene:N の形式で指定してください: {}", input))?;
+    match count.parse::<usize>() {
+        Ok(count) if count > 0 => Ok(ThumbnailMode::Scene(count)),
+        _ => Err(format!(
+            "枚数には 1 以上の整数を指定してください: {}",
+            count
+        )),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SceneChange {
+    pub at: Duration,
+    pub score: f64,
+}
+
+/// `metadata=print` の出力から, フレームの時刻とシーン変化のスコアを読む.
+/// ```text
+/// frame:0    pts:6144    pts_time:0.4
+/// lavfi.scene_score=0.512
+/// ```
+pub fn parse_scene_metadata(text: &str) -> Vec<SceneChange> {
+    let mut scenes = Vec::new();
+    let mut at = None;
+    for line in text.lines().map(str::trim) {
+        if line.starts_with("frame:") {
+            at = line
+                .split_whitespace()
+                .find_map(|field| field.strip_prefix("pts_time:"))
+                .and_then(|t| t.parse::<f64>().ok())
+                .and_then(|t| Duration::try_from_secs_f64(t).ok());
+        } else if let Some(score) = line.strip_prefix("lavfi.scene_score=") {
+            if let (Some(at), Ok(score)) = (at.take(), score.parse()) {
+                scenes.push(SceneChange { at, score });
+            }
+        }
+    }
+
+    scenes
+}
+
+/// スコアの高い順に `count` 個選び, 時刻順に並べる. 同じスコアであれば先のシーンを優先する.
+pub fn pick_scenes(scenes: &[SceneChange], count: usize) -> Vec<Duration> {
+    let mut ranked = scenes.iter().collect::<Vec<_>>();
+    ranked.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.at.cmp(&b.at)));
+    let mut picked = ranked
+        .into_iter()
+        .take(count)
+        .map(|s| s.at)
+        .collect::<Vec<_>>();
+    picked.sort();
+
+    picked
+}
+
+pub fn detect_scenes(input_path: &str) -> Result<Vec<SceneChange>> {
+    let mut command = Command::new(ffmpeg::ffmpeg_path());
+    command
+        .args(["-hide_banner", "-nostats", "-i", input_path, "-vf"])
+        .arg(format!(
+            "select='gt(scene,{})',metadata=print:file=-",
+            SCENE_THRESHOLD
+        ))
+        .args(["-an", "-f", "null", "-"]);
+    let output = sandbox::confine(&mut command)
+        .and_then(|_| command.output())
+        .context("シーン検出の実行に失敗しました.")?;
+    if !output.status.success() {
+        bail!(
+            "シーン検出に失敗しました: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(parse_scene_metadata(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SceneCache {
+    input_size: u64,
+    input_modified_secs: u64,
+    scenes: Vec<SceneChange>,
+}
+
+pub fn cache_path(dir: &Path, input_path: &str) -> PathBuf {
+    let (name, _) = file::get_file_name(input_path);
+    dir.join(format!(".{}.scenes.json", name))
+}
+
+/// シーン検出は元動画を最後までデコードするので, 結果を `dir` に保存して同じ入力では使い回す.
+pub fn load_or_detect(dir: &Path, input_path: &str) -> Result<Vec<SceneChange>> {
+    let path = cache_path(dir, input_path);
+    let (input_size, input_modified_secs) = file::cache_key(input_path)?;
+    let cached = fs::read_to_string(&path)
+        .ok()
+        .and_then(|json| serde_json::from_str::<SceneCache>(&json).ok())
+        .filter(|c| c.input_size == input_size && c.input_modified_secs == input_modified_secs);
+    if let Some(cache) = cached {
+        return Ok(cache.scenes);
+    }
+
+    let scenes = detect_scenes(input_path)?;
+    let cache = SceneCache {
+        input_size,
+        input_modified_secs,
+        scenes,
+    };
+    fs::create_dir_all(dir)
+        .and_then(|_| fs::write(&path, serde_json::to_string(&cache)?))
+        .with_context(|| format!("シーン検出の結果の保存に失敗しました: {}", path.display()))?;
+
+    Ok(cache.scenes)
+}
+
+/// 切り出す範囲内のシーンから `count` 個選び, 元動画での時刻を出力での時刻に直す.
+/// シーンの時刻はタイムスタンプそのものなので, 元動画の最初のタイムスタンプ `origin` を引いてから比べる.
+/// 出力ごとに FPS が異なるため, フレーム番号ではなく時刻で揃える.
+pub fn output_timestamps(
+    scenes: &[SceneChange],
+    origin: Duration,
+    trim: &Trim,
+    count: usize,
+) -> Vec<Duration> {
+    let start = trim.start.unwrap_or_default();
+    let in_range = scenes
+        .iter()
+        .map(|s| SceneChange {
+            at: s.at.saturating_sub(origin),
+            score: s.score,
+        })
+        .filter(|s| s.at >= start && trim.end.is_none_or(|end| s.at < end))
+        .collect::<Vec<_>>();
+
+    pick_scenes(&in_range, count)
+        .into_iter()
+        .map(|at| at - start)
+        .collect()
+}
+
+pub fn thumbnail_path(dir: &Path, output_path: &str, at: Duration) -> PathBuf {
+    let (name, _) = file::get_file_name(output_path);
+    dir.join(format!("{}--t-{:08}.jpg", name, at.as_millis()))
+}
+
+pub fn extract_args(output_path: &str, at: Duration, thumbnail: &Path) -> Vec<String> {
+    [
+        "-y",
+        "-v",
+        "error",
+        "-ss",
+        &format!("{:.3}", at.as_secs_f64()),
+        "-i",
+        output_path,
+        "-frames:v",
+        "1",
+        "-q:v",
+        "2",
+        &thumbnail.to_string_lossy(),
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+/// `output_path` から `timestamps` の各時刻のフレームを書き出す.
+pub fn extract(dir: &Path, output_path: &str, timestamps: &[Duration]) -> Result<Vec<PathBuf>> {
+    fs::create_dir_all(dir)
+        .with_context(|| format!("サムネイルの保存先の作成に失敗しました: {}", dir.display()))?;
+    timestamps
+        .iter()
+        .map(|at| {
+            let thumbnail = thumbnail_path(dir, output_path, *at);
+            let mut command = Command::new(ffmpeg::ffmpeg_path());
+            command.args(extract_args(output_path, *at, &thumbnail));
+            let output = sandbox::confine(&mut command)
+                .and_then(|_| command.output())
+                .context("サムネイルの書き出しの実行に失敗しました.")?;
+            match output.status.success() && thumbnail.exists() {
+                true => Ok(thumbnail),
+                false => Err(anyhow!(
+                    "サムネイルの書き出しに失敗しました ({}): {}",
+                    thumbnail.display(),
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::video::SeekMode;
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("scene:8"), Ok(ThumbnailMode::Scene(8)));
+        assert!(parse_mode("scene:0").is_err());
+        assert!(parse_mode("scene:").is_err());
+        assert!(parse_mode("even:8").is_err());
+    }
+
+    #[test]
+    fn test_parse_scene_metadata() {
+        let text = "\
+frame:0    pts:6144    pts_time:0.4
+lavfi.scene_score=0.512000
+frame:1    pts:92160   pts_time:6
+lavfi.scene_score=0.934
+frame:2    pts:abc     pts_time:x
+lavfi.scene_score=0.7
+frame:3    pts:153600  pts_time:10
+lavfi.scene_score=0.31
+";
+        let scenes = parse_scene_metadata(text);
+        assert_eq!(
+            scenes,
+            vec![
+                SceneChange {
+                    at: Duration::from_millis(400),
+                    score: 0.512
+                },
+                SceneChange {
+                    at: Duration::from_secs(6),
+                    score: 0.934
+                },
+                SceneChange {
+                    at: Duration::from_secs(10),
+                    score: 0.31
+                },
+            ]
+        );
+        assert_eq!(
+            pick_scenes(&scenes, 2),
+            vec![Duration::from_millis(400), Duration::from_secs(6)]
+        );
+        assert_eq!(pick_scenes(&scenes, 10).len(), 3);
+    }
+
+    #[test]
+    fn test_timestamps_align_across_fps() {
+        let scenes = [(5, 0.9), (12, 0.5), (30, 0.4), (45, 0.35), (61, 0.8)].map(|(at, score)| {
+            SceneChange {
+                at: Duration::from_secs(at),
+                score,
+            }
+        });
+        let trim = Trim {
+            start: Some(Duration::from_secs(10)),
+            end: Some(Duration::from_secs(60)),
+            seek: SeekMode::Hybrid,
+        };
+        let timestamps = output_timestamps(&scenes, Duration::ZERO, &trim, 2);
+        assert_eq!(timestamps, [2, 20].map(Duration::from_secs));
+        // .m2ts のように最初のタイムスタンプが 0 でない場合は, その分ずらして範囲を判定する
+        assert_eq!(
+            output_timestamps(&scenes, Duration::from_secs(1), &trim, 2),
+            [1, 19].map(Duration::from_secs)
+        );
+
+        // FPS の異なる出力でも同じ時刻を指定して切り出す
+        let dir = Path::new("out/thumbnails");
+        let seeks = ["out/a--fps-24.mp4", "out/a--fps-60.mp4"].map(|output| {
+            timestamps
+                .iter()
+                .map(|at| {
+                    let args = extract_args(output, *at, &thumbnail_path(dir, output, *at));
+                    let i = args.iter().position(|a| a == "-ss").unwrap();
+                    assert!(args.iter().all(|a| !a.contains("select")));
+                    args[i + 1].clone()
+                })
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(seeks[0], ["2.000", "20.000"]);
+        assert_eq!(seeks[0], seeks[1]);
+        assert_eq!(
+            thumbnail_path(dir, "out/a--fps-24.mp4", Duration::from_secs(2)),
+            PathBuf::from("out/thumbnails/a--fps-24--t-00002000.jpg")
+        );
+    }
+}
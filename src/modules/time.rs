@@ -0,0 +1,364 @@
+use core::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DURATION_FORMATS: &str = "90, 1.5, 45s, 15m, 2h, 2h15m, 30d";
+const TIMESTAMP_FORMATS: &str = "90, 1:30, 00:01:30.250, 2h15m";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseDurationErr {
+    Empty,
+    Invalid(String),
+    InvalidTimestamp(String),
+    Negative(String),
+    DecimalComma(String),
+    Overflow(String),
+}
+
+impl fmt::Display for ParseDurationErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseDurationErr::Empty => write!(f, "時間が指定されていません"),
+            ParseDurationErr::Invalid(s) => write!(
+                f,
+                "時間の形式が不正です: \"{}\" (例: {})",
+                s, DURATION_FORMATS
+            ),
+            ParseDurationErr::InvalidTimestamp(s) => write!(
+                f,
+                "位置の形式が不正です: \"{}\" (例: {})",
+                s, TIMESTAMP_FORMATS
+            ),
+            ParseDurationErr::Negative(s) => {
+                write!(f, "負の時間は指定できません: \"{}\"", s)
+            }
+            ParseDurationErr::DecimalComma(s) => write!(
+                f,
+                "小数点には \".\" を使ってください: \"{}\" (例: 1.5, 00:01:30.250)",
+                s
+            ),
+            ParseDurationErr::Overflow(s) => write!(f, "時間が大きすぎます: \"{}\"", s),
+        }
+    }
+}
+
+impl std::error::Error for ParseDurationErr {}
+
+fn precheck(input: &str) -> Result<&str, ParseDurationErr> {
+    let trimmed = input.trim();
+    if trimmed.is_empty() {
+        Err(ParseDurationErr::Empty)
+    } else if trimmed.starts_with('-') {
+        Err(ParseDurationErr::Negative(input.to_string()))
+    } else if trimmed.contains(',') {
+        Err(ParseDurationErr::DecimalComma(input.to_string()))
+    } else {
+        Ok(trimmed)
+    }
+}
+
+/// 10 進表記の数値に `unit` 秒を掛けて, 浮動小数点を経由せずに `Duration` にする.
+/// 小数点以下は 9 桁 (ナノ秒) まで使い, それより細かい桁は切り捨てる.
+fn parse_decimal(
+    number: &str,
+    unit: u32,
+    input: &str,
+    invalid: impl Fn() -> ParseDurationErr,
+) -> Result<Duration, ParseDurationErr> {
+    let (int, frac) = number.split_once('.').unwrap_or((number, ""));
+    let is_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    if (int.is_empty() && frac.is_empty()) || !is_digits(int) || !is_digits(frac) {
+        return Err(invalid());
+    }
+    let overflow = || ParseDurationErr::Overflow(input.to_string());
+
+    let secs = match int.is_empty() {
+        true => 0,
+        false => int.parse::<u64>().map_err(|_| overflow())?,
+    };
+    let nanos = format!("{:0<9}", &frac[..frac.len().min(9)])
+        .parse::<u32>()
+        .map_err(|_| invalid())?;
+
+    Duration::new(secs, nanos)
+        .checked_mul(unit)
+        .ok_or_else(overflow)
+}
+
+pub fn parse_duration(input: &str) -> Result<Duration, ParseDurationErr> {
+    let trimmed = precheck(input)?;
+    let invalid = || ParseDurationErr::Invalid(input.to_string());
+    let overflow = || ParseDurationErr::Overflow(input.to_string());
+
+    let mut total = Duration::ZERO;
+    let mut number = String::new();
+    let mut prev_unit = None;
+    for c in trimmed.chars() {
+        let unit = match c {
+            'd' => 86400,
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => {
+                number.push(c);
+                continue;
+            }
+        };
+        // 単位は d, h, m, s の順に 1 回ずつ
+        if number.is_empty() || prev_unit.is_some_and(|prev| unit >= prev) {
+            return Err(invalid());
+        }
+        let value = parse_decimal(&number, unit, input, invalid)?;
+        total = total.checked_add(value).ok_or_else(overflow)?;
+        number.clear();
+        prev_unit = Some(unit);
+    }
+
+    match (prev_unit, number.is_empty()) {
+        (None, _) => parse_decimal(&number, 1, input, invalid),
+        (Some(_), true) => Ok(total),
+        (Some(_), false) => Err(invalid()),
+    }
+}
+
+pub fn parse_timestamp(input: &str) -> Result<Duration, ParseDurationErr> {
+    let trimmed = precheck(input)?;
+    let invalid = || ParseDurationErr::InvalidTimestamp(input.to_string());
+    if !trimmed.contains(':') {
+        return parse_duration(input).map_err(|e| match e {
+            ParseDurationErr::Invalid(_) => invalid(),
+            e => e,
+        });
+    }
+    let overflow = || ParseDurationErr::Overflow(input.to_string());
+
+    let parts = trimmed.split(':').collect::<Vec<_>>();
+    if parts.len() > 3 {
+        return Err(invalid());
+    }
+
+    let (secs, rest) = parts.split_last().unwrap();
+    let secs = parse_decimal(secs, 1, input, invalid)?;
+    if secs >= Duration::from_secs(60) {
+        return Err(invalid());
+    }
+    let total = rest.iter().enumerate().try_fold(0u64, |acc, (i, part)| {
+        if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let value = part.parse::<u64>().map_err(|_| overflow())?;
+        // HH:MM:SS の MM は 60 未満. MM:SS の場合は分が 60 以上でもよい
+        if rest.len() == 2 && i == 1 && value >= 60 {
+            return Err(invalid());
+        }
+        acc.checked_mul(60)
+            .and_then(|acc| acc.checked_add(value))
+            .ok_or_else(overflow)
+    })?;
+
+    total
+        .checked_mul(60)
+        .map(Duration::from_secs)
+        .and_then(|total| total.checked_add(secs))
+        .ok_or_else(overflow)
+}
+
+pub fn format_timestamp(duration: Duration) -> String {
+    let millis = duration.as_millis();
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+pub fn format_clock(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// UNIX 時間 (秒) を UTC の `YYYY-MM-DD HH:MM:SS` に変換する.
+pub fn format_unix(secs: u64) -> String {
+    let (days, rest) = (secs / 86_400, secs % 86_400);
+
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        rest / 3600,
+        rest / 60 % 60,
+        rest % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration(" 90 "), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("45s"), Ok(Duration::from_secs(45)));
+        assert_eq!(parse_duration("15m"), Ok(Duration::from_secs(900)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("2h15m"), Ok(Duration::from_secs(8100)));
+        assert_eq!(parse_duration("30d"), Ok(Duration::from_secs(30 * 86400)));
+        assert_eq!(parse_duration("1d12h"), Ok(Duration::from_secs(129_600)));
+        assert_eq!(parse_duration("1h2m3s"), Ok(Duration::from_secs(3723)));
+        assert_eq!(parse_duration("1.5h"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration(""), Err(ParseDurationErr::Empty));
+        assert!(parse_duration("2x").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("2h15").is_err());
+        assert!(parse_duration("-5").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration_strict() {
+        let invalid = |s: &str| Err(ParseDurationErr::Invalid(s.to_string()));
+        assert_eq!(parse_duration("15m2h"), invalid("15m2h"));
+        assert_eq!(parse_duration("2h2h"), invalid("2h2h"));
+        assert_eq!(parse_duration("1e3"), invalid("1e3"));
+        assert_eq!(parse_duration("inf"), invalid("inf"));
+        assert_eq!(parse_duration("NaN"), invalid("NaN"));
+        assert_eq!(parse_duration("1.2.3"), invalid("1.2.3"));
+        assert_eq!(parse_duration("."), invalid("."));
+        assert_eq!(parse_duration("+5"), invalid("+5"));
+        assert_eq!(
+            parse_duration("-5s"),
+            Err(ParseDurationErr::Negative("-5s".to_string()))
+        );
+        assert_eq!(
+            parse_duration("1,5"),
+            Err(ParseDurationErr::DecimalComma("1,5".to_string()))
+        );
+        assert_eq!(
+            parse_duration("99999999999999999999"),
+            Err(ParseDurationErr::Overflow(
+                "99999999999999999999".to_string()
+            ))
+        );
+        assert_eq!(
+            parse_duration("9999999999999999999h"),
+            Err(ParseDurationErr::Overflow(
+                "9999999999999999999h".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_duration_fraction() {
+        assert_eq!(parse_duration(".5"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("5."), Ok(Duration::from_secs(5)));
+        assert_eq!(parse_duration("0.001"), Ok(Duration::from_millis(1)));
+        assert_eq!(parse_duration("1.25m"), Ok(Duration::from_secs(75)));
+        assert_eq!(
+            parse_duration("0.1234567891s"),
+            Ok(Duration::from_nanos(123_456_789))
+        );
+        assert_eq!(
+            parse_duration("5025.125"),
+            Ok(Duration::from_millis(5_025_125))
+        );
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_timestamp("2h15m"), Ok(Duration::from_secs(8100)));
+        assert_eq!(parse_timestamp("1:30"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_timestamp("90:00"), Ok(Duration::from_secs(5400)));
+        assert_eq!(
+            parse_timestamp("00:01:30.250"),
+            Ok(Duration::from_millis(90_250))
+        );
+        assert_eq!(parse_timestamp("1:00:00"), Ok(Duration::from_secs(3600)));
+        assert!(parse_timestamp("1:60").is_err());
+        assert!(parse_timestamp("1:2:3:4").is_err());
+        assert!(parse_timestamp("a:30").is_err());
+    }
+
+    #[test]
+    fn test_parse_timestamp_strict() {
+        let invalid = |s: &str| Err(ParseDurationErr::InvalidTimestamp(s.to_string()));
+        assert_eq!(parse_timestamp("1:60:00"), invalid("1:60:00"));
+        assert_eq!(parse_timestamp("1:"), invalid("1:"));
+        assert_eq!(parse_timestamp(":30"), invalid(":30"));
+        assert_eq!(parse_timestamp("1.5:30"), invalid("1.5:30"));
+        assert_eq!(parse_timestamp("abc"), invalid("abc"));
+        assert_eq!(
+            parse_timestamp("-1:30"),
+            Err(ParseDurationErr::Negative("-1:30".to_string()))
+        );
+        assert_eq!(
+            parse_timestamp("00:01:30,250"),
+            Err(ParseDurationErr::DecimalComma("00:01:30,250".to_string()))
+        );
+        assert!(matches!(
+            parse_timestamp("99999999999999999:00:00"),
+            Err(ParseDurationErr::Overflow(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_err_message() {
+        let message = parse_timestamp("1:xx").unwrap_err().to_string();
+        assert!(message.contains("\"1:xx\""));
+        assert!(message.contains("00:01:30.250"));
+        let message = parse_duration("2x").unwrap_err().to_string();
+        assert!(message.contains("\"2x\""));
+        assert!(message.contains("2h15m"));
+        assert!(parse_duration("1,5")
+            .unwrap_err()
+            .to_string()
+            .contains("\".\""));
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(
+            format_timestamp(Duration::from_millis(90_250)),
+            "00:01:30.250"
+        );
+        assert_eq!(
+            format_timestamp(Duration::from_secs(12 * 3600)),
+            "12:00:00.000"
+        );
+        assert_eq!(
+            parse_timestamp(&format_timestamp(Duration::from_millis(5_025_125))),
+            Ok(Duration::from_millis(5_025_125))
+        );
+        for millis in [0, 1, 999, 59_999, 90_250, 3_599_999, 43_200_001] {
+            let duration = Duration::from_millis(millis);
+            assert_eq!(parse_timestamp(&format_timestamp(duration)), Ok(duration));
+        }
+        assert_eq!(format_clock(Duration::from_millis(5_025_999)), "01:23:45");
+    }
+
+    #[test]
+    fn test_format_unix() {
+        assert_eq!(format_unix(0), "1970-01-01 00:00:00");
+        assert_eq!(format_unix(951_782_400), "2000-02-29 00:00:00");
+        assert_eq!(format_unix(1_700_000_000), "2023-11-14 22:13:20");
+    }
+}
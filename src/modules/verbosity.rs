@@ -0,0 +1,107 @@
+use console::style;
+use indicatif::MultiProgress;
+use std::{
+    fmt::Display,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Mutex,
+    },
+};
+
+/// 表示の詳しさ. `-q` / `-v` / `-vv` で選ぶ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// 進捗と結果だけを表示し, 警告は表示しない.
+    Quiet,
+    Normal,
+    /// 段階ごとの時間の内訳と, ffmpeg の情報のログも表示する.
+    Verbose,
+    /// ffmpeg が出力したすべてのイベントも表示する.
+    Debug,
+}
+
+impl Verbosity {
+    pub fn new(quiet: bool, verbose: u8) -> Self {
+        match (quiet, verbose) {
+            (true, _) => Verbosity::Quiet,
+            (false, 0) => Verbosity::Normal,
+            (false, 1) => Verbosity::Verbose,
+            (false, _) => Verbosity::Debug,
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+/// 表示中の進捗. ある間は, 進捗の表示を崩さないよう一旦消してから書き出す.
+static PROGRESS: Mutex<Option<MultiProgress>> = Mutex::new(None);
+
+pub fn set(verbosity: Verbosity) {
+    LEVEL.store(verbosity as u8, Ordering::Relaxed);
+}
+
+pub fn level() -> Verbosity {
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => Verbosity::Quiet,
+        1 => Verbosity::Normal,
+        2 => Verbosity::Verbose,
+        _ => Verbosity::Debug,
+    }
+}
+
+pub fn enabled(verbosity: Verbosity) -> bool {
+    level() >= verbosity
+}
+
+/// 進捗を表示している間, メッセージをその上に書き出す.
+pub fn attach(progress: &MultiProgress) {
+    *PROGRESS.lock().unwrap() = Some(progress.clone());
+}
+
+pub fn detach() {
+    *PROGRESS.lock().unwrap() = None;
+}
+
+/// 詳しさに関係なく, 進捗の上に 1 行書き出す.
+pub fn println(line: impl Display) {
+    let progress = PROGRESS.lock().unwrap().clone();
+    match progress {
+        // 標準エラー出力が端末でなく進捗を表示していない場合も書き出す
+        Some(progress) => progress.suspend(|| println!("{}", line)),
+        None => println!("{}", line),
+    }
+}
+
+/// `-q` でなければ表示する.
+pub fn warn(message: impl Display) {
+    if enabled(Verbosity::Normal) {
+        println(style(format!("警告: {}", message)).yellow());
+    }
+}
+
+/// `-v` 以上で表示する.
+pub fn info(message: impl Display) {
+    if enabled(Verbosity::Verbose) {
+        println(style(message).dim());
+    }
+}
+
+/// `-vv` で表示する.
+pub fn debug(message: impl Display) {
+    if enabled(Verbosity::Debug) {
+        println(style(message).dim());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new() {
+        assert_eq!(Verbosity::new(false, 0), Verbosity::Normal);
+        assert_eq!(Verbosity::new(false, 1), Verbosity::Verbose);
+        assert_eq!(Verbosity::new(false, 3), Verbosity::Debug);
+        assert_eq!(Verbosity::new(true, 0), Verbosity::Quiet);
+        assert!(Verbosity::Quiet < Verbosity::Normal && Verbosity::Verbose < Verbosity::Debug);
+    }
+}
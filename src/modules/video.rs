@@ -1,18 +1,50 @@
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{anyhow, Context, Result};
+use console::style;
 use core::fmt;
 use ffmpeg_sidecar::{
     command::FfmpegCommand,
     event::{
-        AudioStream, FfmpegDuration, FfmpegEvent, FfmpegProgress, LogLevel, StreamTypeSpecificData,
-        VideoStream,
+        AudioStream, FfmpegDuration, FfmpegEvent, FfmpegProgress, LogLevel, Stream, VideoStream,
     },
 };
+use humansize::format_size;
 use indicatif::ProgressBar;
-use std::{ffi::OsStr, io, iter, ops, time::Duration};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
-use super::file;
+use super::{
+    codec_params::{self, CodecParam},
+    config, ffmpeg, file,
+    frames::{self, EncodeSpeed, FrameCounts, FrameLog, FrameTracker},
+    hwaccel::HwAccel,
+    logs::EncodeLog,
+    mux::{self, classify_mux_error, MuxError},
+    naming,
+    overlay::LabelOverlay,
+    pause::PauseControl,
+    phases::{Phase, PhaseLog},
+    sandbox,
+    schedule::Clock,
+    size_limit::{self, SizeLimit},
+    stall::{self, StallWatch, Stop},
+    time::{self, format_timestamp, parse_timestamp},
+    verbosity,
+    warnings::WarningLog,
+};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
 pub enum VideoRes {
     R240p,
     R360p,
@@ -38,6 +70,50 @@ impl fmt::Display for ToStrError {
     }
 }
 
+#[derive(Debug)]
+pub enum ParseResErr {
+    Invalid(String),
+}
+
+impl fmt::Display for ParseResErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseResErr::Invalid(s) => {
+                write!(f, "解像度の形式が不正です: \"{}\" (例: 1280x720)", s)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseResErr {}
+
+impl FromStr for VideoRes {
+    type Err = ParseResErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseResErr::Invalid(s.to_string());
+        let (w, h) = s.split_once('x').ok_or_else(invalid)?;
+        let width = w.parse::<u32>().map_err(|_| invalid())?;
+        let height = h.parse::<u32>().map_err(|_| invalid())?;
+
+        Ok(VideoRes::from_wh(width, height))
+    }
+}
+
+impl TryFrom<String> for VideoRes {
+    type Error = ParseResErr;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<VideoRes> for String {
+    fn from(res: VideoRes) -> Self {
+        res.to_file_name()
+    }
+}
+
 impl VideoRes {
     pub fn list169() -> Vec<Self> {
         vec![
@@ -100,18 +176,13 @@ impl VideoRes {
         }
     }
 
+    /// 幅か高さの片方を, 元動画の表示上の大きさ (`VideoStat::display_wh`) の縦横比から決める.
     pub fn from_wh_dynamic(
         width: Option<i32>,
         height: Option<i32>,
-        video_stream: VideoStream,
+        (rw, rh): (u32, u32),
     ) -> Result<Self, ToStrError> {
-        let VideoStream {
-            width: rw,
-            height: rh,
-            ..
-        } = video_stream;
         let ratio = rw as f32 / rh as f32;
-        println!("ratio: {}", ratio);
 
         if width.is_none() && height.is_none() {
             return Err(ToStrError::BothAreDynamicValue);
@@ -144,306 +215,3986 @@ impl VideoRes {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::modules::testing::{self, TempDir};
+    use ffmpeg_sidecar::event::StreamTypeSpecificData;
+
+    fn stat(width: u32, height: u32, fps: f32, file_size: u64, secs: u64) -> VideoStat {
+        VideoStat {
+            path: "assets/2.mp4".to_string(),
+            video_stream: VideoStreamInfo {
+                codec: "h264".to_string(),
+                width,
+                height,
+                fps,
+                pix_fmt: "yuv420p".to_string(),
+                ..Default::default()
+            },
+            video_stream_index: 0,
+            audio_streams: vec![],
+            cover_stream_indices: vec![],
+            ignored_stream_indices: vec![],
+            duration: Duration::from_secs(secs),
+            start_time: Duration::ZERO,
+            file_size,
+        }
+    }
+
     #[test]
     fn test_from_wh_dynamic() {
-        use super::*;
-
-        let video_stream = VideoStream {
-            width: 1920,
-            height: 1080,
-            fps: 30.0,
-            pix_fmt: "yuv420p".to_string(),
-        };
+        let video_stream = (1920, 1080);
 
         assert_eq!(
-            VideoRes::from_wh_dynamic(None, Some(720), video_stream.clone())
+            VideoRes::from_wh_dynamic(None, Some(720), video_stream)
                 .unwrap()
                 .to_wh(),
             VideoRes::R720p.to_wh()
         );
         assert_eq!(
-            VideoRes::from_wh_dynamic(Some(1280), None, video_stream.clone())
+            VideoRes::from_wh_dynamic(Some(1280), None, video_stream)
                 .unwrap()
                 .to_wh(),
             VideoRes::R720p.to_wh()
         );
         assert_eq!(
-            VideoRes::from_wh_dynamic(Some(1280), Some(720), video_stream.clone())
+            VideoRes::from_wh_dynamic(Some(1280), Some(720), video_stream)
                 .unwrap()
                 .to_wh(),
             VideoRes::R720p.to_wh()
         );
+
+        // 横長の画素の DV (720x480, SAR 32:27) は 16:9 として扱う
+        let mut dv = stat(720, 480, 29.97, 1, 1);
+        dv.video_stream.sar = (32, 27);
+        assert_eq!(dv.display_wh(), (854, 480));
+        assert_eq!(
+            VideoRes::from_wh_dynamic(None, Some(480), dv.display_wh())
+                .unwrap()
+                .to_wh(),
+            VideoRes::R480p.to_wh()
+        );
     }
-}
 
-#[derive(Debug, Clone)]
-pub struct VideoStat {
-    pub path: String,
-    pub video_stream: VideoStream,
-    pub audio_streams: Vec<AudioStream>,
-    pub duration: Duration,
-    pub file_size: u64,
-}
+    #[test]
+    fn test_video_res_serde() {
+        assert_eq!("1280x720".parse::<VideoRes>().unwrap().to_wh(), (1280, 720));
+        assert!(matches!(
+            "1000x500".parse::<VideoRes>(),
+            Ok(VideoRes::Other(1000, 500))
+        ));
+        assert!("720p".parse::<VideoRes>().is_err());
+        assert!("1280x".parse::<VideoRes>().is_err());
 
-#[derive(Debug)]
-pub enum VideoStatErr {
-    NoVideoStreamFound,
-    MultipleVideoStreamFound,
-    NoDurationFound,
-    FfmpegError(String),
-    FileError(io::Error),
-}
+        let config = VideoConfig {
+            res: VideoRes::R1080p,
+            crf: 28,
+            ..Default::default()
+        };
+        let json = serde_json::to_value(&config).unwrap();
+        assert_eq!(json["res"], "1920x1080");
 
-impl fmt::Display for VideoStatErr {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            VideoStatErr::NoVideoStreamFound => write!(f, "動画ストリームが見つかりません"),
-            VideoStatErr::MultipleVideoStreamFound => {
-                write!(f, "動画ストリームが複数見つかりました")
-            }
-            VideoStatErr::NoDurationFound => write!(f, "動画の長さが取得できません"),
-            VideoStatErr::FfmpegError(e) => write!(f, "ffmpegエラー: {}", e),
-            VideoStatErr::FileError(e) => write!(f, "ファイルエラー: {}", e),
-        }
+        let restored: VideoConfig =
+            serde_json::from_str(r#"{"res":"1920x1080","fps":60,"crf":28,"future":1}"#).unwrap();
+        assert_eq!(restored.res.to_wh(), (1920, 1080));
+        assert_eq!(restored.fps, 60);
+        assert!(restored.has_audio);
     }
-}
 
-#[derive(Debug, Clone)]
-pub struct VideoConfigParams {
-    pub res: VideoRes,
-    pub fps: u32,
-    pub crf: u32,
-}
+    #[test]
+    fn test_without_missing_audio() {
+        let silent = stat(1920, 1080, 30.0, 1000, 10);
+        let config = VideoConfig {
+            res: VideoRes::R720p,
+            fps: 30,
+            has_audio: true,
+            ..Default::default()
+        };
+        // --strict-audio の場合はそのまま確かめてエラーにする
+        assert!(matches!(
+            config.check_up_scaling(&silent),
+            Err(VideoConfigUpScalingErr::HasAudio)
+        ));
 
-pub struct VideoConfigParamsIter {
-    res: Vec<VideoRes>,
-    fps: Vec<u32>,
-    crf: Vec<u32>,
-}
+        // 既定では音声を外した設定にし, 名前は変わらない
+        let adjusted = config.without_missing_audio(&silent).unwrap();
+        assert!(!adjusted.has_audio);
+        assert!(adjusted.check_up_scaling(&silent).is_ok());
+        assert_eq!(adjusted.to_file_name(), config.to_file_name());
+        assert!(adjusted.without_missing_audio(&silent).is_none());
+    }
 
-#[derive(Debug)]
-pub enum VideoConfigUpScalingErr {
-    Resolution(VideoRes, VideoRes),
-    Fps(u32, u32),
-    HasAudio,
-}
+    #[test]
+    fn test_judge_source() {
+        let config = |res, fps, crf| VideoConfig {
+            res,
+            fps,
+            crf,
+            has_audio: false,
+            ..Default::default()
+        };
 
-impl fmt::Display for VideoConfigUpScalingErr {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let msg = match self {
-            VideoConfigUpScalingErr::Resolution(c, r) => format!(
-                "解像度が元動画より大きいです: {} > {}",
-                c.to_name(),
-                r.to_name()
+        let cases = [
+            (
+                stat(1280, 720, 30.0, 3_000_000, 60),
+                config(VideoRes::R720p, 30, 23),
+                SourceVerdict::AlreadyOptimal,
             ),
-            VideoConfigUpScalingErr::Fps(c, r) => {
-                format!("FPSが元動画より大きいです: {} > {}", c, r)
-            }
-            VideoConfigUpScalingErr::HasAudio => "音声が元動画に含まれていません".to_string(),
-        };
-        write!(f, "アップスケーリングエラー: {}", msg)
+            (
+                stat(1280, 720, 24.0, 3_000_000, 60),
+                config(VideoRes::R720p, 30, 23),
+                SourceVerdict::AlreadyOptimal,
+            ),
+            (
+                stat(1280, 720, 30.0, 60_000_000, 60),
+                config(VideoRes::R720p, 30, 23),
+                SourceVerdict::Encode,
+            ),
+            (
+                stat(1280, 720, 30.0, 3_000_000, 60),
+                config(VideoRes::R720p, 30, 47),
+                SourceVerdict::Encode,
+            ),
+            (
+                stat(1920, 1080, 30.0, 3_000_000, 60),
+                config(VideoRes::R720p, 30, 23),
+                SourceVerdict::Encode,
+            ),
+            (
+                stat(1280, 720, 60.0, 3_000_000, 60),
+                config(VideoRes::R720p, 30, 23),
+                SourceVerdict::Encode,
+            ),
+            (
+                stat(1280, 720, 30.0, 3_000_000, 0),
+                config(VideoRes::R720p, 30, 23),
+                SourceVerdict::Encode,
+            ),
+        ];
+
+        for (stat, config, expected) in cases {
+            assert_eq!(judge_source(&stat, &config), expected, "{:?}", config);
+        }
     }
-}
 
-#[derive(Debug, Clone)]
-pub struct VideoConfig {
-    pub res: VideoRes,
-    pub fps: u32,
-    pub crf: u32,
-    pub has_audio: bool,
-}
+    #[test]
+    fn test_is_attached_pic() {
+        let stream = |raw: &str| Stream {
+            format: "mjpeg".to_string(),
+            language: "und".to_string(),
+            parent_index: 0,
+            stream_index: 1,
+            raw_log_message: raw.to_string(),
+            type_specific_data: StreamTypeSpecificData::Video(VideoStream {
+                width: 600,
+                height: 600,
+                fps: 90000.0,
+                pix_fmt: "yuvj420p".to_string(),
+            }),
+        };
 
-impl VideoConfig {
-    pub fn to_file_name(&self) -> String {
-        format!(
-            "--res-{}--fps-{}--crf-{}",
-            self.res.to_file_name(),
-            self.fps,
-            self.crf
-        )
+        assert!(is_attached_pic(&stream(
+            "Stream #0:1[0x0]: Video: mjpeg (Baseline), yuvj420p(pc, bt470bg/unknown/unknown), 600x600 [SAR 1:1 DAR 1:1], 90k tbr, 90k tbn (attached pic)"
+        )));
+        assert!(!is_attached_pic(&stream(
+            "Stream #0:0[0x1](und): Video: h264 (High) (avc1 / 0x31637661), yuv420p(progressive), 1920x1080, 30 fps, 30 tbr, 15360 tbn (default)"
+        )));
     }
 
-    pub fn check_up_scaling(&self, stat: &VideoStat) -> Result<(), VideoConfigUpScalingErr> {
-        let VideoStat {
-            video_stream:
-                VideoStream {
-                    width: r_width,
-                    height: r_height,
-                    fps: r_fps,
-                    ..
-                },
-            audio_streams,
-            ..
-        } = stat;
-
-        let VideoConfig {
-            res,
-            fps: c_fps,
-            has_audio,
-            ..
-        } = self;
-        let (c_width, c_height) = res.to_wh();
+    #[test]
+    fn test_build_command_cover() {
+        let mut stat = stat(1920, 1080, 30.0, 3_000_000, 60);
+        stat.cover_stream_indices = vec![1];
+        let params = |output_path: &str, keep_cover| VideoProcessParams {
+            output_path: output_path.to_string(),
+            config: VideoConfig::default(),
+            keep_cover,
+            drop_audio: false,
+            trim: Trim::default(),
+            label: None,
+            faststart: false,
+            shortest: false,
+            keep_sar: false,
+            hwaccel: None,
+            cancel: CancelToken::new(),
+            pause: PauseControl::new(),
+            warnings: WarningLog::new(),
+            frames: FrameLog::new(),
+            size_limit: None,
+            phases: PhaseLog::new(),
+            stall_timeout: None,
+            timeout: None,
+            command_hook: None,
+            log: None,
+        };
 
-        if c_width > *r_width || c_height > *r_height {
-            return Err(VideoConfigUpScalingErr::Resolution(
-                self.res.clone(),
-                VideoRes::from_wh(*r_width, *r_height),
-            ));
-        }
+        let args = command_args(&build_command(&stat, &params("out/2.mp4", false)));
+        assert!(args.windows(2).any(|w| w == ["-map", "0:V:0"]));
+        assert!(!args.contains(&"attached_pic".to_string()));
 
-        if *c_fps > *r_fps as u32 {
-            return Err(VideoConfigUpScalingErr::Fps(self.fps, *r_fps as u32));
-        }
+        let args = command_args(&build_command(&stat, &params("out/2.mp4", true)));
+        assert!(args.windows(2).any(|w| w == ["-map", "0:v:1"]));
+        assert!(args
+            .windows(2)
+            .any(|w| w == ["-disposition:v:1", "attached_pic"]));
 
-        if *has_audio && audio_streams.is_empty() {
-            return Err(VideoConfigUpScalingErr::HasAudio);
-        }
+        let args = command_args(&build_command(&stat, &params("out/2.webm", true)));
+        assert!(!args.contains(&"attached_pic".to_string()));
 
-        Ok(())
+        let labeled = VideoProcessParams {
+            label: Some(LabelOverlay::new(
+                &VideoConfig::default(),
+                "/fonts/a.ttf".into(),
+            )),
+            ..params("out/2.mp4", true)
+        };
+        let args = command_args(&build_command(&stat, &labeled));
+        let filter = args.iter().position(|a| a == "-filter:v:0").unwrap();
+        assert!(args[filter + 1].starts_with("drawtext="));
+        assert!(!args.iter().any(|a| a == "-vf"));
     }
-}
 
-impl Default for VideoConfig {
-    fn default() -> Self {
-        Self {
-            res: VideoRes::R720p,
-            fps: 30,
-            crf: 23,
-            has_audio: true,
-        }
-    }
-}
+    #[test]
+    fn test_build_remux_command() {
+        let mut stat = stat(1920, 1080, 30.0, 3_000_000, 60);
+        let args = |stat: &VideoStat, params: &VideoProcessParams| {
+            command_args(&build_remux_command(stat, params))
+        };
 
-pub struct VideoProcessParams {
-    pub output_path: String,
-    pub config: VideoConfig,
-}
+        let params = VideoProcessParams::new("out/2.mp4", VideoConfig::default());
+        let copied = args(&stat, &params);
+        assert!(copied.windows(2).any(|w| w == ["-c:a", "copy"]));
+        assert!(!copied.contains(&"-map".to_string()));
 
-pub fn handle_ffmpeg_event_log(
-    level: LogLevel,
-    err: String,
-    ignore_no_input: bool,
-) -> Result<(), String> {
-    match level {
-        LogLevel::Fatal | LogLevel::Error => {
-            let err_body = err.split("[fatal] ").last().unwrap().to_owned();
-            let expected = err_body == "At least one output file must be specified";
+        let dropped = args(
+            &stat,
+            &VideoProcessParams {
+                drop_audio: true,
+                ..VideoProcessParams::new("out/2.mp4", VideoConfig::default())
+            },
+        );
+        assert!(dropped.contains(&"-an".to_string()));
+        assert!(!dropped.contains(&"-c:a".to_string()));
+        let silent = args(
+            &stat,
+            &VideoProcessParams::new(
+                "out/2.mp4",
+                VideoConfig {
+                    has_audio: false,
+                    ..Default::default()
+                },
+            ),
+        );
+        assert!(silent.contains(&"-an".to_string()));
 
-            if expected && ignore_no_input {
-                return Ok(());
-            }
+        stat.cover_stream_indices = vec![1];
+        let without_cover = args(&stat, &params);
+        assert!(without_cover.windows(2).any(|w| w == ["-map", "0:V:0"]));
+        assert!(!without_cover.contains(&"attached_pic".to_string()));
 
-            Err(err_body)
-        }
-        LogLevel::Warning => Ok(()).inspect(|_| {
-            println!("警告: {}", err);
-        }),
-        _ => Ok(()),
+        let with_cover = args(
+            &stat,
+            &VideoProcessParams {
+                keep_cover: true,
+                ..VideoProcessParams::new("out/2.mp4", VideoConfig::default())
+            },
+        );
+        assert!(with_cover.windows(2).any(|w| w == ["-map", "0:v:1"]));
+        assert!(with_cover
+            .windows(2)
+            .any(|w| w == ["-disposition:v:1", "attached_pic"]));
     }
-}
 
-pub async fn stat(input_path: String) -> Result<VideoStat, VideoStatErr> {
-    let mut runner = FfmpegCommand::new()
-        .input(input_path.clone())
-        .spawn()
-        .unwrap();
+    #[test]
+    fn test_build_command_audio() {
+        let mut stat = stat(1920, 1080, 30.0, 3_000_000, 60);
+        stat.audio_streams = vec![AudioStreamInfo {
+            codec: "aac".to_string(),
+            ..Default::default()
+        }];
+        let args = |output_path: &str, has_audio| {
+            command_args(&build_command(
+                &stat,
+                &VideoProcessParams::new(
+                    output_path,
+                    VideoConfig {
+                        has_audio,
+                        ..Default::default()
+                    },
+                ),
+            ))
+        };
 
-    let mut input_duration_sec: Option<f64> = None;
-    let mut input_streams: Vec<StreamTypeSpecificData> = Vec::new();
+        let silent = args("out/2.mp4", false);
+        assert!(silent.contains(&"-an".to_string()));
+        assert!(!silent.contains(&"-c:a".to_string()));
 
-    for e in runner.iter().unwrap() {
-        match e {
-            FfmpegEvent::ParsedDuration(FfmpegDuration { duration, .. }) => {
-                input_duration_sec = Some(duration);
-            }
-            FfmpegEvent::ParsedInputStream(s) => {
-                input_streams.push(s.type_specific_data);
-            }
-            FfmpegEvent::Log(level, err) => {
-                handle_ffmpeg_event_log(level, err, true).map_err(VideoStatErr::FfmpegError)?;
-            }
-            _ => {
-                // println!("{:?}", e);
-            }
-        }
+        assert!(args("out/2.mp4", true)
+            .windows(2)
+            .any(|w| w == ["-c:a", "copy"]));
+        assert!(!args("out/2.mp4", true).contains(&"-an".to_string()));
+        // 格納できない音声はエンコードし, 音声を格納できないコンテナでは除く
+        let webm = args("out/2.webm", true);
+        assert!(webm.windows(2).any(|w| w == ["-c:a", "libopus"]));
+        assert!(webm.windows(2).any(|w| w == ["-b:a", "128000"]));
+        assert!(args("out/2.gif", true).contains(&"-an".to_string()));
+
+        // 元動画に音声がなければ何も指定しない
+        stat.audio_streams.clear();
+        let source = command_args(&build_command(
+            &stat,
+            &VideoProcessParams::new("out/2.mp4", VideoConfig::default()),
+        ));
+        assert!(!source.iter().any(|a| a == "-an" || a == "-c:a"));
     }
 
-    let mut video_streams = input_streams.iter().filter_map(|s| match s {
-        StreamTypeSpecificData::Video(v) => Some(v),
-        _ => None,
-    });
+    #[test]
+    fn test_build_command_hwaccel() {
+        let stat = stat(1920, 1080, 30.0, 3_000_000, 60);
+        let args = |hwaccel, codec| {
+            command_args(&build_command(
+                &stat,
+                &VideoProcessParams {
+                    hwaccel,
+                    ..VideoProcessParams::new(
+                        "out/2.mp4",
+                        VideoConfig {
+                            res: VideoRes::R720p,
+                            crf: 28,
+                            codec,
+                            codec_params: vec![CodecParam {
+                                key: "aq-mode".to_string(),
+                                value: "3".to_string(),
+                            }],
+                            ..Default::default()
+                        },
+                    )
+                },
+            ))
+        };
 
-    let video_stream = match video_streams.clone().count() {
-        0 => Err(VideoStatErr::NoVideoStreamFound),
-        1 => Ok(video_streams.next().unwrap().clone()),
-        _ => Err(VideoStatErr::MultipleVideoStreamFound),
-    }?;
+        let nvenc = args(Some(HwAccel::Nvenc), Some(VideoCodec::H265));
+        assert!(nvenc.windows(2).any(|w| w == ["-c:v", "hevc_nvenc"]));
+        assert!(nvenc.windows(2).any(|w| w == ["-cq", "28"]));
+        assert!(!nvenc.contains(&"-crf:v".to_string()));
+        assert!(!nvenc.contains(&"-x265-params".to_string()));
+        // コーデックを指定しなければ H.264 にする
+        let videotoolbox = args(Some(HwAccel::VideoToolbox), None);
+        assert!(videotoolbox
+            .windows(2)
+            .any(|w| w == ["-c:v", "h264_videotoolbox"]));
+        assert!(videotoolbox.windows(2).any(|w| w == ["-q:v", "44"]));
 
-    let audio_streams = input_streams
-        .iter()
-        .filter_map(|a| match a {
-            StreamTypeSpecificData::Audio(a) => Some(a.clone()),
-            _ => None,
-        })
-        .collect::<Vec<_>>();
+        // VA-API はデバイスを開き, フレームを GPU に移す
+        let vaapi = args(Some(HwAccel::Vaapi), None);
+        let device = vaapi.iter().position(|a| a == "-vaapi_device").unwrap();
+        assert!(device < vaapi.iter().position(|a| a == "-i").unwrap());
+        assert!(vaapi
+            .windows(2)
+            .any(|w| w[0] == "-filter:v:0" && w[1].ends_with("format=nv12,hwupload")));
 
-    let duration_sec = input_duration_sec.ok_or(VideoStatErr::NoDurationFound)?;
-    let duration = Duration::from_secs_f64(duration_sec);
+        // 対応していないコーデックはソフトウェアでエンコードする
+        let vp9 = args(Some(HwAccel::Nvenc), Some(VideoCodec::Vp9));
+        assert!(vp9.windows(2).any(|w| w == ["-c:v", "libvpx-vp9"]));
+        assert!(vp9.windows(2).any(|w| w == ["-crf:v", "28"]));
+    }
 
-    let file_size = file::calc_size(&input_path).map_err(VideoStatErr::FileError)?;
+    #[test]
+    fn test_fps_mode() {
+        assert_eq!("blend".parse::<FpsMode>(), Ok(FpsMode::Blend));
+        assert!("interpolate"
+            .parse::<FpsMode>()
+            .unwrap_err()
+            .contains("mci"));
 
-    Ok(VideoStat {
-        path: input_path,
-        video_stream,
-        audio_streams,
-        duration,
-        file_size,
-    })
-}
+        let config = |fps_mode| VideoConfig {
+            fps: 24,
+            fps_mode,
+            ..Default::default()
+        };
+        assert_eq!(
+            config(Some(FpsMode::Mci)).to_file_name(),
+            "--res-1280x720--fps-24--crf-23--fps-mode-mci"
+        );
+        // 間引くだけの場合は, 既存の出力と同じ名前にする
+        assert_eq!(
+            config(Some(FpsMode::Drop)).to_file_name(),
+            config(None).to_file_name()
+        );
+        let json = serde_json::to_string(&config(Some(FpsMode::Blend))).unwrap();
+        assert!(json.contains(r#""fps_mode":"blend""#));
+        assert!(!serde_json::to_string(&config(None))
+            .unwrap()
+            .contains("fps_mode"));
 
-pub async fn process(stat: VideoStat, params: VideoProcessParams, pb: ProgressBar) -> Result<()> {
-    let VideoProcessParams {
-        output_path,
-        config,
-    } = params;
+        // FPS のフィルターは描画の前に置き, 1 つの -filter:v:0 にまとめる
+        let stat = stat(1920, 1080, 60.0, 3_000_000, 60);
+        let params = VideoProcessParams {
+            label: Some(LabelOverlay::new(
+                &config(Some(FpsMode::Mci)),
+                "/fonts/a.ttf".into(),
+            )),
+            ..VideoProcessParams::new("out/2.mp4".to_string(), config(Some(FpsMode::Mci)))
+        };
+        let args = command_args(&build_command(&stat, &params));
+        let filters = args
+            .iter()
+            .filter(|a| a.starts_with("minterpolate=") || a.starts_with("drawtext="))
+            .collect::<Vec<_>>();
+        assert_eq!(filters.len(), 1);
+        assert!(filters[0].starts_with("minterpolate=fps=24:mi_mode=mci"));
+        assert!(filters[0].contains(",drawtext="));
+        for (mode, filter) in [
+            (FpsMode::Drop, "fps=24"),
+            (FpsMode::Blend, "framerate=fps=24"),
+        ] {
+            let params = VideoProcessParams::new("out/2.mp4".to_string(), config(Some(mode)));
+            let args = command_args(&build_command(&stat, &params));
+            assert!(args.windows(2).any(|w| w == ["-filter:v:0", filter]));
+        }
 
-    if let Err(e) = VideoConfig::check_up_scaling(&config, &stat) {
-        return Err(anyhow!(e)).context("エンコード設定に問題があります");
+        // 作り方を指定しなくても, FPS を下げる場合は間引く. 元動画と同じ FPS ならフィルターを使わない
+        let args = |fps| {
+            let config = VideoConfig {
+                fps,
+                ..Default::default()
+            };
+            command_args(&build_command(
+                &stat,
+                &VideoProcessParams::new("out/2.mp4".to_string(), config),
+            ))
+        };
+        assert!(args(24).windows(2).any(|w| w == ["-filter:v:0", "fps=24"]));
+        assert!(!args(60).iter().any(|a| a == "-filter:v:0"));
+        let ntsc = VideoStat {
+            video_stream: VideoStreamInfo {
+                fps: 29.97,
+                ..stat.video_stream.clone()
+            },
+            ..stat.clone()
+        };
+        let same = VideoConfig {
+            fps: 29,
+            ..Default::default()
+        };
+        assert_eq!(same.fps_filter(&ntsc), None);
+        assert_eq!(same.output_fps(&ntsc), 29.97);
+        assert_eq!(config(None).output_fps(&stat), 24.0);
     }
 
-    let arg = config.res.to_args();
-    let arg_os_str: Vec<&OsStr> = arg.split_whitespace().map(OsStr::new).collect();
+    #[tokio::test]
+    async fn test_fps_mode_preserves_duration() {
+        if !testing::ffmpeg_tests_enabled("test_fps_mode_preserves_duration") {
+            return;
+        }
+        let dir = TempDir::new("fps-mode");
+        let source = dir.join("source.mp4").to_string_lossy().into_owned();
+        let status = std::process::Command::new(ffmpeg_sidecar::paths::ffmpeg_path())
+            .args([
+                "-y",
+                "-f",
+                "lavfi",
+                "-i",
+                "testsrc=s=320x240:r=60:d=2",
+                &source,
+            ])
+            .status()
+            .unwrap();
+        assert!(status.success());
 
-    let mut runner = FfmpegCommand::new()
-        .input(stat.path)
-        .crf(config.crf)
-        .args(arg_os_str)
-        .output(output_path)
-        .overwrite()
-        .spawn()
-        .unwrap();
+        let stat = super::stat(source).await.unwrap();
+        for mode in FpsMode::ALL {
+            let output = dir
+                .join(format!("{}.mp4", mode))
+                .to_string_lossy()
+                .into_owned();
+            let config = VideoConfig {
+                res: VideoRes::Other(320, 240),
+                fps: 24,
+                has_audio: false,
+                fps_mode: Some(mode),
+                ..Default::default()
+            };
+            process(
+                stat.clone(),
+                VideoProcessParams::new(output.clone(), config),
+                ProgressBar::hidden(),
+            )
+            .await
+            .unwrap();
 
-    for e in runner.iter().unwrap() {
-        match e {
-            FfmpegEvent::Progress(FfmpegProgress {
-                frame: current_frame,
-                ..
-            }) => {
-                let total_frame = (stat.duration.as_secs() as f32) * stat.video_stream.fps;
-                pb.set_length(total_frame as u64);
-                pb.set_position(current_frame as u64);
-                pb.set_message("エンコード中...");
-            }
-            FfmpegEvent::Log(level, err) => {
-                if let Err(e) = handle_ffmpeg_event_log(level, err, false) {
-                    return Err(anyhow!(e));
-                }
-            }
-            _ => {
-                // println!("{:?}", e);
-            }
+            let output = super::stat(output).await.unwrap();
+            assert_eq!(output.video_stream.fps, 24.0, "{}", mode);
+            let drift = output.duration.as_secs_f64() - stat.duration.as_secs_f64();
+            assert!(drift.abs() < 0.1, "{}: {:?}", mode, output.duration);
         }
     }
 
-    Ok(())
+    #[test]
+    fn test_classify_encoder_rejection() {
+        let cases = [
+            (
+                "[libx264 @ 0x55d5c8a0] high10 profile doesn't support a bit depth of 8",
+                Some(EncoderRejection::Profile),
+            ),
+            (
+                "[libx264 @ 0x55d5c8a0] Error setting profile main10.",
+                Some(EncoderRejection::Profile),
+            ),
+            (
+                "[libx265 @ 0x7f8e] Specified pixel format yuv420p10le is invalid or not supported",
+                Some(EncoderRejection::PixelFormat),
+            ),
+            (
+                "x265 [error]: Unsupported bit depth 10",
+                Some(EncoderRejection::PixelFormat),
+            ),
+            ("assets/2.mp4: No such file or directory", None),
+        ];
+
+        for (message, expected) in cases {
+            assert_eq!(classify_encoder_rejection(message), expected, "{}", message);
+        }
+    }
+
+    #[test]
+    fn test_downgrade() {
+        let config = |pix_fmt: Option<&str>, profile: Option<&str>| VideoConfig {
+            pix_fmt: pix_fmt.map(str::to_string),
+            profile: profile.map(str::to_string),
+            ..Default::default()
+        };
+
+        let (downgraded, note) = config(Some("yuv420p10le"), Some("high10"))
+            .downgrade()
+            .unwrap();
+        assert_eq!(downgraded.pix_fmt.as_deref(), Some("yuv420p"));
+        assert_eq!(downgraded.profile.as_deref(), Some("high"));
+        assert_eq!(note, "yuv420p10le → yuv420p, high10 → high");
+
+        let (downgraded, note) = config(Some("yuv420p"), Some("main10")).downgrade().unwrap();
+        assert_eq!(downgraded.pix_fmt.as_deref(), Some("yuv420p"));
+        assert_eq!(downgraded.profile.as_deref(), Some("main"));
+        assert_eq!(note, "main10 → main");
+
+        assert!(config(Some("yuv420p"), Some("high")).downgrade().is_none());
+        assert!(config(None, None).downgrade().is_none());
+    }
+
+    #[test]
+    fn test_trim_args() {
+        let trim = |seek| Trim {
+            start: Some(Duration::from_secs(90)),
+            end: Some(Duration::from_secs(120)),
+            seek,
+        };
+
+        assert_eq!(
+            trim(SeekMode::Fast).to_args(),
+            (
+                vec!["-ss".to_string(), "00:01:30.000".to_string()],
+                vec!["-t".to_string(), "00:00:30.000".to_string()]
+            )
+        );
+        assert_eq!(
+            trim(SeekMode::Hybrid).to_args(),
+            (
+                vec!["-ss".to_string(), "00:01:25.000".to_string()],
+                vec![
+                    "-ss".to_string(),
+                    "00:00:05.000".to_string(),
+                    "-t".to_string(),
+                    "00:00:30.000".to_string()
+                ]
+            )
+        );
+        assert_eq!(
+            trim(SeekMode::Accurate).to_args(),
+            (
+                vec![],
+                vec![
+                    "-ss".to_string(),
+                    "00:01:30.000".to_string(),
+                    "-t".to_string(),
+                    "00:00:30.000".to_string()
+                ]
+            )
+        );
+        assert_eq!(Trim::default().to_args(), (vec![], vec![]));
+    }
+
+    #[test]
+    fn test_frame_progress() {
+        let stat = stat(1920, 1080, 24.0, 3_000_000, 300);
+        let trim = |seek| Trim {
+            start: Some(Duration::from_millis(90_250)),
+            end: Some(Duration::from_secs(120)),
+            seek,
+        };
+
+        assert_eq!(
+            trim(SeekMode::Fast).output_duration(stat.duration),
+            Duration::from_millis(29_750)
+        );
+        assert_eq!(
+            FrameProgress::new(&stat, &trim(SeekMode::Fast)),
+            FrameProgress {
+                preroll: 0,
+                preroll_duration: Duration::ZERO,
+                total: 714,
+                duration: Duration::from_millis(29_750)
+            }
+        );
+        assert_eq!(
+            FrameProgress::new(&stat, &trim(SeekMode::Hybrid)),
+            FrameProgress {
+                preroll: 120,
+                preroll_duration: HYBRID_PREROLL,
+                total: 120 + 714,
+                duration: Duration::from_millis(29_750)
+            }
+        );
+
+        let accurate = FrameProgress::new(&stat, &trim(SeekMode::Accurate));
+        assert_eq!(
+            accurate,
+            FrameProgress {
+                preroll: 2166,
+                preroll_duration: Duration::from_millis(90_250),
+                total: 2166 + 714,
+                duration: Duration::from_millis(29_750)
+            }
+        );
+        assert_eq!(accurate.position(0), 0);
+        assert_eq!(accurate.position(1), 2167);
+        assert_eq!(accurate.position(10_000), accurate.total);
+        assert_eq!(accurate.position_at(Duration::ZERO), 2166);
+        // 前置きのデコード中も, 出力の開始までの残りから進む
+        assert_eq!(accurate.position_before(Duration::from_millis(90_250)), 0);
+        assert_eq!(
+            accurate.position_before(Duration::from_millis(45_125)),
+            1083
+        );
+        assert_eq!(accurate.position_before(Duration::ZERO), 2166);
+        assert_eq!(
+            accurate.position_at(Duration::from_millis(14_875)),
+            2166 + 357
+        );
+        // 調べた長さより少し長く出力されても 100% で止まる
+        assert_eq!(
+            accurate.position_at(Duration::from_secs(30)),
+            accurate.total
+        );
+
+        let whole = FrameProgress::new(&stat, &Trim::default());
+        assert_eq!(whole.total, 300 * 24);
+    }
+
+    #[test]
+    fn test_long_source_math() {
+        let stat = stat(1920, 1080, 120.0, 500_000_000_000, 12 * 3600);
+        let frames = 12 * 3600 * 120;
+
+        assert_eq!(
+            FrameProgress::new(&stat, &Trim::default()),
+            FrameProgress {
+                preroll: 0,
+                preroll_duration: Duration::ZERO,
+                total: frames,
+                duration: Duration::from_secs(12 * 3600)
+            }
+        );
+        let tail = Trim {
+            start: Some(Duration::from_secs(12 * 3600 - 60)),
+            end: None,
+            seek: SeekMode::Hybrid,
+        };
+        let progress = FrameProgress::new(&stat, &tail);
+        assert_eq!(progress.preroll, 5 * 120);
+        assert_eq!(progress.total, (5 + 60) * 120);
+        assert_eq!(progress.position(u32::MAX as u64), progress.total);
+
+        let config = VideoConfig {
+            res: VideoRes::R1080p,
+            fps: 120,
+            ..Default::default()
+        };
+        let estimated = estimate_output_size(&stat, &config, &Trim::default());
+        assert!(estimated > LARGE_MP4_THRESHOLD);
+        assert_eq!(
+            estimated,
+            ((estimate_bits_per_pixel(23) * 1920.0 * 1080.0 * 120.0 * 43200.0) / 8.0).round()
+                as u64
+        );
+
+        assert_eq!(
+            extrapolate_size(
+                5_000_000_000,
+                Duration::from_secs(600),
+                stat.duration,
+                true,
+                None
+            ),
+            360_000_000_000
+        );
+        assert!(stat.bits_per_pixel() > 0.0);
+    }
+
+    #[test]
+    fn test_check_compat() {
+        let config = |res, fps, profile: Option<&str>| VideoConfig {
+            res,
+            fps,
+            profile: profile.map(str::to_string),
+            ..Default::default()
+        };
+        let texts = |container, config: VideoConfig| {
+            check_compat(container, DEFAULT_VIDEO_CODEC, &config)
+                .iter()
+                .map(|r| (r.severity, r.text))
+                .collect::<Vec<_>>()
+        };
+
+        assert!(texts("mp4", config(VideoRes::R1080p, 60, None)).is_empty());
+        assert!(texts("mp4", config(VideoRes::R1080p, 300, None)).is_empty());
+        assert_eq!(
+            texts("mp4", config(VideoRes::R1080p, 301, None))[0].0,
+            CompatSeverity::Reject
+        );
+        assert!(texts("mkv", config(VideoRes::R1080p, 301, None)).is_empty());
+        assert_eq!(
+            texts("GIF", config(VideoRes::R480p, 60, None))[0].0,
+            CompatSeverity::Warn
+        );
+        assert_eq!(texts("webm", config(VideoRes::R480p, 30, None)).len(), 1);
+
+        let dci = VideoRes::Other(4096, 2160);
+        let uhd8k = VideoRes::Other(7680, 4320);
+        assert!(texts("mkv", config(dci, 30, Some("main"))).is_empty());
+        assert_eq!(
+            texts("mkv", config(uhd8k.clone(), 30, Some("main"))).len(),
+            1
+        );
+        assert!(texts("mkv", config(uhd8k.clone(), 30, Some("high"))).is_empty());
+        assert!(texts("mkv", config(uhd8k, 30, None)).is_empty());
+        assert_eq!(
+            texts("mkv", config(VideoRes::Other(10240, 4320), 30, None)).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_with_default_codec() {
+        let webm = VideoConfig::default().with_default_codec("webm");
+        assert_eq!(webm.codec, Some(VideoCodec::Vp9));
+        assert_eq!(codec_name(&webm), "libvpx-vp9");
+        assert_eq!(default_crf(&webm), VideoCodec::Vp9.default_crf());
+        assert!(check_compat("webm", codec_name(&webm), &webm).is_empty());
+
+        let mp4 = VideoConfig::default().with_default_codec("mp4");
+        assert_eq!(mp4.codec, None);
+        assert_eq!(mp4.to_file_name(), VideoConfig::default().to_file_name());
+        let h265 = VideoConfig {
+            codec: Some(VideoCodec::H265),
+            ..Default::default()
+        };
+        assert_eq!(
+            h265.with_default_codec("webm").codec,
+            Some(VideoCodec::H265)
+        );
+
+        assert_eq!(output_container("out/a.WebM"), "WebM");
+        assert_eq!(output_container("rtmp://example.com/live"), "flv");
+    }
+
+    #[test]
+    fn test_command_hook() {
+        let stat = stat(1920, 1080, 30.0, 3_000_000, 60);
+        let params = VideoProcessParams::new("out/2.mp4", VideoConfig::default())
+            .with_command_hook(|command| {
+                command.args(["-metadata", "title=hooked"]);
+            });
+
+        let args = command_args(&build_command(&stat, &params));
+        let position = |arg: &str| args.iter().position(|a| a == arg).unwrap();
+        assert!(position("-crf:v") < position("title=hooked"));
+        assert!(position("title=hooked") < position("out/2.mp4"));
+        assert_eq!(args.last().map(String::as_str), Some("-y"));
+        assert!(!args.contains(&"-movflags".to_string()));
+
+        let params = VideoProcessParams {
+            faststart: true,
+            ..params
+        };
+        let args = command_args(&build_command(&stat, &params));
+        assert!(args.windows(2).any(|w| w == ["-movflags", "+faststart"]));
+        assert!(!args.contains(&"-shortest".to_string()));
+
+        let params = VideoProcessParams {
+            shortest: true,
+            ..params
+        };
+        let args = command_args(&build_command(&stat, &params));
+        assert!(args.contains(&"-shortest".to_string()));
+
+        let args = command_args(&build_remux_command(&stat, &params));
+        assert!(args.windows(2).any(|w| w == ["-metadata", "title=hooked"]));
+    }
+
+    #[test]
+    fn test_progress_drivers() {
+        let progress = |frame: u32, size_kb: u32, time: &str| {
+            FfmpegEvent::Progress(FfmpegProgress {
+                frame,
+                fps: 0.0,
+                q: 0.0,
+                size_kb,
+                time: time.to_string(),
+                bitrate_kbps: 0.0,
+                speed: 1.0,
+                raw_log_message: String::new(),
+            })
+        };
+        let run = |driver: ProgressDriver, events: Vec<FfmpegEvent>| {
+            let mut seen = Vec::new();
+            consume_events(
+                events,
+                &driver,
+                &CancelToken::new(),
+                &WarningLog::new(),
+                &FrameLog::new(),
+                None,
+                |position, length, _| seen.push((position, length)),
+            )
+            .unwrap();
+            seen
+        };
+        let stat = stat(1280, 720, 30.0, 4 * 1024 * 1024, 10);
+        let params = VideoProcessParams::new("out/a.mp4", VideoConfig::default());
+
+        let driver = ProgressDriver::new(&stat, &params, TaskMode::Encode);
+        assert_eq!(
+            driver,
+            ProgressDriver::Frames(FrameProgress {
+                preroll: 0,
+                preroll_duration: Duration::ZERO,
+                total: 300,
+                duration: Duration::from_secs(10)
+            })
+        );
+        assert_eq!(
+            run(
+                driver,
+                vec![
+                    progress(150, 512, "00:00:05.00"),
+                    progress(300, 1024, "00:00:10.00")
+                ]
+            ),
+            [(150, 300), (300, 300)]
+        );
+        // FPS を下げる場合は, 出力のフレーム数で数える
+        let lowered = VideoProcessParams::new(
+            "out/a.mp4",
+            VideoConfig {
+                fps: 24,
+                ..Default::default()
+            },
+        );
+        assert!(matches!(
+            ProgressDriver::new(&stat, &lowered, TaskMode::Encode),
+            ProgressDriver::Frames(FrameProgress { total: 240, .. })
+        ));
+        // 可変フレームレートでフレーム数が見込みと合わなくても, 出力の時刻で進む.
+        // 時刻が分からなければフレーム数で数える
+        assert_eq!(
+            run(
+                driver,
+                vec![
+                    progress(40, 128, "N/A"),
+                    progress(100, 512, "00:00:05.00"),
+                    progress(290, 1024, "00:00:10.00"),
+                    progress(320, 1024, "00:00:10.05")
+                ]
+            ),
+            [(40, 300), (150, 300), (300, 300), (300, 300)]
+        );
+        // 前置きのデコード中は, フレーム数が 0 のままでも出力の開始までの残りから進む
+        let trimmed = VideoProcessParams {
+            trim: Trim {
+                start: Some(Duration::from_secs(7)),
+                end: Some(Duration::from_secs(9)),
+                seek: SeekMode::Hybrid,
+            },
+            ..VideoProcessParams::new("out/a.mp4", VideoConfig::default())
+        };
+        assert_eq!(
+            run(
+                ProgressDriver::new(&stat, &trimmed, TaskMode::Encode),
+                vec![
+                    progress(0, 0, "N/A"),
+                    progress(0, 0, "-00:00:02.50"),
+                    progress(30, 256, "00:00:01.00")
+                ]
+            ),
+            [(0, 210), (75, 210), (180, 210)]
+        );
+
+        // コピーと音声だけの書き出しは, フレーム数が 0 のままでも出力の時刻で進む
+        for mode in [TaskMode::Copy, TaskMode::Audio] {
+            let driver = ProgressDriver::new(&stat, &params, mode);
+            assert_eq!(driver, ProgressDriver::Time(Duration::from_secs(10)));
+            assert_eq!(driver.unit(), "s");
+            assert_eq!(
+                run(
+                    driver,
+                    vec![
+                        progress(0, 512, "00:00:04.50"),
+                        progress(0, 1024, "00:00:12.00")
+                    ]
+                ),
+                [(4, 10), (10, 10)]
+            );
+        }
+
+        // 長さも分からない場合は, 書き出した大きさを元動画の大きさと比べる
+        let unknown = VideoStat {
+            duration: Duration::ZERO,
+            ..stat.clone()
+        };
+        let driver = ProgressDriver::new(&unknown, &params, TaskMode::Copy);
+        assert_eq!(driver, ProgressDriver::Bytes(4096));
+        assert_eq!(driver.unit(), "KiB");
+        assert_eq!(driver.to_string(), "書き出した大きさ (目安 4096 KiB)");
+        assert_eq!(
+            run(
+                driver,
+                vec![progress(0, 1024, "N/A"), progress(0, 8192, "N/A")]
+            ),
+            [(1024, 4096), (4096, 4096)]
+        );
+        // 長さがなければフレーム数も数えられない
+        assert_eq!(
+            ProgressDriver::new(&unknown, &params, TaskMode::Encode),
+            ProgressDriver::Bytes(4096)
+        );
+    }
+
+    #[test]
+    fn test_stream_output() {
+        assert_eq!(stream_format("rtmp://localhost/live/test"), Some("flv"));
+        assert_eq!(stream_format("SRT://192.168.0.10:9000"), Some("mpegts"));
+        assert_eq!(stream_format("out/2--res-1280x720.mp4"), None);
+        assert_eq!(stream_format("C:/out/2.mp4"), None);
+
+        let stat = stat(1920, 1080, 30.0, 3_000_000, 60);
+        let params = VideoProcessParams {
+            output_path: "rtmp://localhost/live/test".to_string(),
+            config: VideoConfig::default(),
+            keep_cover: false,
+            drop_audio: false,
+            trim: Trim::default(),
+            label: None,
+            faststart: false,
+            shortest: false,
+            keep_sar: false,
+            hwaccel: None,
+            cancel: CancelToken::new(),
+            pause: PauseControl::new(),
+            warnings: WarningLog::new(),
+            frames: FrameLog::new(),
+            size_limit: None,
+            phases: PhaseLog::new(),
+            stall_timeout: None,
+            timeout: None,
+            command_hook: None,
+            log: None,
+        };
+
+        let args = command_args(&build_command(&stat, &params));
+        assert_eq!(args.iter().position(|a| a == "-re"), Some(2));
+        assert!(args
+            .windows(3)
+            .any(|w| w == ["-f", "flv", "rtmp://localhost/live/test"]));
+        assert!(!args.contains(&"-y".to_string()));
+
+        let driver = ProgressDriver::new(&stat, &params, TaskMode::Encode);
+        assert_eq!(driver, ProgressDriver::Time(Duration::from_secs(60)));
+
+        let progress = FfmpegProgress {
+            frame: 0,
+            fps: 0.0,
+            q: 0.0,
+            size_kb: 0,
+            time: "00:00:30.50".to_string(),
+            bitrate_kbps: 0.0,
+            speed: 1.0,
+            raw_log_message: String::new(),
+        };
+        assert_eq!(driver.measure(&progress), (30, 60));
+    }
+
+    #[test]
+    fn test_trim_sample() {
+        let source = Duration::from_secs(300);
+        let length = Duration::from_secs(10);
+
+        let sample = Trim::default().sample(source, length);
+        assert_eq!(sample.start, Some(Duration::from_secs(145)));
+        assert_eq!(sample.end, Some(Duration::from_secs(155)));
+
+        let trimmed = Trim {
+            start: Some(Duration::from_secs(100)),
+            end: Some(Duration::from_secs(200)),
+            seek: SeekMode::Accurate,
+        };
+        let sample = trimmed.sample(source, length);
+        assert_eq!(sample.start, Some(Duration::from_secs(145)));
+        assert_eq!(sample.end, Some(Duration::from_secs(155)));
+        assert_eq!(sample.seek, SeekMode::Accurate);
+
+        let short = Trim::default().sample(Duration::from_secs(5), length);
+        assert_eq!(short.start, None);
+        assert_eq!(short.end, None);
+    }
+
+    #[test]
+    fn test_extrapolate_size() {
+        let sample = Duration::from_secs(10);
+        let full = Duration::from_secs(600);
+
+        assert_eq!(
+            extrapolate_size(1_000_000, sample, full, true, None),
+            60_000_000
+        );
+        assert_eq!(
+            extrapolate_size(1_000_000, sample, full, false, None),
+            60_000_000
+        );
+        assert_eq!(
+            extrapolate_size(1_000_000, sample, full, false, Some(DEFAULT_AUDIO_BITRATE)),
+            60_000_000 + 9_600_000
+        );
+        assert_eq!(
+            extrapolate_size(1_000_000, Duration::ZERO, full, false, None),
+            1_000_000
+        );
+    }
+
+    #[test]
+    fn test_output_audio_bitrate() {
+        let aac = AudioStreamInfo {
+            codec: "aac".to_string(),
+            bitrate_kbps: Some(320),
+            ..Default::default()
+        };
+        // コピーする場合は元の音声のビットレートになる
+        assert_eq!(output_audio_bitrate(&aac, "out/a.mp4"), Some(320_000));
+        // WebM には格納できないので再エンコードされる
+        assert_eq!(
+            output_audio_bitrate(&aac, "out/a.webm"),
+            Some(DEFAULT_AUDIO_BITRATE)
+        );
+        let unknown = AudioStreamInfo {
+            bitrate_kbps: None,
+            ..aac
+        };
+        assert_eq!(
+            output_audio_bitrate(&unknown, "out/a.mp4"),
+            Some(DEFAULT_AUDIO_BITRATE)
+        );
+        assert_eq!(output_audio_bitrate(&unknown, "out/a.gif"), None);
+    }
+
+    /// テスト用の再現可能な疑似乱数 (xorshift64).
+    fn xorshift(seed: &mut u64) -> u64 {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 7;
+        *seed ^= *seed << 17;
+        *seed
+    }
+
+    #[test]
+    fn test_progress_fraction() {
+        let mut fraction = ProgressFraction::new(&[2.0, 1.0]);
+        assert_eq!(fraction.update(0, 50, 100), 1.0 / 3.0);
+        assert_eq!(fraction.update(0, 100, 100), 2.0 / 3.0);
+        // 次のフェーズの始まりで戻らない
+        assert_eq!(fraction.update(0, 10, 100), 2.0 / 3.0);
+        assert_eq!(fraction.update(1, 0, 100), 2.0 / 3.0);
+        assert_eq!(fraction.update(1, 50, 100), 2.5 / 3.0);
+        assert_eq!(fraction.update(1, 500, 100), 1.0);
+        assert_eq!(fraction.update(9, 0, 0), 1.0);
+
+        let mut fraction = ProgressFraction::new(&[]);
+        assert_eq!(fraction.update(0, 1, 2), 0.0);
+        assert_eq!(fraction.finish(), 1.0);
+    }
+
+    #[test]
+    fn test_progress_fraction_monotonic() {
+        let mut seed = 0x2545_f491_4f6c_dd1d;
+        for _ in 0..200 {
+            let phases = (xorshift(&mut seed) % 4 + 1) as usize;
+            let weights = (0..phases)
+                .map(|_| (xorshift(&mut seed) % 10) as f32)
+                .collect::<Vec<_>>();
+            let mut fraction = ProgressFraction::new(&weights);
+            let mut last = 0.0;
+            for _ in 0..100 {
+                let phase = (xorshift(&mut seed) % (phases as u64 + 1)) as usize;
+                let length = xorshift(&mut seed) % 1000;
+                let position = xorshift(&mut seed) % 1200;
+                let value = fraction.update(phase, position, length);
+                assert!((0.0..=1.0).contains(&value), "{} out of range", value);
+                assert!(value >= last, "{} < {} ({:?})", value, last, weights);
+                last = value;
+            }
+        }
+    }
+
+    #[test]
+    fn test_keep_alpha() {
+        assert!(has_alpha("yuva420p"));
+        assert!(has_alpha("yuva444p10le"));
+        assert!(has_alpha("rgba"));
+        assert!(has_alpha("gbrap12le"));
+        assert!(!has_alpha("yuv420p"));
+        assert!(!has_alpha("yuvj420p"));
+        assert!(!has_alpha("gbrp"));
+
+        let config = VideoConfig::default();
+        let webm = keep_alpha(&config, "webm").unwrap();
+        assert_eq!(webm.codec, Some(VideoCodec::Vp9));
+        assert_eq!(webm.pix_fmt.as_deref(), Some("yuva420p"));
+        let mov = keep_alpha(&config, "MOV").unwrap();
+        assert_eq!(mov.codec, Some(VideoCodec::ProRes));
+        assert_eq!(mov.profile.as_deref(), Some("4444"));
+
+        assert_eq!(
+            keep_alpha(&config, "mp4").unwrap_err(),
+            AlphaErr::UnsupportedContainer("mp4".to_string())
+        );
+        let h264 = VideoConfig {
+            codec: Some(VideoCodec::H264),
+            ..Default::default()
+        };
+        assert_eq!(
+            keep_alpha(&h264, "webm").unwrap_err(),
+            AlphaErr::UnsupportedCodec("libx264".to_string())
+        );
+        let no_alpha = VideoConfig {
+            pix_fmt: Some("yuv420p".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            keep_alpha(&no_alpha, "webm").unwrap_err(),
+            AlphaErr::PixelFormat("yuv420p".to_string())
+        );
+        let vp9_in_mov = VideoConfig {
+            codec: Some(VideoCodec::Vp9),
+            ..Default::default()
+        };
+        assert!(keep_alpha(&vp9_in_mov, "mov").is_err());
+    }
+
+    #[test]
+    fn test_build_command_alpha() {
+        let stat = stat(1920, 1080, 30.0, 3_000_000, 60);
+        let command = |output_path: &str, container| {
+            let config = keep_alpha(&VideoConfig::default(), container).unwrap();
+            command_args(&build_command(
+                &stat,
+                &VideoProcessParams::new(output_path, config),
+            ))
+        };
+
+        let args = command("out/2.webm", "webm");
+        assert!(args.windows(2).any(|w| w == ["-c:v", "libvpx-vp9"]));
+        assert!(args.windows(2).any(|w| w == ["-pix_fmt", "yuva420p"]));
+        assert!(args.iter().any(|a| a == "-crf:v"));
+        assert!(args.windows(2).any(|w| w == ["-b:v", "0"]));
+
+        let args = command("out/2.mov", "mov");
+        assert!(args.windows(2).any(|w| w == ["-c:v", "prores_ks"]));
+        assert!(args.windows(2).any(|w| w == ["-profile:v", "4444"]));
+        assert!(!args.iter().any(|a| a == "-crf:v"));
+        assert!(!args.iter().any(|a| a == "-b:v"));
+
+        let args = command_args(&build_command(
+            &stat,
+            &VideoProcessParams::new("out/2.mp4", VideoConfig::default()),
+        ));
+        assert!(!args.iter().any(|a| a == "-c:v"));
+    }
+
+    #[test]
+    fn test_video_codec_serde() {
+        assert_eq!(
+            serde_json::to_string(&VideoCodec::Vp9).unwrap(),
+            "\"libvpx-vp9\""
+        );
+        assert_eq!(
+            serde_json::from_str::<VideoCodec>("\"prores_ks\"").unwrap(),
+            VideoCodec::ProRes
+        );
+        assert_eq!(
+            serde_json::from_str::<VideoCodec>("\"h264_nvenc\"").unwrap(),
+            VideoCodec::Other("h264_nvenc".to_string())
+        );
+    }
+
+    /// 実際にエンコードした出力を調べるため, ffmpeg が必要 (`VVCNV_FFMPEG_TESTS=1 cargo test`).
+    #[tokio::test]
+    async fn test_alpha_survives_encode() {
+        if !testing::ffmpeg_tests_enabled("test_alpha_survives_encode") {
+            return;
+        }
+        let dir = TempDir::new("alpha");
+        let source = dir.join("source.mov").to_string_lossy().into_owned();
+        let status = std::process::Command::new(ffmpeg_sidecar::paths::ffmpeg_path())
+            .args(["-y", "-f", "lavfi", "-i"])
+            .arg("color=c=red@0.5:s=320x240:r=30:d=1,format=yuva444p10le")
+            .args(["-c:v", "prores_ks", "-profile:v", "4444", &source])
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let stat = super::stat(source).await.unwrap();
+        assert!(has_alpha(&stat.video_stream.pix_fmt));
+        let output = dir.join("output.mov").to_string_lossy().into_owned();
+        let config = keep_alpha(
+            &VideoConfig {
+                res: VideoRes::R240p,
+                has_audio: false,
+                ..Default::default()
+            },
+            "mov",
+        )
+        .unwrap();
+        process(
+            stat,
+            VideoProcessParams::new(output.clone(), config),
+            ProgressBar::hidden(),
+        )
+        .await
+        .unwrap();
+
+        let output = super::stat(output).await.unwrap();
+        assert!(
+            has_alpha(&output.video_stream.pix_fmt),
+            "{}",
+            output.video_stream.pix_fmt
+        );
+    }
+
+    const AVCHD_LOG: &str = "\
+[info] Input #0, mpegts, from 'assets/00001.m2ts':
+[info]   Duration: 00:00:10.01, start: 1.033367, bitrate: 17030 kb/s
+[info]   Program 1
+[info]   Stream #0:0[0x1011]: Video: h264 (High) (HDMV / 0x564D4448), yuv420p(top first), 1920x1080 [SAR 1:1 DAR 16:9], 29.97 fps, 59.94 tbr, 90k tbn
+[info]   Stream #0:1[0x1100]: Audio: ac3 (AC-3 / 0x332D4341), 48000 Hz, stereo, fltp, 256 kb/s
+[info]   Stream #0:2[0x1200]: Subtitle: hdmv_pgs_subtitle ([144][0][0][0] / 0x0090), 1920x1080
+[info]   Stream #0:3[0x1b00]: Video: mjpeg (Baseline), yuvj420p(pc, bt470bg/unknown/unknown), 160x120, 1 fps, 1 tbr, 90k tbn
+[info]   Program 2
+[info]   Stream #0:4[0x1021]: Video: h264 (Main) (HDMV / 0x564D4448), yuv420p(progressive), 1280x720, 29.97 fps, 29.97 tbr, 90k tbn
+[info]   Stream #0:5[0x1101]: Audio: ac3 (AC-3 / 0x332D4341), 48000 Hz, mono, fltp, 96 kb/s
+";
+
+    fn probe_log(log: &str) -> Result<VideoStat, VideoStatErr> {
+        let mut parser = ffmpeg_sidecar::log_parser::FfmpegLogParser::new(log.as_bytes());
+        let events = std::iter::from_fn(|| match parser.parse_next_event() {
+            Ok(FfmpegEvent::LogEOF) | Err(_) => None,
+            Ok(e) => Some(e),
+        });
+        ProbeLog::collect(events)?.into_stat("assets/00001.m2ts".to_string(), 1)
+    }
+
+    #[test]
+    fn test_stat_header() {
+        assert_eq!(
+            stat(1920, 1080, 29.97, 12_345_678, 90).header(),
+            "2.mp4 - 1920x1080, 29.97fps, 00:01:30.000, 12.35 MB"
+        );
+        let stat = VideoStat {
+            path: "assets/夏の日.m2ts".to_string(),
+            ..stat(1280, 720, 30.0, 999, 5)
+        };
+        assert_eq!(
+            stat.header(),
+            "夏の日.m2ts - 1280x720, 30fps, 00:00:05.000, 999 B"
+        );
+    }
+
+    const DV_LOG: &str = "\
+[info] Input #0, dv, from 'assets/tape.dv':
+[info]   Duration: 00:00:04.00, start: 0.000000, bitrate: 28771 kb/s
+[info]   Stream #0:0: Video: dvvideo, yuv411p, 720x480 [SAR 32:27 DAR 16:9], 28800 kb/s, 29.97 fps, 29.97 tbr, 29.97 tbn
+[info]   Stream #0:1: Audio: pcm_s16le, 48000 Hz, stereo, s16, 1536 kb/s
+";
+
+    #[test]
+    fn test_anamorphic() {
+        assert_eq!(
+            parse_sar("720x480 [SAR 32:27 DAR 16:9], 28800 kb/s"),
+            Some((32, 27))
+        );
+        assert_eq!(parse_sar("720x576 [SAR 0:1 DAR 0:1]"), None);
+        assert_eq!(parse_sar("1280x720, 29.97 fps"), None);
+        assert_eq!(probe_log(AVCHD_LOG).unwrap().video_stream.sar, (1, 1));
+
+        let dv = probe_log(DV_LOG).unwrap();
+        assert_eq!(dv.video_stream.sar, (32, 27));
+        assert!(dv.is_anamorphic());
+        assert_eq!(dv.display_wh(), (854, 480));
+        assert!(dv.header().contains("720x480 (SAR 32:27, 表示 854x480)"));
+
+        // 拡大の確認は表示上の大きさで行う
+        let config = |res| VideoConfig {
+            res,
+            fps: 24,
+            has_audio: false,
+            ..Default::default()
+        };
+        assert!(config(VideoRes::R480p).check_up_scaling(&dv).is_ok());
+        assert!(config(VideoRes::R720p).check_up_scaling(&dv).is_err());
+        assert_eq!(
+            judge_source(&dv, &config(VideoRes::Other(720, 480))),
+            SourceVerdict::Encode
+        );
+
+        // 既定では画素を正方形にし, --keep-sar では元の SAR のまま表示上の大きさを合わせる
+        let params = |keep_sar| VideoProcessParams {
+            keep_sar,
+            ..VideoProcessParams::new("out/tape.mp4", config(VideoRes::R480p))
+        };
+        let args = command_args(&build_command(&dv, &params(false)));
+        assert!(args
+            .windows(2)
+            .any(|w| w == ["-filter:v:0", "fps=24,scale=854:480,setsar=1"]));
+        assert!(!args.iter().any(|a| a == "-s"));
+        let args = command_args(&build_command(&dv, &params(true)));
+        assert!(args
+            .windows(2)
+            .any(|w| w == ["-filter:v:0", "fps=24,scale=720:480,setsar=32/27"]));
+
+        let square = stat(1920, 1080, 30.0, 1, 1);
+        assert_eq!(square.scale_filter(&VideoRes::R480p, false), None);
+        let args = command_args(&build_command(&square, &params(false)));
+        assert!(args.windows(2).any(|w| w == ["-s", "854x480"]));
+    }
+
+    #[test]
+    fn test_duration_matches() {
+        let secs = Duration::from_secs_f64;
+        assert!(duration_matches(secs(10.0), secs(10.3), 30.0));
+        assert!(duration_matches(secs(10.0), secs(9.6), 30.0));
+        // 途中で止まった出力は一致しない
+        assert!(!duration_matches(secs(10.0), secs(4.0), 30.0));
+        // 低い FPS では 2 フレーム分まで許す
+        assert!(duration_matches(secs(10.0), secs(11.5), 1.0));
+        assert!(!duration_matches(secs(10.0), secs(12.5), 1.0));
+    }
+
+    #[test]
+    fn test_encode_args() {
+        let source = stat(1920, 1080, 30.0, 1, 10);
+        let config = |res| VideoConfig {
+            res,
+            fps: 30,
+            has_audio: false,
+            ..Default::default()
+        };
+
+        // エンコードで使うものと同じ引数を, ffmpeg を起動せずに得られる
+        let params = VideoProcessParams::new("out/clip.mp4", config(VideoRes::R720p));
+        let args = encode_args(&source, &params).unwrap();
+        assert_eq!(args, command_args(&build_command(&source, &params)));
+        assert!(args.windows(2).any(|w| w == ["-s", "1280x720"]));
+        assert!(args.iter().any(|a| a == "out/clip.mp4"));
+
+        // 拡大になる設定は理由とともに断る
+        let params = VideoProcessParams::new("out/clip.mp4", config(VideoRes::Other(3840, 2160)));
+        let err = format!("{:#}", encode_args(&source, &params).unwrap_err());
+        assert!(err.contains("アップスケーリングエラー"));
+    }
+
+    #[tokio::test]
+    async fn test_anamorphic_keeps_aspect() {
+        if !testing::ffmpeg_tests_enabled("test_anamorphic_keeps_aspect") {
+            return;
+        }
+        let dir = TempDir::new("anamorphic");
+        let source = dir.join("source.mp4").to_string_lossy().into_owned();
+        let status = std::process::Command::new(ffmpeg_sidecar::paths::ffmpeg_path())
+            .args(["-y", "-f", "lavfi", "-i"])
+            .arg("testsrc=s=720x480:r=30:d=1,setsar=32/27")
+            .arg(&source)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let stat = super::stat(source).await.unwrap();
+        assert_eq!(stat.video_stream.sar, (32, 27));
+        let dar = |stat: &VideoStat| {
+            let (width, height) = stat.display_wh();
+            width as f64 / height as f64
+        };
+        for keep_sar in [false, true] {
+            let output = dir
+                .join(format!("output-{}.mp4", keep_sar))
+                .to_string_lossy()
+                .into_owned();
+            let params = VideoProcessParams {
+                keep_sar,
+                ..VideoProcessParams::new(
+                    output.clone(),
+                    VideoConfig {
+                        res: VideoRes::R480p,
+                        has_audio: false,
+                        ..Default::default()
+                    },
+                )
+            };
+            process(stat.clone(), params, ProgressBar::hidden())
+                .await
+                .unwrap();
+
+            let output = super::stat(output).await.unwrap();
+            assert_eq!(output.is_anamorphic(), keep_sar);
+            assert!((dar(&output) - dar(&stat)).abs() < 0.01, "{:?}", output);
+        }
+    }
+
+    #[test]
+    fn test_consume_event_flood() {
+        let warnings = WarningLog::new();
+        let events = (0..1_000_000).map(|i| match i % 1000 {
+            0 => FfmpegEvent::Done,
+            _ => FfmpegEvent::Log(
+                LogLevel::Warning,
+                format!("[warning] [h264 @ 0x55d0] corrupted macroblock {} 17", i),
+            ),
+        });
+        let driver = ProgressDriver::Time(Duration::from_secs(60));
+        consume_events(
+            events,
+            &driver,
+            &CancelToken::new(),
+            &warnings,
+            &FrameLog::new(),
+            None,
+            |_, _, _| {},
+        )
+        .unwrap();
+
+        assert_eq!(warnings.total(), 999_000);
+        assert_eq!(
+            warnings.lines().len(),
+            crate::modules::warnings::WARNING_HEAD + 1 + crate::modules::warnings::WARNING_TAIL
+        );
+        assert!(warnings.retained_bytes() < 64 * 1024);
+
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let events = std::iter::repeat_with(|| FfmpegEvent::Done);
+        assert!(matches!(
+            consume_events(
+                events,
+                &driver,
+                &cancel,
+                &warnings,
+                &FrameLog::new(),
+                None,
+                |_, _, _| {}
+            ),
+            Err(ProcessErr::Cancelled)
+        ));
+
+        // 最後のイベントを読んだ後に中断された場合も完了にしない
+        let cancel = CancelToken::new();
+        let events = [FfmpegEvent::Done].into_iter().inspect(|_| cancel.cancel());
+        assert!(matches!(
+            consume_events(
+                events,
+                &driver,
+                &cancel,
+                &warnings,
+                &FrameLog::new(),
+                None,
+                |_, _, _| {}
+            ),
+            Err(ProcessErr::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn test_consume_stalled() {
+        let watch = StallWatch::new(
+            Arc::new(crate::modules::schedule::SystemClock),
+            Some(Duration::ZERO),
+        );
+        let Some(Stop::Stalled(stall)) = watch.check() else {
+            panic!("停止していない");
+        };
+        let events = std::iter::repeat_with(|| FfmpegEvent::Done);
+        let result = consume_events(
+            events,
+            &ProgressDriver::Time(Duration::from_secs(10)),
+            &CancelToken::new(),
+            &WarningLog::new(),
+            &FrameLog::new(),
+            Some(&watch),
+            |_, _, _| {},
+        );
+        assert!(
+            matches!(result, Err(ProcessErr::Stalled { frame: 0, idle }) if idle == stall.idle)
+        );
+        assert!(!ProcessErr::TimedOut {
+            limit: Duration::from_secs(60)
+        }
+        .is_retryable());
+        assert!(ProcessErr::Ffmpeg("Cannot allocate memory".to_string()).is_retryable());
+        assert!(ProcessErr::Stalled {
+            frame: 1234,
+            idle: Duration::from_secs(600)
+        }
+        .to_string()
+        .contains("600 秒間進まない"));
+
+        let watch = StallWatch::new(Arc::new(crate::modules::schedule::SystemClock), None)
+            .with_limit(Some(Duration::ZERO));
+        assert!(watch.check().is_some());
+        let result = consume_events(
+            std::iter::repeat_with(|| FfmpegEvent::Done),
+            &ProgressDriver::Time(Duration::from_secs(10)),
+            &CancelToken::new(),
+            &WarningLog::new(),
+            &FrameLog::new(),
+            Some(&watch),
+            |_, _, _| {},
+        );
+        assert!(matches!(result, Err(ProcessErr::TimedOut { .. })));
+        assert!(ProcessErr::TimedOut {
+            limit: Duration::from_secs(5400)
+        }
+        .to_string()
+        .contains("01:30:00"));
+    }
+
+    #[test]
+    fn test_consume_frame_counts() {
+        let log = "\
+[info] frame=  120 fps= 60 q=28.0 size=     512KiB time=00:00:02.00 bitrate=2097.2kbits/s dup=2 drop=30 speed=2x
+[info] frame=  240 fps= 60 q=28.0 size=    1024KiB time=00:00:04.00 bitrate=2097.2kbits/s dup=3 drop=61 speed=2x
+";
+        let mut parser = ffmpeg_sidecar::log_parser::FfmpegLogParser::new(log.as_bytes());
+        let events = std::iter::from_fn(|| match parser.parse_next_event() {
+            Ok(FfmpegEvent::LogEOF) | Err(_) => None,
+            Ok(e) => Some(e),
+        });
+        let frames = FrameLog::new();
+        consume_events(
+            events,
+            &ProgressDriver::Time(Duration::from_secs(10)),
+            &CancelToken::new(),
+            &WarningLog::new(),
+            &frames,
+            None,
+            |_, _, _| {},
+        )
+        .unwrap();
+
+        assert_eq!(
+            frames.counts(),
+            FrameCounts {
+                frames: 240,
+                dup: 3,
+                drop: 61
+            }
+        );
+        assert!(frames.counts().note().is_some());
+    }
+
+    #[test]
+    fn test_probe_transport_stream() {
+        let stat = probe_log(AVCHD_LOG).unwrap();
+        assert_eq!(stat.duration, Duration::from_millis(10_010));
+        assert_eq!(stat.start_time, Duration::from_micros(1_033_367));
+        assert_eq!(
+            (stat.video_stream.width, stat.video_stream.height),
+            (1920, 1080)
+        );
+        assert_eq!(stat.video_stream_index, 0);
+        assert_eq!(stat.ignored_stream_indices, vec![1, 2]);
+        assert!(stat.cover_stream_indices.is_empty());
+        assert_eq!(stat.audio_streams.len(), 1);
+        assert_eq!(stat.audio_streams[0].channels, "stereo");
+        assert_eq!(stat.video_selector(), "v:0");
+
+        let args = command_args(&build_command(
+            &stat,
+            &VideoProcessParams::new("out/00001.mp4", VideoConfig::default()),
+        ));
+        assert!(args.windows(2).any(|w| w == ["-map", "0:v:0"]));
+        assert!(args.windows(2).any(|w| w == ["-map", "0:a:0?"]));
+    }
+
+    #[test]
+    fn test_probe_plain_container() {
+        let log = "\
+[info] Input #0, mov,mp4,m4a,3gp,3g2,mj2, from 'assets/2.mp4':
+[info]   Duration: 00:01:00.00, start: 0.000000, bitrate: 2000 kb/s
+[info]   Stream #0:0[0x1](und): Video: h264 (High) (avc1 / 0x31637661), yuv420p(progressive), 1920x1080, 1800 kb/s, 30 fps, 30 tbr, 15360 tbn (default)
+[info]   Stream #0:1[0x2](und): Audio: aac (LC) (mp4a / 0x6134706D), 48000 Hz, stereo, fltp, 128 kb/s (default)
+";
+        let stat = probe_log(log).unwrap();
+        assert_eq!(stat.start_time, Duration::ZERO);
+        assert!(stat.ignored_stream_indices.is_empty());
+        assert_eq!(stat.video_selector(), "V:0");
+        assert_eq!(stat.audio_streams.len(), 1);
+
+        let two_videos = log.replace(
+            "[info]   Stream #0:1",
+            "[info]   Stream #0:2[0x3](und): Video: h264 (High), yuv420p, 1280x720, 30 fps, 30 tbr, 15360 tbn\n[info]   Stream #0:1",
+        );
+        assert!(matches!(
+            probe_log(&two_videos),
+            Err(VideoStatErr::MultipleVideoStreamFound)
+        ));
+    }
+
+    #[test]
+    fn test_stream_info() {
+        let log = "\
+[info] Input #0, mov,mp4,m4a,3gp,3g2,mj2, from 'assets/portrait.mp4':
+[info]   Duration: 00:00:08.00, start: 0.000000, bitrate: 9000 kb/s
+[info]   Stream #0:0[0x1](und): Video: hevc (Main) (hvc1 / 0x31637668), yuv420p(tv, bt709), 1920x1080, 8700 kb/s, 29.97 fps, 29.97 tbr, 600 tbn (default)
+[info]     Side data:
+[info]       displaymatrix: rotation of -90.00 degrees
+[info]   Stream #0:1[0x2](jpn): Audio: aac (LC) (mp4a / 0x6134706D), 44100 Hz, mono, fltp, 96 kb/s (default)
+";
+        let stat = probe_log(log).unwrap();
+        assert_eq!(
+            stat.video_stream,
+            VideoStreamInfo {
+                codec: "hevc".to_string(),
+                width: 1920,
+                height: 1080,
+                fps: 29.97,
+                pix_fmt: "yuv420p".to_string(),
+                bitrate_kbps: Some(8700),
+                sar: (1, 1),
+                rotation: Some(-90),
+                alpha_mode: false,
+            }
+        );
+        assert_eq!(
+            stat.audio_streams,
+            [AudioStreamInfo {
+                codec: "aac".to_string(),
+                sample_rate: 44100,
+                channels: "mono".to_string(),
+                language: "jpn".to_string(),
+                bitrate_kbps: Some(96),
+            }]
+        );
+
+        // VP9 のアルファチャンネルはタグだけで示される
+        let vp9_alpha = "\
+[info] Input #0, matroska,webm, from 'assets/alpha.webm':
+[info]   Duration: 00:00:02.00, start: 0.000000, bitrate: 900 kb/s
+[info]   Stream #0:0: Video: vp9 (Profile 0), yuv420p(tv), 640x360, SAR 1:1 DAR 16:9, 30 fps, 30 tbr, 1k tbn (default)
+[info]     Metadata:
+[info]       alpha_mode      : 1
+[info]       ENCODER         : Lavc libvpx-vp9
+";
+        let stat = probe_log(vp9_alpha).unwrap();
+        assert!(stat.video_stream.alpha_mode);
+        assert!(stat.video_stream.has_alpha());
+        assert!(stat.video_stream.needs_alpha_decoder());
+        let args = command_args(&build_command(
+            &stat,
+            &VideoProcessParams::new(
+                "out/alpha.webm",
+                keep_alpha(&VideoConfig::default(), "webm").unwrap(),
+            ),
+        ));
+        let input = args.iter().position(|a| a == "-i").unwrap();
+        assert!(args[..input]
+            .windows(2)
+            .any(|w| w == ["-c:v", "libvpx-vp9"]));
+        assert!(!probe_log(AVCHD_LOG).unwrap().video_stream.has_alpha());
+
+        // 回転のない動画やビットレートの書かれていないストリーム
+        let avchd = probe_log(AVCHD_LOG).unwrap();
+        assert_eq!(avchd.video_stream.rotation, None);
+        assert_eq!(avchd.video_stream.bitrate_kbps, None);
+        assert_eq!(
+            probe_log(DV_LOG).unwrap().video_stream.bitrate_kbps,
+            Some(28800)
+        );
+
+        // 自前の型なので記録や設定の保存にそのまま使える
+        let json = serde_json::to_string(&stat.video_stream).unwrap();
+        assert_eq!(
+            serde_json::from_str::<VideoStreamInfo>(&json).unwrap(),
+            stat.video_stream
+        );
+        let sidecar = VideoStreamInfo::from(VideoStream {
+            width: 640,
+            height: 360,
+            fps: 30.0,
+            pix_fmt: "yuv420p".to_string(),
+        });
+        assert_eq!((sidecar.width, sidecar.sar), (640, (1, 1)));
+    }
+}
+
+/// 元動画の動画ストリームの情報. ffmpeg-sidecar の `VideoStream` に, ログから読み取った項目を足したもの.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VideoStreamInfo {
+    /// `h264`, `hevc` など. 不明な場合は空.
+    pub codec: String,
+    pub width: u32,
+    pub height: u32,
+    pub fps: f32,
+    pub pix_fmt: String,
+    /// ストリームの行に書かれたビットレート (kb/s). 書かれていないコンテナも多い.
+    pub bitrate_kbps: Option<u32>,
+    /// 画素の縦横比 (SAR). DV や放送の素材では正方形にならず, 保存上の大きさと表示上の大きさが異なる.
+    pub sar: (u32, u32),
+    /// `displaymatrix` の回転 (度). スマートフォンで縦に撮った動画などに付く.
+    pub rotation: Option<i32>,
+    /// ストリームのタグ `alpha_mode: 1`. VP9 のアルファチャンネルはピクセルフォーマットではなくこのタグで示される.
+    #[serde(default)]
+    pub alpha_mode: bool,
+}
+
+impl VideoStreamInfo {
+    /// ピクセルフォーマットかタグ (`alpha_mode`) でアルファチャンネルを持つと分かるか.
+    pub fn has_alpha(&self) -> bool {
+        self.alpha_mode || has_alpha(&self.pix_fmt)
+    }
+
+    /// ffmpeg 標準の VP9 デコーダーはアルファチャンネルを捨てるので, libvpx でデコードする必要があるか.
+    pub fn needs_alpha_decoder(&self) -> bool {
+        self.alpha_mode && self.codec == "vp9"
+    }
+}
+
+impl Default for VideoStreamInfo {
+    fn default() -> Self {
+        Self {
+            codec: String::new(),
+            width: 0,
+            height: 0,
+            fps: 0.0,
+            pix_fmt: String::new(),
+            bitrate_kbps: None,
+            sar: (1, 1),
+            rotation: None,
+            alpha_mode: false,
+        }
+    }
+}
+
+impl From<VideoStream> for VideoStreamInfo {
+    fn from(stream: VideoStream) -> Self {
+        Self {
+            width: stream.width,
+            height: stream.height,
+            fps: stream.fps,
+            pix_fmt: stream.pix_fmt,
+            ..Default::default()
+        }
+    }
+}
+
+impl VideoStreamInfo {
+    /// 動画ストリームでなければ `None`. 回転は別の行に書かれるので `ProbeLog` が後から設定する.
+    pub fn from_stream(stream: &Stream) -> Option<Self> {
+        Some(Self {
+            codec: stream.format.clone(),
+            bitrate_kbps: parse_stream_bitrate(&stream.raw_log_message),
+            sar: parse_sar(&stream.raw_log_message).unwrap_or((1, 1)),
+            ..Self::from(stream.video_data()?.clone())
+        })
+    }
+}
+
+/// 元動画の音声ストリームの情報. ffmpeg-sidecar の `AudioStream` に, ログから読み取った項目を足したもの.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AudioStreamInfo {
+    /// `aac`, `ac3` など. 不明な場合は空.
+    pub codec: String,
+    pub sample_rate: u32,
+    /// `stereo`, `5.1` など, ffmpeg の表記のまま.
+    pub channels: String,
+    /// `jpn`, `und` など. 不明な場合は空.
+    pub language: String,
+    pub bitrate_kbps: Option<u32>,
+}
+
+impl From<AudioStream> for AudioStreamInfo {
+    fn from(stream: AudioStream) -> Self {
+        Self {
+            sample_rate: stream.sample_rate,
+            channels: stream.channels,
+            ..Default::default()
+        }
+    }
+}
+
+impl AudioStreamInfo {
+    /// 音声ストリームでなければ `None`.
+    pub fn from_stream(stream: &Stream) -> Option<Self> {
+        Some(Self {
+            codec: stream.format.clone(),
+            language: stream.language.clone(),
+            bitrate_kbps: parse_stream_bitrate(&stream.raw_log_message),
+            ..Self::from(stream.audio_data()?.clone())
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VideoStat {
+    pub path: String,
+    pub video_stream: VideoStreamInfo,
+    /// 動画ストリームの中での番号 (`0:v:N` の N). 以下の `*_stream_indices` も同じ.
+    pub video_stream_index: usize,
+    pub audio_streams: Vec<AudioStreamInfo>,
+    pub cover_stream_indices: Vec<usize>,
+    /// 選ばなかった動画ストリーム (静止画や他のプログラムのもの).
+    pub ignored_stream_indices: Vec<usize>,
+    pub duration: Duration,
+    /// 最初のタイムスタンプ. トランスポートストリームでは 0 にならないことが多い.
+    pub start_time: Duration,
+    pub file_size: u64,
+}
+
+#[derive(Debug)]
+pub enum VideoStatErr {
+    NoVideoStreamFound,
+    MultipleVideoStreamFound,
+    NoDurationFound,
+    FfmpegError(String),
+    FileError(io::Error),
+}
+
+impl fmt::Display for VideoStatErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VideoStatErr::NoVideoStreamFound => write!(f, "動画ストリームが見つかりません"),
+            VideoStatErr::MultipleVideoStreamFound => {
+                write!(f, "動画ストリームが複数見つかりました")
+            }
+            VideoStatErr::NoDurationFound => write!(f, "動画の長さが取得できません"),
+            VideoStatErr::FfmpegError(e) => write!(f, "ffmpegエラー: {}", e),
+            VideoStatErr::FileError(e) => write!(f, "ファイルエラー: {}", e),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum VideoConfigUpScalingErr {
+    Resolution(VideoRes, VideoRes),
+    Fps(u32, u32),
+    HasAudio,
+}
+
+impl fmt::Display for VideoConfigUpScalingErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            VideoConfigUpScalingErr::Resolution(c, r) => format!(
+                "解像度が元動画より大きいです: {} > {}",
+                c.to_name(),
+                r.to_name()
+            ),
+            VideoConfigUpScalingErr::Fps(c, r) => {
+                format!("FPSが元動画より大きいです: {} > {}", c, r)
+            }
+            VideoConfigUpScalingErr::HasAudio => "音声が元動画に含まれていません".to_string(),
+        };
+        write!(f, "アップスケーリングエラー: {}", msg)
+    }
+}
+
+const REFERENCE_CRF: f64 = 23.0;
+const REFERENCE_BPP: f64 = 0.1;
+
+pub fn estimate_bits_per_pixel(crf: u32) -> f64 {
+    REFERENCE_BPP * 2f64.powf((REFERENCE_CRF - crf as f64) / 6.0)
+}
+
+impl VideoStat {
+    /// 入力ごとの進捗の上に表示する見出し. `<ファイル名> - <幅>x<高さ>, <FPS>fps, <長さ>, <サイズ>`
+    pub fn header(&self) -> String {
+        let name = Path::new(&self.path)
+            .file_name()
+            .map_or(self.path.clone(), |n| n.to_string_lossy().into_owned());
+        let display = match self.is_anamorphic() {
+            true => {
+                let (width, height) = self.display_wh();
+                format!(
+                    " (SAR {}:{}, 表示 {}x{})",
+                    self.video_stream.sar.0, self.video_stream.sar.1, width, height
+                )
+            }
+            false => String::new(),
+        };
+        format!(
+            "{} - {}x{}{}, {}fps, {}, {}",
+            name,
+            self.video_stream.width,
+            self.video_stream.height,
+            display,
+            self.video_stream.fps,
+            format_timestamp(self.duration),
+            format_size(self.file_size, config::size_format())
+        )
+    }
+
+    pub fn is_anamorphic(&self) -> bool {
+        let (num, den) = self.video_stream.sar;
+        num != den
+    }
+
+    /// 画素を正方形にしたときの大きさ. 高さはそのままで, 幅を SAR に合わせて偶数に丸める.
+    pub fn display_wh(&self) -> (u32, u32) {
+        let VideoStreamInfo {
+            width, height, sar, ..
+        } = self.video_stream;
+        if !self.is_anamorphic() {
+            return (width, height);
+        }
+
+        let (num, den) = sar;
+        let display = width as f64 * num as f64 / den as f64;
+        ((display / 2.0).round() as u32 * 2, height)
+    }
+
+    /// 画素が正方形でない場合に `-s` の代わりに使うフィルター. 既定では画素を正方形にして `res` の大きさにする.
+    /// `keep_sar` の場合は元の SAR のまま, 表示上の大きさが `res` になるように幅を縮める.
+    pub fn scale_filter(&self, res: &VideoRes, keep_sar: bool) -> Option<String> {
+        if !self.is_anamorphic() {
+            return None;
+        }
+
+        let (width, height) = res.to_wh();
+        let (num, den) = self.video_stream.sar;
+        Some(match keep_sar {
+            false => format!("scale={}:{},setsar=1", width, height),
+            true => {
+                let stored = width as f64 * den as f64 / num as f64;
+                format!(
+                    "scale={}:{},setsar={}/{}",
+                    (stored / 2.0).round() as u32 * 2,
+                    height,
+                    num,
+                    den
+                )
+            }
+        })
+    }
+
+    /// 選んだ動画ストリームを指す `-map` / `-select_streams` の指定 (入力の番号を除く).
+    pub fn video_selector(&self) -> String {
+        match self.ignored_stream_indices.is_empty() {
+            true => "V:0".to_string(),
+            false => format!("v:{}", self.video_stream_index),
+        }
+    }
+
+    pub fn bitrate(&self) -> f64 {
+        self.file_size as f64 * 8.0 / self.duration.as_secs_f64()
+    }
+
+    pub fn bits_per_pixel(&self) -> f64 {
+        let VideoStreamInfo {
+            width, height, fps, ..
+        } = self.video_stream;
+
+        self.bitrate() / (width as f64 * height as f64 * fps as f64)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceVerdict {
+    Encode,
+    AlreadyOptimal,
+}
+
+pub fn judge_source(stat: &VideoStat, config: &VideoConfig) -> SourceVerdict {
+    let VideoStreamInfo {
+        width, height, fps, ..
+    } = &stat.video_stream;
+    let (width, height, fps) = (*width, *height, *fps);
+
+    let same_res = config.res.to_wh() == (width, height) && !stat.is_anamorphic();
+    let fps_fits = fps <= config.fps as f32;
+    let bpp_fits = stat.bits_per_pixel() <= estimate_bits_per_pixel(config.crf);
+
+    if same_res && fps_fits && bpp_fits {
+        SourceVerdict::AlreadyOptimal
+    } else {
+        SourceVerdict::Encode
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VideoConfig {
+    pub res: VideoRes,
+    pub fps: u32,
+    pub crf: u32,
+    pub has_audio: bool,
+    pub pix_fmt: Option<String>,
+    pub profile: Option<String>,
+    /// `None` の場合は libx264. 出力形式によって既定のエンコーダーが異なる場合 (WebM など) は,
+    /// [`VideoConfig::with_default_codec`] で埋めてから使う.
+    pub codec: Option<VideoCodec>,
+    /// `-preset` に渡す値. `None` の場合はエンコーダーの既定値になる.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    /// AV1 のフィルムグレインの合成の強さ.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub film_grain: Option<u32>,
+    /// `-x264-params` などにまとめて渡すエンコーダー固有のパラメーター.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub codec_params: Vec<CodecParam>,
+    /// 出力のファイル名に含めるプリセット名 (`--name-preset`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset_name: Option<String>,
+    /// FPS を変えるときのフレームの作り方 (`--fps-mode`). `None` の場合は, FPS を下げるときだけ間引く.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fps_mode: Option<FpsMode>,
+    /// 元動画に音声がないため `has_audio` を外した ([`VideoConfig::without_missing_audio`]).
+    /// 出力の名前は音声を含める設定のままにする.
+    #[serde(skip)]
+    pub audio_missing: bool,
+}
+
+impl VideoConfig {
+    pub fn to_file_name(&self) -> String {
+        let preset = match &self.preset_name {
+            Some(name) => format!("{}{}", naming::PRESET_PREFIX, name),
+            None => String::new(),
+        };
+        let fps_mode = match self.fps_mode.filter(FpsMode::interpolates) {
+            Some(mode) => format!("{}{}", naming::FPS_MODE_PREFIX, mode),
+            None => String::new(),
+        };
+        // H.264 は既定のエンコーダーと同じなので, 既存の出力と同じ名前にする
+        let codec = match self.codec.as_ref().filter(|c| **c != VideoCodec::H264) {
+            Some(codec) => format!("{}{}", naming::CODEC_PREFIX, codec.name()),
+            None => String::new(),
+        };
+        format!(
+            "{}--res-{}--fps-{}--crf-{}{}{}{}",
+            preset,
+            self.res.to_file_name(),
+            self.fps,
+            self.crf,
+            fps_mode,
+            codec,
+            if self.has_audio || self.audio_missing {
+                ""
+            } else {
+                naming::NO_AUDIO_SUFFIX
+            }
+        )
+    }
+
+    pub fn check_up_scaling(&self, stat: &VideoStat) -> Result<(), VideoConfigUpScalingErr> {
+        let VideoStat {
+            video_stream: VideoStreamInfo { fps: r_fps, .. },
+            audio_streams,
+            ..
+        } = stat;
+        let (r_width, r_height) = &stat.display_wh();
+
+        let VideoConfig {
+            res,
+            fps: c_fps,
+            has_audio,
+            ..
+        } = self;
+        let (c_width, c_height) = res.to_wh();
+
+        if c_width > *r_width || c_height > *r_height {
+            return Err(VideoConfigUpScalingErr::Resolution(
+                self.res.clone(),
+                VideoRes::from_wh(*r_width, *r_height),
+            ));
+        }
+
+        if *c_fps > *r_fps as u32 {
+            return Err(VideoConfigUpScalingErr::Fps(self.fps, *r_fps as u32));
+        }
+
+        if *has_audio && audio_streams.is_empty() {
+            return Err(VideoConfigUpScalingErr::HasAudio);
+        }
+
+        Ok(())
+    }
+
+    /// 出力の FPS を変えるフィルター. 元動画の FPS の整数部分を指定した場合は, フレームの作り方を指定しない限り
+    /// 元動画の FPS のままにする (29.97fps の元動画に 29 を指定しても間引かない).
+    pub fn fps_filter(&self, stat: &VideoStat) -> Option<String> {
+        let lowers = self.fps < stat.video_stream.fps.floor() as u32;
+        match self.fps_mode {
+            Some(mode) => Some(mode.to_filter(self.fps)),
+            None => lowers.then(|| FpsMode::Drop.to_filter(self.fps)),
+        }
+    }
+
+    /// 出力の FPS. 進捗のフレーム数の見込みに使う.
+    pub fn output_fps(&self, stat: &VideoStat) -> f32 {
+        match self.fps_filter(stat) {
+            Some(_) => self.fps as f32,
+            None => stat.video_stream.fps,
+        }
+    }
+
+    /// コーデックを指定していない場合に, 出力形式 `container` の既定のエンコーダーを入れる.
+    /// CRF の既定値, 互換性の確認やエンコーダー固有のパラメーターが実際のエンコーダーに合うように, 入力ごとに計画の前に行う.
+    /// H.264 は `None` のままにして, 既存の出力と同じ名前にする.
+    pub fn with_default_codec(&self, container: &str) -> Self {
+        let codec = match &self.codec {
+            Some(codec) => Some(codec.clone()),
+            None => Some(default_codec(container)).filter(|c| *c != VideoCodec::H264),
+        };
+        Self {
+            codec,
+            ..self.clone()
+        }
+    }
+
+    /// 音声を含める設定で元動画に音声がない場合の, 音声を含めない設定. 変える必要がなければ `None`.
+    /// `--strict-audio` でなければ, 入力ごとにこれに置き換えてから名前の決定や重複の除去を行う.
+    pub fn without_missing_audio(&self, stat: &VideoStat) -> Option<Self> {
+        (self.has_audio && stat.audio_streams.is_empty()).then(|| Self {
+            has_audio: false,
+            audio_missing: true,
+            ..self.clone()
+        })
+    }
+
+    pub fn downgrade(&self) -> Option<(Self, String)> {
+        let pix_fmt = self.pix_fmt.as_deref().and_then(downgrade_pix_fmt);
+        let profile = self.profile.as_deref().and_then(downgrade_profile);
+
+        let changes = [(&self.pix_fmt, &pix_fmt), (&self.profile, &profile)]
+            .into_iter()
+            .filter_map(|(from, to)| Some(format!("{} → {}", from.as_ref()?, to.as_ref()?)))
+            .collect::<Vec<_>>();
+
+        if changes.is_empty() {
+            return None;
+        }
+
+        Some((
+            Self {
+                pix_fmt: pix_fmt.or(self.pix_fmt.clone()),
+                profile: profile.or(self.profile.clone()),
+                ..self.clone()
+            },
+            changes.join(", "),
+        ))
+    }
+}
+
+fn downgrade_pix_fmt(pix_fmt: &str) -> Option<String> {
+    ["10le", "10be", "12le", "12be"]
+        .iter()
+        .find_map(|suffix| pix_fmt.strip_suffix(suffix))
+        .map(str::to_string)
+}
+
+fn downgrade_profile(profile: &str) -> Option<String> {
+    match profile {
+        "high10" => Some("high"),
+        "main10" | "main12" => Some("main"),
+        _ => None,
+    }
+    .map(str::to_string)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncoderRejection {
+    PixelFormat,
+    Profile,
+}
+
+impl fmt::Display for EncoderRejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncoderRejection::PixelFormat => write!(f, "ピクセルフォーマット"),
+            EncoderRejection::Profile => write!(f, "プロファイル"),
+        }
+    }
+}
+
+const ENCODER_REJECTION_PATTERNS: &[(&str, EncoderRejection)] = &[
+    (
+        "profile doesn't support a bit depth",
+        EncoderRejection::Profile,
+    ),
+    ("error setting profile", EncoderRejection::Profile),
+    ("unknown profile", EncoderRejection::Profile),
+    ("invalid profile", EncoderRejection::Profile),
+    ("unsupported pixel format", EncoderRejection::PixelFormat),
+    ("incompatible pixel format", EncoderRejection::PixelFormat),
+    ("specified pixel format", EncoderRejection::PixelFormat),
+    ("unsupported bit depth", EncoderRejection::PixelFormat),
+];
+
+pub fn classify_encoder_rejection(message: &str) -> Option<EncoderRejection> {
+    let message = message.to_lowercase();
+    ENCODER_REJECTION_PATTERNS
+        .iter()
+        .find(|(pattern, _)| message.contains(pattern))
+        .map(|(_, rejection)| *rejection)
+}
+
+impl Default for VideoConfig {
+    fn default() -> Self {
+        Self {
+            res: VideoRes::R720p,
+            fps: 30,
+            crf: 23,
+            has_audio: true,
+            pix_fmt: None,
+            profile: None,
+            codec: None,
+            preset: None,
+            film_grain: None,
+            codec_params: Vec::new(),
+            preset_name: None,
+            fps_mode: None,
+            audio_missing: false,
+        }
+    }
+}
+
+pub const DEFAULT_VIDEO_CODEC: &str = "libx264";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", from = "String")]
+pub enum VideoCodec {
+    H264,
+    H265,
+    Vp9,
+    Av1,
+    ProRes,
+    Other(String),
+}
+
+impl VideoCodec {
+    /// 出力のファイル名に含める名前.
+    pub fn name(&self) -> &str {
+        match self {
+            VideoCodec::H264 => "h264",
+            VideoCodec::H265 => "h265",
+            VideoCodec::Vp9 => "vp9",
+            VideoCodec::Av1 => "av1",
+            VideoCodec::ProRes => "prores",
+            VideoCodec::Other(encoder) => encoder,
+        }
+    }
+
+    pub fn encoder(&self) -> &str {
+        match self {
+            VideoCodec::H264 => "libx264",
+            VideoCodec::H265 => "libx265",
+            VideoCodec::Vp9 => "libvpx-vp9",
+            VideoCodec::Av1 => "libaom-av1",
+            VideoCodec::ProRes => "prores_ks",
+            VideoCodec::Other(encoder) => encoder,
+        }
+    }
+
+    /// `--crf` を指定しなかった場合の CRF. 同じ数値でもコーデックによって画質が異なるため, それぞれの標準的な値にする.
+    /// CRF を使わないコーデックと不明なエンコーダーは x264 と同じ値にする.
+    pub fn default_crf(&self) -> u32 {
+        match self {
+            VideoCodec::H265 => 28,
+            VideoCodec::Vp9 => 32,
+            VideoCodec::Av1 => 30,
+            VideoCodec::H264 | VideoCodec::ProRes | VideoCodec::Other(_) => 23,
+        }
+    }
+
+    /// CRF の上限. 不明なエンコーダーでは確認しない.
+    pub fn max_crf(&self) -> Option<u32> {
+        match self {
+            VideoCodec::H264 | VideoCodec::H265 => Some(51),
+            VideoCodec::Vp9 | VideoCodec::Av1 => Some(63),
+            VideoCodec::ProRes | VideoCodec::Other(_) => None,
+        }
+    }
+
+    /// ProRes は品質を CRF ではなくプロファイルで指定する.
+    pub fn uses_crf(&self) -> bool {
+        !matches!(self, VideoCodec::ProRes)
+    }
+
+    pub fn supports_alpha(&self) -> bool {
+        matches!(self, VideoCodec::Vp9 | VideoCodec::ProRes)
+    }
+
+    /// libvpx と libaom は `-b:v 0` がないと, CRF を品質の下限とするビットレート指定 (constrained quality) になる.
+    pub fn needs_zero_bitrate(&self) -> bool {
+        matches!(self, VideoCodec::Vp9 | VideoCodec::Av1)
+    }
+}
+
+impl From<String> for VideoCodec {
+    fn from(s: String) -> Self {
+        match s.to_lowercase().as_str() {
+            "h264" | "libx264" => VideoCodec::H264,
+            "h265" | "hevc" | "libx265" => VideoCodec::H265,
+            "vp9" | "libvpx-vp9" => VideoCodec::Vp9,
+            "av1" | "libaom-av1" => VideoCodec::Av1,
+            "prores" | "prores_ks" => VideoCodec::ProRes,
+            _ => VideoCodec::Other(s),
+        }
+    }
+}
+
+impl From<VideoCodec> for String {
+    fn from(codec: VideoCodec) -> Self {
+        codec.encoder().to_string()
+    }
+}
+
+/// 出力形式 `container` で ffmpeg が既定で選ぶエンコーダー.
+pub fn default_codec(container: &str) -> VideoCodec {
+    match container.to_ascii_lowercase().as_str() {
+        "webm" => VideoCodec::Vp9,
+        _ => VideoCodec::H264,
+    }
+}
+
+/// 出力先 (ファイルのパスか配信先の URL) の出力形式.
+pub fn output_container(output: &str) -> String {
+    match stream_format(output) {
+        Some(format) => format.to_string(),
+        None => Path::new(output)
+            .extension()
+            .map_or(String::new(), |ext| ext.to_string_lossy().into_owned()),
+    }
+}
+
+pub fn default_crf(config: &VideoConfig) -> u32 {
+    config
+        .codec
+        .as_ref()
+        .map_or(VideoCodec::H264.default_crf(), VideoCodec::default_crf)
+}
+
+pub fn codec_name(config: &VideoConfig) -> &str {
+    config
+        .codec
+        .as_ref()
+        .map_or(DEFAULT_VIDEO_CODEC, VideoCodec::encoder)
+}
+
+/// FPS を変えるときのフレームの作り方.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FpsMode {
+    /// フレームを間引く (`fps`). パンなどでカクつきやすい.
+    Drop,
+    /// 前後のフレームを混ぜる (`framerate`).
+    Blend,
+    /// 動きを推定して中間のフレームを作る (`minterpolate`). 滑らかだが非常に遅い.
+    Mci,
+}
+
+impl FpsMode {
+    pub const ALL: [FpsMode; 3] = [FpsMode::Drop, FpsMode::Blend, FpsMode::Mci];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FpsMode::Drop => "drop",
+            FpsMode::Blend => "blend",
+            FpsMode::Mci => "mci",
+        }
+    }
+
+    /// 元のフレームから新しいフレームを作るかどうか. 作る場合はファイル名に含める.
+    pub fn interpolates(&self) -> bool {
+        !matches!(self, FpsMode::Drop)
+    }
+
+    pub fn to_filter(&self, fps: u32) -> String {
+        match self {
+            FpsMode::Drop => format!("fps={}", fps),
+            FpsMode::Blend => format!("framerate=fps={}", fps),
+            FpsMode::Mci => format!(
+                "minterpolate=fps={}:mi_mode=mci:mc_mode=aobmc:me_mode=bidir:vsbmc=1",
+                fps
+            ),
+        }
+    }
+
+    /// drop を 1 としたおおよそのエンコード時間の比. 動き補間はエンコードよりもはるかに重い.
+    pub fn cost_factor(&self) -> f64 {
+        match self {
+            FpsMode::Drop => 1.0,
+            FpsMode::Blend => 1.3,
+            FpsMode::Mci => 12.0,
+        }
+    }
+}
+
+impl fmt::Display for FpsMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for FpsMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        FpsMode::ALL
+            .into_iter()
+            .find(|mode| mode.name() == s)
+            .ok_or_else(|| {
+                format!(
+                    "不明なフレームの作り方です: {} (drop / blend / mci のいずれか)",
+                    s
+                )
+            })
+    }
+}
+
+/// ffmpeg のピクセルフォーマット名からアルファチャンネルを持つかを判定する.
+pub fn has_alpha(pix_fmt: &str) -> bool {
+    let pix_fmt = pix_fmt.to_lowercase();
+    ["yuva", "gbrap", "ya8", "ya16", "ayuv", "vuya"]
+        .iter()
+        .any(|prefix| pix_fmt.starts_with(prefix))
+        || ["rgba", "bgra", "argb", "abgr"]
+            .iter()
+            .any(|part| pix_fmt.contains(part))
+}
+
+/// アルファチャンネルを保持できる出力形式と, そのためのエンコーダーとピクセルフォーマット.
+struct AlphaTarget {
+    containers: &'static [&'static str],
+    codec: VideoCodec,
+    pix_fmt: &'static str,
+    profile: Option<&'static str>,
+}
+
+fn alpha_targets() -> [AlphaTarget; 2] {
+    [
+        AlphaTarget {
+            containers: &["webm", "mkv"],
+            codec: VideoCodec::Vp9,
+            pix_fmt: "yuva420p",
+            profile: None,
+        },
+        AlphaTarget {
+            containers: &["mov"],
+            codec: VideoCodec::ProRes,
+            pix_fmt: "yuva444p10le",
+            profile: Some("4444"),
+        },
+    ]
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AlphaErr {
+    UnsupportedContainer(String),
+    UnsupportedCodec(String),
+    PixelFormat(String),
+}
+
+impl fmt::Display for AlphaErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AlphaErr::UnsupportedContainer(container) => write!(
+                f,
+                ".{} ではアルファチャンネルを保持できません (.webm/.mkv (VP9) か .mov (ProRes) に対応しています)",
+                container
+            ),
+            AlphaErr::UnsupportedCodec(codec) => {
+                write!(f, "{} はアルファチャンネルに対応していません", codec)
+            }
+            AlphaErr::PixelFormat(pix_fmt) => write!(
+                f,
+                "ピクセルフォーマット {} にはアルファチャンネルがありません",
+                pix_fmt
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AlphaErr {}
+
+/// `config` を出力形式 `container` でアルファチャンネルを保持する設定にする.
+/// コーデックやピクセルフォーマットが指定されている場合は, それがアルファチャンネルに対応しているかを確かめる.
+pub fn keep_alpha(config: &VideoConfig, container: &str) -> Result<VideoConfig, AlphaErr> {
+    if let Some(codec) = config.codec.as_ref().filter(|c| !c.supports_alpha()) {
+        return Err(AlphaErr::UnsupportedCodec(codec.encoder().to_string()));
+    }
+    if let Some(pix_fmt) = config.pix_fmt.as_deref().filter(|p| !has_alpha(p)) {
+        return Err(AlphaErr::PixelFormat(pix_fmt.to_string()));
+    }
+    let target = alpha_targets()
+        .into_iter()
+        .filter(|t| {
+            t.containers
+                .iter()
+                .any(|c| c.eq_ignore_ascii_case(container))
+        })
+        .find(|t| config.codec.as_ref().is_none_or(|codec| *codec == t.codec))
+        .ok_or_else(|| AlphaErr::UnsupportedContainer(container.to_string()))?;
+
+    Ok(VideoConfig {
+        pix_fmt: config.pix_fmt.clone().or(Some(target.pix_fmt.to_string())),
+        profile: target
+            .profile
+            .map(str::to_string)
+            .or(config.profile.clone()),
+        codec: Some(target.codec),
+        ..config.clone()
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompatSeverity {
+    Warn,
+    Reject,
+}
+
+/// 空のリストはすべてに一致する. `fps_above` と `size_above` はどちらかを超えた場合に一致する.
+#[derive(Debug)]
+pub struct CompatRule {
+    pub containers: &'static [&'static str],
+    pub codecs: &'static [&'static str],
+    pub profiles: &'static [&'static str],
+    pub fps_above: Option<u32>,
+    pub size_above: Option<(u32, u32)>,
+    pub severity: CompatSeverity,
+    pub text: &'static str,
+}
+
+pub const COMPAT_RULES: &[CompatRule] = &[
+    CompatRule {
+        containers: &["webm"],
+        codecs: &["libx264", "libx265"],
+        profiles: &[],
+        fps_above: None,
+        size_above: None,
+        severity: CompatSeverity::Reject,
+        text: "WebM は H.264/H.265 に対応していません (VP8/VP9/AV1 のみ)",
+    },
+    CompatRule {
+        containers: &["mp4", "m4v", "mov"],
+        codecs: &[],
+        profiles: &[],
+        fps_above: Some(300),
+        size_above: None,
+        severity: CompatSeverity::Reject,
+        text: "MP4/MOV で 300fps を超えると, タイムベースの制約でタイムスタンプが重複することがあります",
+    },
+    CompatRule {
+        containers: &["gif"],
+        codecs: &[],
+        profiles: &[],
+        fps_above: Some(50),
+        size_above: None,
+        severity: CompatSeverity::Warn,
+        text: "GIF は 1/100 秒単位の遅延しか表せないため, 50fps を超えるとプレイヤーによって再生速度が変わります",
+    },
+    CompatRule {
+        containers: &["flv"],
+        codecs: &[],
+        profiles: &[],
+        fps_above: Some(60),
+        size_above: Some((1920, 1080)),
+        severity: CompatSeverity::Warn,
+        text: "RTMP (FLV) の配信サービスの多くは 1080p/60fps を超える入力を受け付けません",
+    },
+    CompatRule {
+        containers: &[],
+        codecs: &["libx264"],
+        profiles: &["baseline", "main"],
+        fps_above: None,
+        size_above: Some((4096, 2304)),
+        severity: CompatSeverity::Reject,
+        text: "H.264 の baseline/main プロファイル (レベル 5.2 まで) は 4096x2304 を超える解像度に対応していません",
+    },
+    CompatRule {
+        containers: &[],
+        codecs: &["libx264"],
+        profiles: &[],
+        fps_above: None,
+        size_above: Some((8192, 4320)),
+        severity: CompatSeverity::Reject,
+        text: "H.264 は 8192x4320 を超える解像度に対応していません",
+    },
+];
+
+impl CompatRule {
+    pub fn matches(&self, container: &str, codec: &str, config: &VideoConfig) -> bool {
+        let listed = |list: &[&str], value: &str| {
+            list.is_empty() || list.iter().any(|v| v.eq_ignore_ascii_case(value))
+        };
+        let profile = config.profile.as_deref().unwrap_or_default();
+        if !listed(self.containers, container)
+            || !listed(self.codecs, codec)
+            || !(self.profiles.is_empty() || listed(self.profiles, profile))
+        {
+            return false;
+        }
+        if self.fps_above.is_none() && self.size_above.is_none() {
+            return true;
+        }
+
+        let (width, height) = config.res.to_wh();
+        self.fps_above.is_some_and(|fps| config.fps > fps)
+            || self
+                .size_above
+                .is_some_and(|(w, h)| width > w || height > h)
+    }
+}
+
+pub fn check_compat(
+    container: &str,
+    codec: &str,
+    config: &VideoConfig,
+) -> Vec<&'static CompatRule> {
+    COMPAT_RULES
+        .iter()
+        .filter(|rule| rule.matches(container, codec, config))
+        .collect()
+}
+
+/// [`CancelToken::cancelled`] で中断を確かめる間隔.
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    flag: Arc<AtomicBool>,
+    parent: Option<Arc<CancelToken>>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn child(&self) -> Self {
+        Self {
+            flag: Arc::default(),
+            parent: Some(Arc::new(self.clone())),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::SeqCst) || self.parent.as_ref().is_some_and(|p| p.is_cancelled())
+    }
+
+    /// 中断されるまで待つ. 通知の仕組みはないので, 短い間隔で確かめる.
+    pub async fn cancelled(&self) {
+        while !self.is_cancelled() {
+            tokio::time::sleep(CANCEL_POLL_INTERVAL).await;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ProcessErr {
+    Cancelled,
+    /// フレーム数が `idle` の間増えなかったため中止した (`--stall-timeout`).
+    Stalled {
+        frame: u64,
+        idle: Duration,
+    },
+    /// 1 回のエンコードが `limit` を超えたため中止した (`--timeout`).
+    TimedOut {
+        limit: Duration,
+    },
+    EncoderRejected(EncoderRejection, String),
+    Mux(MuxError, String),
+    Ffmpeg(String),
+}
+
+impl fmt::Display for ProcessErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProcessErr::Cancelled => write!(f, "エンコードが中断されました"),
+            ProcessErr::Stalled { frame, idle } => write!(
+                f,
+                "エンコードが {} 秒間進まないため中止しました (フレーム {} で停止)",
+                idle.as_secs(),
+                frame
+            ),
+            ProcessErr::TimedOut { limit } => write!(
+                f,
+                "エンコードが時間の上限 ({}) を超えたため中止しました",
+                time::format_clock(*limit)
+            ),
+            ProcessErr::EncoderRejected(rejection, msg) => write!(
+                f,
+                "エンコーダーが{}の設定を受け付けませんでした: {} (ffmpeg のビルドが対応していない可能性があります)",
+                rejection, msg
+            ),
+            ProcessErr::Mux(mux, msg) => write!(f, "{}: {}", mux, msg),
+            ProcessErr::Ffmpeg(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl ProcessErr {
+    /// 再試行すれば成功しうる失敗. ffmpeg が実行中に出したエラー (メモリ不足, 出力先への書き込みの失敗など) に限る.
+    /// エンコーダーやコンテナーが設定を受け付けない場合や, 中断・停止・時間切れは何度実行しても同じになる.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, ProcessErr::Ffmpeg(_))
+    }
+}
+
+impl From<Stop> for ProcessErr {
+    fn from(stop: Stop) -> Self {
+        match stop {
+            Stop::Stalled(stall) => ProcessErr::Stalled {
+                frame: stall.frame,
+                idle: stall.idle,
+            },
+            Stop::TimedOut(limit) => ProcessErr::TimedOut { limit },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SeekMode {
+    Fast,
+    #[default]
+    Hybrid,
+    Accurate,
+}
+
+pub const HYBRID_PREROLL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trim {
+    pub start: Option<Duration>,
+    pub end: Option<Duration>,
+    pub seek: SeekMode,
+}
+
+impl Trim {
+    pub fn output_duration(&self, source: Duration) -> Duration {
+        let end = self.end.map_or(source, |e| e.min(source));
+        end.saturating_sub(self.start.unwrap_or_default())
+    }
+
+    pub fn preroll(&self) -> Duration {
+        let start = self.start.unwrap_or_default();
+        match self.seek {
+            SeekMode::Fast => Duration::ZERO,
+            SeekMode::Hybrid => start.min(HYBRID_PREROLL),
+            SeekMode::Accurate => start,
+        }
+    }
+
+    pub fn sample(&self, source: Duration, length: Duration) -> Self {
+        let start = self.start.unwrap_or_default();
+        let range = self.output_duration(source);
+        if length >= range {
+            return self.clone();
+        }
+
+        let sample_start = start + (range - length) / 2;
+        Self {
+            start: Some(sample_start),
+            end: Some(sample_start + length),
+            seek: self.seek,
+        }
+    }
+
+    pub fn for_stream_copy(&self) -> Self {
+        Self {
+            seek: SeekMode::Fast,
+            ..self.clone()
+        }
+    }
+
+    pub fn to_args(&self) -> (Vec<String>, Vec<String>) {
+        let start = self.start.unwrap_or_default();
+        let preroll = self.preroll();
+        let input_seek = start - preroll;
+
+        let mut input_args = vec![];
+        let mut output_args = vec![];
+        if !input_seek.is_zero() {
+            input_args.extend(["-ss".to_string(), format_timestamp(input_seek)]);
+        }
+        if !preroll.is_zero() {
+            output_args.extend(["-ss".to_string(), format_timestamp(preroll)]);
+        }
+        if let Some(end) = self.end {
+            output_args.extend([
+                "-t".to_string(),
+                format_timestamp(end.saturating_sub(start)),
+            ]);
+        }
+
+        (input_args, output_args)
+    }
+}
+
+pub const DEFAULT_AUDIO_BITRATE: u64 = 128_000;
+
+pub const LARGE_MP4_THRESHOLD: u64 = 4 * 1024 * 1024 * 1024;
+
+pub fn estimate_output_size(stat: &VideoStat, config: &VideoConfig, trim: &Trim) -> u64 {
+    let (width, height) = config.res.to_wh();
+    let fps = (config.fps as f64).min(stat.video_stream.fps as f64);
+    let secs = trim.output_duration(stat.duration).as_secs_f64();
+
+    let video_bits =
+        estimate_bits_per_pixel(config.crf) * width as f64 * height as f64 * fps * secs;
+    let audio_bits = match config.has_audio && !stat.audio_streams.is_empty() {
+        true => DEFAULT_AUDIO_BITRATE as f64 * secs,
+        false => 0.0,
+    };
+
+    ((video_bits + audio_bits) / 8.0).round() as u64
+}
+
+pub fn extrapolate_size(
+    sample_size: u64,
+    sample_duration: Duration,
+    full_duration: Duration,
+    sample_has_audio: bool,
+    audio_bitrate: Option<u64>,
+) -> u64 {
+    if sample_duration.is_zero() {
+        return sample_size;
+    }
+
+    let ratio = full_duration.as_secs_f64() / sample_duration.as_secs_f64();
+    let scaled = (sample_size as f64 * ratio).round() as u64;
+    if sample_has_audio {
+        return scaled;
+    }
+
+    let audio_size = audio_bitrate.map_or(0.0, |b| b as f64 * full_duration.as_secs_f64() / 8.0);
+    scaled + audio_size.round() as u64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameProgress {
+    pub preroll: u64,
+    /// 前置きの長さ. 前置きのデコード中の進捗を, 出力の開始までの残りの時間から数えるのに使う.
+    pub preroll_duration: Duration,
+    pub total: u64,
+    /// 前置きを除いた出力の長さ. 出力の時刻から進捗を数えるのに使う.
+    pub duration: Duration,
+}
+
+impl FrameProgress {
+    /// 元動画の FPS で数える.
+    pub fn new(stat: &VideoStat, trim: &Trim) -> Self {
+        Self::with_fps(stat, trim, stat.video_stream.fps)
+    }
+
+    /// 出力を `fps` で数える. 前置きは元動画のフレームをデコードするだけなので, 元動画の FPS で数える.
+    pub fn with_fps(stat: &VideoStat, trim: &Trim, fps: f32) -> Self {
+        let preroll_duration = trim.preroll();
+        let preroll =
+            (preroll_duration.as_secs_f64() * stat.video_stream.fps as f64).round() as u64;
+        let duration = trim.output_duration(stat.duration);
+        let output = (duration.as_secs_f64() * fps as f64).round() as u64;
+
+        Self {
+            preroll,
+            preroll_duration,
+            total: preroll + output,
+            duration,
+        }
+    }
+
+    pub fn position(&self, frame: u64) -> u64 {
+        match frame {
+            0 => 0,
+            _ => (self.preroll + frame).min(self.total),
+        }
+    }
+
+    /// 出力の時刻から数えた位置. 可変フレームレートや FPS を変える出力でも, フレーム数と違い長さに比例して進む.
+    /// ffmpeg が報告する時刻が調べた長さを少し超えても, `total` で止める.
+    pub fn position_at(&self, out_time: Duration) -> u64 {
+        if self.duration.is_zero() {
+            return self.total;
+        }
+        let ratio = (out_time.as_secs_f64() / self.duration.as_secs_f64()).min(1.0);
+        self.preroll + ((self.total - self.preroll) as f64 * ratio).round() as u64
+    }
+
+    /// 前置きのデコード中の位置. 出力のフレームはまだないため, ffmpeg が負の時刻で報告する出力の開始までの残り
+    /// `remaining` から数える.
+    pub fn position_before(&self, remaining: Duration) -> u64 {
+        if self.preroll_duration.is_zero() {
+            return 0;
+        }
+        let ratio = (remaining.as_secs_f64() / self.preroll_duration.as_secs_f64()).min(1.0);
+        self.preroll - (self.preroll as f64 * ratio).round() as u64
+    }
+}
+
+/// ffmpeg に実行させる処理の種類. 進捗をどの値から数えるか ([`ProgressDriver::new`]) を決める.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskMode {
+    /// 映像をエンコードする.
+    Encode,
+    /// `-c copy` でストリームをコピーする. ffmpeg が報告するフレーム数は 0 か当てにならない値になる.
+    Copy,
+    /// 映像を含まない, 音声だけの書き出し.
+    Audio,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressDriver {
+    Frames(FrameProgress),
+    Time(Duration),
+    /// 長さも分からない場合の, 書き出した大きさ (KiB). 元動画の大きさを全体の目安にする.
+    Bytes(u64),
+}
+
+impl ProgressDriver {
+    /// 映像をエンコードする場合はフレーム数, コピーや音声だけの場合は出力の時刻で数える.
+    /// 配信は `-re` で実時間に合わせるので時刻で数え, 長さが分からなければ書き出した大きさで数える.
+    pub fn new(stat: &VideoStat, params: &VideoProcessParams, mode: TaskMode) -> Self {
+        let frames = FrameProgress::with_fps(stat, &params.trim, params.config.output_fps(stat));
+        let duration = params.trim.output_duration(stat.duration);
+        match mode {
+            TaskMode::Encode if !is_stream_url(&params.output_path) && frames.total > 0 => {
+                ProgressDriver::Frames(frames)
+            }
+            _ if !duration.is_zero() => ProgressDriver::Time(duration),
+            _ => ProgressDriver::Bytes(stat.file_size.div_ceil(1024).max(1)),
+        }
+    }
+
+    pub fn measure(&self, progress: &FfmpegProgress) -> (u64, u64) {
+        match self {
+            ProgressDriver::Frames(frames) => {
+                let position = match progress.time.strip_prefix('-') {
+                    // 前置きのデコード中は, 出力の開始までの残りが負の時刻で報告される
+                    Some(remaining) => {
+                        parse_timestamp(remaining).map_or(0, |r| frames.position_before(r))
+                    }
+                    // 時刻が分からない間 (`N/A`) だけフレーム数で数える
+                    None => match parse_timestamp(&progress.time) {
+                        Ok(out_time) if !out_time.is_zero() => frames.position_at(out_time),
+                        _ => frames.position(progress.frame as u64),
+                    },
+                };
+                (position, frames.total)
+            }
+            ProgressDriver::Time(total) => {
+                let out_time = parse_timestamp(&progress.time).unwrap_or_default();
+                (out_time.min(*total).as_secs(), total.as_secs())
+            }
+            ProgressDriver::Bytes(total) => ((progress.size_kb as u64).min(*total), *total),
+        }
+    }
+
+    /// 進捗の表示の単位.
+    pub fn unit(&self) -> &'static str {
+        match self {
+            ProgressDriver::Frames(_) => "fr",
+            ProgressDriver::Time(_) => "s",
+            ProgressDriver::Bytes(_) => "KiB",
+        }
+    }
+
+    pub fn is_seeking(&self, progress: &FfmpegProgress) -> bool {
+        matches!(self, ProgressDriver::Frames(frames) if frames.preroll > 0 && progress.frame == 0)
+    }
+}
+
+pub fn is_stream_url(output: &str) -> bool {
+    stream_format(output).is_some()
+}
+
+impl fmt::Display for ProgressDriver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProgressDriver::Frames(frames) => write!(f, "フレーム数 ({} fr)", frames.total),
+            ProgressDriver::Time(total) => {
+                write!(f, "出力の時刻 ({})", time::format_clock(*total))
+            }
+            ProgressDriver::Bytes(total) => write!(f, "書き出した大きさ (目安 {} KiB)", total),
+        }
+    }
+}
+
+pub fn stream_format(output: &str) -> Option<&'static str> {
+    let (scheme, _) = output.split_once("://")?;
+    match scheme.to_ascii_lowercase().as_str() {
+        "rtmp" | "rtmps" => Some("flv"),
+        "srt" | "udp" => Some("mpegts"),
+        _ => None,
+    }
+}
+
+pub type CommandHook = Box<dyn Fn(&mut FfmpegCommand) + Send + Sync>;
+
+pub struct VideoProcessParams {
+    pub output_path: String,
+    pub config: VideoConfig,
+    pub keep_cover: bool,
+    pub drop_audio: bool,
+    pub trim: Trim,
+    pub label: Option<LabelOverlay>,
+    pub faststart: bool,
+    /// 映像と音声の短い方に合わせて出力を切る (`-shortest`).
+    pub shortest: bool,
+    /// 画素が正方形でない元動画の SAR を出力でも保つ (`--keep-sar`).
+    pub keep_sar: bool,
+    /// エンコードに使うハードウェア (`--hwaccel`). 対応していないコーデックの設定はソフトウェアでエンコードする.
+    pub hwaccel: Option<HwAccel>,
+    pub cancel: CancelToken,
+    pub pause: PauseControl,
+    /// ffmpeg の警告の記録先. 上限を超えた分は省略される.
+    pub warnings: WarningLog,
+    /// 複製・破棄したフレームの数と, 書き出した大きさの記録先.
+    pub frames: FrameLog,
+    /// 出力の大きさの上限. 超える見込みになった時点で `cancel` が中断される.
+    pub size_limit: Option<SizeLimit>,
+    /// 分割エンコードの解析・結合などの段階の記録先.
+    pub phases: PhaseLog,
+    /// フレーム数がこの時間増えなければ, 停止したとみなして中止する (`--stall-timeout`).
+    pub stall_timeout: Option<Duration>,
+    /// 1 回のエンコードの時間の上限 (`--timeout`). 一時停止していた時間は含めない.
+    pub timeout: Option<Duration>,
+    /// vvcnv が入力・エンコードの引数をすべて追加した後, 出力先 (`-f`, 出力パス, `-y`) を追加する前に呼ばれる.
+    /// ここで追加した引数は出力オプションとして扱われる. 分割エンコードでは各分割と最後の結合の両方に適用される.
+    pub command_hook: Option<CommandHook>,
+    /// 実行した ffmpeg のコマンドとログをすべて書き出す先. 分割エンコードでは各分割のものも追記する.
+    pub log: Option<EncodeLog>,
+}
+
+impl VideoProcessParams {
+    pub fn new(output_path: impl Into<String>, config: VideoConfig) -> Self {
+        Self {
+            output_path: output_path.into(),
+            config,
+            keep_cover: false,
+            drop_audio: false,
+            trim: Trim::default(),
+            label: None,
+            faststart: false,
+            shortest: false,
+            keep_sar: false,
+            hwaccel: None,
+            cancel: CancelToken::new(),
+            pause: PauseControl::new(),
+            warnings: WarningLog::new(),
+            frames: FrameLog::new(),
+            size_limit: None,
+            phases: PhaseLog::new(),
+            stall_timeout: None,
+            timeout: None,
+            command_hook: None,
+            log: None,
+        }
+    }
+
+    pub fn run_context(&self) -> RunContext<'_> {
+        RunContext {
+            cancel: &self.cancel,
+            pause: &self.pause,
+            warnings: &self.warnings,
+            frames: &self.frames,
+            stall_timeout: self.stall_timeout,
+            timeout: self.timeout,
+            log: self.log.as_ref(),
+        }
+    }
+
+    pub fn with_command_hook(
+        mut self,
+        hook: impl Fn(&mut FfmpegCommand) + Send + Sync + 'static,
+    ) -> Self {
+        self.command_hook = Some(Box::new(hook));
+        self
+    }
+}
+
+pub fn handle_ffmpeg_event_log(
+    level: LogLevel,
+    err: String,
+    ignore_no_input: bool,
+) -> Result<(), String> {
+    match level {
+        LogLevel::Fatal | LogLevel::Error => {
+            let err_body = err.split("[fatal] ").last().unwrap().to_owned();
+            let expected = err_body == "At least one output file must be specified";
+
+            if expected && ignore_no_input {
+                return Ok(());
+            }
+
+            Err(err_body)
+        }
+        LogLevel::Warning => Ok(()).inspect(|_| {
+            verbosity::warn(err);
+        }),
+        _ => Ok(()).inspect(|_| {
+            verbosity::info(format!("ffmpeg: {}", err));
+        }),
+    }
+}
+
+pub fn is_attached_pic(stream: &Stream) -> bool {
+    stream.is_video() && stream.raw_log_message.contains("(attached pic)")
+}
+
+/// 長さの確認で許す誤差の最小値. 実際には 2 フレーム分と比べて大きい方を使う.
+const MIN_DURATION_TOLERANCE: Duration = Duration::from_millis(500);
+
+/// 出力の長さが期待した長さと一致するか. 分割の結合や, 途中で止まった出力の判定に使う.
+pub fn duration_matches(expected: Duration, actual: Duration, fps: f32) -> bool {
+    let frame = Duration::from_secs_f64(1.0 / fps.max(1.0) as f64);
+    actual.abs_diff(expected) <= MIN_DURATION_TOLERANCE.max(frame * 2)
+}
+
+pub fn supports_faststart(ext: &str) -> bool {
+    matches!(ext.to_ascii_lowercase().as_str(), "mp4" | "m4v" | "mov")
+}
+
+pub fn supports_attached_pic(ext: &str) -> bool {
+    matches!(
+        ext.to_ascii_lowercase().as_str(),
+        "mp4" | "m4v" | "mov" | "mkv"
+    )
+}
+
+pub async fn stat(input_path: String) -> Result<VideoStat, VideoStatErr> {
+    stat_blocking(input_path)
+}
+
+pub(crate) fn stat_blocking(input_path: String) -> Result<VideoStat, VideoStatErr> {
+    let mut command = ffmpeg::command();
+    command.input(&input_path);
+    let mut runner = sandbox::confine(command.as_inner_mut())
+        .and_then(|_| command.spawn())
+        .map_err(|e| VideoStatErr::FfmpegError(e.to_string()))?;
+    let probe = ProbeLog::collect(runner.iter().unwrap())?;
+    let file_size = file::calc_size(&input_path).map_err(VideoStatErr::FileError)?;
+
+    probe.into_stat(input_path, file_size)
+}
+
+/// `ffmpeg -i` のログから入力のすべてのストリームを列挙する. 字幕など, 動画と音声以外のストリームも含む.
+pub fn list_streams(
+    events: impl IntoIterator<Item = FfmpegEvent>,
+) -> Result<Vec<Stream>, VideoStatErr> {
+    Ok(ProbeLog::collect(events)?
+        .streams
+        .into_iter()
+        .map(|(_, s)| s)
+        .collect())
+}
+
+pub fn probe_streams(input_path: &str) -> Result<Vec<Stream>, VideoStatErr> {
+    let mut command = ffmpeg::command();
+    command.input(input_path);
+    let mut runner = sandbox::confine(command.as_inner_mut())
+        .and_then(|_| command.spawn())
+        .map_err(|e| VideoStatErr::FfmpegError(e.to_string()))?;
+    let events = runner
+        .iter()
+        .map_err(|e| VideoStatErr::FfmpegError(e.to_string()))?;
+
+    list_streams(events)
+}
+
+/// サムネイルなどの静止画として扱う動画ストリームのコーデック.
+const STILL_IMAGE_CODECS: &[&str] = &["mjpeg", "png", "bmp", "gif", "webp"];
+
+/// 動画として扱わない静止画のストリーム (トランスポートストリームのサムネイルなど).
+pub fn is_still_image(stream: &Stream) -> bool {
+    stream
+        .video_data()
+        .is_some_and(|v| STILL_IMAGE_CODECS.contains(&stream.format.as_str()) && v.fps <= 1.0)
+}
+
+/// `ffmpeg -i` のログから読み取った入力の情報. トランスポートストリーム (.m2ts / .ts) では
+/// ストリームが `Program N` ごとに列挙されるので, どのプログラムに属するかも記録しておく.
+#[derive(Debug, Default)]
+struct ProbeLog {
+    duration: Option<f64>,
+    start_time: Option<f64>,
+    streams: Vec<(Option<u32>, Stream)>,
+    /// ストリーム (`#入力:番号`) と, その後の行に書かれた回転.
+    rotations: HashMap<(u32, u32), i32>,
+    /// その後の行に `alpha_mode: 1` のタグが書かれたストリーム.
+    alpha_modes: HashSet<(u32, u32)>,
+}
+
+fn parse_program(line: &str) -> Option<u32> {
+    line.strip_prefix("[info]")
+        .unwrap_or(line)
+        .trim()
+        .strip_prefix("Program ")?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// `720x480 [SAR 32:27 DAR 16:9]` の SAR. 不明 (0:1) の場合は `None`.
+fn parse_sar(line: &str) -> Option<(u32, u32)> {
+    let (_, rest) = line.split_once("SAR ")?;
+    let ratio = rest.split([' ', ',', ']']).next()?;
+    let (num, den) = ratio.split_once(':')?;
+    let sar = (num.parse().ok()?, den.parse().ok()?);
+
+    (sar.0 > 0 && sar.1 > 0).then_some(sar)
+}
+
+/// `..., 28800 kb/s, 29.97 fps` のビットレート (kb/s). 最後の項目では後ろに `(default)` などが付く.
+fn parse_stream_bitrate(line: &str) -> Option<u32> {
+    line.split(',')
+        .find_map(|field| field.split(" (").next()?.trim().strip_suffix(" kb/s"))?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// ストリームのメタデータの `alpha_mode      : 1`. 行の先頭にはログのレベルが付く.
+fn parse_alpha_mode(line: &str) -> bool {
+    line.split_once(':').is_some_and(|(key, value)| {
+        key.split_whitespace().last() == Some("alpha_mode") && value.trim() == "1"
+    })
+}
+
+/// `displaymatrix: rotation of -90.00 degrees` の回転 (度).
+fn parse_rotation(line: &str) -> Option<i32> {
+    let (_, rest) = line.split_once("displaymatrix: rotation of ")?;
+    let degrees = rest.strip_suffix("degrees")?.trim().parse::<f32>().ok()?;
+
+    Some(degrees.round() as i32)
+}
+
+/// `Duration: 00:00:10.01, start: 1.033367, bitrate: ...` の `start`.
+fn parse_start_time(line: &str) -> Option<f64> {
+    line.split(',')
+        .find_map(|field| field.trim().strip_prefix("start:"))?
+        .trim()
+        .parse()
+        .ok()
+}
+
+impl ProbeLog {
+    fn collect(events: impl IntoIterator<Item = FfmpegEvent>) -> Result<Self, VideoStatErr> {
+        let mut probe = Self::default();
+        let mut program = None;
+
+        for e in events {
+            match e {
+                FfmpegEvent::ParsedDuration(FfmpegDuration {
+                    duration,
+                    raw_log_message,
+                    ..
+                }) => {
+                    probe.duration = Some(duration);
+                    probe.start_time = parse_start_time(&raw_log_message);
+                }
+                FfmpegEvent::ParsedInputStream(s) => {
+                    probe.streams.push((program, s));
+                }
+                FfmpegEvent::Log(level, err) => match (parse_program(&err), parse_rotation(&err)) {
+                    (Some(id), _) => program = Some(id),
+                    (_, Some(rotation)) if !probe.streams.is_empty() => {
+                        let (_, s) = probe.streams.last().unwrap();
+                        probe
+                            .rotations
+                            .insert((s.parent_index, s.stream_index), rotation);
+                    }
+                    _ if parse_alpha_mode(&err) && !probe.streams.is_empty() => {
+                        let (_, s) = probe.streams.last().unwrap();
+                        probe.alpha_modes.insert((s.parent_index, s.stream_index));
+                    }
+                    _ => handle_ffmpeg_event_log(level, err, true)
+                        .map_err(VideoStatErr::FfmpegError)?,
+                },
+                e => verbosity::debug(format!("ffmpeg: {:?}", e)),
+            }
+        }
+
+        Ok(probe)
+    }
+
+    /// カバー画像と静止画を除いた動画ストリームのうち, 最初のプログラムに属するものを選ぶ.
+    /// ffmpeg も既定では最初のプログラムを使う.
+    fn into_stat(self, input_path: String, file_size: u64) -> Result<VideoStat, VideoStatErr> {
+        let videos = self
+            .streams
+            .iter()
+            .filter(|(_, s)| s.is_video())
+            .enumerate()
+            .collect::<Vec<_>>();
+        let cover_stream_indices = videos
+            .iter()
+            .filter(|(_, (_, s))| is_attached_pic(s))
+            .map(|(i, _)| *i)
+            .collect();
+        let candidates = videos
+            .iter()
+            .filter(|(_, (_, s))| !is_attached_pic(s) && !is_still_image(s))
+            .collect::<Vec<_>>();
+        let program = candidates.first().and_then(|(_, (program, _))| *program);
+        let selected = candidates
+            .iter()
+            .filter(|(_, (p, _))| *p == program)
+            .collect::<Vec<_>>();
+
+        let (video_stream_index, video_stream) = match selected.as_slice() {
+            [] => Err(VideoStatErr::NoVideoStreamFound),
+            [(i, (_, s))] => Ok((
+                *i,
+                VideoStreamInfo {
+                    rotation: self
+                        .rotations
+                        .get(&(s.parent_index, s.stream_index))
+                        .copied(),
+                    alpha_mode: self.alpha_modes.contains(&(s.parent_index, s.stream_index)),
+                    ..VideoStreamInfo::from_stream(s).unwrap()
+                },
+            )),
+            _ => Err(VideoStatErr::MultipleVideoStreamFound),
+        }?;
+        let ignored_stream_indices = videos
+            .iter()
+            .map(|(i, (_, s))| (*i, s))
+            .filter(|(i, s)| *i != video_stream_index && !is_attached_pic(s))
+            .map(|(i, _)| i)
+            .collect();
+
+        let audio_streams = self
+            .streams
+            .iter()
+            .filter(|(p, _)| program.is_none() || *p == program)
+            .filter_map(|(_, s)| AudioStreamInfo::from_stream(s))
+            .collect::<Vec<_>>();
+
+        let duration_sec = self.duration.ok_or(VideoStatErr::NoDurationFound)?;
+        let duration = Duration::from_secs_f64(duration_sec);
+        let start_time = self
+            .start_time
+            .and_then(|t| Duration::try_from_secs_f64(t).ok())
+            .unwrap_or_default();
+
+        Ok(VideoStat {
+            path: input_path,
+            video_stream,
+            video_stream_index,
+            audio_streams,
+            cover_stream_indices,
+            ignored_stream_indices,
+            duration,
+            start_time,
+            file_size,
+        })
+    }
+}
+
+pub fn finish_command(
+    mut command: FfmpegCommand,
+    output_path: &str,
+    hook: Option<&CommandHook>,
+) -> FfmpegCommand {
+    if let Some(hook) = hook {
+        hook(&mut command);
+    }
+
+    match stream_format(output_path) {
+        Some(format) => command.format(format).output(output_path),
+        None => command.output(output_path).overwrite(),
+    };
+
+    command
+}
+
+pub fn build_command(stat: &VideoStat, params: &VideoProcessParams) -> FfmpegCommand {
+    build_command_with_hook(stat, params, params.command_hook.as_ref())
+}
+
+pub fn build_command_with_hook(
+    stat: &VideoStat,
+    params: &VideoProcessParams,
+    hook: Option<&CommandHook>,
+) -> FfmpegCommand {
+    let VideoProcessParams {
+        output_path,
+        config,
+        keep_cover,
+        drop_audio,
+        trim,
+        label,
+        faststart,
+        shortest,
+        keep_sar,
+        hwaccel,
+        ..
+    } = params;
+
+    let scale = stat.scale_filter(&config.res, *keep_sar);
+    let (input_args, output_args) = trim.to_args();
+    let hardware = hwaccel.and_then(|hw| {
+        let codec = config
+            .codec
+            .clone()
+            .unwrap_or_else(|| default_codec(&output_container(output_path)));
+        Some((hw, hw.encoder(&codec)?))
+    });
+
+    let mut command = ffmpeg::command();
+    if is_stream_url(output_path) {
+        command.realtime();
+    }
+    if let Some((hw, _)) = hardware {
+        command.args(hw.input_args());
+    }
+    let keeps_alpha = config.pix_fmt.as_deref().is_some_and(has_alpha);
+    if keeps_alpha && stat.video_stream.needs_alpha_decoder() {
+        command.args(["-c:v", "libvpx-vp9"]);
+    }
+    command.args(input_args).input(&stat.path).args(output_args);
+    match hardware {
+        Some((hw, encoder)) => {
+            command
+                .codec_video(encoder)
+                .args(hw.quality_args(config.crf));
+        }
+        None => {
+            if let Some(codec) = &config.codec {
+                command.codec_video(codec.encoder());
+            }
+            if config.codec.as_ref().is_none_or(VideoCodec::uses_crf) {
+                command.crf(config.crf);
+            }
+            if config
+                .codec
+                .as_ref()
+                .is_some_and(VideoCodec::needs_zero_bitrate)
+            {
+                command.args(["-b:v", "0"]);
+            }
+        }
+    }
+    if scale.is_none() {
+        command.args(config.res.to_args().split_whitespace());
+    }
+
+    if let Some(pix_fmt) = &config.pix_fmt {
+        command.pix_fmt(pix_fmt);
+    }
+    if let Some(profile) = &config.profile {
+        command.args(["-profile:v", profile]);
+    }
+    if let Some(preset) = &config.preset {
+        command.preset(preset);
+    }
+    // -x264-params などはソフトウェアのエンコーダーにしか渡せない
+    if let Some((flag, params)) = codec_params::to_args(config).filter(|_| hardware.is_none()) {
+        command.args([flag, &params]);
+    }
+    if *drop_audio || !config.has_audio {
+        command.no_audio();
+    } else {
+        if let Some(audio) = stat.audio_streams.first() {
+            audio_codec(&mut command, &audio.codec, output_path);
+        }
+        if *shortest {
+            command.args(["-shortest"]);
+        }
+    }
+    let filters = config
+        .fps_filter(stat)
+        .into_iter()
+        .chain(scale)
+        .chain(label.as_ref().map(LabelOverlay::to_filter))
+        .chain(hardware.and_then(|(hw, _)| hw.upload_filter().map(String::from)))
+        .collect::<Vec<_>>();
+    if !filters.is_empty() {
+        command.args(["-filter:v:0", &filters.join(",")]);
+    }
+
+    if *faststart && supports_faststart(&file::get_file_name(output_path).1) {
+        command.args(["-movflags", "+faststart"]);
+    }
+
+    map_streams(&mut command, stat, output_path, *keep_cover);
+
+    finish_command(command, output_path, hook)
+}
+
+/// カバー画像や無視するストリームがあれば, 本編の動画と音声を明示して選ぶ.
+/// `keep_cover` なら, 格納できるコンテナに限ってカバー画像もコピーする.
+fn map_streams(command: &mut FfmpegCommand, stat: &VideoStat, output_path: &str, keep_cover: bool) {
+    if !stat.cover_stream_indices.is_empty() || !stat.ignored_stream_indices.is_empty() {
+        command
+            .map(format!("0:{}", stat.video_selector()))
+            .map("0:a:0?");
+    }
+    if let Some(cover_index) = stat.cover_stream_indices.first() {
+        let (_, ext) = file::get_file_name(output_path);
+        if keep_cover && supports_attached_pic(&ext) {
+            command.map(format!("0:v:{}", cover_index)).args([
+                "-c:v:1",
+                "copy",
+                "-disposition:v:1",
+                "attached_pic",
+            ]);
+        }
+    }
+}
+
+/// 音声は出力のコンテナに格納できればコピーし, できなければエンコードする.
+/// ffmpeg の既定に任せると, コンテナによってコピーされずに再エンコードされたり, 格納できない音声で失敗したりする.
+fn audio_codec(command: &mut FfmpegCommand, codec: &str, output_path: &str) {
+    let ext = stream_format(output_path)
+        .map_or_else(|| file::get_file_name(output_path).1, str::to_string);
+    match mux::audio_encoder(&ext) {
+        None => command.no_audio(),
+        Some(_) if mux::can_copy_audio(&ext, codec) => command.codec_audio("copy"),
+        Some(encoder) => command
+            .codec_audio(encoder)
+            .args(["-b:a", &DEFAULT_AUDIO_BITRATE.to_string()]),
+    };
+}
+
+/// [`audio_codec`] で決まる出力の音声のビットレート (bps). 音声を格納できないコンテナでは `None`.
+/// コピーする場合は元動画のビットレートを使い, 分からなければエンコードする場合と同じ値にする.
+pub fn output_audio_bitrate(audio: &AudioStreamInfo, output_path: &str) -> Option<u64> {
+    let ext = stream_format(output_path)
+        .map_or_else(|| file::get_file_name(output_path).1, str::to_string);
+    mux::audio_encoder(&ext)?;
+    let copied = audio
+        .bitrate_kbps
+        .filter(|_| mux::can_copy_audio(&ext, &audio.codec))
+        .map(|kbps| kbps as u64 * 1000);
+
+    Some(copied.unwrap_or(DEFAULT_AUDIO_BITRATE))
+}
+
+pub fn build_remux_command(stat: &VideoStat, params: &VideoProcessParams) -> FfmpegCommand {
+    let VideoProcessParams {
+        output_path,
+        config,
+        trim,
+        drop_audio,
+        keep_cover,
+        shortest,
+        command_hook,
+        ..
+    } = params;
+    let (input_args, output_args) = trim.for_stream_copy().to_args();
+
+    let mut command = ffmpeg::command();
+    command
+        .args(input_args)
+        .input(&stat.path)
+        .args(output_args)
+        .codec_video("copy");
+    if *drop_audio || !config.has_audio {
+        command.no_audio();
+    } else {
+        command.codec_audio("copy");
+        if *shortest {
+            command.args(["-shortest"]);
+        }
+    }
+    map_streams(&mut command, stat, output_path, *keep_cover);
+
+    finish_command(command, output_path, command_hook.as_ref())
+}
+
+#[derive(Debug, Clone)]
+pub struct ProcessOutcome {
+    pub args: Vec<String>,
+    pub elapsed: Duration,
+    pub warnings: Vec<String>,
+    pub frames: FrameCounts,
+    /// `params.phases` に記録された段階.
+    pub phases: Vec<Phase>,
+}
+
+/// 設定を確かめたうえで, `process` が実行するコマンドを組み立てる. ffmpeg は起動しない.
+pub fn prepare_command(stat: &VideoStat, params: &VideoProcessParams) -> Result<FfmpegCommand> {
+    if let Err(e) = VideoConfig::check_up_scaling(&params.config, stat) {
+        return Err(anyhow!(e)).context("エンコード設定に問題があります");
+    }
+
+    Ok(build_command(stat, params))
+}
+
+/// `process` が ffmpeg に渡す引数. `--dry-run` で表示する.
+pub fn encode_args(stat: &VideoStat, params: &VideoProcessParams) -> Result<Vec<String>> {
+    prepare_command(stat, params).map(|command| command_args(&command))
+}
+
+pub fn command_args(command: &FfmpegCommand) -> Vec<String> {
+    command
+        .get_args()
+        .map(|a| a.to_string_lossy().into_owned())
+        .collect()
+}
+
+const PROGRESS_TICK: Duration = Duration::from_secs(1);
+
+/// 複数のフェーズ (2 パスエンコード, 検証など) の進捗を, 各フェーズの重みで 0.0〜1.0 の割合にまとめる.
+/// 返す値は減ることがない.
+#[derive(Debug, Clone)]
+pub struct ProgressFraction {
+    weights: Vec<f32>,
+    last: f32,
+}
+
+impl ProgressFraction {
+    pub fn new(weights: &[f32]) -> Self {
+        Self {
+            weights: weights.iter().map(|w| w.max(0.0)).collect(),
+            last: 0.0,
+        }
+    }
+
+    pub fn update(&mut self, phase: usize, position: u64, length: u64) -> f32 {
+        let total = self.weights.iter().sum::<f32>();
+        if total <= 0.0 {
+            return self.last;
+        }
+        let phase = phase.min(self.weights.len() - 1);
+        let fraction = match length {
+            0 => 0.0,
+            _ => (position as f32 / length as f32).clamp(0.0, 1.0),
+        };
+        let done = self.weights[..phase].iter().sum::<f32>();
+        let value = ((done + self.weights[phase] * fraction) / total).clamp(0.0, 1.0);
+
+        self.last = self.last.max(value);
+        self.last
+    }
+
+    pub fn current(&self) -> f32 {
+        self.last
+    }
+
+    pub fn finish(&mut self) -> f32 {
+        self.last = 1.0;
+        self.last
+    }
+}
+
+pub fn report_to_bar<'a>(pb: &'a ProgressBar, message: &'a str) -> impl FnMut(u64, u64, bool) + 'a {
+    move |position, length, seeking| {
+        pb.set_length(length);
+        pb.set_position(position);
+        if seeking {
+            pb.set_message("シーク中...");
+        } else {
+            pb.set_message(message.to_string());
+        }
+    }
+}
+
+/// `run` が ffmpeg を実行する間に使う, 中断・一時停止・記録先と停止の検出の設定.
+#[derive(Clone, Copy)]
+pub struct RunContext<'a> {
+    pub cancel: &'a CancelToken,
+    pub pause: &'a PauseControl,
+    pub warnings: &'a WarningLog,
+    pub frames: &'a FrameLog,
+    pub stall_timeout: Option<Duration>,
+    pub timeout: Option<Duration>,
+    pub log: Option<&'a EncodeLog>,
+}
+
+pub fn run(
+    mut command: FfmpegCommand,
+    driver: ProgressDriver,
+    ctx: RunContext,
+    on_progress: impl FnMut(u64, u64, bool),
+) -> Result<()> {
+    sandbox::confine(command.as_inner_mut()).context("サンドボックスを準備できませんでした")?;
+    // 端末の Ctrl+C は ffmpeg に届けず, 登録した子プロセスを `PauseControl::kill_children` で終了させる.
+    // 届くと ffmpeg が出力を閉じて正常に終わり, 途中までの出力を完成したものと区別できなくなる
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(command.as_inner_mut(), 0);
+    if let Some(log) = ctx.log {
+        let args = command_args(&command);
+        log.command(command.as_inner().get_program(), &args);
+    }
+    let mut runner = command.spawn().context("ffmpeg を起動できませんでした")?;
+    let pid = runner.as_inner().id();
+    let _child = ctx.pause.register(pid);
+
+    let watch = (ctx.stall_timeout.is_some() || ctx.timeout.is_some()).then(|| {
+        StallWatch::new(Arc::new(ctx.pause.clone()), ctx.stall_timeout).with_limit(ctx.timeout)
+    });
+    let result = thread::scope(|scope| {
+        if let Some(watch) = &watch {
+            scope.spawn(|| watch.watch(stall::CHECK_INTERVAL, |_| stall::kill(pid)));
+        }
+        let events = runner.iter().unwrap().inspect(|e| {
+            if let (Some(log), FfmpegEvent::Log(_, line)) = (ctx.log, e) {
+                log.line(line);
+            }
+        });
+        let result = consume_events(
+            events,
+            &driver,
+            ctx.cancel,
+            ctx.warnings,
+            ctx.frames,
+            watch.as_ref(),
+            on_progress,
+        );
+        if let Some(watch) = &watch {
+            watch.finish();
+        }
+        result
+    });
+    // 停止や上限を検出して終了させた場合, ffmpeg のエラーより優先して返す
+    let result = match watch.as_ref().and_then(StallWatch::stopped) {
+        Some(stop) => Err(ProcessErr::from(stop)),
+        None => result,
+    };
+    if matches!(
+        result,
+        Err(ProcessErr::Cancelled | ProcessErr::Stalled { .. } | ProcessErr::TimedOut { .. })
+    ) {
+        runner.kill().ok();
+        runner.wait().ok();
+    }
+
+    result.map_err(|e| anyhow!(e))
+}
+
+/// ffmpeg のイベントを 1 つずつ処理する. イベントは読んだ順に捨て, 警告も `warnings` の上限までしか残さないので,
+/// ログが大量に出てもメモリの使用量は増えない.
+fn consume_events(
+    events: impl IntoIterator<Item = FfmpegEvent>,
+    driver: &ProgressDriver,
+    cancel: &CancelToken,
+    warnings: &WarningLog,
+    frames: &FrameLog,
+    stall: Option<&StallWatch>,
+    mut on_progress: impl FnMut(u64, u64, bool),
+) -> Result<(), ProcessErr> {
+    let mut tracker = FrameTracker::default();
+    for e in events {
+        if cancel.is_cancelled() {
+            return Err(ProcessErr::Cancelled);
+        }
+        if let Some(stop) = stall.and_then(StallWatch::stopped) {
+            return Err(ProcessErr::from(stop));
+        }
+
+        match e {
+            FfmpegEvent::Progress(progress) => {
+                if let Some(stall) = stall {
+                    stall.observe(progress.frame as u64);
+                }
+                tracker.update(
+                    frames,
+                    frames::parse_progress_line(&progress.raw_log_message, progress.frame),
+                );
+                tracker.update_bytes(frames, progress.size_kb as u64 * 1024);
+                tracker.update_speed(
+                    frames,
+                    EncodeSpeed {
+                        speed: progress.speed,
+                        fps: progress.fps,
+                    },
+                );
+                let (position, length) = driver.measure(&progress);
+                on_progress(position, length, driver.is_seeking(&progress));
+            }
+            FfmpegEvent::Log(LogLevel::Warning, line) => {
+                if let Some(shown) = warnings.push(&line) {
+                    verbosity::warn(shown);
+                }
+            }
+            FfmpegEvent::Log(level, err) => {
+                if let Err(e) = handle_ffmpeg_event_log(level, err, false) {
+                    if let Some(rejection) = classify_encoder_rejection(&e) {
+                        return Err(ProcessErr::EncoderRejected(rejection, e));
+                    }
+                    return Err(match classify_mux_error(&e) {
+                        Some(mux) => ProcessErr::Mux(mux, e),
+                        None => ProcessErr::Ffmpeg(e),
+                    });
+                }
+            }
+            e => verbosity::debug(format!("ffmpeg: {:?}", e)),
+        }
+    }
+    // 中断で ffmpeg を終了させた場合もイベントは尽きるので, 完了とみなさない
+    if cancel.is_cancelled() {
+        return Err(ProcessErr::Cancelled);
+    }
+
+    Ok(())
+}
+
+pub async fn process(
+    stat: VideoStat,
+    params: VideoProcessParams,
+    pb: ProgressBar,
+) -> Result<ProcessOutcome> {
+    let message = if is_stream_url(&params.output_path) {
+        "配信中..."
+    } else {
+        "エンコード中..."
+    };
+
+    let frames = params.frames.clone();
+    let size_limit = params.size_limit.clone();
+    let mut report = report_to_bar(&pb, message);
+    // ffmpeg が大きさを報告しない出力もあるので, 書き出し中のファイルも調べる
+    let mut poll = (!is_stream_url(&params.output_path))
+        .then(|| file::SizePoll::new(&params.output_path, file::SIZE_POLL_INTERVAL));
+    let outcome = process_with(&stat, &params, |position, length, seeking| {
+        report(position, length, seeking);
+        if seeking {
+            return;
+        }
+        let fraction = position as f64 / length.max(1) as f64;
+        let bytes = frames
+            .bytes()
+            .max(poll.as_mut().map_or(0, |poll| poll.size(Instant::now())));
+        if let Some(limit) = &size_limit {
+            limit.check(bytes, fraction);
+        }
+        let speed = frames
+            .speed()
+            .map(|speed| format!(" {}", style(speed).cyan()))
+            .unwrap_or_default();
+        let size = size_limit::progress_note(bytes, fraction)
+            .map(|note| format!(" {}", style(note).dim()))
+            .unwrap_or_default();
+        let note = frames
+            .counts()
+            .note()
+            .map(|note| format!(" {}", style(note).yellow()))
+            .unwrap_or_default();
+        pb.set_message(format!("{}{}{}{}", message, speed, size, note));
+    })?;
+    // 最後の進捗が調べた長さに届かなくても, 完了した時点で 100% にする
+    if let Some(length) = pb.length() {
+        pb.set_position(length);
+    }
+    Ok(outcome)
+}
+
+/// 進捗を 0.0〜1.0 の割合で受け取る簡易版の [`process`].
+/// ffmpeg の進捗がまとめて届く場合でも, `on_progress` は少なくとも 1 秒に 1 回呼ばれる.
+/// 成功した場合は最後に必ず 1.0 で呼ばれる.
+pub async fn process_with_progress(
+    stat: VideoStat,
+    params: VideoProcessParams,
+    on_progress: impl Fn(f32) + Sync,
+) -> Result<ProcessOutcome> {
+    process_with_progress_blocking(&stat, &params, on_progress)
+}
+
+pub(crate) fn process_with_progress_blocking(
+    stat: &VideoStat,
+    params: &VideoProcessParams,
+    on_progress: impl Fn(f32) + Sync,
+) -> Result<ProcessOutcome> {
+    let fraction = Mutex::new(ProgressFraction::new(&[1.0]));
+    let done = (Mutex::new(false), Condvar::new());
+
+    let result = thread::scope(|scope| {
+        scope.spawn(|| {
+            let (lock, stopped) = &done;
+            let mut done = lock.lock().unwrap();
+            while !*done {
+                done = stopped.wait_timeout(done, PROGRESS_TICK).unwrap().0;
+                if !*done {
+                    on_progress(fraction.lock().unwrap().current());
+                }
+            }
+        });
+
+        let result = process_with(stat, params, |position, length, _| {
+            on_progress(fraction.lock().unwrap().update(0, position, length));
+        });
+        *done.0.lock().unwrap() = true;
+        done.1.notify_all();
+
+        result
+    });
+
+    if result.is_ok() {
+        on_progress(fraction.into_inner().unwrap().finish());
+    }
+
+    result
+}
+
+pub(crate) fn process_with(
+    stat: &VideoStat,
+    params: &VideoProcessParams,
+    on_progress: impl FnMut(u64, u64, bool),
+) -> Result<ProcessOutcome> {
+    let command = prepare_command(stat, params)?;
+    let driver = ProgressDriver::new(stat, params, TaskMode::Encode);
+
+    let args = command_args(&command);
+    let started_at = params.pause.now();
+    run(command, driver, params.run_context(), on_progress)?;
+
+    Ok(ProcessOutcome {
+        args,
+        elapsed: params.pause.now().duration_since(started_at),
+        warnings: params.warnings.lines(),
+        frames: params.frames.counts(),
+        phases: params.phases.phases(),
+    })
+}
+
+pub async fn remux(
+    stat: VideoStat,
+    params: VideoProcessParams,
+    pb: ProgressBar,
+) -> Result<ProcessOutcome> {
+    let command = build_remux_command(&stat, &params);
+    let driver = ProgressDriver::new(&stat, &params, TaskMode::Copy);
+
+    let args = command_args(&command);
+    let started_at = params.pause.now();
+    run(
+        command,
+        driver,
+        params.run_context(),
+        report_to_bar(&pb, "コピー中..."),
+    )?;
+
+    Ok(ProcessOutcome {
+        args,
+        elapsed: params.pause.now().duration_since(started_at),
+        warnings: params.warnings.lines(),
+        frames: params.frames.counts(),
+        phases: params.phases.phases(),
+    })
 }
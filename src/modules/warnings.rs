@@ -0,0 +1,142 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+/// 保持する最初の警告の数. この分はその場で表示もする.
+pub const WARNING_HEAD: usize = 20;
+/// 保持する最後の警告の数.
+pub const WARNING_TAIL: usize = 20;
+/// 1 行あたりに保持する最大のバイト数. これを超える部分は切り捨てる.
+pub const MAX_LINE_BYTES: usize = 1024;
+
+#[derive(Debug, Default)]
+struct WarningBuffer {
+    head: Vec<String>,
+    tail: VecDeque<String>,
+    omitted: usize,
+}
+
+/// タスクごとの ffmpeg の警告. 最初と最後の一定数だけを保持するので, 警告が大量に出ても使うメモリは増えない.
+/// 分割エンコードでは各分割の警告をまとめて 1 つに記録する.
+#[derive(Debug, Clone, Default)]
+pub struct WarningLog {
+    inner: Arc<Mutex<WarningBuffer>>,
+}
+
+fn truncate_line(line: &str) -> String {
+    if line.len() <= MAX_LINE_BYTES {
+        return line.to_string();
+    }
+    let end = (0..=MAX_LINE_BYTES)
+        .rev()
+        .find(|i| line.is_char_boundary(*i))
+        .unwrap_or(0);
+    let mut truncated = String::with_capacity(end + '…'.len_utf8());
+    truncated.push_str(&line[..end]);
+    truncated.push('…');
+    truncated
+}
+
+impl WarningLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 警告を記録し, その場で表示すべき文字列を返す.
+    /// 最初の `WARNING_HEAD` 件と, それを超えた時点の一度だけ省略の案内を返す.
+    pub fn push(&self, line: &str) -> Option<String> {
+        let mut buffer = self.inner.lock().unwrap();
+        let line = truncate_line(line);
+        if buffer.head.len() < WARNING_HEAD {
+            buffer.head.push(line.clone());
+            return Some(line);
+        }
+
+        let first_overflow = buffer.tail.is_empty() && buffer.omitted == 0;
+        buffer.tail.push_back(line);
+        if buffer.tail.len() > WARNING_TAIL {
+            buffer.tail.pop_front();
+            buffer.omitted += 1;
+        }
+        first_overflow.then(|| format!("以降の警告は最後の {} 件のみ記録します", WARNING_TAIL))
+    }
+
+    /// 記録した警告. 間を省略した場合は `…N 件省略…` の行が入る.
+    pub fn lines(&self) -> Vec<String> {
+        let buffer = self.inner.lock().unwrap();
+        let mut lines = buffer.head.clone();
+        if buffer.omitted > 0 {
+            lines.push(format!("…{} 件省略…", buffer.omitted));
+        }
+        lines.extend(buffer.tail.iter().cloned());
+
+        lines
+    }
+
+    /// これまでに記録しようとした警告の総数 (省略したものを含む).
+    pub fn total(&self) -> usize {
+        let buffer = self.inner.lock().unwrap();
+        buffer.head.len() + buffer.tail.len() + buffer.omitted
+    }
+
+    #[cfg(test)]
+    pub(crate) fn retained_bytes(&self) -> usize {
+        let buffer = self.inner.lock().unwrap();
+        buffer
+            .head
+            .iter()
+            .chain(buffer.tail.iter())
+            .map(String::capacity)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_warning_log() {
+        let log = WarningLog::new();
+        let shown = (0..100)
+            .filter_map(|i| log.push(&format!("warning {}", i)))
+            .collect::<Vec<_>>();
+        assert_eq!(shown.len(), WARNING_HEAD + 1);
+        assert_eq!(shown[0], "warning 0");
+        assert!(shown[WARNING_HEAD].contains("最後の"));
+
+        let lines = log.lines();
+        assert_eq!(lines.len(), WARNING_HEAD + 1 + WARNING_TAIL);
+        assert_eq!(lines[WARNING_HEAD - 1], "warning 19");
+        assert_eq!(lines[WARNING_HEAD], "…60 件省略…");
+        assert_eq!(lines.last().unwrap(), "warning 99");
+        assert_eq!(log.total(), 100);
+
+        let log = WarningLog::new();
+        (0..WARNING_HEAD + 3).for_each(|i| {
+            log.push(&i.to_string());
+        });
+        assert!(log.lines().iter().all(|l| !l.contains("省略")));
+    }
+
+    #[test]
+    fn test_truncate_line() {
+        assert_eq!(truncate_line("short"), "short");
+        let long = "警".repeat(MAX_LINE_BYTES);
+        let truncated = truncate_line(&long);
+        assert!(truncated.len() <= MAX_LINE_BYTES + '…'.len_utf8());
+        assert!(truncated.ends_with('…'));
+    }
+
+    #[test]
+    fn test_warning_log_bounded() {
+        let log = WarningLog::new();
+        let line = "x".repeat(MAX_LINE_BYTES * 4);
+        for _ in 0..10_000 {
+            log.push(&line);
+        }
+        assert_eq!(log.total(), 10_000);
+        assert!(log.retained_bytes() <= (WARNING_HEAD + WARNING_TAIL) * (MAX_LINE_BYTES + 4));
+    }
+}
@@ -0,0 +1,473 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    env, fs, io,
+    path::{Component, Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use super::{
+    cli::OutputLayout,
+    matrix_file,
+    naming::{self, ParsedName},
+};
+
+/// ワークスペースの目印を兼ねる記録ファイル. vvcnv が作ったファイルだけをここに記録する.
+pub const MANIFEST_FILE: &str = ".vvcnv-workspace.json";
+pub const CONFIG_FILE: &str = "vvcnv.toml";
+pub const OUT_DIR: &str = "out";
+pub const LOGS_DIR: &str = "logs";
+
+const DEFAULT_CONFIG: &str = "\
+# vvcnv のワークスペースの設定
+# このディレクトリ以下で vvcnv を実行すると, 出力は out/ に保存され, vvcnv clean で片付けられます.
+# ここに書いた値は ~/.config/vvcnv/config.toml より優先されます (確認: vvcnv config show --origin).
+# jobs = 4
+# size-units = \"binary\"
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    Output,
+    Sidecar,
+    Thumbnail,
+    Log,
+    Temp,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// ワークスペースのルートからの相対パス.
+    pub path: PathBuf,
+    pub kind: EntryKind,
+    #[serde(default)]
+    pub failed: bool,
+    pub recorded_at: u64,
+    /// 出力を書き出したときの置き方. 出力以外と, 記録していなかった頃の出力は `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layout: Option<OutputLayout>,
+}
+
+impl ManifestEntry {
+    /// 出力のパスから元の名前と設定を読み取る. 置き方の記録がない出力は flat として読む.
+    pub fn parse_name(&self) -> Option<ParsedName> {
+        match self.kind {
+            EntryKind::Output => {
+                naming::parse_output_path(&self.path, self.layout.unwrap_or_default())
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    #[serde(default)]
+    pub entries: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CleanFilter {
+    /// 失敗したタスクのファイルだけ.
+    FailedOnly,
+    /// 記録してから指定した時間が経ったファイルだけ.
+    OlderThan(Duration),
+    All,
+}
+
+impl CleanFilter {
+    /// 一時ファイルはどの指定でも削除する.
+    fn matches(&self, entry: &ManifestEntry, now: u64) -> bool {
+        entry.kind == EntryKind::Temp
+            || match self {
+                CleanFilter::FailedOnly => entry.failed,
+                CleanFilter::OlderThan(age) => {
+                    entry.recorded_at.saturating_add(age.as_secs()) <= now
+                }
+                CleanFilter::All => true,
+            }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CleanPlan {
+    /// 削除するファイル (絶対パス).
+    pub remove: Vec<PathBuf>,
+    /// 記録はあるが既に存在しないファイル. 記録だけを消す.
+    pub missing: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Workspace {
+    root: PathBuf,
+}
+
+impl Workspace {
+    /// `dir` をワークスペースにする. 既にワークスペースであれば何も上書きせずに `false` を返す.
+    pub fn init(dir: &Path) -> Result<(Self, bool)> {
+        let root = std::path::absolute(dir)
+            .with_context(|| format!("ディレクトリを開けません: {}", dir.display()))?;
+        let workspace = Self { root };
+        let created = !workspace.manifest_path().exists();
+
+        for dir in [workspace.out_dir(), workspace.logs_dir()] {
+            fs::create_dir_all(&dir)
+                .with_context(|| format!("ディレクトリの作成に失敗しました: {}", dir.display()))?;
+        }
+        if created {
+            workspace.save_manifest(&Manifest::default())?;
+        }
+        for (name, content) in [
+            (CONFIG_FILE, DEFAULT_CONFIG),
+            (matrix_file::MATRIX_FILE, matrix_file::DEFAULT_CONTENT),
+        ] {
+            let path = workspace.root.join(name);
+            if !path.exists() {
+                fs::write(&path, content).with_context(|| {
+                    format!("設定ファイルの作成に失敗しました: {}", path.display())
+                })?;
+            }
+        }
+
+        Ok((workspace, created))
+    }
+
+    /// `start` から親ディレクトリをたどって記録ファイルを探す.
+    pub fn find(start: &Path) -> Option<Self> {
+        let start = std::path::absolute(start).ok()?;
+        start
+            .ancestors()
+            .find(|dir| dir.join(MANIFEST_FILE).is_file())
+            .map(|root| Self {
+                root: root.to_path_buf(),
+            })
+    }
+
+    /// カレントディレクトリから探したワークスペース. 一度だけ探して使い回す.
+    pub fn current() -> Option<&'static Workspace> {
+        static CURRENT: OnceLock<Option<Workspace>> = OnceLock::new();
+        CURRENT
+            .get_or_init(|| env::current_dir().ok().and_then(|dir| Self::find(&dir)))
+            .as_ref()
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn out_dir(&self) -> PathBuf {
+        self.root.join(OUT_DIR)
+    }
+
+    pub fn logs_dir(&self) -> PathBuf {
+        self.root.join(LOGS_DIR)
+    }
+
+    pub fn manifest_path(&self) -> PathBuf {
+        self.root.join(MANIFEST_FILE)
+    }
+
+    pub fn load_manifest(&self) -> Result<Manifest> {
+        let path = self.manifest_path();
+        let json = fs::read_to_string(&path)
+            .with_context(|| format!("記録ファイルの読み込みに失敗しました: {}", path.display()))?;
+        serde_json::from_str(&json)
+            .with_context(|| format!("記録ファイルの形式が不正です: {}", path.display()))
+    }
+
+    /// 書き込み途中で中断しても壊れないように, 一時ファイルに書いてから置き換える.
+    fn save_manifest(&self, manifest: &Manifest) -> Result<()> {
+        let path = self.manifest_path();
+        let tmp = path.with_extension(format!("json.tmp-{}", std::process::id()));
+        fs::write(&tmp, serde_json::to_string_pretty(manifest)?)
+            .and_then(|_| fs::rename(&tmp, &path))
+            .with_context(|| format!("記録ファイルの保存に失敗しました: {}", path.display()))
+    }
+
+    /// ワークスペースの中のパスをルートからの相対パスにする. 外のパスは `None`.
+    fn relative(&self, path: &Path) -> Option<PathBuf> {
+        let path = std::path::absolute(path).ok()?;
+        path.strip_prefix(&self.root).ok().map(Path::to_path_buf)
+    }
+
+    /// 記録されたパスを絶対パスに戻す. 記録ファイルが書き換えられていても,
+    /// ワークスペースの外や記録ファイル自身を指すことはないようにする.
+    fn resolve(&self, path: &Path) -> Option<PathBuf> {
+        let normal = path.components().all(|c| matches!(c, Component::Normal(_)));
+        match normal && path != Path::new(MANIFEST_FILE) && path.components().next().is_some() {
+            true => Some(self.root.join(path)),
+            false => None,
+        }
+    }
+
+    /// vvcnv が作ったファイルを記録する. 同じパスの記録は新しいもので置き換え, ワークスペースの外のファイルは記録しない.
+    /// 出力には `layout` も記録する.
+    pub fn record(
+        &self,
+        files: &[(PathBuf, EntryKind, bool)],
+        layout: OutputLayout,
+        now: u64,
+    ) -> Result<()> {
+        let mut manifest = self.load_manifest()?;
+        for (path, kind, failed) in files {
+            let Some(path) = self.relative(path) else {
+                continue;
+            };
+            manifest.entries.retain(|e| e.path != path);
+            manifest.entries.push(ManifestEntry {
+                path,
+                kind: *kind,
+                failed: *failed,
+                recorded_at: now,
+                layout: (*kind == EntryKind::Output).then_some(layout),
+            });
+        }
+
+        self.save_manifest(&manifest)
+    }
+
+    /// 記録されたファイルのうち `filter` に当てはまるものを列挙する. 記録のないファイルは対象にしない.
+    pub fn plan_clean(&self, manifest: &Manifest, filter: CleanFilter, now: u64) -> CleanPlan {
+        let mut plan = CleanPlan::default();
+        for entry in manifest.entries.iter().filter(|e| filter.matches(e, now)) {
+            let Some(path) = self.resolve(&entry.path) else {
+                continue;
+            };
+            match fs::symlink_metadata(&path) {
+                Ok(metadata) if metadata.is_file() => plan.remove.push(path),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => plan.missing.push(path),
+                _ => {}
+            }
+        }
+
+        plan
+    }
+
+    /// `plan` のファイルを削除し, 削除したファイルと既に存在しないファイルの記録を消す.
+    pub fn clean(&self, plan: &CleanPlan) -> Result<usize> {
+        let mut removed = Vec::new();
+        for path in &plan.remove {
+            match fs::remove_file(path) {
+                Ok(()) => removed.push(path),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => removed.push(path),
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("削除に失敗しました: {}", path.display()))
+                }
+            }
+        }
+
+        let mut manifest = self.load_manifest()?;
+        manifest.entries.retain(|e| {
+            let path = self.root.join(&e.path);
+            !removed.contains(&&path) && !plan.missing.contains(&path)
+        });
+        self.save_manifest(&manifest)?;
+
+        Ok(removed.len())
+    }
+}
+
+/// 出力先. ワークスペースの中で実行した場合はそのルートの `out/`, それ以外はカレントディレクトリの `out/`.
+pub fn out_dir() -> PathBuf {
+    Workspace::current().map_or(PathBuf::from(OUT_DIR), Workspace::out_dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::testing::TempDir;
+
+    fn temp_dir(name: &str) -> TempDir {
+        TempDir::new(&format!("workspace-{}", name))
+    }
+
+    #[test]
+    fn test_init_and_find() {
+        let dir = temp_dir("init");
+        assert!(Workspace::find(&dir).is_none());
+
+        let (workspace, created) = Workspace::init(&dir).unwrap();
+        assert!(created);
+        assert!(workspace.out_dir().is_dir());
+        assert!(workspace.logs_dir().is_dir());
+        assert!(dir.join(CONFIG_FILE).is_file());
+        assert!(dir.join(matrix_file::MATRIX_FILE).is_file());
+        assert!(workspace.load_manifest().unwrap().entries.is_empty());
+
+        let nested = dir.join("shots").join("day1");
+        fs::create_dir_all(&nested).unwrap();
+        assert_eq!(Workspace::find(&nested), Some(workspace.clone()));
+
+        // 二度目は既存の記録を上書きしない
+        let output = workspace.out_dir().join("a.mp4");
+        fs::write(&output, "a").unwrap();
+        workspace
+            .record(&[(output, EntryKind::Output, false)], OutputLayout::Flat, 0)
+            .unwrap();
+        let (_, created) = Workspace::init(&dir).unwrap();
+        assert!(!created);
+        assert_eq!(workspace.load_manifest().unwrap().entries.len(), 1);
+
+        // 日本語, 絵文字, 結合文字を含むパスも記録から元のファイルに戻る
+        let nested = workspace.out_dir().join("会議");
+        fs::create_dir_all(&nested).unwrap();
+        let outputs = ["会議録画🎥 2024.mp4", "cafe\u{301}.mp4"].map(|name| nested.join(name));
+        for output in &outputs {
+            fs::write(output, "a").unwrap();
+        }
+        let files = outputs
+            .iter()
+            .map(|o| (o.clone(), EntryKind::Output, false))
+            .collect::<Vec<_>>();
+        workspace
+            .record(&files, OutputLayout::PerConfig, 0)
+            .unwrap();
+        let manifest = workspace.load_manifest().unwrap();
+        let resolved = manifest
+            .entries
+            .iter()
+            .filter_map(|e| workspace.resolve(&e.path))
+            .collect::<Vec<_>>();
+        assert!(outputs.iter().all(|o| resolved.contains(o)));
+    }
+
+    #[test]
+    fn test_clean_only_recorded_files() {
+        let dir = temp_dir("clean");
+        let (workspace, _) = Workspace::init(&dir).unwrap();
+        let out = workspace.out_dir();
+        let file = |name: &str| {
+            let path = out.join(name);
+            fs::write(&path, name).unwrap();
+            path
+        };
+        let done = file("done.mp4");
+        let failed = file("failed.mp4");
+        let temp = file(".vvcnv-calibration.mp4");
+        let unrecorded = file("mine.mp4");
+        let outside_dir = temp_dir("clean-outside");
+        let outside = outside_dir.join("outside.mp4");
+        fs::write(&outside, "x").unwrap();
+        workspace
+            .record(
+                &[
+                    (done.clone(), EntryKind::Output, false),
+                    (failed.clone(), EntryKind::Output, true),
+                    (temp.clone(), EntryKind::Temp, false),
+                    (outside.clone(), EntryKind::Output, false),
+                    (out.join("gone.mp4"), EntryKind::Output, false),
+                ],
+                OutputLayout::Flat,
+                1_000,
+            )
+            .unwrap();
+        // 外のファイルは記録されない
+        assert_eq!(workspace.load_manifest().unwrap().entries.len(), 4);
+
+        let manifest = workspace.load_manifest().unwrap();
+        let plan = workspace.plan_clean(&manifest, CleanFilter::FailedOnly, 1_000);
+        assert_eq!(plan.remove, vec![failed.clone(), temp.clone()]);
+        assert_eq!(
+            workspace
+                .plan_clean(
+                    &manifest,
+                    CleanFilter::OlderThan(Duration::from_secs(60)),
+                    1_030
+                )
+                .remove,
+            vec![temp.clone()]
+        );
+        assert_eq!(
+            workspace
+                .plan_clean(
+                    &manifest,
+                    CleanFilter::OlderThan(Duration::from_secs(60)),
+                    1_060
+                )
+                .remove,
+            vec![done.clone(), failed.clone(), temp.clone()]
+        );
+
+        let plan = workspace.plan_clean(&manifest, CleanFilter::All, 1_000);
+        assert_eq!(plan.missing, vec![out.join("gone.mp4")]);
+        assert_eq!(workspace.clean(&plan).unwrap(), 3);
+        assert!(!done.exists() && !failed.exists() && !temp.exists());
+        assert!(unrecorded.exists());
+        assert!(outside.exists());
+        assert!(workspace.manifest_path().exists());
+        assert!(workspace.load_manifest().unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn test_record_layout() {
+        let dir = temp_dir("layout");
+        let (workspace, _) = Workspace::init(&dir).unwrap();
+        let config_dir = workspace.out_dir().join("res-1280x720--fps-30--crf-28");
+        let output = config_dir.join("clip.mp4");
+        let sidecar = config_dir.join("clip.mp4.vvcnv.json");
+        workspace
+            .record(
+                &[
+                    (output, EntryKind::Output, false),
+                    (sidecar, EntryKind::Sidecar, false),
+                ],
+                OutputLayout::PerConfig,
+                0,
+            )
+            .unwrap();
+
+        // 出力の置き方を記録し, 設定をディレクトリの名前から戻せる
+        let manifest = workspace.load_manifest().unwrap();
+        let [output, sidecar] = &manifest.entries[..] else {
+            panic!("記録が 2 件ではありません");
+        };
+        assert_eq!(output.layout, Some(OutputLayout::PerConfig));
+        let parsed = output.parse_name().unwrap();
+        assert_eq!((parsed.source.as_str(), parsed.config.crf), ("clip", 28));
+        assert_eq!(sidecar.layout, None);
+        assert!(sidecar.parse_name().is_none());
+
+        // 置き方を記録していなかった頃の記録は flat として読む
+        let old: ManifestEntry = serde_json::from_str(
+            r#"{"path": "out/clip--res-1280x720--fps-30--crf-28.mp4", "kind": "output", "recorded_at": 0}"#,
+        )
+        .unwrap();
+        assert_eq!(old.layout, None);
+        assert_eq!(old.parse_name().unwrap().source, "clip");
+    }
+
+    /// 記録ファイルを書き換えられても, ワークスペースの外や記録ファイル自身, ディレクトリは削除しない.
+    #[test]
+    fn test_clean_rejects_tampered_entries() {
+        let dir = temp_dir("tampered");
+        let (workspace, _) = Workspace::init(&dir).unwrap();
+        let outside_dir = temp_dir("tampered-outside");
+        let outside = outside_dir.join("keep.mp4");
+        fs::write(&outside, "x").unwrap();
+        let entry = |path: PathBuf| ManifestEntry {
+            path,
+            kind: EntryKind::Output,
+            failed: false,
+            recorded_at: 0,
+            layout: None,
+        };
+        let manifest = Manifest {
+            entries: vec![
+                entry(outside.clone()),
+                entry(PathBuf::from("../").join(outside.strip_prefix(env::temp_dir()).unwrap())),
+                entry(PathBuf::from(MANIFEST_FILE)),
+                entry(PathBuf::from(OUT_DIR)),
+                entry(PathBuf::new()),
+            ],
+        };
+
+        let plan = workspace.plan_clean(&manifest, CleanFilter::All, 0);
+        assert_eq!(plan, CleanPlan::default());
+        assert!(outside.exists());
+        assert!(workspace.manifest_path().exists());
+        assert!(workspace.out_dir().is_dir());
+    }
+}
@@ -0,0 +1,270 @@
+//! 結合テストの共通部分. テストのたびに ffmpeg で小さな元動画を作り, 一時ディレクトリに置く.
+//!
+//! ffmpeg のない環境でも `cargo test` が通るように, 環境変数 `VVCNV_FFMPEG_TESTS=1` を指定した場合だけ実行する.
+//! 新しいケースは, [`Source`] で元動画の特徴を選び, [`Workspace::encode`] の結果を調べるだけで追加できる.
+
+use std::{path::Path, process::Command};
+
+use indicatif::ProgressBar;
+use vvcnv::video::{self, VideoConfig, VideoProcessParams, VideoStat};
+
+/// 単体テストと同じ一時ディレクトリと, ffmpeg を使うテストの切り替え.
+#[path = "../../src/modules/testing.rs"]
+mod testing;
+
+use testing::TempDir;
+
+/// 元動画の音声.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Audio {
+    None,
+    Stereo,
+    Surround,
+}
+
+/// テスト用の元動画の特徴. `testsrc2` の映像と `sine` の音声から作る.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Source {
+    pub width: u32,
+    pub height: u32,
+    /// `-r` に渡す値 (例: `30`, `30000/1001`).
+    pub fps: &'static str,
+    pub secs: u32,
+    pub audio: Audio,
+    /// BT.2020 / PQ の色情報を付ける. 画素は 8 bit のまま.
+    pub hdr: bool,
+    /// 単色の PNG をカバーアート (attached pic) として付ける.
+    pub cover: bool,
+}
+
+impl Source {
+    pub const fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            fps: "30",
+            secs: 2,
+            audio: Audio::Stereo,
+            hdr: false,
+            cover: false,
+        }
+    }
+
+    pub const fn fps(self, fps: &'static str) -> Self {
+        Self { fps, ..self }
+    }
+
+    pub const fn secs(self, secs: u32) -> Self {
+        Self { secs, ..self }
+    }
+
+    pub const fn audio(self, audio: Audio) -> Self {
+        Self { audio, ..self }
+    }
+
+    pub const fn hdr(self) -> Self {
+        Self { hdr: true, ..self }
+    }
+
+    pub const fn cover(self) -> Self {
+        Self {
+            cover: true,
+            ..self
+        }
+    }
+
+    pub fn fps_value(&self) -> f64 {
+        match self.fps.split_once('/') {
+            Some((num, den)) => num.parse::<f64>().unwrap() / den.parse::<f64>().unwrap(),
+            None => self.fps.parse().unwrap(),
+        }
+    }
+
+    fn file_name(&self) -> String {
+        format!(
+            "{}x{}-{}-{}s-{:?}{}{}.mp4",
+            self.width,
+            self.height,
+            self.fps.replace('/', "_"),
+            self.secs,
+            self.audio,
+            if self.hdr { "-hdr" } else { "" },
+            if self.cover { "-cover" } else { "" }
+        )
+        .to_lowercase()
+    }
+
+    fn ffmpeg_args(&self, output: &Path) -> Vec<String> {
+        let mut args = vec![
+            "-y".to_string(),
+            "-f".to_string(),
+            "lavfi".to_string(),
+            "-i".to_string(),
+            format!(
+                "testsrc2=s={}x{}:r={}:d={}",
+                self.width, self.height, self.fps, self.secs
+            ),
+        ];
+        // 入力ごとのオプションより前に置かないと, 後の出力のオプションが入力に付いてしまう
+        if self.cover {
+            args.extend(["-f", "lavfi", "-i", "color=c=blue:s=160x120:r=1:d=1"].map(String::from));
+        }
+        let channels = match self.audio {
+            Audio::None => None,
+            Audio::Stereo => Some(2),
+            Audio::Surround => Some(6),
+        };
+        if let Some(channels) = channels {
+            args.extend([
+                "-f".to_string(),
+                "lavfi".to_string(),
+                "-i".to_string(),
+                format!(
+                    "sine=frequency=440:sample_rate=48000:duration={}",
+                    self.secs
+                ),
+                "-c:a".to_string(),
+                "aac".to_string(),
+                "-ac".to_string(),
+                channels.to_string(),
+            ]);
+        }
+        args.extend(["-c:v", "libx264", "-pix_fmt:v:0", "yuv420p"].map(String::from));
+        if self.hdr {
+            args.extend(
+                [
+                    "-color_primaries",
+                    "bt2020",
+                    "-color_trc",
+                    "smpte2084",
+                    "-colorspace",
+                    "bt2020nc",
+                ]
+                .map(String::from),
+            );
+        }
+        if self.cover {
+            args.extend(["-map", "0:v"].map(String::from));
+            if channels.is_some() {
+                args.extend(["-map", "2:a"].map(String::from));
+            }
+            args.extend(
+                [
+                    "-map",
+                    "1:v",
+                    "-c:v:1",
+                    "png",
+                    "-disposition:v:1",
+                    "attached_pic",
+                ]
+                .map(String::from),
+            );
+        }
+        args.push(output.to_string_lossy().into_owned());
+        args
+    }
+}
+
+pub const LANDSCAPE: Source = Source::new(640, 360);
+pub const PORTRAIT: Source = Source::new(360, 640);
+pub const NTSC: Source = Source::new(640, 360).fps("30000/1001");
+pub const SURROUND: Source = Source::new(640, 360).audio(Audio::Surround);
+pub const SILENT: Source = Source::new(640, 360).audio(Audio::None);
+pub const HDR: Source = Source::new(640, 360).hdr();
+/// 前置き ([`vvcnv::video::HYBRID_PREROLL`]) を挟んで切り出せる長さの元動画.
+pub const LONG: Source = Source::new(640, 360).secs(8);
+pub const COVER: Source = Source::new(640, 360).cover();
+
+/// テストの中で作るすべての元動画.
+pub const ALL: [Source; 8] = [
+    LANDSCAPE, PORTRAIT, NTSC, SURROUND, SILENT, HDR, LONG, COVER,
+];
+
+/// 1 つのテストが使う一時ディレクトリ. 終わると削除する.
+pub struct Workspace {
+    dir: TempDir,
+}
+
+impl Workspace {
+    /// `VVCNV_FFMPEG_TESTS` を指定していなければ `None` を返すので, テストはそのまま終える.
+    pub fn new(name: &str) -> Option<Self> {
+        if !testing::ffmpeg_tests_enabled(name) {
+            return None;
+        }
+        Some(Self {
+            dir: TempDir::new(&format!("it-{}", name)),
+        })
+    }
+
+    /// 元動画を作り, そのパスを返す. 同じ元動画は 1 回だけ作る.
+    pub fn source(&self, source: &Source) -> String {
+        let path = self.dir.join(source.file_name());
+        if !path.exists() {
+            let status = Command::new(ffmpeg_sidecar::paths::ffmpeg_path())
+                .args(["-hide_banner", "-loglevel", "error"])
+                .args(source.ffmpeg_args(&path))
+                .status()
+                .expect("ffmpeg を起動できません");
+            assert!(status.success(), "元動画を作れません: {:?}", source);
+        }
+        path.to_string_lossy().into_owned()
+    }
+
+    pub fn output(&self, name: &str) -> String {
+        self.dir.join(name).to_string_lossy().into_owned()
+    }
+
+    pub async fn stat(&self, source: &Source) -> VideoStat {
+        video::stat(self.source(source)).await.unwrap()
+    }
+
+    /// `config` でエンコードし, 出力を調べ直した結果を返す.
+    pub async fn encode(&self, source: &Source, config: VideoConfig) -> VideoStat {
+        let output = self.output(&format!(
+            "{}--{}.mp4",
+            source.file_name().trim_end_matches(".mp4"),
+            config.to_file_name()
+        ));
+        self.process(source, VideoProcessParams::new(output, config))
+            .await
+    }
+
+    /// `params` でエンコードし, 出力を調べ直した結果を返す. 出力先は `params.output_path`.
+    pub async fn process(&self, source: &Source, params: VideoProcessParams) -> VideoStat {
+        let stat = self.stat(source).await;
+        let output = params.output_path.clone();
+        video::process(stat, params, ProgressBar::hidden())
+            .await
+            .unwrap();
+        video::stat(output).await.unwrap()
+    }
+
+    /// `params` で再エンコードせずにコピーし, 出力を調べ直した結果を返す.
+    pub async fn remux(&self, source: &Source, params: VideoProcessParams) -> VideoStat {
+        let stat = self.stat(source).await;
+        let output = params.output_path.clone();
+        video::remux(stat, params, ProgressBar::hidden())
+            .await
+            .unwrap();
+        video::stat(output).await.unwrap()
+    }
+}
+
+/// 動画ストリームをデコードして数えたフレーム数.
+pub fn frame_count(path: &str) -> u64 {
+    let output = Command::new(vvcnv::ffmpeg::ffprobe_path())
+        .args(["-v", "error", "-count_frames", "-select_streams", "v:0"])
+        .args(["-show_entries", "stream=nb_read_frames", "-of", "csv=p=0"])
+        .arg(path)
+        .output()
+        .expect("ffprobe を起動できません");
+    assert!(
+        output.status.success(),
+        "フレーム数を数えられません: {}",
+        path
+    );
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .unwrap()
+}
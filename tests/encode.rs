@@ -0,0 +1,203 @@
+//! ffmpeg で作った元動画を使う, 解析とエンコードの結合テスト. `VVCNV_FFMPEG_TESTS=1 cargo test` で実行する.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{
+    Audio, Workspace, ALL, COVER, HDR, LANDSCAPE, LONG, NTSC, PORTRAIT, SILENT, SURROUND,
+};
+use vvcnv::video::{
+    SeekMode, Trim, VideoConfig, VideoConfigUpScalingErr, VideoProcessParams, VideoRes,
+};
+
+/// 元動画の半分の大きさにする設定. FPS と音声は元動画のまま.
+fn half(source: &common::Source) -> VideoConfig {
+    VideoConfig {
+        res: VideoRes::Other(source.width / 2, source.height / 2),
+        fps: source.fps_value().floor() as u32,
+        has_audio: source.audio != Audio::None,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_stat() {
+    let Some(ws) = Workspace::new("stat") else {
+        return;
+    };
+    for source in ALL {
+        let stat = ws.stat(&source).await;
+        assert_eq!(
+            (stat.video_stream.width, stat.video_stream.height),
+            (source.width, source.height),
+            "{:?}",
+            source
+        );
+        assert!(
+            (stat.duration.as_secs_f64() - source.secs as f64).abs() < 0.1,
+            "{:?}: {:?}",
+            source,
+            stat.duration
+        );
+        let channels = stat
+            .audio_streams
+            .iter()
+            .map(|a| a.channels.as_str())
+            .collect::<Vec<_>>();
+        let expected: &[&str] = match source.audio {
+            Audio::None => &[],
+            Audio::Stereo => &["stereo"],
+            Audio::Surround => &["5.1"],
+        };
+        assert_eq!(channels, expected, "{:?}", source);
+    }
+
+    let ntsc = ws.stat(&NTSC).await;
+    assert!((ntsc.video_stream.fps - 29.97).abs() < 0.01);
+    assert_eq!(ws.stat(&HDR).await.video_stream.pix_fmt, "yuv420p");
+}
+
+#[tokio::test]
+async fn test_check_up_scaling() {
+    let Some(ws) = Workspace::new("up-scaling") else {
+        return;
+    };
+    let portrait = ws.stat(&PORTRAIT).await;
+    assert!(half(&PORTRAIT).check_up_scaling(&portrait).is_ok());
+    // 縦長の元動画の高さより低くても, 幅が足りなければ拡大になる
+    assert!(matches!(
+        VideoConfig {
+            res: VideoRes::R480p,
+            has_audio: false,
+            ..Default::default()
+        }
+        .check_up_scaling(&portrait),
+        Err(VideoConfigUpScalingErr::Resolution(..))
+    ));
+
+    let ntsc = ws.stat(&NTSC).await;
+    let fps = |fps| VideoConfig { fps, ..half(&NTSC) };
+    assert!(fps(29).check_up_scaling(&ntsc).is_ok());
+    assert!(matches!(
+        fps(60).check_up_scaling(&ntsc),
+        Err(VideoConfigUpScalingErr::Fps(60, 29))
+    ));
+
+    let silent = ws.stat(&SILENT).await;
+    assert!(matches!(
+        VideoConfig {
+            has_audio: true,
+            ..half(&SILENT)
+        }
+        .check_up_scaling(&silent),
+        Err(VideoConfigUpScalingErr::HasAudio)
+    ));
+}
+
+#[tokio::test]
+async fn test_process() {
+    let Some(ws) = Workspace::new("process") else {
+        return;
+    };
+    for source in [LANDSCAPE, PORTRAIT, NTSC, SURROUND, SILENT, HDR] {
+        let config = half(&source);
+        let output = ws.encode(&source, config.clone()).await;
+        assert_eq!(
+            (output.video_stream.width, output.video_stream.height),
+            config.res.to_wh(),
+            "{:?}",
+            source
+        );
+        assert!(
+            (output.video_stream.fps as f64 - source.fps_value()).abs() < 0.01,
+            "{:?}: {}",
+            source,
+            output.video_stream.fps
+        );
+        assert_eq!(
+            output.audio_streams.is_empty(),
+            !config.has_audio,
+            "{:?}",
+            source
+        );
+        assert!(
+            (output.duration.as_secs_f64() - source.secs as f64).abs() < 0.2,
+            "{:?}: {:?}",
+            source,
+            output.duration
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_process_lowers_fps() {
+    let Some(ws) = Workspace::new("lower-fps") else {
+        return;
+    };
+    let config = VideoConfig {
+        fps: 15,
+        ..half(&LANDSCAPE)
+    };
+    let output = ws.encode(&LANDSCAPE, config).await;
+    assert!(
+        (output.video_stream.fps - 15.0).abs() < 0.01,
+        "{}",
+        output.video_stream.fps
+    );
+    assert!(
+        (output.duration.as_secs_f64() - LANDSCAPE.secs as f64).abs() < 0.2,
+        "{:?}",
+        output.duration
+    );
+}
+
+#[tokio::test]
+async fn test_process_trim_is_frame_accurate() {
+    let Some(ws) = Workspace::new("trim") else {
+        return;
+    };
+    // 前置きより後ろから切り出すので, 入力側で大まかにシークしてから前置きをデコードする
+    let trim = Trim {
+        start: Some(Duration::from_millis(6_500)),
+        end: Some(Duration::from_millis(7_500)),
+        seek: SeekMode::Hybrid,
+    };
+    let params = VideoProcessParams {
+        trim,
+        ..VideoProcessParams::new(ws.output("trim.mp4"), half(&LONG))
+    };
+    let output = ws.process(&LONG, params).await;
+    assert_eq!(common::frame_count(&output.path), 30);
+    assert!(
+        (output.duration.as_secs_f64() - 1.0).abs() < 0.05,
+        "{:?}",
+        output.duration
+    );
+}
+
+#[tokio::test]
+async fn test_remux_keeps_cover() {
+    let Some(ws) = Workspace::new("remux-cover") else {
+        return;
+    };
+    let source = ws.stat(&COVER).await;
+    assert_eq!(source.cover_stream_indices.len(), 1);
+
+    let params = |name: &str, keep_cover, drop_audio| VideoProcessParams {
+        keep_cover,
+        drop_audio,
+        ..VideoProcessParams::new(ws.output(name), half(&COVER))
+    };
+    let kept = ws.remux(&COVER, params("kept.mp4", true, false)).await;
+    assert_eq!(kept.cover_stream_indices.len(), 1);
+    assert_eq!(
+        (kept.video_stream.width, kept.video_stream.height),
+        (COVER.width, COVER.height)
+    );
+    assert_eq!(kept.audio_streams.len(), 1);
+
+    let dropped = ws.remux(&COVER, params("dropped.mp4", false, true)).await;
+    assert!(dropped.cover_stream_indices.is_empty());
+    assert!(dropped.audio_streams.is_empty());
+}